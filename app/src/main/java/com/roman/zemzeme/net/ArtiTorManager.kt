@@ -5,6 +5,8 @@ import android.util.Log
 import com.roman.zemzeme.util.AppConstants
 import info.guardianproject.arti.ArtiLogListener
 import info.guardianproject.arti.ArtiProxy
+import org.torproject.arti.ArtiNative
+import org.torproject.arti.ArtiStateListener
 import kotlinx.coroutines.CoroutineScope
 import kotlinx.coroutines.Dispatchers
 import kotlinx.coroutines.SupervisorJob
@@ -133,13 +135,13 @@ class ArtiTorManager private constructor() {
                 Log.i(TAG, "arti: $s")
                 lastLogTime.set(System.currentTimeMillis())
                 _statusFlow.update { it.copy(lastLogLine = s) }
-                handleArtiLogLine(s)
             }
 
             artiProxy = ArtiProxy.Builder(application)
                 .setSocksPort(currentSocksPort)
                 .setDnsPort(currentSocksPort + 1)
                 .setLogListener(logListener)
+                .setStateListener(ArtiStateListener { state -> handleArtiState(state) })
                 .build()
 
             val savedMode = TorPreferenceManager.get(application)
@@ -437,53 +439,32 @@ class ArtiTorManager private constructor() {
         }
     }
 
-    private fun handleArtiLogLine(s: String) {
-        val currentState = _statusFlow.value.state
+    /**
+     * Follow a native lifecycle transition (see [ArtiNative.setStateCallback]).
+     *
+     * Transitions that don't fit the lifecycle this manager is in are stale
+     * (from a start or stop it has since moved past) and ignored.
+     */
+    private fun handleArtiState(state: Int) {
         val currentLifecycle = lifecycleState
 
-        when {
-            s.contains("AMEx: state changed to Initialized", ignoreCase = true) -> {
+        when (state) {
+            ArtiNative.STATE_INITIALIZED, ArtiNative.STATE_STARTING -> {
                 if (currentLifecycle != LifecycleState.STARTING && currentLifecycle != LifecycleState.RUNNING) {
-                    Log.w(TAG, "Ignoring stale 'Initialized' log (lifecycle: $currentLifecycle)")
+                    Log.w(TAG, "Ignoring stale state $state (lifecycle: $currentLifecycle)")
                     return
                 }
                 _statusFlow.update { it.copy(state = TorState.STARTING) }
                 completeWaitersIf(TorState.STARTING)
             }
 
-            s.contains("AMEx: state changed to Starting", ignoreCase = true) -> {
-                if (currentLifecycle != LifecycleState.STARTING && currentLifecycle != LifecycleState.RUNNING) {
-                    Log.w(TAG, "Ignoring stale 'Starting' log (lifecycle: $currentLifecycle)")
-                    return
-                }
-                _statusFlow.update { it.copy(state = TorState.STARTING) }
-                completeWaitersIf(TorState.STARTING)
-            }
-
-            s.contains(
-                "Sufficiently bootstrapped; system SOCKS now functional",
-                ignoreCase = true
-            ) -> {
-                if (currentLifecycle != LifecycleState.RUNNING) {
-                    Log.w(TAG, "Ignoring bootstrap log (lifecycle: $currentLifecycle)")
-                    return
-                }
-                _statusFlow.update {
-                    it.copy(
-                        bootstrapPercent = 75,
-                        state = TorState.BOOTSTRAPPING
-                    )
-                }
-                retryAttempts = 0
-                bindRetryAttempts = 0
-                startInactivityMonitoring()
-            }
-
-            s.contains("We have found that guard [scrubbed] is usable.", ignoreCase = true) -> {
+            ArtiNative.STATE_RUNNING -> {
                 if (currentLifecycle != LifecycleState.RUNNING) {
-                    Log.w(TAG, "Ignoring guard discovery log (lifecycle: $currentLifecycle)")
+                    Log.w(TAG, "Ignoring stale 'Running' state (lifecycle: $currentLifecycle)")
                     return
                 }
+                // initialize returns only once bootstrapped, so the proxy
+                // is usable as soon as it runs
                 _statusFlow.update {
                     it.copy(
                         state = TorState.RUNNING,
@@ -491,12 +472,15 @@ class ArtiTorManager private constructor() {
                         running = true
                     )
                 }
+                retryAttempts = 0
+                bindRetryAttempts = 0
+                startInactivityMonitoring()
                 completeWaitersIf(TorState.RUNNING)
             }
 
-            s.contains("AMEx: state changed to Stopping", ignoreCase = true) -> {
+            ArtiNative.STATE_STOPPING -> {
                 if (currentLifecycle != LifecycleState.STOPPING) {
-                    Log.w(TAG, "Ignoring stale 'Stopping' log (lifecycle: $currentLifecycle)")
+                    Log.w(TAG, "Ignoring stale 'Stopping' state (lifecycle: $currentLifecycle)")
                     return
                 }
                 _statusFlow.update {
@@ -507,11 +491,11 @@ class ArtiTorManager private constructor() {
                 }
             }
 
-            s.contains("AMEx: state changed to Stopped", ignoreCase = true) -> {
+            ArtiNative.STATE_STOPPED -> {
                 if (currentLifecycle != LifecycleState.STOPPING && currentLifecycle != LifecycleState.STOPPED) {
                     Log.w(
                         TAG,
-                        "Ignoring stale 'Stopped' log (lifecycle: $currentLifecycle, preventing state corruption)"
+                        "Ignoring stale 'Stopped' state (lifecycle: $currentLifecycle, preventing state corruption)"
                     )
                     return
                 }
@@ -525,9 +509,8 @@ class ArtiTorManager private constructor() {
                 completeWaitersIf(TorState.OFF)
             }
 
-            s.contains("Another process has the lock on our state files", ignoreCase = true) -> {
-                _statusFlow.update { it.copy(state = TorState.ERROR) }
-            }
+            // Storage loss and a stale directory leave the proxy running
+            else -> Log.i(TAG, "Native state $state")
         }
     }
 
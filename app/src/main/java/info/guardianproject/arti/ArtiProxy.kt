@@ -3,6 +3,7 @@ package info.guardianproject.arti
 import android.app.Application
 import android.util.Log
 import org.torproject.arti.ArtiNative
+import org.torproject.arti.ArtiStateListener
 import java.io.File
 
 /**
@@ -28,7 +29,8 @@ class ArtiProxy private constructor(
     private val application: Application,
     private val socksPort: Int,
     private val dnsPort: Int,
-    private val logListener: ArtiLogListener?
+    private val logListener: ArtiLogListener?,
+    private val stateListener: ArtiStateListener?
 ) {
     companion object {
         private const val TAG = "ArtiProxy"
@@ -41,9 +43,10 @@ class ArtiProxy private constructor(
      * Start the Arti Tor proxy.
      *
      * This method:
-     * 1. Registers log callback
-     * 2. Initializes Arti runtime with data directory
-     * 3. Starts SOCKS proxy on configured port
+     * 1. Registers log and state callbacks
+     * 2. Hands the audit log its key (see [AuditKey])
+     * 3. Initializes Arti runtime with data directory
+     * 4. Starts SOCKS proxy on configured port
      *
      * @throws ArtiException if initialization or startup fails
      */
@@ -58,6 +61,16 @@ class ArtiProxy private constructor(
                 Log.d(TAG, "Registering log callback")
                 ArtiNative.setLogCallback(listener)
             }
+            stateListener?.let { listener ->
+                Log.d(TAG, "Registering state callback")
+                ArtiNative.setStateCallback(listener)
+            }
+
+            val auditKey = AuditKey.load(application)
+            if (ArtiNative.setAuditKey(auditKey) != 0) {
+                Log.w(TAG, "Audit key rejected; audit events stay in memory")
+            }
+            auditKey.fill(0)
 
             val dataDir = getDataDirectory()
             Log.i(TAG, "Initializing Arti with data directory: $dataDir")
@@ -119,9 +132,8 @@ class ArtiProxy private constructor(
     /**
      * Get or create Arti data directory.
      *
-     * Directory structure:
-     * - {app_data}/arti/cache - Tor directory cache
-     * - {app_data}/arti/state - Tor persistent state
+     * The layout inside it is owned by the native layer (see layout.rs),
+     * which also migrates directories created by older releases.
      */
     private fun getDataDirectory(): File {
         val artiDir = File(application.filesDir, "arti")
@@ -129,9 +141,6 @@ class ArtiProxy private constructor(
             artiDir.mkdirs()
         }
 
-        File(artiDir, "cache").apply { if (!exists()) mkdirs() }
-        File(artiDir, "state").apply { if (!exists()) mkdirs() }
-
         return artiDir
     }
 
@@ -144,6 +153,7 @@ class ArtiProxy private constructor(
         private var socksPort: Int = 9050
         private var dnsPort: Int = 9051
         private var logListener: ArtiLogListener? = null
+        private var stateListener: ArtiStateListener? = null
 
         /**
          * Set SOCKS proxy port.
@@ -172,12 +182,21 @@ class ArtiProxy private constructor(
             this.logListener = listener
         }
 
+        /**
+         * Set listener for lifecycle state changes.
+         * @param listener Callback receiving `ArtiNative.STATE_*` values
+         * @return this Builder for chaining
+         */
+        fun setStateListener(listener: ArtiStateListener) = apply {
+            this.stateListener = listener
+        }
+
         /**
          * Build and return the configured ArtiProxy instance.
          * @return Configured ArtiProxy (not yet started)
          */
         fun build(): ArtiProxy {
-            return ArtiProxy(application, socksPort, dnsPort, logListener)
+            return ArtiProxy(application, socksPort, dnsPort, logListener, stateListener)
         }
     }
 }
@@ -0,0 +1,69 @@
+package info.guardianproject.arti
+
+import android.content.Context
+import android.security.keystore.KeyGenParameterSpec
+import android.security.keystore.KeyProperties
+import android.util.Base64
+import java.security.KeyStore
+import java.security.SecureRandom
+import javax.crypto.Cipher
+import javax.crypto.KeyGenerator
+import javax.crypto.SecretKey
+import javax.crypto.spec.GCMParameterSpec
+
+/**
+ * Key that authenticates the native audit log.
+ *
+ * 32 random bytes generated once and stored in app preferences, wrapped by
+ * an AES key held in the Android Keystore. Neither lives in the Arti data
+ * directory, so editing the audit log there isn't enough to re-sign it.
+ */
+internal object AuditKey {
+    private const val KEYSTORE = "AndroidKeyStore"
+    private const val WRAP_ALIAS = "arti_audit_key_wrap"
+    private const val PREFS = "arti_audit"
+    private const val PREF_WRAPPED = "wrapped_key"
+    private const val KEY_LEN = 32
+    private const val IV_LEN = 12
+    private const val TAG_BITS = 128
+
+    /** Load the audit key, creating it on first use */
+    fun load(context: Context): ByteArray {
+        val prefs = context.getSharedPreferences(PREFS, Context.MODE_PRIVATE)
+        prefs.getString(PREF_WRAPPED, null)?.let { stored ->
+            val wrapped = Base64.decode(stored, Base64.NO_WRAP)
+            val cipher = Cipher.getInstance("AES/GCM/NoPadding")
+            cipher.init(
+                Cipher.DECRYPT_MODE,
+                wrappingKey(),
+                GCMParameterSpec(TAG_BITS, wrapped, 0, IV_LEN)
+            )
+            return cipher.doFinal(wrapped, IV_LEN, wrapped.size - IV_LEN)
+        }
+
+        val key = ByteArray(KEY_LEN).also { SecureRandom().nextBytes(it) }
+        val cipher = Cipher.getInstance("AES/GCM/NoPadding")
+        cipher.init(Cipher.ENCRYPT_MODE, wrappingKey())
+        val wrapped = cipher.iv + cipher.doFinal(key)
+        prefs.edit().putString(PREF_WRAPPED, Base64.encodeToString(wrapped, Base64.NO_WRAP)).apply()
+        return key
+    }
+
+    private fun wrappingKey(): SecretKey {
+        val keyStore = KeyStore.getInstance(KEYSTORE).apply { load(null) }
+        (keyStore.getKey(WRAP_ALIAS, null) as? SecretKey)?.let { return it }
+
+        val generator = KeyGenerator.getInstance(KeyProperties.KEY_ALGORITHM_AES, KEYSTORE)
+        generator.init(
+            KeyGenParameterSpec.Builder(
+                WRAP_ALIAS,
+                KeyProperties.PURPOSE_ENCRYPT or KeyProperties.PURPOSE_DECRYPT
+            )
+                .setBlockModes(KeyProperties.BLOCK_MODE_GCM)
+                .setEncryptionPaddings(KeyProperties.ENCRYPTION_PADDING_NONE)
+                .setKeySize(256)
+                .build()
+        )
+        return generator.generateKey()
+    }
+}
@@ -0,0 +1,20 @@
+package org.torproject.arti
+
+/**
+ * Listener interface for app-level connection policy.
+ *
+ * Called from the native layer for each SOCKS CONNECT that passed the native
+ * checks. The connection waits for [ArtiNative.submitPolicyVerdict] with the
+ * same id, at most the policy budget (see [ArtiNative.setPolicyBudgetMs]).
+ */
+fun interface ArtiConnectionPolicyListener {
+    /**
+     * Called when a connection needs a verdict. Must return quickly; the
+     * verdict may be submitted from here or later from any thread.
+     *
+     * @param id Request id to pass to [ArtiNative.submitPolicyVerdict]
+     * @param targetJson JSON object with the requested `host`, `port` and
+     *        whether it is an `onion` address (no SOCKS credentials)
+     */
+    fun onConnectionRequest(id: Long, targetJson: String)
+}
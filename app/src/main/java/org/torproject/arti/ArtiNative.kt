@@ -13,9 +13,69 @@ import info.guardianproject.arti.ArtiLogListener
  * - 16KB page size support (Google Play Nov 2025 ready)
  * - Onion service client support
  * - Pure Rust TLS (rustls)
+ *
+ * Every function returning JSON wraps its payload in one envelope:
+ * `{"v":1,"ok":true,"data":…}` or
+ * `{"v":1,"ok":false,"error":{"kind":…,"message":…}}`. The `@return` of
+ * each function describes its `data`; [describeApi] lists them all.
  */
 object ArtiNative {
 
+    /** Version (`v`) of the JSON envelope */
+    const val API_VERSION = 1
+
+    /** Lifecycle states delivered to [ArtiStateListener.onStateChanged] */
+    const val STATE_STOPPED = 0
+    const val STATE_INITIALIZED = 1
+    const val STATE_STARTING = 2
+    const val STATE_STOPPING = 3
+    const val STATE_RUNNING = 4
+    /**
+     * The data directory's storage disappeared (e.g. unmounted SD card).
+     * The client keeps running in memory with persistence suspended; the
+     * previous state is reported again once the storage is back.
+     */
+    const val STATE_STORAGE_LOST = 5
+    /**
+     * Running on a consensus older than its validity plus the grace set with
+     * [setMaxConsensusAge]; [STATE_RUNNING] is reported again once a fresh
+     * consensus arrives.
+     */
+    const val STATE_DEGRADED_STALE_DIRECTORY = 6
+
+    /**
+     * [initialize] success: the client bootstrapped earlier for the same data
+     * directory and settings was reused (e.g. after Activity recreation)
+     */
+    const val INIT_REUSED = 1
+
+    /** Bootstrap failure codes returned by [initialize] */
+    const val INIT_ERR_BOOTSTRAP = -3
+    const val INIT_ERR_STORAGE = -4
+    const val INIT_ERR_CONSENSUS = -7
+    /** No reachable guard: the network may be filtered, suggest bridges */
+    const val INIT_ERR_NO_USABLE_GUARDS = -8
+    const val INIT_ERR_CLOCK_SKEW = -9
+    const val INIT_ERR_CONFIG = -10
+    /** A [stop] arrived during the bootstrap and cancelled it */
+    const val INIT_ERR_CANCELLED = -11
+    /**
+     * Debug builds with [setRequireLogCallback]: [initialize] was called
+     * before [setLogCallback]
+     */
+    const val INIT_ERR_NO_LOG_CALLBACK = -12
+    /**
+     * Another process (e.g. a second copy spawned by the system) holds the
+     * data directory; see [getStateLockHolder] and [setStateLockWait]
+     */
+    const val INIT_ERR_STATE_LOCKED = -13
+
+    /**
+     * Returned by functions of an optional feature the native library was
+     * built without (see [getLinkedFeatures])
+     */
+    const val ERR_FEATURE_DISABLED = -100
+
     init {
         System.loadLibrary("arti_android")
     }
@@ -26,29 +86,1298 @@ object ArtiNative {
      */
     external fun getVersion(): String
 
+    /**
+     * Get the optional Arti features compiled into the native library, so
+     * the UI can hide what the build doesn't support
+     *
+     * Functions of a missing feature still exist but do nothing: they return
+     * [ERR_FEATURE_DISABLED], or a `feature_disabled` error if they return
+     * JSON.
+     * @return JSON object of booleans: `bridges`, `pluggable_transports`,
+     *         `onion_service_client`, `onion_service_hosting`, `vanguards`,
+     *         `metrics_endpoint`
+     */
+    external fun getLinkedFeatures(): String
+
+    /**
+     * Describe the JSON envelope and the payload schema of every
+     * JSON-returning function (a JSON Schema subset, keyed by function name)
+     * @return JSON object with `version`, `envelope` and `functions`
+     */
+    external fun describeApi(): String
+
+    /**
+     * Cheap liveness probe for watchdog threads
+     *
+     * Never blocks: reads only atomics, no locks and no async runtime.
+     * Packed fields, most significant first:
+     * - bits 56-63: lifecycle state (`STATE_*`)
+     * - bits 28-55: ms since the last runtime heartbeat (saturating; only
+     *   meaningful after a successful [initialize]). Over 3s means the runtime
+     *   is busy, over 30s treat the native layer as wedged.
+     * - bits 0-27: ms the current Java callback has been running (0 if none).
+     *   Over 5s means a Kotlin callback is blocking native threads.
+     * @return Packed liveness fields
+     */
+    external fun ping(): Long
+
     /**
      * Set log callback for Arti logs
-     * @param callback Callback object with onLogLine(String?) method
+     *
+     * Each callback setter holds one JNI global reference per kind: setting
+     * again releases the previous listener, and null releases it outright.
+     * Register listeners once per process (e.g. from the Application), not
+     * per Activity. Without a log callback, lines are still kept for
+     * [getRecentLogs] (and [setLogFile]), and a one-time warning is written
+     * to logcat.
+     * @param callback Callback object with onLogLine(String?) method, or null
+     */
+    external fun setLogCallback(callback: ArtiLogListener?)
+
+    /**
+     * Replace the log callback without losing or repeating lines
+     *
+     * For a recreated LogCollector: every line goes either to the previous
+     * callback (if logged before the swap) or to [callback] (after it), and
+     * the previous listener is released once no line can reach it. With
+     * [replayFromRing], [callback] first receives the lines retained for
+     * [getRecentLogs], then every later line, each exactly once.
+     * @param callback Callback object with onLogLine(String?) method, or null
+     * @param replayFromRing true to deliver the recent-log ring first
+     */
+    external fun setLogCallbackWithReplay(callback: ArtiLogListener?, replayFromRing: Boolean)
+
+    /**
+     * Make [initialize] fail fast when no log callback is registered
+     *
+     * Debug builds of the native library always write a logcat warning when
+     * [initialize] runs before [setLogCallback]; with this set, [initialize]
+     * also returns [INIT_ERR_NO_LOG_CALLBACK] without doing anything.
+     * Release builds ignore it and stay silent.
+     * @param enabled true to fail initialize without a log callback
+     */
+    external fun setRequireLogCallback(enabled: Boolean)
+
+    /**
+     * Enable or disable writing native log lines to logcat
+     *
+     * Defaults to enabled in debug builds of the native library and disabled
+     * in release builds. The log callback still receives every line.
+     * @param enabled true to write to logcat
+     */
+    external fun setLogcatEnabled(enabled: Boolean)
+
+    /**
+     * Enable or disable safe logging
+     *
+     * On by default: destination hosts and peer addresses in native log
+     * lines read `[scrubbed]` in logcat, the log callback, the log file and
+     * [getRecentLogs]. A change also covers lines logged earlier but not yet
+     * delivered or read. Turning it off in a release build is recorded in the
+     * audit trail.
+     * @param enabled false to show hosts and addresses in log lines
+     */
+    external fun setSafeLogging(enabled: Boolean)
+
+    /**
+     * Enable or disable verbose (DEBUG) logging
+     *
+     * Also logs the circuit path (guard -> middle -> exit, with the guard
+     * scrubbed) of every established connection. Off by default.
+     * @param enabled true to forward DEBUG events to the log sinks
+     */
+    external fun setVerboseLogging(enabled: Boolean)
+
+    /**
+     * Limit the metadata the native layer retains, for device-seizure threat models
+     *
+     * - 0 (full): everything is kept (default)
+     * - 1 (reduced): no destination-quality persistence, no [getBridgeHistory];
+     *   audit and failure record timestamps rounded down to 10 minutes
+     * - 2 (minimal): as reduced, plus no recent-log or debug ring, no
+     *   [setLogFile] file, no persisted counters, no [getMetricsHistory]
+     *   snapshots, no [getReliabilityStats] history, no persisted
+     *   [getBootstrapHistory], metrics reset on [stop],
+     *   and [getNotificationStatus] carries only `state`
+     *
+     * Raising the level purges what it would not have kept. Audit records
+     * written earlier keep their timestamps (the chain can't be rewritten).
+     * @param level 0, 1 or 2
+     * @return 0 on success, -1 for an unknown level
+     */
+    external fun setMetadataRetention(level: Int): Int
+
+    /**
+     * Set state callback for lifecycle transitions
+     *
+     * This is the authoritative source for lifecycle state, called once per
+     * transition; the "AMEx: state changed to X" log lines are for humans only.
+     * @param callback Callback object with onStateChanged(Int) method, or null
+     */
+    external fun setStateCallback(callback: ArtiStateListener?)
+
+    /**
+     * Set callback for notification status changes
+     *
+     * Fired at most once every 5 seconds, only when the status returned by
+     * [getNotificationStatus] would differ from the last delivered one.
+     * @param callback Callback object with onNotificationStatusChanged(String) method, or null
+     */
+    external fun setNotificationStatusCallback(callback: ArtiNotificationStatusListener?)
+
+    /**
+     * Set callback for high-priority security events (signature verification
+     * failures, unexpected authority responses, relay identity mismatches)
+     * @param callback Callback object with onSecurityEvent(String) method, or null
+     */
+    external fun setSecurityEventCallback(callback: ArtiSecurityEventListener?)
+
+    /**
+     * Set the app-level connection policy callback
+     *
+     * While set, every CONNECT that passes the native checks is put to the
+     * callback and waits for [submitPolicyVerdict], at most the policy budget.
+     * Without an answer in time the default verdict applies and the timeout is
+     * counted as `policy_timeouts` in [getMetrics].
+     * @param callback Callback object with onConnectionRequest(long, String) method, or null
+     */
+    external fun setConnectionPolicyCallback(callback: ArtiConnectionPolicyListener?)
+
+    /**
+     * Answer a request from [ArtiConnectionPolicyListener.onConnectionRequest]
+     *
+     * Denied connections get SOCKS reply 0x02 (not allowed by ruleset).
+     * @param id The request's id
+     * @param allow true to let the connection proceed
+     * @param annotationJson JSON value attached to the connection's log lines
+     *        (e.g. a category label), or null
+     * @return 0 if taken, -1 if the request is no longer waiting (answered,
+     *         timed out or unknown), -2 if the annotation isn't valid JSON
+     */
+    external fun submitPolicyVerdict(id: Long, allow: Boolean, annotationJson: String?): Int
+
+    /**
+     * Set how long a connection waits for its policy verdict
+     * @param millis Budget in milliseconds (default 50)
+     * @return 0 on success, -1 outside 1..200
+     */
+    external fun setPolicyBudgetMs(millis: Int): Int
+
+    /**
+     * Set the verdict for policy requests not answered within the budget
+     * @param allow true to allow (the default), false to deny
+     */
+    external fun setPolicyDefaultAllow(allow: Boolean)
+
+    /**
+     * Get the callback references currently held by the native layer
+     *
+     * A callback that blocks for over 5 s is marked unhealthy and its
+     * messages are dropped until it answers promptly again; calls over
+     * 500 ms count as slow.
+     * @return JSON object with `live`, `releases` and per-kind `registered`,
+     *         `registrations`, `healthy`, `slow_calls` and `dropped`
+     */
+    external fun getCallbackRegistrations(): String
+
+    /**
+     * Get a consistent status for the foreground-service notification
+     *
+     * All fields are taken from the same instant, e.g.
+     * `{"state":"running","detail_key":"active_connections","args":[3],"bytes_today":1234567,"since_epoch_ms":...,"prewarm":"done"}`.
+     * `prewarm` is one of `done`, `in_progress`, `skipped` or `failed`.
+     * While bootstrapping, `detail_key` `no_usable_guards` means a consensus
+     * was fetched but no guard is reachable: the network may be filtered, and
+     * bridges may help.
+     * `detail_key` plus `args` are meant to be mapped to a localized string resource.
+     * @return JSON status object
+     */
+    external fun getNotificationStatus(): String
+
+    /**
+     * Get Arti's bootstrap progress
+     *
+     * Answered within the query budget (see [setQueryTimeoutMs]); when the
+     * runtime is too busy, the last known status is returned with `stale`
+     * set instead of blocking. Calls within the cache TTL (see
+     * [setQueryCacheTtlMs]) share one result, and calls while it is being
+     * computed share that computation; a lifecycle change drops the cache.
+     * @return JSON object with `percent`, `ready_for_traffic`, `blocked`
+     *         (reason or null), `description`, `directory_fetch_attempts`
+     *         (directory download passes seen in Arti's bootstrap events
+     *         since [initialize]), `degraded` (`DEGRADED_STALE_DIRECTORY`
+     *         while [setMaxConsensusAge] applies, else null) and `stale`
+     */
+    external fun getBootstrapStatus(): String
+
+    /**
+     * Like [getBootstrapStatus], but always computes a new status instead of
+     * using the cache (for tests)
+     */
+    external fun getBootstrapStatusFresh(): String
+
+    /**
+     * Set what happens once the consensus is older than its validity plus a grace
+     *
+     * The age is measured so that setting the clock back never makes an old
+     * consensus fresh again.
+     * @param mode 0 = Tor's default behaviour, 1 = traffic allowed but
+     *        [STATE_DEGRADED_STALE_DIRECTORY] reported, 2 = as 1 and new
+     *        connections refused (SOCKS reply 0x03)
+     * @param graceMinutes Minutes past the consensus validity, 0..1440
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setMaxConsensusAge(mode: Int, graceMinutes: Int): Int
+
+    /**
+     * Set the time budget of runtime-backed status queries such as
+     * [getBootstrapStatus]. Default is 250 ms.
+     * @param ms Budget in milliseconds, 10..10000
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setQueryTimeoutMs(ms: Int): Int
+
+    /**
+     * Set how long status query results such as [getBootstrapStatus] are
+     * shared between callers. Default is 500 ms; 0 disables the cache.
+     * @param ms TTL in milliseconds, 0..60000
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setQueryCacheTtlMs(ms: Int): Int
+
+    /**
+     * Set the window within which start/stop commands are coalesced
+     *
+     * With the proxy running, [stop] is deferred for the window and a
+     * [startSocksProxy] on the same port within it takes the stop back, so
+     * rapid toggling doesn't tear the proxy down. Set 0 for deterministic
+     * integration tests.
+     * @param ms Window in milliseconds, 0..10000 (default 1500, 0 disables)
+     * @return 0 on success, -1 if out of range
      */
-    external fun setLogCallback(callback: ArtiLogListener)
+    external fun setLifecycleDebounceMs(ms: Int): Int
+
+    /**
+     * Give every destination port its own isolated circuit
+     *
+     * Composes with other isolation modes: streams only share a circuit
+     * when they agree on the port and every other isolation component.
+     * @param enabled true to isolate streams by destination port
+     */
+    external fun setIsolateByDestinationPort(enabled: Boolean)
+
+    /**
+     * Refuse IPv6 targets, for networks where IPv6 is advertised but broken
+     *
+     * IPv6 CONNECT targets get SOCKS reply 0x08 (address type not supported)
+     * at once, so apps fall back to IPv4 without waiting on a dead path, and
+     * exits connect to hostnames over IPv4 only. Refusals are counted as
+     * `ipv6_targets_refused` in [getMetrics]. Off by default.
+     * @param disabled true to refuse IPv6 targets
+     */
+    external fun setDisableIpv6Targets(disabled: Boolean)
+
+    /**
+     * Guarantee that no traffic leaves Tor through an exit
+     *
+     * CONNECT targets that aren't `.onion` addresses get SOCKS reply 0x02
+     * (not allowed by ruleset). Names with an onion alias ([addOnionAlias])
+     * still connect to their onion. Off by default.
+     * @param enabled true to allow onion targets only
+     */
+    external fun setOnionOnly(enabled: Boolean)
+
+    /**
+     * Require CONNECT targets to be named
+     *
+     * IP-literal targets get SOCKS reply 0x02 (not allowed by ruleset)
+     * unless the SOCKS username carries a `host=` claim naming them. Off by
+     * default.
+     * @param enabled true to refuse unnamed IP literals
+     */
+    external fun setRequireDomainTargets(enabled: Boolean)
+
+    /**
+     * Start a new identity
+     *
+     * Streams opened afterwards never share circuits with earlier ones.
+     * Destination quality scores are cleared too, unless persistent quality
+     * tracking is enabled.
+     */
+    external fun newIdentity()
+
+    /**
+     * List the isolation groups with live streams
+     *
+     * Streams in different groups never share a circuit. Network tokens and
+     * claimed hosts are replaced by a digest that only tells them apart
+     * within this process.
+     * @return JSON object with `groups` (up to 64, busiest first: `group`,
+     *         the key components or `"default"`, `streams` and `circuits`),
+     *         `total_groups` and `truncated`
+     */
+    external fun listIsolationGroups(): String
+
+    /**
+     * Set the token identifying the current network
+     *
+     * Circuits are never shared across network tokens. Call from the
+     * NetworkCallback on every change; repeating the same value marks it as
+     * still current.
+     * @param token Opaque network identifier, or "" to clear
+     */
+    external fun setNetworkToken(token: String)
+
+    /**
+     * Set how long the network token stays fresh without a refresh
+     *
+     * A stale token additionally isolates circuits by time period, and
+     * `NETWORK_TOKEN_STALE` is logged once per stale period. Default 0 (off).
+     * @param seconds Maximum age, 0 to disable
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setNetworkTokenMaxAge(seconds: Int): Int
+
+    /**
+     * Confirm each Tor stream is open and writable before the SOCKS success reply
+     *
+     * Adds a round trip to the exit to every connect; in exchange, a stream
+     * that fails then is reported to the client as a SOCKS error instead of
+     * a reset after it has sent its first payload. Off by default; the added
+     * latency is reported as `stream_confirm_ms_total` / `stream_confirmations`
+     * in [getMetrics].
+     * @param enabled true to confirm streams before replying
+     */
+    external fun setConfirmStreamWritable(enabled: Boolean)
+
+    /**
+     * Dial a clearnet host as its known onion address (e.g. from its
+     * Onion-Location header), keeping the traffic end-to-end inside Tor
+     *
+     * Applies to CONNECTs naming [clearHost] (directly or via a `host=`
+     * claim), on the same port; not to fronted connections. Each upgrade is
+     * logged and counted as `onion_upgrades` in [getMetrics].
+     * @param clearHost Clearnet hostname
+     * @param onionHost v3 onion address, or "" to remove the alias
+     * @return 0 on success, -1 if either name is invalid
+     */
+    external fun addOnionAlias(clearHost: String, onionHost: String): Int
+
+    /**
+     * Refuse SOCKS CONNECTs to matching destinations
+     *
+     * Refused clients get SOCKS reply 0x02 (connection not allowed by
+     * ruleset) and the connection is closed.
+     * @param patterns Comma- or newline-separated `host`, `*.domain`,
+     *        optionally with `:port`; "" clears the list
+     * @return 0 on success, -1 if a pattern is malformed
+     */
+    external fun setDestinationDenylist(patterns: String): Int
+
+    /**
+     * Only allow SOCKS CONNECTs to matching destinations
+     *
+     * The denylist takes precedence. Other targets get SOCKS reply 0x02.
+     * @param patterns Same syntax as [setDestinationDenylist]; "" allows everything
+     * @return 0 on success, -1 if a pattern is malformed
+     */
+    external fun setDestinationAllowlist(patterns: String): Int
+
+    /**
+     * Refuse every direct (non-Tor) outbound connection from the native layer
+     * @param enabled true to enable strict Tor-only mode
+     * @return 0 on success, -1 if the build forces strict mode on
+     */
+    external fun setStrictTorOnly(enabled: Boolean): Int
+
+    /**
+     * Set the cap on total relay buffer memory across all connections
+     *
+     * When the cap is reached, new connections use smaller buffers or are
+     * briefly queued. Default is 8 MiB.
+     * @param bytes Cap in bytes
+     * @return 0 on success, -1 if the cap is too small for a single connection
+     */
+    external fun setRelayBufferMemoryCap(bytes: Long): Int
+
+    /**
+     * Set the cap on the approximate memory of the native tables together
+     *
+     * Covers destination quality and bridge history. A
+     * table growing past its share of the cap evicts its least recently used
+     * entries. Default is 2 MB.
+     * @param bytes Cap in bytes (64 KiB to 64 MiB)
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setCacheBudget(bytes: Long): Int
+
+    /**
+     * Get the SOCKS feature set in effect
+     *
+     * SOCKS clients get the same bitmap from a CONNECT to
+     * `capabilities.invalid`: reply 0x02 with the bitmap as BND.ADDR (IPv4,
+     * big-endian) and the encoding version as BND.PORT; a plain SOCKS proxy
+     * replies with BND.PORT 0. The target is never dialed or counted.
+     * @return JSON object with `version`, `bitmap`, `capabilities` (names of
+     *         the set bits: "host_claim", "front", "onion_alias",
+     *         "listener_auth", "onion_only", "ipv6_targets_disabled",
+     *         "class", "deadline", "connid", "iso",
+     *         "crypto_failure_event") and `bits` (name to bit number, for
+     *         decoding the probe reply; retired bits such as 6,
+     *         "crypto_failure_reply", are listed but never set)
+     */
+    external fun getProxyCapabilities(): String
+
+    /**
+     * Get the memory used by the native tables
+     *
+     * Sizes are estimates of what each entry holds, not allocator figures.
+     * @return JSON `{"budget_bytes":..,"total_bytes":..,"trims":..,
+     *         "relay_buffer_bytes":..,"caches":[{"name":..,"entries":..,
+     *         "max_entries":..,"approx_bytes":..}]}`
+     */
+    external fun getMemoryStats(): String
+
+    /**
+     * Forward `ComponentCallbacks2.onTrimMemory` to the native layer
+     *
+     * Trims the native tables proportionally: nothing for
+     * `TRIM_MEMORY_UI_HIDDEN`, everything for `TRIM_MEMORY_COMPLETE`.
+     * @param level The level passed to `onTrimMemory`
+     */
+    external fun onTrimMemory(level: Int)
+
+    /**
+     * Set how long a write toward a local client may make no progress
+     *
+     * A client that stops reading its socket is disconnected after this long
+     * (close reason `client_stalled`), freeing its Tor stream and buffers.
+     * Default is 60 seconds.
+     * @param seconds Timeout, 1..3600
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setClientStallTimeout(seconds: Int): Int
+
+    /**
+     * Opt into a check that the proxy gets any connection through
+     *
+     * If connects were attempted in the window after [startSocksProxy] but
+     * none was established while the client is ready for traffic, a
+     * `PROXY_NON_FUNCTIONAL` error line is logged and [getLastError] reports
+     * `proxy_non_functional`. An idle proxy or one still bootstrapping is not
+     * judged. Off by default; applies from the next start.
+     * @param windowSecs Window in seconds, 1..3600, or 0 to turn it off
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setProxyWatchdog(windowSecs: Int): Int
+
+    /**
+     * Set how long [initialize] waits for another process holding the data
+     * directory to release it before returning [INIT_ERR_STATE_LOCKED]
+     *
+     * Applies from the next [initialize].
+     * @param waitSecs 0..60 seconds; 0 (the default) fails at once
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setStateLockWait(waitSecs: Int): Int
+
+    /**
+     * Get the data directory lock's state
+     * @return JSON `{"held", "holder"}`: this process while it holds the
+     *         lock, else the holder seen by the last [INIT_ERR_STATE_LOCKED]
+     *         failure (null if none), as `pid`, `start_ticks` (process
+     *         start, clock ticks since boot), `since_epoch_ms` and `alive`
+     */
+    external fun getStateLockHolder(): String
+
+    /**
+     * Set how long a circuit keeps being used for new streams
+     *
+     * Shorter windows make streams harder to link to each other but build
+     * more circuits; longer ones favour performance. Default is 600 seconds.
+     * Takes effect on the next initialize.
+     * @param seconds Reuse window, 10..86400
+     * @return 0 on success, -1 if out of range
+     */
+    external fun setMaxCircuitDirtinessSecs(seconds: Int): Int
+
+    /**
+     * Restrict the address family relays are connected on, for carrier
+     * networks where one family hangs
+     *
+     * Prefer IPv6 (3) allows both families, since Arti has no family
+     * preference: IPv6 isn't necessarily tried first, which
+     * [getEffectiveConfig] reports as the `effect`
+     * `both_families_order_not_guaranteed`. Bridges are dialed on their
+     * configured address whatever the mode; one of an excluded family is
+     * logged as a warning. Takes effect on the next [initialize] and shows
+     * in [getEffectiveConfig] as `relay_address_family`.
+     * @param mode 0 auto, 1 IPv4 only, 2 IPv6 only, 3 prefer IPv6
+     * @return 0 on success, -1 for an unknown mode
+     */
+    external fun setRelayAddressFamily(mode: Int): Int
+
+    /**
+     * Get wrapper metrics
+     * @return JSON object with wrapper counters, including `user_bytes`,
+     *         `directory_bytes` (estimated), `directory_bytes_by_month`,
+     *         `client_stalled_closes`, `connects_established`, `connect_ms_total`,
+     *         `connect_failures`, `stream_confirmations`,
+     *         `stream_confirm_ms_total`, `stream_confirm_failures`, `onion_upgrades`,
+     *         `ipv6_targets_refused`, `policy_timeouts`, `no_usable_guards`, `entry_suspicions`, `stale_query_answers`,
+     *         `query_cache_hits`, `query_cache_misses`, `query_cache_coalesced`,
+     *         `log_file_dropped_lines`, `log_callback_dropped_lines`,
+     *         `network_token_age_secs`, `network_token_stale`, and flow stalls
+     *         (blocked writes of 2 s or more) by side: `client_stalls`,
+     *         `client_stall_ms`, `tor_stalls` and `tor_stall_ms`
+     */
+    external fun getMetrics(): String
+
+    /**
+     * Get the main wrapper metrics in the Prometheus text exposition format
+     *
+     * Bootstrap progress, active connections, established and failed
+     * connects, relayed bytes, security events and connect latency (a
+     * summary in seconds), all named `arti_*`. Counters restart from zero
+     * like those of [getMetrics].
+     * @return Exposition text, not a JSON envelope; empty without the
+     *         `metrics_endpoint` feature (see [getLinkedFeatures])
+     */
+    external fun getPrometheusMetrics(): String
+
+    /**
+     * Estimate the share of traffic that did not go through the proxy
+     *
+     * Compares device byte counters with the tunnel's own counters over the
+     * interval since the previous call; the first call only records a
+     * baseline (`"status":"baseline"`). Measurement only.
+     * @return JSON object with `status`, `source`, `interval_secs`,
+     *         `device_bytes`, `tunnel_bytes`, `bypass_fraction` (0..1 or
+     *         null), `confidence` ("none", "low" or "medium") and `caveats`
+     */
+    external fun getBypassEstimate(): String
+
+    /**
+     * Supply cumulative byte counters for [getBypassEstimate]
+     *
+     * Only used when `/proc/net` can't be read (Android 10+). Pass
+     * `TrafficStats.getUidRxBytes(myUid)` / `getUidTxBytes(myUid)` to measure
+     * this app alone, and call it right before [getBypassEstimate].
+     * @return 0 on success, -1 if a counter is negative (unsupported)
+     */
+    external fun reportTrafficCounters(rxBytes: Long, txBytes: Long): Int
+
+    /**
+     * Configure a client authorization key for a restricted-discovery onion service
+     *
+     * Takes effect on the next [initialize].
+     * @param onionAddr Onion address (with or without ".onion")
+     * @param privateKey Base32 x25519 private key, or "" to remove the entry
+     * @return 0 on success, -1 on invalid arguments, [ERR_FEATURE_DISABLED]
+     *         without the onion-service-client feature
+     */
+    external fun setOnionClientAuth(onionAddr: String, privateKey: String): Int
+
+    /**
+     * Configure bridge lines
+     *
+     * Pluggable transport binaries are set via `pt_binaries` in the config
+     * passed to [initializeWithConfig]. Takes effect on the next [initialize].
+     * @param lines Bridge lines, one per line; "" to stop using bridges
+     * @return 0 on success, -1 if a line can't be parsed (or names a
+     *         transport in a build without pluggable transports),
+     *         [ERR_FEATURE_DISABLED] without the bridges feature
+     */
+    external fun setBridges(lines: String): Int
+
+    /**
+     * Set the order in which bridge transports are tried during bootstrap
+     *
+     * Each listed transport is tried alone, falling back to the next after a
+     * failure or a 60 second timeout; unlisted transports are tried last.
+     * Takes effect on the next [initialize].
+     * @param list Comma-separated transport names, e.g. "snowflake,obfs4,direct"
+     * @return 0 on success, -1 if the list names a transport no configured bridge uses,
+     *         [ERR_FEATURE_DISABLED] without the bridges feature
+     */
+    external fun setTransportPriority(list: String): Int
+
+    /**
+     * Tune Arti's directory download retries, for flaky networks
+     *
+     * Schedules are `bootstrap` (the whole directory download),
+     * `consensus`, `certs` and `microdescs`, each with optional `attempts`,
+     * `initial_delay_ms` and `parallelism`; omitted ones keep Arti's
+     * defaults. Takes effect on the next [initialize].
+     * @param schedulesJson e.g. {"consensus":{"attempts":6,"initial_delay_ms":2000}}; "{}" for defaults
+     * @return 0 on success, -1 if the object is invalid
+     */
+    external fun setDirectoryRetry(schedulesJson: String): Int
+
+    /**
+     * Set the target ports Tor prepares circuits for ahead of time
+     *
+     * Circuits ready at startup then go to exits allowing these ports. A
+     * hint only: other ports still work, and are prepared for once used.
+     * Takes effect on the next [initialize].
+     * @param list Comma-separated ports (at most 16), e.g. "443"; "" for the
+     *        default of 80 and 443
+     * @return 0 on success, -1 if a port is invalid or listed twice
+     */
+    external fun setTargetPortHints(list: String): Int
+
+    /**
+     * Require exit relays to carry consensus flags
+     *
+     * Supported flags are `Fast` and `Stable`. Every connection's exit is
+     * checked against the consensus: one lacking a required flag is dropped
+     * and the connect retried on a fresh circuit, up to 3 times, before it
+     * is refused as not allowed by ruleset. Path selection also picks a
+     * Stable exit up front for the ports named in [setTargetPortHints] (and
+     * Arti's own long-lived ports, e.g. 22 and 5222). Fewer flaky exits, at
+     * the cost of a smaller exit pool. [getEffectiveConfig] reports how each
+     * flag is enforced under `effect`. Takes effect on the next [initialize].
+     * @param list Comma-separated flag names, e.g. "Stable,Fast"; "" for none
+     * @return 0 on success, -1 if a flag can't be required of exits
+     */
+    external fun setRequiredExitFlags(list: String): Int
+
+    /**
+     * Bias circuits toward fast exits
+     *
+     * Arti weights exits by bandwidth and cannot reweight them; this lowers
+     * the circuit build timeout quantile instead, so builds slower than most
+     * are abandoned and retried on other relays. Costs some exit diversity,
+     * and only bites once Arti has learned build times. Takes effect on the
+     * next [initialize].
+     * @param enabled true to prefer fast exits
+     * @return 0 on success
+     */
+    external fun setPreferFastExits(enabled: Boolean): Int
+
+    /**
+     * Get the bridge the current client bootstrapped through
+     *
+     * @return JSON `{"transport":..,"bridge":..,"candidates":[..]}` with
+     *         bridges identified by fingerprint only; `bridge` is null when
+     *         several bridges of the same transport were tried together (all
+     *         listed in `candidates`). "null" when no bridges are in use.
+     */
+    external fun getActiveBridge(): String
+
+    /**
+     * Get the health history of each configured bridge, for a sparkline
+     *
+     * Hourly buckets over the last 7 days of connects through the bridge.
+     * `degrading` is set when the last 6 hours are significantly worse than
+     * the days before (success rate down by more than 20 points, or median
+     * connect latency more than doubled); a `BRIDGE_DEGRADING` log line is
+     * emitted when it turns on. Kept at the full retention level only.
+     * @return JSON `{"bridges":[{"fingerprint":..,"degrading":..,
+     *         "recent_success_rate":..,"baseline_success_rate":..,
+     *         "recent_median_connect_ms":..,"baseline_median_connect_ms":..,
+     *         "hours":[{"hour_epoch_ms":..,"attempts":..,"success_rate":..,
+     *         "median_connect_ms":..}]}]}`
+     */
+    external fun getBridgeHistory(): String
+
+    /**
+     * Get the connection quality of one destination through Tor
+     *
+     * @param host Destination host as passed in SOCKS CONNECT
+     * @param port Destination port
+     * @return JSON with `score` (0-100), `success_rate`, `attempts`,
+     *         `median_connect_ms`, `median_first_byte_ms`,
+     *         `throughput_bytes_per_sec` and `last_used_epoch_ms`, or "null"
+     *         if the destination hasn't been seen
+     */
+    external fun getDestinationQuality(host: String, port: Int): String
+
+    /**
+     * Get the best-scoring destinations, for diagnostics
+     *
+     * @param n Maximum number of entries
+     * @return JSON array of quality objects with a hashed `key` instead of
+     *         the host and port
+     */
+    external fun getTopDestinations(n: Int): String
+
+    /**
+     * Keep destination quality scores across restarts
+     *
+     * Off by default, since scores are linkable history. Disabling deletes
+     * the stored copy.
+     * @param enabled true to persist scores in the wrapper state directory
+     */
+    external fun setPersistentDestinationQuality(enabled: Boolean)
+
+    /**
+     * Get the diagnostic record for the most recent failure
+     *
+     * The record's `debug_context` holds the DEBUG-level lines captured in
+     * the 15 seconds before the failure, even if debug logging was off.
+     * @return JSON failure record, or "null" if nothing has failed
+     */
+    external fun getLastError(): String
+
+    /**
+     * Set the key that authenticates the audit log
+     *
+     * The key never touches the Tor data directory, so whoever can edit the
+     * log can't re-sign it. Generate 32 random bytes once, keep them wrapped
+     * by an Android Keystore key outside the data directory, and pass the
+     * unwrapped bytes at every process start, before or after [initialize].
+     * Until then audit events are held in memory.
+     * @param key 32 bytes, the same every time
+     * @return 0 on success, -1 if the length is wrong or another key is
+     *         already set in this process
+     */
+    external fun setAuditKey(key: ByteArray): Int
+
+    /**
+     * Get the most recent audit records (strict mode disabled, onion client
+     * keys changed, data directory migrations, ...)
+     *
+     * Records never contain secrets or destinations.
+     * @param maxRecords Maximum number of records to return
+     * @return JSON array of audit records, oldest first
+     */
+    external fun getAuditLog(maxRecords: Int): String
+
+    /**
+     * Verify the audit log's MAC chain
+     * @return JSON object with `ok`, `records`, `first_seq`, `last_seq` and a
+     *         `problems` array (bad_mac, broken_chain, seq_gap, unparseable,
+     *         truncated_head, truncated_tail, bad_head, or no_key before
+     *         [setAuditKey])
+     */
+    external fun verifyAuditLog(): String
+
+    /**
+     * Get the most recent native log lines, including those logged before
+     * a log callback was registered
+     * @return JSON array of log lines, oldest first
+     */
+    external fun getRecentLogs(): String
+
+    /**
+     * Set how many recent log lines are retained (default 200, max 10000)
+     * @param lines Number of lines, 0 disables the ring
+     */
+    external fun setRecentLogCapacity(lines: Int)
+
+    /**
+     * Cap how many log lines per second reach the log callback, to keep a
+     * UI that shows them responsive during bootstrap
+     *
+     * Lines over the cap are dropped and counted as
+     * `log_callback_dropped_lines` in [getMetrics]; the next delivered line is
+     * preceded by a note of how many. Errors and warnings always go through.
+     * The recent-log ring and the log file still get every line.
+     * @param perSec Lines per second, 0..10000 (0 means no limit, the default)
+     * @return 0 on success, -1 on invalid limit
+     */
+    external fun setLogRateLimitPerSec(perSec: Int): Int
+
+    /**
+     * Also append native log lines to a file, so crash reports can include
+     * them even if the process died before the log callback got them.
+     * The file is rotated at 1 MiB, keeping `path`, `path.1` and `path.2`.
+     * @param path Absolute path in an existing directory (e.g. under
+     *             `cacheDir`), or "" to stop writing
+     * @return 0 on success, -1 if the path is unusable
+     */
+    external fun setLogFile(path: String): Int
+
+    /**
+     * Export a diagnostics bundle for bug reports
+     * @return JSON object with state, metrics, the last failure record,
+     *         recent log lines, the persisted metrics snapshots and the
+     *         bootstrap history (as [getBootstrapHistory])
+     */
+    external fun exportDiagnostics(): String
+
+    /**
+     * Get the persisted metrics snapshots, for post-mortem graphs
+     *
+     * A snapshot is written every [setMetricsSnapshotInterval] minutes and
+     * kept across restarts (at most 24). Snapshots are skipped while the
+     * battery is low and nothing happened since the last one.
+     * @param maxSnapshots How many of the newest snapshots to return
+     * @return JSON array, oldest first, of `{"at_epoch_ms":..,"state":..,
+     *         "bootstrap_percent":..,"ready_for_traffic":..,"battery_low":..,
+     *         "metrics":{..}}` (`metrics` as in [getMetrics])
+     */
+    external fun getMetricsHistory(maxSnapshots: Int): String
+
+    /**
+     * Set how often a metrics snapshot is persisted
+     * @param minutes Minutes between snapshots (default 15), 0 to disable
+     * @return 0 on success, -1 outside 0..1440
+     */
+    external fun setMetricsSnapshotInterval(minutes: Int): Int
+
+    /**
+     * Get reliability over recent sessions, e.g. for "available 97% of the time"
+     *
+     * Folded from lifecycle transitions persisted across sessions: time
+     * `Running` counts as ready, `Initialized`/`Starting`/`StorageLost` as
+     * degraded, and stopped states plus the time between sessions as stopped.
+     * Time before the first recorded transition isn't counted. Timestamps are
+     * coarse at the reduced retention level; nothing is kept at minimal.
+     * @param days Window length in days, 1..90
+     * @return JSON object with `days`, `from_epoch_ms`, `covered_ms`,
+     *         `ready_ms`, `degraded_ms`, `stopped_ms`, `ready_percent` (null
+     *         without history), `sessions`, `bootstraps`, `avg_bootstrap_ms`
+     *         (null if none completed), `recoveries` (storage came back) and
+     *         `clock_jumps` (wall clock set back; those spans are skipped)
+     */
+    external fun getReliabilityStats(days: Int): String
+
+    /**
+     * Get the recent bootstrap attempts and why each happened
+     *
+     * The last 20, including earlier processes' from the persisted history
+     * (none persisted at the minimal retention level).
+     * @return JSON array, oldest first, of objects with `at_epoch_ms`,
+     *         `trigger` (`user`, `data_dir_change` or `migration_rollback`),
+     *         `config_fingerprint` (equal for equal settings), `outcome`
+     *         (`ok` or a failure kind), `duration_ms` and `entry` (`direct`,
+     *         `bridge` or a transport name; null on failure)
+     */
+    external fun getBootstrapHistory(): String
+
+    /**
+     * Get the condition of the data directory's storage
+     * @return JSON object with `state` ("ok", "lost" or "unwatched"),
+     *         `lost_since_epoch_ms`, `probes`, `next_probe_in_secs`,
+     *         `suspended_writes`, `free_bytes` and `total_bytes`
+     */
+    external fun getStorageStats(): String
+
+    /**
+     * Report the battery condition (forward battery-low / battery-saver broadcasts)
+     *
+     * While low, the SOCKS proxy stops accepting new connections and the
+     * client goes dormant; established connections continue. Connection
+     * attempts made meanwhile are accepted once the battery is no longer low.
+     * @param low true when the battery is low or battery saver is on
+     */
+    external fun onBatteryLow(low: Boolean)
+
+    /**
+     * Report that the device entered Doze (e.g. from
+     * `PowerManager.ACTION_DEVICE_IDLE_MODE_CHANGED`)
+     *
+     * The client goes dormant until [onDozeExit].
+     */
+    external fun onDozeEnter()
+
+    /**
+     * Report that the device left Doze
+     *
+     * The client wakes (unless the battery is low) and, if the Doze lasted a
+     * minute or more, its circuits are retired so the first connection
+     * afterwards doesn't try dead ones.
+     * @return 1 if circuits were retired, 0 if they were kept, -1 if not initialized
+     */
+    external fun onDozeExit(): Int
 
     /**
      * Initialize Arti runtime
-     * @param dataDir Directory for Arti state/cache
-     * @return 0 on success, error code otherwise
+     * @param dataDir Directory for Arti state/cache (layout managed natively)
+     * Calling it again for the same data directory without changing any
+     * client setting reuses the bootstrapped client and leaves the lifecycle
+     * state (and a running proxy) as it is.
+     * @return 0 on success, [INIT_REUSED] if the existing client was reused;
+     *         a bootstrap failure is categorized as
+     *         [INIT_ERR_STORAGE] (also when the data directory can't be
+     *         prepared or migrated), [INIT_ERR_CONSENSUS],
+     *         [INIT_ERR_NO_USABLE_GUARDS], [INIT_ERR_CLOCK_SKEW],
+     *         [INIT_ERR_CONFIG], [INIT_ERR_CANCELLED] or else
+     *         [INIT_ERR_BOOTSTRAP]; [INIT_ERR_STATE_LOCKED] if another
+     *         process holds the data directory; [INIT_ERR_NO_LOG_CALLBACK]
+     *         (debug builds only, see [setRequireLogCallback]); -1 and -2 for
+     *         an invalid path or a runtime that couldn't be created
      */
     external fun initialize(dataDir: String): Int
 
+    /**
+     * Initialize Arti runtime with settings given as a JSON object
+     *
+     * The object is merged over settings recorded by earlier setter calls;
+     * keys are those reported by [getEffectiveConfig].
+     *
+     * `first_destination` ("host:port", onion or clearnet) names the
+     * destination the app will connect to first. Once bootstrapped, ready is
+     * held back for up to `prewarm_budget_secs` (default 5) while a circuit to
+     * it is built; after that the prewarm continues in the background.
+     * @param dataDir Directory for Arti state/cache (layout managed natively)
+     * @param configJson JSON object of settings, e.g. {"isolate_by_dest_port":true}
+     * @return -5 if the object is malformed or names an unknown setting
+     *         (nothing is changed), otherwise as [initialize]
+     */
+    external fun initializeWithConfig(dataDir: String, configJson: String): Int
+
+    /**
+     * Get every wrapper setting with its effective value and provenance
+     * @return JSON object keyed by setting; each entry has `value`, `scope`
+     *         ("live" or "client") and `source` ("default", "applied" or
+     *         "pending", with the awaiting value under `pending`); settings
+     *         only partly carried out say how under `effect`
+     */
+    external fun getEffectiveConfig(): String
+
+    /**
+     * Export the effective Tor configuration as an arti config file, to
+     * reproduce the setup in a desktop arti
+     *
+     * Covers what the last [initialize] gave arti (directories, bridges,
+     * transports, circuit timing); wrapper-only settings follow as comments.
+     * Bridge lines are scrubbed unless [redactSecrets] is false and logcat
+     * logging is on ([setLogcatEnabled]); an unredacted export is recorded
+     * in the audit trail.
+     * @param redactSecrets false to export bridge lines in full where allowed
+     * @return TOML document (not a JSON envelope)
+     */
+    external fun exportArtiConfig(redactSecrets: Boolean): String
+
+    /**
+     * Discard every setting that is still pending (recorded since the last
+     * [initialize] but not applied yet), including onion client auth keys
+     * added, replaced or removed with [setOnionClientAuth] since then
+     */
+    external fun resetPendingConfig()
+
+    /**
+     * Estimate how much the next bootstrap will download
+     * @return JSON object with `estimated_bytes`, `basis` ("no_cache",
+     *         "stale_cache", "fresh_cache" or "unknown" before [initialize])
+     *         and `cache_age_secs`
+     */
+    external fun estimateBootstrapCost(): String
+
+    /**
+     * Estimate the bytes Tor has put on the wire since process start
+     *
+     * `user_bytes` and `directory_bytes` in [getMetrics] count payload; this
+     * adds what carrying it cost: 514-byte cells holding at most 498 payload
+     * bytes each, stream setup and flow-control cells, and TLS record
+     * framing. Circuit construction, padding and TCP/IP headers are not
+     * included, so it is a lower bound for data-budget purposes, but never
+     * below the payload counters.
+     * @return Estimated wire bytes, sent and received together
+     */
+    external fun getWireBytes(): Long
+
+    /**
+     * Get relay-to-country index metadata
+     * @return JSON object with `source`, `built_at_epoch_ms`, `entries`,
+     *         `relays_without_country`, `consensus_valid_after_epoch_ms` and `stale`
+     */
+    external fun getGeoDbInfo(): String
+
+    /**
+     * Get the exit diversity of the last hour's streams
+     *
+     * Exits are named by a short fingerprint prefix only. Nothing is recorded
+     * at the minimal retention level.
+     * @return JSON object with `window_secs`, `streams`, `distinct_exits`,
+     *         `entropy_bits`, `score` (0..1, null under two streams),
+     *         `top_exits` (`exit`, `country`, `share`), `restricted_fraction`,
+     *         `usable_exits`, `pool_warning` (text or null) and `retained`
+     */
+    external fun getDiversityReport(): String
+
+    /**
+     * Rebuild the relay-to-country index from the current consensus now
+     *
+     * The index is otherwise rebuilt automatically on every new consensus.
+     * @return Number of relays indexed, -1 if not initialized,
+     *         -2 if no consensus is available yet
+     */
+    external fun refreshGeoIndex(): Int
+
+    /**
+     * Move Tor state and cache to a new data directory without re-bootstrapping
+     *
+     * Stops the proxy and client, moves the data directory (rolling back on a
+     * partial failure) and reinitializes from the new location. The SOCKS
+     * proxy is not restarted; call [startSocksProxy] afterwards.
+     * @param newPath Absolute path of the new data directory (must not exist or be empty)
+     * @return 0 on success, -1 if never initialized, -5 on an invalid
+     *         destination, -6 if the move failed and was rolled back,
+     *         otherwise an [initialize] error code
+     */
+    external fun migrateDataDirectory(newPath: String): Int
+
+    /**
+     * Write Tor's pending persistent state (guards, circuit timing) to disk now
+     *
+     * Tor saves it periodically on its own; call this when the process may be
+     * killed soon, e.g. when the app goes to the background, so the next start
+     * keeps the same guards.
+     * @return 0 on success, -1 if not initialized, -2 if another process holds
+     *         the state lock, -3 if writing failed
+     */
+    external fun flushState(): Int
+
+    /**
+     * Set or rotate the secret SOCKS clients must authenticate with
+     *
+     * Once set, every handshake must use username/password auth: the
+     * password must be [newPassword] and the username must start with
+     * [newUsername] (per-connection options may follow after a `;`). For
+     * [graceSeconds] the previous secret is still accepted, so components
+     * that haven't picked up the new one yet don't fail; established
+     * connections are never affected. Rotating again during a grace window
+     * invalidates the secret it replaces immediately, and the older secret
+     * keeps its (possibly shortened) window.
+     * @param newUsername Non-empty, without `;` or `=`, at most 255 bytes
+     * @param newPassword Non-empty, at most 255 bytes
+     * @param graceSeconds Grace window for the previous secret, 0..3600
+     * @return 0 on success, -1 on invalid arguments
+     */
+    external fun rotateSocksAuth(newUsername: String, newPassword: String, graceSeconds: Int): Int
+
     /**
      * Start SOCKS proxy on specified port
+     *
+     * If the listener is already running on this port with the current
+     * client (e.g. a start repeated from restored service state), it is kept
+     * as is and live connections are untouched; see [getLastStartResult].
      * @param port Port number for SOCKS proxy (e.g., 9050)
-     * @return 0 on success, error code otherwise
+     * @return 0 on success, -4 if another start is still in progress,
+     *         other error codes otherwise
      */
     external fun startSocksProxy(port: Int): Int
 
+    /**
+     * Get the outcome of the last successful [startSocksProxy]
+     * @return JSON `{"port":..,"reconciled":..,"coalesced":..,"at_epoch_ms":..}`,
+     *         where `reconciled` is true if the running listener was kept
+     *         instead of rebinding and `coalesced` if the start took back a
+     *         pending [stop], or `null` before the first start
+     */
+    external fun getLastStartResult(): String
+
+    /**
+     * Get why the SOCKS accept loop last exited
+     *
+     * `reason` is `stopped` ([stop], [shutdown] or a data directory
+     * migration), `restarted` (replaced by a later [startSocksProxy]),
+     * `accept_error` (the listener failed; restarting the proxy may help) or
+     * `fatal` (the task ended some other way). A low-battery pause doesn't
+     * end the loop.
+     * @return JSON `{"reason":..,"detail":..,"at_epoch_ms":..}`, or `null`
+     *         if the loop never exited
+     */
+    external fun getLastShutdownReason(): String
+
+    /**
+     * Test whether an onion service is reachable
+     *
+     * Opens a connection with a timeout and closes it immediately.
+     * @param onionAddr Onion address (e.g. "xyz...abc.onion")
+     * @param port Virtual port on the onion service
+     * @param timeoutSecs Maximum time to wait
+     * @return 0 reachable, 1 descriptor not found, 2 introduction/rendezvous failed,
+     *         3 timed out, 4 other failure; -1 client not initialized,
+     *         -2 runtime not initialized, -3 invalid arguments,
+     *         [ERR_FEATURE_DISABLED] without the onion-service-client feature
+     */
+    external fun testOnionReachable(onionAddr: String, port: Int, timeoutSecs: Int): Int
+
+    /**
+     * Quickly test whether the Tor network is directly reachable
+     *
+     * Races direct connections to a few relays; use it to decide whether to
+     * suggest bridges. Works before [initialize] has bootstrapped.
+     * @param timeoutSecs Maximum time to wait
+     * @return 0 reachable, 1 blocked, 2 unknown (device appears offline);
+     *         -2 runtime not initialized, -3 invalid arguments,
+     *         -4 blocked by strict Tor-only mode
+     */
+    external fun probeDirectReachability(timeoutSecs: Int): Int
+
+    /**
+     * Repeat the reachability probe in the background
+     *
+     * Every [minutes] the probe of [probeDirectReachability] runs again and
+     * changes between open and filtered are reported to the callback set with
+     * [setNetworkConditionCallback] (after two probes in a row agree). Skipped
+     * while the battery is low or strict Tor-only mode is on. Off by default.
+     * @param minutes Minutes between probes, 0..1440 (0 turns the monitor off)
+     * @return 0 on success, -1 on invalid interval
+     */
+    external fun setCensorshipMonitorInterval(minutes: Int): Int
+
+    /**
+     * Set callback for network condition changes found by the background
+     * reachability monitor ([setCensorshipMonitorInterval])
+     * @param callback Callback object with onNetworkConditionChanged(String) method, or null
+     */
+    external fun setNetworkConditionCallback(callback: ArtiNetworkConditionListener?)
+
+    /**
+     * Measure the round-trip time through an exit circuit
+     *
+     * Times a DNS resolve through the exit, after an unmeasured one that
+     * makes sure a circuit exists. Use it for a circuit quality indicator,
+     * e.g. to suggest [newIdentity] when it stays high.
+     * @param timeoutSecs Maximum time to wait for both resolves
+     * @return Latency in milliseconds (at least 1); -1 client not initialized,
+     *         -2 runtime not initialized, -3 invalid arguments, -4 no circuit
+     *         ready for traffic, -5 timed out or the resolve failed
+     */
+    external fun measureCircuitLatency(timeoutSecs: Int): Int
+
+    /**
+     * Run an end-to-end smoke test of the native chain
+     *
+     * Pushes a known pattern through a local echo server, greets the SOCKS
+     * listener, and, when a destination is set with [setSmokeTestDestination]
+     * and the client is ready for traffic, pushes the pattern through a Tor
+     * stream to it and verifies the echo. Legs that can't run are `skipped`
+     * with the reason in `detail`. In a `testing-net` build the Tor leg goes
+     * back to the local echo server instead (`route` is `loopback`, otherwise
+     * `tor`). Blocks; call off the main thread.
+     * @return JSON `{"ok":..,"state":..,"echo":..,"listener":..,"tor_leg":..,
+     *         "route":..,"detail":..,"echo_ms":..,"listener_ms":..,
+     *         "connect_ms":..,"round_trip_ms":..,"bytes_sent":..,
+     *         "bytes_received":..}`
+     */
+    external fun loopbackSmokeTest(): String
+
+    /**
+     * Set the destination the smoke test reaches through Tor
+     *
+     * It must echo back what it receives, e.g. a test onion service running
+     * an echo server.
+     * @param hostPort `host:port`, or empty to skip the Tor leg
+     * @return 0 on success, -1 if not `host:port`
+     */
+    external fun setSmokeTestDestination(hostPort: String): Int
+
     /**
      * Stop Arti and cleanup
+     *
+     * In-flight connections are cancelled, including connects still waiting
+     * on a circuit build, so this doesn't wait on slow circuits. With the
+     * proxy running the stop is debounced: it runs after the window set by
+     * [setLifecycleDebounceMs] unless a matching start takes it back. A stop
+     * during [initialize] cancels its bootstrap.
      * @return 0 on success, error code otherwise
      */
     external fun stop(): Int
+
+    /**
+     * Stop like [stop], returning within a hard deadline
+     *
+     * For constrained paths such as the Android 14 foreground service
+     * timeout. State flushes come first, then the drain that releases the
+     * port; phases that don't fit are skipped, and a flush that overruns its
+     * budget is abandoned. Never waits on a concurrent call: a bootstrap in
+     * flight is cancelled, and the drain is skipped while another call holds
+     * the runtime. See [getLastShutdownReport] for what happened.
+     * @param deadlineMs Time allowed in milliseconds; 0 or less skips every
+     *        optional phase
+     * @return 0 on success, error code otherwise
+     */
+    external fun stopWithDeadline(deadlineMs: Int): Int
+
+    /**
+     * Get what the last stop did with each of its phases
+     * @return JSON object with `deadline_ms` (null for [stop]), `elapsed_ms`,
+     *         `within_deadline`, `at_epoch_ms` and `phases` (`name`,
+     *         `budget_ms`, `elapsed_ms` and `outcome`: "completed", "skipped"
+     *         or "timed_out"), or null before the first stop
+     */
+    external fun getLastShutdownReport(): String
+
+    /**
+     * Close the live connections to a misbehaving host, leaving the rest
+     *
+     * Matched against the target the client asked for, case-insensitively.
+     * Connections still in the SOCKS handshake aren't affected.
+     * @param hostPattern Host where `*` matches any run of characters (e.g.
+     *        `*.example.com`), optionally with a `:port` suffix
+     * @return Number of connections closed, or -1 for an invalid pattern
+     */
+    external fun closeConnectionsToTarget(hostPattern: String): Int
+
+    /**
+     * List the live connections with their flow stalls
+     *
+     * A stall is a write blocked for 2 s or more, blamed on the side that
+     * wasn't consuming: `client` (the app stopped reading) or `tor` (the
+     * circuit's flow control or the exit's leg to the server).
+     * @return JSON array of objects with `id`, `host`, `port`,
+     *         `client_stalls`, `client_stall_ms`, `tor_stalls`,
+     *         `tor_stall_ms`, `stalled_on` (`client`, `tor` or null),
+     *         `class` and `connid` (from the SOCKS username, or null) and
+     *         `exit_platform` (the exit's advertised version, e.g.
+     *         `Tor 0.4.8.12`, or null when unknown or not yet connected)
+     */
+    external fun listConnections(): String
+
+    /**
+     * Stop for good because the process is going away
+     *
+     * From the start of this call, native log lines only go to logcat (if
+     * enabled) and the [setLogFile] file, never to the callbacks, so the
+     * final diagnostics survive a JVM that is tearing down. Callbacks are
+     * released, and so is the data directory lock; a later [initialize]
+     * turns callback logging back on and takes the lock again.
+     * @return Same as [stop]
+     */
+    external fun shutdown(): Int
+
+    /**
+     * Switch the native layer to a frozen fake clock (test-seams builds only)
+     *
+     * Throttles, heartbeats and lifecycle sleeps then only progress when
+     * [testAdvanceClock] is called.
+     */
+    external fun testUseFakeClock()
+
+    /**
+     * Advance the fake clock (test-seams builds only)
+     * @param millis Milliseconds to advance by
+     */
+    external fun testAdvanceClock(millis: Long)
+
+    /**
+     * Make native randomness deterministic (test-seams builds only)
+     * @param seed Seed for the generator
+     */
+    external fun testSeedEntropy(seed: Long)
+
+    /**
+     * Check the stored identity key of an onion service (a `feature_disabled`
+     * error in builds without onion-service-hosting)
+     *
+     * Catches storage damage before the service fails to publish. The key
+     * must parse and be internally consistent; the secret half isn't checked
+     * against the public key.
+     * @param nickname The service's nickname
+     * @return JSON object with `nickname`, `valid`, `problem` (null, or
+     *         "missing", "unreadable", "malformed", "encrypted", "wrong_type",
+     *         "check_mismatch", "public_key_mismatch" or "bad_length") and
+     *         `address` (the onion address of an intact key, else null)
+     */
+    external fun verifyOnionKey(nickname: String): String
+
+    /**
+     * Degrade new connections with synthetic network trouble (chaos builds only)
+     *
+     * Fields (all optional): `connect_delay_ms`, `connect_jitter_ms`,
+     * `connect_failure_rate` (0..1), `connect_failure_replies`
+     * (`general_failure`, `connection_refused`), `latency_ms`, `jitter_ms`,
+     * `stall_probability` (0..1), `stall_ms` and `max_bytes_per_sec`. Applies
+     * to connections accepted afterwards; existing ones keep their profile.
+     * @param profileJson Profile as a JSON object, or "" / "{}" to clear it
+     * @return 0 on success, -1 if the profile is invalid
+     */
+    external fun setChaosProfile(profileJson: String): Int
 }
\ No newline at end of file
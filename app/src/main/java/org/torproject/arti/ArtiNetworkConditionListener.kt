@@ -0,0 +1,19 @@
+package org.torproject.arti
+
+/**
+ * Listener interface for network condition changes.
+ *
+ * Called from the native layer when the background reachability monitor
+ * finds that the Tor network turned from directly reachable to blocked, or
+ * back. Use it to suggest bridges before connections start failing.
+ */
+fun interface ArtiNetworkConditionListener {
+    /**
+     * Called when the network condition changes.
+     *
+     * @param conditionJson JSON object with `condition` ("open" or
+     *        "filtered"), `previous` (null for the first condition found)
+     *        and `at_epoch_ms`
+     */
+    fun onNetworkConditionChanged(conditionJson: String)
+}
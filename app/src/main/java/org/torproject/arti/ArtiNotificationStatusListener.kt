@@ -0,0 +1,17 @@
+package org.torproject.arti
+
+/**
+ * Listener interface for foreground-service notification status changes.
+ *
+ * Called from the native layer at most once every 5 seconds, and only when
+ * the rendered status differs from the previously delivered one.
+ */
+fun interface ArtiNotificationStatusListener {
+    /**
+     * Called when the notification status changes.
+     *
+     * @param statusJson Status in the same format as the `data` of
+     *                   `ArtiNative.getNotificationStatus()` (no envelope)
+     */
+    fun onNotificationStatusChanged(statusJson: String)
+}
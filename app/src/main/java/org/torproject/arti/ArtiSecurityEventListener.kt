@@ -0,0 +1,23 @@
+package org.torproject.arti
+
+/**
+ * Listener interface for high-priority security events.
+ *
+ * Called from the native layer when Arti reports something that may indicate
+ * an active attacker, such as a directory document failing signature
+ * verification or a relay presenting the wrong identity.
+ */
+fun interface ArtiSecurityEventListener {
+    /**
+     * Called when a security event is detected.
+     *
+     * @param eventJson JSON object with `category` ("directory_verification",
+     *        "authority_response", "relay_identity_mismatch" or
+     *        "protocol_violation"), `source` ("connect" for a stream connect
+     *        that failed at the relay handshake or cryptographic layer),
+     *        `detail` and `at_epoch_ms`; a `connect` event also has `stream`
+     *        with the `target_port` and the `socks_reply` its client got
+     *        (0x04, host unreachable, which no other failure gets)
+     */
+    fun onSecurityEvent(eventJson: String)
+}
@@ -0,0 +1,16 @@
+package org.torproject.arti
+
+/**
+ * Listener interface for Arti lifecycle state changes.
+ *
+ * Called from the native layer on every lifecycle transition with one of
+ * the `ArtiNative.STATE_*` constants.
+ */
+fun interface ArtiStateListener {
+    /**
+     * Called when the native lifecycle state changes.
+     *
+     * @param state One of the `ArtiNative.STATE_*` constants
+     */
+    fun onStateChanged(state: Int)
+}
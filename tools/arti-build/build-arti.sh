@@ -19,6 +19,8 @@
 #   ./build-arti.sh              # Build both architectures (debug/emulator)
 #   ./build-arti.sh --release    # Build ARM64 only (production)
 #   ./build-arti.sh --clean      # Remove cloned Arti repo and rebuild
+#   ./build-arti.sh --check-features  # Check every optional feature combination compiles
+#   ./build-arti.sh --test       # Run the wrapper's unit tests on the host
 
 set -euo pipefail
 
@@ -99,6 +101,8 @@ MIN_SDK_VERSION=26
 # Parse arguments
 RELEASE_ONLY=false
 CLEAN_BUILD=false
+CHECK_FEATURES=false
+RUN_TESTS=false
 
 while [[ $# -gt 0 ]]; do
   case "$1" in
@@ -110,12 +114,22 @@ while [[ $# -gt 0 ]]; do
       CLEAN_BUILD=true
       shift
       ;;
+    --check-features)
+      CHECK_FEATURES=true
+      shift
+      ;;
+    --test)
+      RUN_TESTS=true
+      shift
+      ;;
     --help|-h)
-      echo "Usage: $0 [--release] [--clean]"
+      echo "Usage: $0 [--release] [--clean] [--check-features] [--test]"
       echo ""
       echo "Options:"
-      echo "  --release    Build ARM64 only (smaller, for production)"
-      echo "  --clean      Remove cached Arti source and rebuild from scratch"
+      echo "  --release         Build ARM64 only (smaller, for production)"
+      echo "  --clean           Remove cached Arti source and rebuild from scratch"
+      echo "  --check-features  Check every combination of optional features compiles"
+      echo "  --test            Run the wrapper's unit tests on the host"
       echo ""
       exit 0
       ;;
@@ -357,7 +371,7 @@ setup_wrapper() {
   rm -rf "$WRAPPER_DIR"
   mkdir -p "$WRAPPER_DIR/src"
 
-  cp "$SCRIPT_DIR/src/lib.rs" "$WRAPPER_DIR/src/"
+  cp -R "$SCRIPT_DIR/src/." "$WRAPPER_DIR/src/"
   cp "$SCRIPT_DIR/Cargo.toml" "$WRAPPER_DIR/"
 
   print_success "Wrapper files copied to $WRAPPER_DIR"
@@ -505,6 +519,91 @@ verify_jni_symbols() {
   echo ""
 }
 
+check_feature_matrix() {
+  print_header "Checking Optional Feature Combinations"
+
+  local MANIFEST="$ARTI_SOURCE_DIR/arti-android-wrapper/Cargo.toml"
+
+  # src/features.rs is the list getLinkedFeatures reports; each entry must be
+  # a cargo feature, or the report and the build disagree
+  local FEATURES=()
+  mapfile -t FEATURES < <(grep -o 'cfg!(feature = "[a-z-]*")' "$SCRIPT_DIR/src/features.rs" | cut -d'"' -f2)
+  if [ "${#FEATURES[@]}" -eq 0 ]; then
+    print_error "No features found in src/features.rs"
+    return 1
+  fi
+  local FEATURE
+  for FEATURE in "${FEATURES[@]}"; do
+    if ! grep -q "^$FEATURE = " "$SCRIPT_DIR/Cargo.toml"; then
+      print_error "src/features.rs lists $FEATURE, which Cargo.toml doesn't declare"
+      return 1
+    fi
+  done
+
+  local COUNT=$((1 << ${#FEATURES[@]}))
+  local FAILED=()
+  local MASK
+  for ((MASK = 0; MASK < COUNT; MASK++)); do
+    local SELECTED=()
+    local I
+    for I in "${!FEATURES[@]}"; do
+      if (( MASK & (1 << I) )); then
+        SELECTED+=("${FEATURES[$I]}")
+      fi
+    done
+    local LIST
+    LIST="$(IFS=,; echo "${SELECTED[*]}")"
+    print_info "[$((MASK + 1))/$COUNT] features: ${LIST:-(none)}"
+    if ! cargo check --quiet --locked --no-default-features --features "$LIST" --manifest-path "$MANIFEST"; then
+      FAILED+=("${LIST:-(none)}")
+    fi
+  done
+
+  # The ALIASES and RESERVED names of src/features.rs stand for the features
+  # above or for nothing, so each is checked on its own rather than combined
+  local EXTRA=()
+  mapfile -t EXTRA < <(sed -n '/^pub(crate) const \(ALIASES\|RESERVED\): /,/^];/p' "$SCRIPT_DIR/src/features.rs" \
+    | grep -o '^    (\?"[a-z-]*"' | cut -d'"' -f2)
+  for FEATURE in "${EXTRA[@]}"; do
+    if ! grep -q "^$FEATURE = " "$SCRIPT_DIR/Cargo.toml"; then
+      print_error "src/features.rs lists $FEATURE, which Cargo.toml doesn't declare"
+      return 1
+    fi
+    print_info "[alone] features: $FEATURE"
+    if ! cargo check --quiet --locked --no-default-features --features "$FEATURE" --manifest-path "$MANIFEST"; then
+      FAILED+=("$FEATURE")
+    fi
+  done
+
+  if [ "${#FAILED[@]}" -gt 0 ]; then
+    print_error "Failed combinations:"
+    printf '  %s\n' "${FAILED[@]}"
+    return 1
+  fi
+  print_success "All $COUNT feature combinations and ${#EXTRA[@]} capability names compile"
+  echo ""
+}
+
+run_host_tests() {
+  print_header "Running Wrapper Unit Tests"
+
+  local MANIFEST="$ARTI_SOURCE_DIR/arti-android-wrapper/Cargo.toml"
+
+  # Host build: the tests use fakes for the clock, entropy and Tor client,
+  # so nothing here needs a device or the network. Once with the default
+  # features, once with everything (feature-gated modules have tests too)
+  if ! cargo test --locked --manifest-path "$MANIFEST"; then
+    print_error "Unit tests failed (default features)"
+    return 1
+  fi
+  if ! cargo test --locked --all-features --manifest-path "$MANIFEST"; then
+    print_error "Unit tests failed (all features)"
+    return 1
+  fi
+  print_success "Unit tests passed"
+  echo ""
+}
+
 show_summary() {
   print_header "Build Complete!"
 
@@ -549,6 +648,16 @@ main() {
   setup_wrapper
   ensure_wrapper_lockfile
 
+  if [ "$CHECK_FEATURES" = true ]; then
+    check_feature_matrix
+    return
+  fi
+
+  if [ "$RUN_TESTS" = true ]; then
+    run_host_tests
+    return
+  fi
+
   for TARGET in "${TARGETS[@]}"; do
     build_for_target "$TARGET"
   done
@@ -0,0 +1,253 @@
+//! Pinning relay connections to one address family
+//!
+//! Some carrier networks (NAT64/DNS64, broken dual stack) let connections to
+//! relays over one family through while the other hangs for the full connect
+//! timeout first, which makes bootstrap and channel building crawl.
+//! `setRelayAddressFamily` restricts the addresses Arti may connect to
+//! relays on, through its `path_rules.reachable_addrs`:
+//!
+//! | mode | name          | reachable addresses |
+//! |------|---------------|---------------------|
+//! | 0    | auto          | all (Arti default)  |
+//! | 1    | ipv4_only     | `*4:*`              |
+//! | 2    | ipv6_only     | `*6:*`              |
+//! | 3    | prefer_ipv6   | all                 |
+//!
+//! Arti has no family preference to configure (its channel connector tries
+//! a relay's addresses itself), so `prefer_ipv6` is approximated by allowing
+//! both families: IPv6 is never excluded, but nothing makes it tried first.
+//! Initialize logs that, and `getEffectiveConfig` reports it as the
+//! setting's `effect`. Only the first hop is dialed directly, so with bridges the mode doesn't apply: bridges are dialed on
+//! the address their line gives, and a bridge of an excluded family is only
+//! warned about. Like other client settings, the mode applies at the next
+//! initialize.
+
+use arti_client::config::TorClientConfigBuilder;
+use serde_json::Value;
+
+use crate::bridges;
+
+/// Modes (the numbers are part of the JNI contract)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Auto = 0,
+    Ipv4Only = 1,
+    Ipv6Only = 2,
+    PreferIpv6 = 3,
+}
+
+/// The `effect` of `prefer_ipv6` in `getEffectiveConfig`
+const PREFER_IPV6_EFFECT: &str = "both_families_order_not_guaranteed";
+
+impl Mode {
+    pub(crate) fn from_code(code: i64) -> Option<Mode> {
+        match code {
+            0 => Some(Mode::Auto),
+            1 => Some(Mode::Ipv4Only),
+            2 => Some(Mode::Ipv6Only),
+            3 => Some(Mode::PreferIpv6),
+            _ => None,
+        }
+    }
+
+    /// The mode of a `relay_address_family` setting value
+    pub(crate) fn of(value: &Value) -> Mode {
+        value.as_i64().and_then(Mode::from_code).unwrap_or(Mode::Auto)
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Mode::Auto => "auto",
+            Mode::Ipv4Only => "ipv4_only",
+            Mode::Ipv6Only => "ipv6_only",
+            Mode::PreferIpv6 => "prefer_ipv6",
+        }
+    }
+
+    /// Arti's reachable address patterns, if restricted
+    pub(crate) fn reachable_addrs(self) -> Option<&'static str> {
+        match self {
+            Mode::Ipv4Only => Some("*4:*"),
+            Mode::Ipv6Only => Some("*6:*"),
+            Mode::Auto | Mode::PreferIpv6 => None,
+        }
+    }
+
+    /// How the mode is carried out, if only approximately
+    pub(crate) fn effect(self) -> Option<Value> {
+        (self == Mode::PreferIpv6).then(|| Value::from(PREFER_IPV6_EFFECT))
+    }
+
+    /// Whether the mode lets relays be dialed on an address of this family
+    fn allows(self, ipv6: bool) -> bool {
+        match self {
+            Mode::Ipv4Only => !ipv6,
+            Mode::Ipv6Only => ipv6,
+            Mode::Auto | Mode::PreferIpv6 => true,
+        }
+    }
+}
+
+/// Check a `relay_address_family` setting value
+pub(crate) fn check(value: &Value) -> Result<(), String> {
+    match value.as_i64().and_then(Mode::from_code) {
+        Some(_) => Ok(()),
+        None => Err("expected 0 (auto), 1 (IPv4 only), 2 (IPv6 only) or 3 (prefer IPv6)".into()),
+    }
+}
+
+/// Whether a bridge line's address is IPv6 (None: no literal address)
+fn bridge_is_ipv6(line: &str) -> Option<bool> {
+    let mut words = line.split_whitespace();
+    let first = words.next()?;
+    let address = if bridges::transport_of(line) == bridges::DIRECT { first } else { words.next()? };
+    address.parse::<std::net::SocketAddr>().ok().map(|addr| addr.is_ipv6())
+}
+
+/// How many of bridge `lines` have an address of a family `mode` excludes
+pub(crate) fn excluded_bridges(mode: Mode, lines: &[String]) -> usize {
+    lines.iter().filter(|line| bridge_is_ipv6(line).is_some_and(|ipv6| !mode.allows(ipv6))).count()
+}
+
+/// Configure `builder` for `mode` when bootstrapping with bridge `group`
+pub(crate) fn apply(builder: &mut TorClientConfigBuilder, mode: Mode, group: &[String]) -> anyhow::Result<()> {
+    if !group.is_empty() {
+        let excluded = excluded_bridges(mode, group);
+        if excluded > 0 {
+            log_info!(
+                "{} bridge(s) use an address family {} excludes; bridges are dialed as configured",
+                excluded,
+                mode.as_str()
+            );
+        }
+        return Ok(());
+    }
+    if mode == Mode::PreferIpv6 {
+        log_info!("prefer_ipv6: Arti has no address family preference; relays are connected on either family, IPv6 not necessarily first");
+    }
+    if let Some(pattern) = mode.reachable_addrs() {
+        builder.path_rules().set_reachable_addrs(vec![pattern.parse()?]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::testing::{self, TempDir};
+    use serde_json::json;
+
+    const IPV4_BRIDGE: &str = "192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567";
+    const IPV6_BRIDGE: &str = "[2001:db8::1]:443 0123456789ABCDEF0123456789ABCDEF01234567";
+    const IPV6_OBFS4: &str = "obfs4 [2001:db8::2]:9001 0123456789ABCDEF0123456789ABCDEF01234567 cert=AAAA iat-mode=0";
+
+    fn builder(temp: &TempDir) -> TorClientConfigBuilder {
+        TorClientConfigBuilder::from_directories(temp.path().join("state"), temp.path().join("cache"))
+    }
+
+    #[test]
+    fn modes_map_onto_reachable_addrs() {
+        let temp = TempDir::new();
+        let table: &[(Mode, &[&str], Option<&str>)] = &[
+            (Mode::Auto, &[], None),
+            (Mode::Ipv4Only, &[], Some("*4:*")),
+            (Mode::Ipv6Only, &[], Some("*6:*")),
+            // Both families, as with auto
+            (Mode::PreferIpv6, &[], None),
+            // Bridges are dialed as configured: no restriction at all
+            (Mode::Ipv4Only, &[IPV6_BRIDGE], None),
+            (Mode::Ipv6Only, &[IPV4_BRIDGE], None),
+        ];
+        for &(mode, bridges, expected) in table {
+            let group: Vec<String> = bridges.iter().map(|line| line.to_string()).collect();
+            let mut configured = builder(&temp);
+            apply(&mut configured, mode, &group).unwrap();
+
+            let mut wanted = builder(&temp);
+            if let Some(pattern) = expected {
+                wanted.path_rules().set_reachable_addrs(vec![pattern.parse().unwrap()]);
+            }
+            assert_eq!(configured.build().unwrap(), wanted.build().unwrap(), "{:?} with {:?}", mode, bridges);
+            if group.is_empty() {
+                assert_eq!(mode.reachable_addrs(), expected, "{:?}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn bridges_of_an_excluded_family_are_warned_about() {
+        let _serial = testing::serial();
+        let lines: Vec<String> =
+            [IPV4_BRIDGE, IPV6_BRIDGE, IPV6_OBFS4, "snowflake"].iter().map(|line| line.to_string()).collect();
+        let table = [(Mode::Auto, 0), (Mode::Ipv4Only, 2), (Mode::Ipv6Only, 1), (Mode::PreferIpv6, 0)];
+        for (mode, excluded) in table {
+            assert_eq!(excluded_bridges(mode, &lines), excluded, "{:?}", mode);
+
+            crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+            apply(&mut TorClientConfigBuilder::default(), mode, &lines).unwrap();
+            let warning = format!("{} bridge(s) use an address family {} excludes", excluded, mode.as_str());
+            let warned = crate::tests::SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains(&warning));
+            assert_eq!(warned, excluded > 0, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn unknown_modes_are_refused() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        config::set("relay_address_family", json!(Mode::Ipv6Only as i64)).unwrap();
+
+        let table: &[(Value, Option<&str>)] = &[
+            (json!(0), None),
+            (json!(1), None),
+            (json!(2), None),
+            (json!(3), None),
+            (json!(4), Some("expected 0")),
+            (json!(-1), Some("expected 0")),
+            (json!("ipv4_only"), Some("expected 0")),
+            (json!(1.5), Some("expected 0")),
+        ];
+        for (value, refusal) in table {
+            assert_eq!(Mode::from_code(value.as_i64().unwrap_or(-1)).is_some(), refusal.is_none(), "{}", value);
+            match (check(value), refusal) {
+                (Ok(()), None) => {}
+                (Err(error), Some(reason)) => {
+                    assert!(error.contains(reason), "{}: {}", value, error);
+                    // A refused mode leaves the setting as it was
+                    assert!(config::set("relay_address_family", value.clone()).is_err(), "{}", value);
+                    assert_eq!(config::upcoming("relay_address_family"), json!(2), "{}", value);
+                }
+                (result, _) => panic!("{}: {:?}", value, result),
+            }
+        }
+        // Anything unreadable as a mode connects like auto
+        assert_eq!(Mode::of(&json!(4)), Mode::Auto);
+        assert_eq!(Mode::of(&json!(null)), Mode::Auto);
+    }
+
+    #[test]
+    fn prefer_ipv6_is_reported_as_approximated() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let temp = TempDir::new();
+        let effect = || {
+            let effective: Value = serde_json::from_str(&config::effective_json()).unwrap();
+            effective["relay_address_family"]["effect"].clone()
+        };
+
+        for mode in [Mode::Auto, Mode::Ipv4Only, Mode::Ipv6Only] {
+            assert_eq!(mode.effect(), None, "{:?}", mode);
+        }
+        config::set("relay_address_family", json!(Mode::PreferIpv6 as i64)).unwrap();
+        // Not before the next initialize
+        assert_eq!(effect(), Value::Null);
+        config::commit_pending();
+        assert_eq!(effect(), json!("both_families_order_not_guaranteed"));
+
+        crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+        apply(&mut builder(&temp), Mode::PreferIpv6, &[]).unwrap();
+        let logged = crate::tests::SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains("IPv6 not necessarily first"));
+        assert!(logged);
+    }
+}
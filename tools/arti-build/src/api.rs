@@ -0,0 +1,464 @@
+//! Envelope of every JSON-returning JNI function, and its self-description
+//!
+//! Every function that returns JSON wraps its payload in one envelope, so the
+//! Kotlin side parses one shape and can detect it by its version field:
+//!
+//! ```text
+//! {"v":1,"ok":true,"data":<payload>}
+//! {"v":1,"ok":false,"error":{"kind":"<kind>","message":"<text>"}}
+//! ```
+//!
+//! `v` changes only when the envelope itself changes. Payloads may gain
+//! fields without a version change; fields are never removed or retyped
+//! without one.
+//!
+//! `describeApi` returns the envelope and, for each function, the schema of
+//! its payload (a JSON Schema subset) from the `PAYLOADS` table below. A new
+//! JSON-returning function must add its row, naming every field it always
+//! emits, so Kotlin can check payloads against the description.
+
+use serde_json::{json, Value};
+
+use crate::json_string;
+
+/// Envelope version
+pub(crate) const VERSION: u32 = 1;
+
+/// A successful response carrying `data` (already JSON)
+pub(crate) fn ok(data: &str) -> String {
+    format!("{{\"v\":{},\"ok\":true,\"data\":{}}}", VERSION, data)
+}
+
+/// A failed response
+pub(crate) fn error(kind: &str, message: &str) -> String {
+    format!(
+        "{{\"v\":{},\"ok\":false,\"error\":{{\"kind\":{},\"message\":{}}}}}",
+        VERSION,
+        json_string(kind),
+        json_string(message)
+    )
+}
+
+/// Shape of a payload
+enum Shape {
+    Object(&'static [&'static str]),
+    ObjectOrNull(&'static [&'static str]),
+    ArrayOfObjects(&'static [&'static str]),
+    ArrayOfStrings,
+    /// Object keyed by names only known at runtime, with object values
+    Map,
+}
+
+impl Shape {
+    fn schema(&self) -> Value {
+        match self {
+            Shape::Object(fields) => json!({"type": "object", "required": fields}),
+            Shape::ObjectOrNull(fields) => json!({"type": ["object", "null"], "required": fields}),
+            Shape::ArrayOfObjects(fields) => json!({"type": "array", "items": {"type": "object", "required": fields}}),
+            Shape::ArrayOfStrings => json!({"type": "array", "items": {"type": "string"}}),
+            Shape::Map => json!({"type": "object", "additionalProperties": {"type": "object"}}),
+        }
+    }
+}
+
+const QUALITY_FIELDS: &[&str] = &[
+    "score",
+    "success_rate",
+    "attempts",
+    "median_connect_ms",
+    "median_first_byte_ms",
+    "throughput_bytes_per_sec",
+    "last_used_epoch_ms",
+];
+
+const TOP_FIELDS: &[&str] = &[
+    "key",
+    "score",
+    "success_rate",
+    "attempts",
+    "median_connect_ms",
+    "median_first_byte_ms",
+    "throughput_bytes_per_sec",
+    "last_used_epoch_ms",
+];
+
+/// Payload of every JSON-returning function
+static PAYLOADS: &[(&str, Shape)] = &[
+    ("describeApi", Shape::Object(&["version", "envelope", "functions"])),
+    (
+        "getLinkedFeatures",
+        Shape::Object(&["bridges", "pluggable_transports", "onion_service_client", "onion_service_hosting", "vanguards", "metrics_endpoint"]),
+    ),
+    ("getCallbackRegistrations", Shape::Object(&["live", "releases", "kinds"])),
+    // Only `state` at the minimal retention level
+    ("getNotificationStatus", Shape::Object(&["state"])),
+    (
+        "getBootstrapStatus",
+        Shape::Object(&["percent", "ready_for_traffic", "blocked", "description", "directory_fetch_attempts", "degraded", "stale"]),
+    ),
+    (
+        "getBootstrapStatusFresh",
+        Shape::Object(&["percent", "ready_for_traffic", "blocked", "description", "directory_fetch_attempts", "degraded", "stale"]),
+    ),
+    (
+        "getMetrics",
+        Shape::Object(&[
+            "strict_tor_only",
+            "strict_mode_violations",
+            "bytes_relayed",
+            "buffer_bytes_in_use",
+            "security_events",
+            "client_stalled_closes",
+            "connects_established",
+            "connect_ms_total",
+            "connect_failures",
+            "stream_confirmations",
+            "stream_confirm_ms_total",
+            "stream_confirm_failures",
+            "onion_upgrades",
+            "ipv6_targets_refused",
+            "policy_timeouts",
+            "no_usable_guards",
+            "entry_suspicions",
+            "stale_query_answers",
+            "query_cache_hits",
+            "query_cache_misses",
+            "query_cache_coalesced",
+            "log_file_dropped_lines",
+            "log_callback_dropped_lines",
+            "network_token_age_secs",
+            "network_token_stale",
+            "client_stalls",
+            "client_stall_ms",
+            "tor_stalls",
+            "tor_stall_ms",
+            "user_bytes",
+            "directory_bytes",
+            "directory_bytes_by_month",
+        ]),
+    ),
+    (
+        "getStorageStats",
+        Shape::Object(&[
+            "state",
+            "lost_since_epoch_ms",
+            "probes",
+            "next_probe_in_secs",
+            "suspended_writes",
+            "free_bytes",
+            "total_bytes",
+        ]),
+    ),
+    ("getBypassEstimate", Shape::Object(&["status", "source", "bypass_fraction", "confidence", "caveats"])),
+    ("getDestinationQuality", Shape::ObjectOrNull(QUALITY_FIELDS)),
+    ("getTopDestinations", Shape::ArrayOfObjects(TOP_FIELDS)),
+    ("getLastError", Shape::ObjectOrNull(&["kind", "message", "at_epoch_ms", "debug_context"])),
+    ("getAuditLog", Shape::ArrayOfObjects(&["seq", "at_epoch_ms", "event", "detail", "prev", "mac"])),
+    ("verifyAuditLog", Shape::Object(&["ok", "records", "first_seq", "last_seq", "problems"])),
+    ("getRecentLogs", Shape::ArrayOfStrings),
+    (
+        "exportDiagnostics",
+        Shape::Object(&["version", "state", "metrics", "last_error", "recent_logs", "metrics_history", "bootstrap_history"]),
+    ),
+    (
+        "getBootstrapHistory",
+        Shape::ArrayOfObjects(&["at_epoch_ms", "trigger", "config_fingerprint", "outcome", "duration_ms", "entry"]),
+    ),
+    (
+        "getMetricsHistory",
+        Shape::ArrayOfObjects(&["at_epoch_ms", "state", "bootstrap_percent", "ready_for_traffic", "battery_low", "metrics"]),
+    ),
+    (
+        "getReliabilityStats",
+        Shape::Object(&[
+            "days",
+            "from_epoch_ms",
+            "covered_ms",
+            "ready_ms",
+            "degraded_ms",
+            "stopped_ms",
+            "ready_percent",
+            "sessions",
+            "bootstraps",
+            "avg_bootstrap_ms",
+            "recoveries",
+            "clock_jumps",
+        ]),
+    ),
+    ("getActiveBridge", Shape::ObjectOrNull(&["transport", "bridge", "candidates"])),
+    ("getBridgeHistory", Shape::Object(&["bridges"])),
+    (
+        "loopbackSmokeTest",
+        Shape::Object(&[
+            "ok",
+            "state",
+            "echo",
+            "listener",
+            "tor_leg",
+            "route",
+            "detail",
+            "echo_ms",
+            "listener_ms",
+            "connect_ms",
+            "round_trip_ms",
+            "bytes_sent",
+            "bytes_received",
+        ]),
+    ),
+    ("getLastShutdownReason", Shape::ObjectOrNull(&["reason", "detail", "at_epoch_ms"])),
+    ("getProxyCapabilities", Shape::Object(&["version", "bitmap", "capabilities", "bits"])),
+    ("getLastStartResult", Shape::ObjectOrNull(&["port", "reconciled", "coalesced", "at_epoch_ms"])),
+    ("getLastShutdownReport", Shape::ObjectOrNull(&["deadline_ms", "elapsed_ms", "within_deadline", "phases", "at_epoch_ms"])),
+    ("getStateLockHolder", Shape::Object(&["held", "holder"])),
+    ("verifyOnionKey", Shape::Object(&["nickname", "valid", "problem", "address"])),
+    ("getMemoryStats", Shape::Object(&["budget_bytes", "total_bytes", "trims", "relay_buffer_bytes", "caches"])),
+    ("getEffectiveConfig", Shape::Map),
+    ("estimateBootstrapCost", Shape::Object(&["estimated_bytes", "basis", "cache_age_secs"])),
+    (
+        "getGeoDbInfo",
+        Shape::Object(&[
+            "source",
+            "built_at_epoch_ms",
+            "entries",
+            "relays_without_country",
+            "consensus_valid_after_epoch_ms",
+            "stale",
+        ]),
+    ),
+    (
+        "getDiversityReport",
+        Shape::Object(&[
+            "window_secs",
+            "streams",
+            "distinct_exits",
+            "entropy_bits",
+            "score",
+            "top_exits",
+            "restricted_fraction",
+            "usable_exits",
+            "pool_warning",
+            "retained",
+        ]),
+    ),
+    ("listIsolationGroups", Shape::Object(&["groups", "total_groups", "truncated"])),
+    (
+        "listConnections",
+        Shape::ArrayOfObjects(&[
+            "id",
+            "host",
+            "port",
+            "client_stalls",
+            "client_stall_ms",
+            "tor_stalls",
+            "tor_stall_ms",
+            "stalled_on",
+            "exit_platform",
+        ]),
+    ),
+];
+
+/// The envelope and every payload schema, as the `data` of `describeApi`
+pub(crate) fn describe_json() -> String {
+    let functions: serde_json::Map<String, Value> =
+        PAYLOADS.iter().map(|(name, shape)| (name.to_string(), shape.schema())).collect();
+    json!({
+        "version": VERSION,
+        "envelope": {
+            "ok": {"type": "object", "required": ["v", "ok", "data"]},
+            "error": {
+                "type": "object",
+                "required": ["v", "ok", "error"],
+                "properties": {"error": {"type": "object", "required": ["kind", "message"]}},
+            },
+        },
+        "functions": functions,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{
+        audit, bootstrap_history, bridge_health, bridges, bypass, caches, callbacks, capabilities, config, connections,
+        diagnostics, diversity, features, geo, isolation_groups, listener_exit, metrics_history, notification, quality,
+        recent_logs, reconcile, reliability, shutdown, smoke, state_lock, storage, testing, traffic,
+    };
+
+    /// Check `value` against the subset of JSON Schema `describeApi` emits
+    fn validate(schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        let type_name = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        let allowed = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            other => return Err(format!("{}: unsupported type {}", at, other)),
+        };
+        if !allowed.contains(&type_name) {
+            return Err(format!("{}: {} is not one of {:?}", at, type_name, allowed));
+        }
+        if let Value::Object(object) = value {
+            for field in schema["required"].as_array().into_iter().flatten() {
+                let field = field.as_str().unwrap();
+                if !object.contains_key(field) {
+                    return Err(format!("{}: missing {}", at, field));
+                }
+            }
+            if let Some(properties) = schema["properties"].as_object() {
+                for (field, schema) in properties {
+                    if let Some(value) = object.get(field) {
+                        validate(schema, value, &format!("{}.{}", at, field))?;
+                    }
+                }
+            }
+            if let Some(schema) = schema.get("additionalProperties") {
+                for (field, value) in object {
+                    validate(schema, value, &format!("{}.{}", at, field))?;
+                }
+            }
+        }
+        if let Value::Array(items) = value {
+            for (i, item) in items.iter().enumerate() {
+                validate(&schema["items"], item, &format!("{}[{}]", at, i))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Payloads the wrapper returns for `name` as its JNI function would
+    /// build them: the idle state, then state that fills optional objects
+    fn payloads(name: &str) -> Vec<String> {
+        match name {
+            "describeApi" => vec![describe_json()],
+            "getLinkedFeatures" => vec![features::linked_json()],
+            "getCallbackRegistrations" => vec![callbacks::registrations_json()],
+            "getNotificationStatus" => vec![notification::render()],
+            "getBootstrapStatus" => vec![crate::bootstrap_status_json(false)],
+            "getBootstrapStatusFresh" => vec![crate::bootstrap_status_json(true)],
+            "getMetrics" => vec![crate::METRICS.to_json()],
+            "getStorageStats" => vec![storage::stats_json()],
+            "getBypassEstimate" => vec![bypass::estimate_json()],
+            "getDestinationQuality" => vec![quality::destination_json("unseen.example", 443)],
+            "getTopDestinations" => vec![quality::top_json(10)],
+            "getLastError" => {
+                let idle = diagnostics::last_error_json();
+                diagnostics::record_failure("api_test", "schema check".to_string());
+                vec![idle, diagnostics::last_error_json()]
+            }
+            "getAuditLog" => vec![audit::tail_json(10)],
+            "verifyAuditLog" => vec![audit::verify_json()],
+            "getRecentLogs" => {
+                recent_logs::push("api schema check");
+                vec![recent_logs::to_json()]
+            }
+            "exportDiagnostics" => vec![crate::diagnostics_json()],
+            "getBootstrapHistory" => vec![bootstrap_history::history_json()],
+            "getMetricsHistory" => vec![metrics_history::history_json(10)],
+            "getReliabilityStats" => vec![reliability::stats_json(1), reliability::stats_json(reliability::MAX_DAYS)],
+            "getActiveBridge" => vec![bridges::active_json()],
+            "getBridgeHistory" => vec![bridge_health::history_json()],
+            "loopbackSmokeTest" => vec![testing::runtime().block_on(smoke::run("stopped", None, false))],
+            "getLastShutdownReason" => {
+                let idle = listener_exit::last_json();
+                let run = listener_exit::Run::start();
+                run.end(listener_exit::Reason::Stopped, "api schema check".to_string());
+                vec![idle, listener_exit::last_json()]
+            }
+            "getProxyCapabilities" => vec![capabilities::to_json()],
+            "getLastStartResult" => {
+                let idle = reconcile::last_start_json();
+                reconcile::record_start(9150, false, false);
+                vec![idle, reconcile::last_start_json()]
+            }
+            "getLastShutdownReport" => {
+                let idle = shutdown::report_json();
+                // Every budgeted phase is skipped at a zero deadline
+                shutdown::run(Instant::now(), Some(Duration::ZERO));
+                vec![idle, shutdown::report_json()]
+            }
+            "getStateLockHolder" => vec![state_lock::holder_json()],
+            #[cfg(feature = "onion-service-hosting")]
+            "verifyOnionKey" => {
+                let dir = testing::TempDir::new();
+                vec![crate::onion_key::verify_json(dir.path(), "absent")]
+            }
+            #[cfg(not(feature = "onion-service-hosting"))]
+            "verifyOnionKey" => vec![],
+            "getMemoryStats" => vec![caches::stats_json()],
+            "getEffectiveConfig" => vec![config::effective_json()],
+            "estimateBootstrapCost" => {
+                let dir = testing::TempDir::new();
+                vec![traffic::estimate_json(None), traffic::estimate_json(Some(dir.path()))]
+            }
+            "getGeoDbInfo" => vec![geo::info_json()],
+            "getDiversityReport" => vec![diversity::report_json()],
+            "listIsolationGroups" => vec![isolation_groups::list_json()],
+            "listConnections" => vec![connections::list_json()],
+            other => panic!("no payload producer for {}; add one here", other),
+        }
+    }
+
+    #[test]
+    fn envelopes_carry_the_version_and_escape_their_text() {
+        let described: Value = serde_json::from_str(&describe_json()).unwrap();
+        let envelope = &described["envelope"];
+
+        let ok: Value = serde_json::from_str(&super::ok(r#"{"a":[1,"two"]}"#)).unwrap();
+        validate(&envelope["ok"], &ok, "ok").unwrap();
+        assert_eq!(ok, json!({"v": VERSION, "ok": true, "data": {"a": [1, "two"]}}));
+
+        let message = "quote \" backslash \\ newline \n tab \t bell \u{7}";
+        let error: Value = serde_json::from_str(&super::error("invalid_argument", message)).unwrap();
+        validate(&envelope["error"], &error, "error").unwrap();
+        assert_eq!(error, json!({"v": VERSION, "ok": false, "error": {"kind": "invalid_argument", "message": message}}));
+    }
+
+    #[test]
+    fn every_payload_matches_its_described_schema() {
+        let _serial = testing::serial();
+        let described: Value = serde_json::from_str(&describe_json()).unwrap();
+        assert_eq!(described["version"], json!(VERSION));
+        let functions = described["functions"].as_object().unwrap();
+        assert_eq!(functions.len(), PAYLOADS.len(), "duplicate rows in PAYLOADS");
+
+        for (name, schema) in functions {
+            for payload in payloads(name) {
+                let data: Value = serde_json::from_str(&payload)
+                    .unwrap_or_else(|e| panic!("{} returned invalid JSON ({}): {}", name, e, payload));
+                // What Kotlin receives, through the envelope
+                let enveloped: Value = serde_json::from_str(&super::ok(&payload)).unwrap();
+                assert_eq!(enveloped["data"], data, "{}", name);
+                if let Err(problem) = validate(schema, &data, name) {
+                    panic!("{} drifted from its schema: {}\n{}", name, problem, payload);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_function_returning_an_envelope_is_described() {
+        let source = include_str!("lib.rs");
+        let described: HashSet<&str> = PAYLOADS.iter().map(|(name, _)| *name).collect();
+        let mut enveloped = HashSet::new();
+        for function in source.split(concat!("fn Java_org_torproject_arti_", "ArtiNative_")).skip(1) {
+            let name = &function[..function.find('(').unwrap()];
+            if function.contains("api::ok(") {
+                enveloped.insert(name);
+            }
+        }
+        assert!(enveloped.len() > 30, "found only {:?}", enveloped);
+        let undescribed: Vec<_> = enveloped.difference(&described).collect();
+        assert!(undescribed.is_empty(), "missing from PAYLOADS: {:?}", undescribed);
+        let stale: Vec<_> = described.difference(&enveloped).collect();
+        assert!(stale.is_empty(), "in PAYLOADS but never returned: {:?}", stale);
+    }
+}
@@ -0,0 +1,304 @@
+//! The effective Tor configuration as an arti config file
+//!
+//! To reproduce a user's setup in a desktop `arti`, `exportArtiConfig`
+//! renders what the last initialize handed to `TorClientConfigBuilder` in
+//! arti's own TOML format:
+//!
+//! - `[storage]`: the state and cache directories, as literal paths like
+//!   the wrapper gives them (arti would expand `$` variables in a plain
+//!   string), and the C Tor client keystore holding the onion client auth
+//!   keys (onion_auth.rs);
+//! - `[bridges]`: the bridge lines and pluggable transport binaries
+//!   (bridges.rs). All configured bridges are listed; the staged bootstrap
+//!   tries them in transport priority order, which arti doesn't have;
+//! - `[circuit_timing]`: `max_dirtiness`;
+//! - `[path_rules]`: `reachable_addrs` for the relay address family, left out
+//!   with bridges as the wrapper does (address_family.rs), and
+//!   `long_lived_ports` for required Stable exits (exits.rs);
+//! - `[preemptive_circuits]`: the target port hints (port_hints.rs);
+//! - `[override_net_params]`: the build timeout quantile of the fast exit
+//!   preference (exits.rs);
+//! - `[download_schedule]`: the retry schedules set (dir_retry.rs).
+//!
+//! Settings the wrapper applies itself (the SOCKS listener, isolation,
+//! required exit flags, timeouts) follow in a comment block under their
+//! wrapper names, for reference only.
+//!
+//! Bridge lines carry addresses, fingerprints and transport secrets, so they
+//! are exported as `<transport> [scrubbed]`, which arti will not parse, unless
+//! redaction is turned off while logcat logging is on (the default only in
+//! debug builds; see `setLogcatEnabled`), when the logs may carry them anyway.
+//! The client auth keys themselves are never exported: the keystore entry
+//! only names their directory.
+
+use std::fmt::Write as _;
+use std::sync::atomic::Ordering;
+
+use serde_json::Value;
+
+use crate::layout::{self, Purpose};
+use crate::{address_family, bridges, config, dir_retry, exits, json_string, port_hints};
+
+/// Wrapper settings listed in the comment block
+const WRAPPER_SETTINGS: &[&str] = &[
+    "transport_priority",
+    "required_exit_flags",
+    "strict_tor_only",
+    "onion_only",
+    "require_domain_targets",
+    "disable_ipv6_targets",
+    "isolate_by_dest_port",
+    "confirm_stream_writable",
+    "client_stall_timeout_secs",
+    "prewarm_budget_secs",
+];
+
+/// Whether bridge lines may be exported in full
+pub(crate) fn unredacted_allowed() -> bool {
+    crate::LOGCAT_ENABLED.load(Ordering::Relaxed)
+}
+
+fn toml_array(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|item| json_string(item)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// Render the effective configuration; bridge lines in full unless `redact`
+pub(crate) fn render(redact: bool) -> String {
+    let redact = redact || !unredacted_allowed();
+    let mut out = String::new();
+    let _ = writeln!(out, "# arti configuration exported from arti-android-wrapper {}", env!("CARGO_PKG_VERSION"));
+    if redact {
+        let _ = writeln!(out, "# Bridge lines are scrubbed; fill them in before use.");
+    }
+
+    if let Some(data_dir) = crate::DATA_DIR.lock().unwrap().clone() {
+        let _ = writeln!(out, "\n[storage]");
+        let state_dir = layout::path(&data_dir, Purpose::ArtiState);
+        let cache_dir = layout::path(&data_dir, Purpose::ArtiCache);
+        let _ = writeln!(out, "state_dir = {{ literal = {} }}", json_string(&state_dir.to_string_lossy()));
+        let _ = writeln!(out, "cache_dir = {{ literal = {} }}", json_string(&cache_dir.to_string_lossy()));
+        if !crate::onion_auth::applied_ids().is_empty() {
+            let auth_dir = layout::path(&data_dir, Purpose::OnionClientAuth);
+            let _ = writeln!(out, "\n[[storage.keystore.ctor.clients]]");
+            let _ = writeln!(out, "id = \"wrapper-client-auth\"");
+            let _ = writeln!(out, "path = {}", json_string(&auth_dir.to_string_lossy()));
+        }
+    }
+
+    let lines = config::strings(&config::applied("bridges"));
+    if !lines.is_empty() {
+        let shown: Vec<String> = lines
+            .iter()
+            .map(|line| match bridges::transport_of(line) {
+                _ if !redact => line.clone(),
+                bridges::DIRECT => "[scrubbed]".to_string(),
+                transport => format!("{} [scrubbed]", transport),
+            })
+            .collect();
+        let _ = writeln!(out, "\n[bridges]");
+        let _ = writeln!(out, "enabled = true");
+        let _ = writeln!(out, "bridges = {}", toml_array(&shown));
+
+        let binaries = config::applied("pt_binaries").as_object().cloned().unwrap_or_default();
+        for (name, path) in &binaries {
+            if !lines.iter().any(|line| bridges::transport_of(line) == name) {
+                continue;
+            }
+            let _ = writeln!(out, "\n[[bridges.transports]]");
+            let _ = writeln!(out, "protocols = {}", toml_array(std::slice::from_ref(name)));
+            let _ = writeln!(out, "path = {}", json_string(path.as_str().unwrap_or_default()));
+            let _ = writeln!(out, "run_on_startup = false");
+        }
+    }
+
+    if let Some(secs) = config::applied("max_circuit_dirtiness_secs").as_u64() {
+        let _ = writeln!(out, "\n[circuit_timing]");
+        let _ = writeln!(out, "max_dirtiness = \"{}s\"", secs);
+    }
+
+    let hinted = port_hints::ports(&config::applied("target_port_hints"));
+    if !hinted.is_empty() {
+        let hinted: Vec<String> = hinted.iter().map(u16::to_string).collect();
+        let _ = writeln!(out, "\n[preemptive_circuits]");
+        let _ = writeln!(out, "initial_predicted_ports = [{}]", hinted.join(", "));
+    }
+
+    let params = exits::net_params(config::applied("prefer_fast_exits").as_bool().unwrap_or(false));
+    if !params.is_empty() {
+        let _ = writeln!(out, "\n[override_net_params]");
+        for (name, value) in params {
+            let _ = writeln!(out, "{} = {}", name, value);
+        }
+    }
+
+    for (name, fields) in dir_retry::schedules(&config::applied("directory_retry")) {
+        let _ = writeln!(out, "\n[download_schedule.retry_{}]", name);
+        for (field, value) in &fields {
+            match field.as_str() {
+                "initial_delay_ms" => {
+                    let _ = writeln!(out, "initial_delay = \"{}ms\"", value);
+                }
+                _ => {
+                    let _ = writeln!(out, "{} = {}", field, value);
+                }
+            }
+        }
+    }
+
+    let exit_flags = config::strings(&config::applied("required_exit_flags"));
+    let long_lived = exits::long_lived_ports(&exit_flags, &port_hints::ports(&config::applied("target_port_hints")));
+    // Not applied while bootstrapping with bridges (see address_family.rs)
+    let reachable = if lines.is_empty() {
+        address_family::Mode::of(&config::applied("relay_address_family")).reachable_addrs()
+    } else {
+        None
+    };
+    if long_lived.is_some() || reachable.is_some() {
+        let _ = writeln!(out, "\n[path_rules]");
+    }
+    if let Some(pattern) = reachable {
+        let _ = writeln!(out, "reachable_addrs = [{}]", json_string(pattern));
+    }
+    if let Some(ports) = long_lived {
+        let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+        let _ = writeln!(out, "# Stable exits are required for the hinted target ports");
+        let _ = writeln!(out, "long_lived_ports = [{}]", ports.join(", "));
+    }
+
+    let _ = writeln!(out, "\n# --- Managed by the wrapper, not by arti (reference only) ---");
+    let port = crate::SOCKS_PORT.load(Ordering::SeqCst);
+    if port > 0 {
+        let _ = writeln!(out, "# socks_port = {}", port);
+    }
+    for name in WRAPPER_SETTINGS {
+        let value = config::applied(name);
+        if value != Value::Null {
+            let _ = writeln!(out, "# {} = {}", name, value);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use arti_client::config::{TorClientConfig, TorClientConfigBuilder};
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    const OBFS4: &str = "obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AAAA iat-mode=0";
+    const DIRECT: &str = "192.0.2.2:9001 1123456789ABCDEF0123456789ABCDEF01234567";
+
+    /// Read an export the way a desktop arti reads its config file
+    fn reimport(exported: &str) -> TorClientConfig {
+        let builder: TorClientConfigBuilder = toml::from_str(exported).unwrap_or_else(|e| panic!("{}\n{}", e, exported));
+        builder.build().unwrap()
+    }
+
+    /// Puts back the data directory and the logcat switch
+    struct Restore(Option<std::path::PathBuf>, bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            *crate::DATA_DIR.lock().unwrap() = self.0.take();
+            crate::LOGCAT_ENABLED.store(self.1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn exports_read_back_as_the_live_config() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let temp = TempDir::new();
+        let _restore = Restore(
+            crate::DATA_DIR.lock().unwrap().replace(temp.path().join("data $HOME")),
+            crate::LOGCAT_ENABLED.swap(true, Ordering::Relaxed),
+        );
+
+        let mut cases = vec![
+            ("defaults", vec![]),
+            ("dirtiness", vec![("max_circuit_dirtiness_secs", json!(120))]),
+            ("port hints", vec![("target_port_hints", json!([443, 5222]))]),
+            ("stable exits", vec![("target_port_hints", json!([443, 5222])), ("required_exit_flags", json!(["Stable"]))]),
+            ("ipv4 relays", vec![("relay_address_family", json!(1))]),
+            ("fast exits", vec![("prefer_fast_exits", json!(true))]),
+            ("ipv6 relays", vec![("relay_address_family", json!(2))]),
+            (
+                "directory retry",
+                vec![(
+                    "directory_retry",
+                    json!({"bootstrap": {"attempts": 4}, "consensus": {"initial_delay_ms": 1500, "parallelism": 2}}),
+                )],
+            ),
+        ];
+        if cfg!(feature = "pluggable-transports") {
+            cases.push((
+                "bridges",
+                vec![
+                    ("bridges", json!([OBFS4, DIRECT])),
+                    ("pt_binaries", json!({"obfs4": "/data/app/lib/liblyrebird.so", "snowflake": "/data/x"})),
+                    // Left out with bridges, by the wrapper and the export alike
+                    ("relay_address_family", json!(1)),
+                ],
+            ));
+        }
+
+        for (name, settings) in cases {
+            let _case = config::save();
+            for (setting, value) in settings {
+                config::set(setting, value).unwrap();
+            }
+            config::commit_pending();
+            let group = config::strings(&config::applied("bridges"));
+            let live = crate::StageSettings::applied().client_config(&temp.path().join("data $HOME"), &group).unwrap();
+
+            let exported = render(false);
+            assert_eq!(reimport(&exported), live, "{}:\n{}", name, exported);
+            // The redacted form is still TOML, with nothing of the lines left
+            let redacted = render(true);
+            let table: toml::Table = toml::from_str(&redacted).unwrap();
+            assert!(!redacted.contains("192.0.2."), "{}", name);
+            if !group.is_empty() {
+                assert_eq!(table["bridges"]["bridges"], toml::Value::from(vec!["obfs4 [scrubbed]", "[scrubbed]"]));
+            }
+        }
+    }
+
+    #[test]
+    fn unredacted_exports_need_logcat_logging() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let _restore = Restore(crate::DATA_DIR.lock().unwrap().clone(), crate::LOGCAT_ENABLED.load(Ordering::Relaxed));
+        if !cfg!(feature = "bridges") {
+            return;
+        }
+        config::set("bridges", json!([DIRECT])).unwrap();
+        config::commit_pending();
+
+        for (logcat, redact, full) in [(true, false, true), (true, true, false), (false, false, false), (false, true, false)] {
+            crate::LOGCAT_ENABLED.store(logcat, Ordering::Relaxed);
+            let exported = render(redact);
+            assert_eq!(exported.contains(DIRECT), full, "logcat {} redact {}", logcat, redact);
+            assert_eq!(exported.contains("# Bridge lines are scrubbed"), !full);
+        }
+    }
+
+    #[test]
+    fn wrapper_settings_stay_in_comments() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        config::set("onion_only", json!(true)).unwrap();
+        config::set("client_stall_timeout_secs", json!(90)).unwrap();
+        config::commit_pending();
+
+        let exported = render(true);
+        let managed = exported.split_once("# --- Managed by the wrapper").expect("no wrapper block").1;
+        assert!(managed.lines().skip(1).all(|line| line.starts_with("# ")), "{}", managed);
+        assert!(managed.contains("# onion_only = true") && managed.contains("# client_stall_timeout_secs = 90"));
+        // None of it reaches the parsed document
+        let table: toml::Table = toml::from_str(&exported).unwrap();
+        assert!(!table.contains_key("onion_only") && !table.contains_key("socks_port"));
+    }
+}
@@ -0,0 +1,673 @@
+//! Append-only audit trail for security-relevant changes
+//!
+//! Separate from the debug logs: a short list of events that change how well
+//! the user is protected (strict mode disabled, logging to logcat in a release
+//! build, onion client keys added or removed, SOCKS listener secret rotated,
+//! pending config discarded, data directory migrations and their rollbacks,
+//! refused layout downgrades) is written to `<data_dir>/audit.log` as one
+//! JSON object per line.
+//!
+//! Each record carries the MAC of the record before it (`prev`) and its own
+//! `mac`, an HMAC-SHA256 over the record text. The key comes from the app
+//! (`setAuditKey`, e.g. unwrapped with an Android Keystore key) and is only
+//! held in memory, never stored in the directory it protects; until it is
+//! set, events are buffered and the log stays closed. Editing, dropping or
+//! reordering records breaks the chain.
+//!
+//! The head marker `wrapper/audit.head`, rewritten with every append, holds
+//! the seq and MAC of the last record and the anchor (seq and MAC of the last
+//! record rotated out for good), authenticated with the same key. Cutting
+//! records off the end is caught against the last record, cutting them off
+//! the start (or deleting the rotated file) against the anchor. `verify_json`
+//! reports which.
+//!
+//! Records never contain secrets or destinations, only event names and
+//! non-identifying details. Events raised before the log is opened are
+//! buffered and written on open.
+//!
+//! The log is capped at `MAX_LOG_BYTES`; when full it is rotated to
+//! `audit.log.1`, replacing an older one whose last record becomes the
+//! anchor, and the chain continues into the new file, so verification spans
+//! both.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::json_string;
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+
+/// Rotate once the current log reaches this size
+const MAX_LOG_BYTES: u64 = 256 * 1024;
+
+/// `prev` of the very first record
+const GENESIS: &str = "";
+
+/// Upper bound on events buffered before the log is opened
+const MAX_BUFFERED: usize = 64;
+
+/// Length of the MAC key the app supplies
+pub(crate) const KEY_LEN: usize = 32;
+
+type Key = Zeroizing<[u8; KEY_LEN]>;
+
+/// Seq and MAC of a record
+type Link = (u64, String);
+
+/// Contents of the head marker
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Head {
+    /// The last record appended
+    last: Link,
+    /// The last record rotated out for good (None: nothing dropped yet)
+    anchor: Option<Link>,
+}
+
+struct Audit {
+    log: PathBuf,
+    rotated: PathBuf,
+    head_path: PathBuf,
+    key: Key,
+    /// None until the first record
+    head: Option<Head>,
+    max_log_bytes: u64,
+}
+
+struct State {
+    key: Option<Key>,
+    open: Option<Audit>,
+    buffered: Vec<(u64, &'static str, String)>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    key: None,
+    open: None,
+    buffered: Vec::new(),
+});
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// HMAC-SHA256 (RFC 2104)
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block_key = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block_key[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block_key.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block_key.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Record text without its MAC, exactly as the MAC is computed over
+fn body(seq: u64, at_epoch_ms: u64, event: &str, detail: &str, prev: &str) -> String {
+    format!(
+        "{{\"seq\":{},\"at_epoch_ms\":{},\"event\":{},\"detail\":{},\"prev\":\"{}\"",
+        seq,
+        at_epoch_ms,
+        json_string(event),
+        json_string(detail),
+        prev,
+    )
+}
+
+/// Head marker text: `<seq> <mac> <anchor seq> <anchor mac> <tag>`, `-` for no anchor
+fn head_text(key: &[u8], head: &Head) -> String {
+    let (anchor_seq, anchor_mac) = match &head.anchor {
+        Some((seq, mac)) => (seq.to_string(), mac.as_str()),
+        None => ("-".to_string(), "-"),
+    };
+    let fields = format!("{} {} {} {}", head.last.0, head.last.1, anchor_seq, anchor_mac);
+    let tag = hex(&hmac_sha256(key, format!("audit-head {}", fields).as_bytes()));
+    format!("{} {}", fields, tag)
+}
+
+/// The head marker at `path`: Ok(None) if there is none, Err if it is
+/// malformed or its tag doesn't verify
+fn read_head(path: &Path, key: &[u8]) -> Result<Option<Head>, ()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(_) => return Err(()),
+    };
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let [seq, mac, anchor_seq, anchor_mac, _tag] = fields[..] else { return Err(()) };
+    let anchor = match (anchor_seq, anchor_mac) {
+        ("-", "-") => None,
+        (seq, mac) => Some((seq.parse().map_err(|_| ())?, mac.to_string())),
+    };
+    let head = Head { last: (seq.parse().map_err(|_| ())?, mac.to_string()), anchor };
+    if head_text(key, &head) != text.trim() {
+        return Err(());
+    }
+    Ok(Some(head))
+}
+
+/// Parsed record fields: seq, at, event, detail, prev, mac
+fn parse(line: &str) -> Option<(u64, u64, String, String, String, String)> {
+    let record: Value = serde_json::from_str(line).ok()?;
+    Some((
+        record["seq"].as_u64()?,
+        record["at_epoch_ms"].as_u64()?,
+        record["event"].as_str()?.to_string(),
+        record["detail"].as_str()?.to_string(),
+        record["prev"].as_str()?.to_string(),
+        record["mac"].as_str()?.to_string(),
+    ))
+}
+
+/// The link just before the first record of the log at `path`
+fn link_before(path: &Path) -> Option<Link> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let (seq, _, _, _, prev, _) = parse(text.lines().next()?)?;
+    Some((seq.checked_sub(1)?, prev))
+}
+
+impl Audit {
+    fn append(&mut self, at_epoch_ms: u64, event: &str, detail: &str) -> io::Result<()> {
+        crate::storage::check_writable()?;
+        let (seq, prev) = match &self.head {
+            Some(head) => (head.last.0 + 1, head.last.1.clone()),
+            None => (0, GENESIS.to_string()),
+        };
+        let body = body(seq, at_epoch_ms, event, detail, &prev);
+        let mac = hex(&hmac_sha256(&*self.key, body.as_bytes()));
+        let line = format!("{},\"mac\":\"{}\"}}\n", body, mac);
+
+        let mut anchor = self.head.as_ref().and_then(|head| head.anchor.clone());
+        let size = std::fs::metadata(&self.log).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_log_bytes {
+            // The records of an older rotated file are dropped for good
+            if self.rotated.exists() {
+                anchor = link_before(&self.log).or(anchor);
+            }
+            std::fs::rename(&self.log, &self.rotated)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.log)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        let head = Head { last: (seq, mac), anchor };
+        layout::write_atomic(&self.head_path, head_text(&*self.key, &head).as_bytes())?;
+        self.head = Some(head);
+        Ok(())
+    }
+}
+
+/// Set the MAC key (`KEY_LEN` bytes from the app)
+///
+/// Returns false if the length is wrong or a different key is already set:
+/// switching keys would break the chain of an open log.
+pub(crate) fn set_key(key: &[u8]) -> bool {
+    let Ok(key) = <[u8; KEY_LEN]>::try_from(key) else {
+        return false;
+    };
+    let key = Zeroizing::new(key);
+    let mut state = STATE.lock().unwrap();
+    match &state.key {
+        Some(current) => *current == key,
+        None => {
+            state.key = Some(key);
+            true
+        }
+    }
+}
+
+/// Open (or switch to) the audit log under `data_dir` and flush buffered events
+///
+/// Does nothing while no key is set; events stay buffered until `setAuditKey`.
+pub(crate) fn open(data_dir: &Path) -> io::Result<()> {
+    let mut state = STATE.lock().unwrap();
+    let Some(key) = state.key.clone() else {
+        log_info!("Audit log stays closed until the app sets its key (setAuditKey)");
+        return Ok(());
+    };
+    let log = layout::path(data_dir, Purpose::AuditLog);
+    let head_path = layout::path(data_dir, Purpose::AuditHead);
+    if let Some(parent) = head_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut restarted = false;
+    let head = match read_head(&head_path, &*key) {
+        Ok(head) => head,
+        Err(()) => {
+            // Continue from the last record on disk, and say so in the chain
+            restarted = true;
+            let text = std::fs::read_to_string(&log).unwrap_or_default();
+            let last = text.lines().last().and_then(parse).map(|(seq, _, _, _, _, mac)| (seq, mac));
+            last.map(|last| Head { last, anchor: None })
+        }
+    };
+
+    let mut audit = Audit {
+        log,
+        rotated: layout::path(data_dir, Purpose::AuditLogRotated),
+        head_path,
+        key,
+        head,
+        max_log_bytes: MAX_LOG_BYTES,
+    };
+    if restarted {
+        log_error!("Audit head marker doesn't verify; the chain continues from the last record on disk");
+        audit.append(crate::retention::timestamp(now_epoch_ms()), "audit_head_invalid", "")?;
+    }
+    for (at, event, detail) in std::mem::take(&mut state.buffered) {
+        audit.append(at, event, &detail)?;
+    }
+    state.open = Some(audit);
+    Ok(())
+}
+
+/// Whether the log is open
+pub(crate) fn is_open() -> bool {
+    STATE.lock().unwrap().open.is_some()
+}
+
+/// Append an audit record
+///
+/// `detail` must never contain secrets or destinations.
+pub(crate) fn record(event: &'static str, detail: &str) {
+    log_info!("Audit: {} {}", event, detail);
+    let at = crate::retention::timestamp(now_epoch_ms());
+    let mut state = STATE.lock().unwrap();
+    let state = &mut *state;
+    match state.open.as_mut() {
+        Some(audit) => {
+            if let Err(e) = audit.append(at, event, detail) {
+                crate::storage::observe(&e);
+                log_error!("Failed to write audit record: {}", e);
+            }
+        }
+        None if state.buffered.len() < MAX_BUFFERED => state.buffered.push((at, event, detail.to_string())),
+        None => log_error!("Audit buffer full; dropping {} record", event),
+    }
+}
+
+/// Every line of the rotated and current log, oldest first
+fn read_lines(audit: &Audit) -> Vec<String> {
+    [&audit.rotated, &audit.log]
+        .iter()
+        .filter_map(|p| std::fs::read_to_string(p).ok())
+        .flat_map(|text| text.lines().map(str::to_string).collect::<Vec<_>>())
+        .collect()
+}
+
+/// The last `max_records` records as a JSON array, oldest first
+pub(crate) fn tail_json(max_records: usize) -> String {
+    let state = STATE.lock().unwrap();
+    let Some(audit) = state.open.as_ref() else {
+        return "[]".to_string();
+    };
+    let lines = read_lines(audit);
+    let start = lines.len().saturating_sub(max_records);
+    format!("[{}]", lines[start..].join(","))
+}
+
+/// Check the chain over both log files against the head marker
+///
+/// Returns `{"ok":..,"records":..,"first_seq":..,"last_seq":..,"problems":[..]}`
+/// where each problem is `{"line":..,"problem":..}` with `problem` one of
+/// `unparseable`, `bad_mac`, `broken_chain`, `seq_gap`, `truncated_head`
+/// (the first record doesn't follow the anchor), or with line null
+/// `truncated_tail` or `bad_head` (the marker on disk was altered).
+/// `first_seq` above 0 means older records were rotated out.
+pub(crate) fn verify_json() -> String {
+    let state = STATE.lock().unwrap();
+    let Some(audit) = state.open.as_ref() else {
+        let problem = if state.key.is_some() { "not_open" } else { "no_key" };
+        return format!(
+            "{{\"ok\":false,\"records\":0,\"first_seq\":null,\"last_seq\":null,\"problems\":[{{\"line\":null,\"problem\":\"{}\"}}]}}",
+            problem
+        );
+    };
+
+    let lines = read_lines(audit);
+    let mut problems = Vec::new();
+    let mut first_seq = None;
+    let mut last: Option<Link> = None;
+
+    for (index, line) in lines.iter().enumerate() {
+        let Some((seq, at, event, detail, prev, mac)) = parse(line) else {
+            problems.push((Some(index), "unparseable"));
+            continue;
+        };
+
+        let expected = hex(&hmac_sha256(&*audit.key, body(seq, at, &event, &detail, &prev).as_bytes()));
+        if expected != mac {
+            problems.push((Some(index), "bad_mac"));
+        }
+        match &last {
+            Some((last_seq, last_mac)) => {
+                if prev != *last_mac {
+                    problems.push((Some(index), "broken_chain"));
+                }
+                if seq != last_seq + 1 {
+                    problems.push((Some(index), "seq_gap"));
+                }
+            }
+            None => {
+                // The chain starts at the genesis, or right after the anchor
+                let follows = match audit.head.as_ref().and_then(|head| head.anchor.as_ref()) {
+                    Some((anchor_seq, anchor_mac)) => seq == anchor_seq + 1 && prev == *anchor_mac,
+                    None => seq == 0 && prev == GENESIS,
+                };
+                if !follows {
+                    problems.push((Some(index), "truncated_head"));
+                }
+                first_seq = Some(seq);
+            }
+        }
+        last = Some((seq, mac));
+    }
+
+    // The head this process wrote (or verified at open) is the reference
+    if last != audit.head.as_ref().map(|head| head.last.clone()) {
+        problems.push((None, "truncated_tail"));
+    }
+    if read_head(&audit.head_path, &*audit.key) != Ok(audit.head.clone()) {
+        problems.push((None, "bad_head"));
+    }
+
+    let problems_json = problems
+        .iter()
+        .map(|(line, problem)| match line {
+            Some(l) => format!("{{\"line\":{},\"problem\":\"{}\"}}", l, problem),
+            None => format!("{{\"line\":null,\"problem\":\"{}\"}}", problem),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let seq_json = |s: Option<u64>| s.map_or("null".to_string(), |s| s.to_string());
+    format!(
+        "{{\"ok\":{},\"records\":{},\"first_seq\":{},\"last_seq\":{},\"problems\":[{}]}}",
+        problems.is_empty(),
+        lines.len(),
+        seq_json(first_seq),
+        seq_json(last.map(|(s, _)| s)),
+        problems_json,
+    )
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&STATE);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::PoisonError;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    /// A fresh audit state for one test; the previous one is put back on drop
+    struct Isolated(Option<State>);
+
+    impl Isolated {
+        fn new(key: Option<[u8; KEY_LEN]>) -> Isolated {
+            let fresh = State { key: key.map(Zeroizing::new), open: None, buffered: Vec::new() };
+            Isolated(Some(std::mem::replace(&mut *STATE.lock().unwrap(), fresh)))
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            *STATE.lock().unwrap_or_else(PoisonError::into_inner) = self.0.take().unwrap();
+        }
+    }
+
+    fn record_n(n: usize) {
+        for i in 0..n {
+            record("test_event", &format!("record {}", i));
+        }
+    }
+
+    /// Rotate every few records
+    fn rotate_often() {
+        STATE.lock().unwrap().open.as_mut().unwrap().max_log_bytes = 600;
+    }
+
+    fn verify() -> Value {
+        serde_json::from_str(&verify_json()).unwrap()
+    }
+
+    /// Names of the problems `verify_json` reports, sorted and deduplicated
+    fn problems() -> Vec<String> {
+        let mut names: Vec<String> = verify()["problems"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["problem"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn lines_of(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(str::to_string).collect()
+    }
+
+    fn write_lines(path: &Path, lines: &[String]) {
+        std::fs::write(path, lines.iter().map(|l| format!("{}\n", l)).collect::<String>()).unwrap();
+    }
+
+    #[test]
+    fn chain_verifies_across_reopen_and_rotation() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new(Some(KEY));
+        let temp = TempDir::new();
+        let dir = temp.path();
+
+        open(dir).unwrap();
+        record_n(3);
+        assert_eq!(verify()["ok"], true);
+        assert_eq!(verify()["last_seq"], 2);
+
+        // A restart picks the chain up from the head marker
+        STATE.lock().unwrap().open = None;
+        open(dir).unwrap();
+        record_n(2);
+        assert_eq!(verify()["ok"], true, "{}", verify_json());
+        assert_eq!(verify()["records"], 5);
+
+        rotate_often();
+        record_n(20);
+        let report = verify();
+        assert_eq!(report["ok"], true, "{}", report);
+        assert_eq!(report["last_seq"], 24);
+        assert!(report["first_seq"].as_u64().unwrap() > 0, "{}", report);
+        assert!(layout::path(dir, Purpose::AuditLogRotated).exists());
+        let tail: Value = serde_json::from_str(&tail_json(2)).unwrap();
+        assert_eq!(tail[1]["seq"], 24);
+    }
+
+    /// Damage done to the log files after the records were written
+    type Tamper = fn(&Path);
+
+    #[test]
+    fn tampering_is_detected() {
+        fn log(dir: &Path) -> PathBuf {
+            layout::path(dir, Purpose::AuditLog)
+        }
+        fn rotated(dir: &Path) -> PathBuf {
+            layout::path(dir, Purpose::AuditLogRotated)
+        }
+        let cases: &[(&str, bool, Tamper, &[&str])] = &[
+            ("untouched", false, |_| {}, &[]),
+            ("untouched after rotation", true, |_| {}, &[]),
+            ("detail edited", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines[1] = lines[1].replace("record 1", "record 9");
+                write_lines(&log(dir), &lines);
+            }, &["bad_mac"]),
+            ("middle record deleted", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.remove(2);
+                write_lines(&log(dir), &lines);
+            }, &["broken_chain", "seq_gap"]),
+            ("records reordered", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.swap(1, 2);
+                write_lines(&log(dir), &lines);
+            }, &["broken_chain", "seq_gap"]),
+            ("last record cut off", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.pop();
+                write_lines(&log(dir), &lines);
+            }, &["truncated_tail"]),
+            ("last record cut off, head rewritten without the key", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.pop();
+                let (seq, _, _, _, _, mac) = parse(lines.last().unwrap()).unwrap();
+                write_lines(&log(dir), &lines);
+                let head = Head { last: (seq, mac), anchor: None };
+                std::fs::write(layout::path(dir, Purpose::AuditHead), head_text(&[0; KEY_LEN], &head)).unwrap();
+            }, &["bad_head", "truncated_tail"]),
+            ("log emptied", false, |dir| {
+                std::fs::write(log(dir), "").unwrap();
+            }, &["truncated_tail"]),
+            ("first record cut off", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.remove(0);
+                write_lines(&log(dir), &lines);
+            }, &["truncated_head"]),
+            ("rotated file deleted", true, |dir| {
+                std::fs::remove_file(rotated(dir)).unwrap();
+            }, &["truncated_head"]),
+            ("start of the rotated file cut off", true, |dir| {
+                let mut lines = lines_of(&rotated(dir));
+                lines.remove(0);
+                write_lines(&rotated(dir), &lines);
+            }, &["truncated_head"]),
+            ("rotated records moved after the current ones", true, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.extend(lines_of(&rotated(dir)));
+                std::fs::remove_file(rotated(dir)).unwrap();
+                write_lines(&log(dir), &lines);
+            }, &["broken_chain", "seq_gap", "truncated_head", "truncated_tail"]),
+            ("record appended without the key", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                let (seq, at, _, _, _, mac) = parse(lines.last().unwrap()).unwrap();
+                let body = body(seq + 1, at, "strict_mode_enabled", "", &mac);
+                let forged = hex(&hmac_sha256(&[0; KEY_LEN], body.as_bytes()));
+                lines.push(format!("{},\"mac\":\"{}\"}}", body, forged));
+                write_lines(&log(dir), &lines);
+            }, &["bad_mac", "truncated_tail"]),
+            ("garbage line", false, |dir| {
+                let mut lines = lines_of(&log(dir));
+                lines.insert(2, "not json".to_string());
+                write_lines(&log(dir), &lines);
+            }, &["unparseable"]),
+            ("head deleted", false, |dir| {
+                std::fs::remove_file(layout::path(dir, Purpose::AuditHead)).unwrap();
+            }, &["bad_head"]),
+        ];
+
+        let _serial = testing::serial();
+        for (name, rotate, tamper, expected) in cases {
+            let _isolated = Isolated::new(Some(KEY));
+            let temp = TempDir::new();
+            let dir = temp.path();
+            open(dir).unwrap();
+            if *rotate {
+                rotate_often();
+                record_n(12);
+                assert!(verify()["first_seq"].as_u64().unwrap() > 0, "{}", name);
+            } else {
+                record_n(5);
+            }
+            tamper(dir);
+            assert_eq!(problems(), *expected, "{}: {}", name, verify_json());
+        }
+    }
+
+    #[test]
+    fn a_bad_head_at_open_is_recorded_in_the_chain() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new(Some(KEY));
+        let temp = TempDir::new();
+        let dir = temp.path();
+        open(dir).unwrap();
+        record_n(3);
+
+        STATE.lock().unwrap().open = None;
+        std::fs::write(layout::path(dir, Purpose::AuditHead), "0 forged - - tag").unwrap();
+        open(dir).unwrap();
+
+        let tail: Value = serde_json::from_str(&tail_json(1)).unwrap();
+        assert_eq!(tail[0]["event"], "audit_head_invalid");
+        assert_eq!(tail[0]["seq"], 3);
+        assert_eq!(verify()["ok"], true, "{}", verify_json());
+    }
+
+    #[test]
+    fn the_key_comes_from_the_app_and_stays_out_of_the_directory() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new(None);
+        let temp = TempDir::new();
+        let dir = temp.path();
+
+        // Without a key the log stays closed and events wait in memory
+        record("strict_mode_disabled", "");
+        open(dir).unwrap();
+        assert_eq!(problems(), ["no_key"]);
+        assert!(!layout::path(dir, Purpose::AuditLog).exists());
+
+        assert!(!set_key(&KEY[..16]));
+        assert!(set_key(&KEY));
+        assert!(set_key(&KEY));
+        assert!(!set_key(&[8; KEY_LEN]));
+        assert_eq!(problems(), ["not_open"]);
+
+        open(dir).unwrap();
+        let tail: Value = serde_json::from_str(&tail_json(10)).unwrap();
+        assert_eq!(tail[0]["event"], "strict_mode_disabled");
+        assert_eq!(verify()["ok"], true);
+
+        // Nothing under the data directory holds the key
+        fn files(dir: &Path, out: &mut Vec<PathBuf>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    files(&path, out);
+                } else {
+                    out.push(path);
+                }
+            }
+        }
+        let mut all = Vec::new();
+        files(dir, &mut all);
+        for file in all {
+            let contents = std::fs::read(&file).unwrap();
+            assert!(!contents.windows(KEY_LEN).any(|w| w == KEY), "{:?}", file);
+            assert!(!String::from_utf8_lossy(&contents).contains(&hex(&KEY)), "{:?}", file);
+        }
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        // Test case 2
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
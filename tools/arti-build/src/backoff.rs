@@ -0,0 +1,182 @@
+//! Shared retry and cooldown policies
+//!
+//! Components that retry or hold off after acting use these instead of
+//! keeping their own delay arithmetic, so schedules behave alike:
+//!
+//! - `Backoff` runs an `Exponential` policy: the first delay is `initial_ms`,
+//!   each later one doubles up to `max_ms`, and `reset` (on success) starts
+//!   over from `initial_ms`. With `jitter_percent` each delay is spread by up
+//!   to that share either way, drawn from `seams::fill_random`, and never
+//!   exceeds `max_ms`;
+//! - `Cooldown` is a latch that, once tripped, stays active for its period.
+//!
+//! Both take time as monotonic ms from the caller (usually
+//! `liveness::monotonic_ms`, which follows the injectable clock) and hold no
+//! locks, so they can live inside whatever state their owner already guards.
+//!
+//! Users: storage re-probes (storage.rs) and the entry watch cooldown
+//! (entry_watch.rs).
+
+use crate::seams;
+
+/// Exponential delays between attempts
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Exponential {
+    pub initial_ms: u64,
+    pub max_ms: u64,
+    /// Spread of each delay either way, in percent (0: exact schedule)
+    pub jitter_percent: u64,
+}
+
+/// An `Exponential` schedule in progress
+pub(crate) struct Backoff {
+    policy: Exponential,
+    /// Delay the next attempt gets, before jitter
+    current_ms: u64,
+}
+
+impl Backoff {
+    pub(crate) const fn new(policy: Exponential) -> Self {
+        Backoff { policy, current_ms: policy.initial_ms }
+    }
+
+    /// Delay before the next attempt; the one after is twice as long
+    pub(crate) fn next_ms(&mut self) -> u64 {
+        let delay = self.current_ms.min(self.policy.max_ms);
+        self.current_ms = delay.saturating_mul(2).min(self.policy.max_ms);
+        jittered(delay, self.policy.jitter_percent).min(self.policy.max_ms)
+    }
+
+    /// Start over from the initial delay (after a success)
+    pub(crate) fn reset(&mut self) {
+        self.current_ms = self.policy.initial_ms;
+    }
+}
+
+/// `delay` spread by up to `percent` either way
+fn jittered(delay: u64, percent: u64) -> u64 {
+    let spread = delay.saturating_mul(percent.min(100)) / 100;
+    if spread == 0 {
+        return delay;
+    }
+    let mut bytes = [0u8; 8];
+    seams::fill_random(&mut bytes);
+    let offset = u64::from_le_bytes(bytes) % (2 * spread + 1);
+    (delay - spread).saturating_add(offset)
+}
+
+/// A latch that stays active for `period_ms` after each trip
+pub(crate) struct Cooldown {
+    period_ms: u64,
+    until_ms: u64,
+}
+
+impl Cooldown {
+    pub(crate) const fn new(period_ms: u64) -> Self {
+        Cooldown { period_ms, until_ms: 0 }
+    }
+
+    /// Whether the last trip still holds at `now_ms`
+    pub(crate) fn active(&self, now_ms: u64) -> bool {
+        now_ms < self.until_ms
+    }
+
+    pub(crate) fn trip(&mut self, now_ms: u64) {
+        self.until_ms = now_ms.saturating_add(self.period_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seams::fakes;
+    use crate::testing;
+
+    const fn exact(initial_ms: u64, max_ms: u64) -> Exponential {
+        Exponential { initial_ms, max_ms, jitter_percent: 0 }
+    }
+
+    fn schedule(backoff: &mut Backoff, n: usize) -> Vec<u64> {
+        (0..n).map(|_| backoff.next_ms()).collect()
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        for (policy, expected) in [
+            (exact(5_000, 300_000), vec![5_000, 10_000, 20_000, 40_000, 80_000, 160_000, 300_000, 300_000]),
+            // The cap may fall between doublings, or equal the first delay
+            (exact(100, 250), vec![100, 200, 250, 250]),
+            (exact(100, 100), vec![100, 100, 100]),
+            // A first delay above the cap is capped too
+            (exact(1_000, 300), vec![300, 300]),
+            (exact(0, 1_000), vec![0, 0, 0]),
+            // Doubling near the top of the range saturates instead of wrapping
+            (exact(u64::MAX / 2 + 1, u64::MAX), vec![u64::MAX / 2 + 1, u64::MAX, u64::MAX]),
+        ] {
+            let mut backoff = Backoff::new(policy);
+            assert_eq!(schedule(&mut backoff, expected.len()), expected, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn reset_starts_over_at_any_point() {
+        let mut backoff = Backoff::new(exact(10, 1_000));
+        // Before any attempt, mid-schedule and at the cap
+        for attempts in [0, 3, 20] {
+            schedule(&mut backoff, attempts);
+            backoff.reset();
+            assert_eq!(schedule(&mut backoff, 3), [10, 20, 40], "after {} attempts", attempts);
+        }
+        // A reset on partial success doesn't carry the old delay over
+        schedule(&mut backoff, 5);
+        backoff.reset();
+        backoff.reset();
+        assert_eq!(backoff.next_ms(), 10);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_spread_and_the_cap() {
+        let _serial = testing::serial();
+        fakes::seed_entropy(7);
+        let policy = Exponential { initial_ms: 1_000, max_ms: 6_000, jitter_percent: 20 };
+        let unjittered = [1_000, 2_000, 4_000, 6_000, 6_000, 6_000];
+        let mut spread_seen = false;
+        for _ in 0..200 {
+            let mut backoff = Backoff::new(policy);
+            for (delay, base) in schedule(&mut backoff, unjittered.len()).into_iter().zip(unjittered) {
+                assert!(delay >= base - base / 5 && delay <= (base + base / 5).min(6_000), "{} from {}", delay, base);
+                spread_seen |= delay != base;
+            }
+        }
+        assert!(spread_seen);
+
+        // Nothing to spread: the exact delay, without drawing entropy
+        for (delay, percent) in [(1_000, 0), (4, 10), (0, 50)] {
+            assert_eq!(jittered(delay, percent), delay);
+        }
+        // Over 100% is held to 100%: anywhere from 0 to twice the delay
+        for _ in 0..200 {
+            assert!(jittered(50, 500) <= 100);
+        }
+        fakes::use_os_entropy();
+    }
+
+    #[test]
+    fn a_cooldown_holds_for_its_period_after_each_trip() {
+        let mut cooldown = Cooldown::new(1_000);
+        assert!(!cooldown.active(0));
+        cooldown.trip(5_000);
+        for (now_ms, active) in [(4_999, true), (5_000, true), (5_999, true), (6_000, false), (10_000, false)] {
+            assert_eq!(cooldown.active(now_ms), active, "{}", now_ms);
+        }
+        // Tripping again moves the end, even during the cooldown
+        cooldown.trip(5_500);
+        assert!(cooldown.active(6_000) && !cooldown.active(6_500));
+        cooldown.trip(u64::MAX - 10);
+        assert!(cooldown.active(u64::MAX - 1));
+
+        let mut never = Cooldown::new(0);
+        never.trip(100);
+        assert!(!never.active(100));
+    }
+}
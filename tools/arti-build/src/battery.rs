@@ -0,0 +1,115 @@
+//! Low-battery pause
+//!
+//! The app forwards Android's battery-low / battery-saver broadcasts through
+//! `onBatteryLow`. While the battery is low the SOCKS proxy stops accepting
+//! new connections and the client is put into soft dormant mode, which stops
+//! Arti's background work (preemptive circuits, periodic directory fetches).
+//! Connections already established keep running. Clearing the condition
+//! reverses both.
+//!
+//! Connection attempts made while paused wait in the listen backlog and are
+//! accepted on resume, unless the client gives up first.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use arti_client::{DormantMode, TorClient};
+use tokio::sync::Notify;
+use tor_rtcompat::PreferredRuntime;
+
+static LOW: AtomicBool = AtomicBool::new(false);
+
+/// Woken on every change of `LOW`
+static CHANGED: Notify = Notify::const_new();
+
+/// Record the battery condition; returns whether it changed
+pub(crate) fn set_low(low: bool) -> bool {
+    let changed = LOW.swap(low, Ordering::AcqRel) != low;
+    if changed {
+        CHANGED.notify_waiters();
+    }
+    changed
+}
+
+/// Whether the battery is low (the client is dormant)
+pub(crate) fn low() -> bool {
+    LOW.load(Ordering::Acquire)
+}
+
+/// Dormant mode matching the battery condition and Doze
+fn dormant_mode() -> DormantMode {
+    if LOW.load(Ordering::Acquire) || crate::doze::dozing() {
+        DormantMode::Soft
+    } else {
+        DormantMode::Normal
+    }
+}
+
+/// Put `client` into the dormant mode matching the battery condition and Doze
+pub(crate) fn apply_dormancy(client: &TorClient<PreferredRuntime>) {
+    client.set_dormant(dormant_mode());
+}
+
+/// Resolves while accepting is allowed (immediately if it already is)
+pub(crate) async fn accepting() {
+    loop {
+        // Registered before the check, so a change in between isn't missed
+        let changed = CHANGED.notified();
+        if !LOW.load(Ordering::Acquire) {
+            return;
+        }
+        changed.await;
+    }
+}
+
+/// Resolves once the battery goes low (immediately if it already is)
+pub(crate) async fn paused() {
+    loop {
+        let changed = CHANGED.notified();
+        if LOW.load(Ordering::Acquire) {
+            return;
+        }
+        changed.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{doze, testing};
+    use std::time::Duration;
+
+    #[test]
+    fn dormant_while_low_or_dozing() {
+        let _serial = testing::serial();
+        // low, dozing => soft dormant
+        for (low, dozing, soft) in [(false, false, false), (true, false, true), (false, true, true), (true, true, true)] {
+            set_low(low);
+            if dozing {
+                doze::enter();
+            }
+            assert_eq!(matches!(dormant_mode(), DormantMode::Soft), soft, "low {} dozing {}", low, dozing);
+            doze::exit();
+        }
+        set_low(false);
+    }
+
+    #[test]
+    fn changes_wake_the_waiters() {
+        let _serial = testing::serial();
+        assert!(!set_low(false));
+        testing::runtime().block_on(async {
+            let paused = tokio::spawn(paused());
+            tokio::task::yield_now().await;
+            assert!(!paused.is_finished());
+            assert!(set_low(true));
+            assert!(!set_low(true));
+            tokio::time::timeout(Duration::from_secs(2), paused).await.unwrap().unwrap();
+
+            let accepting = tokio::spawn(accepting());
+            tokio::task::yield_now().await;
+            assert!(!accepting.is_finished());
+            assert!(set_low(false));
+            tokio::time::timeout(Duration::from_secs(2), accepting).await.unwrap().unwrap();
+        });
+    }
+}
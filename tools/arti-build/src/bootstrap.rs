@@ -0,0 +1,194 @@
+//! Categorized bootstrap failures
+//!
+//! `initialize` used to return -3 for every bootstrap failure, leaving the
+//! Java layer to parse logs to tell a filtered network from a wrong clock.
+//! Failures are now classified into categories with their own return codes
+//! (mirrored as `INIT_*` constants in `ArtiNative.kt`) and failure-record
+//! kinds, so the app can route straight to the right remediation:
+//!
+//! | code | kind                     | cause                                            |
+//! |------|--------------------------|--------------------------------------------------|
+//! | -3   | `bootstrap_failed`       | anything not covered below                       |
+//! | -4   | `storage_error`          | Arti's state or cache can't be read or written   |
+//! | -7   | `consensus_fetch_failed` | no usable directory information could be fetched |
+//! | -8   | `no_usable_guards`       | the network may be filtered (see guards.rs)      |
+//! | -9   | `clock_skew`             | the device clock is too far off                  |
+//! | -10  | `config_error`           | the client configuration was rejected            |
+//! | -11  | `bootstrap_cancelled`    | `stop` cancelled it (see debounce.rs)            |
+//! | -13  | `state_locked`           | another process holds the data directory         |
+//!
+//! Classification uses Arti's `ErrorKind`, plus the wrapper's own signals:
+//! errors from building the client configuration are tagged with
+//! `ConfigStage`, and the no-usable-guards detector takes precedence since a
+//! guard failure usually surfaces as a generic timeout. `state_locked` is not
+//! a bootstrap error: the data directory lock is taken before bootstrapping
+//! (see state_lock.rs).
+
+use std::fmt;
+
+use arti_client::{ErrorKind, HasKind};
+
+/// Context attached to errors from building the client configuration
+#[derive(Debug)]
+pub(crate) struct ConfigStage;
+
+impl fmt::Display for ConfigStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("building the client configuration")
+    }
+}
+
+/// Category of a failed bootstrap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Failure {
+    Other,
+    Storage,
+    Consensus,
+    Guards,
+    ClockSkew,
+    Config,
+    Cancelled,
+    StateLocked,
+}
+
+impl Failure {
+    /// Return code of `initialize` (part of the JNI contract)
+    pub(crate) fn code(self) -> i32 {
+        match self {
+            Failure::Other => -3,
+            Failure::Storage => -4,
+            Failure::Consensus => -7,
+            Failure::Guards => -8,
+            Failure::ClockSkew => -9,
+            Failure::Config => -10,
+            Failure::Cancelled => -11,
+            Failure::StateLocked => -13,
+        }
+    }
+
+    /// Failure record kind
+    pub(crate) fn kind(self) -> &'static str {
+        match self {
+            Failure::Other => "bootstrap_failed",
+            Failure::Storage => "storage_error",
+            Failure::Consensus => "consensus_fetch_failed",
+            Failure::Guards => "no_usable_guards",
+            Failure::ClockSkew => "clock_skew",
+            Failure::Config => "config_error",
+            Failure::Cancelled => "bootstrap_cancelled",
+            Failure::StateLocked => "state_locked",
+        }
+    }
+}
+
+const FAILURES: [Failure; 8] = [
+    Failure::Other,
+    Failure::Storage,
+    Failure::Consensus,
+    Failure::Guards,
+    Failure::ClockSkew,
+    Failure::Config,
+    Failure::Cancelled,
+    Failure::StateLocked,
+];
+
+/// Failure record kind of an `initialize` bootstrap failure code
+pub(crate) fn kind_of(code: i32) -> &'static str {
+    FAILURES
+        .into_iter()
+        .find(|failure| failure.code() == code)
+        .map_or(Failure::Other.kind(), Failure::kind)
+}
+
+/// Map an Arti error kind to a category
+fn classify_kind(kind: ErrorKind) -> Failure {
+    match kind {
+        ErrorKind::ClockSkew => Failure::ClockSkew,
+        ErrorKind::InvalidConfig | ErrorKind::FeatureDisabled => Failure::Config,
+        ErrorKind::PersistentStateAccessFailed | ErrorKind::CacheAccessFailed | ErrorKind::CacheCorrupted => {
+            Failure::Storage
+        }
+        ErrorKind::TorDirectoryError
+        | ErrorKind::TorDirectoryUnusable
+        | ErrorKind::DirectoryExpired
+        | ErrorKind::TorNetworkTimeout => Failure::Consensus,
+        // The first hop itself couldn't be reached
+        ErrorKind::TorAccessFailed => Failure::Guards,
+        _ => Failure::Other,
+    }
+}
+
+/// Classify a bootstrap error
+pub(crate) fn classify(error: &anyhow::Error) -> Failure {
+    if error.downcast_ref::<ConfigStage>().is_some() {
+        return Failure::Config;
+    }
+    if error.downcast_ref::<crate::debounce::BootstrapCancelled>().is_some() {
+        return Failure::Cancelled;
+    }
+    if crate::guards::unusable() {
+        return Failure::Guards;
+    }
+    error
+        .downcast_ref::<arti_client::Error>()
+        .map_or(Failure::Other, |e| classify_kind(e.kind()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use anyhow::Context as _;
+
+    #[test]
+    fn arti_error_kinds_map_to_categories() {
+        for (kind, failure) in [
+            (ErrorKind::ClockSkew, Failure::ClockSkew),
+            (ErrorKind::InvalidConfig, Failure::Config),
+            (ErrorKind::FeatureDisabled, Failure::Config),
+            (ErrorKind::PersistentStateAccessFailed, Failure::Storage),
+            (ErrorKind::CacheAccessFailed, Failure::Storage),
+            (ErrorKind::CacheCorrupted, Failure::Storage),
+            (ErrorKind::TorDirectoryError, Failure::Consensus),
+            (ErrorKind::TorDirectoryUnusable, Failure::Consensus),
+            (ErrorKind::DirectoryExpired, Failure::Consensus),
+            (ErrorKind::TorNetworkTimeout, Failure::Consensus),
+            (ErrorKind::TorAccessFailed, Failure::Guards),
+            (ErrorKind::LocalNetworkError, Failure::Other),
+            (ErrorKind::Internal, Failure::Other),
+        ] {
+            assert_eq!(classify_kind(kind), failure, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn every_category_has_its_own_code_and_kind() {
+        for (i, a) in FAILURES.iter().enumerate() {
+            for b in &FAILURES[i + 1..] {
+                assert_ne!(a.code(), b.code(), "{:?} and {:?}", a, b);
+                assert_ne!(a.kind(), b.kind(), "{:?} and {:?}", a, b);
+            }
+            assert_eq!(kind_of(a.code()), a.kind());
+        }
+        // Codes outside the table are generic failures
+        assert_eq!(kind_of(-99), "bootstrap_failed");
+    }
+
+    #[test]
+    fn wrapper_signals_are_classified_first() {
+        let _serial = testing::serial();
+        crate::guards::clear();
+        let config = Err::<(), _>(anyhow::anyhow!("unknown transport")).context(ConfigStage).unwrap_err();
+        let cancelled = Err::<(), _>(anyhow::anyhow!("stopped")).context(crate::debounce::BootstrapCancelled).unwrap_err();
+        let timeout = anyhow::anyhow!("bootstrap timed out");
+        assert_eq!(classify(&config), Failure::Config);
+        assert_eq!(classify(&cancelled), Failure::Cancelled);
+        assert_eq!(classify(&timeout), Failure::Other);
+
+        // A guard failure usually surfaces as a timeout
+        crate::guards::check_error(&"Guard(AllGuardsDown)", "test");
+        assert_eq!(classify(&timeout), Failure::Guards);
+        assert_eq!(classify(&config), Failure::Config);
+        crate::guards::clear();
+    }
+}
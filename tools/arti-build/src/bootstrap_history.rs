@@ -0,0 +1,356 @@
+//! Why and how each bootstrap happened
+//!
+//! "Why did Tor just re-bootstrap?" needs the reason recorded where the
+//! bootstrap starts. Every bootstrap goes through `initialize_at` in lib.rs,
+//! which takes a `Trigger` and records the attempt here when it ends, so no
+//! path can bootstrap without one:
+//!
+//! - `user`: `initialize` or `initializeWithConfig` (a reused client is not a
+//!   bootstrap and isn't recorded);
+//! - `data_dir_change`: `migrateDataDirectory` reinitializing at the new
+//!   location;
+//! - `migration_rollback`: a failed migration reinitializing at the old one.
+//!
+//! Each record has the trigger, a fingerprint of the applied settings (the
+//! first `FINGERPRINT_LEN` hex digits of a SHA-256 over them, so equal
+//! fingerprints mean equal configurations), the outcome (`ok` or the failure
+//! kind), the duration and the winning entry: `direct`, `bridge` for plain
+//! bridges, or the pluggable transport's name.
+//!
+//! The last `MAX_RECENT` records are kept in memory and the last
+//! `MAX_PERSISTED` under `wrapper/bootstrap-history`, one JSON object per
+//! line, so `getBootstrapHistory` also covers earlier processes. Timestamps
+//! follow the retention level, and the minimal level keeps no history at all.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+use crate::retention::{self, Sink};
+use crate::{bridges, config, seams};
+
+/// Records kept in memory
+const MAX_RECENT: usize = 20;
+
+/// Records kept on disk
+const MAX_PERSISTED: usize = 100;
+
+/// Hex digits of the configuration fingerprint
+const FINGERPRINT_LEN: usize = 16;
+
+/// What started a bootstrap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Trigger {
+    User,
+    DataDirChange,
+    MigrationRollback,
+}
+
+impl Trigger {
+    fn as_str(self) -> &'static str {
+        match self {
+            Trigger::User => "user",
+            Trigger::DataDirChange => "data_dir_change",
+            Trigger::MigrationRollback => "migration_rollback",
+        }
+    }
+}
+
+static RECENT: Mutex<VecDeque<Value>> = Mutex::new(VecDeque::new());
+
+/// The history file of the open data directory
+static FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// A bootstrap in progress, recorded by `finish`
+pub(crate) struct Attempt {
+    trigger: Trigger,
+    at_epoch_ms: u64,
+    started: Instant,
+}
+
+impl Attempt {
+    pub(crate) fn begin(trigger: Trigger) -> Attempt {
+        Attempt { trigger, at_epoch_ms: now_epoch_ms(), started: seams::now_monotonic() }
+    }
+
+    /// Record the attempt with `outcome` (`ok` or a failure kind)
+    pub(crate) fn finish(self, outcome: &str) {
+        let entry = match bridges::active_transport() {
+            _ if outcome != "ok" => None,
+            None => Some("direct".to_string()),
+            Some(transport) if transport == bridges::DIRECT => Some("bridge".to_string()),
+            Some(transport) => Some(transport),
+        };
+        let record = json!({
+            "at_epoch_ms": retention::timestamp(self.at_epoch_ms),
+            "trigger": self.trigger.as_str(),
+            "config_fingerprint": fingerprint(),
+            "outcome": outcome,
+            "duration_ms": seams::now_monotonic().saturating_duration_since(self.started).as_millis() as u64,
+            "entry": entry,
+        });
+        log_info!("Bootstrap ({}) ended: {}", self.trigger.as_str(), outcome);
+        if retention::retains(Sink::BootstrapHistory) {
+            push(&mut RECENT.lock().unwrap(), record.clone(), MAX_RECENT);
+            persist(&record);
+        }
+    }
+}
+
+fn push(records: &mut VecDeque<Value>, record: Value, max: usize) {
+    if records.len() >= max {
+        records.pop_front();
+    }
+    records.push_back(record);
+}
+
+/// Fingerprint of the applied settings
+fn fingerprint() -> String {
+    let settings: Value = serde_json::from_str(&config::effective_json()).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    for (name, entry) in settings.as_object().into_iter().flatten() {
+        hasher.update(name.as_bytes());
+        hasher.update(entry["value"].to_string().as_bytes());
+    }
+    let digest: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    digest[..FINGERPRINT_LEN].to_string()
+}
+
+fn read(path: &Path) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn persist(record: &Value) {
+    let Some(path) = FILE.lock().unwrap().clone() else {
+        return;
+    };
+    let mut records: VecDeque<Value> = read(&path).into();
+    push(&mut records, record.clone(), MAX_PERSISTED);
+    let text: String = records.iter().map(|record| format!("{}\n", record)).collect();
+    if let Err(e) = layout::write_atomic(&path, text.as_bytes()) {
+        log_error!("Failed to write the bootstrap history: {}", e);
+    }
+}
+
+/// Use the history under `data_dir`, seeding memory with its newest records
+pub(crate) fn open(data_dir: &Path) {
+    let path = layout::path(data_dir, Purpose::BootstrapHistory);
+    let mut file = FILE.lock().unwrap();
+    if file.as_ref() == Some(&path) {
+        return;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).ok();
+    }
+    let persisted = read(&path);
+    let mut recent = RECENT.lock().unwrap();
+    recent.clear();
+    for record in persisted.into_iter().rev().take(MAX_RECENT).rev() {
+        recent.push_back(record);
+    }
+    *file = Some(path);
+}
+
+/// Forget the history, in memory and on disk (retention.rs)
+pub(crate) fn purge() {
+    RECENT.lock().unwrap().clear();
+    let Some(path) = FILE.lock().unwrap().clone() else {
+        return;
+    };
+    if let Err(e) = std::fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log_error!("Failed to delete the bootstrap history: {}", e);
+        }
+    }
+}
+
+/// The recent records as a JSON array, oldest first
+pub(crate) fn history_json() -> String {
+    Value::Array(RECENT.lock().unwrap().iter().cloned().collect()).to_string()
+}
+
+/// Stop using the open data directory (tests open temporary ones)
+#[cfg(test)]
+pub(crate) fn close() {
+    *FILE.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&RECENT);
+    held.hold(&FILE);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+
+    /// An empty history with no file for one test, the previous one
+    /// restored after
+    struct Isolated {
+        recent: VecDeque<Value>,
+        file: Option<PathBuf>,
+    }
+
+    impl Isolated {
+        fn new() -> Isolated {
+            Isolated { recent: std::mem::take(&mut *RECENT.lock().unwrap()), file: FILE.lock().unwrap().take() }
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            *RECENT.lock().unwrap() = std::mem::take(&mut self.recent);
+            *FILE.lock().unwrap() = self.file.take();
+            bridges::set_active(&[]);
+        }
+    }
+
+    fn history() -> Vec<Value> {
+        serde_json::from_str(&history_json()).unwrap()
+    }
+
+    #[test]
+    fn every_attempt_records_its_trigger_and_outcome() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let time = FakeTime::start();
+        let obfs4 = "obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=abc iat-mode=0";
+        let plain = "192.0.2.2:443 0123456789ABCDEF0123456789ABCDEF01234567";
+
+        // (trigger, bridges of the stage, outcome, recorded entry)
+        for (trigger, group, outcome, entry) in [
+            (Trigger::User, vec![], "ok", json!("direct")),
+            (Trigger::User, vec![obfs4], "ok", json!("obfs4")),
+            (Trigger::DataDirChange, vec![plain], "ok", json!("bridge")),
+            (Trigger::MigrationRollback, vec![], "storage_error", Value::Null),
+            (Trigger::User, vec![obfs4], "consensus_fetch_failed", Value::Null),
+        ] {
+            let group: Vec<String> = group.into_iter().map(String::from).collect();
+            bridges::set_active(&group);
+            let attempt = Attempt::begin(trigger);
+            time.advance(Duration::from_millis(1500));
+            attempt.finish(outcome);
+
+            let record = history().pop().unwrap();
+            assert_eq!(record["trigger"], trigger.as_str());
+            assert_eq!(record["outcome"], outcome);
+            assert_eq!(record["entry"], entry, "{:?} over {:?}", trigger, group);
+            assert_eq!(record["duration_ms"], 1500);
+            assert_eq!(record["config_fingerprint"], fingerprint());
+        }
+        assert_eq!(history().len(), 5);
+    }
+
+    #[test]
+    fn the_fingerprint_follows_the_settings() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let before = fingerprint();
+        assert_eq!(before.len(), FINGERPRINT_LEN);
+        assert_eq!(fingerprint(), before);
+
+        config::set("isolate_by_dest_port", json!(true)).unwrap();
+        let changed = fingerprint();
+        assert_ne!(changed, before);
+        config::set("isolate_by_dest_port", json!(false)).unwrap();
+        assert_eq!(fingerprint(), before);
+    }
+
+    #[test]
+    fn the_history_is_bounded_and_survives_a_restart() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let dir = TempDir::new();
+        let file = layout::path(dir.path(), Purpose::BootstrapHistory);
+        open(dir.path());
+        let on_disk = || read(&file);
+
+        for i in 0..MAX_RECENT + 5 {
+            Attempt::begin(Trigger::User).finish(if i % 2 == 0 { "ok" } else { "bootstrap_failed" });
+        }
+        assert_eq!(history().len(), MAX_RECENT);
+        assert_eq!(on_disk().len(), MAX_RECENT + 5);
+
+        // A new process seeds memory with the newest persisted records
+        RECENT.lock().unwrap().clear();
+        close();
+        open(dir.path());
+        assert_eq!(history(), on_disk()[5..].to_vec());
+
+        for _ in 0..MAX_PERSISTED {
+            Attempt::begin(Trigger::DataDirChange).finish("ok");
+        }
+        let persisted = on_disk();
+        assert_eq!(persisted.len(), MAX_PERSISTED);
+        assert!(persisted.iter().all(|record| record["trigger"] == "data_dir_change"));
+        close();
+    }
+
+    #[test]
+    fn minimal_retention_keeps_no_history() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let _isolated = Isolated::new();
+        let dir = TempDir::new();
+        let file = layout::path(dir.path(), Purpose::BootstrapHistory);
+        open(dir.path());
+        Attempt::begin(Trigger::User).finish("ok");
+        assert!(file.exists());
+
+        config::set("metadata_retention", json!(2)).unwrap();
+        assert!(history().is_empty());
+        assert!(!file.exists());
+        Attempt::begin(Trigger::User).finish("ok");
+        assert!(history().is_empty());
+        assert!(!file.exists());
+        close();
+    }
+
+    #[test]
+    fn no_bootstrap_path_starts_without_a_recorded_reason() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+
+        // `bootstrap_at` is only ever called by `initialize_at`, the single
+        // entry, and every call of that names its trigger
+        let source = include_str!("lib.rs");
+        let entry = concat!("fn initialize_", "at(");
+        let start = source.find(entry).unwrap();
+        let body = &source[start..start + source[start..].find("\n}\n").unwrap()];
+        let bootstrap = concat!("bootstrap_", "at(");
+        assert_eq!(source.matches(bootstrap).count(), 2, "bootstrap_at is called outside initialize_at");
+        assert_eq!(body.matches(bootstrap).count(), 1);
+        // Calls (and the definition) are one line each
+        let calls: Vec<&str> = source.split(concat!("initialize_", "at(")).skip(1).filter_map(|call| call.lines().next()).collect();
+        assert!(calls.len() >= 4, "{:?}", calls);
+        for args in calls {
+            assert!(args.contains("Trigger::") || args.contains("trigger: bootstrap_history::Trigger"), "initialize_at({} without a trigger", args);
+        }
+
+        // A bootstrap that fails before it gets anywhere is recorded too
+        let dir = TempDir::new();
+        let not_a_dir = dir.path().join("file");
+        std::fs::write(&not_a_dir, b"").unwrap();
+        for trigger in [Trigger::User, Trigger::DataDirChange, Trigger::MigrationRollback] {
+            let code = crate::initialize_at(not_a_dir.to_string_lossy().into_owned(), trigger);
+            assert_eq!(code, crate::bootstrap::Failure::Storage.code());
+            let record = history().pop().unwrap();
+            assert_eq!(record["trigger"], trigger.as_str());
+            assert_eq!(record["outcome"], "storage_error");
+        }
+    }
+}
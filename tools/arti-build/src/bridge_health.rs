@@ -0,0 +1,587 @@
+//! Per-bridge health history
+//!
+//! A private bridge rarely dies at once: it gets slower and starts failing
+//! now and then first. For every configured bridge that carries traffic we
+//! keep `HISTORY_HOURS` of hourly buckets, each with the connect successes
+//! and failures through it and the median connect latency, for the settings
+//! screen (`getBridgeHistory`).
+//!
+//! A successful connect is counted for the bridge its circuit entered
+//! through. A failed one can't name its circuit, so it is counted for the
+//! only bridge of the active stage, or else for the bridge the last success
+//! went through; only errors that point at the path (a network timeout or a
+//! failed entry), not at the destination, are counted.
+//!
+//! A bridge is degrading when its last `RECENT_HOURS` are significantly
+//! worse than the rest of the history, its baseline: the success rate fell by
+//! more than `SUCCESS_RATE_DROP`, or the median of the hourly latency medians
+//! more than `LATENCY_FACTOR` times over. Both windows need a minimum of
+//! attempts first. Turning degrading logs a `BRIDGE_DEGRADING` event, once
+//! until the bridge recovers.
+//!
+//! The history is owned by the bookkeeping actor (see actor.rs). It is kept
+//! at the full retention level only, persisted in the wrapper state
+//! directory, and at most `MAX_BRIDGES` bridges are tracked (counted against
+//! the shared cache budget, see caches.rs).
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use arti_client::ErrorKind;
+use serde_json::{json, Map, Value};
+use tor_linkspec::{HasRelayIds, OwnedChanTarget};
+
+use crate::actor;
+use crate::caches::BoundedMap;
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+use crate::retention::{self, Sink};
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+/// Hours of history kept per bridge
+const HISTORY_HOURS: u64 = 7 * 24;
+
+/// Bridges tracked at most (least recently used ones are dropped)
+const MAX_BRIDGES: usize = 16;
+
+/// Latency samples kept for the median of the current hour
+const HOUR_SAMPLES: usize = 32;
+
+/// Hours compared against the baseline
+const RECENT_HOURS: u64 = 6;
+
+/// Attempts each window needs before the comparison is made
+const MIN_RECENT_ATTEMPTS: u32 = 10;
+const MIN_BASELINE_ATTEMPTS: u32 = 30;
+
+/// Drop of the success rate (absolute) that counts as degrading
+const SUCCESS_RATE_DROP: f64 = 0.2;
+
+/// Growth of the median connect latency that counts as degrading
+const LATENCY_FACTOR: f64 = 2.0;
+
+/// Persist at most this often while connections keep updating the history
+const SAVE_INTERVAL_MS: u64 = 10 * 60 * 1000;
+
+#[derive(Default)]
+struct Bucket {
+    /// Hours since the epoch
+    hour: u64,
+    successes: u32,
+    failures: u32,
+    median_ms: Option<u32>,
+    /// Latencies of this hour (not persisted; older buckets keep the median)
+    samples: Vec<u32>,
+}
+
+impl Bucket {
+    fn attempts(&self) -> u32 {
+        self.successes + self.failures
+    }
+
+    fn add_sample(&mut self, ms: u32) {
+        if self.samples.len() < HOUR_SAMPLES {
+            self.samples.push(ms);
+        } else {
+            // Spread over the hour rather than keeping its first samples only
+            let slot = self.successes as usize % HOUR_SAMPLES;
+            self.samples[slot] = ms;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        self.median_ms = sorted.get(sorted.len() / 2).copied();
+    }
+}
+
+/// How the recent window compares to the baseline
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Assessment {
+    recent_success_rate: Option<f64>,
+    baseline_success_rate: Option<f64>,
+    recent_median_ms: Option<u32>,
+    baseline_median_ms: Option<u32>,
+    degrading: bool,
+}
+
+/// Success rate and median of hourly medians over `buckets`
+fn summarize<'a>(buckets: impl Iterator<Item = &'a Bucket>) -> (u32, Option<f64>, Option<u32>) {
+    let (mut successes, mut attempts) = (0u32, 0u32);
+    let mut medians = Vec::new();
+    for bucket in buckets {
+        successes += bucket.successes;
+        attempts += bucket.attempts();
+        medians.extend(bucket.median_ms);
+    }
+    medians.sort_unstable();
+    let rate = (attempts > 0).then(|| successes as f64 / attempts as f64);
+    (attempts, rate, medians.get(medians.len() / 2).copied())
+}
+
+/// Compare the last `RECENT_HOURS` up to `now_hour` against the older buckets
+fn assess(buckets: &VecDeque<Bucket>, now_hour: u64) -> Assessment {
+    let recent_from = now_hour.saturating_sub(RECENT_HOURS - 1);
+    let (recent_attempts, recent_rate, recent_ms) = summarize(buckets.iter().filter(|b| b.hour >= recent_from));
+    let (baseline_attempts, baseline_rate, baseline_ms) = summarize(buckets.iter().filter(|b| b.hour < recent_from));
+
+    let comparable = recent_attempts >= MIN_RECENT_ATTEMPTS && baseline_attempts >= MIN_BASELINE_ATTEMPTS;
+    let rate_dropped = matches!((recent_rate, baseline_rate), (Some(r), Some(b)) if r < b - SUCCESS_RATE_DROP);
+    let slower = matches!((recent_ms, baseline_ms), (Some(r), Some(b)) if r as f64 > LATENCY_FACTOR * b as f64);
+    Assessment {
+        recent_success_rate: recent_rate,
+        baseline_success_rate: baseline_rate,
+        recent_median_ms: recent_ms,
+        baseline_median_ms: baseline_ms,
+        degrading: comparable && (rate_dropped || slower),
+    }
+}
+
+#[derive(Default)]
+struct Bridge {
+    /// Oldest first, at most one per hour, only hours with attempts
+    buckets: VecDeque<Bucket>,
+    degrading: bool,
+    updated_epoch_ms: u64,
+}
+
+impl Bridge {
+    /// Approximate memory held for this bridge under `fingerprint` (caches.rs)
+    fn approx_bytes(&self, fingerprint: &str) -> usize {
+        let samples: usize = self.buckets.iter().map(|b| 4 * b.samples.capacity()).sum();
+        fingerprint.len() + std::mem::size_of::<Bridge>() + self.buckets.len() * std::mem::size_of::<Bucket>() + samples
+    }
+
+    /// The bucket for `hour`, dropping those older than the history
+    fn bucket(&mut self, hour: u64) -> &mut Bucket {
+        while self.buckets.front().is_some_and(|b| b.hour + HISTORY_HOURS <= hour) {
+            self.buckets.pop_front();
+        }
+        if self.buckets.back().is_none_or(|b| b.hour != hour) {
+            self.buckets.push_back(Bucket { hour, ..Bucket::default() });
+        }
+        self.buckets.back_mut().expect("bucket was just ensured")
+    }
+
+    fn to_json(&self, fingerprint: &str, now_hour: u64) -> Value {
+        let assessment = assess(&self.buckets, now_hour);
+        let hours: Vec<Value> = self
+            .buckets
+            .iter()
+            .map(|b| {
+                json!({
+                    "hour_epoch_ms": b.hour * HOUR_MS,
+                    "attempts": b.attempts(),
+                    "success_rate": b.successes as f64 / b.attempts().max(1) as f64,
+                    "median_connect_ms": b.median_ms,
+                })
+            })
+            .collect();
+        json!({
+            "fingerprint": fingerprint,
+            "degrading": self.degrading,
+            "recent_success_rate": assessment.recent_success_rate,
+            "baseline_success_rate": assessment.baseline_success_rate,
+            "recent_median_connect_ms": assessment.recent_median_ms,
+            "baseline_median_connect_ms": assessment.baseline_median_ms,
+            "hours": hours,
+        })
+    }
+
+    /// `[[hour, successes, failures, median_ms], ...]`
+    fn to_stored(&self) -> Value {
+        Value::Array(self.buckets.iter().map(|b| json!([b.hour, b.successes, b.failures, b.median_ms])).collect())
+    }
+
+    fn from_stored(value: &Value) -> Option<Bridge> {
+        let buckets = value
+            .as_array()?
+            .iter()
+            .filter_map(|b| {
+                Some(Bucket {
+                    hour: b[0].as_u64()?,
+                    successes: u32::try_from(b[1].as_u64()?).ok()?,
+                    failures: u32::try_from(b[2].as_u64()?).ok()?,
+                    median_ms: b[3].as_u64().and_then(|ms| u32::try_from(ms).ok()),
+                    samples: Vec::new(),
+                })
+            })
+            .take(HISTORY_HOURS as usize)
+            .collect::<VecDeque<_>>();
+        let updated_epoch_ms = buckets.back().map_or(0, |b| b.hour * HOUR_MS);
+        Some(Bridge { buckets, degrading: false, updated_epoch_ms })
+    }
+}
+
+/// Every tracked bridge's history (owned by the actor)
+pub(crate) struct History {
+    bridges: BoundedMap<String, Bridge>,
+    /// Bridge the last successful connect went through
+    last_bridge: Option<String>,
+    file: Option<PathBuf>,
+    dirty: bool,
+    saved_epoch_ms: u64,
+}
+
+impl History {
+    pub(crate) fn new() -> Self {
+        History {
+            bridges: BoundedMap::new(
+                "bridge_history",
+                MAX_BRIDGES,
+                |fingerprint, bridge| bridge.approx_bytes(fingerprint),
+                |target| actor::post(move |state| state.bridge_health.bridges.trim(target)),
+            ),
+            last_bridge: None,
+            file: None,
+            dirty: false,
+            saved_epoch_ms: 0,
+        }
+    }
+
+    /// Count one outcome for `fingerprint` at `now` (epoch ms)
+    fn record(&mut self, fingerprint: &str, now: u64, latency_ms: Option<u32>) {
+        let hour = now / HOUR_MS;
+        self.bridges.update(fingerprint.to_string(), Bridge::default, |bridge| {
+            bridge.updated_epoch_ms = now;
+            let bucket = bridge.bucket(hour);
+            match latency_ms {
+                Some(ms) => {
+                    bucket.add_sample(ms);
+                    bucket.successes += 1;
+                }
+                None => bucket.failures += 1,
+            }
+
+            let assessment = assess(&bridge.buckets, hour);
+            if assessment.degrading && !bridge.degrading {
+                // The fingerprint identifies the user's private bridge: not logged
+                log_error!(
+                    "BRIDGE_DEGRADING: a configured bridge succeeded {:.0}% of the last {} hours against {:.0}% before, median connect {} ms against {} ms; see getBridgeHistory",
+                    100.0 * assessment.recent_success_rate.unwrap_or_default(),
+                    RECENT_HOURS,
+                    100.0 * assessment.baseline_success_rate.unwrap_or_default(),
+                    assessment.recent_median_ms.map_or("-".to_string(), |ms| ms.to_string()),
+                    assessment.baseline_median_ms.map_or("-".to_string(), |ms| ms.to_string()),
+                );
+            } else if !assessment.degrading && bridge.degrading {
+                log_info!("A configured bridge is no longer degrading");
+            }
+            bridge.degrading = assessment.degrading;
+        });
+        self.dirty = true;
+        if now.saturating_sub(self.saved_epoch_ms) >= SAVE_INTERVAL_MS {
+            self.save();
+        }
+    }
+
+    fn remove_file(&self) {
+        let Some(file) = self.file.as_ref() else {
+            return;
+        };
+        if let Err(e) = std::fs::remove_file(file) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log_error!("Failed to delete bridge history file: {}", e);
+            }
+        }
+    }
+
+    fn save(&mut self) {
+        let Some(file) = self.file.clone().filter(|_| retention::retains(Sink::BridgeHistory)) else {
+            return;
+        };
+        let bridges: Map<String, Value> = self.bridges.iter().map(|(k, b)| (k.clone(), b.to_stored())).collect();
+        let text = json!({ "v": 1, "bridges": bridges }).to_string();
+        match layout::write_atomic(&file, text.as_bytes()) {
+            Ok(()) => {
+                self.dirty = false;
+                self.saved_epoch_ms = now_epoch_ms();
+            }
+            Err(e) => log_error!("Failed to save bridge history: {}", e),
+        }
+    }
+
+    fn load(&mut self) {
+        let Some(file) = self.file.as_ref() else {
+            return;
+        };
+        let Ok(text) = std::fs::read_to_string(file) else {
+            return;
+        };
+        let Ok(stored) = serde_json::from_str::<Value>(&text) else {
+            log_error!("Ignoring unreadable bridge history file");
+            return;
+        };
+        let mut bridges: Vec<(String, Bridge)> = stored["bridges"]
+            .as_object()
+            .map(|m| m.iter().filter_map(|(k, v)| Some((k.clone(), Bridge::from_stored(v)?))).collect())
+            .unwrap_or_default();
+        // Least recently used first, so eviction order survives the restart
+        bridges.sort_by_key(|(_, bridge)| bridge.updated_epoch_ms);
+        self.bridges.clear();
+        for (fingerprint, bridge) in bridges {
+            self.bridges.insert(fingerprint, bridge);
+        }
+    }
+}
+
+/// Errors that point at the path through the bridge rather than the destination
+fn is_path_failure(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::TorNetworkTimeout | ErrorKind::TorAccessFailed)
+}
+
+/// Record a successful connect through `entry` that took `latency`
+pub(crate) fn connected(entry: Option<&OwnedChanTarget>, latency: Duration) {
+    if !retention::retains(Sink::BridgeHistory) {
+        return;
+    }
+    let Some(fingerprint) = entry.and_then(|e| e.rsa_identity()).map(|id| crate::geo::fingerprint(id.as_bytes())) else {
+        return;
+    };
+    let now = now_epoch_ms();
+    let latency_ms = u32::try_from(latency.as_millis()).unwrap_or(u32::MAX);
+    actor::post(move |state| {
+        // Only configured bridges are tracked, not ordinary guards
+        if !crate::bridges::active_fingerprints().contains(&fingerprint) {
+            return;
+        }
+        state.bridge_health.record(&fingerprint, now, Some(latency_ms));
+        state.bridge_health.last_bridge = Some(fingerprint);
+    });
+}
+
+/// Record a failed connect
+pub(crate) fn failed(kind: ErrorKind) {
+    if !is_path_failure(kind) || !retention::retains(Sink::BridgeHistory) {
+        return;
+    }
+    let now = now_epoch_ms();
+    actor::post(move |state| {
+        let active = crate::bridges::active_fingerprints();
+        let fingerprint = match active.as_slice() {
+            [only] => Some(only.clone()),
+            _ => state.bridge_health.last_bridge.clone().filter(|f| active.contains(f)),
+        };
+        if let Some(fingerprint) = fingerprint {
+            state.bridge_health.record(&fingerprint, now, None);
+        }
+    });
+}
+
+/// The history of every tracked bridge as JSON (`{"bridges":[...]}`)
+pub(crate) fn history_json() -> String {
+    let now_hour = now_epoch_ms() / HOUR_MS;
+    actor::query("getBridgeHistory", "{\"bridges\":[]}".to_string(), move |state| {
+        let mut bridges: Vec<(&String, &Bridge)> = state.bridge_health.bridges.iter().collect();
+        bridges.sort_by(|a, b| a.0.cmp(b.0));
+        let bridges: Vec<Value> = bridges.into_iter().map(|(f, b)| b.to_json(f, now_hour)).collect();
+        json!({ "bridges": bridges }).to_string()
+    })
+}
+
+/// Use `data_dir` for the persisted history, loading it
+pub(crate) fn open(data_dir: &Path) {
+    let file = layout::path(data_dir, Purpose::BridgeHistory);
+    actor::post(move |state| {
+        let history = &mut state.bridge_health;
+        history.file = Some(file);
+        if retention::retains(Sink::BridgeHistory) {
+            history.load();
+        }
+    });
+}
+
+/// Forget the history and delete its file (retention.rs)
+pub(crate) fn clear() {
+    actor::post(|state| {
+        let history = &mut state.bridge_health;
+        history.bridges.clear();
+        history.last_bridge = None;
+        history.dirty = false;
+        history.remove_file();
+    });
+}
+
+/// Write out pending changes, waiting up to `timeout` (called on stop)
+pub(crate) fn flush(timeout: Duration) -> bool {
+    actor::ask(timeout, |state| {
+        if state.bridge_health.dirty {
+            state.bridge_health.save();
+        }
+    })
+    .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const FP: &str = "AAAABBBBCCCCDDDDEEEEFFFF0000111122223333";
+    const NOW_HOUR: u64 = 480_000;
+
+    /// One hour of synthetic history
+    fn hour(hour: u64, successes: u32, failures: u32, median_ms: Option<u32>) -> Bucket {
+        Bucket { hour, successes, failures, median_ms, samples: Vec::new() }
+    }
+
+    /// `baseline` hours before the recent window, then `recent` hours up to `NOW_HOUR`,
+    /// each with the given successes, failures and median
+    fn synthetic(baseline: (u64, u32, u32, u32), recent: (u64, u32, u32, u32)) -> VecDeque<Bucket> {
+        let first_recent = NOW_HOUR + 1 - recent.0;
+        let baseline_hours = (first_recent - baseline.0..first_recent).map(|h| hour(h, baseline.1, baseline.2, Some(baseline.3)));
+        let recent_hours = (first_recent..=NOW_HOUR).map(|h| hour(h, recent.1, recent.2, Some(recent.3)));
+        baseline_hours.chain(recent_hours).collect()
+    }
+
+    fn bridge<'a>(history: &'a History, fingerprint: &str) -> Option<&'a Bridge> {
+        history.bridges.get(&fingerprint.to_string())
+    }
+
+    fn lines_with(marker: &str) -> usize {
+        crate::tests::SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains(marker)).count()
+    }
+
+    #[test]
+    fn degradation_compares_the_recent_window_with_the_baseline() {
+        for (name, buckets, degrading) in [
+            ("steady", synthetic((48, 10, 0, 400), (6, 5, 0, 400)), false),
+            ("success rate fell by more than the threshold", synthetic((48, 10, 0, 400), (6, 3, 2, 400)), true),
+            ("success rate fell by exactly the threshold", synthetic((48, 10, 0, 400), (6, 4, 1, 400)), false),
+            ("latency more than doubled", synthetic((48, 10, 0, 400), (6, 5, 0, 801)), true),
+            ("latency exactly doubled", synthetic((48, 10, 0, 400), (6, 5, 0, 800)), false),
+            ("a better recent window", synthetic((48, 5, 5, 900), (6, 5, 0, 300)), false),
+            ("too few recent attempts", synthetic((48, 10, 0, 400), (1, 0, 9, 4000)), false),
+            ("too few baseline attempts", synthetic((2, 10, 0, 400), (6, 0, 5, 4000)), false),
+            ("no baseline at all", synthetic((0, 0, 0, 0), (6, 0, 5, 4000)), false),
+        ] {
+            assert_eq!(assess(&buckets, NOW_HOUR).degrading, degrading, "{}", name);
+        }
+
+        let assessment = assess(&synthetic((48, 8, 2, 400), (6, 1, 1, 900)), NOW_HOUR);
+        assert_eq!(assessment.baseline_success_rate, Some(0.8));
+        assert_eq!(assessment.recent_success_rate, Some(0.5));
+        assert_eq!((assessment.baseline_median_ms, assessment.recent_median_ms), (Some(400), Some(900)));
+    }
+
+    #[test]
+    fn outcomes_are_bucketed_by_hour_within_the_history() {
+        let mut history = History::new();
+        let start = NOW_HOUR * HOUR_MS;
+        for (offset_ms, latency) in [(0, Some(300)), (1000, Some(100)), (2000, None), (HOUR_MS, Some(500))] {
+            history.record(FP, start + offset_ms, latency);
+        }
+        let tracked = bridge(&history, FP).unwrap();
+        let buckets: Vec<_> = tracked.buckets.iter().map(|b| (b.hour, b.successes, b.failures, b.median_ms)).collect();
+        assert_eq!(buckets, [(NOW_HOUR, 2, 1, Some(300)), (NOW_HOUR + 1, 1, 0, Some(500))]);
+
+        // Samples are capped per hour; the median follows the kept ones
+        for i in 0..(3 * HOUR_SAMPLES as u32) {
+            history.record(FP, start + HOUR_MS + 10, Some(1000 + i));
+        }
+        let last = bridge(&history, FP).unwrap().buckets.back().unwrap();
+        assert_eq!(last.samples.len(), HOUR_SAMPLES);
+        assert_eq!(last.successes, 1 + 3 * HOUR_SAMPLES as u32);
+        assert!(last.median_ms.unwrap() >= 1000);
+
+        // A week later only the new hour is left
+        history.record(FP, start + HISTORY_HOURS * HOUR_MS + HOUR_MS, None);
+        let hours: Vec<_> = bridge(&history, FP).unwrap().buckets.iter().map(|b| b.hour).collect();
+        assert_eq!(hours, [NOW_HOUR + 1 + HISTORY_HOURS]);
+    }
+
+    #[test]
+    fn degrading_is_logged_once_until_the_bridge_recovers() {
+        let _serial = testing::serial();
+        let mut history = History::new();
+        let start = (NOW_HOUR - 20) * HOUR_MS;
+        for h in 0..14 {
+            for _ in 0..5 {
+                history.record(FP, start + h * HOUR_MS, Some(200));
+            }
+        }
+        let (degrading, recovered) = (lines_with("BRIDGE_DEGRADING"), lines_with("no longer degrading"));
+        for h in 14..20 {
+            for _ in 0..5 {
+                history.record(FP, start + h * HOUR_MS, None);
+            }
+        }
+        assert!(bridge(&history, FP).unwrap().degrading);
+        assert_eq!(lines_with("BRIDGE_DEGRADING"), degrading + 1);
+        // The private bridge's fingerprint is never logged
+        assert!(!crate::tests::SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains(FP)));
+
+        for _ in 0..200 {
+            history.record(FP, start + 19 * HOUR_MS, Some(200));
+        }
+        assert!(!bridge(&history, FP).unwrap().degrading);
+        assert_eq!(lines_with("no longer degrading"), recovered + 1);
+        assert_eq!(lines_with("BRIDGE_DEGRADING"), degrading + 1);
+    }
+
+    #[test]
+    fn history_persists_compactly_and_reloads() {
+        let _serial = testing::serial();
+        let dir = testing::TempDir::new();
+        let file = layout::path(dir.path(), Purpose::BridgeHistory);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+
+        let mut history = History::new();
+        history.file = Some(file.clone());
+        let start = NOW_HOUR * HOUR_MS;
+        history.record("OLDER", start, Some(700));
+        history.record(FP, start, Some(250));
+        history.record(FP, start + 1, None);
+        history.record(FP, start + HOUR_MS, Some(300));
+        history.save();
+
+        let stored: Value = serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(
+            stored,
+            json!({"v": 1, "bridges": {
+                "OLDER": [[NOW_HOUR, 1, 0, 700]],
+                FP: [[NOW_HOUR, 1, 1, 250], [NOW_HOUR + 1, 1, 0, 300]],
+            }})
+        );
+
+        let mut reloaded = History::new();
+        reloaded.file = Some(file.clone());
+        reloaded.load();
+        for fingerprint in [FP, "OLDER"] {
+            assert_eq!(
+                bridge(&reloaded, fingerprint).unwrap().to_json(fingerprint, NOW_HOUR + 1),
+                bridge(&history, fingerprint).unwrap().to_json(fingerprint, NOW_HOUR + 1),
+            );
+        }
+        // Latency samples are not persisted, only each hour's median
+        assert!(bridge(&reloaded, FP).unwrap().buckets.iter().all(|b| b.samples.is_empty()));
+        // Least recently updated first, as before the restart: the first evicted
+        for i in 1..MAX_BRIDGES {
+            reloaded.record(&format!("NEW{}", i), start + 2 * HOUR_MS, None);
+        }
+        assert!(bridge(&reloaded, "OLDER").is_none());
+        assert!(bridge(&reloaded, FP).is_some());
+
+        // Unreadable entries are skipped, an unreadable file is ignored
+        std::fs::write(&file, json!({"v": 1, "bridges": {"A": [[1, 2, 3, null], ["x"]], "B": "junk"}}).to_string()).unwrap();
+        reloaded.load();
+        assert_eq!(bridge(&reloaded, "A").unwrap().to_stored(), json!([[1, 2, 3, null]]));
+        assert!(bridge(&reloaded, "B").is_none());
+        std::fs::write(&file, "not json").unwrap();
+        reloaded.load();
+        assert!(bridge(&reloaded, "A").is_some());
+    }
+
+    #[test]
+    fn only_path_failures_count_against_a_bridge() {
+        for (kind, counts) in [
+            (ErrorKind::TorNetworkTimeout, true),
+            (ErrorKind::TorAccessFailed, true),
+            (ErrorKind::RemoteConnectionRefused, false),
+            (ErrorKind::RemoteHostNotFound, false),
+            (ErrorKind::ExitTimeout, false),
+        ] {
+            assert_eq!(is_path_failure(kind), counts, "{:?}", kind);
+        }
+    }
+}
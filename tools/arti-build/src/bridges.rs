@@ -0,0 +1,488 @@
+//! Bridge lines and transport priority
+//!
+//! Bridge lines are configured as C Tor style lines
+//! (`[transport] host:port FINGERPRINT [key=value ...]`); a line without a
+//! transport name is a plain bridge, reported as transport `direct`.
+//! Pluggable transport binaries are configured per transport name.
+//!
+//! Arti picks among configured bridges itself and has no notion of transport
+//! preference, so a priority order is honored by bootstrapping in stages:
+//! first with only the bridges of the highest-priority transport, falling
+//! back to the next transport if that attempt fails or doesn't finish within
+//! `ATTEMPT_TIMEOUT`, and finally with every bridge whose transport isn't in
+//! the priority list. The last stage has no timeout of its own.
+//!
+//! The stage that bootstrapped is remembered so the user can tell which
+//! bridge worked. Arti doesn't expose which configured bridge its guard
+//! manager picked, so the answer is exact only when that stage held a single
+//! bridge; otherwise every bridge of the stage is reported as a candidate.
+//! Bridges are identified by fingerprint only, never by address.
+//!
+//! Builds without the `bridges` feature refuse bridge lines, and builds
+//! without `pluggable-transports` refuse transport lines and binaries, so a
+//! slimmed library fails at the setting rather than at bootstrap.
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(feature = "pluggable-transports")]
+use arti_client::config::{pt::TransportConfigBuilder, CfgPath};
+use arti_client::config::{BoolOrAuto, BridgeConfigBuilder, ConfigBuildError, TorClientConfigBuilder};
+use serde_json::{Map, Value};
+
+use crate::{features, json_string, seams};
+
+/// How long a non-final bootstrap stage may take before falling back
+pub(crate) const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Transport name reported for bridges without a pluggable transport
+pub(crate) const DIRECT: &str = "direct";
+
+/// Bridges of the stage that bootstrapped the current client
+struct Active {
+    transport: String,
+    fingerprints: Vec<String>,
+}
+
+static ACTIVE: Mutex<Option<Active>> = Mutex::new(None);
+
+/// Transport name of a bridge line
+pub(crate) fn transport_of(line: &str) -> &str {
+    match line.split_whitespace().next() {
+        // A transport name is a bare identifier; plain bridges start with an address
+        Some(first) if first.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => first,
+        _ => DIRECT,
+    }
+}
+
+/// Relay fingerprint of a bridge line (uppercase hex), if it has one
+fn fingerprint_of(line: &str) -> Option<String> {
+    line.split_whitespace()
+        .map(|token| token.trim_start_matches('$'))
+        .find(|token| token.len() == 40 && token.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_ascii_uppercase)
+}
+
+/// Remember `group` as the stage that bootstrapped (empty: no bridges in use)
+pub(crate) fn set_active(group: &[String]) {
+    *ACTIVE.lock().unwrap() = group.first().map(|first| Active {
+        transport: transport_of(first).to_string(),
+        fingerprints: group.iter().map(|line| fingerprint_of(line).unwrap_or_else(|| "unknown".to_string())).collect(),
+    });
+}
+
+/// Transport of the active stage (`DIRECT` for plain bridges), or None if
+/// the client bootstrapped without bridges
+pub(crate) fn active_transport() -> Option<String> {
+    ACTIVE.lock().unwrap().as_ref().map(|active| active.transport.clone())
+}
+
+/// Fingerprints of the bridges of the active stage (those that have one)
+pub(crate) fn active_fingerprints() -> Vec<String> {
+    ACTIVE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|active| active.fingerprints.iter().filter(|f| *f != "unknown").cloned().collect())
+        .unwrap_or_default()
+}
+
+/// The bridge in use as JSON, or `null` when bootstrapped without bridges
+///
+/// `{"transport":..,"bridge":..,"candidates":[..]}` where `bridge` is the
+/// fingerprint when known exactly and null when several candidates remain.
+pub(crate) fn active_json() -> String {
+    let active = ACTIVE.lock().unwrap();
+    let Some(active) = active.as_ref() else {
+        return "null".to_string();
+    };
+    let bridge = match active.fingerprints.as_slice() {
+        [only] => json_string(only),
+        _ => "null".to_string(),
+    };
+    let candidates = active
+        .fingerprints
+        .iter()
+        .map(|f| json_string(f))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"transport\":{},\"bridge\":{},\"candidates\":[{}]}}",
+        json_string(&active.transport),
+        bridge,
+        candidates,
+    )
+}
+
+/// Check a `bridges` setting value: an array of parseable bridge lines, of
+/// a kind this build supports
+pub(crate) fn check_lines(value: &Value) -> Result<(), String> {
+    let lines = value.as_array().ok_or("expected an array of bridge lines")?;
+    if !lines.is_empty() && !features::enabled("bridges") {
+        return Err("this build lacks the `bridges` feature".into());
+    }
+    for line in lines {
+        let line = line.as_str().ok_or("expected an array of bridge lines")?;
+        line.parse::<BridgeConfigBuilder>()
+            .map_err(|e| format!("invalid bridge line: {}", e))?;
+        if transport_of(line) != DIRECT && !features::enabled("pluggable-transports") {
+            return Err(format!("{} bridges need the `pluggable-transports` feature", transport_of(line)));
+        }
+    }
+    Ok(())
+}
+
+/// Check a `transport_priority` setting value: distinct transport names
+pub(crate) fn check_priority(value: &Value) -> Result<(), String> {
+    let names = value.as_array().ok_or("expected an array of transport names")?;
+    let mut seen = Vec::new();
+    for name in names {
+        let name = name.as_str().filter(|n| !n.is_empty()).ok_or("expected an array of transport names")?;
+        if seen.contains(&name) {
+            return Err(format!("{} is listed twice", name));
+        }
+        seen.push(name);
+    }
+    Ok(())
+}
+
+/// Check a `pt_binaries` setting value: transport name -> absolute binary path
+pub(crate) fn check_binaries(value: &Value) -> Result<(), String> {
+    let binaries = value.as_object().ok_or("expected an object of transport -> binary path")?;
+    if !binaries.is_empty() && !features::enabled("pluggable-transports") {
+        return Err("this build lacks the `pluggable-transports` feature".into());
+    }
+    for (name, path) in binaries {
+        match path.as_str() {
+            Some(p) if p.starts_with('/') => {}
+            _ => return Err(format!("binary path for {} must be absolute", name)),
+        }
+    }
+    Ok(())
+}
+
+/// Transports in `priority` that no bridge in `lines` uses
+pub(crate) fn unconfigured<'a>(lines: &[String], priority: &'a [String]) -> Vec<&'a str> {
+    priority
+        .iter()
+        .filter(|name| !lines.iter().any(|line| transport_of(line) == name.as_str()))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Bridge groups to bootstrap with, in order
+///
+/// One group per prioritized transport that has bridges, then one with all
+/// remaining bridges. With no bridges this is a single empty group (a normal
+/// bootstrap without bridges).
+pub(crate) fn attempt_order(lines: &[String], priority: &[String]) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = priority
+        .iter()
+        .map(|name| lines.iter().filter(|l| transport_of(l) == name).cloned().collect::<Vec<_>>())
+        .filter(|group| !group.is_empty())
+        .collect();
+
+    let rest: Vec<String> = lines
+        .iter()
+        .filter(|l| !priority.iter().any(|name| transport_of(l) == name))
+        .cloned()
+        .collect();
+    if !rest.is_empty() || groups.is_empty() {
+        groups.push(rest);
+    }
+    groups
+}
+
+/// Configure `builder` to use exactly `group` (no-op for an empty group)
+pub(crate) fn apply(
+    builder: &mut TorClientConfigBuilder,
+    group: &[String],
+    binaries: &Map<String, Value>,
+) -> Result<(), ConfigBuildError> {
+    if group.is_empty() {
+        return Ok(());
+    }
+
+    let bridges = builder.bridges();
+    bridges.enabled(BoolOrAuto::Explicit(true));
+    for line in group {
+        bridges.bridges().push(line.parse()?);
+    }
+
+    #[cfg(not(feature = "pluggable-transports"))]
+    let _ = binaries;
+    #[cfg(feature = "pluggable-transports")]
+    for (name, path) in binaries {
+        if !group.iter().any(|line| transport_of(line) == name) {
+            continue;
+        }
+        let mut transport = TransportConfigBuilder::default();
+        transport
+            .protocols(vec![name.parse()?])
+            .path(CfgPath::new(path.as_str().unwrap_or_default().to_string()))
+            .run_on_startup(false);
+        bridges.transports().push(transport);
+    }
+    Ok(())
+}
+
+/// Run the bootstrap `attempts`, one per stage of `stages`, until one succeeds
+///
+/// Every stage but the last gets `ATTEMPT_TIMEOUT`. The stage that succeeded
+/// becomes the active one; if none does, the last failure is returned.
+pub(crate) async fn bootstrap_in_stages<C, E, Fut>(
+    stages: &[Vec<String>],
+    attempts: impl IntoIterator<Item = Fut>,
+) -> anyhow::Result<C>
+where
+    Fut: Future<Output = Result<C, E>>,
+    E: Into<anyhow::Error>,
+{
+    set_active(&[]);
+    let mut last_error = None;
+    for ((index, group), attempt) in stages.iter().enumerate().zip(attempts) {
+        if !group.is_empty() {
+            log_info!("Bootstrapping with {} {} bridge(s)", group.len(), transport_of(&group[0]));
+        }
+        let outcome = if index + 1 < stages.len() {
+            tokio::select! {
+                outcome = attempt => outcome.map_err(Into::into),
+                _ = seams::sleep(ATTEMPT_TIMEOUT) => Err(anyhow::anyhow!("bootstrap attempt timed out")),
+            }
+        } else {
+            attempt.await.map_err(Into::into)
+        };
+        match outcome {
+            Ok(client) => {
+                set_active(group);
+                return Ok(client);
+            }
+            Err(e) => {
+                log_error!("Bootstrap stage {} of {} failed: {}", index + 1, stages.len(), e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no bootstrap attempted")))
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&ACTIVE);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, FakeTime};
+    use crate::config;
+
+    const OBFS4_A: &str = "obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AAAA iat-mode=0";
+    const OBFS4_B: &str = "obfs4 192.0.2.2:443 1123456789ABCDEF0123456789ABCDEF01234567 cert=BBBB iat-mode=0";
+    const SNOWFLAKE: &str = "snowflake 192.0.2.3:80 2B280B23E1107BB62ABFC40DDCC8824814F80A72 url=https://example.com/";
+    const PLAIN: &str = "192.0.2.4:9001 $3123456789abcdef0123456789abcdef01234567";
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn bridge_lines_are_classified() {
+        for (line, transport, fingerprint) in [
+            (OBFS4_A, "obfs4", Some("0123456789ABCDEF0123456789ABCDEF01234567")),
+            (SNOWFLAKE, "snowflake", Some("2B280B23E1107BB62ABFC40DDCC8824814F80A72")),
+            (PLAIN, DIRECT, Some("3123456789ABCDEF0123456789ABCDEF01234567")),
+            ("[2001:db8::1]:443", DIRECT, None),
+            ("meek_lite 192.0.2.5:80 url=https://example.com/", "meek_lite", None),
+        ] {
+            assert_eq!(transport_of(line), transport, "{}", line);
+            assert_eq!(fingerprint_of(line).as_deref(), fingerprint, "{}", line);
+        }
+    }
+
+    #[test]
+    fn stages_follow_the_priority() {
+        let all = lines(&[OBFS4_A, PLAIN, SNOWFLAKE, OBFS4_B]);
+        for (priority, expected) in [
+            (vec![], vec![lines(&[OBFS4_A, PLAIN, SNOWFLAKE, OBFS4_B])]),
+            (vec!["snowflake"], vec![lines(&[SNOWFLAKE]), lines(&[OBFS4_A, PLAIN, OBFS4_B])]),
+            (
+                vec!["obfs4", "snowflake"],
+                vec![lines(&[OBFS4_A, OBFS4_B]), lines(&[SNOWFLAKE]), lines(&[PLAIN])],
+            ),
+            (
+                vec!["direct", "obfs4", "snowflake"],
+                vec![lines(&[PLAIN]), lines(&[OBFS4_A, OBFS4_B]), lines(&[SNOWFLAKE])],
+            ),
+            // A listed transport without bridges has no stage
+            (vec!["meek_lite", "snowflake"], vec![lines(&[SNOWFLAKE]), lines(&[OBFS4_A, PLAIN, OBFS4_B])]),
+        ] {
+            let priority = lines(&priority);
+            assert_eq!(attempt_order(&all, &priority), expected, "{:?}", priority);
+        }
+        // No bridges: one plain bootstrap
+        assert_eq!(attempt_order(&[], &lines(&["obfs4"])), vec![Vec::<String>::new()]);
+        assert_eq!(unconfigured(&all, &lines(&["obfs4", "meek_lite", "direct"])), ["meek_lite"]);
+    }
+
+    #[test]
+    fn settings_need_the_features_they_use() {
+        let bridges = features::enabled("bridges");
+        let transports = features::enabled("pluggable-transports");
+        // (check, value, accepted)
+        for (check, value, ok) in [
+            (check_lines as fn(&Value) -> Result<(), String>, json!([]), true),
+            (check_lines, json!([PLAIN]), bridges),
+            (check_lines, json!([PLAIN, OBFS4_A]), bridges && transports),
+            (check_lines, json!([SNOWFLAKE]), bridges && transports),
+            (check_binaries, json!({}), true),
+            (check_binaries, json!({"obfs4": "/data/obfs4proxy"}), transports),
+            (check_binaries, json!({"obfs4": "obfs4proxy"}), false),
+        ] {
+            let checked = check(&value);
+            assert_eq!(checked.is_ok(), ok, "{} gave {:?}", value, checked);
+        }
+        if !bridges {
+            assert_eq!(check_lines(&json!([PLAIN])).unwrap_err(), "this build lacks the `bridges` feature");
+        } else if !transports {
+            assert_eq!(check_lines(&json!([OBFS4_A])).unwrap_err(), "obfs4 bridges need the `pluggable-transports` feature");
+        }
+    }
+
+    #[test]
+    fn priority_values_are_checked() {
+        for (value, ok) in [
+            (json!([]), true),
+            (json!(["obfs4", "snowflake"]), true),
+            (json!(["obfs4", "obfs4"]), false),
+            (json!([""]), false),
+            (json!([1]), false),
+            (json!("obfs4"), false),
+        ] {
+            assert_eq!(check_priority(&value).is_ok(), ok, "{}", value);
+        }
+    }
+
+    #[test]
+    fn priority_is_reflected_in_the_applied_config() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        if !features::enabled("pluggable-transports") {
+            assert!(config::set("bridges", json!([OBFS4_A])).is_err());
+            return;
+        }
+
+        config::set("bridges", json!([OBFS4_A, SNOWFLAKE, PLAIN])).unwrap();
+        config::set("transport_priority", json!(["snowflake", "obfs4"])).unwrap();
+        config::set("pt_binaries", json!({"obfs4": "/data/obfs4proxy", "snowflake": "/data/snowflake"})).unwrap();
+        // Not in effect until the next initialize commits it
+        assert!(config::strings(&config::applied("transport_priority")).is_empty());
+        config::commit_pending();
+
+        let stages = attempt_order(
+            &config::strings(&config::applied("bridges")),
+            &config::strings(&config::applied("transport_priority")),
+        );
+        assert_eq!(stages, vec![lines(&[SNOWFLAKE]), lines(&[OBFS4_A]), lines(&[PLAIN])]);
+        let binaries = config::applied("pt_binaries").as_object().cloned().unwrap();
+        for stage in &stages {
+            apply(&mut TorClientConfigBuilder::default(), stage, &binaries).unwrap();
+        }
+    }
+
+    /// How a fake bootstrap through one stage goes
+    #[derive(Clone, Copy, Debug)]
+    enum Fake {
+        Works,
+        Fails,
+        Hangs,
+    }
+
+    /// Bootstrap in stages with `fakes[i]` standing in for stage `i`
+    fn fake_bootstrap(stages: &[Vec<String>], fakes: &[Fake], time: Option<&FakeTime>) -> anyhow::Result<usize> {
+        let runtime = testing::runtime();
+        let attempts: Vec<_> = fakes
+            .iter()
+            .enumerate()
+            .map(|(index, fake)| {
+                let fake = *fake;
+                async move {
+                    match fake {
+                        Fake::Works => Ok(index),
+                        Fake::Fails => Err(anyhow::anyhow!("bridge unreachable")),
+                        Fake::Hangs => std::future::pending().await,
+                    }
+                }
+            })
+            .collect();
+        let stages = stages.to_vec();
+        let bootstrap = runtime.spawn(async move { bootstrap_in_stages(&stages, attempts).await });
+        if let Some(time) = time {
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!bootstrap.is_finished());
+            time.advance(ATTEMPT_TIMEOUT);
+        }
+        runtime.block_on(bootstrap).unwrap()
+    }
+
+    #[test]
+    fn the_working_bridge_is_reported_not_the_dead_one() {
+        let _serial = testing::serial();
+        let saved = ACTIVE.lock().unwrap().take();
+        let time = FakeTime::start();
+        // Prioritized one per stage, as setTransportPriority arranges them
+        let stages = attempt_order(&lines(&[OBFS4_A, SNOWFLAKE]), &lines(&["obfs4", "snowflake"]));
+        let working = json!({
+            "transport": "snowflake",
+            "bridge": "2B280B23E1107BB62ABFC40DDCC8824814F80A72",
+            "candidates": ["2B280B23E1107BB62ABFC40DDCC8824814F80A72"],
+        });
+
+        for (fakes, paced) in [
+            // The dead bridge refuses, or never answers until the stage times out
+            ([Fake::Fails, Fake::Works], false),
+            ([Fake::Hangs, Fake::Works], true),
+        ] {
+            set_active(&lines(&[OBFS4_B]));
+            assert_eq!(fake_bootstrap(&stages, &fakes, paced.then_some(&time)).unwrap(), 1, "{:?}", fakes);
+            assert_eq!(serde_json::from_str::<Value>(&active_json()).unwrap(), working, "{:?}", fakes);
+        }
+
+        // With the live bridge first the dead one is never tried
+        let reversed = attempt_order(&lines(&[OBFS4_A, SNOWFLAKE]), &lines(&["snowflake", "obfs4"]));
+        assert_eq!(fake_bootstrap(&reversed, &[Fake::Works, Fake::Hangs], None).unwrap(), 0);
+        assert_eq!(serde_json::from_str::<Value>(&active_json()).unwrap(), working);
+
+        // Nothing worked: no bridge is reported
+        let error = fake_bootstrap(&stages, &[Fake::Fails, Fake::Fails], None).unwrap_err();
+        assert_eq!(error.to_string(), "bridge unreachable");
+        assert_eq!(active_json(), "null");
+
+        *ACTIVE.lock().unwrap() = saved;
+    }
+
+    #[test]
+    fn the_bootstrapped_stage_is_reported() {
+        let _serial = testing::serial();
+        let saved = ACTIVE.lock().unwrap().take();
+
+        assert_eq!(active_json(), "null");
+        set_active(&lines(&[SNOWFLAKE]));
+        assert_eq!(active_transport().as_deref(), Some("snowflake"));
+        let active: Value = serde_json::from_str(&active_json()).unwrap();
+        assert_eq!(active, json!({"transport": "snowflake", "bridge": "2B280B23E1107BB62ABFC40DDCC8824814F80A72", "candidates": ["2B280B23E1107BB62ABFC40DDCC8824814F80A72"]}));
+
+        // Several bridges leave the one in use open; one without a fingerprint isn't a candidate
+        set_active(&lines(&[OBFS4_A, "obfs4 192.0.2.9:443 cert=CCCC"]));
+        let active: Value = serde_json::from_str(&active_json()).unwrap();
+        assert_eq!(active["bridge"], Value::Null);
+        assert_eq!(active_fingerprints(), ["0123456789ABCDEF0123456789ABCDEF01234567"]);
+
+        set_active(&[]);
+        assert_eq!(active_transport(), None);
+        *ACTIVE.lock().unwrap() = saved;
+    }
+}
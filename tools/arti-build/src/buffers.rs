@@ -0,0 +1,182 @@
+//! Global budget for relay buffer memory
+//!
+//! Every proxied connection needs one buffer per direction. Leases are taken
+//! from a process-wide budget so that many concurrent connections can't grow
+//! the buffering layer without bound: when the budget is tight new connections
+//! fall back to small buffers, and when even those don't fit they wait briefly
+//! for another connection to release its lease.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+/// Per-direction buffer size when the budget allows it
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Per-direction buffer size used under budget pressure
+pub(crate) const MIN_BUFFER_SIZE: usize = 2 * 1024;
+
+/// Default cap on total relay buffer memory
+pub(crate) const DEFAULT_BUDGET_BYTES: usize = 8 * 1024 * 1024;
+
+/// How long a new connection may wait for budget before giving up
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+static BUDGET_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_BUDGET_BYTES);
+static IN_USE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static RELEASED: Notify = Notify::const_new();
+
+/// Buffer memory reserved for one connection, returned to the budget on drop
+pub(crate) struct BufferLease {
+    bytes: usize,
+    /// Size of each per-direction buffer covered by this lease
+    pub buffer_size: usize,
+}
+
+impl Drop for BufferLease {
+    fn drop(&mut self) {
+        IN_USE_BYTES.fetch_sub(self.bytes, Ordering::AcqRel);
+        RELEASED.notify_waiters();
+    }
+}
+
+/// Set the cap on total relay buffer memory
+///
+/// Returns false if `bytes` can't hold even one minimum-size connection.
+/// Leases already handed out are unaffected; the cap applies to new ones.
+pub(crate) fn set_budget(bytes: usize) -> bool {
+    if bytes < 2 * MIN_BUFFER_SIZE {
+        return false;
+    }
+    BUDGET_BYTES.store(bytes, Ordering::Relaxed);
+    true
+}
+
+/// Bytes currently leased to connections
+pub(crate) fn in_use() -> usize {
+    IN_USE_BYTES.load(Ordering::Relaxed)
+}
+
+fn try_reserve(bytes: usize) -> bool {
+    let budget = BUDGET_BYTES.load(Ordering::Relaxed);
+    IN_USE_BYTES
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            (used + bytes <= budget).then_some(used + bytes)
+        })
+        .is_ok()
+}
+
+fn try_lease() -> Option<BufferLease> {
+    [DEFAULT_BUFFER_SIZE, MIN_BUFFER_SIZE].into_iter().find_map(|buffer_size| {
+        let bytes = 2 * buffer_size;
+        // Lazily: a lease that was never reserved must not be dropped
+        try_reserve(bytes).then(|| BufferLease { bytes, buffer_size })
+    })
+}
+
+/// Lease buffers for both directions of a new connection
+///
+/// Returns None if no budget frees up within the queueing timeout.
+pub(crate) async fn acquire() -> Option<BufferLease> {
+    let timeout = crate::seams::sleep(QUEUE_TIMEOUT);
+    tokio::pin!(timeout);
+    loop {
+        // Register for release notifications before checking, so a release
+        // between the check and the wait isn't missed
+        let released = RELEASED.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+
+        if let Some(lease) = try_lease() {
+            if lease.buffer_size < DEFAULT_BUFFER_SIZE {
+                crate::caches::relieve_buffer_pressure();
+            }
+            return Some(lease);
+        }
+        crate::caches::relieve_buffer_pressure();
+        tokio::select! {
+            _ = &mut released => {}
+            _ = &mut timeout => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    /// Run `f` with the budget set to `bytes`, then restore the default
+    fn with_budget(bytes: usize, f: impl FnOnce()) {
+        assert!(set_budget(bytes));
+        f();
+        set_budget(DEFAULT_BUDGET_BYTES);
+        assert_eq!(in_use(), 0, "a lease outlived its test");
+    }
+
+    #[test]
+    fn budget_must_fit_one_small_connection() {
+        let _serial = testing::serial();
+        assert!(!set_budget(0));
+        assert!(!set_budget(2 * MIN_BUFFER_SIZE - 1));
+        assert!(set_budget(2 * MIN_BUFFER_SIZE));
+        assert!(set_budget(DEFAULT_BUDGET_BYTES));
+    }
+
+    #[test]
+    fn tight_budget_falls_back_to_small_buffers() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        with_budget(2 * DEFAULT_BUFFER_SIZE + 2 * MIN_BUFFER_SIZE, || {
+            testing::runtime().block_on(async {
+                let full = acquire().await.unwrap();
+                let small = acquire().await.unwrap();
+                assert_eq!((full.buffer_size, small.buffer_size), (DEFAULT_BUFFER_SIZE, MIN_BUFFER_SIZE));
+                assert_eq!(in_use(), 2 * DEFAULT_BUFFER_SIZE + 2 * MIN_BUFFER_SIZE);
+
+                // Nothing fits: the next one queues, and gives up at the timeout
+                let queued = tokio::spawn(acquire());
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                assert!(!queued.is_finished());
+                time.advance(QUEUE_TIMEOUT);
+                assert!(queued.await.unwrap().is_none());
+
+                // A queued connection gets the budget a released one returns
+                let queued = tokio::spawn(acquire());
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                drop(full);
+                assert_eq!(queued.await.unwrap().unwrap().buffer_size, DEFAULT_BUFFER_SIZE);
+                drop(small);
+            });
+        });
+    }
+
+    #[test]
+    fn many_connections_stay_within_the_budget() {
+        let _serial = testing::serial();
+        let budget = 3 * 2 * DEFAULT_BUFFER_SIZE;
+        with_budget(budget, || {
+            testing::runtime().block_on(async {
+                let peak = std::sync::Arc::new(AtomicUsize::new(0));
+                let connections: Vec<_> = (0..200)
+                    .map(|_| {
+                        let peak = peak.clone();
+                        tokio::spawn(async move {
+                            let lease = acquire().await.expect("queued connection timed out");
+                            peak.fetch_max(in_use(), Ordering::Relaxed);
+                            tokio::task::yield_now().await;
+                            lease.buffer_size
+                        })
+                    })
+                    .collect();
+                let mut sizes = Vec::new();
+                for connection in connections {
+                    sizes.push(connection.await.unwrap());
+                }
+                assert!(peak.load(Ordering::Relaxed) <= budget, "peak {} over {}", peak.load(Ordering::Relaxed), budget);
+                assert!(sizes.iter().all(|size| [DEFAULT_BUFFER_SIZE, MIN_BUFFER_SIZE].contains(size)));
+            });
+        });
+    }
+}
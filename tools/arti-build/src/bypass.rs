@@ -0,0 +1,363 @@
+//! Estimate of traffic that bypasses the SOCKS proxy
+//!
+//! Only traffic an app explicitly sends to our SOCKS port goes through Tor;
+//! without a VPN nothing forces the rest through it. This compares the
+//! device's interface byte counters against the tunnel's own counters over
+//! the interval between two `getBypassEstimate` calls and reports the share
+//! that did not go through the tunnel. It is measurement only and changes no
+//! network behavior.
+//!
+//! Device counters come from the first readable source:
+//!
+//! - `/proc/net/dev` (sum over every interface but loopback);
+//! - `/proc/net/xt_qtaguid/iface_stat_fmt` on older kernels;
+//! - values Kotlin passes via `reportTrafficCounters`, for when `/proc/net`
+//!   is restricted (Android 10+). Passing the app's own `TrafficStats` UID
+//!   counters there makes the estimate specific to the app.
+//!
+//! Tunnel bytes are the relayed user bytes plus Tor's cell overhead, plus the
+//! estimated directory bytes (see traffic.rs). An interval whose source
+//! changed or whose counters went backwards starts a new baseline instead of
+//! producing an estimate. The JSON always lists the caveats that apply.
+
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crate::liveness::monotonic_ms;
+use crate::{traffic, METRICS};
+
+/// Payload bytes per 514-byte relay cell (RELAY_DATA)
+const CELL_PAYLOAD_BYTES: u64 = 498;
+const CELL_BYTES: u64 = 514;
+
+/// Below this many device bytes in an interval the estimate is mostly noise
+const MIN_INTERVAL_BYTES: u64 = 256 * 1024;
+
+const PROC_NET_DEV: &str = "/proc/net/dev";
+const QTAGUID_IFACE_STAT: &str = "/proc/net/xt_qtaguid/iface_stat_fmt";
+
+/// Where device byte counters were read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    ProcNetDev,
+    Qtaguid,
+    Kotlin,
+}
+
+impl Source {
+    fn as_str(self) -> &'static str {
+        match self {
+            Source::ProcNetDev => "proc_net_dev",
+            Source::Qtaguid => "xt_qtaguid",
+            Source::Kotlin => "kotlin",
+        }
+    }
+}
+
+/// Counter values at one instant
+#[derive(Clone, Copy)]
+struct Sample {
+    at_ms: u64,
+    source: Source,
+    device_bytes: u64,
+    tunnel_bytes: u64,
+}
+
+/// Sample taken by the previous estimate (the start of the next interval)
+static LAST: Mutex<Option<Sample>> = Mutex::new(None);
+
+/// Latest cumulative rx + tx bytes reported by Kotlin
+static REPORTED: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Record cumulative counters from Kotlin (used when /proc is unreadable)
+pub(crate) fn report(rx_bytes: u64, tx_bytes: u64) {
+    *REPORTED.lock().unwrap() = Some(rx_bytes.saturating_add(tx_bytes));
+}
+
+/// Sum receive and transmit bytes over non-loopback interfaces in `/proc/net/dev`
+fn parse_proc_net_dev(text: &str) -> Option<u64> {
+    let mut total = None;
+    // Two header lines, then "iface: rx_bytes rx_packets ... (8 rx fields) tx_bytes ..."
+    for line in text.lines().skip(2) {
+        let Some((iface, fields)) = line.split_once(':') else {
+            continue;
+        };
+        if iface.trim() == "lo" {
+            continue;
+        }
+        let fields: Vec<u64> = fields.split_whitespace().filter_map(|f| f.parse().ok()).collect();
+        if fields.len() < 9 {
+            continue;
+        }
+        total = Some(total.unwrap_or(0u64).saturating_add(fields[0]).saturating_add(fields[8]));
+    }
+    total
+}
+
+/// Sum receive and transmit bytes over non-loopback interfaces in `iface_stat_fmt`
+fn parse_qtaguid(text: &str) -> Option<u64> {
+    let mut lines = text.lines();
+    let header: Vec<&str> = lines.next()?.split_whitespace().collect();
+    let column = |name: &str| header.iter().position(|h| *h == name);
+    let (iface, rx, tx) = (column("ifname")?, column("total_skb_rx_bytes")?, column("total_skb_tx_bytes")?);
+
+    let mut total = 0u64;
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(iface).is_none_or(|name| *name == "lo") {
+            continue;
+        }
+        let value = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok()).unwrap_or(0);
+        total = total.saturating_add(value(rx)).saturating_add(value(tx));
+    }
+    Some(total)
+}
+
+/// Read device counters from the first available source, reading files with `read`
+fn device_bytes(read: impl Fn(&str) -> Option<String>) -> Option<(Source, u64)> {
+    if let Some(total) = read(PROC_NET_DEV).as_deref().and_then(parse_proc_net_dev) {
+        return Some((Source::ProcNetDev, total));
+    }
+    if let Some(total) = read(QTAGUID_IFACE_STAT).as_deref().and_then(parse_qtaguid) {
+        return Some((Source::Qtaguid, total));
+    }
+    REPORTED.lock().unwrap().map(|total| (Source::Kotlin, total))
+}
+
+/// Bytes the tunnel put on the wire so far: relayed payload with cell overhead, plus directory traffic
+fn tunnel_bytes() -> u64 {
+    let relayed = METRICS.bytes_relayed.load(Ordering::Relaxed);
+    (relayed as u128 * CELL_BYTES as u128 / CELL_PAYLOAD_BYTES as u128) as u64 + traffic::directory_bytes()
+}
+
+/// Share of `device` bytes not accounted for by `tunnel` bytes, with caveats
+fn bypass_fraction(device: u64, tunnel: u64, caveats: &mut Vec<&'static str>) -> Option<f64> {
+    if device == 0 {
+        caveats.push("no_device_traffic");
+        return None;
+    }
+    if tunnel > device {
+        caveats.push("tunnel_exceeds_device");
+    }
+    Some(device.saturating_sub(tunnel) as f64 / device as f64)
+}
+
+/// Take a new sample and compare it with the previous one, as JSON
+pub(crate) fn estimate_json() -> String {
+    estimate(device_bytes(|path| std::fs::read_to_string(path).ok()))
+}
+
+/// `estimate_json` for device counters read as `device`
+fn estimate(device: Option<(Source, u64)>) -> String {
+    let Some((source, device_bytes)) = device else {
+        *LAST.lock().unwrap() = None;
+        return "{\"status\":\"unavailable\",\"source\":null,\"bypass_fraction\":null,\"confidence\":\"none\",\
+                \"caveats\":[\"no_device_counters\"]}"
+            .to_string();
+    };
+    let now = Sample { at_ms: monotonic_ms(), source, device_bytes, tunnel_bytes: tunnel_bytes() };
+    let previous = LAST.lock().unwrap().replace(now);
+
+    let mut caveats = vec!["tunnel_overhead_estimated"];
+    if source != Source::Kotlin {
+        caveats.push("device_counters_include_other_apps");
+    }
+
+    let baseline = |reason: &'static str| {
+        format!(
+            "{{\"status\":\"baseline\",\"reason\":\"{}\",\"source\":\"{}\",\"bypass_fraction\":null,\"confidence\":\"none\",\"caveats\":[]}}",
+            reason,
+            source.as_str(),
+        )
+    };
+    let Some(previous) = previous else {
+        return baseline("first_sample");
+    };
+    if previous.source != source {
+        return baseline("source_changed");
+    }
+    if device_bytes < previous.device_bytes || now.tunnel_bytes < previous.tunnel_bytes {
+        return baseline("counter_reset");
+    }
+
+    let device = device_bytes - previous.device_bytes;
+    let tunnel = now.tunnel_bytes - previous.tunnel_bytes;
+    let fraction = bypass_fraction(device, tunnel, &mut caveats);
+    let confidence = match fraction {
+        None => "none",
+        Some(_) if device < MIN_INTERVAL_BYTES => {
+            caveats.push("little_traffic_in_interval");
+            "low"
+        }
+        // Never "high": timing skew between the two counters and traffic the
+        // tunnel never sees (DNS, other apps) always remain
+        Some(_) => "medium",
+    };
+
+    format!(
+        "{{\"status\":\"estimate\",\"source\":\"{}\",\"interval_secs\":{},\"device_bytes\":{},\"tunnel_bytes\":{},\
+         \"bypass_fraction\":{},\"confidence\":\"{}\",\"caveats\":[{}]}}",
+        source.as_str(),
+        (now.at_ms - previous.at_ms) / 1000,
+        device,
+        tunnel,
+        fraction.map_or_else(|| "null".to_string(), |f| format!("{:.3}", f)),
+        confidence,
+        caveats.iter().map(|c| format!("\"{}\"", c)).collect::<Vec<_>>().join(","),
+    )
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&LAST);
+    held.hold(&REPORTED);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    const PROC_NET_DEV_TEXT: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 9000000    100    0    0    0     0          0         0  9000000    100    0    0    0     0       0          0
+ wlan0: 1000000   2000    0    0    0     0          0         0   200000    900    0    0    0     0       0          0
+rmnet0:   30000     40    0    0    0     0          0         0     4000     30    0    0    0     0       0          0
+";
+
+    const QTAGUID_TEXT: &str = "\
+ifname total_skb_rx_bytes total_skb_rx_packets total_skb_tx_bytes total_skb_tx_packets
+lo 5000 10 5000 10
+wlan0 70000 60 8000 20
+";
+
+    /// Start from no baseline and no Kotlin counters, relayed bytes at zero
+    struct Restore(u64);
+
+    impl Restore {
+        fn start() -> Restore {
+            *LAST.lock().unwrap() = None;
+            *REPORTED.lock().unwrap() = None;
+            Restore(METRICS.bytes_relayed.swap(0, Ordering::Relaxed))
+        }
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            *LAST.lock().unwrap() = None;
+            *REPORTED.lock().unwrap() = None;
+            METRICS.bytes_relayed.store(self.0, Ordering::Relaxed);
+        }
+    }
+
+    fn json(text: String) -> Value {
+        serde_json::from_str(&text).unwrap()
+    }
+
+    /// Relay `cells` full cells' worth of payload through the tunnel
+    fn relay_cells(cells: u64) {
+        METRICS.bytes_relayed.fetch_add(cells * CELL_PAYLOAD_BYTES, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn counters_are_parsed_without_loopback() {
+        assert_eq!(parse_proc_net_dev(PROC_NET_DEV_TEXT), Some(1_000_000 + 200_000 + 30_000 + 4_000));
+        assert_eq!(parse_proc_net_dev("Inter-|\n face |\n"), None);
+        assert_eq!(parse_qtaguid(QTAGUID_TEXT), Some(78_000));
+        assert_eq!(parse_qtaguid("ifname rx tx\nwlan0 1 2\n"), None);
+    }
+
+    #[test]
+    fn sources_are_tried_in_order_down_to_kotlin() {
+        let _serial = testing::serial();
+        let _restore = Restore::start();
+        let only = |available: &'static [(&'static str, &'static str)]| {
+            move |path: &str| available.iter().find(|(p, _)| *p == path).map(|(_, text)| text.to_string())
+        };
+
+        let both = only(&[(PROC_NET_DEV, PROC_NET_DEV_TEXT), (QTAGUID_IFACE_STAT, QTAGUID_TEXT)]);
+        assert_eq!(device_bytes(both), Some((Source::ProcNetDev, 1_234_000)));
+        let unparsable_proc = only(&[(PROC_NET_DEV, "restricted"), (QTAGUID_IFACE_STAT, QTAGUID_TEXT)]);
+        assert_eq!(device_bytes(unparsable_proc), Some((Source::Qtaguid, 78_000)));
+        assert_eq!(device_bytes(only(&[])), None);
+
+        report(600, 400);
+        assert_eq!(device_bytes(only(&[])), Some((Source::Kotlin, 1000)));
+        report(u64::MAX, 1);
+        assert_eq!(device_bytes(only(&[])), Some((Source::Kotlin, u64::MAX)));
+    }
+
+    #[test]
+    fn fractions_and_their_caveats() {
+        // device, tunnel => fraction, caveats
+        let table: [(u64, u64, Option<f64>, &[&str]); 4] = [
+            (0, 0, None, &["no_device_traffic"]),
+            (1000, 0, Some(1.0), &[]),
+            (1000, 750, Some(0.25), &[]),
+            (1000, 1200, Some(0.0), &["tunnel_exceeds_device"]),
+        ];
+        for (device, tunnel, fraction, expected) in table {
+            let mut caveats = Vec::new();
+            assert_eq!(bypass_fraction(device, tunnel, &mut caveats), fraction, "{} / {}", device, tunnel);
+            assert_eq!(caveats, expected, "{} / {}", device, tunnel);
+        }
+    }
+
+    #[test]
+    fn intervals_compare_consecutive_samples() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _restore = Restore::start();
+        let first = json(estimate(Some((Source::Kotlin, 10_000_000))));
+        assert_eq!((first["status"].as_str(), first["reason"].as_str()), (Some("baseline"), Some("first_sample")));
+
+        // The tunnel carried three quarters of the device's bytes
+        time.advance(Duration::from_secs(60));
+        relay_cells(1000);
+        let tunnel = 1000 * CELL_BYTES;
+        let estimate_1 = json(estimate(Some((Source::Kotlin, 10_000_000 + 4 * tunnel / 3))));
+        assert_eq!(estimate_1["status"], "estimate");
+        assert_eq!(estimate_1["interval_secs"], 60);
+        assert_eq!(estimate_1["tunnel_bytes"], tunnel);
+        assert_eq!(estimate_1["bypass_fraction"].as_f64(), Some(0.25));
+        assert_eq!(estimate_1["confidence"], "medium");
+        assert_eq!(estimate_1["caveats"], serde_json::json!(["tunnel_overhead_estimated"]));
+
+        // Little traffic lowers the confidence; /proc counters cover other apps too
+        let device = 10_000_000 + 4 * tunnel / 3;
+        let _ = json(estimate(Some((Source::ProcNetDev, device))));
+        time.advance(Duration::from_secs(5));
+        let quiet = json(estimate(Some((Source::ProcNetDev, device + 1000))));
+        assert_eq!(quiet["bypass_fraction"].as_f64(), Some(1.0));
+        assert_eq!(quiet["confidence"], "low");
+        assert_eq!(
+            quiet["caveats"],
+            serde_json::json!(["tunnel_overhead_estimated", "device_counters_include_other_apps", "little_traffic_in_interval"])
+        );
+    }
+
+    #[test]
+    fn a_changed_source_or_reset_counter_starts_over() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let _restore = Restore::start();
+
+        let reason = |device| json(estimate(device))["reason"].as_str().map(str::to_string);
+        assert_eq!(reason(Some((Source::Kotlin, 5000))).as_deref(), Some("first_sample"));
+        assert_eq!(reason(Some((Source::ProcNetDev, 9000))).as_deref(), Some("source_changed"));
+        assert_eq!(reason(Some((Source::ProcNetDev, 100))).as_deref(), Some("counter_reset"));
+        relay_cells(10);
+        let _ = estimate(Some((Source::ProcNetDev, 200)));
+        METRICS.bytes_relayed.store(0, Ordering::Relaxed);
+        assert_eq!(reason(Some((Source::ProcNetDev, 300))).as_deref(), Some("counter_reset"));
+
+        // No counters at all: unavailable, and the baseline is dropped
+        let unavailable = json(estimate(None));
+        assert_eq!(unavailable["status"], "unavailable");
+        assert_eq!(unavailable["caveats"], serde_json::json!(["no_device_counters"]));
+        assert_eq!(reason(Some((Source::ProcNetDev, 400))).as_deref(), Some("first_sample"));
+    }
+}
@@ -0,0 +1,512 @@
+//! Shared memory budget for the wrapper's in-memory tables
+//!
+//! Each table is capped on its own (destination quality at 512 entries,
+//! bridge history at 16 bridges, ...), but nothing bounded them together, and
+//! on a low-memory device their sum is what matters. Every such table is now a
+//! `BoundedMap`, which registers itself here when it is constructed, so a
+//! table can't be added without being counted.
+//!
+//! For each table the registry knows its name, entry count, approximate size
+//! in bytes (from a per-table estimate of each entry) and a trim hook that
+//! shrinks it to a byte target, evicting least-recently-used entries first:
+//!
+//! - the sum is capped by a global budget (default 2 MB, see
+//!   `setCacheBudget`): a table growing past it trims itself to its share of
+//!   the budget, and the others are asked to do the same;
+//! - `onTrimMemory` trims every table proportionally, more the higher the
+//!   level;
+//! - the relay buffer budget running short trims them by half, at most every
+//!   `PRESSURE_INTERVAL`.
+//!
+//! `getMemoryStats` reports the breakdown. Tables owned by the bookkeeping
+//! actor are trimmed on it, so a trim there applies shortly after the call.
+//!
+//! An update only checks the budget against a running total kept in an
+//! atomic; the registry lock is taken when the budget is exceeded (or for
+//! stats and trims), not on every update of every table.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::seams;
+
+/// Default cap on the approximate size of all tables together
+pub(crate) const DEFAULT_BUDGET_BYTES: u64 = 2 * 1024 * 1024;
+
+/// Bounds of `setCacheBudget`
+pub(crate) const MIN_BUDGET_BYTES: u64 = 64 * 1024;
+pub(crate) const MAX_BUDGET_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Least time between two trims for relay buffer pressure
+const PRESSURE_INTERVAL: Duration = Duration::from_secs(10);
+
+static BUDGET_BYTES: AtomicU64 = AtomicU64::new(DEFAULT_BUDGET_BYTES);
+
+/// Trims for the budget, `onTrimMemory` and buffer pressure since process start
+static TRIMS: AtomicU64 = AtomicU64::new(0);
+
+static LAST_PRESSURE_TRIM: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Size of one table, published for the registry
+#[derive(Default)]
+struct Usage {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+struct Registered {
+    name: &'static str,
+    max_entries: usize,
+    usage: Arc<Usage>,
+    trim: fn(usize),
+}
+
+static REGISTRY: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// Bytes published by every live table, registered or not
+static ALL_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn set_budget(bytes: u64) {
+    BUDGET_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+/// `bytes` scaled by `numerator / denominator`
+fn share(bytes: usize, numerator: u64, denominator: u64) -> usize {
+    (bytes as u128 * numerator as u128 / denominator.max(1) as u128) as usize
+}
+
+/// Trim every table except `skip` to `numerator / denominator` of its size
+///
+/// Returns the target for `skip`, which its caller trims itself: it already
+/// holds whatever lock guards that table.
+fn trim_proportionally(numerator: u64, denominator: u64, skip: Option<&Arc<Usage>>) -> Option<usize> {
+    let mut own = None;
+    let mut others = Vec::new();
+    for table in REGISTRY.lock().unwrap().iter() {
+        let target = share(table.usage.bytes.load(Ordering::Relaxed), numerator, denominator);
+        if skip.is_some_and(|usage| Arc::ptr_eq(usage, &table.usage)) {
+            own = Some(target);
+        } else {
+            others.push((table.trim, target));
+        }
+    }
+    // Hooks run without the registry lock: they may touch their own table's
+    // registration
+    for (trim, target) in others {
+        trim(target);
+    }
+    TRIMS.fetch_add(1, Ordering::Relaxed);
+    own
+}
+
+fn total_bytes() -> u64 {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|table| table.usage.bytes.load(Ordering::Relaxed) as u64)
+        .sum()
+}
+
+/// The byte target for the table behind `usage` if the budget is exceeded
+fn over_budget(usage: &Arc<Usage>) -> Option<usize> {
+    let budget = BUDGET_BYTES.load(Ordering::Relaxed);
+    // Never below the registered total, so the common case needs no lock
+    if ALL_BYTES.load(Ordering::Relaxed) as u64 <= budget {
+        return None;
+    }
+    let total = total_bytes();
+    if total <= budget {
+        return None;
+    }
+    trim_proportionally(budget, total, Some(usage))
+}
+
+/// Trim for `onTrimMemory(level)`; returns the percentage of each table kept
+pub(crate) fn trim_memory(level: i32) -> u64 {
+    // ComponentCallbacks2 levels
+    let keep_percent = match level {
+        80.. => 0, // TRIM_MEMORY_COMPLETE
+        60.. => 25, // TRIM_MEMORY_MODERATE
+        40.. => 50, // TRIM_MEMORY_BACKGROUND
+        20.. => 100, // TRIM_MEMORY_UI_HIDDEN: not memory pressure
+        15.. => 25, // TRIM_MEMORY_RUNNING_CRITICAL
+        10.. => 50, // TRIM_MEMORY_RUNNING_LOW
+        _ => 75, // TRIM_MEMORY_RUNNING_MODERATE
+    };
+    if keep_percent < 100 {
+        trim_proportionally(keep_percent, 100, None);
+    }
+    keep_percent
+}
+
+/// Halve every table when relay buffers run short (rate limited)
+pub(crate) fn relieve_buffer_pressure() {
+    let now = seams::now_monotonic();
+    {
+        let mut last = LAST_PRESSURE_TRIM.lock().unwrap();
+        if last.is_some_and(|at| now.saturating_duration_since(at) < PRESSURE_INTERVAL) {
+            return;
+        }
+        *last = Some(now);
+    }
+    trim_proportionally(1, 2, None);
+}
+
+/// Every table's size as JSON (`getMemoryStats`)
+pub(crate) fn stats_json() -> String {
+    let caches: Vec<Value> = REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|table| {
+            json!({
+                "name": table.name,
+                "entries": table.usage.entries.load(Ordering::Relaxed),
+                "max_entries": table.max_entries,
+                "approx_bytes": table.usage.bytes.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    json!({
+        "budget_bytes": BUDGET_BYTES.load(Ordering::Relaxed),
+        "total_bytes": total_bytes(),
+        "trims": TRIMS.load(Ordering::Relaxed),
+        "relay_buffer_bytes": crate::buffers::in_use(),
+        "caches": caches,
+    })
+    .to_string()
+}
+
+struct Slot<V> {
+    /// Access stamp for least-recently-used eviction
+    used: u64,
+    bytes: usize,
+    value: V,
+}
+
+/// A map capped in entries, counted against the shared budget
+///
+/// Constructing one registers it; dropping it removes the registration.
+pub(crate) struct BoundedMap<K, V> {
+    map: HashMap<K, Slot<V>>,
+    clock: u64,
+    bytes: usize,
+    max_entries: usize,
+    size: fn(&K, &V) -> usize,
+    usage: Arc<Usage>,
+}
+
+impl<K: Eq + Hash + Clone, V> BoundedMap<K, V> {
+    /// A table `name` of at most `max_entries`, each about `size` bytes
+    ///
+    /// `trim` must shrink this table to the given number of bytes (e.g. by
+    /// calling `trim` on it under its lock or on the actor).
+    pub(crate) fn new(name: &'static str, max_entries: usize, size: fn(&K, &V) -> usize, trim: fn(usize)) -> Self {
+        let usage = Arc::new(Usage::default());
+        REGISTRY.lock().unwrap().push(Registered { name, max_entries, usage: Arc::clone(&usage), trim });
+        BoundedMap { map: HashMap::new(), clock: 0, bytes: 0, max_entries, size, usage }
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&V> {
+        self.map.get(key).map(|slot| &slot.value)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map.iter().map(|(key, slot)| (key, &slot.value))
+    }
+
+    fn publish(&self) {
+        self.usage.entries.store(self.map.len(), Ordering::Relaxed);
+        let previous = self.usage.bytes.swap(self.bytes, Ordering::Relaxed);
+        if self.bytes >= previous {
+            ALL_BYTES.fetch_add(self.bytes - previous, Ordering::Relaxed);
+        } else {
+            ALL_BYTES.fetch_sub(previous - self.bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.map.iter().min_by_key(|(_, slot)| slot.used).map(|(key, _)| key.clone());
+        match oldest.and_then(|key| self.map.remove(&key)) {
+            Some(slot) => {
+                self.bytes -= slot.bytes;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Enforce the entry cap and the shared budget after a change
+    fn settle(&mut self) {
+        while self.map.len() > self.max_entries && self.evict_oldest() {}
+        self.publish();
+        if let Some(target) = over_budget(&self.usage) {
+            self.trim(target);
+        }
+    }
+
+    /// Update the entry for `key` with `f`, inserting `make()` first if absent
+    pub(crate) fn update<R>(&mut self, key: K, make: impl FnOnce() -> V, f: impl FnOnce(&mut V) -> R) -> R {
+        self.clock += 1;
+        let size = self.size;
+        let slot = self.map.entry(key.clone()).or_insert_with(|| Slot { used: 0, bytes: 0, value: make() });
+        slot.used = self.clock;
+        let result = f(&mut slot.value);
+        let bytes = size(&key, &slot.value);
+        self.bytes = self.bytes - slot.bytes + bytes;
+        slot.bytes = bytes;
+        self.settle();
+        result
+    }
+
+    /// Insert `value`, as the most recently used entry
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.update(key, || value, |_| ());
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+        self.bytes = 0;
+        self.publish();
+    }
+
+    /// Evict least-recently-used entries until at most `target_bytes` remain
+    pub(crate) fn trim(&mut self, target_bytes: usize) {
+        while self.bytes > target_bytes && self.evict_oldest() {}
+        self.publish();
+    }
+}
+
+impl<K, V> Drop for BoundedMap<K, V> {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().retain(|table| !Arc::ptr_eq(&table.usage, &self.usage));
+        ALL_BYTES.fetch_sub(self.usage.bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&LAST_PRESSURE_TRIM);
+    held.hold(&REGISTRY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Fake tables whose values are their own size in bytes
+    type Fake = BoundedMap<u32, usize>;
+
+    static FAKES: [Mutex<Option<Fake>>; 3] = [Mutex::new(None), Mutex::new(None), Mutex::new(None)];
+    const TRIM_HOOKS: [fn(usize); 3] = [|target| trim_fake(0, target), |target| trim_fake(1, target), |target| trim_fake(2, target)];
+
+    fn trim_fake(index: usize, target: usize) {
+        if let Some(fake) = FAKES[index].lock().unwrap().as_mut() {
+            fake.trim(target);
+        }
+    }
+
+    /// The registry holding only the fakes, with the real tables and the
+    /// budget put back on drop
+    struct Isolated {
+        real: Vec<Registered>,
+        budget: u64,
+    }
+
+    impl Isolated {
+        fn new(max_entries: usize) -> Isolated {
+            let real = std::mem::take(&mut *REGISTRY.lock().unwrap());
+            for (index, fake) in FAKES.iter().enumerate() {
+                let name = ["fake_a", "fake_b", "fake_c"][index];
+                *fake.lock().unwrap() = Some(BoundedMap::new(name, max_entries, |_, bytes| *bytes, TRIM_HOOKS[index]));
+            }
+            Isolated { real, budget: BUDGET_BYTES.load(Ordering::Relaxed) }
+        }
+
+        /// Insert `count` entries of `bytes` each into fake `index`, keys
+        /// continuing from its largest
+        fn fill(&self, index: usize, count: u32, bytes: usize) {
+            for _ in 0..count {
+                let mut fake = FAKES[index].lock().unwrap();
+                let fake = fake.as_mut().unwrap();
+                let next = fake.iter().map(|(key, _)| key + 1).max().unwrap_or(0);
+                fake.insert(next, bytes);
+            }
+        }
+
+        fn bytes(&self, index: usize) -> usize {
+            FAKES[index].lock().unwrap().as_ref().unwrap().iter().map(|(_, bytes)| bytes).sum()
+        }
+
+        fn keys(&self, index: usize) -> Vec<u32> {
+            let mut keys: Vec<u32> = FAKES[index].lock().unwrap().as_ref().unwrap().iter().map(|(key, _)| *key).collect();
+            keys.sort();
+            keys
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            for fake in &FAKES {
+                fake.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+            }
+            REGISTRY.lock().unwrap_or_else(std::sync::PoisonError::into_inner).append(&mut self.real);
+            set_budget(self.budget);
+        }
+    }
+
+    #[test]
+    fn shares_scale_without_overflow() {
+        for (bytes, numerator, denominator, expected) in [
+            (1000, 1, 2, 500),
+            (1000, 0, 100, 0),
+            (1000, 100, 100, 1000),
+            (999, 1, 2, 499),
+            (1000, 3000, 4000, 750),
+            (1000, 1, 0, 1000),
+            (usize::MAX, 1, 2, usize::MAX / 2),
+        ] {
+            assert_eq!(share(bytes, numerator, denominator), expected, "{} * {} / {}", bytes, numerator, denominator);
+        }
+    }
+
+    #[test]
+    fn trim_memory_shrinks_every_table_in_proportion() {
+        let _serial = testing::serial();
+        let tables = Isolated::new(100);
+        tables.fill(0, 10, 100);
+        tables.fill(1, 20, 100);
+        tables.fill(2, 40, 100);
+
+        // UI_HIDDEN isn't memory pressure
+        let trims = TRIMS.load(Ordering::Relaxed);
+        assert_eq!(trim_memory(20), 100);
+        assert_eq!(TRIMS.load(Ordering::Relaxed), trims);
+        assert_eq!([tables.bytes(0), tables.bytes(1), tables.bytes(2)], [1000, 2000, 4000]);
+
+        assert_eq!(trim_memory(40), 50);
+        assert_eq!([tables.bytes(0), tables.bytes(1), tables.bytes(2)], [500, 1000, 2000]);
+        // Least recently used go first
+        assert_eq!(tables.keys(0), (5..10).collect::<Vec<_>>());
+
+        assert_eq!(trim_memory(60), 25);
+        assert_eq!([tables.bytes(0), tables.bytes(1), tables.bytes(2)], [100, 200, 500]);
+        assert_eq!(TRIMS.load(Ordering::Relaxed), trims + 2);
+
+        assert_eq!(trim_memory(80), 0);
+        assert_eq!([tables.bytes(0), tables.bytes(1), tables.bytes(2)], [0, 0, 0]);
+
+        for (level, keep) in [(5, 75), (10, 50), (15, 25), (20, 100), (40, 50), (60, 25), (80, 0)] {
+            assert_eq!(trim_memory(level), keep, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn the_budget_is_enforced_on_insert() {
+        let _serial = testing::serial();
+        let tables = Isolated::new(1000);
+        set_budget(3000);
+        tables.fill(0, 10, 100);
+        tables.fill(1, 10, 100);
+        tables.fill(2, 10, 100);
+        assert_eq!(total_bytes(), 3000);
+
+        // 3100 bytes: each table is trimmed to 3000/3100 of its size, the
+        // inserting one by itself
+        tables.fill(2, 1, 100);
+        assert_eq!([tables.bytes(0), tables.bytes(1), tables.bytes(2)], [900, 900, 1000]);
+        assert_eq!(tables.keys(2), (1..11).collect::<Vec<_>>());
+
+        for _ in 0..50 {
+            tables.fill(1, 1, 100);
+            assert!(total_bytes() <= 3000, "{}", total_bytes());
+        }
+        // Growing one table squeezes the others
+        assert!(tables.bytes(1) > 2000 && tables.bytes(0) < 500, "{} {}", tables.bytes(0), tables.bytes(1));
+
+        // One entry larger than the budget can't stay
+        tables.fill(0, 1, 4000);
+        assert!(total_bytes() <= 3000, "{}", total_bytes());
+    }
+
+    #[test]
+    fn updates_within_the_budget_leave_the_registry_alone() {
+        let _serial = testing::serial();
+        let tables = Isolated::new(1000);
+        set_budget(MAX_BUDGET_BYTES);
+        let all = ALL_BYTES.load(Ordering::Relaxed);
+
+        let registry = REGISTRY.lock().unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for fake in &FAKES {
+                for bytes in [100, 50, 300] {
+                    fake.lock().unwrap().as_mut().unwrap().insert(bytes as u32, bytes);
+                }
+            }
+            let _ = done.send(());
+        });
+        let updated = finished.recv_timeout(Duration::from_secs(5));
+        drop(registry);
+        assert!(updated.is_ok(), "an update within the budget waited for the registry");
+
+        // The running total follows every table, shrinking with them
+        assert_eq!(ALL_BYTES.load(Ordering::Relaxed), all + 3 * 450);
+        FAKES[0].lock().unwrap().as_mut().unwrap().trim(350);
+        FAKES[1].lock().unwrap().take();
+        assert_eq!(ALL_BYTES.load(Ordering::Relaxed), all + 350 + 450);
+        assert_eq!(total_bytes(), tables.bytes(0) as u64 + tables.bytes(2) as u64);
+    }
+
+    #[test]
+    fn the_entry_cap_evicts_the_least_recently_used() {
+        let _serial = testing::serial();
+        let tables = Isolated::new(3);
+        tables.fill(0, 3, 10);
+        // Touching 0 makes 1 the oldest
+        FAKES[0].lock().unwrap().as_mut().unwrap().update(0, || 0, |bytes| *bytes += 5);
+        tables.fill(0, 1, 10);
+        assert_eq!(tables.keys(0), vec![0, 2, 3]);
+        assert_eq!(tables.bytes(0), 35);
+    }
+
+    #[test]
+    fn buffer_pressure_halves_the_tables_at_most_every_interval() {
+        let _serial = testing::serial();
+        let time = testing::FakeTime::start();
+        *LAST_PRESSURE_TRIM.lock().unwrap() = None;
+        let tables = Isolated::new(100);
+        tables.fill(0, 8, 100);
+
+        relieve_buffer_pressure();
+        assert_eq!(tables.bytes(0), 400);
+        time.advance(PRESSURE_INTERVAL - Duration::from_secs(1));
+        relieve_buffer_pressure();
+        assert_eq!(tables.bytes(0), 400);
+        time.advance(Duration::from_secs(1));
+        relieve_buffer_pressure();
+        assert_eq!(tables.bytes(0), 200);
+    }
+
+    #[test]
+    fn stats_list_registered_tables_until_dropped() {
+        let _serial = testing::serial();
+        let tables = Isolated::new(16);
+        tables.fill(1, 2, 100);
+        let stats: Value = serde_json::from_str(&stats_json()).unwrap();
+        assert_eq!(stats["total_bytes"], 200);
+        assert_eq!(stats["caches"][1], json!({"name": "fake_b", "entries": 2, "max_entries": 16, "approx_bytes": 200}));
+
+        FAKES[1].lock().unwrap().take();
+        let stats: Value = serde_json::from_str(&stats_json()).unwrap();
+        let names: Vec<&str> = stats["caches"].as_array().unwrap().iter().map(|cache| cache["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["fake_a", "fake_c"]);
+    }
+}
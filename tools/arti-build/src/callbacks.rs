@@ -0,0 +1,619 @@
+//! Registry of the Java callback objects held as JNI global references
+//!
+//! Android's global reference table is limited (51200 entries, shared with
+//! every other library in the process), and a callback held after its Activity
+//! is destroyed keeps that context alive. So every callback is registered
+//! here, one slot per kind:
+//!
+//! - registering a kind again releases the previous reference right away,
+//!   after the new one is in place;
+//! - registering `null` releases the kind's reference;
+//! - `JNI_OnUnload` releases everything.
+//!
+//! At most one reference per kind is ever live. Registering the same kind over
+//! and over (e.g. from `onCreate`) still hints at callbacks tied to short-lived
+//! contexts, so the registry logs an error once a kind has been registered more
+//! than `EXPECTED_MAX_REGISTRATIONS` times.
+//!
+//! Upcalls go through `invoke`, which runs them on the calling thread without
+//! holding any lock another kind needs, and watches how long each takes. A
+//! callback that blocks (say, a log listener waiting on fsync) would otherwise
+//! hold up every thread that emits its kind:
+//!
+//! - an upcall over `SOFT_BUDGET_MS` is logged with its kind;
+//! - one over `HARD_BUDGET_MS`, finished or still in flight, marks the kind
+//!   unhealthy: its messages are dropped and counted instead of delivered;
+//! - while unhealthy, one message per `PROBE_INTERVAL_MS` still goes through
+//!   as a probe. After `RECOVERY_PROMPT_CALLS` prompt upcalls in a row the
+//!   kind is healthy again; a slow one starts the count over.
+//!
+//! Other kinds are unaffected. Health and counters are part of
+//! `getCallbackRegistrations`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use jni::objects::{GlobalRef, JObject};
+use jni::JNIEnv;
+
+use crate::liveness::{self, monotonic_ms};
+
+/// Registrations per kind beyond which re-registration looks like a leak
+const EXPECTED_MAX_REGISTRATIONS: u64 = 16;
+
+/// Upcalls longer than this are logged
+const SOFT_BUDGET_MS: u64 = 500;
+
+/// Upcalls longer than this make their kind unhealthy
+const HARD_BUDGET_MS: u64 = 5000;
+
+/// How often an unhealthy kind still gets a message through
+const PROBE_INTERVAL_MS: u64 = 10_000;
+
+/// Prompt upcalls in a row that make an unhealthy kind healthy again
+const RECOVERY_PROMPT_CALLS: u32 = 2;
+
+/// Kinds of callback the wrapper calls into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Log,
+    State,
+    NotificationStatus,
+    SecurityEvent,
+    ConnectionPolicy,
+    NetworkCondition,
+}
+
+const KINDS: [Kind; 6] = [
+    Kind::Log,
+    Kind::State,
+    Kind::NotificationStatus,
+    Kind::SecurityEvent,
+    Kind::ConnectionPolicy,
+    Kind::NetworkCondition,
+];
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Log => "log",
+            Kind::State => "state",
+            Kind::NotificationStatus => "notification_status",
+            Kind::SecurityEvent => "security_event",
+            Kind::ConnectionPolicy => "connection_policy",
+            Kind::NetworkCondition => "network_condition",
+        }
+    }
+}
+
+/// One reference slot per kind
+struct Slots<R>([Mutex<Option<R>>; 6]);
+
+impl<R> Slots<R> {
+    const fn new() -> Self {
+        Slots([const { Mutex::new(None) }; 6])
+    }
+
+    fn get(&self, kind: Kind) -> &Mutex<Option<R>> {
+        &self.0[kind as usize]
+    }
+
+    /// Put `reference` in `kind`'s slot (`None` empties it), then release the
+    /// one it replaced; returns whether one was released
+    fn replace(&self, kind: Kind, reference: Option<R>) -> bool {
+        let previous = std::mem::replace(&mut *self.get(kind).lock().unwrap(), reference);
+        release(previous)
+    }
+
+    /// Empty every slot; returns how many references were released
+    fn release_all(&self) -> usize {
+        KINDS.iter().filter(|kind| self.replace(**kind, None)).count()
+    }
+}
+
+static SLOTS: Slots<GlobalRef> = Slots::new();
+
+/// Registrations per kind since process start
+static REGISTRATIONS: [AtomicU64; 6] = [const { AtomicU64::new(0) }; 6];
+
+/// Global references released so far (replaced, cleared, or unloaded)
+static RELEASES: AtomicU64 = AtomicU64::new(0);
+
+/// Upcall watchdog state of one kind
+#[derive(Clone, Copy)]
+struct Health {
+    unhealthy: bool,
+    /// Prompt upcalls in a row while unhealthy
+    prompt_streak: u32,
+    last_probe_ms: u64,
+    /// Start of the oldest upcall in flight (0: none)
+    in_flight_since_ms: u64,
+    slow_calls: u64,
+    dropped: u64,
+}
+
+static HEALTH: Mutex<[Health; 6]> = Mutex::new(
+    [Health {
+        unhealthy: false,
+        prompt_streak: 0,
+        last_probe_ms: 0,
+        in_flight_since_ms: 0,
+        slow_calls: 0,
+        dropped: 0,
+    }; 6],
+);
+
+/// The slot holding `kind`'s callback
+pub(crate) fn slot(kind: Kind) -> &'static Mutex<Option<GlobalRef>> {
+    SLOTS.get(kind)
+}
+
+/// Drop a reference outside any slot lock, counting the release
+fn release<R>(reference: Option<R>) -> bool {
+    let released = reference.is_some();
+    if released {
+        drop(reference);
+        RELEASES.fetch_add(1, Ordering::Relaxed);
+    }
+    released
+}
+
+/// Register `callback` for `kind`, releasing the one it replaces
+///
+/// A null `callback` only releases. Returns whether a callback is now held.
+pub(crate) fn register(env: &JNIEnv, kind: Kind, callback: JObject) -> bool {
+    // Cache JavaVM if not already cached
+    if crate::JAVA_VM.get().is_none() {
+        if let Ok(vm) = env.get_java_vm() {
+            let _ = crate::JAVA_VM.set(vm);
+        }
+    }
+
+    if callback.is_null() {
+        SLOTS.replace(kind, None);
+        return false;
+    }
+    let Ok(global_ref) = env.new_global_ref(callback) else {
+        return false;
+    };
+    SLOTS.replace(kind, Some(global_ref));
+    registered(kind);
+    true
+}
+
+/// Count a new callback for `kind`, which starts out healthy
+fn registered(kind: Kind) {
+    let mut health = HEALTH.lock().unwrap();
+    health[kind as usize].unhealthy = false;
+    health[kind as usize].prompt_streak = 0;
+    drop(health);
+
+    let count = REGISTRATIONS[kind as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    if count == EXPECTED_MAX_REGISTRATIONS + 1 {
+        log_error!(
+            "{} callback registered {} times; register callbacks once per process, not per Activity",
+            kind.as_str(),
+            count
+        );
+    }
+}
+
+/// Whether a message of `kind` may be delivered now; counts it as dropped if not
+fn admit(kind: Kind, now: u64) -> bool {
+    let mut health = HEALTH.lock().unwrap();
+    let health = &mut health[kind as usize];
+    let in_flight = health.in_flight_since_ms;
+    if in_flight != 0 && now.saturating_sub(in_flight) > HARD_BUDGET_MS && !health.unhealthy {
+        health.unhealthy = true;
+        health.prompt_streak = 0;
+        health.last_probe_ms = now;
+    }
+    let admitted = if !health.unhealthy {
+        true
+    } else if in_flight != 0 {
+        false
+    } else if health.prompt_streak > 0 || now.saturating_sub(health.last_probe_ms) >= PROBE_INTERVAL_MS {
+        health.last_probe_ms = now;
+        true
+    } else {
+        false
+    };
+    if !admitted {
+        health.dropped += 1;
+    }
+    admitted
+}
+
+/// What an upcall's duration did to its kind
+enum Outcome {
+    Prompt,
+    Slow,
+    BecameUnhealthy,
+    Recovered,
+}
+
+fn finish(kind: Kind, started: u64, owns_in_flight: bool, elapsed: u64) -> Outcome {
+    let mut health = HEALTH.lock().unwrap();
+    let health = &mut health[kind as usize];
+    if owns_in_flight && health.in_flight_since_ms == started {
+        health.in_flight_since_ms = 0;
+    }
+    if elapsed > SOFT_BUDGET_MS {
+        health.slow_calls += 1;
+    }
+    if elapsed > HARD_BUDGET_MS {
+        health.prompt_streak = 0;
+        // The slow upcall itself counts as the latest probe
+        health.last_probe_ms = started + elapsed;
+        return if std::mem::replace(&mut health.unhealthy, true) { Outcome::Slow } else { Outcome::BecameUnhealthy };
+    }
+    if health.unhealthy {
+        if elapsed > SOFT_BUDGET_MS {
+            health.prompt_streak = 0;
+            return Outcome::Slow;
+        }
+        health.prompt_streak += 1;
+        if health.prompt_streak >= RECOVERY_PROMPT_CALLS {
+            health.unhealthy = false;
+            health.prompt_streak = 0;
+            return Outcome::Recovered;
+        }
+        return Outcome::Prompt;
+    }
+    if elapsed > SOFT_BUDGET_MS {
+        Outcome::Slow
+    } else {
+        Outcome::Prompt
+    }
+}
+
+/// Count a message of `kind` dropped without an upcall
+pub(crate) fn count_dropped(kind: Kind) {
+    HEALTH.lock().unwrap()[kind as usize].dropped += 1;
+}
+
+/// Whether an upcall of `kind` has been in flight longer than the soft budget
+pub(crate) fn stalled(kind: Kind) -> bool {
+    let since = HEALTH.lock().unwrap()[kind as usize].in_flight_since_ms;
+    since != 0 && monotonic_ms().saturating_sub(since) > SOFT_BUDGET_MS
+}
+
+/// Call `kind`'s callback with `call`, under the upcall watchdog
+///
+/// Does nothing while shutting down, without a callback or a cached VM, or
+/// when the watchdog drops the message. No lock is held during the upcall.
+pub(crate) fn invoke(kind: Kind, call: impl FnOnce(&mut JNIEnv, &JObject)) {
+    if crate::SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return;
+    }
+    let Some(callback) = slot(kind).lock().unwrap().clone() else {
+        return;
+    };
+    watched(kind, || {
+        let Some(vm) = crate::JAVA_VM.get() else {
+            return;
+        };
+        let Ok(mut env) = vm.attach_current_thread() else {
+            return;
+        };
+        let _upcall = liveness::UpcallGuard::new();
+        call(&mut env, callback.as_obj());
+    });
+}
+
+/// Run `upcall` for `kind` under the watchdog, unless it drops the message
+fn watched(kind: Kind, upcall: impl FnOnce()) {
+    let started = monotonic_ms();
+    if !admit(kind, started) {
+        return;
+    }
+    let owns_in_flight = {
+        let mut health = HEALTH.lock().unwrap();
+        let in_flight = &mut health[kind as usize].in_flight_since_ms;
+        let owns = *in_flight == 0;
+        if owns {
+            *in_flight = started;
+        }
+        owns
+    };
+    upcall();
+    let elapsed = monotonic_ms().saturating_sub(started);
+    match finish(kind, started, owns_in_flight, elapsed) {
+        Outcome::Prompt => {}
+        Outcome::Slow => log_error!("{} callback took {} ms", kind.as_str(), elapsed),
+        Outcome::BecameUnhealthy => log_error!(
+            "{} callback took {} ms; dropping its messages until it answers promptly again",
+            kind.as_str(),
+            elapsed
+        ),
+        Outcome::Recovered => log_info!("{} callback answers promptly again; delivering its messages", kind.as_str()),
+    }
+}
+
+/// Release every held reference; returns how many were released
+pub(crate) fn release_all() -> usize {
+    SLOTS.release_all()
+}
+
+/// Live registrations and counters as JSON
+pub(crate) fn registrations_json() -> String {
+    let mut live = 0;
+    let kinds: Vec<String> = KINDS
+        .iter()
+        .map(|kind| {
+            let held = slot(*kind).lock().unwrap().is_some();
+            live += held as u32;
+            let health = HEALTH.lock().unwrap()[*kind as usize];
+            format!(
+                "\"{}\":{{\"registered\":{},\"registrations\":{},\"healthy\":{},\"slow_calls\":{},\"dropped\":{}}}",
+                kind.as_str(),
+                held,
+                REGISTRATIONS[*kind as usize].load(Ordering::Relaxed),
+                !health.unhealthy,
+                health.slow_calls,
+                health.dropped,
+            )
+        })
+        .collect();
+    format!(
+        "{{\"live\":{},\"releases\":{},\"kinds\":{{{}}}}}",
+        live,
+        RELEASES.load(Ordering::Relaxed),
+        kinds.join(","),
+    )
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    for slot in &SLOTS.0 {
+        held.hold(slot);
+    }
+    held.hold(&HEALTH);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Stands in for a `GlobalRef`: counts how often it was dropped
+    struct FakeRef(Arc<AtomicU64>);
+
+    impl Drop for FakeRef {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn fake() -> (FakeRef, Arc<AtomicU64>) {
+        let drops = Arc::new(AtomicU64::new(0));
+        (FakeRef(Arc::clone(&drops)), drops)
+    }
+
+    #[test]
+    fn re_registration_frees_the_old_reference_exactly_once() {
+        let slots: Slots<FakeRef> = Slots::new();
+        let releases = RELEASES.load(Ordering::Relaxed);
+        let (first, first_drops) = fake();
+        let (second, second_drops) = fake();
+
+        assert!(!slots.replace(Kind::Log, Some(first)));
+        assert!(slots.replace(Kind::Log, Some(second)));
+        assert_eq!(first_drops.load(Ordering::Relaxed), 1);
+        assert_eq!(second_drops.load(Ordering::Relaxed), 0);
+        assert!(slots.get(Kind::Log).lock().unwrap().is_some());
+        // Other kinds are untouched
+        assert!(slots.get(Kind::State).lock().unwrap().is_none());
+
+        // Registering null releases; releasing an empty slot releases nothing
+        assert!(slots.replace(Kind::Log, None));
+        assert!(!slots.replace(Kind::Log, None));
+        assert_eq!((first_drops.load(Ordering::Relaxed), second_drops.load(Ordering::Relaxed)), (1, 1));
+        assert!(RELEASES.load(Ordering::Relaxed) >= releases + 2);
+    }
+
+    #[test]
+    fn releasing_everything_leaves_the_registry_empty() {
+        let _serial = testing::serial();
+        let slots: Slots<FakeRef> = Slots::new();
+        let counters: Vec<Arc<AtomicU64>> = KINDS
+            .iter()
+            .map(|kind| {
+                let (reference, drops) = fake();
+                slots.replace(*kind, Some(reference));
+                drops
+            })
+            .collect();
+        assert_eq!(slots.release_all(), KINDS.len());
+        assert!(counters.iter().all(|drops| drops.load(Ordering::Relaxed) == 1));
+        assert!(slots.0.iter().all(|slot| slot.lock().unwrap().is_none()));
+        assert_eq!(slots.release_all(), 0);
+
+        release_all();
+        let registrations: serde_json::Value = serde_json::from_str(&registrations_json()).unwrap();
+        assert_eq!(registrations["live"], 0);
+        assert!(KINDS.iter().all(|kind| registrations["kinds"][kind.as_str()]["registered"] == false));
+    }
+
+    #[test]
+    fn registering_too_often_is_reported_once() {
+        let _serial = testing::serial();
+        let kind = Kind::NetworkCondition;
+        let saved = REGISTRATIONS[kind as usize].swap(0, Ordering::Relaxed);
+        let warnings = || {
+            crate::tests::SENT_LOG_LINES
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|line| line.contains("network_condition callback registered"))
+                .count()
+        };
+        let before = warnings();
+        for _ in 0..EXPECTED_MAX_REGISTRATIONS {
+            registered(kind);
+        }
+        assert_eq!(warnings(), before);
+        for _ in 0..5 {
+            registered(kind);
+        }
+        assert_eq!(warnings(), before + 1);
+        REGISTRATIONS[kind as usize].store(saved, Ordering::Relaxed);
+    }
+
+    /// Start every kind healthy, putting the watchdog state back on drop
+    struct FreshHealth([Health; 6]);
+
+    impl FreshHealth {
+        fn new() -> FreshHealth {
+            let fresh = Health {
+                unhealthy: false,
+                prompt_streak: 0,
+                last_probe_ms: 0,
+                in_flight_since_ms: 0,
+                slow_calls: 0,
+                dropped: 0,
+            };
+            FreshHealth(std::mem::replace(&mut *HEALTH.lock().unwrap(), [fresh; 6]))
+        }
+    }
+
+    impl Drop for FreshHealth {
+        fn drop(&mut self) {
+            *HEALTH.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = self.0;
+        }
+    }
+
+    fn health(kind: Kind) -> Health {
+        HEALTH.lock().unwrap()[kind as usize]
+    }
+
+    /// Deliver one message of `kind` to a callback taking `ms`; whether it ran
+    fn upcall(time: &FakeTime, kind: Kind, ms: u64) -> bool {
+        let mut ran = false;
+        watched(kind, || {
+            ran = true;
+            time.advance(Duration::from_millis(ms));
+        });
+        ran
+    }
+
+    fn logged(text: &str) -> usize {
+        crate::tests::SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains(text)).count()
+    }
+
+    #[test]
+    fn upcalls_are_held_to_their_budgets() {
+        let _serial = testing::serial();
+        let _fresh = FreshHealth::new();
+        let time = FakeTime::start();
+        let kind = Kind::SecurityEvent;
+        // (upcall duration, delivered, healthy afterwards, slow calls so far)
+        let steps = [
+            (10, true, true, 0),
+            (SOFT_BUDGET_MS, true, true, 0),
+            (SOFT_BUDGET_MS + 1, true, true, 1),
+            (HARD_BUDGET_MS, true, true, 2),
+            (HARD_BUDGET_MS + 1, true, false, 3),
+            // Dropped until the next probe is due
+            (10, false, false, 3),
+        ];
+        for (i, (ms, delivered, healthy, slow_calls)) in steps.into_iter().enumerate() {
+            assert_eq!(upcall(&time, kind, ms), delivered, "step {}", i);
+            let health = health(kind);
+            assert_eq!((!health.unhealthy, health.slow_calls), (healthy, slow_calls), "step {}", i);
+        }
+        assert_eq!(health(kind).dropped, 1);
+        assert_eq!(logged(&format!("security_event callback took {} ms", SOFT_BUDGET_MS + 1)), 1);
+        assert_eq!(logged(&format!("security_event callback took {} ms; dropping", HARD_BUDGET_MS + 1)), 1);
+
+        let registrations: serde_json::Value = serde_json::from_str(&registrations_json()).unwrap();
+        let reported = &registrations["kinds"]["security_event"];
+        assert_eq!((reported["healthy"].as_bool(), reported["slow_calls"].as_u64()), (Some(false), Some(3)));
+        assert_eq!(reported["dropped"], 1);
+    }
+
+    #[test]
+    fn a_stuck_upcall_only_holds_up_its_own_kind() {
+        let _serial = testing::serial();
+        let _fresh = FreshHealth::new();
+        let time = FakeTime::start();
+        let (entered_tx, entered) = std::sync::mpsc::channel();
+        let (release, release_rx) = std::sync::mpsc::channel::<()>();
+
+        // A log listener blocked on fsync
+        let stuck = std::thread::spawn(move || {
+            watched(Kind::Log, || {
+                entered_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            })
+        });
+        entered.recv().unwrap();
+
+        // Within the hard budget a second log message still goes out
+        time.advance(Duration::from_millis(HARD_BUDGET_MS));
+        assert!(upcall(&time, Kind::Log, 1));
+        assert!(stalled(Kind::Log));
+        assert!(!stalled(Kind::State));
+        // Past it the kind turns unhealthy while the upcall is still in flight
+        time.advance(Duration::from_millis(1));
+        for _ in 0..3 {
+            assert!(!upcall(&time, Kind::Log, 1));
+        }
+        assert!(health(Kind::Log).unhealthy);
+        assert_eq!(health(Kind::Log).dropped, 3);
+        // Every other kind keeps flowing
+        for kind in KINDS.into_iter().filter(|kind| *kind != Kind::Log) {
+            assert!(upcall(&time, kind, 1), "{:?}", kind);
+            assert!(!health(kind).unhealthy, "{:?}", kind);
+        }
+
+        // Once it returns, nothing is in flight; the next probe comes later
+        release.send(()).unwrap();
+        stuck.join().unwrap();
+        assert_eq!(health(Kind::Log).in_flight_since_ms, 0);
+        assert!(!upcall(&time, Kind::Log, 1));
+    }
+
+    #[test]
+    fn recovery_needs_prompt_upcalls_in_a_row() {
+        let _serial = testing::serial();
+        let _fresh = FreshHealth::new();
+        let time = FakeTime::start();
+        let kind = Kind::NotificationStatus;
+        assert!(upcall(&time, kind, HARD_BUDGET_MS + 1));
+        assert!(health(kind).unhealthy);
+
+        // A probe that is slow (though within the hard budget) doesn't count
+        assert!(!upcall(&time, kind, 1));
+        time.advance(Duration::from_millis(PROBE_INTERVAL_MS));
+        assert!(upcall(&time, kind, SOFT_BUDGET_MS + 1));
+        assert!(health(kind).unhealthy);
+        assert!(!upcall(&time, kind, 1));
+
+        // A prompt probe lets the next message through to complete the streak
+        time.advance(Duration::from_millis(PROBE_INTERVAL_MS));
+        let before = logged("notification_status callback answers promptly again");
+        for i in 0..RECOVERY_PROMPT_CALLS {
+            assert!(health(kind).unhealthy, "call {}", i);
+            assert!(upcall(&time, kind, 1), "call {}", i);
+        }
+        assert!(!health(kind).unhealthy);
+        assert_eq!(logged("notification_status callback answers promptly again"), before + 1);
+
+        // A slow upcall in the middle of a streak starts it over
+        assert!(upcall(&time, kind, HARD_BUDGET_MS + 1));
+        time.advance(Duration::from_millis(PROBE_INTERVAL_MS));
+        assert!(upcall(&time, kind, 1));
+        assert!(upcall(&time, kind, HARD_BUDGET_MS + 1));
+        assert!(health(kind).unhealthy);
+        assert_eq!(health(kind).prompt_streak, 0);
+        assert!(!upcall(&time, kind, 1));
+
+        // Registering a new callback starts it out healthy
+        registered(kind);
+        assert!(!health(kind).unhealthy);
+        assert!(upcall(&time, kind, 1));
+    }
+}
@@ -0,0 +1,83 @@
+//! Cancellation of the proxy's in-flight connections
+//!
+//! Aborting the accept task releases the port but leaves the handlers it
+//! spawned running, including any still waiting on `client.connect` for a slow
+//! circuit build. Every handler therefore races its work against the proxy's
+//! `CancelToken`; `stop` (and a data directory migration) cancel it, so all of
+//! them unwind at their current await point instead of finishing first.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Shared cancellation signal (clones observe the same signal)
+#[derive(Clone)]
+pub(crate) struct CancelToken(Arc<Inner>);
+
+impl CancelToken {
+    pub(crate) fn new() -> Self {
+        CancelToken(Arc::new(Inner {
+            cancelled: AtomicBool::new(false),
+            notify: Notify::new(),
+        }))
+    }
+
+    /// Signal every holder; idempotent
+    pub(crate) fn cancel(&self) {
+        if !self.0.cancelled.swap(true, Ordering::AcqRel) {
+            self.0.notify.notify_waiters();
+        }
+    }
+
+    /// Resolves once `cancel` has been called (immediately if it already was)
+    pub(crate) async fn cancelled(&self) {
+        loop {
+            // Registered before the flag check, so a cancel in between isn't missed
+            let notified = self.0.notify.notified();
+            if self.0.cancelled.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn every_clone_sees_one_cancel() {
+        testing::runtime().block_on(async {
+            let token = CancelToken::new();
+            let waiters: Vec<_> = (0..3)
+                .map(|_| {
+                    let token = token.clone();
+                    tokio::spawn(async move { token.cancelled().await })
+                })
+                .collect();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(waiters.iter().all(|waiter| !waiter.is_finished()));
+
+            token.cancel();
+            token.cancel();
+            for waiter in waiters {
+                tokio::time::timeout(Duration::from_secs(2), waiter).await.unwrap().unwrap();
+            }
+            // Waiting after the fact returns at once
+            tokio::time::timeout(Duration::from_secs(2), token.clone().cancelled()).await.unwrap();
+            // A new token is a new signal
+            let fresh = CancelToken::new();
+            assert!(tokio::time::timeout(Duration::from_millis(20), fresh.cancelled()).await.is_err());
+        });
+    }
+}
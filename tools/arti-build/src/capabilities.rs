@@ -0,0 +1,216 @@
+//! Capability probe for SOCKS clients
+//!
+//! A client can't tell this proxy from a plain SOCKS proxy (e.g. a
+//! user-configured external Tor) before relying on the credential options of
+//! target.rs. So a CONNECT to the reserved name `capabilities.invalid` is
+//! answered right here, before any other processing of the target: it is
+//! never evaluated, dialed or counted anywhere. The reply is
+//!
+//! ```text
+//! VER 0x05 | REP 0x02 | RSV 0x00 | ATYP 0x01 | BND.ADDR bitmap (u32, big-endian) | BND.PORT version (u16, big-endian)
+//! ```
+//!
+//! A plain proxy answers the same CONNECT with some failure and BND.PORT 0,
+//! so a nonzero version identifies this proxy; bits the client doesn't know
+//! are to be ignored. `getProxyCapabilities` reports the same bitmap with
+//! names. Bits are never reassigned: a retired capability keeps its bit,
+//! which is never set again. With `host_claim`, `front` and `onion_alias`
+//! set and nothing else, for example, BND.ADDR reads `0.0.0.7` and
+//! BND.PORT 1. The tests pin golden reply frames and the
+//! decoding a client does, for the Go and Kotlin decoders to check against.
+
+use std::sync::atomic::Ordering;
+
+use serde_json::json;
+
+/// The reserved probe name (`.invalid` can never resolve, RFC 2606)
+const PROBE_HOST: &str = "capabilities.invalid";
+
+/// Encoding version, reported as BND.PORT
+pub(crate) const VERSION: u16 = 1;
+
+struct Capability {
+    bit: u32,
+    name: &'static str,
+    /// Whether the capability is in effect now
+    active: fn() -> bool,
+}
+
+const CAPABILITIES: &[Capability] = &[
+    // `host=` credential option (target.rs)
+    Capability { bit: 0, name: "host_claim", active: || true },
+    // `front=` credential option (target.rs)
+    Capability { bit: 1, name: "front", active: || true },
+    // Names with an onion alias are dialed as their onion
+    Capability { bit: 2, name: "onion_alias", active: || true },
+    // Handshakes must carry the listener secret
+    Capability { bit: 3, name: "listener_auth", active: crate::listener_auth::required },
+    // Only onion targets are connected
+    Capability { bit: 4, name: "onion_only", active: crate::target::onion_only },
+    // IPv6 targets are refused with reply 0x08
+    Capability { bit: 5, name: "ipv6_targets_disabled", active: || crate::DISABLE_IPV6_TARGETS.load(Ordering::Relaxed) },
+    // Retired: connects failing at the TLS or crypto layer replied 0x09
+    Capability { bit: 6, name: "crypto_failure_reply", active: || false },
+    // `class=` credential option: traffic classes are isolated (target.rs)
+    Capability { bit: 7, name: "class", active: || true },
+    // `deadline=` credential option (target.rs)
+    Capability { bit: 8, name: "deadline", active: || true },
+    // `connid=` credential option, listed by listConnections (target.rs)
+    Capability { bit: 9, name: "connid", active: || true },
+    // `iso=` credential option: isolation tokens (target.rs)
+    Capability { bit: 10, name: "iso", active: || true },
+    // Connects failing at the relay handshake or crypto layer reply 0x04 and
+    // are reported as a stream event (security.rs)
+    Capability { bit: 11, name: "crypto_failure_event", active: || true },
+];
+
+/// Whether a domain CONNECT target is the probe
+pub(crate) fn is_probe(host: &str) -> bool {
+    host.trim_end_matches('.').eq_ignore_ascii_case(PROBE_HOST)
+}
+
+/// The capabilities in effect, one bit each
+pub(crate) fn bitmap() -> u32 {
+    CAPABILITIES
+        .iter()
+        .filter(|capability| (capability.active)())
+        .fold(0, |bits, capability| bits | 1 << capability.bit)
+}
+
+/// The probe's reply frame
+pub(crate) fn reply_frame() -> [u8; 10] {
+    frame(bitmap())
+}
+
+/// The reply frame carrying `bitmap`
+fn frame(bitmap: u32) -> [u8; 10] {
+    crate::socks::Reply::NotAllowedByRuleset.frame_with_bound(bitmap.to_be_bytes(), VERSION)
+}
+
+/// The bitmap with names as JSON (`getProxyCapabilities`)
+pub(crate) fn to_json() -> String {
+    let bitmap = bitmap();
+    let active: Vec<&str> = CAPABILITIES
+        .iter()
+        .filter(|capability| bitmap & 1 << capability.bit != 0)
+        .map(|capability| capability.name)
+        .collect();
+    let bits: serde_json::Map<String, serde_json::Value> =
+        CAPABILITIES.iter().map(|capability| (capability.name.to_string(), json!(capability.bit))).collect();
+    json!({
+        "version": VERSION,
+        "bitmap": bitmap,
+        "capabilities": active,
+        "bits": bits,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// What a client does with the reply: the bitmap and version, or `None`
+    /// for a proxy without the probe
+    fn decode(frame: [u8; 10]) -> Option<(u32, u16)> {
+        let version = u16::from_be_bytes([frame[8], frame[9]]);
+        (frame[..4] == [0x05, 0x02, 0x00, 0x01] && version != 0)
+            .then(|| (u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]), version))
+    }
+
+    #[test]
+    fn reply_frames_match_the_golden_vectors() {
+        for (bitmap, golden) in [
+            (0x0000_0000, [0x05, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]),
+            // host_claim, front and onion_alias: the module docs' example
+            (0x0000_0007, [0x05, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x07, 0x00, 0x01]),
+            (0x0000_0047, [0x05, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x47, 0x00, 0x01]),
+            (0x0000_007f, [0x05, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7f, 0x00, 0x01]),
+            // Every bit defined so far
+            (0x0000_0fff, [0x05, 0x02, 0x00, 0x01, 0x00, 0x00, 0x0f, 0xff, 0x00, 0x01]),
+            // Bits this version doesn't define still travel big-endian
+            (0x8001_0200, [0x05, 0x02, 0x00, 0x01, 0x80, 0x01, 0x02, 0x00, 0x00, 0x01]),
+        ] {
+            assert_eq!(frame(bitmap), golden, "{:#010x}", bitmap);
+            assert_eq!(decode(golden), Some((bitmap, VERSION)), "{:#010x}", bitmap);
+        }
+    }
+
+    #[test]
+    fn clients_tell_a_plain_proxy_from_this_one() {
+        for (frame, decoded) in [
+            // A plain proxy refusing the name, or failing to resolve it
+            ([0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0], None),
+            ([0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0], None),
+            ([0x05, 0x01, 0x00, 0x01, 0, 0, 0, 7, 0, 1], None),
+            // A later version keeps the layout
+            ([0x05, 0x02, 0x00, 0x01, 0, 0, 0x01, 0x03, 0, 2], Some((0x0103, 2))),
+        ] {
+            assert_eq!(decode(frame), decoded, "{:02x?}", frame);
+        }
+    }
+
+    #[test]
+    fn bits_are_never_reassigned() {
+        let assigned: Vec<(u32, &str)> = CAPABILITIES.iter().map(|capability| (capability.bit, capability.name)).collect();
+        assert_eq!(
+            assigned,
+            [
+                (0, "host_claim"),
+                (1, "front"),
+                (2, "onion_alias"),
+                (3, "listener_auth"),
+                (4, "onion_only"),
+                (5, "ipv6_targets_disabled"),
+                (6, "crypto_failure_reply"),
+                (7, "class"),
+                (8, "deadline"),
+                (9, "connid"),
+                (10, "iso"),
+                (11, "crypto_failure_event"),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_bitmap_follows_the_settings() {
+        let _serial = testing::serial();
+        let _saved = crate::config::save();
+        // Retired bit 6 is never set
+        let always = 0b1111_1000_0111;
+        crate::config::set("onion_only", json!(false)).unwrap();
+        crate::config::set("disable_ipv6_targets", json!(false)).unwrap();
+        assert_eq!(bitmap() & !(1 << 3), always);
+
+        crate::config::set("onion_only", json!(true)).unwrap();
+        assert_eq!(bitmap() & !(1 << 3), always | 1 << 4);
+        crate::config::set("disable_ipv6_targets", json!(true)).unwrap();
+        assert_eq!(bitmap() & !(1 << 3), always | 1 << 4 | 1 << 5);
+        assert_eq!(decode(reply_frame()), Some((bitmap(), VERSION)));
+
+        let reported: serde_json::Value = serde_json::from_str(&to_json()).unwrap();
+        assert_eq!(reported["bitmap"], bitmap());
+        assert_eq!(reported["version"], VERSION);
+        assert_eq!(reported["bits"]["onion_only"], 4);
+        let names = reported["capabilities"].as_array().unwrap();
+        assert!(names.contains(&json!("onion_only")) && names.contains(&json!("ipv6_targets_disabled")));
+        assert_eq!(names.len() as u32, bitmap().count_ones());
+        assert!(!names.contains(&json!("crypto_failure_reply")));
+        assert_eq!(reported["bits"]["crypto_failure_reply"], 6);
+    }
+
+    #[test]
+    fn only_the_reserved_name_is_the_probe() {
+        for (host, probe) in [
+            ("capabilities.invalid", true),
+            ("CAPABILITIES.Invalid", true),
+            ("capabilities.invalid.", true),
+            ("x.capabilities.invalid", false),
+            ("capabilities.invalid.example", false),
+            ("capabilities", false),
+        ] {
+            assert_eq!(is_probe(host), probe, "{}", host);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Ongoing watch for the network turning censored or open again
+//!
+//! `probeDirectReachability` answers once, when asked. With a monitor interval
+//! set (`setCensorshipMonitorInterval`, off by default) the heartbeat repeats
+//! the same probe in the background and tells the app when the answer
+//! changes, so the UI can suggest bridges before the user notices failures:
+//!
+//! - the condition is `open` (relays directly reachable) or `filtered`
+//!   (blocked); an `Unknown` probe (device offline) leaves it as it was;
+//! - a change is only believed after `CONFIRMATIONS` probes in a row agree,
+//!   so a single lost probe doesn't flip it back and forth;
+//! - each change is delivered to `onNetworkConditionChanged(String)` as
+//!   `{"condition":..,"previous":..,"at_epoch_ms":..}`; the first condition
+//!   found has `previous` null.
+//!
+//! A probe is a few TCP connects with nothing sent, and none is started
+//! while one is in flight, the battery is low, or strict Tor-only mode
+//! forbids direct connections.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use serde_json::json;
+
+use crate::callbacks::Kind;
+use crate::notification::now_epoch_ms;
+use crate::reachability::{self, Reachability};
+use crate::{net, retention, seams};
+
+/// Longest interval of `setCensorshipMonitorInterval`
+pub(crate) const MAX_INTERVAL_MINS: u64 = 24 * 60;
+
+/// Probes in a row that must agree before the condition changes
+const CONFIRMATIONS: u32 = 2;
+
+/// Time allowed for each probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minutes between probes (0: monitor off)
+static INTERVAL_MINS: AtomicU64 = AtomicU64::new(0);
+
+static PROBING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Condition {
+    Open,
+    Filtered,
+}
+
+impl Condition {
+    fn name(self) -> &'static str {
+        match self {
+            Condition::Open => "open",
+            Condition::Filtered => "filtered",
+        }
+    }
+}
+
+struct Monitor {
+    last_probe: Option<Instant>,
+    condition: Option<Condition>,
+    /// A differing result and how many probes in a row have seen it
+    candidate: Option<(Condition, u32)>,
+}
+
+static MONITOR: Mutex<Monitor> = Mutex::new(Monitor { last_probe: None, condition: None, candidate: None });
+
+pub(crate) fn set_interval_mins(mins: u64) {
+    INTERVAL_MINS.store(mins, Ordering::Relaxed);
+}
+
+/// Start a probe if one is due (called from the heartbeat)
+pub(crate) fn tick() {
+    tick_with(|| {
+        Box::pin(async {
+            let client = crate::ARTI_CLIENT.lock().unwrap().as_ref().map(Arc::clone);
+            reachability::probe(client.as_deref(), PROBE_TIMEOUT).await
+        })
+    });
+}
+
+/// `tick` running `probe` for the reachability probe
+fn tick_with(probe: fn() -> BoxFuture<'static, Reachability>) {
+    let mins = INTERVAL_MINS.load(Ordering::Relaxed);
+    if mins == 0 || crate::battery::low() || net::ensure_direct_allowed("censorship_monitor").is_err() {
+        return;
+    }
+    let now = seams::now_monotonic();
+    {
+        let mut monitor = MONITOR.lock().unwrap();
+        if monitor.last_probe.is_some_and(|at| now.saturating_duration_since(at) < Duration::from_secs(mins * 60)) {
+            return;
+        }
+        if PROBING.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        monitor.last_probe = Some(now);
+    }
+    tokio::spawn(async move {
+        let result = probe().await;
+        PROBING.store(false, Ordering::SeqCst);
+        if let Some(event) = observe(result) {
+            // The upcall may block; keep it off the runtime's workers
+            tokio::task::spawn_blocking(move || {
+                crate::send_string_to_java(Kind::NetworkCondition, "onNetworkConditionChanged", &event);
+            });
+        }
+    });
+}
+
+/// Fold a probe result into the condition; the event to deliver on a change
+fn observe(result: Reachability) -> Option<String> {
+    let seen = match result {
+        Reachability::Reachable => Condition::Open,
+        Reachability::Blocked => Condition::Filtered,
+        Reachability::Unknown => return None,
+    };
+    let mut monitor = MONITOR.lock().unwrap();
+    if monitor.condition == Some(seen) {
+        monitor.candidate = None;
+        return None;
+    }
+    let count = match monitor.candidate {
+        Some((condition, count)) if condition == seen => count + 1,
+        _ => 1,
+    };
+    // The first condition is believed at once: there is nothing to flap from
+    if monitor.condition.is_some() && count < CONFIRMATIONS {
+        monitor.candidate = Some((seen, count));
+        return None;
+    }
+    let previous = monitor.condition.replace(seen);
+    monitor.candidate = None;
+    log_info!("Network condition: {}", seen.name());
+    Some(
+        json!({
+            "condition": seen.name(),
+            "previous": previous.map(Condition::name),
+            "at_epoch_ms": retention::timestamp(now_epoch_ms()),
+        })
+        .to_string(),
+    )
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&MONITOR);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    /// What the fake probe answers next
+    static ANSWER: Mutex<Reachability> = Mutex::new(Reachability::Unknown);
+
+    /// Probes the fake has run
+    static PROBES: AtomicU64 = AtomicU64::new(0);
+
+    fn fake_probe() -> BoxFuture<'static, Reachability> {
+        PROBES.fetch_add(1, Ordering::SeqCst);
+        let answer = *ANSWER.lock().unwrap();
+        Box::pin(async move { answer })
+    }
+
+    /// Puts back the monitor state and the interval
+    struct Isolated(u64);
+
+    impl Isolated {
+        fn new() -> Isolated {
+            *MONITOR.lock().unwrap() = Monitor { last_probe: None, condition: None, candidate: None };
+            Isolated(INTERVAL_MINS.load(Ordering::Relaxed))
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            *MONITOR.lock().unwrap() = Monitor { last_probe: None, condition: None, candidate: None };
+            INTERVAL_MINS.store(self.0, Ordering::Relaxed);
+        }
+    }
+
+    fn condition_events() -> Vec<(String, Option<String>)> {
+        crate::tests::SENT_STRINGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(method, _)| method == "onNetworkConditionChanged")
+            .map(|(_, event)| {
+                let event: serde_json::Value = serde_json::from_str(event).unwrap();
+                (event["condition"].as_str().unwrap().to_string(), event["previous"].as_str().map(str::to_string))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_change_is_believed_after_two_agreeing_probes() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        use Reachability::*;
+        // (probe result, condition afterwards, whether it changed)
+        let steps = [
+            (Unknown, None, false),
+            (Reachable, Some(Condition::Open), true),
+            (Blocked, Some(Condition::Open), false),
+            (Reachable, Some(Condition::Open), false),
+            (Blocked, Some(Condition::Open), false),
+            (Unknown, Some(Condition::Open), false),
+            (Blocked, Some(Condition::Filtered), true),
+            (Blocked, Some(Condition::Filtered), false),
+            (Reachable, Some(Condition::Filtered), false),
+            (Reachable, Some(Condition::Open), true),
+        ];
+        for (i, (result, condition, changed)) in steps.into_iter().enumerate() {
+            assert_eq!(observe(result).is_some(), changed, "step {}", i);
+            assert_eq!(MONITOR.lock().unwrap().condition, condition, "step {}", i);
+        }
+    }
+
+    #[test]
+    fn the_monitor_reports_a_transition_to_the_callback() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let time = FakeTime::start();
+        crate::tests::SENT_STRINGS.lock().unwrap().clear();
+        PROBES.store(0, Ordering::SeqCst);
+        let wait_for = |probes: u64, events: usize| async move {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while PROBES.load(Ordering::SeqCst) < probes || PROBING.load(Ordering::SeqCst) || condition_events().len() < events {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("probe or callback never came");
+        };
+
+        testing::runtime().block_on(async {
+            // Off by default
+            tick_with(fake_probe);
+            assert_eq!(PROBES.load(Ordering::SeqCst), 0);
+
+            set_interval_mins(5);
+            *ANSWER.lock().unwrap() = Reachability::Reachable;
+            tick_with(fake_probe);
+            if cfg!(feature = "strict-tor-only") {
+                // Strict builds never probe directly
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                assert_eq!(PROBES.load(Ordering::SeqCst), 0);
+                return;
+            }
+            wait_for(1, 1).await;
+            // Not due again until the interval has passed
+            time.advance(Duration::from_secs(4 * 60));
+            tick_with(fake_probe);
+            assert_eq!(PROBES.load(Ordering::SeqCst), 1);
+
+            // The network starts filtering: reported on the second probe
+            *ANSWER.lock().unwrap() = Reachability::Blocked;
+            for probes in [2, 3] {
+                time.advance(Duration::from_secs(5 * 60));
+                tick_with(fake_probe);
+                wait_for(probes, 1).await;
+            }
+            wait_for(3, 2).await;
+
+            // Skipped on low battery and in strict mode
+            let was_low = crate::battery::low();
+            crate::battery::set_low(true);
+            time.advance(Duration::from_secs(5 * 60));
+            tick_with(fake_probe);
+            crate::battery::set_low(was_low);
+            let strict = crate::net::strict_tor_only();
+            crate::net::set_strict_tor_only(true);
+            tick_with(fake_probe);
+            crate::net::set_strict_tor_only(strict);
+            assert_eq!(PROBES.load(Ordering::SeqCst), 3);
+        });
+
+        let expected = [("open".to_string(), None), ("filtered".to_string(), Some("open".to_string()))];
+        assert_eq!(condition_events(), if cfg!(feature = "strict-tor-only") { &[][..] } else { &expected[..] });
+    }
+}
@@ -0,0 +1,440 @@
+//! Synthetic bad-network conditions for QA (`chaos` builds only)
+//!
+//! To reproduce a terrible Tor day on an emulator, `setChaosProfile` installs
+//! a profile that degrades the local forwarding path:
+//!
+//! ```text
+//! {"connect_delay_ms":800,"connect_jitter_ms":400,
+//!  "connect_failure_rate":0.1,"connect_failure_replies":["connection_refused"],
+//!  "latency_ms":200,"jitter_ms":100,
+//!  "stall_probability":0.01,"stall_ms":5000,
+//!  "max_bytes_per_sec":32768}
+//! ```
+//!
+//! - before connecting, each connection waits the connect delay (± jitter),
+//!   then fails with the given probability, sending one of the listed SOCKS
+//!   replies (`general_failure` or `connection_refused`);
+//! - each direction of the forwarding loop is shaped on its own: every chunk
+//!   is held back by the latency (± jitter), stalls for `stall_ms` with
+//!   `stall_probability`, and is paced to `max_bytes_per_sec`. Bytes are
+//!   never altered.
+//!
+//! All fields are optional. A profile applies to connections accepted after
+//! it is set, which log that they run under chaos; an empty profile clears
+//! it. Delays go through the seams clock and rolls through its entropy, so a
+//! test-seams build can drive them deterministically.
+//!
+//! Without the `chaos` cargo feature only inert stand-ins remain: there is no
+//! profile, no JNI function, and the hooks compile to nothing.
+
+#[cfg(feature = "chaos")]
+pub(crate) use enabled::*;
+
+#[cfg(not(feature = "chaos"))]
+pub(crate) use disabled::*;
+
+#[cfg(feature = "chaos")]
+mod enabled {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use crate::seams;
+    use crate::socks::Reply;
+
+    /// Longest delay any field may ask for
+    const MAX_DELAY_MS: u64 = 120_000;
+
+    pub(crate) struct Profile {
+        connect_delay_ms: u64,
+        connect_jitter_ms: u64,
+        connect_failure_rate: f64,
+        connect_failure_replies: Vec<Reply>,
+        latency_ms: u64,
+        jitter_ms: u64,
+        stall_probability: f64,
+        stall_ms: u64,
+        max_bytes_per_sec: Option<u64>,
+    }
+
+    static PROFILE: Mutex<Option<Arc<Profile>>> = Mutex::new(None);
+
+    fn delay_field(object: &serde_json::Map<String, Value>, name: &str) -> Result<u64, String> {
+        match object.get(name) {
+            None => Ok(0),
+            Some(v) => v
+                .as_u64()
+                .filter(|ms| *ms <= MAX_DELAY_MS)
+                .ok_or_else(|| format!("{} must be 0..={} ms", name, MAX_DELAY_MS)),
+        }
+    }
+
+    fn probability_field(object: &serde_json::Map<String, Value>, name: &str) -> Result<f64, String> {
+        match object.get(name) {
+            None => Ok(0.0),
+            Some(v) => v
+                .as_f64()
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(|| format!("{} must be 0..=1", name)),
+        }
+    }
+
+    /// Parse a profile; `None` for an empty one
+    pub(crate) fn parse(text: &str) -> Result<Option<Profile>, String> {
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        let value: Value = serde_json::from_str(text).map_err(|e| format!("invalid JSON: {}", e))?;
+        let object = value.as_object().ok_or("expected a JSON object")?;
+        if object.is_empty() {
+            return Ok(None);
+        }
+
+        let replies = match object.get("connect_failure_replies") {
+            None => vec![Reply::GeneralFailure],
+            Some(v) => {
+                let names = v.as_array().ok_or("connect_failure_replies must be an array")?;
+                let replies = names
+                    .iter()
+                    .map(|name| match name.as_str() {
+                        Some("general_failure") => Ok(Reply::GeneralFailure),
+                        Some("connection_refused") => Ok(Reply::ConnectionRefused),
+                        _ => Err(format!("unknown reply {}", name)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if replies.is_empty() {
+                    return Err("connect_failure_replies must not be empty".into());
+                }
+                replies
+            }
+        };
+        let max_bytes_per_sec = match object.get("max_bytes_per_sec") {
+            None => None,
+            Some(v) => Some(v.as_u64().filter(|n| *n > 0).ok_or("max_bytes_per_sec must be positive")?),
+        };
+
+        Ok(Some(Profile {
+            connect_delay_ms: delay_field(object, "connect_delay_ms")?,
+            connect_jitter_ms: delay_field(object, "connect_jitter_ms")?,
+            connect_failure_rate: probability_field(object, "connect_failure_rate")?,
+            connect_failure_replies: replies,
+            latency_ms: delay_field(object, "latency_ms")?,
+            jitter_ms: delay_field(object, "jitter_ms")?,
+            stall_probability: probability_field(object, "stall_probability")?,
+            stall_ms: delay_field(object, "stall_ms")?,
+            max_bytes_per_sec,
+        }))
+    }
+
+    /// Install `profile` for connections accepted from now on
+    pub(crate) fn set(profile: Option<Profile>) {
+        *PROFILE.lock().unwrap() = profile.map(Arc::new);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+        held.hold(&PROFILE);
+    }
+
+    /// Uniform in [0, 1)
+    fn roll() -> f64 {
+        let mut bytes = [0u8; 8];
+        seams::fill_random(&mut bytes);
+        (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// `base` ± up to `jitter`, never negative
+    fn jittered(base: u64, jitter: u64) -> Duration {
+        let offset = (roll() * 2.0 - 1.0) * jitter as f64;
+        Duration::from_millis((base as f64 + offset).max(0.0) as u64)
+    }
+
+    /// The profile a connection was accepted under
+    pub(crate) struct Connection(Option<Arc<Profile>>);
+
+    impl Connection {
+        pub(crate) fn start() -> Self {
+            Connection(PROFILE.lock().unwrap().clone())
+        }
+
+        pub(crate) fn active(&self) -> bool {
+            self.0.is_some()
+        }
+
+        /// Apply the connect delay, then the forced failure if it is rolled
+        pub(crate) async fn before_connect(&self) -> Result<(), Reply> {
+            let Some(profile) = self.0.as_ref() else {
+                return Ok(());
+            };
+            let delay = jittered(profile.connect_delay_ms, profile.connect_jitter_ms);
+            if !delay.is_zero() {
+                seams::sleep(delay).await;
+            }
+            if roll() < profile.connect_failure_rate {
+                let replies = &profile.connect_failure_replies;
+                let index = ((roll() * replies.len() as f64) as usize).min(replies.len() - 1);
+                return Err(replies[index]);
+            }
+            Ok(())
+        }
+
+        /// Shaping for one direction of the forwarding loop
+        pub(crate) fn shaper(&self) -> Shaper {
+            Shaper(self.0.clone())
+        }
+    }
+
+    pub(crate) struct Shaper(Option<Arc<Profile>>);
+
+    impl Shaper {
+        /// Hold back a chunk of `len` bytes before it is written
+        pub(crate) async fn pace(&mut self, len: usize) {
+            let Some(profile) = self.0.as_ref() else {
+                return;
+            };
+            let mut delay = jittered(profile.latency_ms, profile.jitter_ms);
+            if roll() < profile.stall_probability {
+                delay += Duration::from_millis(profile.stall_ms);
+            }
+            if let Some(rate) = profile.max_bytes_per_sec {
+                delay += Duration::from_secs_f64(len as f64 / rate as f64);
+            }
+            if !delay.is_zero() {
+                seams::sleep(delay).await;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::future::Future;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::*;
+        use crate::seams::fakes::{self, FakeConnector};
+        use crate::testing::{self, socks_connect, socks_greet, socks_session, FakeTime};
+
+        /// Uninstalls the profile and the seeded entropy when dropped
+        struct Installed;
+
+        impl Installed {
+            fn profile(json: &str) -> Installed {
+                set(parse(json).unwrap());
+                Installed
+            }
+        }
+
+        impl Drop for Installed {
+            fn drop(&mut self) {
+                set(None);
+                fakes::use_os_entropy();
+            }
+        }
+
+        /// Spawn `future` and report how much fake time it took, advancing
+        /// in `step`s until it finishes
+        fn fake_elapsed<T: Send + 'static>(
+            time: &FakeTime,
+            step: Duration,
+            future: impl Future<Output = T> + Send + 'static,
+        ) -> (Duration, T) {
+            let runtime = testing::runtime();
+            let started = seams::now_monotonic();
+            let task = runtime.spawn(future);
+            while !task.is_finished() {
+                // Let the task reach its sleep before moving time
+                std::thread::sleep(Duration::from_millis(2));
+                if !task.is_finished() {
+                    time.advance(step);
+                }
+            }
+            let elapsed = seams::now_monotonic() - started;
+            (elapsed, runtime.block_on(task).unwrap())
+        }
+
+        #[test]
+        fn profiles_are_checked_field_by_field() {
+            assert!(parse("").unwrap().is_none());
+            assert!(parse("{}").unwrap().is_none());
+            let full = r#"{"connect_delay_ms":800,"connect_jitter_ms":400,"connect_failure_rate":0.1,
+                "connect_failure_replies":["connection_refused","general_failure"],"latency_ms":200,"jitter_ms":100,
+                "stall_probability":0.01,"stall_ms":5000,"max_bytes_per_sec":32768}"#;
+            let profile = parse(full).unwrap().unwrap();
+            assert_eq!(profile.connect_failure_replies, [Reply::ConnectionRefused, Reply::GeneralFailure]);
+            assert_eq!(profile.max_bytes_per_sec, Some(32768));
+            assert_eq!(parse(r#"{"latency_ms":1}"#).unwrap().unwrap().connect_failure_replies, [Reply::GeneralFailure]);
+
+            for invalid in [
+                "[]",
+                "not json",
+                r#"{"latency_ms":-1}"#,
+                r#"{"latency_ms":120001}"#,
+                r#"{"connect_failure_rate":1.5}"#,
+                r#"{"stall_probability":"often"}"#,
+                r#"{"connect_failure_replies":[]}"#,
+                r#"{"connect_failure_replies":["ttl_expired"]}"#,
+                r#"{"max_bytes_per_sec":0}"#,
+            ] {
+                assert!(parse(invalid).is_err(), "{}", invalid);
+            }
+        }
+
+        #[test]
+        fn connect_delay_and_jitter_follow_the_clock() {
+            let _serial = testing::serial();
+            let time = FakeTime::start();
+            let step = Duration::from_millis(10);
+            for (json, low, high) in [
+                (r#"{"connect_delay_ms":800}"#, 800, 800),
+                (r#"{"connect_delay_ms":800,"connect_jitter_ms":400}"#, 400, 1200),
+                (r#"{"latency_ms":100}"#, 0, 0),
+            ] {
+                let _installed = Installed::profile(json);
+                for seed in 0..5 {
+                    fakes::seed_entropy(seed);
+                    let connection = Connection::start();
+                    let (elapsed, outcome) = fake_elapsed(&time, step, async move { connection.before_connect().await });
+                    assert_eq!(outcome, Ok(()));
+                    let ms = elapsed.as_millis() as u64;
+                    // Time moves in steps, so the last one may overshoot
+                    assert!(ms >= low && ms <= high + 10, "{}: {} ms", json, ms);
+                }
+            }
+        }
+
+        #[test]
+        fn forced_failures_follow_the_rate_and_replies() {
+            let _serial = testing::serial();
+            let runtime = testing::runtime();
+            let outcomes = |json: &str, seed: u64| {
+                let _installed = Installed::profile(json);
+                fakes::seed_entropy(seed);
+                let connection = Connection::start();
+                (0..1000).map(|_| runtime.block_on(connection.before_connect())).collect::<Vec<_>>()
+            };
+
+            let never = outcomes(r#"{"connect_failure_rate":0}"#, 1);
+            assert!(never.iter().all(Result::is_ok));
+            let always = outcomes(r#"{"connect_failure_rate":1,"connect_failure_replies":["connection_refused"]}"#, 1);
+            assert!(always.iter().all(|o| *o == Err(Reply::ConnectionRefused)));
+
+            let json = r#"{"connect_failure_rate":0.3,"connect_failure_replies":["connection_refused","general_failure"]}"#;
+            let some = outcomes(json, 7);
+            let count = |outcome: Result<(), Reply>| some.iter().filter(|o| **o == outcome).count();
+            assert!((250..=350).contains(&(1000 - count(Ok(())))), "{} failures", 1000 - count(Ok(())));
+            assert!(count(Err(Reply::ConnectionRefused)) > 100 && count(Err(Reply::GeneralFailure)) > 100);
+            // Deterministic under a seed
+            assert_eq!(outcomes(json, 7), some);
+        }
+
+        #[test]
+        fn shaping_adds_latency_stalls_and_pacing_per_chunk() {
+            let _serial = testing::serial();
+            let time = FakeTime::start();
+            let step = Duration::from_millis(10);
+            for (json, len, expected_ms) in [
+                (r#"{"latency_ms":200}"#, 1, 200),
+                (r#"{"max_bytes_per_sec":1000}"#, 500, 500),
+                (r#"{"latency_ms":200,"max_bytes_per_sec":1000}"#, 500, 700),
+                (r#"{"latency_ms":200,"stall_probability":1,"stall_ms":5000}"#, 1, 5200),
+                (r#"{"connect_delay_ms":800}"#, 1, 0),
+            ] {
+                let _installed = Installed::profile(json);
+                let mut shaper = Connection::start().shaper();
+                let (elapsed, ()) = fake_elapsed(&time, step, async move { shaper.pace(len).await });
+                let ms = elapsed.as_millis() as u64;
+                assert!(ms >= expected_ms && ms <= expected_ms + 10, "{}: {} ms", json, ms);
+            }
+        }
+
+        #[test]
+        fn a_connection_keeps_the_profile_it_was_accepted_under() {
+            let _serial = testing::serial();
+            let installed = Installed::profile(r#"{"latency_ms":200}"#);
+            let accepted = Connection::start();
+            drop(installed);
+            assert!(accepted.active());
+            assert!(!Connection::start().active());
+        }
+
+        #[test]
+        fn chaos_applies_to_real_socks_sessions() {
+            let _serial = testing::serial();
+            let time = FakeTime::start();
+            let _installed =
+                Installed::profile(r#"{"connect_failure_rate":1,"connect_failure_replies":["connection_refused"]}"#);
+            let connector = Arc::new(FakeConnector::new());
+            testing::runtime().block_on(async {
+                let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::ConnectionRefused.frame());
+                assert!(handler.await.unwrap().is_err());
+            });
+            // The failure came from the profile, not from Tor
+            assert!(connector.requests().is_empty());
+
+            // Latency holds back each direction of the forwarding loop
+            set(parse(r#"{"latency_ms":300}"#).unwrap());
+            let mut destination = connector.then_connect();
+            let runtime = testing::runtime();
+            let (mut client, handler) = runtime.block_on(async {
+                let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
+                client.write_all(b"ping").await.unwrap();
+                (client, handler)
+            });
+            let mut buf = [0u8; 4];
+            let arrived = |destination: &mut tokio::io::DuplexStream, buf: &mut [u8; 4]| {
+                runtime.block_on(async { tokio::time::timeout(Duration::from_millis(50), destination.read_exact(buf)).await })
+            };
+            assert!(arrived(&mut destination, &mut buf).is_err(), "arrived before the latency passed");
+            time.advance(Duration::from_millis(300));
+            arrived(&mut destination, &mut buf).unwrap().unwrap();
+            assert_eq!(&buf, b"ping");
+
+            runtime.block_on(async {
+                drop(destination);
+                client.shutdown().await.unwrap();
+                let _ = handler.await;
+            });
+            let established = crate::tests::SENT_LOG_LINES.lock().unwrap().iter().any(|line| {
+                line.contains("Tor connection established") && line.contains("(chaos)")
+            });
+            assert!(established);
+        }
+    }
+}
+
+#[cfg(not(feature = "chaos"))]
+mod disabled {
+    use crate::socks::Reply;
+
+    pub(crate) struct Connection;
+
+    impl Connection {
+        pub(crate) fn start() -> Self {
+            Connection
+        }
+
+        pub(crate) fn active(&self) -> bool {
+            false
+        }
+
+        pub(crate) async fn before_connect(&self) -> Result<(), Reply> {
+            Ok(())
+        }
+
+        pub(crate) fn shaper(&self) -> Shaper {
+            Shaper
+        }
+    }
+
+    pub(crate) struct Shaper;
+
+    impl Shaper {
+        pub(crate) async fn pace(&mut self, _len: usize) {}
+    }
+}
@@ -0,0 +1,164 @@
+//! Circuit path description for established connections
+//!
+//! With verbose logging on, every established connection logs the circuit
+//! its stream was attached to, as a DEBUG event (so it also lands in the
+//! debug capture ring when a failure follows). Hops are described as
+//! `role FINGERPRINT (CC)` using Arti's circuit introspection and the shared
+//! geo index.
+//!
+//! The guard is the one relay that stays the same across sessions for months,
+//! which makes it identifying, so it is always written as `[scrubbed]` with
+//! only its country kept. Middle and exit relays change per circuit and are
+//! shown in full, the exit with its advertised platform when the consensus
+//! has one. The destination itself is never part of the line.
+
+use arti_client::DataStream;
+use tor_linkspec::{HasRelayIds, OwnedChanTarget};
+
+use crate::geo;
+
+/// Role of the hop at `index` in a path of `len` hops
+fn role(index: usize, len: usize) -> &'static str {
+    match index {
+        0 => "guard",
+        i if i + 1 == len => "exit",
+        _ => "middle",
+    }
+}
+
+/// Describe the path of the circuit carrying `stream`, if Arti exposes one
+pub(crate) fn describe(stream: &DataStream) -> Option<String> {
+    let circuit = stream.client_stream_ctrl()?.circuit()?;
+    let path = circuit.path_ref().ok()?;
+    let hops: Vec<Option<String>> = path
+        .iter()
+        .map(|hop| {
+            hop.as_chan_target()
+                .and_then(|target| target.rsa_identity())
+                .map(|id| geo::fingerprint(id.as_bytes()))
+        })
+        .collect();
+    Some(describe_hops(hops))
+}
+
+/// Describe a path given each hop's fingerprint (None where Arti has none)
+fn describe_hops(hops: Vec<Option<String>>) -> String {
+    let len = hops.len();
+    let hops: Vec<String> = hops
+        .into_iter()
+        .enumerate()
+        .map(|(index, fingerprint)| {
+            let country = fingerprint.as_deref().map_or_else(|| geo::UNKNOWN_COUNTRY.to_string(), geo::country_of);
+            match (index, fingerprint) {
+                (0, _) => format!("guard [scrubbed] ({})", country),
+                (_, None) => format!("{} unknown ({})", role(index, len), country),
+                (i, Some(f)) if i + 1 == len => match geo::platform_of(&f) {
+                    Some(platform) => format!("exit {} ({}, {})", f, country, platform),
+                    None => format!("exit {} ({})", f, country),
+                },
+                (_, Some(f)) => format!("{} {} ({})", role(index, len), f, country),
+            }
+        })
+        .collect();
+    hops.join(" -> ")
+}
+
+/// The entry (first hop) of the circuit carrying `stream`
+pub(crate) fn entry(stream: &DataStream) -> Option<OwnedChanTarget> {
+    let circuit = stream.client_stream_ctrl()?.circuit()?;
+    let path = circuit.path_ref().ok()?;
+    let entry = path.iter().next()?.as_chan_target()?.clone();
+    Some(entry)
+}
+
+/// Fingerprint of the exit (last hop) of the circuit carrying `stream`
+pub(crate) fn exit(stream: &DataStream) -> Option<String> {
+    let circuit = stream.client_stream_ctrl()?.circuit()?;
+    let path = circuit.path_ref().ok()?;
+    let exit = path.iter().last()?.as_chan_target()?.rsa_identity()?;
+    Some(geo::fingerprint(exit.as_bytes()))
+}
+
+/// Identifier of the circuit carrying `stream`, unique within the process
+pub(crate) fn circuit_id(stream: &DataStream) -> Option<String> {
+    let circuit = stream.client_stream_ctrl()?.circuit()?;
+    Some(circuit.unique_id().to_string())
+}
+
+/// Log the path of `stream`'s circuit at DEBUG level when verbose logging is on
+pub(crate) fn log(stream: &DataStream, target_port: u16) {
+    if !crate::diagnostics::verbose() {
+        return;
+    }
+    log_path(describe(stream), target_port);
+}
+
+fn log_path(path: Option<String>, target_port: u16) {
+    match path {
+        Some(path) => tracing::debug!(target: "arti_android::circuit", "Circuit path for port {}: {}", target_port, path),
+        None => tracing::debug!(target: "arti_android::circuit", "Circuit path for port {}: unavailable", target_port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::{self, BridgeLayer};
+    use crate::testing;
+
+    const GUARD: &str = "AAAA000000000000000000000000000000000001";
+    const MIDDLE: &str = "BBBB000000000000000000000000000000000002";
+    const EXIT: &str = "CCCC000000000000000000000000000000000003";
+
+    fn hops(fingerprints: &[Option<&str>]) -> Vec<Option<String>> {
+        fingerprints.iter().map(|f| f.map(str::to_string)).collect()
+    }
+
+    #[test]
+    fn hops_are_described_with_the_guard_scrubbed() {
+        let _serial = testing::serial();
+        let _index = geo::fake_index(&[
+            (GUARD, Some("de"), None),
+            (MIDDLE, Some("nl"), None),
+            (EXIT, Some("se"), Some("Tor 0.4.8.12")),
+        ]);
+        for (path, described) in [
+            (
+                hops(&[Some(GUARD), Some(MIDDLE), Some(EXIT)]),
+                format!("guard [scrubbed] (de) -> middle {} (nl) -> exit {} (se, Tor 0.4.8.12)", MIDDLE, EXIT),
+            ),
+            // An exit without a known platform, a middle without a fingerprint
+            (
+                hops(&[Some(GUARD), None, Some(MIDDLE)]),
+                format!("guard [scrubbed] (de) -> middle unknown (??) -> exit {} (nl)", MIDDLE),
+            ),
+            (hops(&[None, Some(EXIT)]), format!("guard [scrubbed] (??) -> exit {} (se, Tor 0.4.8.12)", EXIT)),
+        ] {
+            assert_eq!(describe_hops(path), described);
+        }
+        assert!(!describe_hops(hops(&[Some(GUARD), Some(MIDDLE), Some(EXIT)])).contains(GUARD));
+    }
+
+    #[test]
+    fn the_path_reaches_the_logs_only_when_verbose() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let _serial = testing::serial();
+        let _index = geo::fake_index(&[(GUARD, Some("de"), None), (EXIT, Some("se"), None)]);
+        let was_verbose = diagnostics::verbose();
+        let logged = |marker: &str| crate::tests::SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains(marker));
+        let path = describe_hops(hops(&[Some(GUARD), Some(MIDDLE), Some(EXIT)]));
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(BridgeLayer), || {
+            for (verbose, port) in [(false, 8443), (true, 9443)] {
+                diagnostics::set_verbose(verbose);
+                log_path(Some(path.clone()), port);
+                let line = format!("DEBUG arti_android::circuit: Circuit path for port {}: {}", port, path);
+                assert_eq!(logged(&line), verbose, "{}", line);
+            }
+            log_path(None, 9444);
+            assert!(logged("Circuit path for port 9444: unavailable"));
+        });
+        diagnostics::set_verbose(was_verbose);
+    }
+}
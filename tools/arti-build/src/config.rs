@@ -0,0 +1,969 @@
+//! Wrapper settings with pending vs applied provenance
+//!
+//! Every setter may be called before `initialize`. Values are recorded here
+//! under these precedence rules:
+//!
+//! - The later call wins, whichever entry point it came through.
+//! - `initializeWithConfig` merges its JSON object over whatever is pending:
+//!   keys it names override earlier setter calls, keys it omits keep them.
+//! - `resetPendingConfig` discards everything that hasn't been applied yet,
+//!   onion client auth keys staged in onion_auth.rs included.
+//!
+//! Live settings (logging, isolation, buffer caps) take effect the moment they
+//! are recorded, so they are never pending. Client settings feed the Arti
+//! client config and only take effect at `initialize`, which commits every
+//! pending value in one step before building that config. Values are validated
+//! when recorded, so the commit itself can't fail halfway.
+//!
+//! Operations on a running client (probes, per-connection calls) are not
+//! settings and can't be deferred; they return `ERR_NOT_RUNNING` instead.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use serde_json::{json, Map, Value};
+
+/// When a setting takes effect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Scope {
+    /// Immediately, whatever the lifecycle state
+    Live,
+    /// On the next `initialize`
+    Client,
+}
+
+struct SettingDef {
+    name: &'static str,
+    scope: Scope,
+    default: fn() -> Value,
+    /// Reject malformed values before anything is recorded
+    check: fn(&Value) -> Result<(), String>,
+    /// Put a checked value into effect (live settings only)
+    put: fn(&Value),
+    /// Whether `initializeWithConfig` may set this key; settings carrying
+    /// secrets have their own setter and only report a summary here
+    json_settable: bool,
+}
+
+static SETTINGS: &[SettingDef] = &[
+    SettingDef {
+        name: "isolate_by_dest_port",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::ISOLATE_BY_DEST_PORT.store(v == &json!(true), Ordering::Relaxed),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "disable_ipv6_targets",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::DISABLE_IPV6_TARGETS.store(v == &json!(true), Ordering::Relaxed),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "onion_only",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::target::set_onion_only(v == &json!(true)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "require_domain_targets",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::target::set_require_domain_targets(v == &json!(true)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "strict_tor_only",
+        scope: Scope::Live,
+        default: || json!(cfg!(feature = "strict-tor-only")),
+        check: |v| {
+            expect_bool(v)?;
+            if cfg!(feature = "strict-tor-only") && v == &json!(false) {
+                return Err("strict Tor-only mode is forced by this build".into());
+            }
+            Ok(())
+        },
+        put: |v| {
+            let was_enabled = crate::net::strict_tor_only();
+            crate::net::set_strict_tor_only(v == &json!(true));
+            if was_enabled && v == &json!(false) {
+                crate::audit::record("strict_mode_disabled", "");
+            }
+        },
+        json_settable: true,
+    },
+    SettingDef {
+        name: "relay_buffer_cap_bytes",
+        scope: Scope::Live,
+        default: || json!(crate::buffers::DEFAULT_BUDGET_BYTES),
+        check: |v| match v.as_u64() {
+            Some(n) if n >= 2 * crate::buffers::MIN_BUFFER_SIZE as u64 => Ok(()),
+            _ => Err("expected a byte count of at least two minimum-size buffers".into()),
+        },
+        put: |v| {
+            crate::buffers::set_budget(v.as_u64().unwrap_or_default() as usize);
+        },
+        json_settable: true,
+    },
+    SettingDef {
+        name: "cache_budget_bytes",
+        scope: Scope::Live,
+        default: || json!(crate::caches::DEFAULT_BUDGET_BYTES),
+        check: |v| match v.as_u64() {
+            Some(n) if (crate::caches::MIN_BUDGET_BYTES..=crate::caches::MAX_BUDGET_BYTES).contains(&n) => Ok(()),
+            _ => Err(format!(
+                "expected {}..={} bytes",
+                crate::caches::MIN_BUDGET_BYTES,
+                crate::caches::MAX_BUDGET_BYTES
+            )),
+        },
+        put: |v| crate::caches::set_budget(v.as_u64().unwrap_or(crate::caches::DEFAULT_BUDGET_BYTES)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "metrics_snapshot_interval_mins",
+        scope: Scope::Live,
+        default: || json!(crate::metrics_history::DEFAULT_INTERVAL_MINS),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::metrics_history::MAX_INTERVAL_MINS => Ok(()),
+            _ => Err(format!("expected 0..={} minutes", crate::metrics_history::MAX_INTERVAL_MINS)),
+        },
+        put: |v| {
+            crate::metrics_history::set_interval_mins(v.as_u64().unwrap_or(crate::metrics_history::DEFAULT_INTERVAL_MINS))
+        },
+        json_settable: true,
+    },
+    SettingDef {
+        name: "query_timeout_ms",
+        scope: Scope::Live,
+        default: || json!(crate::query::DEFAULT_BUDGET_MS),
+        check: |v| match v.as_u64() {
+            Some(n) if (crate::query::MIN_BUDGET_MS..=crate::query::MAX_BUDGET_MS).contains(&n) => Ok(()),
+            _ => Err(format!("expected {}..={} ms", crate::query::MIN_BUDGET_MS, crate::query::MAX_BUDGET_MS)),
+        },
+        put: |v| crate::query::set_budget_ms(v.as_u64().unwrap_or(crate::query::DEFAULT_BUDGET_MS)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "query_cache_ttl_ms",
+        scope: Scope::Live,
+        default: || json!(crate::query::DEFAULT_TTL_MS),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::query::MAX_TTL_MS => Ok(()),
+            _ => Err(format!("expected 0..={} ms", crate::query::MAX_TTL_MS)),
+        },
+        put: |v| crate::query::set_ttl_ms(v.as_u64().unwrap_or(crate::query::DEFAULT_TTL_MS)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "log_file",
+        scope: Scope::Live,
+        default: || json!(""),
+        check: |v| match v.as_str() {
+            Some("") => Ok(()),
+            Some(path) => crate::log_file::check_path(path),
+            None => Err("expected a path string".into()),
+        },
+        put: |v| crate::log_file::set_path(v.as_str().unwrap_or_default()),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "policy_budget_ms",
+        scope: Scope::Live,
+        default: || json!(crate::policy::DEFAULT_BUDGET_MS),
+        check: |v| match v.as_u64() {
+            Some(n) if (1..=crate::policy::MAX_BUDGET_MS).contains(&n) => Ok(()),
+            _ => Err(format!("expected 1..={} ms", crate::policy::MAX_BUDGET_MS)),
+        },
+        put: |v| crate::policy::set_budget_ms(v.as_u64().unwrap_or(crate::policy::DEFAULT_BUDGET_MS)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "policy_default_allow",
+        scope: Scope::Live,
+        default: || json!(true),
+        check: expect_bool,
+        put: |v| crate::policy::set_default_allow(v != &json!(false)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "max_consensus_age_mode",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(0..=2) => Ok(()),
+            _ => Err("expected 0 (off), 1 (degraded) or 2 (strict)".to_string()),
+        },
+        put: |v| crate::consensus_age::set_mode(v.as_u64().unwrap_or(0) as u8),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "max_consensus_age_grace_mins",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::consensus_age::MAX_GRACE_MINUTES => Ok(()),
+            _ => Err(format!("expected 0..={} minutes", crate::consensus_age::MAX_GRACE_MINUTES)),
+        },
+        put: |v| crate::consensus_age::set_grace_minutes(v.as_u64().unwrap_or(0)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "lifecycle_debounce_ms",
+        scope: Scope::Live,
+        default: || json!(crate::debounce::DEFAULT_WINDOW_MS),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::debounce::MAX_WINDOW_MS => Ok(()),
+            _ => Err(format!("expected 0..={} ms", crate::debounce::MAX_WINDOW_MS)),
+        },
+        put: |v| crate::debounce::set_window_ms(v.as_u64().unwrap_or(crate::debounce::DEFAULT_WINDOW_MS)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "censorship_monitor_interval_mins",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::censorship::MAX_INTERVAL_MINS => Ok(()),
+            _ => Err(format!("expected 0..={} minutes", crate::censorship::MAX_INTERVAL_MINS)),
+        },
+        put: |v| crate::censorship::set_interval_mins(v.as_u64().unwrap_or(0)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "smoke_test_destination",
+        scope: Scope::Live,
+        default: || json!(""),
+        check: crate::smoke::check,
+        put: |v| crate::smoke::set_destination(v.as_str().unwrap_or_default()),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "recent_log_capacity",
+        scope: Scope::Live,
+        default: || json!(crate::recent_logs::DEFAULT_CAPACITY),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::recent_logs::MAX_CAPACITY as u64 => Ok(()),
+            _ => Err(format!("expected 0..={}", crate::recent_logs::MAX_CAPACITY)),
+        },
+        put: |v| crate::recent_logs::set_capacity(v.as_u64().unwrap_or_default() as usize),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "log_rate_limit_per_sec",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::log_rate::MAX_PER_SEC => Ok(()),
+            _ => Err(format!("expected 0..={}", crate::log_rate::MAX_PER_SEC)),
+        },
+        put: |v| crate::log_rate::set_limit(v.as_u64().unwrap_or_default() as u32),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "logcat_enabled",
+        scope: Scope::Live,
+        default: || json!(cfg!(debug_assertions)),
+        check: expect_bool,
+        put: |v| {
+            let enabled = v == &json!(true);
+            if enabled && !cfg!(debug_assertions) && !crate::LOGCAT_ENABLED.load(Ordering::Relaxed) {
+                crate::audit::record("unsafe_logging_enabled", "logcat in a release build");
+            }
+            crate::LOGCAT_ENABLED.store(enabled, Ordering::Relaxed);
+        },
+        json_settable: true,
+    },
+    SettingDef {
+        name: "safe_logging",
+        scope: Scope::Live,
+        default: || json!(true),
+        check: expect_bool,
+        put: |v| {
+            let enabled = v == &json!(true);
+            if !enabled && !cfg!(debug_assertions) && crate::redact::safe_logging() {
+                crate::audit::record("unsafe_logging_enabled", "safe logging off in a release build");
+            }
+            crate::redact::set_safe_logging(enabled);
+        },
+        json_settable: true,
+    },
+    SettingDef {
+        name: "require_log_callback",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::REQUIRE_LOG_CALLBACK.store(v == &json!(true), Ordering::Relaxed),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "verbose_logging",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::diagnostics::set_verbose(v == &json!(true)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "metadata_retention",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= 2 => Ok(()),
+            _ => Err("expected 0 (full), 1 (reduced) or 2 (minimal)".into()),
+        },
+        put: |v| crate::retention::set_level(v.as_u64().unwrap_or_default() as u8),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "persistent_destination_quality",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::quality::set_persistent(v == &json!(true)),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "client_stall_timeout_secs",
+        scope: Scope::Live,
+        default: || json!(crate::stall::DEFAULT_TIMEOUT_SECS),
+        check: |v| match v.as_u64() {
+            Some(n) if (1..=crate::stall::MAX_TIMEOUT_SECS).contains(&n) => Ok(()),
+            _ => Err(format!("expected 1..={} seconds", crate::stall::MAX_TIMEOUT_SECS)),
+        },
+        put: |v| crate::stall::set_timeout_secs(v.as_u64().unwrap_or_default()),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "proxy_watchdog_secs",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::proxy_watchdog::MAX_WINDOW_SECS => Ok(()),
+            _ => Err(format!("expected 0..={} seconds", crate::proxy_watchdog::MAX_WINDOW_SECS)),
+        },
+        put: |v| crate::proxy_watchdog::set_window_secs(v.as_u64().unwrap_or_default()),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "state_lock_wait_secs",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::state_lock::MAX_WAIT_SECS => Ok(()),
+            _ => Err(format!("expected 0..={} seconds", crate::state_lock::MAX_WAIT_SECS)),
+        },
+        put: |v| crate::state_lock::set_wait_secs(v.as_u64().unwrap_or_default()),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "network_token_max_age_secs",
+        scope: Scope::Live,
+        default: || json!(0),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::network_token::MAX_MAX_AGE_SECS => Ok(()),
+            _ => Err(format!("expected 0..={} seconds", crate::network_token::MAX_MAX_AGE_SECS)),
+        },
+        put: |v| crate::network_token::set_max_age_secs(v.as_u64().unwrap_or_default()),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "confirm_stream_writable",
+        scope: Scope::Live,
+        default: || json!(false),
+        check: expect_bool,
+        put: |v| crate::CONFIRM_STREAM_WRITABLE.store(v == &json!(true), Ordering::Relaxed),
+        json_settable: true,
+    },
+    SettingDef {
+        name: "destination_denylist",
+        scope: Scope::Live,
+        default: || json!([]),
+        check: crate::target::check_patterns,
+        put: crate::target::set_denylist,
+        json_settable: true,
+    },
+    SettingDef {
+        name: "onion_aliases",
+        scope: Scope::Live,
+        default: || json!({}),
+        check: crate::target::check_onion_aliases,
+        put: crate::target::set_onion_aliases,
+        json_settable: true,
+    },
+    SettingDef {
+        name: "destination_allowlist",
+        scope: Scope::Live,
+        default: || json!([]),
+        check: crate::target::check_patterns,
+        put: crate::target::set_allowlist,
+        json_settable: true,
+    },
+    SettingDef {
+        name: "relay_address_family",
+        scope: Scope::Client,
+        default: || json!(crate::address_family::Mode::Auto as i64),
+        check: crate::address_family::check,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "max_circuit_dirtiness_secs",
+        scope: Scope::Client,
+        default: || json!(crate::DEFAULT_CIRCUIT_DIRTINESS_SECS),
+        check: |v| match v.as_u64() {
+            Some(n) if (crate::MIN_CIRCUIT_DIRTINESS_SECS..=crate::MAX_CIRCUIT_DIRTINESS_SECS).contains(&n) => Ok(()),
+            _ => Err(format!(
+                "expected {}..={} seconds",
+                crate::MIN_CIRCUIT_DIRTINESS_SECS,
+                crate::MAX_CIRCUIT_DIRTINESS_SECS
+            )),
+        },
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "first_destination",
+        scope: Scope::Client,
+        default: || json!(""),
+        check: crate::prewarm::check_destination,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "prewarm_budget_secs",
+        scope: Scope::Client,
+        default: || json!(crate::prewarm::DEFAULT_BUDGET_SECS),
+        check: |v| match v.as_u64() {
+            Some(n) if n <= crate::prewarm::MAX_BUDGET_SECS => Ok(()),
+            _ => Err(format!("expected 0..={} seconds", crate::prewarm::MAX_BUDGET_SECS)),
+        },
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "bridges",
+        scope: Scope::Client,
+        default: || json!([]),
+        check: crate::bridges::check_lines,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "pt_binaries",
+        scope: Scope::Client,
+        default: || json!({}),
+        check: crate::bridges::check_binaries,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "transport_priority",
+        scope: Scope::Client,
+        default: || json!([]),
+        check: crate::bridges::check_priority,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "prefer_fast_exits",
+        scope: Scope::Client,
+        default: || json!(false),
+        check: expect_bool,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "required_exit_flags",
+        scope: Scope::Client,
+        default: || json!([]),
+        check: crate::exits::check,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "directory_retry",
+        scope: Scope::Client,
+        default: || json!({}),
+        check: crate::dir_retry::check,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        name: "target_port_hints",
+        scope: Scope::Client,
+        default: || json!([]),
+        check: crate::port_hints::check,
+        put: |_| {},
+        json_settable: true,
+    },
+    SettingDef {
+        // Onion ids with a configured key; the keys themselves stay in onion_auth.rs
+        name: "onion_client_auth",
+        scope: Scope::Client,
+        default: || json!([]),
+        check: |_| Ok(()),
+        put: |_| {},
+        json_settable: false,
+    },
+];
+
+fn expect_bool(v: &Value) -> Result<(), String> {
+    match v {
+        Value::Bool(_) => Ok(()),
+        _ => Err("expected a boolean".into()),
+    }
+}
+
+fn find(name: &str) -> Option<&'static SettingDef> {
+    SETTINGS.iter().find(|def| def.name == name)
+}
+
+struct Store {
+    pending: BTreeMap<&'static str, Value>,
+    applied: BTreeMap<&'static str, Value>,
+}
+
+static STORE: Mutex<Store> = Mutex::new(Store {
+    pending: BTreeMap::new(),
+    applied: BTreeMap::new(),
+});
+
+fn record(store: &mut Store, def: &'static SettingDef, value: Value) {
+    match def.scope {
+        Scope::Live => {
+            (def.put)(&value);
+            store.applied.insert(def.name, value);
+        }
+        Scope::Client => {
+            store.pending.insert(def.name, value);
+        }
+    }
+}
+
+/// Record a setting from its dedicated setter
+pub(crate) fn set(name: &str, value: Value) -> Result<(), String> {
+    let def = find(name).ok_or_else(|| format!("unknown setting {}", name))?;
+    (def.check)(&value).map_err(|e| format!("{}: {}", name, e))?;
+    record(&mut STORE.lock().unwrap(), def, value);
+    Ok(())
+}
+
+/// Merge a JSON object of settings over the current ones
+///
+/// Every key is checked before any is recorded, so a bad object changes nothing.
+pub(crate) fn merge_json(config_json: &str) -> Result<(), String> {
+    let object: Map<String, Value> = match serde_json::from_str(config_json) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err("config must be a JSON object".into()),
+        Err(e) => return Err(format!("invalid config JSON: {}", e)),
+    };
+
+    let mut checked = Vec::with_capacity(object.len());
+    for (key, value) in object {
+        let def = match find(&key) {
+            Some(def) if def.json_settable => def,
+            Some(_) => return Err(format!("{} can only be set with its dedicated setter", key)),
+            None => return Err(format!("unknown setting {}", key)),
+        };
+        (def.check)(&value).map_err(|e| format!("{}: {}", key, e))?;
+        checked.push((def, value));
+    }
+
+    let mut store = STORE.lock().unwrap();
+    for (def, value) in checked {
+        record(&mut store, def, value);
+    }
+    Ok(())
+}
+
+/// Apply every pending client setting, returning the names that changed
+///
+/// Called by `initialize` before the client config is built. The onion client
+/// auth keys staged in onion_auth.rs are applied in the same step.
+pub(crate) fn commit_pending() -> Vec<&'static str> {
+    let mut store = STORE.lock().unwrap();
+    let pending = std::mem::take(&mut store.pending);
+    let names = pending.keys().copied().collect();
+    store.applied.extend(pending);
+    crate::onion_auth::commit_staged();
+    names
+}
+
+/// Names of pending settings whose value differs from the one in effect
+///
+/// An Activity recreated after a configuration change typically records the
+/// same values again; those don't need a new client.
+pub(crate) fn pending_changes() -> Vec<&'static str> {
+    let store = STORE.lock().unwrap();
+    let mut changed: Vec<&'static str> = store
+        .pending
+        .iter()
+        .filter(|(name, value)| match store.applied.get(*name) {
+            Some(applied) => applied != *value,
+            None => find(name).is_none_or(|def| (def.default)() != **value),
+        })
+        .map(|(name, _)| *name)
+        .collect();
+    // A replaced key leaves the list of ids, and so the setting, unchanged
+    if !changed.contains(&"onion_client_auth") && !crate::onion_auth::staged_changes().is_empty() {
+        changed.push("onion_client_auth");
+    }
+    changed
+}
+
+/// Discard every setting that hasn't been applied yet, returning what was dropped
+///
+/// Setting names come first, then one `onion_client_auth:<id>` per onion
+/// service whose staged client auth key was discarded.
+pub(crate) fn reset_pending() -> Vec<String> {
+    let pending = std::mem::take(&mut STORE.lock().unwrap().pending);
+    let keys = crate::onion_auth::reset_staged();
+    pending
+        .into_keys()
+        .map(str::to_string)
+        .chain(keys.into_iter().map(|id| format!("onion_client_auth:{}", id)))
+        .collect()
+}
+
+/// Value in effect now: applied, else the default
+pub(crate) fn applied(name: &str) -> Value {
+    let def = find(name).expect("unknown setting");
+    STORE.lock().unwrap().applied.get(name).cloned().unwrap_or_else(def.default)
+}
+
+/// Value the next initialize will use: pending, else applied, else the default
+pub(crate) fn upcoming(name: &str) -> Value {
+    let def = find(name).expect("unknown setting");
+    let store = STORE.lock().unwrap();
+    store
+        .pending
+        .get(name)
+        .or_else(|| store.applied.get(name))
+        .cloned()
+        .unwrap_or_else(def.default)
+}
+
+/// A setting holding an array of strings, as a Vec
+pub(crate) fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|items| items.iter().filter_map(|v| v.as_str().map(str::to_string)).collect())
+        .unwrap_or_default()
+}
+
+/// How the value in effect of setting `name` is carried out, for settings
+/// where that can fall short of what was asked; `setting` looks up others
+fn effect_of(name: &str, setting: impl Fn(&str) -> Value) -> Option<Value> {
+    match name {
+        "required_exit_flags" => crate::exits::effect(
+            &strings(&setting("required_exit_flags")),
+            &crate::port_hints::ports(&setting("target_port_hints")),
+        ),
+        "relay_address_family" => crate::address_family::Mode::of(&setting("relay_address_family")).effect(),
+        _ => None,
+    }
+}
+
+/// Every setting with its effective value and where that value came from
+///
+/// `source` is `default`, `applied` or `pending`. A pending setting reports the
+/// value in effect now as `value` and the one awaiting `initialize` as `pending`.
+/// Settings that are only partly carried out say how in `effect`.
+pub(crate) fn effective_json() -> String {
+    let store = STORE.lock().unwrap();
+    let in_effect = |name: &str| store.applied.get(name).cloned().unwrap_or_else(find(name).expect("unknown setting").default);
+    let mut out = Map::new();
+    for def in SETTINGS {
+        let applied = store.applied.get(def.name);
+        let mut entry = json!({
+            "value": applied.cloned().unwrap_or_else(def.default),
+            "scope": match def.scope {
+                Scope::Live => "live",
+                Scope::Client => "client",
+            },
+        });
+        let source = match (store.pending.get(def.name), applied) {
+            (Some(pending), _) => {
+                entry["pending"] = pending.clone();
+                "pending"
+            }
+            (None, Some(_)) => "applied",
+            (None, None) => "default",
+        };
+        entry["source"] = json!(source);
+        if let Some(effect) = effect_of(def.name, in_effect) {
+            entry["effect"] = effect;
+        }
+        out.insert(def.name.to_string(), entry);
+    }
+    Value::Object(out).to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&STORE);
+}
+
+/// Every setting as it was before `save`, put back on drop
+#[cfg(test)]
+pub(crate) struct Saved(Option<Store>);
+
+/// Start a test from default settings (and no staged onion keys)
+#[cfg(test)]
+pub(crate) fn save() -> Saved {
+    crate::onion_auth::reset_staged();
+    Saved(Some(std::mem::replace(&mut STORE.lock().unwrap(), Store { pending: BTreeMap::new(), applied: BTreeMap::new() })))
+}
+
+#[cfg(test)]
+impl Drop for Saved {
+    fn drop(&mut self) {
+        crate::onion_auth::reset_staged();
+        if let Some(store) = self.0.take() {
+            let mut current = STORE.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+            // Live settings the test changed are put back into effect too
+            for def in SETTINGS.iter().filter(|def| matches!(def.scope, Scope::Live)) {
+                let effective = |store: &Store| store.applied.get(def.name).cloned().unwrap_or_else(def.default);
+                let restored = effective(&store);
+                if effective(&current) != restored {
+                    (def.put)(&restored);
+                }
+            }
+            *current = store;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const ONION_ID: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid";
+    const ONION_KEY: &str = "gtnkrx5wnyxbapyxwkacl3yjtq2mhu6n2p3jn2bxrnnhhbx2a4ea";
+
+    /// A valid value for every client setting, differing from its default
+    /// where this build allows one
+    fn client_samples() -> Vec<(&'static str, Value)> {
+        vec![
+            ("relay_address_family", json!(1)),
+            ("max_circuit_dirtiness_secs", json!(crate::MAX_CIRCUIT_DIRTINESS_SECS)),
+            ("first_destination", json!("example.com:443")),
+            ("prewarm_budget_secs", json!(crate::prewarm::MAX_BUDGET_SECS)),
+            (
+                "bridges",
+                if cfg!(feature = "bridges") {
+                    json!(["192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567"])
+                } else {
+                    json!([])
+                },
+            ),
+            (
+                "pt_binaries",
+                if cfg!(feature = "pluggable-transports") {
+                    json!({"obfs4": "/data/local/obfs4proxy"})
+                } else {
+                    json!({})
+                },
+            ),
+            ("transport_priority", json!(["obfs4"])),
+            ("required_exit_flags", json!(["Fast"])),
+            ("prefer_fast_exits", json!(true)),
+            ("directory_retry", json!({"consensus": {"attempts": 3}})),
+            ("target_port_hints", json!([443])),
+            ("onion_client_auth", json!([ONION_ID])),
+        ]
+    }
+
+    /// Take the whole store and the onion keys, to put back with `restore`
+    fn take_all() -> Store {
+        let mut store = STORE.lock().unwrap();
+        crate::onion_auth::reset_staged();
+        Store {
+            pending: std::mem::take(&mut store.pending),
+            applied: std::mem::take(&mut store.applied),
+        }
+    }
+
+    fn restore(saved: Store) {
+        crate::onion_auth::reset_staged();
+        *STORE.lock().unwrap() = saved;
+    }
+
+    fn source_of(name: &str) -> Value {
+        let effective: Value = serde_json::from_str(&effective_json()).unwrap();
+        effective[name]["source"].clone()
+    }
+
+    #[test]
+    fn every_client_setting_has_a_sample() {
+        let mut sampled: Vec<&str> = client_samples().into_iter().map(|(name, _)| name).collect();
+        let mut client: Vec<&str> = SETTINGS.iter().filter(|def| def.scope == Scope::Client).map(|def| def.name).collect();
+        sampled.sort();
+        client.sort();
+        assert_eq!(sampled, client);
+    }
+
+    #[test]
+    fn every_pending_setting_is_reported_then_reset() {
+        let _serial = testing::serial();
+        let saved = take_all();
+
+        for (name, value) in client_samples() {
+            if name == "onion_client_auth" {
+                // Recorded the way setOnionClientAuth does
+                assert!(crate::onion_auth::set(ONION_ID, ONION_KEY));
+            }
+            set(name, value.clone()).unwrap();
+            let changes_default = value != (find(name).unwrap().default)();
+
+            assert_eq!(source_of(name), json!("pending"), "{}", name);
+            assert_eq!(upcoming(name), value, "{}", name);
+            assert_eq!(applied(name), (find(name).unwrap().default)(), "{}", name);
+            assert_eq!(pending_changes().contains(&name), changes_default, "{}", name);
+
+            let mut expected = vec![name.to_string()];
+            if name == "onion_client_auth" {
+                expected.push(format!("onion_client_auth:{}", ONION_ID));
+            }
+            assert_eq!(reset_pending(), expected, "{}", name);
+            assert_eq!(source_of(name), json!("default"), "{}", name);
+            assert_eq!(upcoming(name), (find(name).unwrap().default)(), "{}", name);
+            assert!(pending_changes().is_empty(), "{}", name);
+            assert!(crate::onion_auth::configured_ids().is_empty(), "{}", name);
+        }
+
+        restore(saved);
+    }
+
+    #[test]
+    fn reset_discards_a_replaced_onion_key() {
+        let _serial = testing::serial();
+        let saved = take_all();
+        const OTHER_KEY: &str = "7vcmd7mf5vklcx6dnyyt4uq3dbw3hyhxgj2ri2n7btwmcy4vkd2a";
+
+        assert!(crate::onion_auth::set(ONION_ID, ONION_KEY));
+        set("onion_client_auth", json!([ONION_ID])).unwrap();
+        assert_eq!(commit_pending(), ["onion_client_auth"]);
+        assert_eq!(crate::onion_auth::applied_ids(), [ONION_ID]);
+
+        // Same id, new key: the setting's value doesn't change, the key does
+        assert!(crate::onion_auth::set(ONION_ID, OTHER_KEY));
+        assert_eq!(pending_changes(), ["onion_client_auth"]);
+        assert_eq!(reset_pending(), [format!("onion_client_auth:{}", ONION_ID)]);
+        assert!(crate::onion_auth::staged_changes().is_empty());
+        assert_eq!(crate::onion_auth::applied_ids(), [ONION_ID]);
+
+        crate::onion_auth::remove(ONION_ID);
+        commit_pending();
+        restore(saved);
+    }
+
+    /// One step of a precedence case
+    #[derive(Clone, Copy)]
+    enum Op {
+        Set(u64),
+        Merge(&'static str),
+        Reset,
+        Commit,
+    }
+
+    #[test]
+    fn precedence_and_merge_semantics() {
+        use Op::*;
+        const NAME: &str = "max_circuit_dirtiness_secs";
+        const DEFAULT: u64 = crate::DEFAULT_CIRCUIT_DIRTINESS_SECS;
+        // ops, then the applied value, the upcoming value and the source
+        let cases: &[(&[Op], u64, u64, &str)] = &[
+            (&[], DEFAULT, DEFAULT, "default"),
+            (&[Set(60)], DEFAULT, 60, "pending"),
+            (&[Set(60), Set(90)], DEFAULT, 90, "pending"),
+            (&[Set(60), Merge(r#"{"max_circuit_dirtiness_secs":90}"#)], DEFAULT, 90, "pending"),
+            (&[Merge(r#"{"max_circuit_dirtiness_secs":90}"#), Set(60)], DEFAULT, 60, "pending"),
+            // A merge that doesn't name the key keeps the setter's value
+            (&[Set(60), Merge(r#"{"prewarm_budget_secs":1}"#)], DEFAULT, 60, "pending"),
+            // A merge with a bad key changes nothing at all
+            (&[Set(60), Merge(r#"{"max_circuit_dirtiness_secs":90,"no_such":1}"#)], DEFAULT, 60, "pending"),
+            (&[Set(60), Merge(r#"{"max_circuit_dirtiness_secs":1}"#)], DEFAULT, 60, "pending"),
+            (&[Set(60), Merge(r#"{"onion_client_auth":[]}"#)], DEFAULT, 60, "pending"),
+            (&[Set(60), Merge("[]")], DEFAULT, 60, "pending"),
+            (&[Set(60), Reset], DEFAULT, DEFAULT, "default"),
+            (&[Set(60), Commit], 60, 60, "applied"),
+            (&[Set(60), Commit, Set(90)], 60, 90, "pending"),
+            (&[Set(60), Commit, Set(90), Reset], 60, 60, "applied"),
+            (&[Set(60), Reset, Set(90), Commit], 90, 90, "applied"),
+            (&[Set(60), Commit, Merge(r#"{"max_circuit_dirtiness_secs":90}"#), Commit], 90, 90, "applied"),
+        ];
+
+        let _serial = testing::serial();
+        for (ops, applied_value, upcoming_value, source) in cases {
+            let saved = take_all();
+            for op in *ops {
+                match *op {
+                    Set(secs) => set(NAME, json!(secs)).unwrap(),
+                    Merge(object) => {
+                        let _ = merge_json(object);
+                    }
+                    Reset => {
+                        reset_pending();
+                    }
+                    Commit => {
+                        commit_pending();
+                    }
+                }
+            }
+            let case = format!("{:?}", ops.iter().map(|op| match op {
+                Set(secs) => format!("set {}", secs),
+                Merge(object) => format!("merge {}", object),
+                Reset => "reset".into(),
+                Commit => "commit".into(),
+            }).collect::<Vec<_>>());
+            assert_eq!(applied(NAME), json!(applied_value), "{}", case);
+            assert_eq!(upcoming(NAME), json!(upcoming_value), "{}", case);
+            assert_eq!(source_of(NAME), json!(source), "{}", case);
+            restore(saved);
+        }
+    }
+
+    #[test]
+    fn live_settings_are_never_pending() {
+        let _serial = testing::serial();
+        let saved = take_all();
+        let was = crate::ISOLATE_BY_DEST_PORT.load(Ordering::Relaxed);
+
+        set("isolate_by_dest_port", json!(!was)).unwrap();
+        assert_eq!(crate::ISOLATE_BY_DEST_PORT.load(Ordering::Relaxed), !was);
+        assert_eq!(source_of("isolate_by_dest_port"), json!("applied"));
+        assert!(reset_pending().is_empty());
+        assert_eq!(applied("isolate_by_dest_port"), json!(!was));
+
+        set("isolate_by_dest_port", json!(was)).unwrap();
+        restore(saved);
+    }
+
+    #[test]
+    fn values_are_checked_when_recorded() {
+        let _serial = testing::serial();
+        let saved = take_all();
+        for (name, value) in [
+            ("isolate_by_dest_port", json!(1)),
+            ("max_circuit_dirtiness_secs", json!(crate::MIN_CIRCUIT_DIRTINESS_SECS - 1)),
+            ("first_destination", json!("no port")),
+            ("target_port_hints", json!([0])),
+            ("no_such_setting", json!(true)),
+        ] {
+            assert!(set(name, value.clone()).is_err(), "{} {}", name, value);
+        }
+        assert!(merge_json("not json").is_err());
+        assert!(STORE.lock().unwrap().pending.is_empty());
+        restore(saved);
+    }
+}
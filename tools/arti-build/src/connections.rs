@@ -0,0 +1,256 @@
+//! Live SOCKS connections by target, for closing them selectively
+//!
+//! `stop` cancels every connection at once (see cancel.rs). To troubleshoot a
+//! single misbehaving host, `closeConnectionsToTarget(pattern)` closes only
+//! the connections whose target matches:
+//!
+//! - each handler registers its target (the name the client asked for, as in
+//!   the logs) once the target has been evaluated, together with its own
+//!   `CancelToken`, and the registration is dropped with the handler;
+//! - patterns match the whole host case-insensitively, with `*` standing for
+//!   any run of characters (`*.example.com`, `cdn*.example.*`, `*`), and an
+//!   optional `:port` suffix restricting the port.
+//!
+//! `listConnections` reports the same registry, with each connection's flow
+//! stalls (see flow_stall.rs), the `class=` and `connid=` the client gave
+//! (null without), and, once connected, the platform its exit advertises in
+//! the consensus (null when unknown).
+//!
+//! A closed connection unwinds at its current await point like a stopped one;
+//! connections still in the SOCKS handshake have no target yet and are left
+//! alone.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::cancel::CancelToken;
+use crate::flow_stall;
+
+struct Live {
+    host: String,
+    port: u16,
+    closer: CancelToken,
+    flow: Arc<flow_stall::Record>,
+    exit_platform: Option<String>,
+    /// The client's `class=` and `connid=` (target.rs)
+    class: Option<String>,
+    connid: Option<String>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static LIVE: Mutex<Option<HashMap<u64, Live>>> = Mutex::new(None);
+
+/// A connection's entry in the registry, removed on drop
+pub(crate) struct Registration(u64);
+
+impl Registration {
+    /// Record the traffic class and id the client gave the connection
+    pub(crate) fn set_client_labels(&self, class: Option<String>, connid: Option<String>) {
+        if let Some(live) = LIVE.lock().unwrap().as_mut().and_then(|live| live.get_mut(&self.0)) {
+            live.class = class;
+            live.connid = connid;
+        }
+    }
+
+    /// Record the advertised platform of the connection's exit
+    pub(crate) fn set_exit_platform(&self, platform: Option<String>) {
+        if let Some(live) = LIVE.lock().unwrap().as_mut().and_then(|live| live.get_mut(&self.0)) {
+            live.exit_platform = platform;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(live) = LIVE.lock().unwrap().as_mut() {
+            live.remove(&self.0);
+        }
+    }
+}
+
+/// Register a connection to `host:port`, closed by cancelling `closer`, with
+/// its flow stall record
+pub(crate) fn register(host: &str, port: u16, closer: &CancelToken, flow: Arc<flow_stall::Record>) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let live = Live {
+        host: host.to_ascii_lowercase(),
+        port,
+        closer: closer.clone(),
+        flow,
+        exit_platform: None,
+        class: None,
+        connid: None,
+    };
+    LIVE.lock().unwrap().get_or_insert_with(HashMap::new).insert(id, live);
+    Registration(id)
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of bytes
+///
+/// Backtracks only to the last `*`, so matching takes at most pattern
+/// length times text length steps however many stars there are.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently covers up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, t));
+            p += 1;
+        } else if pattern.get(p) == Some(&text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            star = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// A checked pattern: host glob and optional port
+pub(crate) struct Pattern {
+    host: String,
+    port: Option<u16>,
+}
+
+impl Pattern {
+    /// Parse `host` or `host:port`; `None` if empty or the port is invalid
+    pub(crate) fn parse(text: &str) -> Option<Pattern> {
+        let text = text.trim().to_ascii_lowercase();
+        // A port suffix, unless the colon belongs to an IPv6 literal
+        let (host, port) = match text.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host.to_string(), Some(port.parse().ok()?)),
+            _ => (text, None),
+        };
+        (!host.is_empty()).then_some(Pattern { host, port })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.port.is_none_or(|p| p == port) && glob(self.host.as_bytes(), host.as_bytes())
+    }
+}
+
+/// Close every registered connection matching `pattern`; returns how many
+pub(crate) fn close_matching(pattern: &Pattern) -> usize {
+    let closers: Vec<CancelToken> = LIVE
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|live| live.values())
+        .filter(|live| pattern.matches(&live.host, live.port))
+        .map(|live| live.closer.clone())
+        .collect();
+    // Cancelled outside the lock: the handlers deregister as they unwind
+    for closer in &closers {
+        closer.cancel();
+    }
+    closers.len()
+}
+
+/// The registered connections, oldest first, as JSON
+pub(crate) fn list_json() -> String {
+    let live = LIVE.lock().unwrap();
+    let mut entries: Vec<(&u64, &Live)> = live.iter().flat_map(|live| live.iter()).collect();
+    entries.sort_by_key(|(id, _)| **id);
+    let list: Vec<serde_json::Value> = entries
+        .into_iter()
+        .map(|(id, live)| {
+            let mut entry = live.flow.json();
+            entry["id"] = (*id).into();
+            entry["host"] = live.host.clone().into();
+            entry["port"] = live.port.into();
+            entry["exit_platform"] = live.exit_platform.clone().into();
+            entry["class"] = live.class.clone().into();
+            entry["connid"] = live.connid.clone().into();
+            entry
+        })
+        .collect();
+    serde_json::Value::Array(list).to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&LIVE);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn globs_match_the_whole_host() {
+        for (pattern, text, expected) in [
+            ("example.com", "example.com", true),
+            ("example.com", "www.example.com", false),
+            ("*.example.com", "www.example.com", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("cdn*.example.*", "cdn42.example.net", true),
+            ("cdn*.example.*", "img.example.net", false),
+            ("*", "anything.onion", true),
+            ("*", "", true),
+            ("a*b*c", "aXbYc", true),
+            ("a*b*c", "aXbY", false),
+            ("**.onion", "x.onion", true),
+            ("*a", "aaaa", true),
+            ("", "", true),
+            ("", "a", false),
+        ] {
+            assert_eq!(glob(pattern.as_bytes(), text.as_bytes()), expected, "{} ~ {}", pattern, text);
+        }
+        // No exponential blowup on adversarial patterns
+        let text = "a".repeat(4000);
+        assert!(!glob(format!("{}b", "*a".repeat(50)).as_bytes(), text.as_bytes()));
+    }
+
+    #[test]
+    fn patterns_parse_with_an_optional_port() {
+        for (text, expected) in [
+            ("Example.COM", Some(("example.com", None))),
+            (" *.example.com:443 ", Some(("*.example.com", Some(443)))),
+            ("[2001:db8::1]", Some(("[2001:db8::1]", None))),
+            ("2001:db8::1", Some(("2001:db8::1", None))),
+            ("example.com:", None),
+            ("example.com:70000", None),
+            ("example.com:http", None),
+            (":443", None),
+            ("", None),
+            ("   ", None),
+        ] {
+            let parsed = Pattern::parse(text);
+            assert_eq!(parsed.as_ref().map(|p| (p.host.as_str(), p.port)), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn closing_cancels_only_matching_registrations() {
+        let _serial = testing::serial();
+        let tokens: Vec<CancelToken> = (0..4).map(|_| CancelToken::new()).collect();
+        let registrations = [("api.example.com", 443), ("API.example.com", 80), ("example.org", 443), ("cdn.example.com", 443)]
+            .iter()
+            .zip(&tokens)
+            .map(|((host, port), token)| register(host, *port, token, Arc::new(flow_stall::Record::default())))
+            .collect::<Vec<_>>();
+        let close = |pattern: &str| close_matching(&Pattern::parse(pattern).unwrap());
+
+        assert_eq!(close("api.example.com:443"), 1);
+        let closed = |token: &CancelToken| token.cancelled().now_or_never().is_some();
+        assert_eq!(tokens.iter().map(closed).collect::<Vec<_>>(), [true, false, false, false]);
+        assert_eq!(close("*.example.com"), 3);
+        assert_eq!(tokens.iter().map(closed).collect::<Vec<_>>(), [true, true, false, true]);
+
+        // Dropped registrations can't be closed again
+        drop(registrations);
+        assert_eq!(close("*"), 0);
+        assert!(!closed(&tokens[2]));
+    }
+}
@@ -0,0 +1,391 @@
+//! Limits on the consensus age traffic may rely on
+//!
+//! After a long time offline the client can carry traffic on an expired
+//! consensus while it catches up. Some users would rather fail than use stale
+//! network data, others want availability. `setMaxConsensusAge(mode,
+//! graceMinutes)` decides what happens once the consensus is older than its
+//! validity plus the grace:
+//!
+//! - 0, off (the default): Arti's own behaviour, nothing is checked;
+//! - 1, degraded: traffic still flows, but a running wrapper reports the
+//!   lifecycle state `DegradedStaleDirectory` and `getBootstrapStatus` carries
+//!   `"degraded":"DEGRADED_STALE_DIRECTORY"`. `Running` comes back with a
+//!   fresh consensus;
+//! - 2, strict: as degraded, and new CONNECTs are refused with SOCKS reply
+//!   0x03 (network unreachable), which the wrapper sends for nothing else.
+//!
+//! Transitions follow the directory rather than polling: a watcher
+//! re-evaluates on every netdir event, and otherwise sleeps until the moment
+//! the current consensus goes stale. Strict mode also decides at each CONNECT.
+//!
+//! The consensus lifetime is wall-clock time, and the wall clock can jump. So
+//! each consensus is anchored when it arrives, with a monotonic and a
+//! wall-clock reading, and its age is measured from there as the larger of
+//! the two elapsed times: the monotonic clock stops while the device sleeps,
+//! and a wall clock set back must not make an old consensus young again. A
+//! wall clock set forward can't be told apart from sleep and counts.
+
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use arti_client::TorClient;
+use futures::StreamExt;
+use tokio::sync::Notify;
+use tor_netdir::Timeliness;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::{seams, LifecycleState, LIFECYCLE_STATE};
+
+/// Longest grace after the consensus validity
+pub(crate) const MAX_GRACE_MINUTES: u64 = 24 * 60;
+
+/// Marker reported in `getBootstrapStatus` while enforcement applies
+pub(crate) const DEGRADED_MARKER: &str = "DEGRADED_STALE_DIRECTORY";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Off = 0,
+    Degraded = 1,
+    Strict = 2,
+}
+
+impl Mode {
+    fn from_u8(value: u8) -> Mode {
+        match value {
+            1 => Mode::Degraded,
+            2 => Mode::Strict,
+            _ => Mode::Off,
+        }
+    }
+}
+
+/// What applies to traffic right now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Enforcement {
+    None = 0,
+    Degraded = 1,
+    Strict = 2,
+}
+
+/// A consensus as it arrived
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Anchor {
+    valid_until: SystemTime,
+    /// Clock readings when it was seen
+    mono: Instant,
+    wall: SystemTime,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Off as u8);
+static GRACE_MINUTES: AtomicU64 = AtomicU64::new(0);
+static ANCHOR: Mutex<Option<Anchor>> = Mutex::new(None);
+
+/// Enforcement last acted on
+static CURRENT: AtomicU8 = AtomicU8::new(Enforcement::None as u8);
+
+/// Wakes the watcher to recompute its deadline after a setting change
+static WAKE: Notify = Notify::const_new();
+
+/// Directory watcher task (replaced whenever a new client is created)
+static WATCHER: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+
+/// Time the anchored consensus has aged, robust to wall-clock jumps
+fn elapsed(anchor: &Anchor, now_mono: Instant, now_wall: SystemTime) -> Duration {
+    let mono = now_mono.saturating_duration_since(anchor.mono);
+    let wall = now_wall.duration_since(anchor.wall).unwrap_or(Duration::ZERO);
+    mono.max(wall)
+}
+
+/// Age the anchored consensus may reach before enforcement starts
+fn allowed(anchor: &Anchor, grace: Duration) -> Duration {
+    (anchor.valid_until + grace).duration_since(anchor.wall).unwrap_or(Duration::ZERO)
+}
+
+/// What applies with `mode` and `grace` to the consensus in `anchor` at the given time
+pub(crate) fn decide(
+    mode: Mode,
+    grace: Duration,
+    anchor: Option<&Anchor>,
+    now_mono: Instant,
+    now_wall: SystemTime,
+) -> Enforcement {
+    let Some(anchor) = anchor else {
+        return Enforcement::None;
+    };
+    if mode == Mode::Off || elapsed(anchor, now_mono, now_wall) < allowed(anchor, grace) {
+        return Enforcement::None;
+    }
+    match mode {
+        Mode::Strict => Enforcement::Strict,
+        _ => Enforcement::Degraded,
+    }
+}
+
+fn mode() -> Mode {
+    Mode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+fn grace() -> Duration {
+    Duration::from_secs(GRACE_MINUTES.load(Ordering::Relaxed) * 60)
+}
+
+fn decide_now() -> Enforcement {
+    let anchor = *ANCHOR.lock().unwrap();
+    decide(mode(), grace(), anchor.as_ref(), seams::now_monotonic(), seams::now_wall())
+}
+
+pub(crate) fn set_mode(mode: u8) {
+    MODE.store(mode, Ordering::Relaxed);
+    WAKE.notify_one();
+}
+
+pub(crate) fn set_grace_minutes(minutes: u64) {
+    GRACE_MINUTES.store(minutes, Ordering::Relaxed);
+    WAKE.notify_one();
+}
+
+/// Act on the current enforcement; returns how long until the consensus goes
+/// stale, if it is fresh and a mode is set
+pub(crate) fn evaluate() -> Option<Duration> {
+    let enforcement = decide_now();
+    let previous = CURRENT.swap(enforcement as u8, Ordering::SeqCst);
+    if previous != enforcement as u8 {
+        match enforcement {
+            Enforcement::None => log_info!("Consensus is fresh again; stale-directory enforcement lifted"),
+            Enforcement::Degraded => log_error!("{}: consensus is older than its validity plus grace", DEGRADED_MARKER),
+            Enforcement::Strict => {
+                log_error!("{}: consensus is older than its validity plus grace; refusing new connections", DEGRADED_MARKER)
+            }
+        }
+    }
+
+    let state = LIFECYCLE_STATE.load(Ordering::SeqCst);
+    match enforcement {
+        Enforcement::None if state == LifecycleState::DegradedStaleDirectory as i32 => {
+            crate::set_state(LifecycleState::Running);
+        }
+        Enforcement::Degraded | Enforcement::Strict if state == LifecycleState::Running as i32 => {
+            crate::set_state(LifecycleState::DegradedStaleDirectory);
+        }
+        _ => {}
+    }
+
+    if enforcement != Enforcement::None || mode() == Mode::Off {
+        return None;
+    }
+    let anchor = (*ANCHOR.lock().unwrap())?;
+    let age = elapsed(&anchor, seams::now_monotonic(), seams::now_wall());
+    Some(allowed(&anchor, grace()).saturating_sub(age))
+}
+
+/// Whether new connections are refused for a stale consensus
+pub(crate) fn refuses_connections() -> bool {
+    decide_now() == Enforcement::Strict
+}
+
+/// `DEGRADED_MARKER` while enforcement applies
+pub(crate) fn degraded_marker() -> Option<&'static str> {
+    (CURRENT.load(Ordering::SeqCst) != Enforcement::None as u8).then_some(DEGRADED_MARKER)
+}
+
+/// Anchor the client's current consensus, expired or not, unless it already is
+fn anchor(client: &TorClient<PreferredRuntime>) {
+    let Ok(netdir) = client.dirmgr().netdir(Timeliness::Unchecked) else {
+        return;
+    };
+    let valid_until = netdir.lifetime().valid_until();
+    let mut anchor = ANCHOR.lock().unwrap();
+    if anchor.is_some_and(|a| a.valid_until == valid_until) {
+        return;
+    }
+    *anchor = Some(Anchor { valid_until, mono: seams::now_monotonic(), wall: seams::now_wall() });
+}
+
+/// Follow the client's directory, re-evaluating on its events and when the
+/// consensus goes stale
+pub(crate) fn start_watcher(runtime: &tokio::runtime::Handle, client: Arc<TorClient<PreferredRuntime>>) {
+    *ANCHOR.lock().unwrap() = None;
+    let mut events = client.dirmgr().events();
+    let handle = runtime.spawn(async move {
+        loop {
+            anchor(&client);
+            let until_stale = evaluate();
+            tokio::select! {
+                event = events.next() => if event.is_none() {
+                    break;
+                },
+                _ = WAKE.notified() => {}
+                _ = seams::sleep(until_stale.unwrap_or(Duration::from_secs(24 * 60 * 60))) => {}
+            }
+        }
+    });
+    if let Some(previous) = WATCHER.lock().unwrap().replace(handle) {
+        previous.abort();
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&ANCHOR);
+    held.hold(&WATCHER);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config;
+    use crate::seams::fakes::FakeConnector;
+    use crate::socks::Reply;
+    use crate::testing::{self, socks_connect, socks_greet, socks_session, FakeTime};
+
+    const MIN: u64 = 60;
+
+    fn mins(m: u64) -> Duration {
+        Duration::from_secs(m * MIN)
+    }
+
+    #[test]
+    fn the_decision_follows_validity_grace_and_clock_jumps() {
+        let mono = Instant::now();
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        // Arrived with an hour of validity left
+        let anchor = Anchor { valid_until: wall + mins(60), mono, wall };
+        let at = |mono_mins: u64, wall_mins: i64| {
+            let now_wall = if wall_mins >= 0 { wall + mins(wall_mins as u64) } else { wall - mins(wall_mins.unsigned_abs()) };
+            (mono + mins(mono_mins), now_wall)
+        };
+        use Enforcement as E;
+        // (mode, grace minutes, monotonic minutes, wall minutes, enforcement)
+        let cases = [
+            (Mode::Off, 0, 600, 600, E::None),
+            (Mode::Degraded, 0, 59, 59, E::None),
+            (Mode::Degraded, 0, 60, 60, E::Degraded),
+            (Mode::Strict, 0, 60, 60, E::Strict),
+            // Within the grace window, then just past it
+            (Mode::Degraded, 30, 89, 89, E::None),
+            (Mode::Strict, 30, 89, 89, E::None),
+            (Mode::Degraded, 30, 90, 90, E::Degraded),
+            (Mode::Strict, 30, 90, 90, E::Strict),
+            (Mode::Strict, MAX_GRACE_MINUTES, 60 + MAX_GRACE_MINUTES - 1, 0, E::None),
+            // The device slept: the monotonic clock stood still
+            (Mode::Strict, 30, 5, 120, E::Strict),
+            // The wall clock was set back: the monotonic clock still counts
+            (Mode::Strict, 30, 120, -600, E::Strict),
+            (Mode::Strict, 30, 30, -600, E::None),
+            // The wall clock was set forward: counts like sleep
+            (Mode::Degraded, 30, 1, 24 * 60, E::Degraded),
+        ];
+        for (i, (mode, grace, mono_mins, wall_mins, enforcement)) in cases.into_iter().enumerate() {
+            let (now_mono, now_wall) = at(mono_mins, wall_mins);
+            assert_eq!(decide(mode, mins(grace), Some(&anchor), now_mono, now_wall), enforcement, "case {}", i);
+        }
+
+        // No consensus yet
+        assert_eq!(decide(Mode::Strict, mins(0), None, mono + mins(600), wall + mins(600)), E::None);
+        // Already expired on arrival: stale as soon as the grace is used up
+        let expired = Anchor { valid_until: wall - mins(60), mono, wall };
+        for (grace, now, enforcement) in [(30, 0, E::Strict), (90, 29, E::None), (90, 30, E::Strict)] {
+            let (now_mono, now_wall) = at(now, now as i64);
+            assert_eq!(decide(Mode::Strict, mins(grace), Some(&expired), now_mono, now_wall), enforcement, "grace {}", grace);
+        }
+    }
+
+    /// Keep the module's state to this test
+    struct Isolated(Option<Anchor>, u8, i32);
+
+    impl Isolated {
+        fn new() -> Isolated {
+            Isolated(
+                ANCHOR.lock().unwrap().take(),
+                CURRENT.swap(Enforcement::None as u8, Ordering::SeqCst),
+                LIFECYCLE_STATE.load(Ordering::SeqCst),
+            )
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            *ANCHOR.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = self.0.take();
+            CURRENT.store(self.1, Ordering::SeqCst);
+            LIFECYCLE_STATE.store(self.2, Ordering::SeqCst);
+        }
+    }
+
+    fn arrive(valid_for: Duration) {
+        let wall = seams::now_wall();
+        *ANCHOR.lock().unwrap() = Some(Anchor { valid_until: wall + valid_for, mono: seams::now_monotonic(), wall });
+    }
+
+    fn state() -> i32 {
+        LIFECYCLE_STATE.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn enforcement_moves_the_lifecycle_in_and_out_of_degraded() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let _isolated = Isolated::new();
+        let time = FakeTime::start();
+        crate::set_state(LifecycleState::Running);
+
+        // Off: nothing to wait for
+        arrive(mins(10));
+        assert_eq!(evaluate(), None);
+
+        config::set("max_consensus_age_mode", json!(1)).unwrap();
+        config::set("max_consensus_age_grace_mins", json!(5)).unwrap();
+        assert_eq!(evaluate(), Some(mins(15)));
+        time.advance(mins(14));
+        assert_eq!(evaluate(), Some(mins(1)));
+        assert_eq!((state(), degraded_marker()), (LifecycleState::Running as i32, None));
+
+        // Stale: degraded, traffic still allowed
+        time.advance(mins(1));
+        assert_eq!(evaluate(), None);
+        assert_eq!(state(), LifecycleState::DegradedStaleDirectory as i32);
+        assert_eq!(degraded_marker(), Some(DEGRADED_MARKER));
+        assert!(!refuses_connections());
+        config::set("max_consensus_age_mode", json!(2)).unwrap();
+        assert!(refuses_connections());
+
+        // A fresh consensus lifts it
+        arrive(mins(180));
+        assert_eq!(evaluate(), Some(mins(185)));
+        assert_eq!((state(), degraded_marker()), (LifecycleState::Running as i32, None));
+        assert!(!refuses_connections());
+
+        // Enforcement while not running leaves the lifecycle alone
+        crate::set_state(LifecycleState::Stopped);
+        time.advance(mins(185));
+        assert_eq!(evaluate(), None);
+        assert_eq!((state(), degraded_marker()), (LifecycleState::Stopped as i32, Some(DEGRADED_MARKER)));
+
+        // Turning it off ends enforcement
+        config::set("max_consensus_age_mode", json!(0)).unwrap();
+        assert_eq!(evaluate(), None);
+        assert_eq!(degraded_marker(), None);
+    }
+
+    #[test]
+    fn strict_mode_refuses_new_connects_with_its_own_reply() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let _isolated = Isolated::new();
+        let time = FakeTime::start();
+        config::set("max_consensus_age_mode", json!(2)).unwrap();
+        arrive(mins(10));
+        time.advance(mins(10));
+
+        let connector = Arc::new(FakeConnector::new());
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, "example.org", 443).await, Reply::NetworkUnreachable.frame());
+            assert!(handler.await.unwrap().is_err());
+        });
+        assert!(connector.requests().is_empty());
+    }
+}
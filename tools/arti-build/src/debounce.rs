@@ -0,0 +1,295 @@
+//! Coalescing of rapid start/stop commands
+//!
+//! Users mash the Tor switch, so the UI sends sequences like
+//! start-stop-start-stop-start within a second or two. Run in full, every
+//! stop tears the proxy down and every start bootstraps or rebinds again,
+//! wasting bootstrap work and hammering guards. Adjacent commands are
+//! therefore merged within a window (`setLifecycleDebounceMs`, default
+//! `DEFAULT_WINDOW_MS`):
+//!
+//! | command | situation                                   | decision           |
+//! |---------|---------------------------------------------|--------------------|
+//! | either  | window 0 (disabled)                         | execute            |
+//! | stop    | a bootstrap is in flight                    | cancel bootstrap   |
+//! | stop    | a stop is already pending                   | merge              |
+//! | stop    | the listener is running                     | defer              |
+//! | stop    | otherwise                                   | execute            |
+//! | start   | stop pending, listener matches the request  | coalesce           |
+//! | start   | stop pending, different request             | flush, execute     |
+//! | start   | otherwise                                   | execute            |
+//!
+//! - defer: `stop` returns 0 at once and the proxy stays up for the window;
+//!   the stop then runs in full unless a start arrived;
+//! - coalesce: the pending stop is dropped and the listener kept, so neither
+//!   call did anything and both succeed; `getLastStartResult` reports
+//!   `"coalesced": true`. This is only safe because the proxy never went down;
+//! - flush: the pending stop runs now, before the start, as it would have
+//!   without a window;
+//! - cancel bootstrap: an `initialize` still bootstrapping is cancelled
+//!   through its `CancelToken` and returns `INIT_ERR_CANCELLED`, instead of
+//!   completing a bootstrap nobody waits for; then the stop runs.
+//!
+//! Only `stop` is debounced. `stopWithDeadline`, `shutdown` and data
+//! directory migration stop at once and drop any pending stop. Set the
+//! window to 0 for deterministic integration tests.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cancel::CancelToken;
+
+pub(crate) const DEFAULT_WINDOW_MS: u64 = 1500;
+
+/// Longest window that can be set
+pub(crate) const MAX_WINDOW_MS: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    Start,
+    Stop,
+}
+
+/// What a command finds when it arrives
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Situation {
+    pub(crate) window_ms: u64,
+    pub(crate) bootstrapping: bool,
+    pub(crate) stop_pending: bool,
+    pub(crate) listener_running: bool,
+    /// The running listener is what the start asks for (see reconcile.rs)
+    pub(crate) listener_matches: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Execute,
+    CancelBootstrap,
+    Merge,
+    Defer,
+    Coalesce,
+    FlushThenExecute,
+}
+
+/// The decision table above
+pub(crate) fn decide(command: Command, situation: &Situation) -> Decision {
+    if situation.window_ms == 0 {
+        return Decision::Execute;
+    }
+    match command {
+        Command::Stop if situation.bootstrapping => Decision::CancelBootstrap,
+        Command::Stop if situation.stop_pending => Decision::Merge,
+        Command::Stop if situation.listener_running => Decision::Defer,
+        Command::Stop => Decision::Execute,
+        Command::Start if situation.stop_pending && situation.listener_matches => Decision::Coalesce,
+        Command::Start if situation.stop_pending => Decision::FlushThenExecute,
+        Command::Start => Decision::Execute,
+    }
+}
+
+/// Error context of a bootstrap cancelled by `stop` (see bootstrap.rs)
+#[derive(Debug)]
+pub(crate) struct BootstrapCancelled;
+
+impl std::fmt::Display for BootstrapCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("bootstrap cancelled by stop")
+    }
+}
+
+impl std::error::Error for BootstrapCancelled {}
+
+static WINDOW_MS: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW_MS);
+
+/// Generation of the pending stop, if any (each deferral gets a new one)
+static PENDING: Mutex<Option<u64>> = Mutex::new(None);
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Cancels the bootstrap of the `initialize` in flight
+static BOOTSTRAP: Mutex<Option<CancelToken>> = Mutex::new(None);
+
+pub(crate) fn set_window_ms(ms: u64) {
+    WINDOW_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Decide `command` given the listener state; the pending stop and bootstrap
+/// are filled in here
+pub(crate) fn decide_now(command: Command, listener_running: bool, listener_matches: bool) -> Decision {
+    let situation = Situation {
+        window_ms: WINDOW_MS.load(Ordering::Relaxed),
+        bootstrapping: BOOTSTRAP.lock().unwrap().is_some(),
+        stop_pending: PENDING.lock().unwrap().is_some(),
+        listener_running,
+        listener_matches,
+    };
+    decide(command, &situation)
+}
+
+/// Defer a stop: `run` is called after the window unless the stop is taken
+/// back or run earlier first
+pub(crate) fn defer_stop(run: fn()) {
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    *PENDING.lock().unwrap() = Some(generation);
+    let window = Duration::from_millis(WINDOW_MS.load(Ordering::Relaxed));
+    let spawned = std::thread::Builder::new().name("arti-stop-debounce".into()).spawn(move || {
+        std::thread::sleep(window);
+        let mut pending = PENDING.lock().unwrap();
+        if *pending == Some(generation) {
+            *pending = None;
+            drop(pending);
+            log_info!("No start within the debounce window; stopping");
+            run();
+        }
+    });
+    if spawned.is_err() {
+        log_error!("Failed to spawn the debounce timer; stopping now");
+        *PENDING.lock().unwrap() = None;
+        run();
+    }
+}
+
+/// Drop the pending stop; returns whether there was one
+pub(crate) fn take_pending() -> bool {
+    PENDING.lock().unwrap().take().is_some()
+}
+
+/// Register a bootstrap that `stop` may cancel
+pub(crate) fn bootstrap_started() -> CancelToken {
+    let token = CancelToken::new();
+    *BOOTSTRAP.lock().unwrap() = Some(token.clone());
+    token
+}
+
+pub(crate) fn bootstrap_finished() {
+    *BOOTSTRAP.lock().unwrap() = None;
+}
+
+/// Cancel the bootstrap in flight, if any
+pub(crate) fn cancel_bootstrap() -> bool {
+    match BOOTSTRAP.lock().unwrap().take() {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&PENDING);
+    held.hold(&BOOTSTRAP);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::testing;
+
+    /// No pending stop or bootstrap and a `window_ms` window for one test,
+    /// the previous state restored after
+    struct Fresh {
+        window_ms: u64,
+    }
+
+    impl Fresh {
+        fn with_window(window_ms: u64) -> Fresh {
+            take_pending();
+            bootstrap_finished();
+            Fresh { window_ms: WINDOW_MS.swap(window_ms, Ordering::Relaxed) }
+        }
+    }
+
+    impl Drop for Fresh {
+        fn drop(&mut self) {
+            take_pending();
+            bootstrap_finished();
+            WINDOW_MS.store(self.window_ms, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn decision_table() {
+        use Command::*;
+        use Decision::*;
+
+        // (command, window, bootstrapping, stop pending, running, matches, decision)
+        for (command, window_ms, bootstrapping, stop_pending, listener_running, listener_matches, decision) in [
+            (Stop, 0, true, true, true, true, Execute),
+            (Start, 0, false, true, true, true, Execute),
+            (Stop, 1500, true, false, false, false, CancelBootstrap),
+            (Stop, 1500, true, true, true, false, CancelBootstrap),
+            (Stop, 1500, false, true, true, false, Merge),
+            (Stop, 1500, false, false, true, false, Defer),
+            (Stop, 1500, false, false, false, false, Execute),
+            (Start, 1500, false, true, true, true, Coalesce),
+            (Start, 1500, false, true, true, false, FlushThenExecute),
+            (Start, 1500, false, false, true, true, Execute),
+            (Start, 1500, true, false, false, false, Execute),
+        ] {
+            let situation = Situation { window_ms, bootstrapping, stop_pending, listener_running, listener_matches };
+            assert_eq!(decide(command, &situation), decision, "{:?} in {:?}", command, situation);
+        }
+    }
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_run() {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn a_deferred_stop_runs_once_after_the_window_unless_taken_back() {
+        let _serial = testing::serial();
+        let _fresh = Fresh::with_window(50);
+        let window = Duration::from_millis(50);
+        RUNS.store(0, Ordering::SeqCst);
+
+        // Left alone, it runs after the window
+        defer_stop(count_run);
+        assert_eq!(decide_now(Command::Stop, true, false), Decision::Merge);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 0);
+        std::thread::sleep(window * 4);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+        assert_eq!(decide_now(Command::Stop, true, false), Decision::Defer);
+
+        // Taken back within the window, it never runs
+        defer_stop(count_run);
+        assert_eq!(decide_now(Command::Start, true, true), Decision::Coalesce);
+        assert!(take_pending());
+        assert!(!take_pending());
+        std::thread::sleep(window * 4);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+
+        // A later deferral supersedes an earlier one: a single run
+        defer_stop(count_run);
+        defer_stop(count_run);
+        std::thread::sleep(window * 4);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn a_stop_cancels_the_bootstrap_in_flight() {
+        let _serial = testing::serial();
+        let _fresh = Fresh::with_window(DEFAULT_WINDOW_MS);
+        assert!(!cancel_bootstrap());
+
+        let token = bootstrap_started();
+        assert_eq!(decide_now(Command::Stop, false, false), Decision::CancelBootstrap);
+        assert!(cancel_bootstrap());
+        let cancelled = testing::runtime().block_on(async { tokio::time::timeout(Duration::from_secs(5), token.cancelled()).await });
+        assert!(cancelled.is_ok());
+        assert_eq!(decide_now(Command::Stop, false, false), Decision::Execute);
+
+        // The cancelled initialize reports its own failure code
+        let error = anyhow::Error::new(BootstrapCancelled);
+        assert_eq!(crate::bootstrap::classify(&error), crate::bootstrap::Failure::Cancelled);
+        assert_eq!(crate::bootstrap::Failure::Cancelled.code(), -11);
+
+        let _token = bootstrap_started();
+        bootstrap_finished();
+        assert!(!cancel_bootstrap());
+    }
+}
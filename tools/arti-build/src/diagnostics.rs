@@ -0,0 +1,425 @@
+//! Tracing bridge, debug capture ring, and failure records
+//!
+//! Arti logs through `tracing`. The bridge layer installed here forwards
+//! INFO-and-above events (DEBUG too with verbose logging on) to the normal
+//! log sinks, and additionally keeps every
+//! DEBUG-and-above event from the last `RING_MAX_AGE` in a small in-memory
+//! ring. Nothing from the ring is delivered anywhere on its own; when an
+//! error-class event happens (bootstrap failure, proxy death, panic) the ring
+//! is frozen into the failure record, so bug reports carry debug context
+//! without anyone having to enable debug logging and reproduce the problem.
+//!
+//! Arti's own safe-logging scrubs sensitive values (addresses, hostnames) from
+//! its events before they reach any layer, so the captured context is already
+//! redacted. Wrapper messages may carry marked values (see redact.rs); the
+//! record renders them when it is read, like every other sink.
+//!
+//! The panic hook records its failure too, but a panic can happen on a thread
+//! holding the ring or the record lock, and the hook runs before that lock is
+//! released. So the hook only ever tries the locks and records nothing when
+//! either is held; the default hook still reports the panic.
+//!
+//! A global subscriber can only be set once per process, so `install` does
+//! its work on the first call only and every initialize may call it. The
+//! bridge holds no per-initialize state: the level (`set_verbose`) and the
+//! sinks it writes to are read per event, so changes made between stop and a
+//! later initialize apply without reinstalling anything.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+use crate::seams;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::{json_string, redact};
+use crate::notification::now_epoch_ms;
+use crate::retention::{self, Sink};
+
+/// How far back the debug capture ring reaches
+const RING_MAX_AGE: Duration = Duration::from_secs(15);
+
+/// Upper bound on the text held by the debug capture ring
+const RING_MAX_BYTES: usize = 64 * 1024;
+
+/// Guards `install`
+static INSTALL: Once = Once::new();
+
+/// Whether DEBUG events also go to the log sinks
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Forward DEBUG events to the log sinks as well
+pub(crate) fn set_verbose(enabled: bool) {
+    VERBOSE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether verbose (DEBUG) logging is on
+pub(crate) fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Recent DEBUG-level lines, oldest first
+struct DebugRing {
+    lines: VecDeque<(Instant, String)>,
+    bytes: usize,
+}
+
+impl DebugRing {
+    fn push(&mut self, line: String) {
+        self.bytes += line.len();
+        self.lines.push_back((seams::now_monotonic(), line));
+        self.prune();
+    }
+
+    /// Drop lines that are too old, then oldest lines until under the byte cap
+    fn prune(&mut self) {
+        let now = seams::now_monotonic();
+        while let Some((at, line)) = self.lines.front() {
+            if now.saturating_duration_since(*at) <= RING_MAX_AGE && self.bytes <= RING_MAX_BYTES {
+                break;
+            }
+            self.bytes -= line.len();
+            self.lines.pop_front();
+        }
+    }
+
+    fn snapshot(&mut self) -> Vec<String> {
+        self.prune();
+        self.lines.iter().map(|(_, line)| line.clone()).collect()
+    }
+}
+
+static DEBUG_RING: Mutex<DebugRing> = Mutex::new(DebugRing {
+    lines: VecDeque::new(),
+    bytes: 0,
+});
+
+/// Diagnostic record for the most recent error-class event
+struct FailureRecord {
+    kind: &'static str,
+    message: String,
+    at_epoch_ms: u64,
+    debug_context: Vec<String>,
+}
+
+static LAST_FAILURE: Mutex<Option<FailureRecord>> = Mutex::new(None);
+
+/// Record an error-class event, freezing the debug ring into its record
+///
+/// `kind` is a stable identifier (e.g. "bootstrap_failed", "proxy_died").
+pub(crate) fn record_failure(kind: &'static str, message: String) {
+    let debug_context = DEBUG_RING.lock().unwrap().snapshot();
+    *LAST_FAILURE.lock().unwrap() = Some(FailureRecord {
+        kind,
+        message,
+        at_epoch_ms: retention::timestamp(now_epoch_ms()),
+        debug_context,
+    });
+}
+
+/// `record_failure` for the panic hook: skipped if either lock is held
+///
+/// Returns whether the failure was recorded.
+fn try_record_failure(kind: &'static str, message: String) -> bool {
+    use std::sync::TryLockError;
+
+    // A lock poisoned by an earlier panic is still usable: the guarded data
+    // is only ever replaced whole
+    fn try_lock<T>(mutex: &Mutex<T>) -> Option<std::sync::MutexGuard<'_, T>> {
+        match mutex.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    let Some(debug_context) = try_lock(&DEBUG_RING).map(|mut ring| ring.snapshot()) else {
+        return false;
+    };
+    let Some(mut last) = try_lock(&LAST_FAILURE) else {
+        return false;
+    };
+    *last = Some(FailureRecord {
+        kind,
+        message,
+        at_epoch_ms: retention::timestamp(now_epoch_ms()),
+        debug_context,
+    });
+    true
+}
+
+/// Drop the debug ring and the context frozen into the last failure record
+pub(crate) fn clear_debug_context() {
+    let mut ring = DEBUG_RING.lock().unwrap();
+    ring.lines.clear();
+    ring.bytes = 0;
+    drop(ring);
+    if let Some(record) = LAST_FAILURE.lock().unwrap().as_mut() {
+        record.debug_context.clear();
+        record.at_epoch_ms = retention::timestamp(record.at_epoch_ms);
+    }
+}
+
+/// The last failure record as JSON, or `null` if nothing has failed
+pub(crate) fn last_error_json() -> String {
+    let guard = LAST_FAILURE.lock().unwrap();
+    let Some(record) = guard.as_ref() else {
+        return "null".to_string();
+    };
+
+    let context = record
+        .debug_context
+        .iter()
+        .map(|line| json_string(&redact::render(line)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"kind\":\"{}\",\"message\":{},\"at_epoch_ms\":{},\"debug_context\":[{}]}}",
+        record.kind,
+        json_string(&redact::render(&record.message)),
+        record.at_epoch_ms,
+        context,
+    )
+}
+
+/// Collects an event's fields into one line
+struct LineVisitor(String);
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Layer bridging Arti's tracing events into the wrapper's log sinks
+pub(crate) struct BridgeLayer;
+
+impl<S: Subscriber> Layer<S> for BridgeLayer {
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        *metadata.level() <= Level::DEBUG
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut visitor = LineVisitor(String::new());
+        event.record(&mut visitor);
+        let line = format!("{} {}: {}", metadata.level(), metadata.target(), visitor.0);
+
+        if *metadata.level() <= Level::INFO || verbose() {
+            log_info!("{}", line);
+        }
+        if let Some(category) = crate::security::classify_event(metadata.level(), metadata.target(), &visitor.0) {
+            crate::security::report(category, metadata.target(), &visitor.0);
+        }
+        crate::guards::observe_event(metadata.target(), &visitor.0);
+        if retention::retains(Sink::DebugRing) {
+            DEBUG_RING.lock().unwrap().push(line);
+        }
+    }
+}
+
+/// Install the tracing bridge and the panic hook (later calls do nothing)
+pub(crate) fn install() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    INSTALL.call_once(|| {
+        let subscriber = tracing_subscriber::registry().with(BridgeLayer);
+        if tracing::subscriber::set_global_default(subscriber).is_err() {
+            log_error!("A tracing subscriber is already installed; Arti logs not bridged");
+        }
+
+        // Installed together with the bridge so repeated calls don't chain
+        // hooks that each record the same panic
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            try_record_failure("panic", info.to_string());
+            log_error!("Panic: {}", info);
+            default_hook(info);
+        }));
+    });
+}
+
+/// Forget the last failure record
+#[cfg(test)]
+pub(crate) fn clear_last_failure() {
+    *LAST_FAILURE.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&DEBUG_RING);
+    held.hold(&LAST_FAILURE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    fn reset() {
+        clear_debug_context();
+        clear_last_failure();
+    }
+
+    fn last_error() -> serde_json::Value {
+        serde_json::from_str(&last_error_json()).unwrap()
+    }
+
+    #[test]
+    fn ring_is_bounded_by_bytes() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        reset();
+        let line = "x".repeat(1000);
+        let mut ring = DEBUG_RING.lock().unwrap();
+        for i in 0..500 {
+            ring.push(format!("{} {}", i, line));
+            assert!(ring.bytes <= RING_MAX_BYTES, "{} bytes after {} lines", ring.bytes, i + 1);
+        }
+        let kept = ring.snapshot();
+        assert!(kept.last().unwrap().starts_with("499 "));
+        assert!(kept.len() < 500);
+        assert_eq!(ring.bytes, kept.iter().map(String::len).sum::<usize>());
+        drop(ring);
+        reset();
+    }
+
+    #[test]
+    fn ring_forgets_old_lines() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        reset();
+        DEBUG_RING.lock().unwrap().push("old".into());
+        time.advance(RING_MAX_AGE);
+        DEBUG_RING.lock().unwrap().push("recent".into());
+        assert_eq!(DEBUG_RING.lock().unwrap().snapshot(), ["old", "recent"]);
+        time.advance(Duration::from_secs(1));
+        assert_eq!(DEBUG_RING.lock().unwrap().snapshot(), ["recent"]);
+        reset();
+    }
+
+    #[test]
+    fn failure_freezes_the_ring() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        reset();
+        assert_eq!(last_error_json(), "null");
+
+        DEBUG_RING.lock().unwrap().push("DEBUG tor_guardmgr: before".into());
+        record_failure("bootstrap_failed", "no guards".into());
+        DEBUG_RING.lock().unwrap().push("DEBUG tor_guardmgr: after".into());
+
+        let record = last_error();
+        assert_eq!(record["kind"], "bootstrap_failed");
+        assert_eq!(record["message"], "no guards");
+        assert_eq!(record["debug_context"], serde_json::json!(["DEBUG tor_guardmgr: before"]));
+
+        // Clearing (retention) drops the frozen context but keeps the record
+        clear_debug_context();
+        assert_eq!(last_error()["debug_context"], serde_json::json!([]));
+        assert_eq!(last_error()["kind"], "bootstrap_failed");
+        reset();
+    }
+
+    #[test]
+    fn record_is_redacted_when_read() {
+        let _serial = testing::serial();
+        reset();
+        let host = redact::Sensitive("example.com");
+        DEBUG_RING.lock().unwrap().push(format!("DEBUG wrapper: dialing {}", host));
+        record_failure("proxy_died", format!("lost {}", host));
+
+        assert_eq!(last_error()["message"], "lost [scrubbed]");
+        assert_eq!(last_error()["debug_context"][0], "DEBUG wrapper: dialing [scrubbed]");
+        // Decided at read time, not when recorded
+        redact::set_safe_logging(false);
+        assert_eq!(last_error()["message"], "lost example.com");
+        assert_eq!(last_error()["debug_context"][0], "DEBUG wrapper: dialing example.com");
+        redact::set_safe_logging(true);
+        reset();
+    }
+
+    #[test]
+    fn bridge_captures_debug_events() {
+        let _serial = testing::serial();
+        reset();
+        use tracing_subscriber::layer::SubscriberExt;
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(BridgeLayer), || {
+            tracing::debug!(target: "tor_dirmgr", "fetching consensus");
+            tracing::trace!(target: "tor_dirmgr", "too fine to keep");
+        });
+        assert_eq!(DEBUG_RING.lock().unwrap().snapshot(), ["DEBUG tor_dirmgr: fetching consensus"]);
+        reset();
+    }
+
+    #[test]
+    fn panic_hook_never_waits_for_a_held_lock() {
+        let _serial = testing::serial();
+        reset();
+        for lock in ["ring", "record"] {
+            let (held_tx, held_rx) = std::sync::mpsc::channel();
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            let holder = std::thread::spawn(move || {
+                let _ring = (lock == "ring").then(|| DEBUG_RING.lock().unwrap());
+                let _record = (lock == "record").then(|| LAST_FAILURE.lock().unwrap());
+                held_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            });
+            held_rx.recv().unwrap();
+            // Would deadlock if it waited; the holder only lets go afterwards
+            assert!(!try_record_failure("panic", "while held".into()), "{} held", lock);
+            release_tx.send(()).unwrap();
+            holder.join().unwrap();
+            assert_eq!(last_error_json(), "null");
+        }
+
+        assert!(try_record_failure("panic", "uncontended".into()));
+        assert_eq!(last_error()["message"], "uncontended");
+        reset();
+    }
+
+    fn sent_lines(marker: &str) -> usize {
+        crate::tests::SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains(marker)).count()
+    }
+
+    #[test]
+    fn every_initialize_may_install_the_bridge() {
+        let _serial = testing::serial();
+        reset();
+        let already = "A tracing subscriber is already installed";
+        let installed_before = sent_lines(already);
+
+        // First init cycle
+        install();
+        tracing::info!(target: "tor_dirmgr", "first cycle bridged");
+        tracing::debug!(target: "tor_dirmgr", "first cycle detail");
+        assert_eq!(sent_lines("INFO tor_dirmgr: first cycle bridged"), 1);
+        assert_eq!(sent_lines("first cycle detail"), 0);
+
+        // Stopped, the level changed, initialized again: no panic, and the
+        // same bridge picks up the new level
+        set_verbose(true);
+        install();
+        tracing::info!(target: "tor_dirmgr", "second cycle bridged");
+        tracing::debug!(target: "tor_dirmgr", "second cycle detail");
+        set_verbose(false);
+        assert_eq!(sent_lines("INFO tor_dirmgr: second cycle bridged"), 1);
+        assert_eq!(sent_lines("DEBUG tor_dirmgr: second cycle detail"), 1);
+        assert_eq!(sent_lines(already), installed_before);
+
+        // One panic hook, not one per initialize
+        let _ = std::thread::spawn(|| panic!("hook count probe")).join();
+        assert_eq!(sent_lines("hook count probe"), 1);
+        assert_eq!(last_error()["kind"], "panic");
+        reset();
+    }
+}
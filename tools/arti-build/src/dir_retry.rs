@@ -0,0 +1,249 @@
+//! Directory download retries: Arti's schedules and attempt counts
+//!
+//! On flaky networks directory downloads fail and bootstrap sits in Arti's
+//! retry loop. `setDirectoryRetry(json)` tunes Arti's download schedules,
+//! applied on the next initialize:
+//!
+//! ```text
+//! {"bootstrap":{..},"consensus":{..},"certs":{..},"microdescs":{..}}
+//! ```
+//!
+//! each with any of `attempts` (1..=`MAX_ATTEMPTS`), `initial_delay_ms`
+//! (up to `MAX_INITIAL_DELAY_MS`; later delays grow from it) and
+//! `parallelism` (1..=255, requests in flight at once). Omitted schedules and
+//! fields keep Arti's defaults; unknown names are refused. `bootstrap` is the
+//! outer loop around the whole directory download, the others one document
+//! type each.
+//!
+//! The passes of that outer loop since initialize are reported as
+//! `directory_fetch_attempts` in `getBootstrapStatus`, so an app can tell a
+//! slow first attempt from one retrying over and over. They are counted from
+//! each client's bootstrap events (`TorClient::bootstrap_events`): its first
+//! status starts a pass, and so does directory progress falling back to
+//! nothing, which is how a failed pass that got anywhere shows. A pass that
+//! fails before the consensus arrives leaves the progress where it was and
+//! isn't told apart from a slow one.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use arti_client::config::TorClientConfigBuilder;
+use futures::{Stream, StreamExt};
+use serde_json::{Map, Value};
+
+/// Schedule names, as in Arti's `download_schedule` section
+const SCHEDULES: &[&str] = &["bootstrap", "consensus", "certs", "microdescs"];
+
+const MAX_ATTEMPTS: u64 = 1000;
+const MAX_INITIAL_DELAY_MS: u64 = 10 * 60 * 1000;
+
+/// Share of `BootstrapStatus::as_frac` that is the connection rather than the
+/// directory; at or below it no directory progress is left
+const CONNECTION_SHARE: f32 = 0.15;
+
+/// Directory download passes seen since initialize
+static ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+
+fn check_schedule(name: &str, schedule: &Value) -> Result<(), String> {
+    let fields = schedule.as_object().ok_or_else(|| format!("{}: expected an object", name))?;
+    for (field, value) in fields {
+        let range = match field.as_str() {
+            "attempts" => 1..=MAX_ATTEMPTS,
+            "initial_delay_ms" => 0..=MAX_INITIAL_DELAY_MS,
+            "parallelism" => 1..=u8::MAX as u64,
+            _ => return Err(format!("{}: unknown field {}", name, field)),
+        };
+        if !value.as_u64().is_some_and(|n| range.contains(&n)) {
+            return Err(format!("{}.{}: expected {}..={}", name, field, range.start(), range.end()));
+        }
+    }
+    Ok(())
+}
+
+/// Check a `directory_retry` setting value
+pub(crate) fn check(value: &Value) -> Result<(), String> {
+    let schedules = value.as_object().ok_or("expected an object of schedules")?;
+    for (name, schedule) in schedules {
+        if !SCHEDULES.contains(&name.as_str()) {
+            return Err(format!("unknown schedule {} (known: {})", name, SCHEDULES.join(", ")));
+        }
+        check_schedule(name, schedule)?;
+    }
+    Ok(())
+}
+
+/// The configured fields of each schedule (checked with `check`)
+pub(crate) fn schedules(value: &Value) -> Vec<(&'static str, Map<String, Value>)> {
+    SCHEDULES
+        .iter()
+        .filter_map(|name| Some((*name, value.get(name)?.as_object()?.clone())))
+        .collect()
+}
+
+/// Apply the schedules in `value` (checked with `check`) to a client config
+pub(crate) fn apply(builder: &mut TorClientConfigBuilder, value: &Value) {
+    for (name, fields) in schedules(value) {
+        let download = builder.download_schedule();
+        let schedule = match name {
+            "bootstrap" => download.retry_bootstrap(),
+            "consensus" => download.retry_consensus(),
+            "certs" => download.retry_certs(),
+            _ => download.retry_microdescs(),
+        };
+        let field = |key: &str| fields.get(key).and_then(Value::as_u64);
+        if let Some(attempts) = field("attempts") {
+            schedule.attempts(attempts as u32);
+        }
+        if let Some(ms) = field("initial_delay_ms") {
+            schedule.initial_delay(Duration::from_millis(ms));
+        }
+        if let Some(parallelism) = field("parallelism") {
+            schedule.parallelism(parallelism as u8);
+        }
+    }
+}
+
+/// Start counting attempts afresh (initialize)
+pub(crate) fn reset_attempts() {
+    ATTEMPTS.store(0, Ordering::Relaxed);
+}
+
+pub(crate) fn attempts() -> u32 {
+    ATTEMPTS.load(Ordering::Relaxed)
+}
+
+/// Whether bootstrap progress `fraction`, following `previous` (None: the
+/// client's first status), starts a new directory download pass
+fn starts_pass(previous: Option<f32>, fraction: f32) -> bool {
+    match previous {
+        None => true,
+        Some(previous) => previous > CONNECTION_SHARE && fraction <= CONNECTION_SHARE,
+    }
+}
+
+/// Counts a client's directory download passes until dropped
+pub(crate) struct AttemptCounter(tokio::task::JoinHandle<()>);
+
+impl AttemptCounter {
+    /// Follow `fractions`, Arti's bootstrap events as `BootstrapStatus::as_frac`
+    pub(crate) fn follow(fractions: impl Stream<Item = f32> + Send + 'static) -> AttemptCounter {
+        AttemptCounter(tokio::spawn(async move {
+            futures::pin_mut!(fractions);
+            let mut previous = None;
+            while let Some(fraction) = fractions.next().await {
+                if starts_pass(previous, fraction) {
+                    ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+                }
+                previous = Some(fraction);
+            }
+        }))
+    }
+}
+
+impl Drop for AttemptCounter {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn settings_are_checked() {
+        for (value, valid) in [
+            (json!({}), true),
+            (json!({"bootstrap": {"attempts": 1}}), true),
+            (json!({"consensus": {"attempts": MAX_ATTEMPTS, "initial_delay_ms": 0, "parallelism": 255}}), true),
+            (json!({"certs": {}, "microdescs": {"initial_delay_ms": MAX_INITIAL_DELAY_MS}}), true),
+            (json!([]), false),
+            (json!({"descriptors": {}}), false),
+            (json!({"bootstrap": 3}), false),
+            (json!({"bootstrap": {"retries": 3}}), false),
+            (json!({"bootstrap": {"attempts": 0}}), false),
+            (json!({"bootstrap": {"attempts": MAX_ATTEMPTS + 1}}), false),
+            (json!({"bootstrap": {"attempts": "3"}}), false),
+            (json!({"consensus": {"initial_delay_ms": MAX_INITIAL_DELAY_MS + 1}}), false),
+            (json!({"consensus": {"initial_delay_ms": -1}}), false),
+            (json!({"certs": {"parallelism": 0}}), false),
+            (json!({"certs": {"parallelism": 256}}), false),
+        ] {
+            assert_eq!(check(&value).is_ok(), valid, "{}", value);
+        }
+    }
+
+    #[test]
+    fn schedules_come_in_arti_order() {
+        let value = json!({"microdescs": {"parallelism": 2}, "bootstrap": {"attempts": 4}, "certs": {}});
+        let names: Vec<_> = schedules(&value).into_iter().map(|(name, fields)| (name, fields.len())).collect();
+        assert_eq!(names, [("bootstrap", 1), ("certs", 0), ("microdescs", 1)]);
+        assert!(schedules(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn a_config_with_custom_retries_builds() {
+        let temp = TempDir::new();
+        for value in [
+            json!({}),
+            json!({"bootstrap": {"attempts": 12, "initial_delay_ms": 250, "parallelism": 1}}),
+            json!({
+                "bootstrap": {"attempts": MAX_ATTEMPTS},
+                "consensus": {"attempts": 2, "initial_delay_ms": MAX_INITIAL_DELAY_MS},
+                "certs": {"parallelism": 255},
+                "microdescs": {"attempts": 1, "initial_delay_ms": 0, "parallelism": 8},
+            }),
+        ] {
+            check(&value).unwrap();
+            let mut builder = TorClientConfigBuilder::from_directories(temp.path().join("state"), temp.path().join("cache"));
+            apply(&mut builder, &value);
+            assert!(builder.build().is_ok(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn passes_start_with_the_client_and_on_falling_back() {
+        // (previous progress, progress, whether a pass starts)
+        for (previous, fraction, starts) in [
+            (None, 0.0, true),
+            (None, 0.6, true),
+            (Some(0.0), 0.0, false),
+            (Some(0.0), 0.15, false),
+            (Some(0.15), 0.4, false),
+            (Some(0.4), 0.9, false),
+            // Directory progress lost, connection kept or not
+            (Some(0.4), 0.15, true),
+            (Some(0.9), 0.0, true),
+            // Only the connection share lost
+            (Some(0.9), 0.75, false),
+            (Some(0.15), 0.0, false),
+        ] {
+            assert_eq!(starts_pass(previous, fraction), starts, "{:?} -> {}", previous, fraction);
+        }
+    }
+
+    #[test]
+    fn attempts_add_up_across_clients_until_reset() {
+        let _serial = testing::serial();
+        reset_attempts();
+        testing::runtime().block_on(async {
+            let follow = |fractions: Vec<f32>| async move {
+                let counter = AttemptCounter::follow(futures::stream::iter(fractions));
+                while !counter.0.is_finished() {
+                    tokio::task::yield_now().await;
+                }
+            };
+            // Two failed passes, the third gets through
+            follow(vec![0.0, 0.15, 0.3, 0.5, 0.15, 0.15, 0.6, 0.0, 0.15, 0.5, 1.0]).await;
+            assert_eq!(attempts(), 3);
+            // The next bootstrap stage's client starts a pass of its own
+            follow(vec![0.0, 0.15, 1.0]).await;
+            assert_eq!(attempts(), 4);
+        });
+        reset_attempts();
+        assert_eq!(attempts(), 0);
+    }
+}
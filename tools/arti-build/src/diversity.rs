@@ -0,0 +1,310 @@
+//! Exit diversity of recent streams
+//!
+//! Privacy-sensitive apps want to know whether their traffic is spread over
+//! many exits or concentrated on a few, and whether their own restrictions
+//! (`setRequiredExitFlags`) are the cause. Each established stream records
+//! the exit of its circuit, and `getDiversityReport` summarises the last
+//! hour:
+//!
+//! - `score`: Shannon entropy of the streams-per-exit distribution over the
+//!   highest entropy those streams could have had (all on different exits,
+//!   as far as the exit pool allows), from 0 (one exit) to 1; `null` with
+//!   fewer than two streams;
+//! - `top_exits`: the `TOP_N` busiest exits with their share of streams.
+//!   Exits are only named by the first `ID_PREFIX_LEN` hex digits of their
+//!   fingerprint, enough to tell them apart in one report;
+//! - `restricted_fraction`: the share of streams opened while exit flags
+//!   were required;
+//! - `pool_warning`: a plain-language note when the required flags leave
+//!   fewer than `LOW_POOL_FRACTION` of the exits in the current consensus
+//!   usable (from the geo index), `null` otherwise.
+//!
+//! Onion service streams have no exit and are not counted. The window is
+//! kept in memory only and, like other histories, not at all at the minimal
+//! retention level.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use arti_client::DataStream;
+use serde_json::json;
+
+use crate::liveness::monotonic_ms;
+use crate::retention::{self, Sink};
+use crate::{circuit_path, config, geo};
+
+/// How far back the report looks
+const WINDOW_MS: u64 = 60 * 60 * 1000;
+
+/// Streams kept at most, oldest dropped first
+const MAX_SAMPLES: usize = 4096;
+
+/// Exits listed in `top_exits`
+const TOP_N: usize = 5;
+
+/// Hex digits of a fingerprint shown in the report
+const ID_PREFIX_LEN: usize = 6;
+
+/// Usable share of the exit pool below which restrictions are flagged
+const LOW_POOL_FRACTION: f64 = 0.5;
+
+struct Sample {
+    at_ms: u64,
+    exit: String,
+    country: String,
+    restricted: bool,
+}
+
+static SAMPLES: Mutex<VecDeque<Sample>> = Mutex::new(VecDeque::new());
+
+fn required_flags() -> Vec<String> {
+    config::strings(&config::applied("required_exit_flags"))
+}
+
+/// Record the exit of an established stream to `target_host`
+pub(crate) fn record(stream: &DataStream, target_host: &str) {
+    if !retention::retains(Sink::ExitHistory) || target_host.ends_with(".onion") {
+        return;
+    }
+    let Some(exit) = circuit_path::exit(stream) else {
+        return;
+    };
+    let country = geo::country_of(&exit);
+    let mut samples = SAMPLES.lock().unwrap();
+    if samples.len() >= MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(Sample { at_ms: monotonic_ms(), exit, country, restricted: !required_flags().is_empty() });
+}
+
+/// Forget all recorded exits
+pub(crate) fn purge() {
+    SAMPLES.lock().unwrap().clear();
+}
+
+/// Warning when the required flags shrink the exit pool, if they do
+fn pool_warning(flags: &[String]) -> Option<String> {
+    if flags.is_empty() {
+        return None;
+    }
+    let (exits, usable) = geo::exit_pool(flags)?;
+    low_pool_warning(flags, exits, usable)
+}
+
+/// Warning when `usable` of `exits` is too small a share
+fn low_pool_warning(flags: &[String], exits: usize, usable: usize) -> Option<String> {
+    if exits == 0 || (usable as f64) >= exits as f64 * LOW_POOL_FRACTION {
+        return None;
+    }
+    Some(format!(
+        "Required exit flags ({}) leave {} of {} exits usable, so traffic is spread over fewer exits.",
+        flags.join(", "),
+        usable,
+        exits
+    ))
+}
+
+/// Entropy in bits of the streams-per-exit `counts`, and the score: that
+/// entropy over the highest the same streams could reach with `pool` usable
+/// exits (unknown: as many as there are streams)
+fn score(counts: &[usize], pool: Option<usize>) -> (f64, Option<f64>) {
+    let streams: usize = counts.iter().sum();
+    let entropy: f64 = counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / streams as f64;
+            p * (1.0 / p).log2()
+        })
+        .sum();
+    let best = pool.map_or(streams, |usable| streams.min(usable.max(1)));
+    let score = (streams >= 2 && best >= 2).then(|| (entropy / (best as f64).log2()).min(1.0));
+    (entropy, score)
+}
+
+/// The report over the current window as JSON
+pub(crate) fn report_json() -> String {
+    let now = monotonic_ms();
+    let mut samples = SAMPLES.lock().unwrap();
+    while samples.front().is_some_and(|sample| now.saturating_sub(sample.at_ms) > WINDOW_MS) {
+        samples.pop_front();
+    }
+
+    let streams = samples.len();
+    let mut per_exit: HashMap<&str, (usize, &str)> = HashMap::new();
+    for sample in samples.iter() {
+        per_exit.entry(&sample.exit).or_insert((0, &sample.country)).0 += 1;
+    }
+    let restricted = samples.iter().filter(|sample| sample.restricted).count();
+
+    let flags = required_flags();
+    let pool = geo::exit_pool(&flags).map(|(_, usable)| usable);
+    let counts: Vec<usize> = per_exit.values().map(|(count, _)| *count).collect();
+    let (entropy, score) = score(&counts, pool);
+
+    let mut ranked: Vec<(&str, usize, &str)> = per_exit.iter().map(|(exit, (count, cc))| (*exit, *count, *cc)).collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let top: Vec<serde_json::Value> = ranked
+        .iter()
+        .take(TOP_N)
+        .map(|(exit, count, country)| {
+            json!({
+                "exit": format!("{}…", &exit[..ID_PREFIX_LEN.min(exit.len())]),
+                "country": country,
+                "share": *count as f64 / streams as f64,
+            })
+        })
+        .collect();
+
+    json!({
+        "window_secs": WINDOW_MS / 1000,
+        "streams": streams,
+        "distinct_exits": per_exit.len(),
+        "entropy_bits": entropy,
+        "score": score,
+        "top_exits": top,
+        "restricted_fraction": if streams == 0 { 0.0 } else { restricted as f64 / streams as f64 },
+        "usable_exits": pool,
+        "pool_warning": pool_warning(&flags),
+        "retained": retention::retains(Sink::ExitHistory),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&SAMPLES);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn scores_of_synthetic_distributions() {
+        // (streams per exit, usable exits, entropy in bits, score)
+        for (counts, pool, entropy, expected) in [
+            (vec![], None, 0.0, None),
+            (vec![1], None, 0.0, None),
+            (vec![5], None, 0.0, Some(0.0)),
+            (vec![5], Some(1), 0.0, None),
+            (vec![1, 1, 1, 1], None, 2.0, Some(1.0)),
+            (vec![2, 2], None, 1.0, Some(0.5)),
+            (vec![2, 2], Some(2), 1.0, Some(1.0)),
+            (vec![3, 1], None, 0.811, Some(0.406)),
+            (vec![1; 8], Some(100), 3.0, Some(1.0)),
+            (vec![97, 1, 1, 1], Some(100), 0.242, Some(0.036)),
+            // A pool smaller than what was seen (an older consensus) still tops out at 1
+            (vec![1, 1, 1, 1], Some(2), 2.0, Some(1.0)),
+            (vec![7, 1], Some(0), 0.544, None),
+        ] {
+            let (bits, score) = score(&counts, pool);
+            assert!(close(bits, entropy), "{:?}: {} bits", counts, bits);
+            match (score, expected) {
+                (Some(score), Some(expected)) => assert!(close(score, expected), "{:?} {:?}: {}", counts, pool, score),
+                (score, expected) => assert_eq!(score, expected, "{:?} {:?}", counts, pool),
+            }
+        }
+    }
+
+    #[test]
+    fn spreading_streams_raises_the_score() {
+        // 12 streams moved one at a time from the busiest exit to the least busy
+        let mut counts = vec![12, 0, 0, 0];
+        let mut last = score(&counts, None).1.unwrap();
+        while counts.iter().max() != counts.iter().min() {
+            let busiest = (0..4).max_by_key(|i| counts[*i]).unwrap();
+            let quietest = (0..4).min_by_key(|i| counts[*i]).unwrap();
+            counts[busiest] -= 1;
+            counts[quietest] += 1;
+            let next = score(&counts, Some(4)).1.unwrap();
+            assert!(next > last, "{:?}: {} after {}", counts, next, last);
+            last = next;
+        }
+        assert!(close(last, 1.0));
+    }
+
+    #[test]
+    fn a_small_pool_is_flagged() {
+        let flags = ["Stable".to_string()];
+        for (exits, usable, warned) in [(1000, 900, false), (1000, 500, false), (1000, 499, true), (10, 0, true), (0, 0, false)] {
+            assert_eq!(low_pool_warning(&flags, exits, usable).is_some(), warned, "{} of {}", usable, exits);
+        }
+        let warning = low_pool_warning(&flags, 1000, 120).unwrap();
+        assert!(warning.contains("(Stable)") && warning.contains("120 of 1000"), "{}", warning);
+        assert_eq!(pool_warning(&[]), None);
+    }
+
+    #[test]
+    fn the_report_covers_the_last_hour() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let time = FakeTime::start();
+        let saved = std::mem::take(&mut *SAMPLES.lock().unwrap());
+        let exits = ["AAAAAA11", "BBBBBB22", "CCCCCC33", "DDDDDD44"];
+        let _index = geo::fake_index(&[
+            (exits[0], Some("de"), Some("Tor 0.4.8")),
+            (exits[1], Some("nl"), Some("Tor 0.4.8")),
+            (exits[2], Some("se"), Some("Tor 0.4.8")),
+            (exits[3], Some("us"), Some("Tor 0.4.8")),
+        ]);
+        let push = |exit: &str, restricted: bool| {
+            SAMPLES.lock().unwrap().push_back(Sample {
+                at_ms: monotonic_ms(),
+                exit: exit.to_string(),
+                country: geo::country_of(exit),
+                restricted,
+            });
+        };
+
+        // An hour-old stream, then six recent ones
+        push(exits[3], false);
+        time.advance(Duration::from_secs(30 * 60));
+        for exit in [exits[0], exits[0], exits[0], exits[1], exits[1], exits[2]] {
+            push(exit, exit == exits[2]);
+        }
+        let report = |advance_mins: u64| {
+            time.advance(Duration::from_secs(advance_mins * 60));
+            serde_json::from_str::<serde_json::Value>(&report_json()).unwrap()
+        };
+        assert_eq!(report(0)["streams"], 7);
+
+        let report = report(31);
+        assert_eq!((report["streams"].as_u64(), report["distinct_exits"].as_u64()), (Some(6), Some(3)));
+        assert_eq!(report["usable_exits"], 4);
+        let (entropy, score) = score(&[3, 2, 1], Some(4));
+        assert!(close(report["entropy_bits"].as_f64().unwrap(), entropy));
+        assert!(close(report["score"].as_f64().unwrap(), score.unwrap()));
+        let top: Vec<_> = report["top_exits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|exit| (exit["exit"].as_str().unwrap(), exit["country"].as_str().unwrap(), exit["share"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(top.iter().map(|(exit, country, _)| (*exit, *country)).collect::<Vec<_>>(), [
+            ("AAAAAA…", "de"),
+            ("BBBBBB…", "nl"),
+            ("CCCCCC…", "se"),
+        ]);
+        assert!(close(top[0].2, 0.5) && close(top[2].2, 1.0 / 6.0));
+        assert!(close(report["restricted_fraction"].as_f64().unwrap(), 1.0 / 6.0));
+        assert!(report["pool_warning"].is_null());
+        assert_eq!(report["retained"], true);
+
+        // The minimal retention level drops the window
+        config::set("metadata_retention", serde_json::json!(2)).unwrap();
+        let empty: serde_json::Value = serde_json::from_str(&report_json()).unwrap();
+        assert_eq!((empty["streams"].as_u64(), empty["restricted_fraction"].as_f64()), (Some(0), Some(0.0)));
+        assert!(empty["score"].is_null());
+        assert_eq!(empty["retained"], false);
+
+        *SAMPLES.lock().unwrap() = saved;
+    }
+}
@@ -0,0 +1,128 @@
+//! Android Doze
+//!
+//! In Doze, Android cuts the app off the network: sockets stay open but
+//! nothing moves, and relays close channels that went quiet on their side.
+//! After a long Doze the first connection would try circuits that are dead
+//! and only fail over once Arti notices. The app forwards the transitions:
+//!
+//! - `onDozeEnter` puts the client into soft dormant mode, like a low
+//!   battery (see battery.rs), so Arti doesn't try to build circuits or fetch
+//!   directory documents that can't get through;
+//! - `onDozeExit` wakes it (unless the battery is still low) and, if the
+//!   Doze lasted at least `STALE_AFTER_MS`, retires every circuit so new streams
+//!   build fresh ones through live channels. Waking resumes Arti's
+//!   preemptive circuits, so fresh ones are usually ready by the time the app
+//!   connects. A shorter Doze leaves circuits alone; an exit without a
+//!   recorded entry is treated as a long one.
+//!
+//! The Doze length is wall-clock time, since the monotonic clock stops while
+//! the device sleeps.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use arti_client::TorClient;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::notification::now_epoch_ms;
+
+/// Doze after which circuits are assumed dead
+const STALE_AFTER_MS: u64 = 60 * 1000;
+
+static DOZING: AtomicBool = AtomicBool::new(false);
+
+/// When the current (or last) Doze started
+static ENTERED_EPOCH_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the device is in Doze
+pub(crate) fn dozing() -> bool {
+    DOZING.load(Ordering::Acquire)
+}
+
+/// Record Doze entry; returns false if already dozing
+pub(crate) fn enter() -> bool {
+    if DOZING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    ENTERED_EPOCH_MS.store(now_epoch_ms(), Ordering::Relaxed);
+    true
+}
+
+/// Record Doze exit; returns how long the Doze lasted, or None if not dozing
+pub(crate) fn exit() -> Option<u64> {
+    if !DOZING.swap(false, Ordering::AcqRel) {
+        return None;
+    }
+    Some(now_epoch_ms().saturating_sub(ENTERED_EPOCH_MS.load(Ordering::Relaxed)))
+}
+
+/// Retire `client`'s circuits if a Doze of `dozed_ms` (None: unknown) left
+/// them stale; returns whether it did
+pub(crate) fn refresh_circuits(client: &TorClient<PreferredRuntime>, dozed_ms: Option<u64>) -> bool {
+    refresh_with(dozed_ms, || client.circmgr().retire_all_circs())
+}
+
+/// Run `retire` if a Doze of `dozed_ms` left the circuits stale
+fn refresh_with(dozed_ms: Option<u64>, retire: impl FnOnce()) -> bool {
+    if dozed_ms.is_some_and(|ms| ms < STALE_AFTER_MS) {
+        return false;
+    }
+    retire();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    /// Enter Doze, sleep `dozed` on the fake wall clock and exit; whether the
+    /// circuits were retired
+    fn doze_for(time: &FakeTime, dozed: Duration) -> bool {
+        assert!(enter());
+        assert!(dozing());
+        time.advance(dozed);
+        let dozed_ms = exit();
+        assert_eq!(dozed_ms, Some(dozed.as_millis() as u64));
+        assert!(!dozing());
+        let mut retired = false;
+        assert_eq!(refresh_with(dozed_ms, || retired = true), retired);
+        retired
+    }
+
+    #[test]
+    fn a_long_doze_retires_the_circuits() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        for (dozed, retired) in [
+            (Duration::ZERO, false),
+            (Duration::from_secs(30), false),
+            (Duration::from_millis(STALE_AFTER_MS - 1), false),
+            (Duration::from_millis(STALE_AFTER_MS), true),
+            (Duration::from_secs(8 * 60 * 60), true),
+        ] {
+            assert_eq!(doze_for(&time, dozed), retired, "{:?}", dozed);
+        }
+    }
+
+    #[test]
+    fn transitions_are_recorded_once() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+
+        // An exit without a recorded entry: the Doze length is unknown
+        assert_eq!(exit(), None);
+        let mut retired = false;
+        assert!(refresh_with(None, || retired = true));
+        assert!(retired);
+
+        // A repeated entry keeps the first start
+        assert!(enter());
+        time.advance(Duration::from_secs(50));
+        assert!(!enter());
+        time.advance(Duration::from_secs(20));
+        assert_eq!(exit(), Some(70_000));
+        assert_eq!(exit(), None);
+    }
+}
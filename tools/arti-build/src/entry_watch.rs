@@ -0,0 +1,278 @@
+//! Detection of an entry relay that silently black-holes traffic
+//!
+//! Some throttling middleboxes leave the connection to the guard (or bridge)
+//! standing but drop what goes through it. Arti can take a long time to give
+//! up on such an entry, while every user connection times out. So the
+//! wrapper watches connect outcomes on a client whose bootstrap says it is
+//! ready for traffic:
+//!
+//! - `THRESHOLD` consecutive timeout-class connect failures within `WINDOW_MS`
+//!   make the entry suspect, provided they span at least `MIN_DESTINATIONS`
+//!   distinct destinations: one unreachable site must not look like a dead
+//!   entry. Any other outcome (a success, or an error the exit reported)
+//!   breaks the run.
+//! - On suspicion, `ENTRY_SUSPECTED_BLOCKED` is logged with the counts and a
+//!   failure record of kind `entry_suspected_blocked` is written. The entry
+//!   that carried the last successful circuit is reported to Arti's guard
+//!   manager as failed, so new circuits avoid it (another guard, or another
+//!   configured bridge), and every circuit is retired.
+//! - The detector then cools down for `COOLDOWN_MS` before it can act again.
+//!
+//! `Detector` holds the decision logic and is pure (time is passed in); the
+//! functions below it feed it and act on the client. Its state is owned by
+//! the bookkeeping actor (see actor.rs), which also acts, so connection tasks
+//! never wait on it. Destinations are kept as hashes only.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use arti_client::{ErrorKind, TorClient};
+use tor_guardmgr::ExternalActivity;
+use tor_linkspec::OwnedChanTarget;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::actor;
+use crate::backoff::Cooldown;
+use crate::diagnostics;
+use crate::liveness::monotonic_ms;
+
+/// Consecutive timeouts that make the entry suspect
+const THRESHOLD: usize = 6;
+
+/// Span within which those timeouts must fall
+const WINDOW_MS: u64 = 60_000;
+
+/// Distinct destinations the timeouts must cover
+const MIN_DESTINATIONS: usize = 3;
+
+/// Quiet period after acting
+const COOLDOWN_MS: u64 = 5 * 60 * 1000;
+
+/// What the detector saw when it decided
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Suspicion {
+    pub failures: usize,
+    pub destinations: usize,
+}
+
+pub(crate) struct Detector {
+    /// Current run of timeouts: (monotonic ms, destination hash)
+    run: VecDeque<(u64, u64)>,
+    cooldown: Cooldown,
+}
+
+impl Detector {
+    pub(crate) const fn new() -> Self {
+        Detector { run: VecDeque::new(), cooldown: Cooldown::new(COOLDOWN_MS) }
+    }
+
+    /// A connect succeeded or failed for a reason other than a timeout
+    pub(crate) fn break_run(&mut self) {
+        self.run.clear();
+    }
+
+    /// A connect to `destination` timed out at `now_ms`
+    pub(crate) fn timeout(&mut self, now_ms: u64, destination: u64) -> Option<Suspicion> {
+        while self.run.front().is_some_and(|(at, _)| at + WINDOW_MS < now_ms) {
+            self.run.pop_front();
+        }
+        self.run.push_back((now_ms, destination));
+        // Older entries can't change the decision once the run is this long
+        while self.run.len() > 4 * THRESHOLD {
+            self.run.pop_front();
+        }
+
+        if self.cooldown.active(now_ms) || self.run.len() < THRESHOLD {
+            return None;
+        }
+        let destinations = self.run.iter().map(|(_, d)| d).collect::<HashSet<_>>().len();
+        if destinations < MIN_DESTINATIONS {
+            return None;
+        }
+
+        let suspicion = Suspicion { failures: self.run.len(), destinations };
+        self.run.clear();
+        self.cooldown.trip(now_ms);
+        Some(suspicion)
+    }
+}
+
+/// The detector and the entry it would retire (owned by the actor)
+pub(crate) struct Tracker {
+    detector: Detector,
+    /// Entry of the last circuit a stream was attached to
+    last_entry: Option<OwnedChanTarget>,
+}
+
+impl Tracker {
+    pub(crate) const fn new() -> Self {
+        Tracker { detector: Detector::new(), last_entry: None }
+    }
+}
+
+/// Times the detector acted since process start
+static SUSPICIONS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn suspicions() -> u64 {
+    SUSPICIONS.load(Ordering::Relaxed)
+}
+
+/// Errors a black-holing entry produces (the exit never answered in time)
+fn is_timeout(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::TorNetworkTimeout | ErrorKind::ExitTimeout)
+}
+
+fn destination_hash(destination: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    destination.to_ascii_lowercase().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Record a successful connect through `entry`
+pub(crate) fn connected(entry: Option<OwnedChanTarget>) {
+    actor::post(move |state| {
+        state.entry.detector.break_run();
+        if entry.is_some() {
+            state.entry.last_entry = entry;
+        }
+    });
+}
+
+/// Record a failed connect to `destination`, acting on the entry if it looks blocked
+pub(crate) fn failed(client: &TorClient<PreferredRuntime>, kind: ErrorKind, destination: &str) {
+    if !is_timeout(kind) {
+        actor::post(|state| state.entry.detector.break_run());
+        return;
+    }
+    // While bootstrapping, timeouts are expected and reported elsewhere
+    if !client.bootstrap_status().ready_for_traffic() {
+        return;
+    }
+    let now_ms = monotonic_ms();
+    let destination = destination_hash(destination);
+    let client = client.clone();
+    actor::post(move |state| {
+        if let Some(suspicion) = state.entry.detector.timeout(now_ms, destination) {
+            act(&client, state.entry.last_entry.take(), suspicion);
+        }
+    });
+}
+
+fn act(client: &TorClient<PreferredRuntime>, last_entry: Option<OwnedChanTarget>, suspicion: Suspicion) {
+    SUSPICIONS.fetch_add(1, Ordering::Relaxed);
+    let message = format!(
+        "{} consecutive connect timeouts across {} destinations while ready for traffic; retiring the entry and all circuits",
+        suspicion.failures, suspicion.destinations
+    );
+    log_error!("ENTRY_SUSPECTED_BLOCKED: {}", message);
+    diagnostics::record_failure("entry_suspected_blocked", message);
+
+    let circmgr = client.circmgr();
+    if let Some(entry) = last_entry {
+        // The only kind of outside failure Arti's guard manager takes; it
+        // makes the entry unusable for new circuits until retried
+        circmgr.note_external_failure(&entry, ExternalActivity::DirCache);
+    }
+    circmgr.retire_all_circs();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Event {
+        /// A timeout at ms to a destination
+        Timeout(u64, u64),
+        /// A success or another kind of failure
+        Break,
+    }
+    use Event::*;
+
+    /// Timeouts every second from `start_ms`, cycling through `destinations`
+    fn timeouts(start_ms: u64, count: u64, destinations: &[u64]) -> Vec<Event> {
+        (0..count).map(|i| Timeout(start_ms + i * 1000, destinations[i as usize % destinations.len()])).collect()
+    }
+
+    fn concat(parts: &[Vec<Event>]) -> Vec<Event> {
+        parts.concat()
+    }
+
+    /// Indices of the events that raised a suspicion, with what it saw
+    fn run(events: &[Event]) -> Vec<(usize, usize, usize)> {
+        let mut detector = Detector::new();
+        let mut raised = Vec::new();
+        for (i, event) in events.iter().enumerate() {
+            match *event {
+                Timeout(at, destination) => {
+                    if let Some(s) = detector.timeout(at, destination) {
+                        raised.push((i, s.failures, s.destinations));
+                    }
+                }
+                Break => detector.break_run(),
+            }
+        }
+        raised
+    }
+
+    #[test]
+    fn suspicion_needs_a_run_across_destinations_within_the_window() {
+        let after_cooldown = 5000 + COOLDOWN_MS;
+        for (name, events, expected) in [
+            ("six timeouts across three destinations", timeouts(0, 6, &[1, 2, 3]), vec![(5, 6, 3)]),
+            ("five are not enough", timeouts(0, 5, &[1, 2, 3]), vec![]),
+            ("one unreachable destination", timeouts(0, 20, &[1]), vec![]),
+            ("two unreachable destinations", timeouts(0, 20, &[1, 2]), vec![]),
+            ("the third destination completes a longer run", timeouts(0, 8, &[1, 1, 1, 1, 1, 1, 2, 3]), vec![(7, 8, 3)]),
+            ("a success breaks the run", concat(&[timeouts(0, 5, &[1, 2, 3]), vec![Break], timeouts(6000, 1, &[3])]), vec![]),
+            (
+                "a run after the break counts afresh",
+                concat(&[timeouts(0, 5, &[1, 2, 3]), vec![Break], timeouts(6000, 6, &[3, 2, 1])]),
+                vec![(11, 6, 3)],
+            ),
+            (
+                "timeouts older than the window drop out",
+                concat(&[timeouts(0, 5, &[1, 2, 3]), timeouts(5000 + WINDOW_MS, 5, &[1, 2, 3])]),
+                vec![],
+            ),
+            (
+                "the window spans exactly WINDOW_MS",
+                concat(&[timeouts(0, 1, &[1]), timeouts(WINDOW_MS - 4000, 5, &[2, 3])]),
+                vec![(5, 6, 3)],
+            ),
+            (
+                "quiet during the cooldown, then acting again",
+                concat(&[
+                    timeouts(0, 6, &[1, 2, 3]),
+                    timeouts(10_000, 12, &[1, 2, 3]),
+                    timeouts(after_cooldown, 6, &[4, 5, 6]),
+                ]),
+                vec![(5, 6, 3), (23, 6, 3)],
+            ),
+        ] {
+            assert_eq!(run(&events), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn only_timeouts_count_against_the_entry() {
+        for (kind, counts) in [
+            (ErrorKind::TorNetworkTimeout, true),
+            (ErrorKind::ExitTimeout, true),
+            (ErrorKind::RemoteConnectionRefused, false),
+            (ErrorKind::RemoteHostNotFound, false),
+            (ErrorKind::TorAccessFailed, false),
+            (ErrorKind::Other, false),
+        ] {
+            assert_eq!(is_timeout(kind), counts, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn destinations_are_told_apart_by_name_only() {
+        assert_eq!(destination_hash("Example.COM:443"), destination_hash("example.com:443"));
+        assert_ne!(destination_hash("example.com:443"), destination_hash("example.org:443"));
+    }
+}
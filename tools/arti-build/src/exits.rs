@@ -0,0 +1,248 @@
+//! Consensus flags required of exit relays
+//!
+//! `setRequiredExitFlags` lets reliability-sensitive apps ask for exits with
+//! certain flags. Arti has no per-flag exit filter, so the flags are enforced
+//! in two places:
+//!
+//! - path selection, where Arti offers it: Arti only builds through Fast
+//!   relays, and requires a Stable exit for streams to its long-lived ports,
+//!   so with `Stable` the ports the app declared with `setTargetPortHints`
+//!   are added to Arti's own long-lived ports;
+//! - an exit check on every CONNECT: once the stream is open, its exit is
+//!   looked up in the consensus index (geo.rs). An exit lacking a required
+//!   flag bumps the exit generation, which is part of the isolation key, so
+//!   neither the retry nor any later stream reuses the circuits built so
+//!   far; the connect is retried up to `MAX_ATTEMPTS` times and then refused
+//!   with "not allowed by ruleset". Exits the index doesn't list pass, as
+//!   there is nothing to check them against.
+//!
+//! `getEffectiveConfig` reports which of the two enforce each flag. Other
+//! flags are refused rather than silently ignored. The setting applies on
+//! the next initialize.
+//!
+//! `setPreferFastExits` biases circuits toward faster relays. Arti already
+//! weights exits by consensus bandwidth and has no option to reweight them;
+//! what it does expose is the quantile of its learned circuit build times
+//! past which a build is abandoned (the `cbtquantile` network parameter,
+//! 80 by default). With the preference set it is lowered to
+//! `FAST_EXITS_TIMEOUT_QUANTILE`: builds slower than most are given up and
+//! retried on other relays, so the circuits that get used lean toward fast
+//! exits (and fast relays generally). The cost is exit diversity: relays that
+//! are slow to build through carry fewer circuits. It only bites once Arti has
+//! observed enough builds to estimate the timeout.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use arti_client::config::TorClientConfigBuilder;
+use serde_json::{json, Map, Value};
+
+use crate::{config, geo};
+
+/// Flags that can be required, in their consensus spelling
+const SUPPORTED: &[&str] = &["Fast", "Stable"];
+
+/// Arti's default long-lived ports (tor-circmgr's `PathConfig`), kept when
+/// ours are added since setting the list replaces them
+const ARTI_LONG_LIVED_PORTS: &[u16] = &[21, 22, 706, 1863, 5050, 5190, 5222, 5223, 6523, 6667, 6697, 8300];
+
+/// Connects tried for one CONNECT before it is refused for its exits
+pub(crate) const MAX_ATTEMPTS: u32 = 3;
+
+/// Bumped whenever an exit is found lacking a required flag
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Network parameter holding the circuit build timeout quantile
+const TIMEOUT_QUANTILE_PARAM: &str = "cbtquantile";
+
+/// Build timeout quantile with fast exits preferred (Arti's default is 80)
+pub(crate) const FAST_EXITS_TIMEOUT_QUANTILE: i32 = 60;
+
+/// The consensus spelling of `name`, if it can be required
+pub(crate) fn canonical(name: &str) -> Option<&'static str> {
+    SUPPORTED.iter().copied().find(|flag| flag.eq_ignore_ascii_case(name))
+}
+
+/// Check a `required_exit_flags` setting value: supported flag names
+pub(crate) fn check(value: &Value) -> Result<(), String> {
+    let names = value.as_array().ok_or("expected an array of flag names")?;
+    for name in names {
+        let name = name.as_str().ok_or("expected an array of flag names")?;
+        if canonical(name) != Some(name) {
+            return Err(format!("{} can't be required of exits (supported: {})", name, SUPPORTED.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+/// Long-lived ports to configure for the required `flags` and the app's
+/// `hinted` target ports, if they differ from Arti's defaults
+pub(crate) fn long_lived_ports(flags: &[String], hinted: &[u16]) -> Option<Vec<u16>> {
+    if !flags.iter().any(|flag| flag == "Stable") {
+        return None;
+    }
+    let mut ports = ARTI_LONG_LIVED_PORTS.to_vec();
+    ports.extend(hinted.iter().filter(|port| !ARTI_LONG_LIVED_PORTS.contains(port)));
+    (ports.len() > ARTI_LONG_LIVED_PORTS.len()).then_some(ports)
+}
+
+/// Apply the required `flags` (checked with `check`) to a client config
+pub(crate) fn apply(builder: &mut TorClientConfigBuilder, flags: &[String], hinted: &[u16]) {
+    if let Some(ports) = long_lived_ports(flags, hinted) {
+        builder.path_rules().set_long_lived_ports(ports);
+    }
+}
+
+/// How each of the required `flags` is enforced, as `getEffectiveConfig`
+/// reports it, or None if nothing is required
+pub(crate) fn effect(flags: &[String], hinted: &[u16]) -> Option<Value> {
+    let mut out = Map::new();
+    for flag in flags {
+        let by_path = match flag.as_str() {
+            "Fast" => true,
+            _ => long_lived_ports(flags, hinted).is_some(),
+        };
+        let mut how = vec!["exit_check"];
+        if by_path {
+            how.insert(0, "path_selection");
+        }
+        out.insert(flag.clone(), json!(how));
+    }
+    (!out.is_empty()).then_some(Value::Object(out))
+}
+
+/// Current exit generation, part of every isolation key
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// The first required flag the consensus says `exit` lacks
+pub(crate) fn missing_flag(exit: &str) -> Option<&'static str> {
+    let required = config::strings(&config::applied("required_exit_flags"));
+    required
+        .iter()
+        .filter_map(|name| canonical(name))
+        .find(|flag| geo::exit_carries(exit, flag) == Some(false))
+}
+
+/// Stop reusing the circuits built so far, as one exits at `exit`
+pub(crate) fn reject(exit: &str, flag: &str) {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    log_info!("Exit {} lacks the required {} flag; retrying on a fresh circuit", exit, flag);
+}
+
+/// Network parameters to override for `prefer_fast_exits`
+pub(crate) fn net_params(prefer_fast: bool) -> Vec<(&'static str, i32)> {
+    if prefer_fast {
+        vec![(TIMEOUT_QUANTILE_PARAM, FAST_EXITS_TIMEOUT_QUANTILE)]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Apply the fast exit preference to a client config
+pub(crate) fn apply_prefer_fast(builder: &mut TorClientConfigBuilder, prefer_fast: bool) {
+    for (name, value) in net_params(prefer_fast) {
+        builder.override_net_params().insert(name.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config;
+    use crate::testing::{self, TempDir};
+
+    fn flags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn only_supported_flags_in_their_spelling_are_accepted() {
+        assert!(check(&json!(["Fast", "Stable"])).is_ok());
+        assert!(check(&json!([])).is_ok());
+        for invalid in [json!(["stable"]), json!(["Guard"]), json!(["Exit"]), json!("Stable"), json!([1])] {
+            assert!(check(&invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(canonical("sTaBlE"), Some("Stable"));
+        assert_eq!(canonical("Valid"), None);
+    }
+
+    #[test]
+    fn stable_covers_the_hinted_ports_only() {
+        let arti = ARTI_LONG_LIVED_PORTS.to_vec();
+        let with = |extra: &[u16]| [arti.as_slice(), extra].concat();
+        for (required, hinted, expected) in [
+            (flags(&[]), vec![443], None),
+            (flags(&["Fast"]), vec![443], None),
+            // Nothing named: Arti's defaults stay as they are
+            (flags(&["Stable"]), vec![], None),
+            (flags(&["Stable"]), vec![22, 5222], None),
+            (flags(&["Stable", "Fast"]), vec![443], Some(with(&[443]))),
+            (flags(&["Stable"]), vec![5222, 443, 8443], Some(with(&[443, 8443]))),
+        ] {
+            assert_eq!(long_lived_ports(&required, &hinted), expected, "{:?} with hints {:?}", required, hinted);
+        }
+        // Never every port
+        let ports = long_lived_ports(&flags(&["Stable"]), &[443]).unwrap();
+        assert!(!ports.contains(&80) && ports.len() < 100);
+    }
+
+    #[test]
+    fn effective_config_says_how_each_flag_is_enforced() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let effect = || {
+            let effective: Value = serde_json::from_str(&config::effective_json()).unwrap();
+            effective["required_exit_flags"]["effect"].clone()
+        };
+
+        assert_eq!(effect(), Value::Null);
+        config::set("required_exit_flags", json!(["Fast"])).unwrap();
+        // Not before the next initialize
+        assert_eq!(effect(), Value::Null);
+        let both = json!(["path_selection", "exit_check"]);
+        for (required, hinted, expected) in [
+            (json!(["Fast"]), json!([]), json!({"Fast": both})),
+            (json!(["Stable"]), json!([]), json!({"Stable": ["exit_check"]})),
+            // Already among Arti's long-lived ports
+            (json!(["Stable"]), json!([22]), json!({"Stable": ["exit_check"]})),
+            (json!(["Stable", "Fast"]), json!([443]), json!({"Stable": both, "Fast": both})),
+        ] {
+            config::set("required_exit_flags", required.clone()).unwrap();
+            config::set("target_port_hints", hinted.clone()).unwrap();
+            config::commit_pending();
+            assert_eq!(effect(), expected, "{} with hints {}", required, hinted);
+        }
+    }
+
+    #[test]
+    fn prefer_fast_exits_lowers_the_build_timeout_quantile() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let temp = TempDir::new();
+        let section = || {
+            let exported = crate::arti_config::render(true);
+            exported.split("\n\n").find(|section| section.starts_with("[override_net_params]")).map(str::to_string)
+        };
+
+        assert_eq!(net_params(false), []);
+        assert_eq!(net_params(true), [("cbtquantile", FAST_EXITS_TIMEOUT_QUANTILE)]);
+        crate::StageSettings::applied().client_config(temp.path(), &[]).unwrap();
+        assert_eq!(section(), None);
+
+        config::set("prefer_fast_exits", json!(true)).unwrap();
+        // Not before the next initialize
+        assert_eq!(section(), None);
+        config::commit_pending();
+        let settings = crate::StageSettings::applied();
+        assert!(settings.prefer_fast_exits);
+        let mut builder = TorClientConfigBuilder::from_directories(temp.path().join("state"), temp.path().join("cache"));
+        apply_prefer_fast(&mut builder, settings.prefer_fast_exits);
+        assert_eq!(builder.override_net_params().get("cbtquantile"), Some(&FAST_EXITS_TIMEOUT_QUANTILE));
+        builder.build().unwrap();
+        settings.client_config(temp.path(), &[]).unwrap();
+        assert_eq!(section().as_deref(), Some("[override_net_params]\ncbtquantile = 60"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Optional capabilities compiled into the library
+//!
+//! Each optional capability is a cargo feature of the wrapper that forwards
+//! the Arti features it needs (see Cargo.toml), so a build without it carries
+//! neither the wrapper code nor Arti's. `FEATURES` is the one list of them:
+//! `getLinkedFeatures` reports it, and `build-arti.sh --check-features`
+//! checks every combination of the same cargo features compiles.
+//!
+//! The JNI functions of a disabled capability stay exported so the Kotlin
+//! side links the same symbols against every build. They do nothing and
+//! return `ERR_FEATURE_DISABLED` (functions returning a status) or a
+//! `feature_disabled` error (functions returning JSON); settings that only a
+//! disabled capability reads are refused by their checks.
+//!
+//! Builds may also use the capability names of `ALIASES`, each enabling the
+//! features it stands for, and the `RESERVED` names of capabilities the
+//! library doesn't have, which are accepted and gate nothing. Neither is
+//! reported separately; the script checks each builds on its own.
+
+/// Optional capabilities: (name in `getLinkedFeatures`, cargo feature, compiled in)
+pub(crate) const FEATURES: &[(&str, &str, bool)] = &[
+    ("bridges", "bridges", cfg!(feature = "bridges")),
+    ("pluggable_transports", "pluggable-transports", cfg!(feature = "pluggable-transports")),
+    ("onion_service_client", "onion-service-client", cfg!(feature = "onion-service-client")),
+    ("onion_service_hosting", "onion-service-hosting", cfg!(feature = "onion-service-hosting")),
+    ("vanguards", "vanguards", cfg!(feature = "vanguards")),
+    ("metrics_endpoint", "metrics-endpoint", cfg!(feature = "metrics-endpoint")),
+];
+
+/// Capability names standing for features of `FEATURES`: (cargo feature,
+/// the features it enables)
+pub(crate) const ALIASES: &[(&str, &[&str])] = &[
+    ("onion-services", &["onion-service-client", "onion-service-hosting"]),
+    ("pt-transports", &["pluggable-transports"]),
+    ("circumvention-api", &["bridges"]),
+];
+
+/// Cargo features naming capabilities the library doesn't have (a DNS port,
+/// an HTTP proxy, a control port shim); they gate nothing, so only the
+/// script and the tests read them
+#[cfg(test)]
+pub(crate) const RESERVED: &[&str] = &[
+    "dns-port",
+    "http-proxy",
+    "control-shim",
+];
+
+/// Whether cargo feature `feature` is compiled in; an alias is if all it
+/// stands for are
+pub(crate) fn enabled(feature: &str) -> bool {
+    if let Some((_, features)) = ALIASES.iter().find(|(alias, _)| *alias == feature) {
+        return features.iter().all(|feature| enabled(feature));
+    }
+    FEATURES.iter().any(|(_, name, on)| *name == feature && *on)
+}
+
+/// Check that `feature` is compiled in before `function` acts, logging it if not
+pub(crate) fn require(feature: &str, function: &str) -> bool {
+    if enabled(feature) {
+        return true;
+    }
+    log_error!("{} needs the `{}` feature, which this build lacks", function, feature);
+    false
+}
+
+/// The compiled-in capabilities as a JSON object of booleans
+pub(crate) fn linked_json() -> String {
+    let fields: Vec<String> = FEATURES.iter().map(|(name, _, on)| format!("\"{}\":{}", name, on)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cargo features compiled into this build, listed independently of `FEATURES`
+    const BUILT: &[&str] = &[
+        #[cfg(feature = "bridges")]
+        "bridges",
+        #[cfg(feature = "pluggable-transports")]
+        "pluggable-transports",
+        #[cfg(feature = "onion-service-client")]
+        "onion-service-client",
+        #[cfg(feature = "onion-service-hosting")]
+        "onion-service-hosting",
+        #[cfg(feature = "vanguards")]
+        "vanguards",
+        #[cfg(feature = "metrics-endpoint")]
+        "metrics-endpoint",
+    ];
+
+    /// Aliases compiled into this build
+    const BUILT_ALIASES: &[&str] = &[
+        #[cfg(feature = "onion-services")]
+        "onion-services",
+        #[cfg(feature = "pt-transports")]
+        "pt-transports",
+        #[cfg(feature = "circumvention-api")]
+        "circumvention-api",
+    ];
+
+    /// The feature names listed in `const <name>` of this file, as the
+    /// script extracts them: the first quoted name of each entry line
+    fn listed_in(source: &str, name: &str) -> Vec<String> {
+        let start = source.find(&format!("pub(crate) const {}: ", name)).unwrap();
+        let block = &source[start..];
+        block[..block.find("\n];").unwrap()]
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.strip_prefix("    (\"").or_else(|| line.strip_prefix("    \"")))
+            .map(|rest| rest.split('"').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn flags_match_the_build_features() {
+        let linked: serde_json::Value = serde_json::from_str(&linked_json()).unwrap();
+        assert_eq!(linked.as_object().unwrap().len(), FEATURES.len());
+        for (name, feature, on) in FEATURES {
+            assert_eq!(*on, BUILT.contains(feature), "{}", feature);
+            assert_eq!(enabled(feature), *on, "{}", feature);
+            assert_eq!(linked[name], *on, "{}", name);
+        }
+        // Pluggable transports are bridges (Cargo.toml)
+        assert!(!enabled("pluggable-transports") || enabled("bridges"));
+        assert!(!enabled("no-such-feature"));
+        for (alias, features) in ALIASES {
+            if BUILT_ALIASES.contains(alias) {
+                assert!(features.iter().all(|feature| enabled(feature)), "{}", alias);
+            }
+            assert_eq!(enabled(alias), features.iter().all(|feature| BUILT.contains(feature)), "{}", alias);
+            // Aliases and reserved names are not reported
+            assert!(linked[alias.replace('-', "_")].is_null(), "{}", alias);
+        }
+        for reserved in RESERVED {
+            assert!(!enabled(reserved), "{}", reserved);
+            assert!(linked[reserved.replace('-', "_")].is_null(), "{}", reserved);
+        }
+    }
+
+    #[test]
+    fn the_feature_check_sees_every_feature() {
+        // What `build-arti.sh --check-features` extracts from this file, in order
+        let marker = concat!("cfg", "!(feature = \"");
+        let source = include_str!("features.rs");
+        let extracted: Vec<&str> =
+            source.split(marker).skip(1).map(|rest| rest.split('"').next().unwrap()).collect();
+        let listed: Vec<&str> = FEATURES.iter().map(|(_, feature, _)| *feature).collect();
+        assert_eq!(extracted, listed);
+
+        // ...and requires Cargo.toml to declare
+        let manifest = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+        for feature in listed {
+            assert!(manifest.lines().any(|line| line.starts_with(&format!("{} = ", feature))), "{}", feature);
+        }
+
+        // Aliases and reserved names, each checked on its own, must be
+        // declared as exactly what they stand for
+        let aliases: Vec<&str> = ALIASES.iter().map(|(alias, _)| *alias).collect();
+        assert_eq!(listed_in(source, "ALIASES"), aliases);
+        assert_eq!(listed_in(source, "RESERVED"), RESERVED);
+        let declared = |feature: &str| manifest.lines().find_map(|line| line.strip_prefix(&format!("{} = ", feature)));
+        for (alias, features) in ALIASES {
+            let quoted: Vec<String> = features.iter().map(|feature| format!("\"{}\"", feature)).collect();
+            assert_eq!(declared(alias), Some(format!("[{}]", quoted.join(", ")).as_str()), "{}", alias);
+        }
+        for reserved in RESERVED {
+            assert_eq!(declared(reserved), Some("[]"), "{}", reserved);
+        }
+    }
+
+    #[test]
+    fn gated_functions_refuse_without_their_feature() {
+        let _serial = crate::testing::serial();
+        crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+        for (_, feature, on) in FEATURES {
+            assert_eq!(require(feature, "gatedFunction"), *on, "{}", feature);
+        }
+        let refused = crate::tests::SENT_LOG_LINES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains("gatedFunction needs the"))
+            .count();
+        assert_eq!(refused, FEATURES.iter().filter(|(_, _, on)| !on).count());
+    }
+}
@@ -0,0 +1,318 @@
+//! Flow stalls per connection, attributed to the side that stopped consuming
+//!
+//! "Downloads stall at 99%" can't be triaged without knowing who stopped
+//! taking data. Each forwarding direction writes to one side, so a write that
+//! waits with data in hand blames that side:
+//!
+//! - `client`: Tor->client writes blocked, the local app isn't reading;
+//! - `tor`: client->Tor writes blocked, the Tor stream has no window left
+//!   (circuit flow control ran dry, or the exit can't pass data on to the
+//!   server).
+//!
+//! A period counts as a stall once it lasts `MIN_STALL_MS`. A destination
+//! that simply sends nothing is not a stall: there is no data waiting.
+//!
+//! Writers record only transitions, like `stall::StallWriter`: the first
+//! `Pending` stamps the start and the next `Ready` closes the period, so a
+//! write that never blocks costs one atomic load. Per-connection figures are
+//! in `listConnections` and the connection-closed log line, totals by side in
+//! `getMetrics`.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use serde_json::json;
+use tokio::io::AsyncWrite;
+
+use crate::liveness::monotonic_ms;
+
+/// Blocked time from which a period counts as a stall
+pub(crate) const MIN_STALL_MS: u64 = 2000;
+
+/// Side a blocked write was waiting on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client = 0,
+    Tor = 1,
+}
+
+const SIDES: [Side; 2] = [Side::Client, Side::Tor];
+
+impl Side {
+    fn as_str(self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Tor => "tor",
+        }
+    }
+}
+
+/// Process-wide stalls and stall time by side
+static STALLS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+static STALL_MS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+
+/// One connection's stall record
+#[derive(Default)]
+pub(crate) struct Record {
+    /// Monotonic ms the current blocked write started, per side (0: none)
+    blocked_since_ms: [AtomicU64; 2],
+    stalls: [AtomicU64; 2],
+    stall_ms: [AtomicU64; 2],
+}
+
+impl Record {
+    fn blocked(&self, side: Side) {
+        let _ = self.blocked_since_ms[side as usize].compare_exchange(0, monotonic_ms(), Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    fn progressed(&self, side: Side) {
+        let since = self.blocked_since_ms[side as usize].swap(0, Ordering::Relaxed);
+        if since == 0 {
+            return;
+        }
+        let ms = monotonic_ms().saturating_sub(since);
+        if ms >= MIN_STALL_MS {
+            self.stalls[side as usize].fetch_add(1, Ordering::Relaxed);
+            self.stall_ms[side as usize].fetch_add(ms, Ordering::Relaxed);
+            STALLS[side as usize].fetch_add(1, Ordering::Relaxed);
+            STALL_MS[side as usize].fetch_add(ms, Ordering::Relaxed);
+        }
+    }
+
+    /// Stalls and stall time for `side`, counting one in progress
+    fn totals(&self, side: Side, now: u64) -> (u64, u64) {
+        let mut stalls = self.stalls[side as usize].load(Ordering::Relaxed);
+        let mut ms = self.stall_ms[side as usize].load(Ordering::Relaxed);
+        let since = self.blocked_since_ms[side as usize].load(Ordering::Relaxed);
+        if since != 0 && now.saturating_sub(since) >= MIN_STALL_MS {
+            stalls += 1;
+            ms += now.saturating_sub(since);
+        }
+        (stalls, ms)
+    }
+
+    /// The side a write is stalled on right now, if any
+    fn stalled_now(&self, now: u64) -> Option<Side> {
+        SIDES.into_iter().find(|side| {
+            let since = self.blocked_since_ms[*side as usize].load(Ordering::Relaxed);
+            since != 0 && now.saturating_sub(since) >= MIN_STALL_MS
+        })
+    }
+
+    /// Per-connection fields as JSON
+    pub(crate) fn json(&self) -> serde_json::Value {
+        let now = monotonic_ms();
+        let (client_stalls, client_ms) = self.totals(Side::Client, now);
+        let (tor_stalls, tor_ms) = self.totals(Side::Tor, now);
+        json!({
+            "client_stalls": client_stalls,
+            "client_stall_ms": client_ms,
+            "tor_stalls": tor_stalls,
+            "tor_stall_ms": tor_ms,
+            "stalled_on": self.stalled_now(now).map(Side::as_str),
+        })
+    }
+
+    /// Summary for the connection-closed log line (empty without stalls)
+    pub(crate) fn summary(&self) -> String {
+        let now = monotonic_ms();
+        let parts: Vec<String> = SIDES
+            .into_iter()
+            .filter_map(|side| {
+                let (stalls, ms) = self.totals(side, now);
+                (stalls > 0).then(|| format!("{} {}x/{} ms", side.as_str(), stalls, ms))
+            })
+            .collect();
+        if parts.is_empty() {
+            String::new()
+        } else {
+            format!(" [stalls: {}]", parts.join(", "))
+        }
+    }
+}
+
+/// Stall tracking for one connection
+#[derive(Clone, Default)]
+pub(crate) struct Tracker(Arc<Record>);
+
+impl Tracker {
+    pub(crate) fn new() -> Self {
+        Tracker::default()
+    }
+
+    pub(crate) fn record(&self) -> Arc<Record> {
+        Arc::clone(&self.0)
+    }
+
+    /// Wrap the writer toward `side`
+    pub(crate) fn writer<W>(&self, side: Side, inner: W) -> FlowWriter<W> {
+        FlowWriter { inner, side, record: Arc::clone(&self.0) }
+    }
+}
+
+/// Writer adapter recording blocked periods toward one side
+pub(crate) struct FlowWriter<W> {
+    inner: W,
+    side: Side,
+    record: Arc<Record>,
+}
+
+impl<W> FlowWriter<W> {
+    fn track<T>(&self, poll: Poll<T>) -> Poll<T> {
+        match poll {
+            Poll::Pending => self.record.blocked(self.side),
+            Poll::Ready(_) => self.record.progressed(self.side),
+        }
+        poll
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FlowWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track(poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Process-wide totals by side, as `getMetrics` fields
+pub(crate) fn metrics_json() -> String {
+    format!(
+        "\"client_stalls\":{},\"client_stall_ms\":{},\"tor_stalls\":{},\"tor_stall_ms\":{}",
+        STALLS[Side::Client as usize].load(Ordering::Relaxed),
+        STALL_MS[Side::Client as usize].load(Ordering::Relaxed),
+        STALLS[Side::Tor as usize].load(Ordering::Relaxed),
+        STALL_MS[Side::Tor as usize].load(Ordering::Relaxed),
+    )
+}
+
+/// Zero the process-wide totals (metrics reset)
+pub(crate) fn reset() {
+    for counter in STALLS.iter().chain(STALL_MS.iter()) {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    /// Block a write toward `side` on a full in-memory stream for `blocked`
+    /// (fake time), then let the reader take the data so the write completes
+    async fn block_for(tracker: &Tracker, side: Side, time: &FakeTime, blocked: Duration) {
+        let (stream, mut reader) = tokio::io::duplex(64);
+        let mut writer = tracker.writer(side, stream);
+        writer.write_all(&[0; 64]).await.unwrap();
+        let pending = tokio::spawn(async move {
+            writer.write_all(&[0; 32]).await.unwrap();
+            writer
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!pending.is_finished());
+        time.advance(blocked);
+        drain(&mut reader, 96).await;
+        let _writer = pending.await.unwrap();
+    }
+
+    async fn drain(reader: &mut DuplexStream, bytes: usize) {
+        let mut buf = vec![0; bytes];
+        reader.read_exact(&mut buf).await.unwrap();
+    }
+
+    fn totals(record: &Record, side: Side) -> (u64, u64) {
+        record.totals(side, monotonic_ms())
+    }
+
+    #[test]
+    fn each_stalled_side_is_blamed() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        testing::runtime().block_on(async {
+            for side in SIDES {
+                let tracker = Tracker::new();
+                let record = tracker.record();
+                block_for(&tracker, side, &time, Duration::from_secs(3)).await;
+                let other = if side == Side::Client { Side::Tor } else { Side::Client };
+                assert_eq!(totals(&record, side), (1, 3000), "{:?}", side);
+                assert_eq!(totals(&record, other), (0, 0), "{:?}", side);
+                assert_eq!(record.summary(), format!(" [stalls: {} 1x/3000 ms]", side.as_str()));
+                assert_eq!(record.json()["stalled_on"], serde_json::Value::Null);
+            }
+        });
+    }
+
+    #[test]
+    fn short_waits_and_idle_sides_are_not_stalls() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        testing::runtime().block_on(async {
+            let tracker = Tracker::new();
+            let record = tracker.record();
+            for blocked in [Duration::ZERO, Duration::from_millis(MIN_STALL_MS - 1)] {
+                block_for(&tracker, Side::Tor, &time, blocked).await;
+            }
+            assert_eq!(totals(&record, Side::Tor), (0, 0));
+
+            // Writes that never block leave nothing behind
+            let (stream, mut reader) = tokio::io::duplex(64);
+            let mut writer = tracker.writer(Side::Client, stream);
+            for _ in 0..4 {
+                writer.write_all(&[0; 32]).await.unwrap();
+                drain(&mut reader, 32).await;
+            }
+            assert_eq!(totals(&record, Side::Client), (0, 0));
+            assert_eq!(record.summary(), "");
+        });
+    }
+
+    #[test]
+    fn a_stall_in_progress_is_reported_and_totalled() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        reset();
+        testing::runtime().block_on(async {
+            let tracker = Tracker::new();
+            let record = tracker.record();
+            let (stream, mut reader) = tokio::io::duplex(64);
+            let mut writer = tracker.writer(Side::Client, stream);
+            writer.write_all(&[0; 64]).await.unwrap();
+            let pending = tokio::spawn(async move {
+                writer.write_all(&[0; 1]).await.unwrap();
+                writer
+            });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            time.advance(Duration::from_millis(MIN_STALL_MS - 1));
+            assert_eq!(record.json()["stalled_on"], serde_json::Value::Null);
+            time.advance(Duration::from_millis(1));
+            let json = record.json();
+            assert_eq!(json["stalled_on"], "client");
+            assert_eq!(json["client_stalls"], 1);
+            assert_eq!(json["client_stall_ms"], MIN_STALL_MS);
+            assert_eq!(json["tor_stalls"], 0);
+            // Not in the process totals until the write moves on
+            assert_eq!(metrics_json(), "\"client_stalls\":0,\"client_stall_ms\":0,\"tor_stalls\":0,\"tor_stall_ms\":0");
+
+            time.advance(Duration::from_secs(1));
+            drain(&mut reader, 65).await;
+            let _writer = pending.await.unwrap();
+            assert_eq!(metrics_json(), "\"client_stalls\":1,\"client_stall_ms\":3000,\"tor_stalls\":0,\"tor_stall_ms\":0");
+        });
+        reset();
+    }
+}
@@ -0,0 +1,381 @@
+//! Relay-to-country index
+//!
+//! Every feature that needs a relay's country (exit badges, guard status,
+//! circuit display, diversity reports) asks this module instead of doing its
+//! own geoip lookups, so they all give the same answer for the same consensus.
+//!
+//! The index maps relay RSA identities to country codes from Arti's embedded
+//! geoip database. It is rebuilt once per new consensus (the watcher task
+//! listens for netdir change events) or on demand via `refreshGeoIndex`, and
+//! lookups are a single hash map read. Until a netdir is available, and for
+//! relays the database has no entry for, the answer is `UNKNOWN_COUNTRY`.
+//!
+//! The same pass keeps each exit's platform as the consensus advertises it
+//! (e.g. `Tor 0.4.8.12`), so connections can report exits on outdated
+//! versions. Relays that advertise none have no entry. It also keeps each
+//! exit's Fast and Stable flags, which connections check required exit flags
+//! against (exits.rs).
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arti_client::TorClient;
+use futures::StreamExt;
+use tor_netdir::{DirEvent, Timeliness};
+use tor_rtcompat::PreferredRuntime;
+
+use crate::notification::now_epoch_ms;
+
+/// Answer for relays without a known country
+pub(crate) const UNKNOWN_COUNTRY: &str = "??";
+
+/// Where country data comes from
+const SOURCE: &str = "arti-embedded-geoip";
+
+struct GeoIndex {
+    /// Uppercase hex RSA identity -> ISO 3166 country code
+    by_relay: HashMap<String, String>,
+    built_at_epoch_ms: u64,
+    consensus_valid_after_ms: u64,
+    consensus_valid_until_ms: u64,
+    relays_without_country: usize,
+    /// Uppercase hex RSA identity -> advertised platform, for exits
+    exit_platforms: HashMap<String, String>,
+    /// Uppercase hex RSA identity -> (Fast, Stable) flags, for exits
+    exit_flags: HashMap<String, (bool, bool)>,
+    /// Exits Arti would pick from (Exit and Fast flags)
+    exits: usize,
+    /// Of those, the ones that are also Stable
+    stable_exits: usize,
+}
+
+/// What the index needs to know about one relay in the consensus
+struct Relay {
+    fingerprint: String,
+    country: Option<String>,
+    exit: bool,
+    fast: bool,
+    stable: bool,
+    platform: Option<String>,
+}
+
+impl GeoIndex {
+    fn build(relays: impl IntoIterator<Item = Relay>, valid_after: SystemTime, valid_until: SystemTime) -> GeoIndex {
+        let mut index = GeoIndex {
+            by_relay: HashMap::new(),
+            built_at_epoch_ms: now_epoch_ms(),
+            consensus_valid_after_ms: epoch_ms(valid_after),
+            consensus_valid_until_ms: epoch_ms(valid_until),
+            relays_without_country: 0,
+            exit_platforms: HashMap::new(),
+            exit_flags: HashMap::new(),
+            exits: 0,
+            stable_exits: 0,
+        };
+        for relay in relays {
+            if relay.exit {
+                index.exit_flags.insert(relay.fingerprint.clone(), (relay.fast, relay.stable));
+                if let Some(platform) = relay.platform {
+                    index.exit_platforms.insert(relay.fingerprint.clone(), platform);
+                }
+            }
+            if relay.exit && relay.fast {
+                index.exits += 1;
+                if relay.stable {
+                    index.stable_exits += 1;
+                }
+            }
+            match relay.country {
+                Some(cc) => {
+                    index.by_relay.insert(relay.fingerprint, cc);
+                }
+                None => index.relays_without_country += 1,
+            }
+        }
+        index
+    }
+}
+
+static INDEX: RwLock<Option<GeoIndex>> = RwLock::new(None);
+
+/// Netdir watcher task (replaced whenever a new client is created)
+static WATCHER: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Uppercase hex form of a relay identity, as used for index keys
+pub(crate) fn fingerprint(rsa_id: &[u8]) -> String {
+    rsa_id.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Country code for a relay, or `UNKNOWN_COUNTRY`
+pub(crate) fn country_of(fingerprint: &str) -> String {
+    INDEX
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|index| index.by_relay.get(&fingerprint.to_ascii_uppercase()).cloned())
+        .unwrap_or_else(|| UNKNOWN_COUNTRY.to_string())
+}
+
+/// Advertised platform of an exit relay, if the consensus has one
+pub(crate) fn platform_of(fingerprint: &str) -> Option<String> {
+    INDEX.read().unwrap().as_ref().and_then(|index| index.exit_platforms.get(&fingerprint.to_ascii_uppercase()).cloned())
+}
+
+/// Whether an exit relay carries consensus `flag` (`Fast` or `Stable`), if
+/// the consensus lists it as an exit
+pub(crate) fn exit_carries(fingerprint: &str, flag: &str) -> Option<bool> {
+    let index = INDEX.read().unwrap();
+    let (fast, stable) = *index.as_ref()?.exit_flags.get(&fingerprint.to_ascii_uppercase())?;
+    match flag {
+        "Fast" => Some(fast),
+        "Stable" => Some(stable),
+        _ => None,
+    }
+}
+
+/// Rebuild the index from the client's current netdir
+///
+/// Returns the number of relays indexed, or None if there is no netdir yet
+/// (the previous index, if any, is kept).
+pub(crate) fn rebuild(client: &TorClient<PreferredRuntime>) -> Option<usize> {
+    let netdir = client.dirmgr().netdir(Timeliness::Timely).ok()?;
+    let relays = netdir.relays().map(|relay| Relay {
+        fingerprint: fingerprint(relay.rsa_id().as_bytes()),
+        country: relay.cc().map(|cc| cc.to_string()),
+        exit: relay.is_flagged_exit(),
+        fast: relay.is_flagged_fast(),
+        stable: relay.is_flagged_stable(),
+        platform: relay.rs().version().map(|platform| platform.to_string()),
+    });
+    Some(install(GeoIndex::build(relays, netdir.lifetime().valid_after(), netdir.lifetime().valid_until())))
+}
+
+/// Make `index` the one lookups answer from; the number of relays it indexes
+fn install(index: GeoIndex) -> usize {
+    let entries = index.by_relay.len();
+    log_info!("Geo index rebuilt: {} relays with a country, {} without", entries, index.relays_without_country);
+    *INDEX.write().unwrap() = Some(index);
+    entries
+}
+
+/// Exits in the current consensus: (all Arti would pick from, those meeting
+/// the required exit `flags`), or None before the first netdir
+pub(crate) fn exit_pool(flags: &[String]) -> Option<(usize, usize)> {
+    let index = INDEX.read().unwrap();
+    let index = index.as_ref()?;
+    let usable = if flags.iter().any(|flag| flag == "Stable") { index.stable_exits } else { index.exits };
+    Some((index.exits, usable))
+}
+
+/// Build the index now and rebuild it on every new consensus
+pub(crate) fn start_watcher(runtime: &tokio::runtime::Handle, client: Arc<TorClient<PreferredRuntime>>) {
+    rebuild(&client);
+    let mut events = client.dirmgr().events();
+    let handle = runtime.spawn(async move {
+        while let Some(event) = events.next().await {
+            if event == DirEvent::NewConsensus {
+                rebuild(&client);
+            }
+        }
+    });
+    if let Some(previous) = WATCHER.lock().unwrap().replace(handle) {
+        previous.abort();
+    }
+}
+
+/// Index metadata as JSON: source, build time, entry count and staleness
+pub(crate) fn info_json() -> String {
+    let index = INDEX.read().unwrap();
+    match index.as_ref() {
+        None => format!(
+            "{{\"source\":\"{}\",\"built_at_epoch_ms\":null,\"entries\":0,\"relays_without_country\":0,\"consensus_valid_after_epoch_ms\":null,\"stale\":true}}",
+            SOURCE,
+        ),
+        Some(index) => format!(
+            "{{\"source\":\"{}\",\"built_at_epoch_ms\":{},\"entries\":{},\"relays_without_country\":{},\"consensus_valid_after_epoch_ms\":{},\"stale\":{}}}",
+            SOURCE,
+            index.built_at_epoch_ms,
+            index.by_relay.len(),
+            index.relays_without_country,
+            index.consensus_valid_after_ms,
+            now_epoch_ms() > index.consensus_valid_until_ms,
+        ),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold_rw(&INDEX);
+    held.hold(&WATCHER);
+}
+
+/// The index as it was before `fake_index`, put back on drop
+#[cfg(test)]
+pub(crate) struct FakeIndex(Option<GeoIndex>);
+
+/// Answer lookups from `relays` (fingerprint, country, exit platform; a
+/// relay with a platform is an exit) until the result is dropped
+#[cfg(test)]
+pub(crate) fn fake_index(relays: &[(&str, Option<&str>, Option<&str>)]) -> FakeIndex {
+    let now = crate::seams::now_wall();
+    let relays = relays.iter().map(|(fingerprint, country, platform)| Relay {
+        fingerprint: fingerprint.to_string(),
+        country: country.map(str::to_string),
+        exit: platform.is_some(),
+        fast: true,
+        stable: true,
+        platform: platform.map(str::to_string),
+    });
+    let index = GeoIndex::build(relays, now, now + std::time::Duration::from_secs(3 * 3600));
+    FakeIndex(INDEX.write().unwrap().replace(index))
+}
+
+/// Answer lookups for `exits` (fingerprint, flags: `F` Fast, `S` Stable)
+/// until the result is dropped
+#[cfg(test)]
+pub(crate) fn fake_exits(exits: &[(&str, &str)]) -> FakeIndex {
+    let now = crate::seams::now_wall();
+    let relays = exits.iter().map(|(fingerprint, flags)| Relay {
+        fingerprint: fingerprint.to_string(),
+        country: None,
+        exit: true,
+        fast: flags.contains('F'),
+        stable: flags.contains('S'),
+        platform: None,
+    });
+    let index = GeoIndex::build(relays, now, now + std::time::Duration::from_secs(3 * 3600));
+    FakeIndex(INDEX.write().unwrap().replace(index))
+}
+
+#[cfg(test)]
+impl Drop for FakeIndex {
+    fn drop(&mut self) {
+        *INDEX.write().unwrap_or_else(std::sync::PoisonError::into_inner) = self.0.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::seams;
+    use crate::testing::{self, FakeTime};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn relay(id: &str, country: Option<&str>, flags: &str, platform: Option<&str>) -> Relay {
+        Relay {
+            fingerprint: id.to_string(),
+            country: country.map(str::to_string),
+            exit: flags.contains('E'),
+            fast: flags.contains('F'),
+            stable: flags.contains('S'),
+            platform: platform.map(str::to_string),
+        }
+    }
+
+    /// A consensus valid for the next three hours, as the fake clock sees it
+    fn fabricated_consensus() -> GeoIndex {
+        let now = seams::now_wall();
+        GeoIndex::build(
+            [
+                relay("AA01", Some("de"), "EFS", Some("Tor 0.4.8.12")),
+                relay("AA02", Some("nl"), "EF", None),
+                relay("AA03", None, "EFS", Some("Tor 0.4.7.1")),
+                relay("AA04", Some("us"), "FS", Some("Tor 0.4.8.12")),
+                relay("AA05", Some("se"), "E", Some("Tor 0.4.8.9")),
+            ],
+            now,
+            now + 3 * HOUR,
+        )
+    }
+
+    fn info() -> serde_json::Value {
+        serde_json::from_str(&info_json()).unwrap()
+    }
+
+    #[test]
+    fn lookups_answer_from_the_built_index() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let saved = INDEX.write().unwrap().take();
+
+        // No netdir yet: every lookup gets the same unknown answer
+        assert_eq!(country_of("AA01"), UNKNOWN_COUNTRY);
+        assert_eq!(platform_of("AA01"), None);
+        assert_eq!(exit_pool(&[]), None);
+        assert_eq!(exit_carries("AA01", "Stable"), None);
+        assert_eq!(info()["entries"], 0);
+        assert_eq!(info()["stale"], true);
+
+        assert_eq!(install(fabricated_consensus()), 4);
+        for (id, country, platform) in [
+            ("AA01", "de", Some("Tor 0.4.8.12")),
+            ("aa01", "de", Some("Tor 0.4.8.12")),
+            ("AA02", "nl", None),
+            ("AA03", UNKNOWN_COUNTRY, Some("Tor 0.4.7.1")),
+            // Only exits keep their platform
+            ("AA04", "us", None),
+            ("AA05", "se", Some("Tor 0.4.8.9")),
+            ("FFFF", UNKNOWN_COUNTRY, None),
+        ] {
+            assert_eq!(country_of(id), country, "{}", id);
+            assert_eq!(platform_of(id).as_deref(), platform, "{}", id);
+        }
+        // Exit and Fast, then also Stable
+        assert_eq!(exit_pool(&[]), Some((3, 3)));
+        assert_eq!(exit_pool(&["Stable".to_string()]), Some((3, 2)));
+        for (id, flag, carried) in [
+            ("AA01", "Stable", Some(true)),
+            ("aa02", "Fast", Some(true)),
+            ("AA02", "Stable", Some(false)),
+            ("AA05", "Fast", Some(false)),
+            ("AA01", "Guard", None),
+            // Not an exit, or not listed
+            ("AA04", "Stable", None),
+            ("FFFF", "Fast", None),
+        ] {
+            assert_eq!(exit_carries(id, flag), carried, "{} {}", id, flag);
+        }
+        assert_eq!(info()["entries"], 4);
+        assert_eq!(info()["relays_without_country"], 1);
+        assert_eq!(fingerprint(&[0x0a, 0xbc]), "0ABC");
+
+        *INDEX.write().unwrap() = saved;
+    }
+
+    #[test]
+    fn index_goes_stale_with_its_consensus_until_refreshed() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let saved = INDEX.write().unwrap().take();
+
+        install(fabricated_consensus());
+        let built_at = info()["built_at_epoch_ms"].as_u64().unwrap();
+        assert_eq!(built_at, now_epoch_ms());
+        assert_eq!(info()["stale"], false);
+        time.advance(3 * HOUR);
+        assert_eq!(info()["stale"], false);
+        time.advance(Duration::from_millis(1));
+        assert_eq!(info()["stale"], true);
+        // Still answering from the old consensus meanwhile
+        assert_eq!(country_of("AA01"), "de");
+
+        // A refresh replaces the answers wholesale
+        let now = seams::now_wall();
+        install(GeoIndex::build([relay("AA01", Some("fr"), "", None)], now, now + 3 * HOUR));
+        assert_eq!(info()["stale"], false);
+        assert!(info()["built_at_epoch_ms"].as_u64().unwrap() > built_at);
+        assert_eq!(country_of("AA01"), "fr");
+        assert_eq!(country_of("AA02"), UNKNOWN_COUNTRY);
+        assert_eq!(exit_pool(&[]), Some((0, 0)));
+
+        *INDEX.write().unwrap() = saved;
+    }
+}
@@ -0,0 +1,168 @@
+//! Detection of the "consensus but no usable guards" stuck state
+//!
+//! A client that has a usable consensus but can't reach any of its guards
+//! looks like a slow bootstrap or a generic connect timeout, yet it has a
+//! specific and common cause: the network filters Tor relays. So it gets its
+//! own signal, which tells the user that bridges may help:
+//!
+//! - a one-shot `NO_USABLE_GUARDS` line in the log sinks and a failure record
+//!   of kind `no_usable_guards`;
+//! - `detail_key` `no_usable_guards` in the notification status while
+//!   bootstrapping, and `no_usable_guards` in the metrics.
+//!
+//! Arti reports the condition as `PickGuardError::AllGuardsDown` ("No usable
+//! guards"), either in errors returned from connect or in the warnings its
+//! directory and circuit managers log while bootstrap retries. Whether a
+//! consensus is present is taken from the directory manager marking one
+//! usable. The text matches are deliberately narrow; a missed event still
+//! surfaces as the generic failure. The signal clears on the next successful
+//! bootstrap or connect.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{diagnostics, notification};
+
+/// A usable consensus has been seen since bootstrap started
+static HAVE_CONSENSUS: AtomicBool = AtomicBool::new(false);
+
+/// The condition currently holds (and has been signalled)
+static UNUSABLE: AtomicBool = AtomicBool::new(false);
+
+/// Whether `text` (an error or log message) says no guard was usable
+fn mentions_no_guards(text: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    text.contains("no usable guards") || text.contains("allguardsdown") || text.contains("all guards are down")
+}
+
+/// Forget the consensus seen by an earlier bootstrap (called as one starts)
+pub(crate) fn reset() {
+    HAVE_CONSENSUS.store(false, Ordering::Relaxed);
+}
+
+/// Whether the no-usable-guards condition currently holds
+pub(crate) fn unusable() -> bool {
+    UNUSABLE.load(Ordering::Relaxed)
+}
+
+/// Classify a tracing event from Arti (called from the bridge layer)
+pub(crate) fn observe_event(target: &str, message: &str) {
+    if target.starts_with("tor_dirmgr") && message.to_ascii_lowercase().contains("consensus usable") {
+        HAVE_CONSENSUS.store(true, Ordering::Relaxed);
+        return;
+    }
+    let relevant = ["tor_guardmgr", "tor_circmgr", "tor_dirmgr"].iter().any(|c| target.starts_with(c));
+    if relevant && HAVE_CONSENSUS.load(Ordering::Relaxed) && mentions_no_guards(message) {
+        signal(target);
+    }
+}
+
+/// Check an error returned by a connect on a bootstrapped client
+pub(crate) fn check_error<E: std::fmt::Debug>(error: &E, source: &str) {
+    if mentions_no_guards(&format!("{:?}", error)) {
+        signal(source);
+    }
+}
+
+fn signal(source: &str) {
+    if UNUSABLE.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let message = format!("no usable guards (from {}); the network may be filtered and bridges may help", source);
+    log_error!("NO_USABLE_GUARDS: {}", message);
+    diagnostics::record_failure("no_usable_guards", message);
+    notification::update(|inputs| inputs.no_usable_guards = true);
+}
+
+/// A bootstrap or connect succeeded, so some guard is usable again
+pub(crate) fn clear() {
+    if UNUSABLE.swap(false, Ordering::Relaxed) {
+        log_info!("Guards usable again");
+        notification::update(|inputs| inputs.no_usable_guards = false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::BridgeLayer;
+    use crate::testing::{self, FakeTime};
+    use crate::LifecycleState;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn signals() -> usize {
+        crate::tests::SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains("NO_USABLE_GUARDS")).count()
+    }
+
+    fn detail_key() -> String {
+        let rendered: serde_json::Value = serde_json::from_str(&notification::render()).unwrap();
+        rendered["detail_key"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn only_the_no_guards_wording_matches() {
+        for (text, matches) in [
+            ("Unable to select a guard relay: No usable guards. Rejected 20/20 as down", true),
+            ("PickGuardError::AllGuardsDown { .. }", true),
+            ("all guards are down; will retry", true),
+            ("Guard usable again", false),
+            ("Consensus usable; 6843 relays", false),
+            ("connection timed out", false),
+        ] {
+            assert_eq!(mentions_no_guards(text), matches, "{}", text);
+        }
+    }
+
+    #[test]
+    fn no_guards_after_a_consensus_is_signalled_once() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        clear();
+        reset();
+        notification::update(|inputs| {
+            inputs.state = LifecycleState::Initialized;
+            inputs.bootstrap_percent = 40;
+        });
+        let before = signals();
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(BridgeLayer), || {
+            // Without a consensus this is an ordinary bootstrap hiccup
+            tracing::warn!(target: "tor_guardmgr::guard", "Unable to select a guard relay: No usable guards");
+            assert!(!unusable());
+            // Other crates' wording is never taken for Arti's
+            tracing::info!(target: "tor_dirmgr::state", "Consensus usable at last");
+            tracing::warn!(target: "some_app", "No usable guards");
+            assert!(!unusable());
+
+            for _ in 0..3 {
+                tracing::warn!(target: "tor_circmgr::build", "Circuit build failed: No usable guards");
+            }
+        });
+        assert!(unusable());
+        assert_eq!(signals(), before + 1);
+        let failure: serde_json::Value = serde_json::from_str(&diagnostics::last_error_json()).unwrap();
+        assert_eq!(failure["kind"], "no_usable_guards");
+        assert!(failure["message"].as_str().unwrap().contains("bridges may help"));
+        assert_eq!(detail_key(), "no_usable_guards");
+
+        // A successful bootstrap or connect ends it
+        clear();
+        assert!(!unusable());
+        assert_eq!(detail_key(), "bootstrap_percent");
+        notification::update(|inputs| inputs.state = LifecycleState::Stopped);
+    }
+
+    #[test]
+    fn connect_errors_signal_without_the_event() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        clear();
+        let before = signals();
+        check_error(&"Circuit(Guard(AllGuardsDown { .. }))", "connect");
+        check_error(&"Circuit(Guard(AllGuardsDown { .. }))", "connect");
+        assert!(unusable());
+        assert_eq!(signals(), before + 1);
+        check_error(&"RemoteConnectionRefused", "connect");
+        clear();
+        assert!(!unusable());
+    }
+}
@@ -0,0 +1,232 @@
+//! Live streams by isolation group, for checking isolation works
+//!
+//! Streams only share a circuit when their isolation keys are equal (see
+//! `IsolationKey` in lib.rs), so every distinct key is a group.
+//! `listIsolationGroups` reports the groups with live streams, how many
+//! streams each carries and over how many distinct circuits, so a developer
+//! can see that per-port or per-network isolation really separates traffic.
+//!
+//! Groups are described by their key components. The network token names the
+//! network the device is on and a claimed host names a destination, so
+//! neither is ever shown: each is replaced by a short digest, keyed per
+//! process, that only tells values apart within one report.
+//! Streams without any isolation component form the `default` group.
+//!
+//! Only live streams are tracked, and the report lists at most `MAX_LISTED`
+//! groups, busiest first.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Mutex, OnceLock};
+
+use serde_json::{json, Value};
+
+/// Groups listed at most; the rest are only counted
+const MAX_LISTED: usize = 64;
+
+/// Hex digits of the network token digest shown
+const DIGEST_LEN: usize = 8;
+
+#[derive(Default)]
+struct Group {
+    /// Key components as reported
+    description: Value,
+    streams: usize,
+    /// Live streams per circuit
+    circuits: HashMap<String, usize>,
+}
+
+static GROUPS: Mutex<Option<HashMap<String, Group>>> = Mutex::new(None);
+
+/// Per-process key of the network token digest
+static DIGEST_KEY: OnceLock<RandomState> = OnceLock::new();
+
+/// The scrubbed stand-in for a network token or claimed host
+pub(crate) fn scrub(token: &str) -> String {
+    let digest = DIGEST_KEY.get_or_init(RandomState::new).hash_one(token);
+    format!("[scrubbed {:0width$x}]", digest >> (64 - 4 * DIGEST_LEN), width = DIGEST_LEN)
+}
+
+/// A stream's place in its group, left on drop
+pub(crate) struct Membership {
+    label: String,
+    circuit: Option<String>,
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let mut groups = GROUPS.lock().unwrap();
+        let Some(groups) = groups.as_mut() else {
+            return;
+        };
+        let Some(group) = groups.get_mut(&self.label) else {
+            return;
+        };
+        group.streams -= 1;
+        if let Some(circuit) = &self.circuit {
+            if let Some(count) = group.circuits.get_mut(circuit) {
+                *count -= 1;
+                if *count == 0 {
+                    group.circuits.remove(circuit);
+                }
+            }
+        }
+        if group.streams == 0 {
+            groups.remove(&self.label);
+        }
+    }
+}
+
+/// Record a live stream in the group described by `description` (scrubbed
+/// key components), carried by `circuit` if known
+pub(crate) fn join(description: Value, circuit: Option<String>) -> Membership {
+    let label = description.to_string();
+    let mut groups = GROUPS.lock().unwrap();
+    let group = groups.get_or_insert_with(HashMap::new).entry(label.clone()).or_default();
+    group.description = description;
+    group.streams += 1;
+    if let Some(circuit) = &circuit {
+        *group.circuits.entry(circuit.clone()).or_default() += 1;
+    }
+    Membership { label, circuit }
+}
+
+/// The groups with live streams as JSON
+pub(crate) fn list_json() -> String {
+    let groups = GROUPS.lock().unwrap();
+    let mut listed: Vec<&Group> = groups.iter().flat_map(|groups| groups.values()).collect();
+    let total = listed.len();
+    listed.sort_by(|a, b| b.streams.cmp(&a.streams).then_with(|| a.description.to_string().cmp(&b.description.to_string())));
+    let entries: Vec<Value> = listed
+        .into_iter()
+        .take(MAX_LISTED)
+        .map(|group| {
+            json!({
+                "group": group.description,
+                "streams": group.streams,
+                "circuits": group.circuits.len(),
+            })
+        })
+        .collect();
+    json!({
+        "groups": entries,
+        "total_groups": total,
+        "truncated": total > MAX_LISTED,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&GROUPS);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+    use crate::{config, IsolationKey};
+
+    /// Empty groups for one test, the previous ones restored after
+    struct Isolated(Option<HashMap<String, Group>>);
+
+    impl Isolated {
+        fn new() -> Isolated {
+            Isolated(GROUPS.lock().unwrap().take())
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            *GROUPS.lock().unwrap() = self.0.take();
+        }
+    }
+
+    fn listed() -> Value {
+        serde_json::from_str(&list_json()).unwrap()
+    }
+
+    #[test]
+    fn distinct_keys_are_distinct_groups() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        config::set("isolate_by_dest_port", json!(true)).unwrap();
+        let https = IsolationKey::for_target(443, None);
+        let http = IsolationKey::for_target(80, None);
+        config::set("isolate_by_dest_port", json!(false)).unwrap();
+
+        let _streams = [
+            join(https.describe(), Some("circ-1".into())),
+            join(https.describe(), Some("circ-1".into())),
+            join(https.describe(), Some("circ-2".into())),
+            join(http.describe(), None),
+        ];
+        let list = listed();
+        assert_eq!(list["total_groups"], 2);
+        assert_eq!(list["truncated"], false);
+        let groups = list["groups"].as_array().unwrap();
+        assert_eq!(groups[0]["group"]["dest_port"], 443);
+        assert_eq!(groups[0]["streams"], 3);
+        assert_eq!(groups[0]["circuits"], 2);
+        assert_eq!(groups[1]["group"]["dest_port"], 80);
+        assert_eq!(groups[1]["streams"], 1);
+        assert_eq!(groups[1]["circuits"], 0);
+    }
+
+    #[test]
+    fn closed_streams_leave_their_group() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let first = join(json!("default"), Some("circ-1".into()));
+        let second = join(json!("default"), Some("circ-2".into()));
+        assert_eq!(listed()["groups"][0]["circuits"], 2);
+
+        drop(first);
+        let list = listed();
+        assert_eq!(list["groups"][0]["streams"], 1);
+        assert_eq!(list["groups"][0]["circuits"], 1);
+
+        drop(second);
+        assert_eq!(listed(), json!({"groups": [], "total_groups": 0, "truncated": false}));
+    }
+
+    #[test]
+    fn the_listing_is_bounded_busiest_first() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let mut streams: Vec<Membership> = (0..MAX_LISTED + 6).map(|port| join(json!({ "dest_port": port }), None)).collect();
+        streams.push(join(json!({ "dest_port": 5 }), None));
+
+        let list = listed();
+        assert_eq!(list["total_groups"], MAX_LISTED + 6);
+        assert_eq!(list["truncated"], true);
+        let groups = list["groups"].as_array().unwrap();
+        assert_eq!(groups.len(), MAX_LISTED);
+        assert_eq!(groups[0]["group"]["dest_port"], 5);
+        assert_eq!(groups[0]["streams"], 2);
+    }
+
+    #[test]
+    fn network_tokens_and_claimed_hosts_are_scrubbed() {
+        let key = IsolationKey {
+            claimed_host: Some("secret.example".into()),
+            network: Some(("home-wifi-ssid".into(), Some(2))),
+            token: Some("private-tab".into()),
+            ..IsolationKey::default()
+        };
+        let shown = key.describe();
+        let text = shown.to_string();
+        assert!(!text.contains("secret.example") && !text.contains("home-wifi-ssid") && !text.contains("private-tab"), "{}", text);
+        assert_eq!(shown["token"], scrub("private-tab"));
+        assert_eq!(shown["network"], scrub("home-wifi-ssid"));
+        assert_eq!(shown["claimed_host"], scrub("secret.example"));
+        assert_eq!(shown["stale_era"], 2);
+        assert_ne!(scrub("home-wifi-ssid"), scrub("other-ssid"));
+        assert_eq!(scrub("x").len(), "[scrubbed ]".len() + DIGEST_LEN);
+
+        assert_eq!(IsolationKey::default().describe(), json!("default"));
+    }
+}
@@ -0,0 +1,590 @@
+//! Data directory layout and its migrations
+//!
+//! Every path the wrapper touches under the app-provided data directory is
+//! derived here via [`path`], so the on-disk layout is defined in one place.
+//! The layout is versioned with a marker file; older layouts are upgraded by a
+//! list of idempotent migration steps. Progress is recorded in a journal file
+//! before each step, so a migration interrupted mid-way (process killed,
+//! storage yanked) resumes where it stopped on the next `initialize`.
+//!
+//! Layout version 2:
+//!
+//! ```text
+//! <data_dir>/
+//! ├── layout.version          # Layout version marker
+//! ├── layout.journal          # Present only while a migration is in progress
+//! ├── wrapper.lock            # Cross-process lock and its holder (state_lock.rs)
+//! ├── audit.log               # Audit trail
+//! ├── audit.log.1             # Audit trail rotated out of audit.log
+//! ├── arti/
+//! │   ├── state/              # Arti persistent state (guards, keys)
+//! │   │   └── keystore/hss/   # Onion service keys, one directory per nickname
+//! │   └── cache/              # Arti directory cache
+//! └── wrapper/
+//!     ├── audit.head          # Last and anchor audit records, authenticated
+//!     ├── directory-bytes     # Directory bytes per calendar month
+//!     ├── destination-quality # Per-destination quality (only when persistent)
+//!     ├── bridge-history      # Hourly per-bridge health history
+//!     ├── metrics-history/    # Ring of periodic metrics snapshots
+//!     ├── session-log         # Lifecycle transitions across sessions
+//!     ├── session-alive       # When this process was last seen alive
+//!     ├── bootstrap-history   # Recent bootstrap attempts
+//!     └── onion-client-auth/  # Generated onion client auth keystore
+//! ```
+//!
+//! Version 1 (releases before the marker existed) kept `state/` and `cache/`
+//! directly under the data directory. Moving them merges into a target that
+//! already exists rather than leaving anything behind; a file present in both
+//! places fails the migration, which leaves the version unchanged and both
+//! copies in place.
+//!
+//! Nothing outside this module builds a path under the data directory: the
+//! rest of the wrapper calls [`path`], or [`entry`] for the files of a
+//! directory whose names are data (snapshot numbers, onion service ids). A
+//! unit test scans the sources to keep it that way.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Current layout version
+pub(crate) const LAYOUT_VERSION: u32 = 2;
+
+/// Everything the wrapper stores under the data directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Purpose {
+    LayoutVersion,
+    MigrationJournal,
+    ArtiState,
+    ArtiCache,
+    #[cfg(feature = "onion-service-hosting")]
+    OnionServiceKeys,
+    OnionClientAuth,
+    AuditLog,
+    AuditLogRotated,
+    AuditHead,
+    DirectoryBytesByMonth,
+    DestinationQuality,
+    BridgeHistory,
+    MetricsHistory,
+    SessionLog,
+    SessionAlive,
+    BootstrapHistory,
+    StateLock,
+}
+
+/// Path for `purpose` under `data_dir` in the current layout
+pub(crate) fn path(data_dir: &Path, purpose: Purpose) -> PathBuf {
+    match purpose {
+        Purpose::LayoutVersion => data_dir.join("layout.version"),
+        Purpose::MigrationJournal => data_dir.join("layout.journal"),
+        Purpose::ArtiState => data_dir.join("arti").join("state"),
+        Purpose::ArtiCache => data_dir.join("arti").join("cache"),
+        #[cfg(feature = "onion-service-hosting")]
+        Purpose::OnionServiceKeys => path(data_dir, Purpose::ArtiState).join("keystore").join("hss"),
+        Purpose::OnionClientAuth => data_dir.join("wrapper").join("onion-client-auth"),
+        Purpose::AuditLog => data_dir.join("audit.log"),
+        Purpose::AuditLogRotated => data_dir.join("audit.log.1"),
+        Purpose::AuditHead => data_dir.join("wrapper").join("audit.head"),
+        Purpose::DirectoryBytesByMonth => data_dir.join("wrapper").join("directory-bytes"),
+        Purpose::DestinationQuality => data_dir.join("wrapper").join("destination-quality"),
+        Purpose::BridgeHistory => data_dir.join("wrapper").join("bridge-history"),
+        Purpose::MetricsHistory => data_dir.join("wrapper").join("metrics-history"),
+        Purpose::SessionLog => data_dir.join("wrapper").join("session-log"),
+        Purpose::SessionAlive => data_dir.join("wrapper").join("session-alive"),
+        Purpose::BootstrapHistory => data_dir.join("wrapper").join("bootstrap-history"),
+        Purpose::StateLock => data_dir.join("wrapper.lock"),
+    }
+}
+
+/// Path of the file `name` in `dir`, a directory obtained from [`path`]
+///
+/// `name` must be a single path component.
+pub(crate) fn entry(dir: &Path, name: &str) -> PathBuf {
+    assert!(
+        !name.is_empty() && name != "." && name != ".." && !name.contains(std::path::is_separator),
+        "not a single path component: {:?}",
+        name,
+    );
+    dir.join(name)
+}
+
+/// Directories that must exist for the current layout
+const DIRECTORIES: &[Purpose] = &[Purpose::ArtiState, Purpose::ArtiCache];
+
+/// One idempotent migration step
+struct Step {
+    name: &'static str,
+    run: fn(&Path) -> io::Result<()>,
+}
+
+/// Steps upgrading version 1 to version 2, in order
+const V1_TO_V2: &[Step] = &[
+    Step { name: "move-state", run: |d| move_dir(&d.join("state"), &path(d, Purpose::ArtiState)) },
+    Step { name: "move-cache", run: |d| move_dir(&d.join("cache"), &path(d, Purpose::ArtiCache)) },
+    Step {
+        name: "drop-legacy-client-auth",
+        // Regenerated on every initialize, so the old copy is simply removed
+        run: |d| remove_dir_if_exists(&path(d, Purpose::ArtiState).join("onion-client-auth")),
+    },
+];
+
+/// Move `from` to `to` unless that already happened
+///
+/// Safe to re-run after an interruption: a completed move leaves `from`
+/// absent, which is a no-op. If `to` already exists (`from` was recreated
+/// after the move, or a merge was interrupted) the two are merged: entries
+/// `to` lacks are moved over, directories on both sides are merged in turn,
+/// and a file on both sides fails the move with both copies kept. `from` is
+/// removed only once it is verifiably empty.
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+    if !to.exists() {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        return std::fs::rename(from, to);
+    }
+    for source in std::fs::read_dir(from)? {
+        let source = source?;
+        let target = to.join(source.file_name());
+        if std::fs::symlink_metadata(&target).is_err() {
+            std::fs::rename(source.path(), &target)?;
+        } else if source.file_type()?.is_dir() && target.is_dir() {
+            move_dir(&source.path(), &target)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists in both the legacy and the current layout", target.display()),
+            ));
+        }
+    }
+    // Fails if anything was left behind
+    std::fs::remove_dir(from)
+}
+
+fn remove_dir_if_exists(dir: &Path) -> io::Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+/// Recursively copy a directory tree (regular files and directories only)
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            #[cfg(test)]
+            tests::fail_copy_of(&target)?;
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Move the whole data directory from `from` to `to`
+///
+/// `to` must not exist yet (an empty directory is accepted). On one filesystem
+/// this is a single rename. Otherwise the tree is copied into a staging
+/// directory beside `to`, which is renamed into place only once complete; any
+/// failure removes the staging copy and leaves `from` untouched, so the data is
+/// always wholly in one place. The old directory is removed last.
+pub(crate) fn relocate(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        if std::fs::read_dir(to)?.next().is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "destination is not empty"));
+        }
+        std::fs::remove_dir(to)?;
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Fails across filesystems (e.g. into an encrypted volume)
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_into_place(from, to)
+}
+
+/// Copy `from` to a staging directory, rename it to `to`, then remove `from`
+fn copy_into_place(from: &Path, to: &Path) -> io::Result<()> {
+    let mut staging_name = to.file_name().unwrap_or_default().to_os_string();
+    staging_name.push(".migrating");
+    let staging = to.with_file_name(staging_name);
+    remove_dir_if_exists(&staging)?;
+
+    if let Err(e) = copy_tree(from, &staging).and_then(|_| std::fs::rename(&staging, to)) {
+        log_error!("Data directory copy failed, rolling back: {}", e);
+        remove_dir_if_exists(&staging).ok();
+        return Err(e);
+    }
+
+    if let Err(e) = std::fs::remove_dir_all(from) {
+        log_error!("Migrated data directory, but the old copy could not be removed: {}", e);
+    }
+    Ok(())
+}
+
+/// Write a file atomically (temp file + rename)
+///
+/// Refused while the data directory's storage is lost; a failure is checked
+/// for the storage having disappeared (see storage.rs).
+pub(crate) fn write_atomic(target: &Path, contents: &[u8]) -> io::Result<()> {
+    crate::storage::check_writable()?;
+    let tmp = target.with_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .and_then(|()| std::fs::rename(&tmp, target))
+        .inspect_err(crate::storage::observe)
+}
+
+/// Version of the layout currently on disk
+fn detect_version(data_dir: &Path) -> io::Result<u32> {
+    match std::fs::read_to_string(path(data_dir, Purpose::LayoutVersion)) {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupt layout.version")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(1),
+        Err(e) => Err(e),
+    }
+}
+
+/// Bring `data_dir` to the current layout and create its directories
+pub(crate) fn prepare(data_dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+
+    let version = detect_version(data_dir)?;
+    if version > LAYOUT_VERSION {
+        crate::audit::record(
+            "layout_downgrade_refused",
+            &format!("on-disk v{}, supported v{}", version, LAYOUT_VERSION),
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("data directory layout v{} is newer than supported v{}", version, LAYOUT_VERSION),
+        ));
+    }
+
+    if version < LAYOUT_VERSION {
+        let journal = path(data_dir, Purpose::MigrationJournal);
+        let resume_at = match std::fs::read_to_string(&journal) {
+            Ok(s) => s.trim().parse::<usize>().unwrap_or(0),
+            Err(_) => 0,
+        };
+        if resume_at > 0 {
+            log_info!("Resuming interrupted layout migration at step {}", resume_at);
+        }
+
+        for (index, step) in V1_TO_V2.iter().enumerate().skip(resume_at) {
+            // Journal first: a crash during the step re-runs it, which is
+            // safe because every step is idempotent
+            write_atomic(&journal, index.to_string().as_bytes())?;
+            log_info!("Layout migration step: {}", step.name);
+            (step.run)(data_dir)?;
+        }
+
+        write_atomic(&path(data_dir, Purpose::LayoutVersion), LAYOUT_VERSION.to_string().as_bytes())?;
+        std::fs::remove_file(&journal).ok();
+        log_info!("Data directory migrated to layout v{}", LAYOUT_VERSION);
+    }
+
+    for purpose in DIRECTORIES {
+        std::fs::create_dir_all(path(data_dir, *purpose))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    /// Create `files` (relative paths) under `root` with their own name as contents
+    fn fabricate(root: &Path, files: &[&str]) {
+        for file in files {
+            let file = root.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(&file, file.file_name().unwrap().as_encoded_bytes()).unwrap();
+        }
+    }
+
+    fn exists(root: &Path, relative: &str) -> bool {
+        root.join(relative).exists()
+    }
+
+    fn version(root: &Path) -> Option<String> {
+        std::fs::read_to_string(path(root, Purpose::LayoutVersion)).ok()
+    }
+
+    const LEGACY_TREE: &[&str] = &[
+        "state/state/guards.json",
+        "state/onion-client-auth/old.auth_private",
+        "cache/dir_blobs/consensus",
+    ];
+
+    #[test]
+    fn migrates_a_legacy_tree() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let root = temp.path();
+        fabricate(root, LEGACY_TREE);
+
+        prepare(root).unwrap();
+        assert!(exists(root, "arti/state/state/guards.json"));
+        assert!(exists(root, "arti/cache/dir_blobs/consensus"));
+        assert!(!exists(root, "arti/state/onion-client-auth"));
+        assert!(!exists(root, "state") && !exists(root, "cache"));
+        assert!(!exists(root, "layout.journal"));
+        assert_eq!(version(root).as_deref(), Some("2"));
+
+        // Idempotent
+        prepare(root).unwrap();
+        assert!(exists(root, "arti/state/state/guards.json"));
+    }
+
+    #[test]
+    fn fresh_directory_gets_the_current_layout() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let root = temp.path().join("new");
+        prepare(&root).unwrap();
+        assert!(path(&root, Purpose::ArtiState).is_dir() && path(&root, Purpose::ArtiCache).is_dir());
+        assert_eq!(version(&root).as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn resumes_after_a_crash_between_steps() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let root = temp.path();
+        fabricate(root, LEGACY_TREE);
+
+        // Killed after the first step, with the journal pointing at the second
+        (V1_TO_V2[0].run)(root).unwrap();
+        write_atomic(&path(root, Purpose::MigrationJournal), b"1").unwrap();
+        assert!(exists(root, "arti/state/state/guards.json") && exists(root, "cache"));
+
+        prepare(root).unwrap();
+        assert!(exists(root, "arti/cache/dir_blobs/consensus"));
+        assert!(!exists(root, "cache") && !exists(root, "layout.journal"));
+        assert_eq!(version(root).as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn merges_a_recreated_legacy_directory() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let root = temp.path();
+        // Killed during the step after the move, and an old release then
+        // wrote a fresh legacy `state/` before this one ran again
+        fabricate(root, &["arti/state/state/guards.json", "state/state/other.json", "state/keystore/k"]);
+        write_atomic(&path(root, Purpose::MigrationJournal), b"0").unwrap();
+
+        prepare(root).unwrap();
+        for file in ["guards.json", "other.json"] {
+            assert!(exists(root, &format!("arti/state/state/{}", file)), "{}", file);
+        }
+        assert!(exists(root, "arti/state/keystore/k"));
+        assert!(!exists(root, "state"));
+        assert_eq!(version(root).as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn conflicting_copies_fail_without_bumping_the_version() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let root = temp.path();
+        fabricate(root, &["arti/state/state/guards.json", "state/state/guards.json", "state/state/extra.json"]);
+        std::fs::write(root.join("state/state/guards.json"), "legacy").unwrap();
+
+        let error = prepare(root).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(version(root), None);
+        assert!(exists(root, "layout.journal"));
+        // Neither copy is lost
+        assert_eq!(std::fs::read_to_string(root.join("state/state/guards.json")).unwrap(), "legacy");
+        assert_eq!(std::fs::read_to_string(root.join("arti/state/state/guards.json")).unwrap(), "guards.json");
+
+        // Once resolved the migration completes
+        std::fs::remove_file(root.join("state/state/guards.json")).unwrap();
+        prepare(root).unwrap();
+        assert!(exists(root, "arti/state/state/extra.json"));
+        assert_eq!(version(root).as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn newer_layout_is_refused() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let root = temp.path();
+        std::fs::write(path(root, Purpose::LayoutVersion), "3").unwrap();
+        assert_eq!(prepare(root).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(version(root).as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn relocate_moves_everything() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let from = temp.path().join("old");
+        let to = temp.path().join("new");
+        fabricate(&from, &["arti/state/state/guards.json", "audit.log"]);
+        std::fs::create_dir_all(&to).unwrap();
+
+        relocate(&from, &to).unwrap();
+        assert!(exists(&to, "arti/state/state/guards.json") && exists(&to, "audit.log"));
+        assert!(!from.exists());
+
+        fabricate(&from, &["audit.log"]);
+        assert_eq!(relocate(&from, &to).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    /// File name the next tree copy fails at (a full disk halfway through)
+    static FAIL_COPY: Mutex<Option<&'static str>> = Mutex::new(None);
+
+    pub(super) fn fail_copy_of(target: &Path) -> io::Result<()> {
+        let fail_at = *FAIL_COPY.lock().unwrap();
+        match fail_at {
+            Some(name) if target.file_name().is_some_and(|n| n == name) => {
+                Err(io::Error::new(io::ErrorKind::StorageFull, "no space left on device"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    const GUARDS: &[u8] = br#"{"guards":[{"id":"$0123456789ABCDEF0123456789ABCDEF01234567","confirmed":true}]}"#;
+
+    /// A data directory as a running client leaves it
+    fn populated(root: &Path) -> PathBuf {
+        prepare(root).unwrap();
+        let guards = path(root, Purpose::ArtiState).join("state").join("guards.json");
+        std::fs::create_dir_all(guards.parent().unwrap()).unwrap();
+        std::fs::write(&guards, GUARDS).unwrap();
+        fabricate(&path(root, Purpose::ArtiCache), &["dir_blobs/consensus"]);
+        fabricate(root, &["audit.log"]);
+        guards
+    }
+
+    #[test]
+    fn migrated_client_finds_its_guards() {
+        let _serial = testing::serial();
+        for copy in [false, true] {
+            let temp = TempDir::new();
+            let from = temp.path().join("old");
+            let to = temp.path().join("encrypted").join("tor");
+            let old_guards = populated(&from);
+
+            if copy {
+                // What a move across filesystems does
+                copy_into_place(&from, &to).unwrap();
+            } else {
+                relocate(&from, &to).unwrap();
+            }
+            assert!(!old_guards.exists() && !from.exists(), "copy: {}", copy);
+
+            // initialize prepares the new directory and hands Arti its state dir
+            prepare(&to).unwrap();
+            let guards = path(&to, Purpose::ArtiState).join("state").join("guards.json");
+            assert_eq!(std::fs::read(&guards).unwrap(), GUARDS, "copy: {}", copy);
+            assert!(exists(&path(&to, Purpose::ArtiCache), "dir_blobs/consensus"), "copy: {}", copy);
+            assert!(exists(&to, "audit.log"), "copy: {}", copy);
+            assert_eq!(version(&to), Some(LAYOUT_VERSION.to_string()), "copy: {}", copy);
+        }
+    }
+
+    #[test]
+    fn failed_copy_rolls_back() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let from = temp.path().join("old");
+        let to = temp.path().join("new");
+        let guards = populated(&from);
+
+        *FAIL_COPY.lock().unwrap() = Some("consensus");
+        let result = copy_into_place(&from, &to);
+        *FAIL_COPY.lock().unwrap() = None;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::StorageFull);
+        // Nothing at the destination, no staging copy, the old tree intact
+        assert!(!to.exists());
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+        assert_eq!(std::fs::read(&guards).unwrap(), GUARDS);
+        assert!(exists(&path(&from, Purpose::ArtiCache), "dir_blobs/consensus"));
+
+        // And the migration can simply be tried again
+        copy_into_place(&from, &to).unwrap();
+        assert_eq!(std::fs::read(path(&to, Purpose::ArtiState).join("state").join("guards.json")).unwrap(), GUARDS);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a single path component")]
+    fn entry_names_are_single_components() {
+        // Serial: the panic hook, once installed, records this panic
+        let _serial = testing::serial();
+        entry(Path::new("/data/wrapper/metrics-history"), "../audit.key");
+    }
+
+    /// Whether `line` derives a path from another (`Path::join` and friends);
+    /// `.join()` on a thread and string joins with a separator don't count
+    fn builds_path(line: &str) -> bool {
+        let derives = [concat!(".with_", "extension("), concat!(".with_", "file_name("), concat!(".set_", "extension(")];
+        if derives.iter().any(|d| line.contains(d)) {
+            return true;
+        }
+        line.match_indices(concat!(".jo", "in(")).any(|(at, call)| {
+            let mut argument = line[at + call.len()..].chars();
+            match (argument.next(), argument.next()) {
+                (Some(')'), _) => false,
+                (Some('"'), Some(c)) => c.is_alphanumeric(),
+                _ => true,
+            }
+        })
+    }
+
+    #[test]
+    fn paths_are_only_built_here() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut offenders = Vec::new();
+        for source in std::fs::read_dir(&src).unwrap() {
+            let file = source.unwrap().path();
+            let name = file.file_name().unwrap().to_string_lossy().into_owned();
+            if !name.ends_with(".rs") || ["layout.rs", "testing.rs"].contains(&name.as_str()) {
+                continue;
+            }
+            let text = std::fs::read_to_string(&file).unwrap();
+            // Tests build paths into their own temporary directories
+            let code = text.split("#[cfg(test)]\nmod tests").next().unwrap();
+            for (number, line) in code.lines().enumerate() {
+                if !line.trim_start().starts_with("//") && builds_path(line) {
+                    offenders.push(format!("{}:{}: {}", name, number + 1, line.trim()));
+                }
+            }
+        }
+        assert!(offenders.is_empty(), "paths built outside layout.rs:\n{}", offenders.join("\n"));
+    }
+
+    #[test]
+    fn the_scan_catches_path_building() {
+        for (line, builds) in [
+            (concat!("let p = dir.jo", "in(\"keystore\");"), true),
+            (concat!("let p = dir.jo", "in(format!(\"{}.json\", n));"), true),
+            (concat!("let p = dir.jo", "in(name);"), true),
+            (concat!("let p = log.with_", "extension(\"1\");"), true),
+            (concat!("thread.jo", "in().unwrap();"), false),
+            (concat!("lines.jo", "in(\",\")"), false),
+            (concat!("hops.jo", "in(\" -> \")"), false),
+        ] {
+            assert_eq!(builds_path(line), builds, "{}", line);
+        }
+    }
+}
@@ -1,15 +1,19 @@
 use jni::JNIEnv;
-use jni::objects::{JClass, JString, JObject, GlobalRef};
-use jni::sys::{jint, jstring};
+use jni::objects::{JByteArray, JClass, JString, JObject};
+use jni::sys::{jboolean, jint, jlong, jstring, JNI_TRUE};
 use jni::JavaVM;
 
-use arti_client::TorClient;
+use arti_client::isolation::IsolationHelper;
+use arti_client::{ErrorKind, HasKind, StreamPrefs, TorClient};
 use arti_client::config::TorClientConfigBuilder;
 use tor_rtcompat::PreferredRuntime;
 
-use std::sync::{Arc, Mutex, Once};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
 use std::path::PathBuf;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures::StreamExt;
 
 // ============================================================================
 // Global State
@@ -21,62 +25,700 @@ static ARTI_CLIENT: Mutex<Option<Arc<TorClient<PreferredRuntime>>>> = Mutex::new
 /// Global Tokio runtime (must persist for Arti to work)
 static TOKIO_RUNTIME: Mutex<Option<tokio::runtime::Runtime>> = Mutex::new(None);
 
+/// A handle to the runtime, taken without keeping the runtime lock, so that
+/// blocking on it (a bootstrap, a probe) never holds up `stop`
+fn runtime_handle() -> Option<tokio::runtime::Handle> {
+    TOKIO_RUNTIME.lock().unwrap().as_ref().map(|rt| rt.handle().clone())
+}
+
 /// Global JavaVM reference (cached on first JNI call)
-static JAVA_VM: Mutex<Option<JavaVM>> = Mutex::new(None);
+static JAVA_VM: OnceLock<JavaVM> = OnceLock::new();
+
+/// Set by `shutdown` (and on unload): the JVM may be detaching threads, so
+/// nothing is sent to Java callbacks and log lines only reach logcat and the
+/// log file
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Longest wait for the log file to catch up at shutdown
+const SHUTDOWN_LOG_FLUSH: std::time::Duration = std::time::Duration::from_millis(500);
 
-/// Global log callback reference
-static LOG_CALLBACK: Mutex<Option<GlobalRef>> = Mutex::new(None);
+/// Whether log lines are written to logcat (off by default in release builds,
+/// where anyone with ADB could read them)
+static LOGCAT_ENABLED: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+/// Current lifecycle state (a `LifecycleState` discriminant)
+static LIFECYCLE_STATE: AtomicI32 = AtomicI32::new(LifecycleState::Stopped as i32);
 
 /// Handle to SOCKS server task (for graceful shutdown)
 static SOCKS_TASK: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
 
+/// Cancels the proxy's in-flight connections on stop (shared across restarts)
+static SOCKS_CANCEL: Mutex<Option<cancel::CancelToken>> = Mutex::new(None);
+
+/// Port the SOCKS listener was last bound to (valid while SOCKS_TASK runs)
+static SOCKS_PORT: AtomicI32 = AtomicI32::new(0);
+
+/// Set while a startSocksProxy call is tearing down, binding or spawning
+static SOCKS_STARTING: AtomicBool = AtomicBool::new(false);
+
 /// Initialization flag
 static INIT_ONCE: Once = Once::new();
 
+/// Data directory the current client was initialized with
+static DATA_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Whether the last initialize succeeded, so its client matches DATA_DIR and
+/// the applied settings (see `reuse_client`)
+static CLIENT_REUSABLE: AtomicBool = AtomicBool::new(false);
+
+/// Whether each destination port gets its own isolated circuit
+static ISOLATE_BY_DEST_PORT: AtomicBool = AtomicBool::new(false);
+
+/// Whether IPv6 CONNECT targets are refused and exits resolve names to IPv4
+static DISABLE_IPV6_TARGETS: AtomicBool = AtomicBool::new(false);
+
+/// Whether a Tor stream must be confirmed open and writable before the SOCKS
+/// success reply is sent
+static CONFIRM_STREAM_WRITABLE: AtomicBool = AtomicBool::new(false);
+
+/// Identity generation (0 until the first newIdentity call)
+static IDENTITY_GENERATION: AtomicU64 = AtomicU64::new(0);
+
 // ============================================================================
 // Logging Integration
 // ============================================================================
 
+/// Call a `(Ljava/lang/String;)V` method on `kind`'s registered Java callback
+fn send_string_to_java(kind: callbacks::Kind, method: &str, value: &str) {
+    #[cfg(test)]
+    tests::SENT_STRINGS.lock().unwrap().push((method.to_string(), value.to_string()));
+    callbacks::invoke(kind, |env, callback| {
+        if let Ok(jvalue) = env.new_string(value) {
+            let _ = env.call_method(
+                callback,
+                method,
+                "(Ljava/lang/String;)V",
+                &[(&jvalue).into()]
+            );
+        }
+    });
+}
+
+/// Set once the missing log callback has been reported (cleared on registration)
+static NO_LOG_CALLBACK_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Whether initialize fails without a log callback (debug builds only)
+static REQUIRE_LOG_CALLBACK: AtomicBool = AtomicBool::new(false);
+
 /// Send log message to Java callback
 fn send_log_to_java(message: String) {
-    let vm_opt = JAVA_VM.lock().unwrap();
-    let callback_opt = LOG_CALLBACK.lock().unwrap();
+    #[cfg(test)]
+    tests::SENT_LOG_LINES.lock().unwrap().push(redact::render(&message).into_owned());
+    #[cfg(test)]
+    if let Some(callback) = tests::MOCK_LOG_CALLBACK.lock().unwrap().as_ref() {
+        return callback.on_log_line(&redact::render(&message), false);
+    }
+    let slot = callbacks::slot(callbacks::Kind::Log);
+    if slot.lock().unwrap().is_none() {
+        warn_no_log_callback();
+        return;
+    }
+    send_string_to_java(callbacks::Kind::Log, "onLogLine", &redact::render(&message));
+}
+
+/// Held while a line goes to the recent-log ring and the Java callback, and
+/// while the log callback is replaced, so every line lands wholly before or
+/// wholly after a replacement
+static LOG_DELIVERY: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    /// Set while this thread holds `LOG_DELIVERY`, so a line it logs
+    /// meanwhile (e.g. from callback registration) doesn't wait on itself
+    static DELIVERING_LOG: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` holding `LOG_DELIVERY` (re-entrant on the holding thread)
+fn with_log_delivery<R>(f: impl FnOnce() -> R) -> R {
+    if DELIVERING_LOG.with(Cell::get) {
+        return f();
+    }
+    let _delivery = LOG_DELIVERY.lock().unwrap();
+    DELIVERING_LOG.with(|held| held.set(true));
+    let result = f();
+    DELIVERING_LOG.with(|held| held.set(false));
+    result
+}
+
+/// Register `callback` as the log callback, at a defined point in the log
+///
+/// Lines delivered before the swap went to the previous callback, lines
+/// after go to the new one, and the previous reference is released once no
+/// line can reach it any more. With `replay_from_ring`, the lines in the
+/// recent-log ring at the swap are delivered to the new callback before any
+/// later line, so each retained line reaches it exactly once.
+fn replace_log_callback(env: &JNIEnv, callback: JObject, replay_from_ring: bool) -> bool {
+    swap_log_callback(
+        || callbacks::register(env, callbacks::Kind::Log, callback),
+        replay_from_ring,
+        |line| send_string_to_java(callbacks::Kind::Log, "onLogLine", line),
+    )
+}
 
-    if let (Some(vm), Some(callback)) = (vm_opt.as_ref(), callback_opt.as_ref()) {
-        if let Ok(mut env) = vm.attach_current_thread() {
-            if let Ok(jmessage) = env.new_string(&message) {
-                let _ = env.call_method(
-                    callback.as_obj(),
-                    "onLogLine",
-                    "(Ljava/lang/String;)V",
-                    &[(&jmessage).into()]
-                );
+/// `replace_log_callback` with the registration and the replay upcall as
+/// parameters: `register` swaps the callback in, `replay` hands it a line
+fn swap_log_callback(register: impl FnOnce() -> bool, replay_from_ring: bool, replay: impl Fn(&str)) -> bool {
+    with_log_delivery(|| {
+        let lines = if replay_from_ring { recent_logs::lines() } else { Vec::new() };
+        let registered = register();
+        if registered {
+            for line in &lines {
+                replay(line);
             }
         }
+        registered
+    })
+}
+
+/// Say once that lines aren't reaching Java
+///
+/// Lines still go to the recent-log ring and the log file (retention
+/// permitting), and to logcat if enabled. The warning itself always goes to
+/// logcat: it carries nothing from the log, and with logcat disabled in
+/// release builds it is the only hint of where the lines went.
+fn warn_no_log_callback() {
+    if SHUTTING_DOWN.load(Ordering::SeqCst) || NO_LOG_CALLBACK_WARNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let warning = "WARNING: no log callback registered (setLogCallback); log lines only reach the recent-log ring (getRecentLogs) and the log file, as retention permits";
+    android_logger::log(&format!("Arti: {}", warning));
+    recent_logs::push(warning);
+    log_file::push(warning);
+}
+
+/// Catch an initialize without `setLogCallback` in debug builds
+///
+/// A forgotten log callback otherwise only shows as logs that never arrive.
+/// Debug builds say so in logcat at every initialize, whatever the logcat
+/// setting, and with `require_log_callback` set the initialize fails fast.
+/// Release builds stay silent. Returns whether initialize may go on.
+fn check_log_callback() -> bool {
+    let registered = callbacks::slot(callbacks::Kind::Log).lock().unwrap().is_some();
+    check_log_callback_in(cfg!(debug_assertions), registered)
+}
+
+/// `check_log_callback` in a debug or release build
+fn check_log_callback_in(debug_build: bool, registered: bool) -> bool {
+    if !debug_build || registered {
+        return true;
+    }
+    android_logger::log("Arti: WARNING: initialize called before setLogCallback; this app receives none of the native log lines (debug builds only)");
+    !REQUIRE_LOG_CALLBACK.load(Ordering::Relaxed)
+}
+
+/// Send lifecycle state change to Java callback
+fn send_state_to_java(state: LifecycleState) {
+    #[cfg(test)]
+    tests::SENT_STATES.lock().unwrap().push(state);
+    callbacks::invoke(callbacks::Kind::State, |env, callback| {
+        let _ = env.call_method(
+            callback,
+            "onStateChanged",
+            "(I)V",
+            &[(state as jint).into()]
+        );
+    });
+}
+
+/// Deliver a log line to every sink (logcat, recent-log ring, log file, Java callback)
+fn emit_log(msg: String) {
+    if LOGCAT_ENABLED.load(Ordering::Relaxed) {
+        android_logger::log(&format!("Arti: {}", redact::render(&msg)));
+    }
+    log_file::push(&msg);
+    // The JVM may be detaching threads; don't go near the callback
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        recent_logs::push(&msg);
+        return;
     }
+    // A log callback stuck in an upcall on another thread holds
+    // `LOG_DELIVERY`; don't queue up behind it
+    if callbacks::stalled(callbacks::Kind::Log) && !DELIVERING_LOG.with(Cell::get) {
+        recent_logs::push(&msg);
+        callbacks::count_dropped(callbacks::Kind::Log);
+        return;
+    }
+    with_log_delivery(|| {
+        recent_logs::push(&msg);
+        let admission = log_rate::admit(&msg);
+        if admission.dropped_before > 0 {
+            send_log_to_java(format!("{} log line(s) dropped by the rate limit", admission.dropped_before));
+        }
+        if admission.deliver {
+            send_log_to_java(msg);
+        }
+    });
 }
 
 /// Macro for logging to both Android logcat and Java callback
 macro_rules! log_info {
     ($($arg:tt)*) => {{
-        let msg = format!($($arg)*);
-        android_logger::log(&format!("Arti: {}", msg));
-        send_log_to_java(msg);
+        $crate::emit_log(format!($($arg)*));
     }};
 }
 
 macro_rules! log_error {
     ($($arg:tt)*) => {{
-        let msg = format!("ERROR: {}", format!($($arg)*));
-        android_logger::log(&format!("Arti: {}", msg));
-        send_log_to_java(msg);
+        $crate::emit_log(format!("ERROR: {}", format!($($arg)*)));
     }};
 }
 
+mod actor;
+mod address_family;
+mod api;
+mod arti_config;
+mod audit;
+mod backoff;
+mod battery;
+mod bootstrap;
+mod bootstrap_history;
+mod bridge_health;
+mod bridges;
+mod buffers;
+mod bypass;
+mod caches;
+mod capabilities;
+mod callbacks;
+mod cancel;
+mod censorship;
+mod chaos;
+mod circuit_path;
+mod config;
+mod consensus_age;
+mod connections;
+mod debounce;
+mod diagnostics;
+mod dir_retry;
+mod diversity;
+mod doze;
+mod entry_watch;
+mod exits;
+mod features;
+mod flow_stall;
+mod geo;
+mod guards;
+mod isolation_groups;
+mod layout;
+mod listener_auth;
+mod listener_exit;
+mod liveness;
+mod log_file;
+mod log_rate;
+mod metrics_history;
+mod net;
+mod network_token;
+mod notification;
+mod onion_auth;
+#[cfg(feature = "onion-service-hosting")]
+mod onion_key;
+mod policy;
+mod port_hints;
+mod prewarm;
+#[cfg(feature = "metrics-endpoint")]
+mod prometheus;
+mod proxy_watchdog;
+mod quality;
+mod query;
+mod reachability;
+mod recent_logs;
+mod reconcile;
+mod redact;
+mod reliability;
+mod retention;
+mod seams;
+mod security;
+mod shutdown;
+mod smoke;
+mod socks;
+mod stall;
+mod state_lock;
+mod storage;
+mod target;
+#[cfg(test)]
+mod testing;
+mod traffic;
+
+// ============================================================================
+// JSON Helpers
+// ============================================================================
+
+/// Encode a string as a JSON string literal (including the quotes)
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// ============================================================================
+// Lifecycle State
+// ============================================================================
+
+/// Lifecycle states reported to Java
+///
+/// The discriminants are part of the JNI contract (mirrored as `STATE_*`
+/// constants in `ArtiNative.kt`), so never renumber existing variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+enum LifecycleState {
+    Stopped = 0,
+    Initialized = 1,
+    Starting = 2,
+    Stopping = 3,
+    Running = 4,
+    /// The data directory's storage disappeared (see storage.rs)
+    StorageLost = 5,
+    /// Running on a consensus past its validity plus grace (see consensus_age.rs)
+    DegradedStaleDirectory = 6,
+}
+
+impl LifecycleState {
+    fn name(self) -> &'static str {
+        match self {
+            LifecycleState::Stopped => "Stopped",
+            LifecycleState::Initialized => "Initialized",
+            LifecycleState::Starting => "Starting",
+            LifecycleState::Stopping => "Stopping",
+            LifecycleState::Running => "Running",
+            LifecycleState::StorageLost => "StorageLost",
+            LifecycleState::DegradedStaleDirectory => "DegradedStaleDirectory",
+        }
+    }
+
+    /// Inverse of the discriminant (unknown values map to `Stopped`)
+    fn from_i32(value: i32) -> Self {
+        match value {
+            1 => LifecycleState::Initialized,
+            2 => LifecycleState::Starting,
+            3 => LifecycleState::Stopping,
+            4 => LifecycleState::Running,
+            5 => LifecycleState::StorageLost,
+            6 => LifecycleState::DegradedStaleDirectory,
+            _ => LifecycleState::Stopped,
+        }
+    }
+}
+
+/// Returned by operations that need a running client when there is none
+///
+/// Unlike settings, these can't be recorded for later (see config.rs).
+const ERR_NOT_RUNNING: jint = -1;
+
+/// Returned by functions of a capability this build lacks (see features.rs)
+const ERR_FEATURE_DISABLED: jint = -100;
+
+/// Returned by initialize when the existing client was reused (warm restart)
+const INIT_REUSED: jint = 1;
+
+/// Returned by initialize in debug builds requiring a log callback without one
+const INIT_ERR_NO_LOG_CALLBACK: jint = -12;
+
+/// Circuit reuse window bounds in seconds (Arti's default is 600)
+///
+/// Below the minimum nearly every stream would build its own circuit; above
+/// the maximum a circuit would outlive several consensus periods.
+pub(crate) const MIN_CIRCUIT_DIRTINESS_SECS: u64 = 10;
+pub(crate) const MAX_CIRCUIT_DIRTINESS_SECS: u64 = 24 * 60 * 60;
+pub(crate) const DEFAULT_CIRCUIT_DIRTINESS_SECS: u64 = 600;
+
+/// Record a lifecycle transition and report it to Java
+///
+/// The state callback is authoritative and called once per transition:
+/// setting the state it is already in does nothing. The "AMEx: state changed
+/// to X" log line is kept for humans reading logcat.
+fn set_state(state: LifecycleState) {
+    if LIFECYCLE_STATE.swap(state as i32, Ordering::SeqCst) == state as i32 {
+        return;
+    }
+    BOOTSTRAP_STATUS.invalidate();
+    log_info!("AMEx: state changed to {}", state.name());
+    reliability::state_changed(state);
+    send_state_to_java(state);
+    notification::update(|inputs| {
+        inputs.state = state;
+        inputs.since_epoch_ms = notification::now_epoch_ms();
+    });
+}
+
+// ============================================================================
+// Stream Isolation
+// ============================================================================
+
+/// Everything a stream's isolation is derived from.
+///
+/// Each isolation mode contributes one field; modes that are disabled leave
+/// their field as `None`. Two streams may only share a circuit when their keys
+/// are equal, so enabled modes compose: with port isolation plus any other mode,
+/// streams are separated by port *and* by that other mode.
+///
+/// A destination that goes by a name other than the one dialed (a `host=`
+/// claim, a front, an onion alias; see target.rs) always adds that name, so
+/// two names pre-resolved to one shared address never share a circuit.
+///
+/// The key is itself the stream's Arti isolation (see `IsolationHelper`
+/// below), so a connect derives it without a shared table or a lock.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+struct IsolationKey {
+    /// Bumped by newIdentity so no later stream reuses an earlier circuit
+    identity: u64,
+    dest_port: Option<u16>,
+    /// `Destination::claimed_host`
+    claimed_host: Option<String>,
+    /// Current network token and, once it has gone stale, its stale era
+    network: Option<(String, Option<u64>)>,
+    /// Bumped when an exit lacked a required flag (exits.rs)
+    exits: u64,
+    /// The client's traffic class (`class=`, target.rs)
+    class: Option<String>,
+    /// The client's isolation token (`iso=`, target.rs)
+    token: Option<String>,
+}
+
+impl IsolationKey {
+    /// Derive the key for a CONNECT to `target_port` (for `claimed_host`,
+    /// if any) from the enabled modes
+    fn for_target(target_port: u16, claimed_host: Option<&str>) -> Self {
+        IsolationKey {
+            identity: IDENTITY_GENERATION.load(Ordering::Relaxed),
+            dest_port: ISOLATE_BY_DEST_PORT
+                .load(Ordering::Relaxed)
+                .then_some(target_port),
+            claimed_host: claimed_host.map(str::to_ascii_lowercase),
+            network: network_token::isolation(),
+            exits: exits::generation(),
+            class: None,
+            token: None,
+        }
+    }
+
+    /// The key for a CONNECT as `for_target`, plus what the client's
+    /// `credentials` ask for
+    fn for_connect(target_port: u16, claimed_host: Option<&str>, credentials: &target::ConnectCredentials) -> Self {
+        IsolationKey {
+            class: credentials.class.clone(),
+            token: credentials.isolation.clone(),
+            ..IsolationKey::for_target(target_port, claimed_host)
+        }
+    }
+
+    /// The key's components for `listIsolationGroups`, network token scrubbed
+    fn describe(&self) -> serde_json::Value {
+        if *self == IsolationKey::default() {
+            return serde_json::json!("default");
+        }
+        serde_json::json!({
+            "identity": self.identity,
+            "dest_port": self.dest_port,
+            "claimed_host": self.claimed_host.as_deref().map(isolation_groups::scrub),
+            "network": self.network.as_ref().map(|(token, _)| isolation_groups::scrub(token)),
+            "stale_era": self.network.as_ref().and_then(|(_, era)| *era),
+            "exits": self.exits,
+            "class": self.class,
+            "token": self.token.as_deref().map(isolation_groups::scrub),
+        })
+    }
+}
+
+/// Build stream preferences for a CONNECT, applying the derived isolation.
+///
+/// With IPv6 targets disabled, exits connect to hostnames over IPv4 only.
+fn stream_prefs_for(key: &IsolationKey) -> StreamPrefs {
+    let mut prefs = StreamPrefs::new();
+    if DISABLE_IPV6_TARGETS.load(Ordering::Relaxed) {
+        prefs.ipv4_only();
+    }
+    if let Some(isolation) = isolation(key) {
+        prefs.set_isolation(isolation);
+    }
+    prefs
+}
+
+/// The isolation for streams with `key`
+///
+/// None for a key with no enabled components: those streams keep Arti's
+/// default isolation.
+fn isolation(key: &IsolationKey) -> Option<IsolationKey> {
+    (*key != IsolationKey::default()).then(|| key.clone())
+}
+
+/// Streams may share a circuit exactly when their keys are equal
+impl IsolationHelper for IsolationKey {
+    fn compatible_same_type(&self, other: &Self) -> bool {
+        self == other
+    }
+
+    fn join_same_type(&self, other: &Self) -> Option<Self> {
+        (self == other).then(|| self.clone())
+    }
+}
+
+// ============================================================================
+// Metrics
+// ============================================================================
+
+/// Wrapper-level counters
+struct Metrics {
+    /// Direct connections attempted (and refused) while in strict Tor-only mode
+    strict_mode_violations: AtomicU64,
+    /// Payload bytes relayed in either direction since process start
+    bytes_relayed: AtomicU64,
+    /// Security events reported (see security.rs)
+    security_events: AtomicU64,
+    /// CONNECTs established through Tor, and the total time from dialing
+    /// until the client could be answered (the connect latency)
+    connects_established: AtomicU64,
+    connect_ms_total: AtomicU64,
+    /// CONNECTs whose Tor connect failed or exceeded the client's timeout
+    connect_failures: AtomicU64,
+    /// Streams confirmed writable before the success reply, and the total
+    /// time that took (the latency the confirmation adds)
+    stream_confirmations: AtomicU64,
+    stream_confirm_ms_total: AtomicU64,
+    /// Streams that connected but failed confirmation (answered with a SOCKS error)
+    stream_confirm_failures: AtomicU64,
+    /// CONNECTs dialed as the onion alias of the requested host
+    onion_upgrades: AtomicU64,
+    /// CONNECTs to IPv6 targets refused while IPv6 targets are disabled
+    ipv6_targets_refused: AtomicU64,
+    /// Policy requests not answered within the budget (default verdict applied)
+    policy_timeouts: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    strict_mode_violations: AtomicU64::new(0),
+    bytes_relayed: AtomicU64::new(0),
+    security_events: AtomicU64::new(0),
+    connects_established: AtomicU64::new(0),
+    connect_ms_total: AtomicU64::new(0),
+    connect_failures: AtomicU64::new(0),
+    stream_confirmations: AtomicU64::new(0),
+    stream_confirm_ms_total: AtomicU64::new(0),
+    stream_confirm_failures: AtomicU64::new(0),
+    onion_upgrades: AtomicU64::new(0),
+    ipv6_targets_refused: AtomicU64::new(0),
+    policy_timeouts: AtomicU64::new(0),
+};
+
+impl Metrics {
+    /// Zero every counter
+    fn reset(&self) {
+        for counter in [
+            &self.strict_mode_violations,
+            &self.bytes_relayed,
+            &self.security_events,
+            &self.connects_established,
+            &self.connect_ms_total,
+            &self.connect_failures,
+            &self.stream_confirmations,
+            &self.stream_confirm_ms_total,
+            &self.stream_confirm_failures,
+            &self.onion_upgrades,
+            &self.ipv6_targets_refused,
+            &self.policy_timeouts,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        flow_stall::reset();
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"strict_tor_only\":{},\"strict_mode_violations\":{},\"bytes_relayed\":{},\"buffer_bytes_in_use\":{},\"security_events\":{},\"client_stalled_closes\":{},\"connects_established\":{},\"connect_ms_total\":{},\"connect_failures\":{},\"stream_confirmations\":{},\"stream_confirm_ms_total\":{},\"stream_confirm_failures\":{},\"onion_upgrades\":{},\"ipv6_targets_refused\":{},\"policy_timeouts\":{},\"no_usable_guards\":{},\"entry_suspicions\":{},\"stale_query_answers\":{},\"query_cache_hits\":{},\"query_cache_misses\":{},\"query_cache_coalesced\":{},\"log_file_dropped_lines\":{},\"log_callback_dropped_lines\":{},{},{},\"user_bytes\":{},\"directory_bytes\":{},\"directory_bytes_by_month\":{}}}",
+            net::strict_tor_only(),
+            self.strict_mode_violations.load(Ordering::Relaxed),
+            self.bytes_relayed.load(Ordering::Relaxed),
+            buffers::in_use(),
+            self.security_events.load(Ordering::Relaxed),
+            stall::closes(),
+            self.connects_established.load(Ordering::Relaxed),
+            self.connect_ms_total.load(Ordering::Relaxed),
+            self.connect_failures.load(Ordering::Relaxed),
+            self.stream_confirmations.load(Ordering::Relaxed),
+            self.stream_confirm_ms_total.load(Ordering::Relaxed),
+            self.stream_confirm_failures.load(Ordering::Relaxed),
+            self.onion_upgrades.load(Ordering::Relaxed),
+            self.ipv6_targets_refused.load(Ordering::Relaxed),
+            self.policy_timeouts.load(Ordering::Relaxed),
+            guards::unusable(),
+            entry_watch::suspicions(),
+            query::stale_answers(),
+            query::cache_hits(),
+            query::cache_misses(),
+            query::coalesced(),
+            log_file::dropped_lines(),
+            log_rate::dropped_lines(),
+            network_token::metrics_json(),
+            flow_stall::metrics_json(),
+            self.bytes_relayed.load(Ordering::Relaxed),
+            traffic::directory_bytes(),
+            traffic::months_json(),
+        )
+    }
+}
+
 // ============================================================================
 // JNI Functions
 // ============================================================================
 
+/// Cheap liveness probe for the Kotlin watchdog
+///
+/// Never locks and never dispatches onto the runtime; see liveness.rs for the
+/// packed layout and the staleness rules to apply to each field.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_ping(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    liveness::ping()
+}
+
+/// Describe the JSON envelope and every JSON-returning function's payload
+///
+/// See api.rs; the description itself comes in the envelope too.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_describeApi(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&api::describe_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the optional Arti features this library was built with as JSON
+///
+/// Read from this crate's cargo features, which are the only way those Arti
+/// features get enabled (see Cargo.toml), so the answer is authoritative.
+/// Functions of a missing feature return `ERR_FEATURE_DISABLED` or a
+/// `feature_disabled` error (see features.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getLinkedFeatures(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&features::linked_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
 /// Get Arti version string
 #[no_mangle]
 pub extern "C" fn Java_org_torproject_arti_ArtiNative_getVersion(
@@ -84,9 +726,9 @@ pub extern "C" fn Java_org_torproject_arti_ArtiNative_getVersion(
     _class: JClass,
 ) -> jstring {
     // Cache JavaVM on first call
-    if JAVA_VM.lock().unwrap().is_none() {
+    if JAVA_VM.get().is_none() {
         if let Ok(vm) = env.get_java_vm() {
-            *JAVA_VM.lock().unwrap() = Some(vm);
+            let _ = JAVA_VM.set(vm);
         }
     }
 
@@ -102,392 +744,5231 @@ pub extern "C" fn Java_org_torproject_arti_ArtiNative_setLogCallback(
     _class: JClass,
     callback: JObject,
 ) {
-    // Cache JavaVM if not already cached
-    if JAVA_VM.lock().unwrap().is_none() {
-        if let Ok(vm) = env.get_java_vm() {
-            *JAVA_VM.lock().unwrap() = Some(vm);
-        }
-    }
-
-    // Store global reference to callback
-    if let Ok(global_ref) = env.new_global_ref(callback) {
-        *LOG_CALLBACK.lock().unwrap() = Some(global_ref);
+    if replace_log_callback(&env, callback, false) {
+        NO_LOG_CALLBACK_WARNED.store(false, Ordering::Relaxed);
         log_info!("Log callback registered");
     }
 }
 
-/// Initialize Arti runtime
+/// Replace the log callback, optionally replaying the recent-log ring to it
+///
+/// For a recreated log collector: with `replay_from_ring`, the new callback
+/// first receives every line retained in the ring (see `getRecentLogs`),
+/// then every later line, with nothing missed or repeated across the swap.
 #[no_mangle]
-pub extern "C" fn Java_org_torproject_arti_ArtiNative_initialize(
-    mut env: JNIEnv,
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setLogCallbackWithReplay(
+    env: JNIEnv,
     _class: JClass,
-    data_dir: JString,
-) -> jint {
-    // Cache JavaVM if not already cached
-    if JAVA_VM.lock().unwrap().is_none() {
-        if let Ok(vm) = env.get_java_vm() {
-            *JAVA_VM.lock().unwrap() = Some(vm);
-        }
+    callback: JObject,
+    replay_from_ring: jboolean,
+) {
+    if replace_log_callback(&env, callback, replay_from_ring == JNI_TRUE) {
+        NO_LOG_CALLBACK_WARNED.store(false, Ordering::Relaxed);
+        log_info!("Log callback registered{}", if replay_from_ring == JNI_TRUE { " (ring replayed)" } else { "" });
     }
+}
 
-    let data_dir_str: String = match env.get_string(&data_dir) {
-        Ok(s) => s.into(),
-        Err(e) => {
-            log_error!("Failed to convert data_dir: {:?}", e);
-            return -1;
-        }
-    };
-
-    log_info!("AMEx: state changed to Initialized");
-    log_info!("Initializing Arti with data directory: {}", data_dir_str);
-
-    // Initialize Tokio runtime (once)
-    INIT_ONCE.call_once(|| {
-        match tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-        {
-            Ok(rt) => {
-                log_info!("Tokio runtime created successfully");
-                *TOKIO_RUNTIME.lock().unwrap() = Some(rt);
-            }
-            Err(e) => {
-                log_error!("Failed to create Tokio runtime: {:?}", e);
-            }
-        }
-    });
-
-    // Check if runtime exists
-    let runtime_guard = TOKIO_RUNTIME.lock().unwrap();
-    let runtime = match runtime_guard.as_ref() {
-        Some(rt) => rt,
-        None => {
-            log_error!("Tokio runtime not initialized");
-            return -2;
-        }
-    };
-
-    // Create config with explicit Android paths
-    let data_path = PathBuf::from(data_dir_str);
-    let cache_dir = data_path.join("cache");
-    let state_dir = data_path.join("state");
-
-    // Create directories if they don't exist
-    std::fs::create_dir_all(&cache_dir).ok();
-    std::fs::create_dir_all(&state_dir).ok();
-
-    let result: Result<()> = runtime.block_on(async {
-        log_info!("Creating Arti client...");
-        log_info!("Cache dir: {:?}", cache_dir);
-        log_info!("State dir: {:?}", state_dir);
-
-        // Create config with Android-specific directories
-        let config = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
-            .build()?;
+/// Enable or disable writing log lines to logcat
+///
+/// The Java callback and the recent-log ring keep receiving lines either way.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setLogcatEnabled(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let _ = config::set("logcat_enabled", serde_json::json!(enabled == JNI_TRUE));
+}
 
-        // Create client with Android-specific config
-        let client = TorClient::create_bootstrapped(config).await?;
+/// Enable or disable safe logging (on by default)
+///
+/// With it on, destination hosts and peer addresses in log lines read
+/// `[scrubbed]` in every sink. The policy is applied as each sink emits a
+/// line, so a change covers lines already logged but not yet emitted (see
+/// redact.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setSafeLogging(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let _ = config::set("safe_logging", serde_json::json!(enabled == JNI_TRUE));
+}
 
-        log_info!("Arti client created successfully");
+/// Make initialize fail fast without a log callback, in debug builds
+///
+/// Debug builds always warn in logcat when initialize runs before
+/// setLogCallback; with this set, initialize also returns -12 without doing
+/// anything. No effect in release builds.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setRequireLogCallback(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let _ = config::set("require_log_callback", serde_json::json!(enabled == JNI_TRUE));
+}
 
-        // Store client globally
-        *ARTI_CLIENT.lock().unwrap() = Some(Arc::new(client));
+/// Set state callback for lifecycle transitions
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setStateCallback(
+    env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) {
+    if callbacks::register(&env, callbacks::Kind::State, callback) {
+        log_info!("State callback registered");
+    }
+}
 
-        Ok(())
-    });
+/// Set callback for notification status changes (throttled to one call per 5s)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setNotificationStatusCallback(
+    env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) {
+    if callbacks::register(&env, callbacks::Kind::NotificationStatus, callback) {
+        log_info!("Notification status callback registered");
+    }
+}
 
-    match result {
-        Ok(_) => {
-            log_info!("Arti initialized successfully");
-            0
-        }
-        Err(e) => {
-            log_error!("Failed to initialize Arti: {:?}", e);
-            -3
-        }
+/// Set callback for network condition changes (see censorship.rs)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setNetworkConditionCallback(
+    env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) {
+    if callbacks::register(&env, callbacks::Kind::NetworkCondition, callback) {
+        log_info!("Network condition callback registered");
     }
 }
 
-/// Start SOCKS proxy on specified port
+/// Set the minutes between background reachability probes (0 turns them off)
+///
+/// Returns 0 on success, -1 outside 0..=1440.
 #[no_mangle]
-pub extern "C" fn Java_org_torproject_arti_ArtiNative_startSocksProxy(
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setCensorshipMonitorInterval(
     _env: JNIEnv,
     _class: JClass,
-    port: jint,
+    minutes: jint,
 ) -> jint {
-    log_info!("AMEx: state changed to Starting");
-    log_info!("Starting SOCKS proxy on port {}", port);
-
-    // Stop any existing SOCKS server first
-    if let Some(handle) = SOCKS_TASK.lock().unwrap().take() {
-        log_info!("Aborting previous SOCKS server task");
-        handle.abort();
+    if let Err(e) = config::set("censorship_monitor_interval_mins", serde_json::json!(minutes)) {
+        log_error!("Invalid censorship monitor interval: {}", e);
+        return -1;
     }
+    log_info!("Censorship monitor interval set to {} min", minutes);
+    0
+}
 
-    let client_guard = ARTI_CLIENT.lock().unwrap();
-    let client = match client_guard.as_ref() {
-        Some(c) => Arc::clone(c),
-        None => {
+/// Set what happens once the consensus is older than its validity plus
+/// `grace_minutes`: 0 nothing (Arti's behaviour), 1 report a degraded state,
+/// 2 also refuse new connections (see consensus_age.rs)
+///
+/// Returns 0 on success, -1 if `mode` or `grace_minutes` is out of range.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setMaxConsensusAge(
+    _env: JNIEnv,
+    _class: JClass,
+    mode: jint,
+    grace_minutes: jint,
+) -> jint {
+    if let Err(e) = config::set("max_consensus_age_grace_mins", serde_json::json!(grace_minutes)) {
+        log_error!("Invalid consensus age grace: {}", e);
+        return -1;
+    }
+    if let Err(e) = config::set("max_consensus_age_mode", serde_json::json!(mode)) {
+        log_error!("Invalid consensus age mode: {}", e);
+        return -1;
+    }
+    log_info!("Max consensus age: mode {}, grace {} min", mode, grace_minutes);
+    0
+}
+
+/// Set callback for high-priority security events (see security.rs)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setSecurityEventCallback(
+    env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) {
+    if callbacks::register(&env, callbacks::Kind::SecurityEvent, callback) {
+        log_info!("Security event callback registered");
+    }
+}
+
+/// Set the connection policy callback (null to remove it; see policy.rs)
+///
+/// While set, each CONNECT that passes the native checks waits for
+/// `submitPolicyVerdict` within the policy budget.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setConnectionPolicyCallback(
+    env: JNIEnv,
+    _class: JClass,
+    callback: JObject,
+) {
+    if callbacks::register(&env, callbacks::Kind::ConnectionPolicy, callback) {
+        log_info!("Connection policy callback registered");
+    } else {
+        log_info!("Connection policy callback removed");
+    }
+}
+
+/// Answer the policy request `id` from `onConnectionRequest`
+///
+/// `annotation` is a JSON value attached to the connection's log lines (null
+/// or empty for none). Returns 0 if the connection took the verdict, -1 if the
+/// request is no longer waiting (answered, timed out or unknown), -2 if the
+/// annotation isn't valid JSON.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_submitPolicyVerdict(
+    mut env: JNIEnv,
+    _class: JClass,
+    id: jlong,
+    allow: jboolean,
+    annotation: JString,
+) -> jint {
+    let annotation: String = if annotation.is_null() {
+        String::new()
+    } else {
+        match env.get_string(&annotation) {
+            Ok(s) => s.into(),
+            Err(e) => {
+                log_error!("Failed to convert policy annotation: {:?}", e);
+                return -2;
+            }
+        }
+    };
+    match policy::submit(id as u64, allow == JNI_TRUE, &annotation) {
+        Ok(()) => 0,
+        Err(policy::Refused::UnknownId) => {
+            log_info!("Policy verdict for request {} arrived after it stopped waiting", id);
+            -1
+        }
+        Err(policy::Refused::InvalidAnnotation) => {
+            log_error!("Policy verdict for request {} has an invalid annotation", id);
+            -2
+        }
+    }
+}
+
+/// Set how long a connection waits for its policy verdict
+///
+/// Returns 0 on success, -1 outside 1..=200 ms.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setPolicyBudgetMs(
+    _env: JNIEnv,
+    _class: JClass,
+    millis: jint,
+) -> jint {
+    if let Err(e) = config::set("policy_budget_ms", serde_json::json!(millis)) {
+        log_error!("Invalid policy budget: {}", e);
+        return -1;
+    }
+    log_info!("Policy budget set to {} ms", millis);
+    0
+}
+
+/// Set the verdict applied when the policy callback doesn't answer in time
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setPolicyDefaultAllow(
+    _env: JNIEnv,
+    _class: JClass,
+    allow: jboolean,
+) {
+    let allow = allow == JNI_TRUE;
+    let _ = config::set("policy_default_allow", serde_json::json!(allow));
+    log_info!("Unanswered policy requests are {} by default", if allow { "allowed" } else { "denied" });
+}
+
+/// Report whether the battery is low (from battery-saver broadcasts)
+///
+/// While low, the SOCKS proxy stops accepting new connections and the client
+/// goes dormant; existing connections continue. See battery.rs.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_onBatteryLow(
+    _env: JNIEnv,
+    _class: JClass,
+    low: jboolean,
+) {
+    let low = low == JNI_TRUE;
+    if !battery::set_low(low) {
+        return;
+    }
+    let client = ARTI_CLIENT.lock().unwrap().clone();
+    if let Some(client) = client {
+        battery::apply_dormancy(&client);
+    }
+    if low {
+        log_info!("Battery low: pausing new connections, client dormant");
+    } else {
+        log_info!("Battery no longer low: accepting connections, client active");
+    }
+}
+
+/// Tell the wrapper the device entered Doze: the client goes dormant until
+/// `onDozeExit` (see doze.rs)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_onDozeEnter(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    if !doze::enter() {
+        return;
+    }
+    let client = ARTI_CLIENT.lock().unwrap().clone();
+    if let Some(client) = client {
+        battery::apply_dormancy(&client);
+    }
+    log_info!("Doze entered: client dormant");
+}
+
+/// Tell the wrapper the device left Doze: the client wakes and, after a long
+/// Doze, its circuits are replaced (see doze.rs)
+///
+/// Returns 1 if circuits were retired, 0 if they were kept, ERR_NOT_RUNNING
+/// if the client is not initialized.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_onDozeExit(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    let dozed_ms = doze::exit();
+    let Some(client) = ARTI_CLIENT.lock().unwrap().clone() else {
+        return ERR_NOT_RUNNING;
+    };
+    battery::apply_dormancy(&client);
+    let refreshed = doze::refresh_circuits(&client, dozed_ms);
+    let dozed = dozed_ms.map_or_else(|| "unknown time".to_string(), |ms| format!("{} s", ms / 1000));
+    if refreshed {
+        log_info!("Doze exited after {}: circuits retired, fresh ones will be built", dozed);
+    } else {
+        log_info!("Doze exited after {}: circuits kept", dozed);
+    }
+    refreshed as jint
+}
+
+/// Get the live callback registrations as JSON (for debugging ref leaks)
+///
+/// `{"live":..,"releases":..,"kinds":{"log":{"registered":..,"registrations":..,
+/// "healthy":..,"slow_calls":..,"dropped":..},..}}`; see callbacks.rs for the
+/// upcall watchdog behind the last three.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getCallbackRegistrations(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&callbacks::registrations_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Release every callback reference when the library is unloaded
+#[no_mangle]
+pub extern "system" fn JNI_OnUnload(_vm: *mut jni::sys::JavaVM, _reserved: *mut std::ffi::c_void) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    log_file::flush(SHUTDOWN_LOG_FLUSH);
+    callbacks::release_all();
+}
+
+/// Get a consistent one-line status for the foreground-service notification
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getNotificationStatus(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&notification::render())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Bootstrap status before any client has reported one
+fn bootstrap_not_started() -> serde_json::Map<String, serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    object.insert("percent".to_string(), serde_json::json!(0));
+    object.insert("ready_for_traffic".to_string(), serde_json::json!(false));
+    object.insert("blocked".to_string(), serde_json::Value::Null);
+    object.insert("description".to_string(), serde_json::Value::Null);
+    object.insert("directory_fetch_attempts".to_string(), serde_json::json!(0));
+    object.insert("degraded".to_string(), serde_json::Value::Null);
+    object
+}
+
+static BOOTSTRAP_STATUS: query::Query = query::Query::new("Bootstrap status", bootstrap_not_started);
+
+fn bootstrap_status_json(fresh: bool) -> String {
+    BOOTSTRAP_STATUS.run(fresh, || {
+        let client = ARTI_CLIENT.lock().unwrap().as_ref().map(Arc::clone);
+        async move {
+            let Some(client) = client else {
+                return bootstrap_not_started();
+            };
+            let status = client.bootstrap_status();
+            let mut object = serde_json::Map::new();
+            object.insert("percent".to_string(), serde_json::json!((status.as_frac() * 100.0) as u8));
+            object.insert("ready_for_traffic".to_string(), serde_json::json!(status.ready_for_traffic()));
+            object.insert("blocked".to_string(), serde_json::json!(status.blocked().map(|b| b.to_string())));
+            object.insert("description".to_string(), serde_json::json!(status.to_string()));
+            object.insert("directory_fetch_attempts".to_string(), serde_json::json!(dir_retry::attempts()));
+            object.insert("degraded".to_string(), serde_json::json!(consensus_age::degraded_marker()));
+            object
+        }
+    })
+}
+
+/// Get Arti's bootstrap progress as JSON
+///
+/// `{"percent":..,"ready_for_traffic":..,"blocked":..,"description":..,"directory_fetch_attempts":..,"degraded":..,"stale":..}`;
+/// answered within the query budget, from the last snapshot (`stale: true`)
+/// if the runtime is too busy, and shared between callers within the cache
+/// TTL (see query.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getBootstrapStatus(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&bootstrap_status_json(false))).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Like `getBootstrapStatus`, but never answered from the cache
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getBootstrapStatusFresh(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&bootstrap_status_json(true))).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set the time budget of runtime-backed status queries
+///
+/// A query that doesn't complete in time answers from its last snapshot,
+/// marked `"stale": true` (see query.rs). Returns 0 on success, -1 if outside
+/// 10..=10000 ms.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setQueryTimeoutMs(
+    _env: JNIEnv,
+    _class: JClass,
+    ms: jint,
+) -> jint {
+    if let Err(e) = config::set("query_timeout_ms", serde_json::json!(ms)) {
+        log_error!("Invalid query timeout: {}", e);
+        return -1;
+    }
+    log_info!("Query timeout set to {} ms", ms);
+    0
+}
+
+/// Set how long a status query result is served to later callers
+///
+/// Callers within the TTL share one result, and callers during a
+/// computation share that computation (see query.rs). 0 disables the cache.
+/// Returns 0 on success, -1 if outside 0..=60000 ms.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setQueryCacheTtlMs(
+    _env: JNIEnv,
+    _class: JClass,
+    ms: jint,
+) -> jint {
+    if let Err(e) = config::set("query_cache_ttl_ms", serde_json::json!(ms)) {
+        log_error!("Invalid query cache TTL: {}", e);
+        return -1;
+    }
+    log_info!("Query cache TTL set to {} ms", ms);
+    0
+}
+
+/// Set the window within which start/stop commands are coalesced
+///
+/// A stop with the proxy running waits this long for a matching start that
+/// takes it back (see debounce.rs). 0 disables debouncing, so every command
+/// runs in full. Returns 0 on success, -1 if outside 0..=10000 ms.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setLifecycleDebounceMs(
+    _env: JNIEnv,
+    _class: JClass,
+    ms: jint,
+) -> jint {
+    if let Err(e) = config::set("lifecycle_debounce_ms", serde_json::json!(ms)) {
+        log_error!("Invalid lifecycle debounce window: {}", e);
+        return -1;
+    }
+    log_info!("Lifecycle debounce window set to {} ms", ms);
+    0
+}
+
+/// Give every destination port its own isolated circuit
+///
+/// Composes with any other isolation mode: streams only share a circuit when
+/// they agree on the port and on every other enabled isolation component.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setIsolateByDestinationPort(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled == JNI_TRUE;
+    let _ = config::set("isolate_by_dest_port", serde_json::json!(enabled));
+    log_info!("Isolation by destination port {}", if enabled { "enabled" } else { "disabled" });
+}
+
+/// Start a new identity: later streams never share circuits with earlier ones
+///
+/// Also clears per-destination quality scores (linkable history) unless
+/// persistent quality tracking is enabled.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_newIdentity(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    IDENTITY_GENERATION.fetch_add(1, Ordering::Relaxed);
+    quality::reset_for_new_identity();
+    log_info!("New identity: later streams use fresh circuits");
+}
+
+/// List the isolation groups with live streams as JSON
+///
+/// Each group is a distinct isolation key, with the network token and
+/// claimed host scrubbed,
+/// and reports its live streams and the distinct circuits carrying them. At
+/// most 64 groups are listed, busiest first; see isolation_groups.rs.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_listIsolationGroups(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&isolation_groups::list_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Refuse IPv6 CONNECT targets, for networks where IPv6 is advertised but broken
+///
+/// IPv6 targets (ATYP 0x04, or an IPv6 literal sent as a name) get SOCKS reply
+/// 0x08 (address type not supported) right away, and exits are asked to
+/// connect to hostnames over IPv4 only. Applies to new connections.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setDisableIpv6Targets(
+    _env: JNIEnv,
+    _class: JClass,
+    disabled: jboolean,
+) {
+    let disabled = disabled == JNI_TRUE;
+    let _ = config::set("disable_ipv6_targets", serde_json::json!(disabled));
+    log_info!("IPv6 targets {}", if disabled { "disabled" } else { "enabled" });
+}
+
+/// Refuse every CONNECT whose target isn't an onion address
+///
+/// Clearnet targets get SOCKS reply 0x02 (not allowed by ruleset), so no
+/// traffic ever leaves through an exit; names with an onion alias still
+/// connect to their onion. Applies to new connections.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setOnionOnly(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled == JNI_TRUE;
+    let _ = config::set("onion_only", serde_json::json!(enabled));
+    log_info!("Onion-only mode {}", if enabled { "enabled" } else { "disabled" });
+}
+
+/// Refuse IP-literal CONNECT targets that carry no `host=` claim
+///
+/// They get SOCKS reply 0x02 (not allowed by ruleset); names, and literals
+/// whose credentials name them, still connect. See target.rs for where this
+/// sits among the other target checks. Applies to new connections.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setRequireDomainTargets(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled == JNI_TRUE;
+    let _ = config::set("require_domain_targets", serde_json::json!(enabled));
+    log_info!("Domain targets {}", if enabled { "required" } else { "not required" });
+}
+
+/// Set the token identifying the current network (empty to clear)
+///
+/// Streams are isolated by this token so circuits are never shared across
+/// networks. Call on every network change; calling again with the same value
+/// marks it as still current.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setNetworkToken(
+    mut env: JNIEnv,
+    _class: JClass,
+    token: JString,
+) {
+    let token: String = match env.get_string(&token) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert network token: {:?}", e);
+            return;
+        }
+    };
+    network_token::set(&token);
+}
+
+/// Set how long the network token stays fresh without a refresh
+///
+/// After this long the token is treated as stale: streams are additionally
+/// isolated by time period and `NETWORK_TOKEN_STALE` is logged once. 0
+/// disables staleness (the default). Returns 0 on success, -1 if out of range.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setNetworkTokenMaxAge(
+    _env: JNIEnv,
+    _class: JClass,
+    seconds: jint,
+) -> jint {
+    if let Err(e) = config::set("network_token_max_age_secs", serde_json::json!(seconds)) {
+        log_error!("Invalid network token max age: {}", e);
+        return -1;
+    }
+    log_info!("Network token max age set to {} seconds", seconds);
+    0
+}
+
+/// Record a destination pattern list setting from a comma/newline-separated string
+fn set_destination_patterns(env: &mut JNIEnv, patterns: &JString, setting: &str) -> jint {
+    let patterns: String = match env.get_string(patterns) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert destination patterns: {:?}", e);
+            return -1;
+        }
+    };
+    let patterns: Vec<&str> = patterns.split([',', '\n']).map(str::trim).filter(|p| !p.is_empty()).collect();
+    if let Err(e) = config::set(setting, serde_json::json!(patterns)) {
+        log_error!("Invalid {}: {}", setting, e);
+        return -1;
+    }
+    log_info!("{} set to {} pattern(s)", setting, patterns.len());
+    0
+}
+
+/// Dial a clearnet host as its onion address from now on
+///
+/// CONNECTs to `clear_host` (as the CONNECT target or a `host=` claim, not
+/// when fronting) go to `onion_host` on the same port instead; each upgrade
+/// is logged and counted in `getMetrics()`. An empty `onion_host` removes the
+/// alias. Returns 0 on success, -1 if either name is invalid.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_addOnionAlias(
+    mut env: JNIEnv,
+    _class: JClass,
+    clear_host: JString,
+    onion_host: JString,
+) -> jint {
+    let (clear, onion): (String, String) = match (env.get_string(&clear_host), env.get_string(&onion_host)) {
+        (Ok(c), Ok(o)) => (c.into(), o.into()),
+        _ => {
+            log_error!("Failed to convert onion alias arguments");
+            return -1;
+        }
+    };
+    add_onion_alias(&clear, &onion)
+}
+
+fn add_onion_alias(clear: &str, onion: &str) -> jint {
+    let clear = clear.trim().to_ascii_lowercase();
+    let onion = onion.trim().to_ascii_lowercase();
+
+    let mut aliases = config::applied("onion_aliases");
+    let Some(map) = aliases.as_object_mut() else {
+        return -1;
+    };
+    if onion.is_empty() {
+        map.remove(&clear);
+    } else {
+        map.insert(clear.clone(), serde_json::json!(onion));
+    }
+    if let Err(e) = config::set("onion_aliases", aliases) {
+        log_error!("Invalid onion alias: {}", e);
+        return -1;
+    }
+    if onion.is_empty() {
+        log_info!("Onion alias for {} removed", clear);
+    } else {
+        log_info!("{} will be dialed as its onion alias", clear);
+    }
+    0
+}
+
+/// Refuse CONNECTs to matching destinations with SOCKS reply 0x02
+///
+/// `patterns` is comma- or newline-separated `host`, `*.domain`, optionally
+/// with `:port`; empty clears the list. Returns 0 on success, -1 if a
+/// pattern is malformed.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setDestinationDenylist(
+    mut env: JNIEnv,
+    _class: JClass,
+    patterns: JString,
+) -> jint {
+    set_destination_patterns(&mut env, &patterns, "destination_denylist")
+}
+
+/// Only allow CONNECTs to matching destinations (empty allows everything)
+///
+/// Same pattern syntax as `setDestinationDenylist`, which takes precedence.
+/// Other targets are refused with SOCKS reply 0x02. Returns 0 on success, -1
+/// if a pattern is malformed.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setDestinationAllowlist(
+    mut env: JNIEnv,
+    _class: JClass,
+    patterns: JString,
+) -> jint {
+    set_destination_patterns(&mut env, &patterns, "destination_allowlist")
+}
+
+/// Confirm each Tor stream is open and writable before the SOCKS success reply
+///
+/// Adds the exit's round trip to every connect, but a stream that fails
+/// then gets a SOCKS error instead of a reset after the client has already
+/// sent its first payload. The added latency is reported in `getMetrics()`.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setConfirmStreamWritable(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled == JNI_TRUE;
+    let _ = config::set("confirm_stream_writable", serde_json::json!(enabled));
+    log_info!("Stream confirmation {}", if enabled { "enabled" } else { "disabled" });
+}
+
+/// Refuse every direct (non-Tor) outbound connection
+///
+/// Returns 0 on success, -1 if this build forces strict mode and it can't be disabled.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setStrictTorOnly(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) -> jint {
+    let enabled = enabled == JNI_TRUE;
+    if let Err(e) = config::set("strict_tor_only", serde_json::json!(enabled)) {
+        log_error!("Cannot change strict Tor-only mode: {}", e);
+        return -1;
+    }
+    log_info!("Strict Tor-only mode {}", if enabled { "enabled" } else { "disabled" });
+    0
+}
+
+/// Get wrapper metrics as JSON
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getMetrics(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&METRICS.to_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the main wrapper metrics as Prometheus exposition text (not JSON)
+///
+/// Empty without the `metrics-endpoint` feature.
+#[cfg(feature = "metrics-endpoint")]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getPrometheusMetrics(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(prometheus::render()).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+#[cfg(not(feature = "metrics-endpoint"))]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getPrometheusMetrics(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    features::require("metrics-endpoint", "getPrometheusMetrics");
+    let output = env.new_string("").expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the data directory storage condition as JSON
+///
+/// `{"state":"ok"|"lost"|"unwatched","lost_since_epoch_ms":..,"probes":..,
+/// "next_probe_in_secs":..,"suspended_writes":..,"free_bytes":..,"total_bytes":..}`
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getStorageStats(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&storage::stats_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Estimate the share of traffic that bypassed the proxy since the last call
+///
+/// The first call (and any call after the counter source changed or reset)
+/// only records a baseline. See bypass.rs for sources and caveats.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getBypassEstimate(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&bypass::estimate_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Report cumulative traffic counters for when /proc/net is unreadable
+///
+/// Returns 0 on success, -1 if either value is negative (TrafficStats
+/// returns UNSUPPORTED = -1).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_reportTrafficCounters(
+    _env: JNIEnv,
+    _class: JClass,
+    rx_bytes: jlong,
+    tx_bytes: jlong,
+) -> jint {
+    if rx_bytes < 0 || tx_bytes < 0 {
+        return -1;
+    }
+    bypass::report(rx_bytes as u64, tx_bytes as u64);
+    0
+}
+
+/// Set the cap on total relay buffer memory across all connections
+///
+/// Returns 0 on success, -1 if the cap is too small for a single connection.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setRelayBufferMemoryCap(
+    _env: JNIEnv,
+    _class: JClass,
+    bytes: jlong,
+) -> jint {
+    if let Err(e) = config::set("relay_buffer_cap_bytes", serde_json::json!(bytes)) {
+        log_error!("Invalid relay buffer memory cap: {}", e);
+        return -1;
+    }
+    log_info!("Relay buffer memory cap set to {} bytes", bytes);
+    0
+}
+
+/// Set the cap on the approximate memory of the wrapper's tables together
+///
+/// Covers destination quality, bridge history and isolation tokens (see
+/// caches.rs); a table growing past its share evicts its least recently used
+/// entries. Returns 0 on success, -1 outside 64 KiB..=64 MiB.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setCacheBudget(
+    _env: JNIEnv,
+    _class: JClass,
+    bytes: jlong,
+) -> jint {
+    if let Err(e) = config::set("cache_budget_bytes", serde_json::json!(bytes)) {
+        log_error!("Invalid cache budget: {}", e);
+        return -1;
+    }
+    log_info!("Cache budget set to {} bytes", bytes);
+    0
+}
+
+/// Memory used by the wrapper's tables, as JSON
+///
+/// `{"budget_bytes":..,"total_bytes":..,"trims":..,"relay_buffer_bytes":..,
+/// "caches":[{"name":..,"entries":..,"max_entries":..,"approx_bytes":..}]}`.
+/// Sizes are estimates of what each entry holds, not allocator figures.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getMemoryStats(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&caches::stats_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the SOCKS feature set in effect as JSON (see capabilities.rs)
+///
+/// The same bitmap a CONNECT to `capabilities.invalid` returns, with names:
+/// `{"version":..,"bitmap":..,"capabilities":[..],"bits":{name: bit}}`.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getProxyCapabilities(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&capabilities::to_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Forward `ComponentCallbacks2.onTrimMemory(level)`
+///
+/// Trims every table proportionally to the pressure: nothing for
+/// `TRIM_MEMORY_UI_HIDDEN`, everything for `TRIM_MEMORY_COMPLETE`.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_onTrimMemory(
+    _env: JNIEnv,
+    _class: JClass,
+    level: jint,
+) {
+    let kept = caches::trim_memory(level);
+    if kept < 100 {
+        log_info!("Trim memory level {}: tables trimmed to {}%", level, kept);
+    }
+}
+
+/// Set how long a write toward a local client may make no progress
+///
+/// Connections whose client stops reading are closed after this long with
+/// close reason `client_stalled`. Returns 0 on success, -1 if out of range.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setClientStallTimeout(
+    _env: JNIEnv,
+    _class: JClass,
+    seconds: jint,
+) -> jint {
+    if let Err(e) = config::set("client_stall_timeout_secs", serde_json::json!(seconds)) {
+        log_error!("Invalid client stall timeout: {}", e);
+        return -1;
+    }
+    log_info!("Client stall timeout set to {} seconds", seconds);
+    0
+}
+
+/// Opt into the check that a started proxy gets any connection through
+///
+/// `window_secs` after each start that starts a listener, connects were
+/// attempted but none established while the client is ready for traffic
+/// means a `proxy_non_functional` failure (see proxy_watchdog.rs). 0 turns
+/// the check off (the default); applies from the next start. Returns 0 on
+/// success, -1 if out of range.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setProxyWatchdog(
+    _env: JNIEnv,
+    _class: JClass,
+    window_secs: jint,
+) -> jint {
+    if let Err(e) = config::set("proxy_watchdog_secs", serde_json::json!(window_secs)) {
+        log_error!("Invalid proxy watchdog window: {}", e);
+        return -1;
+    }
+    log_info!("Proxy watchdog window set to {} seconds", window_secs);
+    0
+}
+
+/// Set how long initialize waits for another process to release the data
+/// directory before failing with `state_locked`
+///
+/// 0 (the default) fails at once (see state_lock.rs). Applies from the next
+/// initialize. Returns 0 on success, -1 if outside 0..=60 seconds.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setStateLockWait(
+    _env: JNIEnv,
+    _class: JClass,
+    wait_secs: jint,
+) -> jint {
+    if let Err(e) = config::set("state_lock_wait_secs", serde_json::json!(wait_secs)) {
+        log_error!("Invalid state lock wait: {}", e);
+        return -1;
+    }
+    log_info!("State lock wait set to {} seconds", wait_secs);
+    0
+}
+
+/// Get the data directory lock's state as JSON
+///
+/// `{"held":..,"holder":{"pid":..,"start_ticks":..,"since_epoch_ms":..,"alive":..}}`:
+/// this process while it holds the lock, else the holder at the last
+/// `state_locked` failure (null if none).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getStateLockHolder(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&state_lock::holder_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set how long a circuit keeps being used for new streams
+///
+/// Shorter windows make streams harder to link to each other at the cost of
+/// more circuit builds; longer ones do the opposite. Takes effect on the next
+/// initialize. Returns 0 on success, -1 if outside 10..=86400 seconds.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setMaxCircuitDirtinessSecs(
+    _env: JNIEnv,
+    _class: JClass,
+    seconds: jint,
+) -> jint {
+    if let Err(e) = config::set("max_circuit_dirtiness_secs", serde_json::json!(seconds)) {
+        log_error!("Invalid circuit dirtiness: {}", e);
+        return -1;
+    }
+    log_info!("Max circuit dirtiness set to {} seconds (applies on next initialize)", seconds);
+    0
+}
+
+/// Restrict the address family relays are connected on
+///
+/// `mode` is 0 (auto), 1 (IPv4 only), 2 (IPv6 only) or 3 (prefer IPv6,
+/// which Arti can't express: both families are allowed, in no guaranteed
+/// order, and `getEffectiveConfig` says so); see address_family.rs. Bridges
+/// are dialed as configured whatever the mode. Takes effect on the next
+/// initialize. Returns 0 on success, -1 for an unknown mode.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setRelayAddressFamily(
+    _env: JNIEnv,
+    _class: JClass,
+    mode: jint,
+) -> jint {
+    if let Err(e) = config::set("relay_address_family", serde_json::json!(mode)) {
+        log_error!("Invalid relay address family: {}", e);
+        return -1;
+    }
+    let mode = address_family::Mode::of(&serde_json::json!(mode));
+    let excluded = address_family::excluded_bridges(mode, &config::strings(&config::upcoming("bridges")));
+    if excluded > 0 {
+        log_info!("{} configured bridge(s) use an address family {} excludes; they are still dialed", excluded, mode.as_str());
+    }
+    log_info!("Relay address family set to {} (applies on next initialize)", mode.as_str());
+    0
+}
+
+/// Forward Arti's DEBUG events to the log sinks, and log circuit paths
+///
+/// Each established connection then logs its circuit path at DEBUG level,
+/// with the guard scrubbed.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setVerboseLogging(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled == JNI_TRUE;
+    let _ = config::set("verbose_logging", serde_json::json!(enabled));
+    log_info!("Verbose logging {}", if enabled { "enabled" } else { "disabled" });
+}
+
+/// Limit the metadata the wrapper retains (see retention.rs)
+///
+/// 0 = full, 1 = reduced, 2 = minimal. Raising the level also purges what it
+/// would not have kept. Returns 0 on success, -1 for an unknown level.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setMetadataRetention(
+    _env: JNIEnv,
+    _class: JClass,
+    level: jint,
+) -> jint {
+    if let Err(e) = config::set("metadata_retention", serde_json::json!(level)) {
+        log_error!("Invalid metadata retention level: {}", e);
+        return -1;
+    }
+    log_info!("Metadata retention level set to {}", level);
+    0
+}
+
+/// Get the connection quality of one destination as JSON
+///
+/// `{"score":..,"success_rate":..,"attempts":..,"median_connect_ms":..,
+/// "median_first_byte_ms":..,"throughput_bytes_per_sec":..,"last_used_epoch_ms":..}`,
+/// or `null` if the destination hasn't been seen (or on invalid arguments).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getDestinationQuality(
+    mut env: JNIEnv,
+    _class: JClass,
+    host: JString,
+    port: jint,
+) -> jstring {
+    let host: Option<String> = env.get_string(&host).ok().map(Into::into);
+    let quality = match (host, u16::try_from(port)) {
+        (Some(h), Ok(p)) => api::ok(&quality::destination_json(&h, p)),
+        _ => {
+            log_error!("Invalid destination quality request");
+            api::error("invalid_argument", "host or port is invalid")
+        }
+    };
+    let output = env.new_string(quality).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the `n` best-scoring destinations as a JSON array
+///
+/// Entries carry a hashed `key` instead of the host and port.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getTopDestinations(
+    env: JNIEnv,
+    _class: JClass,
+    n: jint,
+) -> jstring {
+    let output = env.new_string(api::ok(&quality::top_json(n.max(0) as usize))).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Keep destination quality scores across restarts (off by default)
+///
+/// Scores are stored in the wrapper state directory; disabling deletes them.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setPersistentDestinationQuality(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    let enabled = enabled == JNI_TRUE;
+    let _ = config::set("persistent_destination_quality", serde_json::json!(enabled));
+    log_info!("Persistent destination quality {}", if enabled { "enabled" } else { "disabled" });
+}
+
+/// Get the diagnostic record for the most recent failure as JSON
+///
+/// Includes a `debug_context` array with the DEBUG-level lines captured in the
+/// 15 seconds before the failure. Returns `null` if nothing has failed.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getLastError(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&diagnostics::last_error_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set the audit log's MAC key (`audit::KEY_LEN` bytes, kept only in memory)
+///
+/// Opens the log if initialize already ran. Returns 0 on success, -1 if the
+/// key has the wrong length or differs from the one already set.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setAuditKey(
+    env: JNIEnv,
+    _class: JClass,
+    key: JByteArray,
+) -> jint {
+    let key = match env.convert_byte_array(&key) {
+        Ok(bytes) => zeroize::Zeroizing::new(bytes),
+        Err(e) => {
+            log_error!("Failed to convert audit key: {:?}", e);
+            return -1;
+        }
+    };
+    if !audit::set_key(&key) {
+        log_error!("Audit key refused: expected {} bytes, and the same key as before", audit::KEY_LEN);
+        return -1;
+    }
+    let data_dir = DATA_DIR.lock().unwrap().clone();
+    if let Some(dir) = data_dir {
+        if !audit::is_open() {
+            if let Err(e) = audit::open(&dir) {
+                log_error!("Failed to open audit log: {}", e);
+            }
+        }
+    }
+    0
+}
+
+/// Get the most recent audit records as a JSON array (oldest first)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getAuditLog(
+    env: JNIEnv,
+    _class: JClass,
+    max_records: jint,
+) -> jstring {
+    let output = env.new_string(api::ok(&audit::tail_json(max_records.max(0) as usize))).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Verify the audit log's MAC chain, reporting tampering or truncation as JSON
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_verifyAuditLog(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&audit::verify_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the most recent log lines as a JSON array (oldest first)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getRecentLogs(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&recent_logs::to_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set how many recent log lines are retained (0 disables the ring)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setRecentLogCapacity(
+    _env: JNIEnv,
+    _class: JClass,
+    lines: jint,
+) {
+    let lines = lines.clamp(0, recent_logs::MAX_CAPACITY as jint);
+    let _ = config::set("recent_log_capacity", serde_json::json!(lines));
+    log_info!("Recent log capacity set to {}", lines);
+}
+
+/// Cap how many log lines per second reach the log callback (0: no limit)
+///
+/// Errors always go through; see log_rate.rs. Returns 0 on success, -1 if
+/// `per_sec` is outside 0..=10000.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setLogRateLimitPerSec(
+    _env: JNIEnv,
+    _class: JClass,
+    per_sec: jint,
+) -> jint {
+    if let Err(e) = config::set("log_rate_limit_per_sec", serde_json::json!(per_sec)) {
+        log_error!("Invalid log rate limit: {}", e);
+        return -1;
+    }
+    log_info!("Log rate limit set to {} line(s) per second", per_sec);
+    0
+}
+
+/// Also append log lines to a rotating file, for crash reports
+///
+/// `path` must be absolute, in an existing directory; an empty path stops
+/// writing (the files are kept). Returns 0 on success, -1 if the path is
+/// unusable. See log_file.rs for rotation.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setLogFile(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+) -> jint {
+    let path: String = match env.get_string(&path) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert log file path: {:?}", e);
+            return -1;
+        }
+    };
+    if let Err(e) = config::set("log_file", serde_json::json!(path)) {
+        log_error!("Invalid log file: {}", e);
+        return -1;
+    }
+    if path.is_empty() {
+        log_info!("Log file disabled");
+    } else {
+        log_info!("Logging to file {}", path);
+    }
+    0
+}
+
+/// Get the persisted metrics snapshots (see metrics_history.rs) as JSON
+///
+/// The newest `max_snapshots`, oldest first: `[{"at_epoch_ms":..,"state":..,
+/// "bootstrap_percent":..,"ready_for_traffic":..,"battery_low":..,
+/// "metrics":{..}}]`. Empty at the minimal retention level.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getMetricsHistory(
+    env: JNIEnv,
+    _class: JClass,
+    max_snapshots: jint,
+) -> jstring {
+    let response = match usize::try_from(max_snapshots) {
+        Ok(max) => api::ok(&metrics_history::history_json(max)),
+        Err(_) => api::error("invalid_argument", "maxSnapshots must not be negative"),
+    };
+    let output = env.new_string(response).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get lifecycle reliability over the last `days` days (see reliability.rs)
+///
+/// Time spent ready, degraded and stopped, sessions, bootstraps and their
+/// average duration, and storage recoveries, folded from the persisted
+/// session log. Days must be within 1..=90.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getReliabilityStats(
+    env: JNIEnv,
+    _class: JClass,
+    days: jint,
+) -> jstring {
+    let response = match u64::try_from(days) {
+        Ok(days) if (1..=reliability::MAX_DAYS).contains(&days) => api::ok(&reliability::stats_json(days)),
+        _ => api::error("invalid_argument", &format!("days must be within 1..={}", reliability::MAX_DAYS)),
+    };
+    let output = env.new_string(response).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the recent bootstrap attempts as a JSON array, oldest first
+///
+/// Each with its trigger, configuration fingerprint, outcome, duration and
+/// winning entry; earlier processes' attempts are included from the
+/// persisted history. See bootstrap_history.rs.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getBootstrapHistory(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&bootstrap_history::history_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set the minutes between persisted metrics snapshots (0 disables them)
+///
+/// Returns 0 on success, -1 outside 0..=1440.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setMetricsSnapshotInterval(
+    _env: JNIEnv,
+    _class: JClass,
+    minutes: jint,
+) -> jint {
+    if let Err(e) = config::set("metrics_snapshot_interval_mins", serde_json::json!(minutes)) {
+        log_error!("Invalid metrics snapshot interval: {}", e);
+        return -1;
+    }
+    log_info!("Metrics snapshot interval set to {} min", minutes);
+    0
+}
+
+/// Export a diagnostics bundle (state, metrics, last failure) as JSON
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_exportDiagnostics(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&diagnostics_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+fn diagnostics_json() -> String {
+    let state = LIFECYCLE_STATE.load(Ordering::SeqCst);
+    format!(
+        "{{\"version\":\"{}\",\"state\":{},\"metrics\":{},\"last_error\":{},\"recent_logs\":{},\"metrics_history\":{},\"bootstrap_history\":{}}}",
+        env!("CARGO_PKG_VERSION"),
+        state,
+        METRICS.to_json(),
+        diagnostics::last_error_json(),
+        recent_logs::to_json(),
+        metrics_history::history_json(usize::MAX),
+        bootstrap_history::history_json(),
+    )
+}
+
+/// Configure a client authorization key for a restricted-discovery onion service
+///
+/// Pass an empty key to remove the entry. Takes effect on the next initialize.
+/// Returns 0 on success, -1 on invalid arguments, `ERR_FEATURE_DISABLED`
+/// without the `onion-service-client` feature.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setOnionClientAuth(
+    mut env: JNIEnv,
+    _class: JClass,
+    onion_addr: JString,
+    private_key: JString,
+) -> jint {
+    if !features::require("onion-service-client", "setOnionClientAuth") {
+        return ERR_FEATURE_DISABLED;
+    }
+    let (onion, key): (String, String) = match (env.get_string(&onion_addr), env.get_string(&private_key)) {
+        (Ok(o), Ok(k)) => (o.into(), k.into()),
+        _ => {
+            log_error!("Failed to convert onion client auth arguments");
+            return -1;
+        }
+    };
+
+    if key.is_empty() {
+        onion_auth::remove(&onion);
+        audit::record("onion_client_key_removed", "");
+        log_info!("Removed onion client authorization (applies on next initialize)");
+    } else if onion_auth::set(&onion, &key) {
+        audit::record("onion_client_key_added", "");
+        log_info!("Onion client authorization configured (applies on next initialize)");
+    } else {
+        log_error!("Invalid onion address or client auth key");
+        return -1;
+    }
+    let _ = config::set("onion_client_auth", serde_json::json!(onion_auth::configured_ids()));
+    0
+}
+
+/// Configure bridge lines (newline-separated; empty to stop using bridges)
+///
+/// Pluggable transport binaries are set via `pt_binaries` in the config JSON.
+/// Takes effect on the next initialize. Returns 0 on success, -1 if a line
+/// can't be parsed (or names a transport without the `pluggable-transports`
+/// feature), `ERR_FEATURE_DISABLED` without the `bridges` feature.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setBridges(
+    mut env: JNIEnv,
+    _class: JClass,
+    lines: JString,
+) -> jint {
+    if !features::require("bridges", "setBridges") {
+        return ERR_FEATURE_DISABLED;
+    }
+    let lines: String = match env.get_string(&lines) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert bridge lines: {:?}", e);
+            return -1;
+        }
+    };
+    let lines: Vec<&str> = lines.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    if let Err(e) = config::set("bridges", serde_json::json!(lines)) {
+        log_error!("Invalid bridges: {}", e);
+        return -1;
+    }
+    log_info!("{} bridge lines configured (applies on next initialize)", lines.len());
+    0
+}
+
+/// Get the bridge the current client bootstrapped through as JSON
+///
+/// `{"transport":..,"bridge":..,"candidates":[..]}` with bridges identified by
+/// fingerprint; `bridge` is null when the successful bootstrap stage held
+/// several bridges (all listed in `candidates`). `null` without bridges.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getActiveBridge(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&bridges::active_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the hourly health history of each configured bridge as JSON
+///
+/// `{"bridges":[{"fingerprint":..,"degrading":..,"recent_success_rate":..,
+/// "baseline_success_rate":..,"recent_median_connect_ms":..,
+/// "baseline_median_connect_ms":..,"hours":[{"hour_epoch_ms":..,"attempts":..,
+/// "success_rate":..,"median_connect_ms":..}]}]}` covering the last 7 days,
+/// oldest hour first and only hours with attempts. Empty below the full
+/// retention level.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getBridgeHistory(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&bridge_health::history_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set the order in which bridge transports are tried during bootstrap
+///
+/// `list` is comma-separated transport names (`direct` for plain bridges).
+/// Each transport is tried alone, falling back to the next after a failure or
+/// timeout; unlisted transports are tried last. Takes effect on the next
+/// initialize. Returns 0 on success, -1 if the list is malformed or names a
+/// transport none of the configured bridges use, `ERR_FEATURE_DISABLED`
+/// without the `bridges` feature.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setTransportPriority(
+    mut env: JNIEnv,
+    _class: JClass,
+    list: JString,
+) -> jint {
+    if !features::require("bridges", "setTransportPriority") {
+        return ERR_FEATURE_DISABLED;
+    }
+    let list: String = match env.get_string(&list) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert transport priority: {:?}", e);
+            return -1;
+        }
+    };
+    let priority: Vec<String> = list.split(',').map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect();
+
+    let lines = config::strings(&config::upcoming("bridges"));
+    let missing = bridges::unconfigured(&lines, &priority);
+    if !missing.is_empty() {
+        log_error!("No configured bridges use transport(s): {}", missing.join(", "));
+        return -1;
+    }
+    if let Err(e) = config::set("transport_priority", serde_json::json!(priority)) {
+        log_error!("Invalid transport priority: {}", e);
+        return -1;
+    }
+    log_info!("Transport priority set to {} (applies on next initialize)", priority.join(", "));
+    0
+}
+
+/// Tune Arti's directory download retries
+///
+/// `schedules_json` is an object of schedules (`bootstrap`, `consensus`,
+/// `certs`, `microdescs`) with optional `attempts`, `initial_delay_ms` and
+/// `parallelism` (see dir_retry.rs); `{}` restores Arti's defaults. Takes
+/// effect on the next initialize. Returns 0 on success, -1 if the object is
+/// invalid.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setDirectoryRetry(
+    mut env: JNIEnv,
+    _class: JClass,
+    schedules_json: JString,
+) -> jint {
+    let text: String = match env.get_string(&schedules_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert directory retry schedules: {:?}", e);
+            return -1;
+        }
+    };
+    let value: serde_json::Value = match serde_json::from_str(&text) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!("Invalid directory retry schedules: {}", e);
+            return -1;
+        }
+    };
+    if let Err(e) = config::set("directory_retry", value) {
+        log_error!("Invalid directory retry schedules: {}", e);
+        return -1;
+    }
+    log_info!("Directory retry schedules set (applies on next initialize)");
+    0
+}
+
+/// Set the target ports Arti prepares circuits for ahead of time
+///
+/// `list` is comma-separated port numbers (at most `port_hints::MAX_PORTS`),
+/// empty for Arti's default of 80 and 443. A hint, not a restriction: see
+/// port_hints.rs. Takes effect on the next initialize. Returns 0 on success,
+/// -1 if the list is invalid.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setTargetPortHints(
+    mut env: JNIEnv,
+    _class: JClass,
+    list: JString,
+) -> jint {
+    let list: String = match env.get_string(&list) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert target port hints: {:?}", e);
+            return -1;
+        }
+    };
+    let ports = match port_hints::parse_list(&list) {
+        Ok(ports) => ports,
+        Err(port) => {
+            log_error!("Invalid target port hint {}", port);
+            return -1;
+        }
+    };
+    if let Err(e) = config::set("target_port_hints", serde_json::json!(ports)) {
+        log_error!("Invalid target port hints: {}", e);
+        return -1;
+    }
+    log_info!("Target port hints set to {:?} (applies on next initialize)", ports);
+    0
+}
+
+/// Require exit relays to carry consensus flags
+///
+/// `list` is comma-separated flag names (`Fast`, `Stable`; any case), empty
+/// to require nothing beyond Arti's defaults. See exits.rs for how each flag
+/// is enforced. Takes effect on the next initialize. Returns 0 on success,
+/// -1 if the list names a flag that can't be required.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setRequiredExitFlags(
+    mut env: JNIEnv,
+    _class: JClass,
+    list: JString,
+) -> jint {
+    let list: String = match env.get_string(&list) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert exit flags: {:?}", e);
+            return -1;
+        }
+    };
+    let mut flags = Vec::new();
+    for name in list.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        match exits::canonical(name) {
+            Some(flag) if !flags.contains(&flag) => flags.push(flag),
+            Some(_) => {}
+            None => {
+                log_error!("Exits can't be required to carry flag {}", name);
+                return -1;
+            }
+        }
+    }
+    if let Err(e) = config::set("required_exit_flags", serde_json::json!(flags)) {
+        log_error!("Invalid exit flags: {}", e);
+        return -1;
+    }
+    log_info!("Required exit flags set to [{}] (applies on next initialize)", flags.join(", "));
+    0
+}
+
+/// Bias circuits toward fast exits, at some cost in exit diversity
+///
+/// Lowers Arti's circuit build timeout quantile so slow builds are abandoned
+/// and retried on other relays; see exits.rs. Takes effect on the next
+/// initialize. Returns 0.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setPreferFastExits(
+    _env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) -> jint {
+    let enabled = enabled == JNI_TRUE;
+    if let Err(e) = config::set("prefer_fast_exits", serde_json::json!(enabled)) {
+        log_error!("Invalid fast exit preference: {}", e);
+        return -1;
+    }
+    log_info!("Fast exits {} (applies on next initialize)", if enabled { "preferred" } else { "not preferred" });
+    0
+}
+
+/// Get every wrapper setting with its effective value and provenance as JSON
+///
+/// Each entry has `value`, `scope` (`live` or `client`) and `source`
+/// (`default`, `applied` or `pending`); pending entries also carry the value
+/// that the next initialize will apply. Settings only partly carried out
+/// have an `effect` saying how (e.g. for each required exit flag, whether
+/// path selection or the exit check enforces it; for the prefer IPv6 relay
+/// address family, that both families are allowed in no guaranteed order).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getEffectiveConfig(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&config::effective_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Export the effective Tor configuration as an arti TOML config file
+///
+/// Returns the TOML itself (not a JSON envelope). Bridge lines are scrubbed
+/// unless `redact_secrets` is false and logcat logging is on (see
+/// arti_config.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_exportArtiConfig(
+    env: JNIEnv,
+    _class: JClass,
+    redact_secrets: jboolean,
+) -> jstring {
+    let redact = redact_secrets != 0;
+    if !redact {
+        if arti_config::unredacted_allowed() {
+            audit::record("arti_config_exported", "bridge lines unredacted");
+        } else {
+            log_info!("Unredacted config export needs logcat logging on; bridge lines scrubbed");
+        }
+    }
+    let output = env.new_string(arti_config::render(redact)).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Discard every setting recorded since the last initialize that hasn't been applied
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_resetPendingConfig(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    let discarded = config::reset_pending();
+    if !discarded.is_empty() {
+        // Setting names only; the onion ids of discarded keys stay out of the log
+        let mut names: Vec<&str> = discarded.iter().map(|entry| entry.split(':').next().unwrap_or_default()).collect();
+        names.sort();
+        names.dedup();
+        audit::record("pending_config_discarded", &names.join(","));
+    }
+    log_info!("Pending configuration discarded");
+}
+
+/// Initialize Arti runtime
+///
+/// Bootstrap failures return a categorized code (see bootstrap.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_initialize(
+    mut env: JNIEnv,
+    _class: JClass,
+    data_dir: JString,
+) -> jint {
+    initialize_client(&mut env, &data_dir)
+}
+
+/// Initialize Arti runtime with settings given as a JSON object
+///
+/// The object is merged over any pending settings (keys are the ones reported
+/// by getEffectiveConfig). Returns -5 without changing anything if the object
+/// is malformed or names an unknown setting; otherwise as initialize.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_initializeWithConfig(
+    mut env: JNIEnv,
+    _class: JClass,
+    data_dir: JString,
+    config_json: JString,
+) -> jint {
+    let config_json: String = match env.get_string(&config_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert config JSON: {:?}", e);
+            return -5;
+        }
+    };
+    if let Err(e) = config::merge_json(&config_json) {
+        log_error!("Rejected initialize config: {}", e);
+        return -5;
+    }
+    initialize_client(&mut env, &data_dir)
+}
+
+/// Shared body of initialize and initializeWithConfig
+fn initialize_client(env: &mut JNIEnv, data_dir: &JString) -> jint {
+    SHUTTING_DOWN.store(false, Ordering::SeqCst);
+
+    // Cache JavaVM if not already cached
+    if JAVA_VM.get().is_none() {
+        if let Ok(vm) = env.get_java_vm() {
+            let _ = JAVA_VM.set(vm);
+        }
+    }
+
+    if !check_log_callback() {
+        return INIT_ERR_NO_LOG_CALLBACK;
+    }
+
+    let data_dir_str: String = match env.get_string(data_dir) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert data_dir: {:?}", e);
+            return -1;
+        }
+    };
+
+    initialize_or_reuse(data_dir_str)
+}
+
+/// Reuse the bootstrapped client for `data_dir_str` if it can serve, else bootstrap
+fn initialize_or_reuse(data_dir_str: String) -> jint {
+    // Before the reuse check: after shutdown the client may be reused, but
+    // the lock was released
+    if let Err(code) = lock_data_dir(std::path::Path::new(&data_dir_str)) {
+        return code;
+    }
+    if reuse_client(&data_dir_str) {
+        return INIT_REUSED;
+    }
+    initialize_at(data_dir_str, bootstrap_history::Trigger::User)
+}
+
+/// Take the cross-process lock on `data_dir` (see state_lock.rs), failing
+/// with an initialize error code
+///
+/// A no-op when this process already holds it for `data_dir`.
+fn lock_data_dir(data_dir: &std::path::Path) -> Result<(), jint> {
+    match state_lock::acquire(data_dir) {
+        Ok(()) => Ok(()),
+        Err(state_lock::Error::Locked) => Err(bootstrap::Failure::StateLocked.code()),
+        Err(state_lock::Error::Io(e)) => {
+            log_error!("Failed to lock the data directory: {}", e);
+            diagnostics::record_failure("storage_error", format!("state lock: {}", e));
+            Err(bootstrap::Failure::Storage.code())
+        }
+    }
+}
+
+/// Whether a bootstrapped client for `data_dir_str` can serve this initialize
+///
+/// Activity recreation (rotation, other configuration changes) calls
+/// initialize again; re-bootstrapping then would only drain the battery.
+/// The client is reused unless the directory or a client setting changed.
+/// The lifecycle state is left alone, so a running proxy stays running.
+fn reuse_client(data_dir_str: &str) -> bool {
+    let same_dir = DATA_DIR.lock().unwrap().as_deref() == Some(std::path::Path::new(data_dir_str));
+    if !same_dir || !CLIENT_REUSABLE.load(Ordering::SeqCst) || ARTI_CLIENT.lock().unwrap().is_none() {
+        return false;
+    }
+    let changed = config::pending_changes();
+    if !changed.is_empty() {
+        log_info!("Settings changed ({}); bootstrapping a new client", changed.join(", "));
+        return false;
+    }
+    // Pending values equal the applied ones; committing only clears them
+    config::commit_pending();
+    log_info!("Reusing the bootstrapped client for {}", data_dir_str);
+    true
+}
+
+/// The single bootstrap entry: bootstrap at `data_dir_str` for `trigger`,
+/// recording the attempt (see bootstrap_history.rs)
+fn initialize_at(data_dir_str: String, trigger: bootstrap_history::Trigger) -> jint {
+    let attempt = bootstrap_history::Attempt::begin(trigger);
+    // Taken here too for migrateDataDirectory, which moves to another directory
+    let code = match lock_data_dir(std::path::Path::new(&data_dir_str)) {
+        Ok(()) => bootstrap_at(data_dir_str),
+        Err(code) => code,
+    };
+    attempt.finish(match code {
+        0 => "ok",
+        -2 => "runtime_unavailable",
+        code => bootstrap::kind_of(code),
+    });
+    code
+}
+
+/// Client settings shared by every bootstrap stage, as applied
+struct StageSettings {
+    pt_binaries: serde_json::Map<String, serde_json::Value>,
+    exit_flags: Vec<String>,
+    prefer_fast_exits: bool,
+    directory_retry: serde_json::Value,
+    port_hints: serde_json::Value,
+    max_dirtiness: std::time::Duration,
+    address_family: address_family::Mode,
+}
+
+impl StageSettings {
+    fn applied() -> Self {
+        StageSettings {
+            pt_binaries: config::applied("pt_binaries").as_object().cloned().unwrap_or_default(),
+            exit_flags: config::strings(&config::applied("required_exit_flags")),
+            prefer_fast_exits: config::applied("prefer_fast_exits").as_bool().unwrap_or(false),
+            directory_retry: config::applied("directory_retry"),
+            port_hints: config::applied("target_port_hints"),
+            max_dirtiness: std::time::Duration::from_secs(
+                config::applied("max_circuit_dirtiness_secs").as_u64().unwrap_or(DEFAULT_CIRCUIT_DIRTINESS_SECS),
+            ),
+            address_family: address_family::Mode::of(&config::applied("relay_address_family")),
+        }
+    }
+
+    /// Arti config for the stage trying the bridges in `group`
+    fn client_config(&self, data_path: &std::path::Path, group: &[String]) -> Result<arti_client::config::TorClientConfig> {
+        let mut builder = TorClientConfigBuilder::from_directories(
+            layout::path(data_path, layout::Purpose::ArtiState),
+            layout::path(data_path, layout::Purpose::ArtiCache),
+        );
+        onion_auth::apply(&mut builder, &layout::path(data_path, layout::Purpose::OnionClientAuth))?;
+        bridges::apply(&mut builder, group, &self.pt_binaries)?;
+        address_family::apply(&mut builder, self.address_family, group)?;
+        builder.circuit_timing().max_dirtiness(self.max_dirtiness);
+        exits::apply(&mut builder, &self.exit_flags, &port_hints::ports(&self.port_hints));
+        exits::apply_prefer_fast(&mut builder, self.prefer_fast_exits);
+        dir_retry::apply(&mut builder, &self.directory_retry);
+        port_hints::apply(&mut builder, &self.port_hints);
+        Ok(builder.build()?)
+    }
+}
+
+/// Create the runtime (once) and a bootstrapped client using `data_dir_str`
+fn bootstrap_at(data_dir_str: String) -> jint {
+    CLIENT_REUSABLE.store(false, Ordering::SeqCst);
+    set_state(LifecycleState::Initialized);
+    log_info!("Initializing Arti with data directory: {}", data_dir_str);
+    diagnostics::install();
+
+    // Initialize Tokio runtime (once)
+    INIT_ONCE.call_once(|| {
+        match tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => {
+                log_info!("Tokio runtime created successfully");
+                liveness::start_heartbeat(&rt);
+                query::attach(rt.handle().clone());
+                *TOKIO_RUNTIME.lock().unwrap() = Some(rt);
+            }
+            Err(e) => {
+                log_error!("Failed to create Tokio runtime: {:?}", e);
+            }
+        }
+    });
+
+    // Check if runtime exists
+    let runtime = match runtime_handle() {
+        Some(rt) => rt,
+        None => {
+            log_error!("Tokio runtime not initialized");
+            return -2;
+        }
+    };
+
+    let data_path = PathBuf::from(data_dir_str);
+    if let Err(e) = audit::open(&data_path) {
+        log_error!("Failed to open audit log: {}", e);
+    }
+
+    // Bring the data directory to the current layout (migrating older releases)
+    if let Err(e) = layout::prepare(&data_path) {
+        log_error!("Failed to prepare data directory: {:?}", e);
+        diagnostics::record_failure("storage_error", format!("{:?}", e));
+        return -4;
+    }
+    *DATA_DIR.lock().unwrap() = Some(data_path.clone());
+    storage::watch(&data_path);
+    let cache_dir = layout::path(&data_path, layout::Purpose::ArtiCache);
+    let state_dir = layout::path(&data_path, layout::Purpose::ArtiState);
+    traffic::open(&data_path);
+    quality::open(&data_path);
+    bridge_health::open(&data_path);
+    metrics_history::open(&data_path);
+    reliability::open(&data_path);
+    bootstrap_history::open(&data_path);
+    traffic::baseline(&cache_dir);
+
+    notification::update(|inputs| inputs.bootstrap_percent = 0);
+    prewarm::reset();
+    guards::reset();
+    dir_retry::reset_attempts();
+
+    // Apply everything recorded before this call in one step
+    let committed = config::commit_pending();
+    if !committed.is_empty() {
+        log_info!("Applying pending settings: {}", committed.join(", "));
+    }
+
+    let lines = config::strings(&config::applied("bridges"));
+    let priority = config::strings(&config::applied("transport_priority"));
+    let stages = bridges::attempt_order(&lines, &priority);
+    let settings = StageSettings::applied();
+    let first_destination = config::applied("first_destination").as_str().unwrap_or_default().to_string();
+    let prewarm_budget = std::time::Duration::from_secs(
+        config::applied("prewarm_budget_secs").as_u64().unwrap_or(prewarm::DEFAULT_BUDGET_SECS),
+    );
+
+    let bootstrap = async {
+        log_info!("Creating Arti client...");
+        log_info!("Cache dir: {:?}", cache_dir);
+        log_info!("State dir: {:?}", state_dir);
+
+        // A bad config for any stage fails the bootstrap rather than the stage
+        let mut configs = Vec::with_capacity(stages.len());
+        for group in &stages {
+            // Create config with Android-specific directories
+            let config = settings.client_config(&data_path, group).context(bootstrap::ConfigStage)?;
+            configs.push(config);
+        }
+
+        // Create client with Android-specific config, showing its
+        // bootstrap progress in the notification while it runs
+        let attempts = configs.into_iter().map(|config| async {
+            let client = TorClient::builder().config(config).create_unbootstrapped()?;
+            let _progress = notification::BootstrapProgress::follow(
+                client.bootstrap_events().map(|status| status.as_frac()),
+            );
+            let _attempts = dir_retry::AttemptCounter::follow(client.bootstrap_events().map(|status| status.as_frac()));
+            client.bootstrap().await?;
+            Ok::<_, arti_client::Error>(client)
+        });
+        let client = bridges::bootstrap_in_stages(&stages, attempts).await?;
+
+        log_info!("Arti client created successfully");
+
+        // Store client globally
+        *ARTI_CLIENT.lock().unwrap() = Some(Arc::new(client));
+
+        Ok(())
+    };
+    // A stop arriving meanwhile cancels the bootstrap (see debounce.rs)
+    let cancelled = debounce::bootstrap_started();
+    let result: Result<()> = runtime.block_on(async {
+        tokio::select! {
+            result = bootstrap => result,
+            _ = cancelled.cancelled() => Err(anyhow::Error::new(debounce::BootstrapCancelled)),
+        }
+    });
+    debounce::bootstrap_finished();
+
+    match result {
+        Ok(_) => {
+            guards::clear();
+            let client = ARTI_CLIENT.lock().unwrap().clone();
+            if let Some(client) = client {
+                battery::apply_dormancy(&client);
+                geo::start_watcher(&runtime, Arc::clone(&client));
+                consensus_age::start_watcher(&runtime, Arc::clone(&client));
+                traffic::sample(&cache_dir);
+                traffic::start_watcher(&runtime, Arc::clone(&client), cache_dir.clone());
+                // Holds back ready for at most the budget; never fails initialize
+                runtime.block_on(prewarm::run(client, &first_destination, prewarm_budget));
+            }
+            notification::update(|inputs| inputs.bootstrap_percent = 100);
+            reliability::bootstrapped();
+            CLIENT_REUSABLE.store(true, Ordering::SeqCst);
+            log_info!("Arti initialized successfully");
+            0
+        }
+        Err(e) => {
+            log_error!("Failed to initialize Arti: {:?}", e);
+            if let Some(arti_error) = e.downcast_ref::<arti_client::Error>() {
+                security::check_error(arti_error, "bootstrap");
+            }
+            guards::check_error(&e, "bootstrap");
+            // Keep the specific cause rather than a generic bootstrap failure
+            let failure = bootstrap::classify(&e);
+            diagnostics::record_failure(failure.kind(), format!("{:?}", e));
+            failure.code()
+        }
+    }
+}
+
+/// Move Tor state and cache to a new data directory without re-bootstrapping
+///
+/// Stops the proxy and client, moves the whole data directory (rolling back on
+/// a partial failure), then reinitializes from the new location so existing
+/// guards and cached directory documents are reused. The SOCKS proxy is not
+/// restarted. Returns 0 on success, ERR_NOT_RUNNING if the client was never
+/// initialized, -5 on an invalid or non-empty destination, -6 if the move
+/// failed (the client is reinitialized at the old location); otherwise the
+/// initialize error code.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_migrateDataDirectory(
+    mut env: JNIEnv,
+    _class: JClass,
+    new_path: JString,
+) -> jint {
+    let new_path: PathBuf = match env.get_string(&new_path) {
+        Ok(s) => PathBuf::from(String::from(s)),
+        Err(e) => {
+            log_error!("Failed to convert new data directory path: {:?}", e);
+            return -5;
+        }
+    };
+
+    let old_path = match DATA_DIR.lock().unwrap().clone() {
+        Some(p) => p,
+        None => {
+            log_error!("Arti client not initialized - nothing to migrate");
+            return ERR_NOT_RUNNING;
+        }
+    };
+    if new_path == old_path || new_path.starts_with(&old_path) || !new_path.is_absolute() {
+        log_error!("Invalid data directory migration target: {:?}", new_path);
+        return -5;
+    }
+
+    log_info!("Migrating data directory {:?} -> {:?}", old_path, new_path);
+    debounce::take_pending();
+    set_state(LifecycleState::Stopping);
+    if let Some(handle) = SOCKS_TASK.lock().unwrap().take() {
+        listener_exit::aborting(listener_exit::Reason::Stopped, "migrateDataDirectory");
+        handle.abort();
+    }
+    cancel_connections();
+    // Drop the client so nothing writes to the old state while it moves
+    *ARTI_CLIENT.lock().unwrap() = None;
+    set_state(LifecycleState::Stopped);
+
+    match layout::relocate(&old_path, &new_path) {
+        Ok(()) => {
+            log_info!("Data directory moved; reinitializing");
+            let code = initialize_at(new_path.to_string_lossy().into_owned(), bootstrap_history::Trigger::DataDirChange);
+            // The audit log moved with the directory and is reopened by initialize
+            audit::record("data_dir_migrated", "");
+            code
+        }
+        Err(e) => {
+            log_error!("Data directory migration failed: {}", e);
+            diagnostics::record_failure("storage_error", format!("migration failed: {}", e));
+            // relocate leaves the old directory intact on failure
+            initialize_at(old_path.to_string_lossy().into_owned(), bootstrap_history::Trigger::MigrationRollback);
+            audit::record("data_dir_migration_rolled_back", &format!("{:?}", e.kind()));
+            -6
+        }
+    }
+}
+
+/// Clears `SOCKS_STARTING` when a start attempt ends, whichever way it ends
+struct StartInProgress;
+
+impl StartInProgress {
+    fn acquire() -> Option<Self> {
+        SOCKS_STARTING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| StartInProgress)
+    }
+}
+
+impl Drop for StartInProgress {
+    fn drop(&mut self) {
+        SOCKS_STARTING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Write Arti's pending persistent state (guard samples and status, circuit
+/// build timing) to the state directory now
+///
+/// Arti otherwise saves it periodically, so a process killed in the background
+/// can lose the latest guard updates and pick new guards on the next start.
+/// Returns 0 once written, ERR_NOT_RUNNING if the client is not initialized,
+/// -2 if another process holds the state lock (nothing can be written), or -3
+/// if writing failed.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_flushState(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    flush_state()
+}
+
+fn flush_state() -> jint {
+    let client = match ARTI_CLIENT.lock().unwrap().as_ref() {
+        Some(c) => Arc::clone(c),
+        None => {
+            log_error!("Arti client not initialized - call initialize() first");
+            return ERR_NOT_RUNNING;
+        }
+    };
+    flush_state_with(|| client.circmgr().store_persistent_state())
+}
+
+/// Run `store`, Arti's save of its persistent state, as `flushState`
+fn flush_state_with<E: std::fmt::Display>(store: impl FnOnce() -> Result<bool, E>) -> jint {
+    match store() {
+        Ok(true) => {
+            log_info!("Tor state flushed to disk");
+            0
+        }
+        Ok(false) => {
+            log_error!("Tor state not flushed: the state directory is locked by another process");
+            -2
+        }
+        Err(e) => {
+            log_error!("Failed to flush Tor state: {}", e);
+            -3
+        }
+    }
+}
+
+/// Estimate how many bytes the next bootstrap will download, as JSON
+///
+/// Based on whether the data directory holds a recent directory cache.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_estimateBootstrapCost(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let data_dir = DATA_DIR.lock().unwrap().clone();
+    let output = env.new_string(api::ok(&traffic::estimate_json(data_dir.as_deref()))).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Estimate the bytes Tor put on the wire since process start
+///
+/// Unlike `user_bytes` and `directory_bytes` in `getMetrics`, which count
+/// payload, this includes cell framing, stream setup, SENDMEs and TLS record
+/// overhead. An estimate built from the payloads; see traffic.rs.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getWireBytes(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    traffic::wire_bytes().min(jlong::MAX as u64) as jlong
+}
+
+/// Get relay-to-country index metadata as JSON (source, build time, entries)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getGeoDbInfo(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&geo::info_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Get the exit diversity of the last hour's streams as JSON
+///
+/// An entropy-based score, the busiest exits (fingerprints shortened), the
+/// share of streams opened under required exit flags, and a warning when
+/// those flags leave too few exits in the current consensus. See diversity.rs.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getDiversityReport(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&diversity::report_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Rebuild the relay-to-country index from the current consensus now
+///
+/// Returns the number of relays indexed, ERR_NOT_RUNNING if the client is not
+/// initialized, or -2 if no consensus is available yet.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_refreshGeoIndex(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    let client = match ARTI_CLIENT.lock().unwrap().as_ref() {
+        Some(c) => Arc::clone(c),
+        None => {
+            log_error!("Arti client not initialized - call initialize() first");
+            return ERR_NOT_RUNNING;
+        }
+    };
+    match geo::rebuild(&client) {
+        Some(entries) => entries.min(jint::MAX as usize) as jint,
+        None => {
+            log_error!("No consensus available for the geo index yet");
+            -2
+        }
+    }
+}
+
+/// Set or rotate the secret SOCKS handshakes must authenticate with
+///
+/// The replaced secret stays valid for `grace_secs`, then is wiped;
+/// established connections are unaffected. See listener_auth.rs for
+/// rotations during a grace window. Returns 0 on success, -1 on an invalid
+/// secret or a grace outside 0..=3600 seconds.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_rotateSocksAuth(
+    mut env: JNIEnv,
+    _class: JClass,
+    new_username: JString,
+    new_password: JString,
+    grace_secs: jint,
+) -> jint {
+    let (username, password) = match (env.get_string(&new_username), env.get_string(&new_password)) {
+        (Ok(username), Ok(password)) => (
+            zeroize::Zeroizing::new(String::from(username)),
+            zeroize::Zeroizing::new(String::from(password)),
+        ),
+        _ => {
+            log_error!("Failed to convert SOCKS listener credentials");
+            return -1;
+        }
+    };
+    if let Err(e) = listener_auth::check(&username, &password) {
+        log_error!("Invalid SOCKS listener secret: {}", e);
+        return -1;
+    }
+    if !(0..=listener_auth::MAX_GRACE_SECS as jint).contains(&grace_secs) {
+        log_error!("Invalid SOCKS listener grace window: {}s", grace_secs);
+        return -1;
+    }
+
+    listener_auth::rotate(
+        zeroize::Zeroizing::new(username.as_bytes().to_vec()),
+        zeroize::Zeroizing::new(password.as_bytes().to_vec()),
+        std::time::Duration::from_secs(grace_secs as u64),
+    );
+    log_info!("SOCKS listener secret rotated ({}s grace)", grace_secs);
+    0
+}
+
+/// Start SOCKS proxy on specified port
+///
+/// Returns 0 on success, ERR_NOT_RUNNING if the client is not initialized,
+/// -2 if the runtime is not initialized, -3 if the port can't be bound, and
+/// -4 if another start is still in progress. A start matching the running
+/// listener leaves it alone and returns 0 (see reconcile.rs), and so does a
+/// start taking back a pending stop (see debounce.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_startSocksProxy(
+    _env: JNIEnv,
+    _class: JClass,
+    port: jint,
+) -> jint {
+    start_socks_proxy(port)
+}
+
+fn start_socks_proxy(port: jint) -> jint {
+    // Overlapping starts would abort each other's listener mid-bind
+    let Some(_in_progress) = StartInProgress::acquire() else {
+        log_error!("SOCKS proxy start already in progress; ignoring start on port {}", port);
+        return -4;
+    };
+
+    let matches = socks_listener_matches(port);
+    match debounce::decide_now(debounce::Command::Start, socks_listener_running(), matches) {
+        // Lost to the debounce timer if the stop already ran
+        debounce::Decision::Coalesce if debounce::take_pending() => {
+            log_info!("Start on port {} coalesced with the pending stop; keeping the listener", port);
+            reconcile::record_start(port, true, true);
+            return 0;
+        }
+        debounce::Decision::FlushThenExecute if debounce::take_pending() => {
+            log_info!("Start on port {} differs from the running listener; running the pending stop first", port);
+            stop_within(None);
+        }
+        _ => {}
+    }
+
+    if socks_listener_matches(port) {
+        log_info!("SOCKS proxy already listening on port {} with the same settings; keeping it", port);
+        reconcile::record_start(port, true, false);
+        return 0;
+    }
+
+    set_state(LifecycleState::Starting);
+    log_info!("Starting SOCKS proxy on port {}", port);
+
+    let client_guard = ARTI_CLIENT.lock().unwrap();
+    let client = match client_guard.as_ref() {
+        Some(c) => Arc::clone(c),
+        None => {
+            log_error!("Arti client not initialized - call initialize() first");
+            return ERR_NOT_RUNNING;
+        }
+    };
+    drop(client_guard);
+
+    let runtime = match runtime_handle() {
+        Some(rt) => rt,
+        None => {
+            log_error!("Tokio runtime not initialized");
+            return -2;
+        }
+    };
+
+    // Stop any existing SOCKS server first, waiting until its listener is
+    // dropped so the port is free before the new bind
+    if let Some(handle) = SOCKS_TASK.lock().unwrap().take() {
+        log_info!("Aborting previous SOCKS server task");
+        proxy_watchdog::disarm();
+        listener_exit::aborting(listener_exit::Reason::Restarted, "startSocksProxy");
+        handle.abort();
+        let _ = runtime.block_on(handle);
+    }
+
+    // Connections survive a restart; only stop cancels them
+    let cancel = SOCKS_CANCEL.lock().unwrap().get_or_insert_with(cancel::CancelToken::new).clone();
+
+    // Try to bind IMMEDIATELY to detect port conflicts before returning
+    let addr = format!("127.0.0.1:{}", port);
+
+    // Use block_on to synchronously attempt binding
+    let bind_result = runtime.block_on(async {
+        net::bind_listener(&addr).await
+    });
+
+    let listener = match bind_result {
+        Ok(l) => {
+            log_info!("SOCKS proxy bound to {}", addr);
+            SOCKS_PORT.store(port, Ordering::SeqCst);
+            l
+        }
+        Err(e) => {
+            log_error!("Failed to bind SOCKS proxy to {}: {:?}", addr, e);
+            return -3;
+        }
+    };
+
+    // Now spawn the background task with the already-bound listener
+    let spec = reconcile::Spec::new(&addr, &client);
+    let client_for_watchdog = Arc::clone(&client);
+    let run = listener_exit::Run::start();
+    let handle = runtime.spawn(async move {
+        log_info!("SOCKS proxy listening on {}", addr);
+        log_info!("Sufficiently bootstrapped; system SOCKS now functional");
+        accept_connections(listener, client, cancel, &run).await;
+        log_info!("SOCKS proxy task exiting");
+    });
+
+    // Store handle for cleanup
+    *SOCKS_TASK.lock().unwrap() = Some(handle);
+    proxy_watchdog::arm(&runtime, client_for_watchdog);
+    reconcile::started(spec);
+    reconcile::record_start(port, false, false);
+
+    log_info!("SOCKS proxy started on port {}", port);
+    set_state(LifecycleState::Running);
+    // Straight back to degraded if the consensus is still stale
+    consensus_age::evaluate();
+    0
+}
+
+/// Whether the SOCKS accept loop is running
+fn socks_listener_running() -> bool {
+    SOCKS_TASK.lock().unwrap().as_ref().is_some_and(|task| !task.is_finished())
+}
+
+/// Whether a listener is running on `port` exactly as a start now would make it
+fn socks_listener_matches(port: jint) -> bool {
+    let running = socks_listener_running();
+    let client = ARTI_CLIENT.lock().unwrap().clone();
+    match client {
+        Some(client) if running => reconcile::matches(&reconcile::Spec::new(&format!("127.0.0.1:{}", port), &client)),
+        _ => false,
+    }
+}
+
+/// Get the outcome of the last successful `startSocksProxy` as JSON (null before any)
+///
+/// `reconciled` is true when the start found a matching listener running
+/// and left it alone instead of rebinding.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getLastStartResult(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&reconcile::last_start_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Counts a connection in the notification status for as long as it is alive
+///
+/// Created in the accept loop and moved into the handler task, so the count
+/// is released however the task ends: completion, cancellation, a panic, or
+/// the runtime dropping the task unpolled.
+struct ActiveConnection;
+
+impl ActiveConnection {
+    fn open() -> Self {
+        notification::update(|inputs| inputs.active_connections += 1);
+        ActiveConnection
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        notification::update(|inputs| inputs.active_connections -= 1);
+    }
+}
+
+/// Accept connections on `listener` until accepting fails, serving each on its
+/// own task; paused while the battery is low (see battery.rs)
+async fn accept_connections<C: seams::Connector>(
+    listener: impl seams::Acceptor,
+    connector: Arc<C>,
+    cancel: cancel::CancelToken,
+    run: &listener_exit::Run,
+) {
+    loop {
+        battery::accepting().await;
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = battery::paused() => {
+                log_info!("Battery low; no longer accepting SOCKS connections");
+                continue;
+            }
+        };
+        match accepted {
+            Ok((stream, peer_addr)) => {
+                log_info!("SOCKS connection from: {}", redact::Sensitive(peer_addr));
+                let active = ActiveConnection::open();
+                tokio::spawn(serve_connection(stream, peer_addr, Arc::clone(&connector), cancel.clone(), active));
+            }
+            Err(e) => {
+                log_error!("Failed to accept SOCKS connection: {:?}", e);
+                diagnostics::record_failure("proxy_died", format!("{:?}", e));
+                run.end(listener_exit::Reason::AcceptError, format!("{:?}", e));
+                break; // Exit loop on error
+            }
+        }
+    }
+}
+
+/// Run one accepted connection until it ends, `cancel` fires (stop), or its
+/// target is closed
+///
+/// Dropping the handler mid-connect abandons the circuit build.
+async fn serve_connection<C: seams::Connector>(
+    stream: tokio::net::TcpStream,
+    peer_addr: std::net::SocketAddr,
+    connector: Arc<C>,
+    cancel: cancel::CancelToken,
+    _active: ActiveConnection,
+) {
+    // Cancelled for this connection alone (see connections.rs)
+    let closer = cancel::CancelToken::new();
+    tokio::select! {
+        result = handle_socks_connection(stream, connector, closer.clone()) => {
+            if let Err(e) = result {
+                log_error!("SOCKS connection error: {:?}", e);
+            }
+        }
+        _ = cancel.cancelled() => {
+            log_info!("SOCKS connection from {} cancelled by stop", redact::Sensitive(peer_addr));
+        }
+        _ = closer.cancelled() => {
+            log_info!("SOCKS connection from {} closed by target", redact::Sensitive(peer_addr));
+        }
+    }
+}
+
+/// Handle a single SOCKS connection
+///
+/// The future may be dropped at any await point (stop, or a panic elsewhere
+/// in the task), so everything acquired here is owned by a value that
+/// releases it on drop rather than by cleanup code at the end:
+///
+/// - handshake and request reads/writes hold nothing but the local socket;
+/// - the buffer lease returns its bytes to the budget in `BufferLease::drop`,
+///   including when cancelled while still queued in `buffers::acquire`;
+/// - a connect dropped mid-build abandons the circuit attempt inside Arti,
+///   and once it resolves the Tor stream is owned by a local that closes it
+///   on drop, so there is no window where it is held but unowned;
+/// - the stall watch is held weakly by the sweep and pruned once dropped.
+async fn handle_socks_connection<C: seams::Connector>(
+    mut stream: tokio::net::TcpStream,
+    connector: Arc<C>,
+    closer: cancel::CancelToken,
+) -> Result<()> {
+    use seams::TorStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A chaos profile set later doesn't affect this connection
+    let chaos = chaos::Connection::start();
+
+    // Simple SOCKS5 handshake
+    let mut buf = [0u8; 512];
+
+    // Read version + methods
+    let n = stream.read(&mut buf).await?;
+    if n < 2 {
+        return Err(anyhow::anyhow!("Invalid SOCKS handshake"));
+    }
+    let offered = &buf[2..n.min(2 + buf[1] as usize)];
+
+    // Prefer username/password when offered: the username carries our
+    // per-connection options (see target.rs). Without a listener secret any
+    // credentials are accepted; with one they must match it.
+    let credentials = if offered.contains(&0x02) {
+        stream.write_all(&[0x05, 0x02]).await?;
+        let (username, password) = read_socks_credentials(&mut stream).await?;
+        if listener_auth::required() && !listener_auth::accepts(&username, &password) {
+            stream.write_all(&[0x01, 0x01]).await?;
+            return Err(anyhow::anyhow!("SOCKS handshake with wrong listener credentials refused"));
+        }
+        stream.write_all(&[0x01, 0x00]).await?;
+        target::ConnectCredentials::parse(&String::from_utf8_lossy(&username))
+    } else if listener_auth::required() {
+        // No acceptable method
+        stream.write_all(&[0x05, 0xFF]).await?;
+        return Err(anyhow::anyhow!("SOCKS handshake without listener credentials refused"));
+    } else {
+        // Send "no auth required" response
+        stream.write_all(&[0x05, 0x00]).await?;
+        target::ConnectCredentials::default()
+    };
+
+    // Read request
+    let n = stream.read(&mut buf).await?;
+    if n < 10 {
+        return Err(anyhow::anyhow!("Invalid SOCKS request"));
+    }
+
+    // Parse SOCKS5 request: VER(1) CMD(1) RSV(1) ATYP(1) DST.ADDR DST.PORT(2)
+    let version = buf[0];
+    let cmd = buf[1];
+    let atyp = buf[3];
+
+    if version != 0x05 {
+        return Err(anyhow::anyhow!("Unsupported SOCKS version: {}", version));
+    }
+
+    if cmd != 0x01 {
+        // Only support CONNECT command
+        socks::refuse(&mut stream, socks::Reply::CommandNotSupported).await?;
+        return Err(anyhow::anyhow!("Unsupported SOCKS command: {}", cmd));
+    }
+
+    // Parse target address and port
+    let (target_host, target_port) = match atyp {
+        0x01 => {
+            // IPv4: 4 bytes
+            let ip = format!("{}.{}.{}.{}", buf[4], buf[5], buf[6], buf[7]);
+            let port = u16::from_be_bytes([buf[8], buf[9]]);
+            (ip, port)
+        }
+        0x03 => {
+            // Domain name: length byte + domain
+            let len = buf[4] as usize;
+            if n < 5 + len + 2 {
+                return Err(anyhow::anyhow!("Invalid domain name length"));
+            }
+            let domain = String::from_utf8_lossy(&buf[5..5 + len]).to_string();
+            let port = u16::from_be_bytes([buf[5 + len], buf[5 + len + 1]]);
+            // Answered before anything else looks at the target
+            if capabilities::is_probe(&domain) {
+                socks::refuse_with_frame(&mut stream, capabilities::reply_frame()).await?;
+                return Ok(());
+            }
+            // An IPv6 literal sent as a name is still an IPv6 target
+            let literal = domain.trim_start_matches('[').trim_end_matches(']');
+            if DISABLE_IPV6_TARGETS.load(Ordering::Relaxed) && literal.parse::<std::net::Ipv6Addr>().is_ok() {
+                METRICS.ipv6_targets_refused.fetch_add(1, Ordering::Relaxed);
+                socks::refuse(&mut stream, socks::Reply::AddressTypeNotSupported).await?;
+                return Err(anyhow::anyhow!("IPv6 target refused: IPv6 targets are disabled"));
+            }
+            (domain, port)
+        }
+        0x04 => {
+            // IPv6: 16 bytes + 2 bytes port = 22 bytes total
+            if n < 22 {
+                socks::refuse(&mut stream, socks::Reply::GeneralFailure).await?;
+                return Err(anyhow::anyhow!("Truncated IPv6 request"));
+            }
+            // Refused up front so the app falls back to IPv4 at once instead
+            // of waiting on a dead IPv6 path
+            if DISABLE_IPV6_TARGETS.load(Ordering::Relaxed) {
+                METRICS.ipv6_targets_refused.fetch_add(1, Ordering::Relaxed);
+                socks::refuse(&mut stream, socks::Reply::AddressTypeNotSupported).await?;
+                return Err(anyhow::anyhow!("IPv6 target refused: IPv6 targets are disabled"));
+            }
+            let ip = format!(
+                "{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}",
+                buf[4], buf[5], buf[6], buf[7], buf[8], buf[9], buf[10], buf[11],
+                buf[12], buf[13], buf[14], buf[15], buf[16], buf[17], buf[18], buf[19]
+            );
+            let port = u16::from_be_bytes([buf[20], buf[21]]);
+            (ip, port)
+        }
+        _ => {
+            socks::refuse(&mut stream, socks::Reply::AddressTypeNotSupported).await?;
+            return Err(anyhow::anyhow!("Unsupported address type: {}", atyp));
+        }
+    };
+
+    let destination = match target::evaluate(target_host, target_port, &credentials) {
+        Ok(d) => d,
+        Err(rejection) => {
+            socks::refuse(&mut stream, socks::Reply::NotAllowedByRuleset).await?;
+            return Err(anyhow::anyhow!("CONNECT target rejected: {}", rejection.as_str()));
+        }
+    };
+    if consensus_age::refuses_connections() {
+        socks::refuse(&mut stream, socks::Reply::NetworkUnreachable).await?;
+        return Err(anyhow::anyhow!("CONNECT refused: the consensus is older than the allowed age"));
+    }
+    let target_host = destination.report_host();
+    let flow = flow_stall::Tracker::new();
+    let registration = connections::register(target_host, target_port, &closer, flow.record());
+    registration.set_client_labels(credentials.class.clone(), credentials.connid.clone());
+    // The destination as log lines show it (redacted at the sinks)
+    let shown_host = redact::Sensitive(target_host);
+    let dial_host = redact::Sensitive(&destination.dial_host);
+
+    match (&destination.claimed_host, &credentials.front) {
+        _ if destination.onion_upgraded => {
+            METRICS.onion_upgrades.fetch_add(1, Ordering::Relaxed);
+            log_info!("SOCKS5 CONNECT to {}:{} (upgraded to onion alias {})", shown_host, target_port, dial_host)
+        }
+        (Some(_), Some(_)) => log_info!("SOCKS5 CONNECT to {}:{} (fronted via {})", shown_host, target_port, dial_host),
+        (Some(_), None) => log_info!("SOCKS5 CONNECT to {}:{} (pre-resolved to {})", shown_host, target_port, dial_host),
+        (None, _) => log_info!("SOCKS5 CONNECT to {}:{}", shown_host, target_port),
+    }
+
+    // The app's own policy has the last word (no-op without a callback)
+    let verdict = policy::ask(target_host, target_port).await;
+    let annotation = verdict.annotation.map(|a| format!(" [policy {}]", a)).unwrap_or_default();
+    if !verdict.allow {
+        log_info!("Connection to {}:{} denied by app policy{}", shown_host, target_port, annotation);
+        socks::refuse(&mut stream, socks::Reply::NotAllowedByRuleset).await?;
+        return Err(anyhow::anyhow!("CONNECT target denied by app policy"));
+    }
+
+    // Reserve relay buffers before spending a Tor stream on this connection
+    let lease = match buffers::acquire().await {
+        Some(l) => l,
+        None => {
+            log_error!("Relay buffer budget exhausted; refusing connection to {}:{}", shown_host, target_port);
+            socks::refuse(&mut stream, socks::Reply::GeneralFailure).await?;
+            return Err(anyhow::anyhow!("Relay buffer budget exhausted"));
+        }
+    };
+
+    // Establish Tor connection
+    let mut isolation_key = IsolationKey::for_connect(target_port, destination.claimed_host.as_deref(), &credentials);
+    let connect_started = seams::now_monotonic();
+    let connect_limit = credentials.connect_limit();
+    if connect_limit == Some(std::time::Duration::ZERO) {
+        log_info!("CONNECT to {}:{} arrived after the client's deadline", shown_host, target_port);
+        socks::refuse(&mut stream, socks::Reply::TtlExpired).await?;
+        return Err(anyhow::anyhow!("CONNECT refused: the client's deadline has passed"));
+    }
+    if let Err(reply) = chaos.before_connect().await {
+        log_info!("Chaos profile failed the connect to {}:{}", shown_host, target_port);
+        socks::refuse(&mut stream, reply).await?;
+        return Err(anyhow::anyhow!("Connect failed by the chaos profile"));
+    }
+    proxy_watchdog::attempted();
+    let mut attempt = 1;
+    let mut tor_stream = loop {
+        let prefs = stream_prefs_for(&isolation_key);
+        let connect = connector.connect(&destination.dial_host, target_port, &prefs);
+        // The client's own `timeout=` or `deadline=` (over every attempt),
+        // else Arti's stream timeout alone
+        let connected = match connect_limit {
+            Some(limit) => tokio::select! {
+                result = connect => Some(result),
+                _ = seams::sleep(limit.saturating_sub(seams::now_monotonic().saturating_duration_since(connect_started))) => None,
+            },
+            None => Some(connect.await),
+        };
+        let tor_stream = match connected {
+            Some(Ok(s)) => s,
+            None => {
+                METRICS.connect_failures.fetch_add(1, Ordering::Relaxed);
+                quality::record(target_host, target_port, quality::Sample::failed());
+                log_error!("Tor connect to {}:{} exceeded the client's timeout", shown_host, target_port);
+                socks::refuse(&mut stream, socks::Reply::TtlExpired).await?;
+                return Err(anyhow::anyhow!("Connect exceeded the client's timeout"));
+            }
+            Some(Err(e)) => {
+                METRICS.connect_failures.fetch_add(1, Ordering::Relaxed);
+                quality::record(target_host, target_port, quality::Sample::failed());
+                log_error!("Failed to connect through Tor: {:?}", e);
+                guards::check_error(&e, "connect");
+                if let Some(client) = connector.client() {
+                    entry_watch::failed(client, e.kind(), target_host);
+                }
+                bridge_health::failed(e.kind());
+                let failure = security::check_connect_error(e, target_port);
+                socks::refuse(&mut stream, failure.reply()).await?;
+                return Err(failure.into());
+            }
+        };
+        // Exits lacking a required flag are retried on fresh circuits
+        let Some(exit) = tor_stream.exit_fingerprint() else { break tor_stream };
+        let Some(flag) = exits::missing_flag(&exit) else { break tor_stream };
+        exits::reject(&exit, flag);
+        if attempt == exits::MAX_ATTEMPTS {
+            METRICS.connect_failures.fetch_add(1, Ordering::Relaxed);
+            log_error!("No exit with the required flags for {}:{} in {} attempts", shown_host, target_port, attempt);
+            socks::refuse(&mut stream, socks::Reply::NotAllowedByRuleset).await?;
+            return Err(anyhow::anyhow!("CONNECT refused: no exit carried the required flags"));
+        }
+        attempt += 1;
+        isolation_key = IsolationKey::for_connect(target_port, destination.claimed_host.as_deref(), &credentials);
+    };
+    guards::clear();
+    let entry = tor_stream.data_stream().and_then(circuit_path::entry);
+    bridge_health::connected(entry.as_ref(), seams::now_monotonic().saturating_duration_since(connect_started));
+    entry_watch::connected(entry);
+
+    // Optionally make sure the stream is really open before the client is
+    // told so: with optimistic data, connect returns before the exit has
+    // answered, and a failure then would surface only after the client has
+    // sent its first payload
+    if CONFIRM_STREAM_WRITABLE.load(Ordering::Relaxed) {
+        let confirm_started = seams::now_monotonic();
+        let confirmed = async {
+            tor_stream.wait_for_connection().await?;
+            tor_stream.flush().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = confirmed {
+            METRICS.stream_confirm_failures.fetch_add(1, Ordering::Relaxed);
+            quality::record(target_host, target_port, quality::Sample::failed());
+            log_error!("Tor stream to {}:{} failed confirmation: {}", shown_host, target_port, e);
+            socks::refuse(&mut stream, socks::Reply::ConnectionRefused).await?;
+            return Err(e);
+        }
+        let confirm_ms = seams::now_monotonic().saturating_duration_since(confirm_started).as_millis() as u64;
+        METRICS.stream_confirmations.fetch_add(1, Ordering::Relaxed);
+        METRICS.stream_confirm_ms_total.fetch_add(confirm_ms, Ordering::Relaxed);
+    }
+
+    let connect_latency = seams::now_monotonic().saturating_duration_since(connect_started);
+    METRICS.connects_established.fetch_add(1, Ordering::Relaxed);
+    METRICS.connect_ms_total.fetch_add(connect_latency.as_millis() as u64, Ordering::Relaxed);
+    traffic::count_stream();
+    proxy_watchdog::established();
+    log_info!(
+        "Tor connection established to {}:{}{}{}",
+        shown_host,
+        target_port,
+        if chaos.active() { " (chaos)" } else { "" },
+        annotation
+    );
+    let data_stream = tor_stream.data_stream();
+    if let Some(data_stream) = data_stream {
+        circuit_path::log(data_stream, target_port);
+        diversity::record(data_stream, target_host);
+    }
+    registration.set_exit_platform(tor_stream.exit_fingerprint().and_then(|exit| geo::platform_of(&exit)));
+    let _isolation_group = isolation_groups::join(isolation_key.describe(), data_stream.and_then(circuit_path::circuit_id));
+    let mut meter = quality::Meter::start();
+
+    // Writes toward the client are watched for stalls from here on
+    let watch = stall::Watch::new();
+    let (client_read, client_write) = stream.split();
+    let mut client_write = watch.writer(flow.writer(flow_stall::Side::Client, client_write));
+
+    let forward = async {
+        // Send SOCKS5 success response
+        client_write.write_all(&socks::Reply::Succeeded.frame()).await?;
+
+        // Bidirectional data forwarding
+        let (tor_read, tor_write) = tor_stream.split();
+        let mut tor_write = flow.writer(flow_stall::Side::Tor, tor_write);
+        let mut client_read = CountingReader(client_read);
+        let mut tor_read = quality::MeteredReader { inner: CountingReader(tor_read), meter: &mut meter };
+
+        let mut client_buf = vec![0u8; lease.buffer_size];
+        let mut tor_buf = vec![0u8; lease.buffer_size];
+
+        // Each direction is shaped on its own (a no-op without a chaos profile)
+        let mut upstream = chaos.shaper();
+        let mut downstream = chaos.shaper();
+
+        let client_to_tor = async {
+            relay(&mut client_read, &mut tor_write, &mut client_buf, &mut upstream, traffic::Direction::Upstream).await
+        };
+
+        let tor_to_client = async {
+            relay(&mut tor_read, &mut client_write, &mut tor_buf, &mut downstream, traffic::Direction::Downstream).await
+        };
+
+        // Run both directions concurrently, exit when either completes
+        tokio::select! {
+            result = client_to_tor => {
+                if let Err(ref e) = result {
+                    log_error!("Client->Tor copy error: {:?}", e);
+                }
+            }
+            result = tor_to_client => {
+                if let Err(ref e) = result {
+                    log_error!("Tor->Client copy error: {:?}", e);
+                }
+            }
+        };
+        Ok::<(), std::io::Error>(())
+    };
+
+    // A client that stops reading is closed by the stall sweep
+    let close_reason = tokio::select! {
+        result = forward => {
+            result?;
+            "done"
+        }
+        _ = watch.stalled() => {
+            stall::count_close();
+            "client_stalled"
+        }
+    };
+
+    drop(lease);
+    quality::record(target_host, target_port, meter.sample(connect_latency));
+    log_info!(
+        "SOCKS connection closed for {}:{} ({}){}{}",
+        shown_host,
+        target_port,
+        close_reason,
+        annotation,
+        flow.record().summary()
+    );
+
+    Ok(())
+}
+
+/// Copy one direction of a connection until EOF using a leased buffer
+///
+/// Bytes are forwarded verbatim; nothing after the SOCKS reply is inspected or
+/// rewritten, so application-level credentials and TLS ClientHellos (SNI
+/// included) pass through untouched.
+async fn relay<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buf: &mut [u8],
+    shaper: &mut chaos::Shaper,
+    direction: traffic::Direction,
+) -> std::io::Result<u64>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(buf).await?;
+        if n == 0 {
+            writer.flush().await?;
+            return Ok(total);
+        }
+        shaper.pace(n).await;
+        writer.write_all(&buf[..n]).await?;
+        // Arti packs stream data into cells and only sends on flush
+        writer.flush().await?;
+        traffic::count_relayed(direction, n);
+        total += n as u64;
+    }
+}
+
+/// Read an RFC 1929 username/password request
+async fn read_socks_credentials(
+    stream: &mut tokio::net::TcpStream,
+) -> Result<(zeroize::Zeroizing<Vec<u8>>, zeroize::Zeroizing<Vec<u8>>)> {
+    use tokio::io::AsyncReadExt;
+
+    let version = stream.read_u8().await?;
+    if version != 0x01 {
+        return Err(anyhow::anyhow!("Unsupported SOCKS auth version: {}", version));
+    }
+
+    let ulen = stream.read_u8().await? as usize;
+    let mut username = zeroize::Zeroizing::new(vec![0u8; ulen]);
+    stream.read_exact(&mut username).await?;
+
+    let plen = stream.read_u8().await? as usize;
+    let mut password = zeroize::Zeroizing::new(vec![0u8; plen]);
+    stream.read_exact(&mut password).await?;
+
+    Ok((username, password))
+}
+
+/// Reader adapter that adds every byte read to the relayed-bytes metric
+struct CountingReader<R>(R);
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = std::pin::Pin::new(&mut self.0).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        if read > 0 {
+            METRICS.bytes_relayed.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+/// Test whether an onion service is reachable
+///
+/// Opens a stream to the onion with a timeout and drops it immediately.
+/// Returns 0 if reachable, 1 if no descriptor was found, 2 if introduction or
+/// rendezvous failed, 3 on timeout, 4 on any other connection failure;
+/// -1 if the client is not initialized, -2 if the runtime is not initialized,
+/// -3 on invalid arguments, `ERR_FEATURE_DISABLED` without the
+/// `onion-service-client` feature.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_testOnionReachable(
+    mut env: JNIEnv,
+    _class: JClass,
+    onion_addr: JString,
+    port: jint,
+    timeout_secs: jint,
+) -> jint {
+    if !features::require("onion-service-client", "testOnionReachable") {
+        return ERR_FEATURE_DISABLED;
+    }
+    let onion: String = match env.get_string(&onion_addr) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert onion address: {:?}", e);
+            return -3;
+        }
+    };
+    let port = match u16::try_from(port) {
+        Ok(p) if p != 0 && onion.ends_with(".onion") && timeout_secs > 0 => p,
+        _ => {
+            log_error!("Invalid onion reachability request: {}:{} ({}s)", onion, port, timeout_secs);
+            return -3;
+        }
+    };
+
+    let client = match ARTI_CLIENT.lock().unwrap().as_ref() {
+        Some(c) => Arc::clone(c),
+        None => {
+            log_error!("Arti client not initialized - call initialize() first");
+            return ERR_NOT_RUNNING;
+        }
+    };
+
+    let runtime = match runtime_handle() {
+        Some(rt) => rt,
+        None => {
+            log_error!("Tokio runtime not initialized");
+            return -2;
+        }
+    };
+
+    log_info!("Testing onion reachability: {}:{}", redact::Sensitive(&onion), port);
+    let timeout = tokio::time::Duration::from_secs(timeout_secs as u64);
+    let code = runtime.block_on(onion_reachability(&*client, &onion, port, timeout));
+
+    log_info!("Onion reachability result for {}:{}: {}", redact::Sensitive(&onion), port, code);
+    code
+}
+
+/// One `testOnionReachable` attempt through `connector`, as its result code
+async fn onion_reachability<C: seams::Connector>(
+    connector: &C,
+    onion: &str,
+    port: u16,
+    timeout: std::time::Duration,
+) -> jint {
+    let prefs = StreamPrefs::new();
+    let result = tokio::select! {
+        result = connector.connect(onion, port, &prefs) => Some(result),
+        _ = seams::sleep(timeout) => None,
+    };
+
+    // The stream (if any) is dropped here, so no connection lingers
+    match result {
+        Some(Ok(_stream)) => 0,
+        None => 3,
+        Some(Err(e)) => {
+            log_info!("Onion reachability error: {}", e);
+            security::check_error(&e, "onion_reachability");
+            match e.kind() {
+                ErrorKind::OnionServiceNotFound => 1,
+                ErrorKind::OnionServiceNotRunning
+                | ErrorKind::OnionServiceConnectionFailed => 2,
+                ErrorKind::RemoteNetworkTimeout | ErrorKind::TorNetworkTimeout => 3,
+                _ => 4,
+            }
+        }
+    }
+}
+
+/// Quickly test whether the Tor network is directly reachable (no bridges)
+///
+/// Races direct connections to a few guards or directory authorities; meant
+/// to drive the "should I enable bridges?" decision without a full bootstrap.
+/// Works with or without an initialized client. Returns 0 if reachable, 1 if
+/// blocked, 2 if unknown (the device appears offline); -2 if the runtime is
+/// not initialized, -3 on invalid arguments, -4 if strict Tor-only mode
+/// forbids direct connections.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_probeDirectReachability(
+    _env: JNIEnv,
+    _class: JClass,
+    timeout_secs: jint,
+) -> jint {
+    if timeout_secs <= 0 {
+        log_error!("Invalid reachability probe timeout: {}s", timeout_secs);
+        return -3;
+    }
+    if let Err(e) = net::ensure_direct_allowed("reachability_probe") {
+        log_error!("{}", e);
+        return -4;
+    }
+
+    let client = ARTI_CLIENT.lock().unwrap().as_ref().map(Arc::clone);
+    let runtime = match runtime_handle() {
+        Some(rt) => rt,
+        None => {
+            log_error!("Tokio runtime not initialized");
+            return -2;
+        }
+    };
+
+    let timeout = std::time::Duration::from_secs(timeout_secs as u64);
+    let result = runtime.block_on(reachability::probe(client.as_deref(), timeout));
+    log_info!("Direct reachability: {:?}", result);
+    result as jint
+}
+
+/// Name resolved through the exit to time a round trip
+const LATENCY_PROBE_HOST: &str = "torproject.org";
+
+/// Measure the round-trip time through an exit circuit, in milliseconds
+///
+/// Times a RESOLVE of a fixed name through the exit. One unmeasured resolve
+/// goes first, so that a circuit exists and the exit has the name cached:
+/// the timed one then reflects the circuit, not circuit building or the
+/// exit's DNS. Returns the latency (at least 1); -1 if the client is not
+/// initialized, -2 if the runtime is not initialized, -3 on invalid
+/// arguments, -4 if there is no usable circuit (not ready for traffic), -5 on
+/// timeout or if the resolve failed.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_measureCircuitLatency(
+    _env: JNIEnv,
+    _class: JClass,
+    timeout_secs: jint,
+) -> jint {
+    if timeout_secs <= 0 {
+        log_error!("Invalid circuit latency timeout: {}s", timeout_secs);
+        return -3;
+    }
+
+    let client = match ARTI_CLIENT.lock().unwrap().as_ref() {
+        Some(c) => Arc::clone(c),
+        None => {
             log_error!("Arti client not initialized - call initialize() first");
+            return ERR_NOT_RUNNING;
+        }
+    };
+    let ready = client.bootstrap_status().ready_for_traffic();
+
+    let runtime = match runtime_handle() {
+        Some(rt) => rt,
+        None => {
+            log_error!("Tokio runtime not initialized");
+            return -2;
+        }
+    };
+
+    let timeout = std::time::Duration::from_secs(timeout_secs as u64);
+    runtime.block_on(measure_latency(ready, timeout, || async {
+        client.resolve(LATENCY_PROBE_HOST).await.map(|_| ())
+    }))
+}
+
+/// Time the second of two `resolve` calls (see `measureCircuitLatency`)
+async fn measure_latency<F, E>(ready: bool, timeout: std::time::Duration, mut resolve: impl FnMut() -> F) -> jint
+where
+    F: std::future::Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    if !ready {
+        log_info!("Circuit latency not measured: no circuit is ready for traffic");
+        return -4;
+    }
+    let result = tokio::time::timeout(timeout, async {
+        resolve().await?;
+        let started = seams::now_monotonic();
+        resolve().await?;
+        Ok::<_, E>(seams::now_monotonic().saturating_duration_since(started))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(rtt)) => {
+            let ms = rtt.as_millis().clamp(1, jint::MAX as u128) as jint;
+            log_info!("Circuit latency: {} ms", ms);
+            ms
+        }
+        Ok(Err(e)) => {
+            log_info!("Circuit latency resolve failed: {}", e);
+            -5
+        }
+        Err(_) => {
+            log_info!("Circuit latency measurement timed out after {}s", timeout.as_secs());
+            -5
+        }
+    }
+}
+
+/// Run the loopback smoke test (see smoke.rs) and report as JSON
+///
+/// `{"ok":..,"state":..,"echo":..,"listener":..,"tor_leg":..,"route":..,
+/// "detail":..,"echo_ms":..,"listener_ms":..,"connect_ms":..,
+/// "round_trip_ms":..,"bytes_sent":..,"bytes_received":..}` where each leg is
+/// `passed`, `failed` or `skipped`, `detail` says why the first leg that
+/// didn't pass didn't, and `route` is `tor`, or `loopback` in `testing-net`
+/// builds. Blocks for up to about two minutes when the Tor leg runs.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_loopbackSmokeTest(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let state = LifecycleState::from_i32(LIFECYCLE_STATE.load(Ordering::SeqCst));
+    let ready = ARTI_CLIENT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|client| client.bootstrap_status().ready_for_traffic());
+    let listener_port = SOCKS_TASK
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|task| !task.is_finished())
+        .then(|| SOCKS_PORT.load(Ordering::SeqCst) as u16);
+
+    let response = match runtime_handle() {
+        Some(handle) => api::ok(&handle.block_on(smoke::run(state.name(), listener_port, ready))),
+        None => api::error("not_running", "Tokio runtime not initialized - call initialize() first"),
+    };
+    let output = env.new_string(response).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Set the `host:port` the loopback smoke test connects to through Tor
+///
+/// The destination must echo what it receives (e.g. a test onion service
+/// running an echo server). Empty skips the Tor leg. Returns 0 on success,
+/// -1 if not `host:port`.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setSmokeTestDestination(
+    mut env: JNIEnv,
+    _class: JClass,
+    host_port: JString,
+) -> jint {
+    let destination: String = match env.get_string(&host_port) {
+        Ok(s) => s.into(),
+        Err(_) => return -1,
+    };
+    if let Err(e) = config::set("smoke_test_destination", serde_json::json!(destination)) {
+        log_error!("Invalid smoke test destination: {}", e);
+        return -1;
+    }
+    log_info!("Smoke test destination {}", if destination.is_empty() { "cleared" } else { "set" });
+    0
+}
+
+/// Why the SOCKS accept loop last exited, as JSON
+///
+/// `{"reason":..,"detail":..,"at_epoch_ms":..}`, or `null` if it never
+/// exited. `reason` is `stopped` (stop, shutdown or a data directory
+/// migration), `restarted` (replaced by a later startSocksProxy),
+/// `accept_error` (the listener failed; restarting may help) or `fatal` (the
+/// task ended some other way).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getLastShutdownReason(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&listener_exit::last_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Cancel every in-flight proxy connection, including pending connects
+fn cancel_connections() {
+    if let Some(cancel) = SOCKS_CANCEL.lock().unwrap().take() {
+        log_info!("Cancelling in-flight SOCKS connections");
+        cancel.cancel();
+    }
+}
+
+/// Close the live connections whose target matches `host_pattern`
+///
+/// The pattern is a host where `*` matches any run of characters
+/// (`*.example.com`), optionally with a `:port` suffix; see connections.rs.
+/// Returns how many connections were closed, or -1 for an empty or invalid
+/// pattern.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_closeConnectionsToTarget(
+    mut env: JNIEnv,
+    _class: JClass,
+    host_pattern: JString,
+) -> jint {
+    let text: String = match env.get_string(&host_pattern) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert host pattern: {:?}", e);
+            return -1;
+        }
+    };
+    let Some(pattern) = connections::Pattern::parse(&text) else {
+        log_error!("Invalid host pattern: {}", text);
+        return -1;
+    };
+    let closed = connections::close_matching(&pattern);
+    log_info!("Closed {} connection(s) matching {}", closed, text);
+    closed.min(jint::MAX as usize) as jint
+}
+
+/// List the live connections as JSON
+///
+/// One object per connection past the SOCKS handshake: its target and the
+/// flow stalls so far, by the side that stopped consuming (see flow_stall.rs).
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_listConnections(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&connections::list_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+/// Stop Arti and cleanup
+///
+/// Debounced (see debounce.rs): with the proxy running, the stop is deferred
+/// for the debounce window and returns 0 at once, and a matching start within
+/// the window takes it back. A stop during a bootstrap cancels it first.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_stop(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    stop()
+}
+
+fn stop() -> jint {
+    match debounce::decide_now(debounce::Command::Stop, socks_listener_running(), false) {
+        debounce::Decision::Merge => {
+            log_info!("Stop already pending; merged");
+            0
+        }
+        debounce::Decision::Defer => {
+            log_info!("Stop deferred for the debounce window");
+            debounce::defer_stop(|| {
+                stop_within(None);
+            });
+            0
+        }
+        // `stop_within` cancels a bootstrap in flight
+        _ => stop_within(None),
+    }
+}
+
+/// Stop like `stop`, returning within `deadline_ms`
+///
+/// Never debounced: a pending stop runs now, within the deadline, and a
+/// bootstrap in flight is cancelled.
+///
+/// State flushes and the drain get budgets out of the deadline and are
+/// skipped or abandoned when it is tight (see shutdown.rs); a deadline of 0
+/// or less skips them all. `getLastShutdownReport` tells what happened.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_stopWithDeadline(
+    _env: JNIEnv,
+    _class: JClass,
+    deadline_ms: jint,
+) -> jint {
+    stop_within(Some(std::time::Duration::from_millis(deadline_ms.max(0) as u64)))
+}
+
+/// Get what the last stop did with each of its phases as JSON (null before any stop)
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_getLastShutdownReport(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    let output = env.new_string(api::ok(&shutdown::report_json())).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+fn stop_within(deadline: Option<std::time::Duration>) -> jint {
+    let started = seams::now_monotonic();
+    // An immediate stop supersedes a deferred one
+    debounce::take_pending();
+    if debounce::cancel_bootstrap() {
+        log_info!("Cancelling the bootstrap in flight");
+    }
+    set_state(LifecycleState::Stopping);
+    log_info!("Stopping Arti...");
+
+    // Abort SOCKS proxy task (releases the port)
+    proxy_watchdog::disarm();
+    if let Some(handle) = SOCKS_TASK.lock().unwrap().take() {
+        log_info!("Aborting SOCKS server task");
+        listener_exit::aborting(listener_exit::Reason::Stopped, "stop");
+        handle.abort();
+    }
+    cancel_connections();
+    if !retention::retains(retention::Sink::MetricsAcrossStop) {
+        METRICS.reset();
+    }
+
+    // Flush state, then give the abort and cancellations a moment to complete
+    // and release the port, as far as the deadline allows
+    shutdown::run(started, deadline);
+
+    // NOTE: We do NOT clear ARTI_CLIENT here!
+    // The TorClient can be reused for multiple SOCKS proxy start/stop cycles.
+    // Only clear it if you want to force full reinitialization.
+
+    // Uncomment this line only if you want to force reinitialization on every start:
+    // *ARTI_CLIENT.lock().unwrap() = None;
+
+    set_state(LifecycleState::Stopped);
+    log_info!("Arti stopped successfully");
+
+    0
+}
+
+/// Stop for good because the process is going away
+///
+/// Switches logging to logcat and the log file first, so the final lines
+/// survive a JVM that is already detaching threads (an upcall then fails and
+/// the line is lost). Then stops like `stop`, gives the log file writer a
+/// moment to catch up, and releases every callback and the data directory
+/// lock (see state_lock.rs). A later `initialize` switches Java logging back
+/// on and takes the lock again.
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_shutdown(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    shutdown()
+}
+
+fn shutdown() -> jint {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    log_info!("Shutting down; logging to logcat and the log file only");
+    // Never debounced: the process is going away
+    let result = stop_within(None);
+    state_lock::release();
+    log_file::flush(SHUTDOWN_LOG_FLUSH);
+    callbacks::release_all();
+    result
+}
+
+// ============================================================================
+// Onion Service Keys (`feature_disabled` without `onion-service-hosting`)
+// ============================================================================
+
+/// Check the stored identity key of onion service `nickname` as JSON
+///
+/// Reports `valid`, the `problem` found (`missing`, `unreadable`,
+/// `malformed`, `encrypted`, `wrong_type`, `check_mismatch`,
+/// `public_key_mismatch` or `bad_length`) and the onion `address` derived
+/// from an intact key (see onion_key.rs).
+#[cfg(feature = "onion-service-hosting")]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_verifyOnionKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    nickname: JString,
+) -> jstring {
+    let nickname: String = env.get_string(&nickname).map(Into::into).unwrap_or_default();
+    let data_dir = DATA_DIR.lock().unwrap().clone();
+    let response = match data_dir {
+        _ if !onion_key::valid_nickname(&nickname) => api::error("invalid_argument", "invalid onion service nickname"),
+        Some(dir) => api::ok(&onion_key::verify_json(&dir, &nickname)),
+        None => api::error("not_running", "No data directory - call initialize() first"),
+    };
+    let output = env.new_string(response).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+#[cfg(not(feature = "onion-service-hosting"))]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_verifyOnionKey(
+    env: JNIEnv,
+    _class: JClass,
+    _nickname: JString,
+) -> jstring {
+    let response = api::error("feature_disabled", "this build lacks the `onion-service-hosting` feature");
+    let output = env.new_string(response).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+// ============================================================================
+// Chaos Profile (only with the `chaos` feature)
+// ============================================================================
+
+/// Degrade connections accepted from now on with a synthetic profile
+///
+/// See chaos.rs for the JSON fields; an empty string or object clears the
+/// profile. Returns 0 on success, -1 if the profile is invalid.
+#[cfg(feature = "chaos")]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_setChaosProfile(
+    mut env: JNIEnv,
+    _class: JClass,
+    profile_json: JString,
+) -> jint {
+    let text: String = match env.get_string(&profile_json) {
+        Ok(s) => s.into(),
+        Err(e) => {
+            log_error!("Failed to convert chaos profile: {:?}", e);
             return -1;
         }
     };
-    drop(client_guard);
+    match chaos::parse(&text) {
+        Ok(profile) => {
+            let active = profile.is_some();
+            chaos::set(profile);
+            log_info!("Chaos profile {}", if active { "set for new connections" } else { "cleared" });
+            0
+        }
+        Err(e) => {
+            log_error!("Invalid chaos profile: {}", e);
+            -1
+        }
+    }
+}
 
-    let runtime_guard = TOKIO_RUNTIME.lock().unwrap();
-    let runtime = match runtime_guard.as_ref() {
-        Some(rt) => rt,
-        None => {
-            log_error!("Tokio runtime not initialized");
-            return -2;
+// ============================================================================
+// Test Seams (only with the `test-seams` feature)
+// ============================================================================
+
+/// Switch to a frozen fake clock that only moves via testAdvanceClock
+#[cfg(feature = "test-seams")]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_testUseFakeClock(
+    _env: JNIEnv,
+    _class: JClass,
+) {
+    seams::fakes::use_fake_clock();
+}
+
+/// Advance the fake clock, waking every sleeper whose deadline has passed
+#[cfg(feature = "test-seams")]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_testAdvanceClock(
+    _env: JNIEnv,
+    _class: JClass,
+    millis: jlong,
+) {
+    seams::fakes::advance_clock(std::time::Duration::from_millis(millis.max(0) as u64));
+}
+
+/// Switch to deterministic entropy derived from `seed`
+#[cfg(feature = "test-seams")]
+#[no_mangle]
+pub extern "C" fn Java_org_torproject_arti_ArtiNative_testSeedEntropy(
+    _env: JNIEnv,
+    _class: JClass,
+    seed: jlong,
+) {
+    seams::fakes::seed_entropy(seed as u64);
+}
+
+// ============================================================================
+// Android Logger (simple implementation)
+// ============================================================================
+
+/// Logcat output (stderr on a host build, where host tests run)
+mod android_logger {
+    #[cfg(target_os = "android")]
+    use std::ffi::CString;
+
+    #[cfg(target_os = "android")]
+    #[allow(non_camel_case_types)]
+    type c_int = i32;
+
+    #[cfg(target_os = "android")]
+    #[allow(non_camel_case_types)]
+    type c_char = i8;
+
+    #[cfg(target_os = "android")]
+    extern "C" {
+        fn __android_log_write(prio: c_int, tag: *const c_char, text: *const c_char) -> c_int;
+    }
+
+    #[cfg(target_os = "android")]
+    const ANDROID_LOG_INFO: c_int = 4;
+
+    #[cfg(target_os = "android")]
+    pub fn log(message: &str) {
+        unsafe {
+            let tag = CString::new("ArtiNative").unwrap();
+            let text = CString::new(message).unwrap();
+            __android_log_write(ANDROID_LOG_INFO, tag.as_ptr() as *const c_char, text.as_ptr() as *const c_char);
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub fn log(message: &str) {
+        #[cfg(test)]
+        crate::tests::LOGCAT.lock().unwrap().push(message.to_string());
+        eprintln!("ArtiNative: {}", message);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&ARTI_CLIENT);
+    held.hold(&TOKIO_RUNTIME);
+    held.hold(&SOCKS_TASK);
+    held.hold(&SOCKS_CANCEL);
+    held.hold(&DATA_DIR);
+    held.hold(&LOG_DELIVERY);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use arti_client::ErrorKind;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use super::{add_onion_alias, isolation, measure_latency, onion_reachability, set_state, IsolationKey, LifecycleState, IDENTITY_GENERATION};
+    use crate::seams::fakes::{FakeConnect, FakeConnector};
+    use crate::socks::Reply;
+    use crate::testing::{self, socks_connect, socks_greet, socks_login, socks_session, FakeTime};
+    use crate::config;
+
+    /// States sent to the state callback (there is no JVM to receive them)
+    pub(super) static SENT_STATES: Mutex<Vec<LifecycleState>> = Mutex::new(Vec::new());
+
+    /// `(method, value)` sent to string callbacks
+    pub(crate) static SENT_STRINGS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    /// Lines handed to the log callback
+    pub(super) static SENT_LOG_LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Lines written to logcat
+    pub(super) static LOGCAT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Stands in for the registered Java log callback when set
+    pub(super) static MOCK_LOG_CALLBACK: Mutex<Option<Arc<RecordingCallback>>> = Mutex::new(None);
+
+    /// A log callback recording what reached it, replayed or live
+    #[derive(Default)]
+    pub(super) struct RecordingCallback(Mutex<Vec<(String, bool)>>);
+
+    impl RecordingCallback {
+        pub(super) fn on_log_line(&self, line: &str, replayed: bool) {
+            self.0.lock().unwrap().push((line.to_string(), replayed));
+        }
+
+        /// Lines containing `marker`, as `(line, replayed)`
+        fn lines(&self, marker: &str) -> Vec<(String, bool)> {
+            self.0.lock().unwrap().iter().filter(|(line, _)| line.contains(marker)).cloned().collect()
+        }
+    }
+
+    /// Register `callback` the way `setLogCallback*` does
+    fn register_mock(callback: &Arc<RecordingCallback>, replay_from_ring: bool) -> bool {
+        super::swap_log_callback(
+            || {
+                *MOCK_LOG_CALLBACK.lock().unwrap() = Some(Arc::clone(callback));
+                true
+            },
+            replay_from_ring,
+            |line| callback.on_log_line(line, true),
+        )
+    }
+
+    #[test]
+    fn replacing_the_log_callback_splits_the_log_at_one_point() {
+        const PRODUCERS: usize = 4;
+        // Each producer logs at least this many lines, and this many more
+        // after the swap returns
+        const LINES: usize = 300;
+        let _serial = testing::serial();
+        let marker = "swap-order-probe";
+        let line = |producer: usize, i: usize| format!("{} p{} #{:04}", marker, producer, i);
+        let index = |text: &str| -> (usize, usize) {
+            let (producer, i) = text.rsplit_once(" p").unwrap().1.split_once(" #").unwrap();
+            (producer.parse().unwrap(), i.parse().unwrap())
+        };
+
+        let old = Arc::new(RecordingCallback::default());
+        let new = Arc::new(RecordingCallback::default());
+        assert!(register_mock(&old, false));
+        let started = std::sync::Barrier::new(PRODUCERS + 1);
+        let swapped = std::sync::atomic::AtomicBool::new(false);
+        let old_at_swap = std::thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let (started, swapped) = (&started, &swapped);
+                scope.spawn(move || {
+                    started.wait();
+                    let mut after_swap = 0;
+                    for i in 0.. {
+                        log_info!("{}", line(producer, i));
+                        if swapped.load(Ordering::SeqCst) {
+                            after_swap += 1;
+                        }
+                        if i >= LINES && after_swap >= LINES {
+                            break;
+                        }
+                    }
+                });
+            }
+            started.wait();
+            // Swap once lines are flowing, while the producers keep going
+            while old.lines(marker).len() < PRODUCERS * LINES / 4 {
+                std::thread::yield_now();
+            }
+            assert!(register_mock(&new, true));
+            swapped.store(true, Ordering::SeqCst);
+            old.lines(marker).len()
+        });
+        *MOCK_LOG_CALLBACK.lock().unwrap() = None;
+
+        let old_lines = old.lines(marker);
+        let new_lines = new.lines(marker);
+        // Nothing reached the old callback once the swap returned
+        assert_eq!(old_lines.len(), old_at_swap);
+        assert!(old_lines.iter().all(|(_, replayed)| !replayed));
+
+        // The replay comes first, then only live lines
+        let replayed: Vec<&String> = new_lines.iter().take_while(|(_, replayed)| *replayed).map(|(text, _)| text).collect();
+        let live: Vec<&String> = new_lines[replayed.len()..].iter().map(|(text, replayed)| {
+            assert!(!replayed, "replayed line after a live one: {}", text);
+            text
+        }).collect();
+        assert!(!replayed.is_empty() && !live.is_empty(), "{} replayed, {} live of {}", replayed.len(), live.len(), old_at_swap);
+        // It is the ring at the swap: the newest lines the old callback got,
+        // each once
+        let old_texts: Vec<&String> = old_lines.iter().map(|(text, _)| text).collect();
+        assert_eq!(replayed, old_texts[old_texts.len() - replayed.len()..]);
+        assert!(live.iter().all(|text| !replayed.contains(text)));
+
+        // Every line went live to exactly one callback, each producer's
+        // lines split at one point
+        let mut seen = vec![Vec::<u8>::new(); PRODUCERS];
+        for text in old_texts.iter().chain(&live) {
+            let (producer, i) = index(text);
+            if seen[producer].len() <= i {
+                seen[producer].resize(i + 1, 0);
+            }
+            seen[producer][i] += 1;
+        }
+        assert!(seen.iter().flatten().all(|count| *count == 1));
+        assert!(seen.iter().all(|lines| lines.len() > LINES));
+        for producer in 0..PRODUCERS {
+            let last_old = old_texts.iter().map(|text| index(text)).filter(|(p, _)| *p == producer).map(|(_, i)| i).max();
+            let first_new = live.iter().map(|text| index(text)).filter(|(p, _)| *p == producer).map(|(_, i)| i).min();
+            if let (Some(last_old), Some(first_new)) = (last_old, first_new) {
+                assert_eq!(first_new, last_old + 1, "producer {}", producer);
+            }
+        }
+    }
+
+    #[test]
+    fn logcat_can_be_off_while_the_callback_keeps_lines() {
+        let _serial = testing::serial();
+        let was = super::LOGCAT_ENABLED.load(Ordering::Relaxed);
+
+        for (enabled, marker) in [(false, "logcat test line while off"), (true, "logcat test line while on")] {
+            config::set("logcat_enabled", json!(enabled)).unwrap();
+            log_info!("{}", marker);
+            let in_logcat = LOGCAT.lock().unwrap().iter().any(|line| line.contains(marker));
+            let to_callback = SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains(marker));
+            assert_eq!(in_logcat, enabled, "{}", marker);
+            assert!(to_callback, "{}", marker);
+            assert!(crate::recent_logs::lines().iter().any(|line| line.contains(marker)), "{}", marker);
+        }
+
+        config::set("logcat_enabled", json!(was)).unwrap();
+    }
+
+    #[test]
+    fn a_log_flood_is_capped_but_errors_get_through() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let time = FakeTime::start();
+        config::set("log_rate_limit_per_sec", json!(10)).unwrap();
+        // Start a fresh window
+        time.advance(Duration::from_secs(2));
+        SENT_LOG_LINES.lock().unwrap().clear();
+        let dropped = crate::log_rate::dropped_lines();
+
+        for i in 0..200 {
+            log_info!("flood line {}", i);
+            if i % 40 == 0 {
+                log_error!("flood error {}", i);
+            }
+        }
+        let sent = SENT_LOG_LINES.lock().unwrap().clone();
+        let count = |prefix: &str| sent.iter().filter(|line| line.contains(prefix)).count();
+        // Other tests may log into the same window, so at most the cap
+        assert!((1..=10).contains(&count("flood line")), "{:?}", sent);
+        assert_eq!(count("flood error"), 5, "{:?}", sent);
+        assert!(crate::log_rate::dropped_lines() - dropped >= 190);
+
+        // The next window opens with the count of what was dropped
+        time.advance(Duration::from_secs(1));
+        log_info!("after the flood");
+        let sent = SENT_LOG_LINES.lock().unwrap().clone();
+        let notice = sent.iter().position(|line| line.ends_with("log line(s) dropped by the rate limit"));
+        let after = sent.iter().position(|line| line == "after the flood");
+        assert!(notice.is_some() && notice < after, "{:?}", sent);
+
+        // No limit: everything is delivered
+        config::set("log_rate_limit_per_sec", json!(0)).unwrap();
+        SENT_LOG_LINES.lock().unwrap().clear();
+        for i in 0..50 {
+            log_info!("unlimited line {}", i);
+        }
+        assert_eq!(SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains("unlimited line")).count(), 50);
+    }
+
+    #[test]
+    fn shutdown_logs_reach_logcat_and_the_file_but_not_java() {
+        let _serial = testing::serial();
+        let was = super::LOGCAT_ENABLED.load(Ordering::Relaxed);
+        let temp = testing::TempDir::new();
+        let path = temp.path().join("arti.log");
+        crate::log_file::set_path(path.to_str().unwrap());
+
+        for logcat in [true, false] {
+            super::LOGCAT_ENABLED.store(logcat, Ordering::Relaxed);
+            SENT_LOG_LINES.lock().unwrap().clear();
+            LOGCAT.lock().unwrap().clear();
+            super::shutdown();
+            let marker = format!("final line with logcat {}", logcat);
+            log_info!("{}", marker);
+            crate::log_file::flush(Duration::from_secs(5));
+
+            let file = std::fs::read_to_string(&path).unwrap();
+            let logcat_lines = LOGCAT.lock().unwrap().join("\n");
+            for line in ["Shutting down; logging to logcat and the log file only", "Arti stopped successfully", &marker] {
+                assert!(file.contains(line), "{}: {}", line, file);
+                assert_eq!(logcat_lines.contains(line), logcat, "{}: {}", line, logcat_lines);
+                assert!(crate::recent_logs::lines().iter().any(|l| l.contains(line)), "{}", line);
+            }
+            // Nothing was handed to the Java callback
+            let sent = SENT_LOG_LINES.lock().unwrap().clone();
+            assert!(!sent.iter().any(|line| line.contains("Shutting down") || line.contains(&marker)), "{:?}", sent);
+            super::SHUTTING_DOWN.store(false, Ordering::SeqCst);
+        }
+
+        crate::log_file::set_path("");
+        super::LOGCAT_ENABLED.store(was, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn without_a_log_callback_lines_land_in_the_ring_and_the_warning_comes_once() {
+        let _serial = testing::serial();
+        assert!(crate::callbacks::slot(crate::callbacks::Kind::Log).lock().unwrap().is_none());
+        let was = super::LOGCAT_ENABLED.load(Ordering::Relaxed);
+        // As in release builds
+        super::LOGCAT_ENABLED.store(false, Ordering::Relaxed);
+        let warning = "no log callback registered (setLogCallback)";
+        super::NO_LOG_CALLBACK_WARNED.store(false, Ordering::Relaxed);
+        LOGCAT.lock().unwrap().clear();
+
+        for i in 0..3 {
+            log_info!("line {} without a log callback", i);
+        }
+
+        let ring = crate::recent_logs::lines();
+        for i in 0..3 {
+            let marker = format!("line {} without a log callback", i);
+            assert!(ring.iter().any(|line| line.contains(&marker)), "{}", marker);
+        }
+        assert!(ring.iter().any(|line| line.contains(warning)));
+        let logcat = LOGCAT.lock().unwrap().clone();
+        assert_eq!(logcat.iter().filter(|line| line.contains(warning)).count(), 1, "{:?}", logcat);
+        assert!(!logcat.iter().any(|line| line.contains("without a log callback")), "{:?}", logcat);
+
+        super::LOGCAT_ENABLED.store(was, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn initialize_without_a_log_callback_warns_in_debug_builds() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let was = super::LOGCAT_ENABLED.load(Ordering::Relaxed);
+        // The warning shows whatever the logcat setting
+        super::LOGCAT_ENABLED.store(false, Ordering::Relaxed);
+        let warned = || LOGCAT.lock().unwrap().iter().any(|line| line.contains("initialize called before setLogCallback"));
+
+        // (debug build, callback registered, required, warns, initialize goes on)
+        for (debug_build, registered, required, warns, proceeds) in [
+            (true, false, false, true, true),
+            (true, false, true, true, false),
+            (true, true, false, false, true),
+            (true, true, true, false, true),
+            (false, false, false, false, true),
+            (false, false, true, false, true),
+        ] {
+            config::set("require_log_callback", json!(required)).unwrap();
+            LOGCAT.lock().unwrap().clear();
+            assert_eq!(super::check_log_callback_in(debug_build, registered), proceeds, "debug {} registered {} required {}", debug_build, registered, required);
+            assert_eq!(warned(), warns, "debug {} registered {} required {}", debug_build, registered, required);
+        }
+
+        // This build, which has no callback registered
+        assert!(crate::callbacks::slot(crate::callbacks::Kind::Log).lock().unwrap().is_none());
+        config::set("require_log_callback", json!(false)).unwrap();
+        LOGCAT.lock().unwrap().clear();
+        assert!(super::check_log_callback());
+        assert_eq!(warned(), cfg!(debug_assertions));
+
+        super::LOGCAT_ENABLED.store(was, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn state_values_match_the_java_contract() {
+        // ArtiNative.STATE_* constants
+        let table = [
+            (LifecycleState::Stopped, 0, "Stopped"),
+            (LifecycleState::Initialized, 1, "Initialized"),
+            (LifecycleState::Starting, 2, "Starting"),
+            (LifecycleState::Stopping, 3, "Stopping"),
+            (LifecycleState::Running, 4, "Running"),
+            (LifecycleState::StorageLost, 5, "StorageLost"),
+            (LifecycleState::DegradedStaleDirectory, 6, "DegradedStaleDirectory"),
+        ];
+        for (state, value, name) in table {
+            assert_eq!(state as i32, value);
+            assert_eq!(LifecycleState::from_i32(value), state);
+            assert_eq!(state.name(), name);
+        }
+        assert_eq!(LifecycleState::from_i32(99), LifecycleState::Stopped);
+    }
+
+    #[test]
+    fn state_callback_fires_once_per_transition() {
+        let _serial = testing::serial();
+        set_state(LifecycleState::Stopped);
+        SENT_STATES.lock().unwrap().clear();
+
+        use LifecycleState::*;
+        for state in [
+            Initialized,
+            Starting,
+            Running,
+            Running,
+            DegradedStaleDirectory,
+            Running,
+            StorageLost,
+            Running,
+            Stopping,
+            Stopped,
+            Stopped,
+        ] {
+            set_state(state);
+        }
+        let sent: Vec<i32> = SENT_STATES.lock().unwrap().drain(..).map(|state| state as i32).collect();
+        assert_eq!(sent, [1, 2, 4, 6, 4, 5, 4, 3, 0]);
+    }
+
+    #[test]
+    fn port_isolation_separates_ports() {
+        let _serial = testing::serial();
+        config::set("isolate_by_dest_port", json!(true)).unwrap();
+        let https = isolation(&IsolationKey::for_target(443, None));
+        let http = isolation(&IsolationKey::for_target(80, None));
+        let https_again = isolation(&IsolationKey::for_target(443, None));
+        config::set("isolate_by_dest_port", json!(false)).unwrap();
+
+        assert!(https.is_some() && http.is_some());
+        assert_ne!(https, http);
+        assert_eq!(https, https_again);
+    }
+
+    #[test]
+    fn without_port_isolation_ports_share() {
+        let _serial = testing::serial();
+        let https = IsolationKey::for_target(443, None);
+        assert_eq!(https, IsolationKey::for_target(80, None));
+        assert_eq!(https.dest_port, None);
+        assert_eq!(isolation(&https), isolation(&IsolationKey::for_target(80, None)));
+    }
+
+    #[test]
+    fn streams_share_circuits_only_with_equal_keys() {
+        use arti_client::isolation::IsolationHelper;
+
+        let _serial = testing::serial();
+        config::set("isolate_by_dest_port", json!(true)).unwrap();
+        let keys = [
+            IsolationKey::for_target(443, None),
+            IsolationKey::for_target(443, None),
+            IsolationKey::for_target(80, None),
+            IsolationKey::for_target(443, Some("example.com")),
+        ];
+        config::set("isolate_by_dest_port", json!(false)).unwrap();
+        for a in &keys {
+            for b in &keys {
+                assert_eq!(a.compatible_same_type(b), a == b, "{:?} and {:?}", a, b);
+                assert_eq!(a.join_same_type(b), (a == b).then(|| a.clone()), "{:?} and {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn traffic_classes_are_isolated_from_each_other() {
+        let _serial = testing::serial();
+        let token = |username: &str| isolation(&IsolationKey::for_connect(443, None, &crate::target::ConnectCredentials::parse(username)));
+        let chat = token("class=chat");
+        assert!(chat.is_some());
+        assert_eq!(token("connid=1;class=CHAT"), chat);
+        assert_ne!(token("class=bulk"), chat);
+        // No class, or one that isn't well formed: the key without one
+        let classless = isolation(&IsolationKey::for_target(443, None));
+        assert_ne!(classless, chat);
+        assert_eq!(token("connid=1"), classless);
+        assert_eq!(token("class=a b"), classless);
+    }
+
+    #[test]
+    fn isolation_tokens_are_isolated_from_each_other() {
+        let _serial = testing::serial();
+        let token = |username: &str| isolation(&IsolationKey::for_connect(443, None, &crate::target::ConnectCredentials::parse(username)));
+        let tab = token("iso=tab-1");
+        assert_ne!(tab, token("iso=tab-2"));
+        assert_ne!(tab, token("iso=TAB-1"));
+        assert_eq!(tab, token("connid=7;iso=tab-1"));
+        // Tokens and classes are separate: neither stands in for the other
+        assert_ne!(tab, token("class=tab-1"));
+        assert_ne!(token("iso=a;class=chat"), token("iso=a;class=bulk"));
+        assert_eq!(token("iso="), isolation(&IsolationKey::for_target(443, None)));
+    }
+
+    #[test]
+    fn port_isolation_composes_with_new_identity() {
+        let _serial = testing::serial();
+        config::set("isolate_by_dest_port", json!(true)).unwrap();
+        let before = isolation(&IsolationKey::for_target(443, None));
+        IDENTITY_GENERATION.fetch_add(1, Ordering::Relaxed);
+        let after = isolation(&IsolationKey::for_target(443, None));
+        let other_port = isolation(&IsolationKey::for_target(80, None));
+        config::set("isolate_by_dest_port", json!(false)).unwrap();
+
+        // Same port, but separated by the identity as well
+        assert_ne!(before, after);
+        assert_ne!(after, other_port);
+    }
+
+    #[test]
+    fn claimed_hosts_are_isolated_from_each_other() {
+        let _serial = testing::serial();
+        let token = |port, claim| isolation(&IsolationKey::for_target(port, claim));
+        let unclaimed = token(443, None);
+        let example = token(443, Some("example.com"));
+        // (port, claim, port isolation, shares a circuit with the first `example.com` stream)
+        for (port, claim, by_port, shares) in [
+            (443, Some("example.com"), false, true),
+            (443, Some("EXAMPLE.com"), false, true),
+            (80, Some("example.com"), false, true),
+            (443, Some("example.org"), false, false),
+            (443, None, false, false),
+            (80, Some("example.com"), true, false),
+        ] {
+            config::set("isolate_by_dest_port", json!(by_port)).unwrap();
+            assert_eq!(token(port, claim) == example, shares, "{:?} on port {} (port isolation {})", claim, port, by_port);
+        }
+        config::set("isolate_by_dest_port", json!(false)).unwrap();
+
+        // Unclaimed targets keep Arti's default isolation
+        assert_eq!(unclaimed, None);
+        assert!(example.is_some());
+    }
+
+    #[test]
+    fn socks_connection_relays_through_the_connector() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        let mut destination = connector.then_connect();
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            assert_eq!(socks_greet(&mut client, &[0x00]).await, 0x00);
+            assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
+
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            destination.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            destination.write_all(b"world").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+
+            drop(destination);
+            handler.await.unwrap().unwrap();
+        });
+        assert_eq!(connector.requests(), [("example.com".to_string(), 443)]);
+    }
+
+    #[test]
+    fn wire_bytes_are_at_least_the_payload_of_a_transfer() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        let mut destination = connector.then_connect();
+        let (payload_before, wire_before) = (super::METRICS.bytes_relayed.load(Ordering::Relaxed), crate::traffic::wire_bytes());
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
+
+            // Small and cell-sized chunks up, a bulk download back
+            let mut buf = vec![0u8; 64 * 1024];
+            for size in [1, 498, 499, 5000] {
+                client.write_all(&buf[..size]).await.unwrap();
+                destination.read_exact(&mut buf[..size]).await.unwrap();
+            }
+            let download = vec![7u8; 64 * 1024];
+            destination.write_all(&download).await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, download);
+
+            drop(destination);
+            handler.await.unwrap().unwrap();
+        });
+
+        let payload = super::METRICS.bytes_relayed.load(Ordering::Relaxed) - payload_before;
+        let wire = crate::traffic::wire_bytes() - wire_before;
+        assert_eq!(payload, 1 + 498 + 499 + 5000 + 64 * 1024);
+        assert!(wire >= payload, "{} wire bytes for {} payload bytes", wire, payload);
+    }
+
+    #[test]
+    fn failed_connect_is_refused() {
+        use crate::security::ConnectFailure;
+
+        let _serial = testing::serial();
+        // (failure, reply, failed at the crypto layer)
+        for (kind, reply, crypto) in [
+            (ErrorKind::RemoteConnectionRefused, Reply::ConnectionRefused, false),
+            (ErrorKind::TorNetworkTimeout, Reply::ConnectionRefused, false),
+            (ErrorKind::RelayIdMismatch, Reply::HostUnreachable, true),
+            (ErrorKind::TorProtocolViolation, Reply::HostUnreachable, true),
+        ] {
+            let connector = Arc::new(FakeConnector::new());
+            connector.then(FakeConnect::Fail(kind, "connect failed"));
+            SENT_STRINGS.lock().unwrap().clear();
+            testing::runtime().block_on(async {
+                let (mut client, handler, _closer) = socks_session(connector).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, reply.frame(), "{:?}", kind);
+                let error = handler.await.unwrap().unwrap_err();
+                let failure = error.downcast_ref::<ConnectFailure>().expect("a typed connect failure");
+                assert_eq!(matches!(failure, ConnectFailure::Crypto { .. }), crypto, "{:?}", kind);
+            });
+            // The cause travels in the stream event, not the reply
+            let events: Vec<serde_json::Value> = SENT_STRINGS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(method, _)| method == "onSecurityEvent")
+                .map(|(_, event)| serde_json::from_str(event).unwrap())
+                .collect();
+            if crypto {
+                assert_eq!(events.len(), 1, "{:?}", kind);
+                assert_eq!(events[0]["stream"], json!({"target_port": 443, "socks_reply": 0x04}));
+            } else {
+                assert!(events.is_empty(), "{:?}: {:?}", kind, events);
+            }
+        }
+    }
+
+    #[test]
+    fn denied_target_gets_the_ruleset_reply() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        crate::target::set_denylist(&json!(["*.blocked.example"]));
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            let reply = socks_connect(&mut client, "tracker.blocked.example", 443).await;
+            assert_eq!(reply, [0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+            // Closed with a FIN right after the reply, never a reset
+            let mut rest = Vec::new();
+            assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+            assert!(handler.await.unwrap().is_err());
+        });
+        assert!(connector.requests().is_empty());
+        crate::target::set_denylist(&json!([]));
+    }
+
+    #[test]
+    fn unconfirmed_streams_get_a_socks_error_not_a_success() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let metrics = &super::METRICS;
+        let failures = metrics.stream_confirm_failures.load(Ordering::Relaxed);
+        let confirmations = metrics.stream_confirmations.load(Ordering::Relaxed);
+        let connector = Arc::new(FakeConnector::new());
+        let _refused = connector.then_unconfirmed(ErrorKind::ExitPolicyRejected);
+        let _broken = connector.then_unwritable();
+        let mut working = connector.then_connect();
+
+        super::CONFIRM_STREAM_WRITABLE.store(true, Ordering::Relaxed);
+        testing::runtime().block_on(async {
+            // Refused by the exit, and connected but failing the first write
+            for _ in 0..2 {
+                let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::ConnectionRefused.frame());
+                let mut rest = Vec::new();
+                assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+                assert!(handler.await.unwrap().is_err());
+            }
+
+            let (mut client, _handler, closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
+            client.write_all(b"first").await.unwrap();
+            let mut buf = [0u8; 5];
+            working.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"first");
+            closer.cancel();
+        });
+        super::CONFIRM_STREAM_WRITABLE.store(false, Ordering::Relaxed);
+
+        assert_eq!(metrics.stream_confirm_failures.load(Ordering::Relaxed), failures + 2);
+        assert_eq!(metrics.stream_confirmations.load(Ordering::Relaxed), confirmations + 1);
+    }
+
+    #[test]
+    fn custom_circuit_dirtiness_builds_a_config() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let temp = testing::TempDir::new();
+        let setting = "max_circuit_dirtiness_secs";
+
+        assert_eq!(super::StageSettings::applied().max_dirtiness, Duration::from_secs(super::DEFAULT_CIRCUIT_DIRTINESS_SECS));
+        for rejected in [super::MIN_CIRCUIT_DIRTINESS_SECS - 1, super::MAX_CIRCUIT_DIRTINESS_SECS + 1] {
+            assert!(config::set(setting, json!(rejected)).is_err(), "{}", rejected);
+        }
+        for accepted in [super::MIN_CIRCUIT_DIRTINESS_SECS, 120, super::MAX_CIRCUIT_DIRTINESS_SECS] {
+            config::set(setting, json!(accepted)).unwrap();
+            config::commit_pending();
+            let settings = super::StageSettings::applied();
+            assert_eq!(settings.max_dirtiness, Duration::from_secs(accepted));
+            settings.client_config(temp.path(), &[]).unwrap();
+            let exported = crate::arti_config::render(true);
+            assert!(exported.contains(&format!("max_dirtiness = \"{}s\"", accepted)), "{}", exported);
         }
-    };
+    }
 
-    // Try to bind IMMEDIATELY to detect port conflicts before returning
-    let addr = format!("127.0.0.1:{}", port);
+    #[test]
+    fn stable_exits_cover_only_the_hinted_ports() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let temp = testing::TempDir::new();
 
-    // Use block_on to synchronously attempt binding
-    let bind_result = runtime.block_on(async {
-        tokio::net::TcpListener::bind(&addr).await
-    });
+        config::set("required_exit_flags", json!(["Stable"])).unwrap();
+        config::commit_pending();
+        super::StageSettings::applied().client_config(temp.path(), &[]).unwrap();
+        assert!(!crate::arti_config::render(true).contains("long_lived_ports"));
 
-    let listener = match bind_result {
-        Ok(l) => {
-            log_info!("SOCKS proxy bound to {}", addr);
-            l
-        }
-        Err(e) => {
-            log_error!("Failed to bind SOCKS proxy to {}: {:?}", addr, e);
-            return -3;
+        config::set("target_port_hints", json!([443, 5222])).unwrap();
+        config::commit_pending();
+        super::StageSettings::applied().client_config(temp.path(), &[]).unwrap();
+        let exported = crate::arti_config::render(true);
+        let line = exported.lines().find(|line| line.starts_with("long_lived_ports")).expect("no long_lived_ports");
+        assert_eq!(line, "long_lived_ports = [21, 22, 706, 1863, 5050, 5190, 5222, 5223, 6523, 6667, 6697, 8300, 443]");
+    }
+
+    const TEST_ONION: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+    #[test]
+    fn onion_reachability_codes() {
+        let _serial = testing::serial();
+        let runtime = testing::runtime();
+        let timeout = Duration::from_secs(30);
+        for (outcome, code) in [
+            (FakeConnect::Fail(ErrorKind::OnionServiceNotFound, "no descriptor"), 1),
+            (FakeConnect::Fail(ErrorKind::OnionServiceConnectionFailed, "introduction failed"), 2),
+            (FakeConnect::Fail(ErrorKind::OnionServiceNotRunning, "no introduction points"), 2),
+            (FakeConnect::Fail(ErrorKind::TorNetworkTimeout, "circuit timed out"), 3),
+            (FakeConnect::Fail(ErrorKind::Other, "something else"), 4),
+        ] {
+            let connector = FakeConnector::new();
+            connector.then(outcome);
+            assert_eq!(runtime.block_on(onion_reachability(&connector, TEST_ONION, 80, timeout)), code);
         }
-    };
+    }
 
-    // Now spawn the background task with the already-bound listener
-    let handle = runtime.spawn(async move {
-        log_info!("SOCKS proxy listening on {}", addr);
-        log_info!("Sufficiently bootstrapped; system SOCKS now functional");
+    #[test]
+    fn reachable_onion_leaves_no_connection() {
+        let _serial = testing::serial();
+        let connector = FakeConnector::new();
+        let mut service = connector.then_connect();
+        testing::runtime().block_on(async {
+            assert_eq!(onion_reachability(&connector, TEST_ONION, 80, Duration::from_secs(30)).await, 0);
+            // The service sees the stream open and close with nothing sent
+            let mut buf = [0u8; 1];
+            assert_eq!(service.read(&mut buf).await.unwrap(), 0);
+        });
+        assert_eq!(connector.requests(), [(TEST_ONION.to_string(), 80)]);
+    }
 
-        // Signal bootstrap completion to chaty-android (expected by ArtiTorManager)
-        // This sets bootstrapPercent to 100% and stops inactivity restarts
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-        log_info!("We have found that guard [scrubbed] is usable.");
-
-        // Accept connections
-        loop {
-            match listener.accept().await {
-                Ok((stream, peer_addr)) => {
-                    log_info!("SOCKS connection from: {}", peer_addr);
-                    let client_clone = Arc::clone(&client);
-
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_socks_connection(stream, client_clone).await {
-                            log_error!("SOCKS connection error: {:?}", e);
-                        }
-                    });
+    #[test]
+    fn hung_onion_connect_times_out() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let connector = Arc::new(FakeConnector::new());
+        connector.then(FakeConnect::Hang);
+        let runtime = testing::runtime();
+        let attempt = {
+            let connector = Arc::clone(&connector);
+            runtime.spawn(async move { onion_reachability(&*connector, TEST_ONION, 80, Duration::from_secs(30)).await })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!attempt.is_finished());
+        time.advance(Duration::from_secs(30));
+        assert_eq!(runtime.block_on(attempt).unwrap(), 3);
+    }
+
+    #[test]
+    fn tunnel_forwards_credentials_verbatim() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        let mut service = connector.then_connect();
+        let request: &[u8] = b"GET /private HTTP/1.1\r\nHost: example.com\r\n\
+            Authorization: Basic dXNlcjpwYXNz\r\nProxy-Authorization: Bearer \x00\xff\r\n\r\n";
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, TEST_ONION, 80).await, Reply::Succeeded.frame());
+
+            client.write_all(request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            service.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, request);
+            drop(service);
+            handler.await.unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn fronted_connects_dial_the_front_and_leave_the_client_hello_alone() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        let mut front = connector.then_connect();
+        // A TLS 1.3 ClientHello header with SNI "hidden.example", as the app sends it
+        let mut client_hello: Vec<u8> = vec![0x16, 0x03, 0x01, 0x00, 0x45, 0x01, 0x00, 0x00, 0x41, 0x03, 0x03];
+        client_hello.extend_from_slice(&[0x5a; 32]);
+        client_hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00, 0x0f, 0x00]);
+        client_hello.extend_from_slice(&[0x0d, 0x00, 0x00, 0x0e]);
+        client_hello.extend_from_slice(b"hidden.example");
+        let server_hello: &[u8] = &[0x16, 0x03, 0x03, 0x00, 0x02, 0x02, 0x00];
+
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            assert_eq!(socks_greet(&mut client, &[0x02]).await, 0x02);
+            assert_eq!(socks_login(&mut client, "front=cdn.example.net", "x").await, 0x00);
+            assert_eq!(socks_connect(&mut client, "hidden.example", 443).await, Reply::Succeeded.frame());
+
+            client.write_all(&client_hello).await.unwrap();
+            let mut received = vec![0u8; client_hello.len()];
+            front.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, client_hello);
+
+            front.write_all(server_hello).await.unwrap();
+            drop(front);
+            let mut answered = Vec::new();
+            client.read_to_end(&mut answered).await.unwrap();
+            assert_eq!(answered, server_hello);
+            client.shutdown().await.unwrap();
+            handler.await.unwrap().unwrap();
+        });
+        assert_eq!(connector.requests(), [("cdn.example.net".to_string(), 443)]);
+    }
+
+    #[test]
+    fn clearnet_connects_are_dialed_as_their_onion_alias() {
+        const ONION: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        let _serial = testing::serial();
+        let _config = config::save();
+        assert_eq!(add_onion_alias("Example.com", &ONION.to_ascii_uppercase()), 0);
+        assert_eq!(add_onion_alias("example.org", "not-an-onion.onion"), -1);
+        assert_eq!(add_onion_alias("10.0.0.1", ONION), -1);
+        let upgrades = super::METRICS.onion_upgrades.load(Ordering::Relaxed);
+
+        let connector = Arc::new(FakeConnector::new());
+        let mut onion = connector.then_connect();
+        connector.then_connect();
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, "EXAMPLE.com", 443).await, Reply::Succeeded.frame());
+            // The client can't tell: it relays as if it reached the clearnet host
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            onion.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            drop(onion);
+            handler.await.unwrap().unwrap();
+
+            // Removing the alias dials the clearnet host again
+            assert_eq!(add_onion_alias("example.com", ""), 0);
+            let (mut client, _handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
+        });
+        assert_eq!(connector.requests(), [(ONION.to_string(), 443), ("example.com".to_string(), 443)]);
+        assert_eq!(super::METRICS.onion_upgrades.load(Ordering::Relaxed), upgrades + 1);
+        // Reported, with both names redacted like every destination
+        let lines = SENT_LOG_LINES.lock().unwrap();
+        assert!(lines.iter().any(|line| line.contains("upgraded to onion alias")));
+        assert!(!lines.iter().any(|line| line.contains(ONION)));
+    }
+
+    #[test]
+    fn onion_only_refuses_clearnet_connects() {
+        const ONION: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        let _serial = testing::serial();
+        let _config = config::save();
+        config::set("onion_only", json!(true)).unwrap();
+        config::commit_pending();
+
+        let connector = Arc::new(FakeConnector::new());
+        connector.then_connect();
+        testing::runtime().block_on(async {
+            for host in ["example.com", "192.0.2.1"] {
+                let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, host, 443).await, Reply::NotAllowedByRuleset.frame(), "{}", host);
+                assert!(handler.await.unwrap().unwrap_err().to_string().contains("onion_only"));
+            }
+            let (mut client, _handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, ONION, 80).await, Reply::Succeeded.frame());
+        });
+        // Nothing was dialed for the refused targets
+        assert_eq!(connector.requests(), [(ONION.to_string(), 80)]);
+    }
+
+    #[test]
+    fn the_capability_probe_is_answered_before_the_target_is_looked_at() {
+        let _serial = testing::serial();
+        let _config = config::save();
+        // Both would refuse the name with a plain 0x02 if they saw it first
+        config::set("onion_only", json!(true)).unwrap();
+        crate::target::set_denylist(&json!(["*.invalid"]));
+        let failures = super::METRICS.connect_failures.load(Ordering::Relaxed);
+        let connector = Arc::new(FakeConnector::new());
+        testing::runtime().block_on(async {
+            for host in ["capabilities.invalid", "Capabilities.INVALID."] {
+                let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                let reply = socks_connect(&mut client, host, 443).await;
+                assert_eq!(reply, crate::capabilities::reply_frame(), "{}", host);
+                assert_eq!(reply[4..8], crate::capabilities::bitmap().to_be_bytes());
+                assert_ne!(reply[8..], [0, 0]);
+                let mut rest = Vec::new();
+                assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+                handler.await.unwrap().unwrap();
+            }
+        });
+        crate::target::set_denylist(&json!([]));
+
+        // Never dialed, and not counted as a failure or a destination
+        assert!(connector.requests().is_empty());
+        assert_eq!(super::METRICS.connect_failures.load(Ordering::Relaxed), failures);
+        assert_eq!(crate::quality::destination_json("capabilities.invalid", 443), "null");
+    }
+
+    #[test]
+    fn the_username_timeout_bounds_the_connect() {
+        use crate::seams::fakes::FakeStream;
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let runtime = testing::runtime();
+        // (username, how the connect goes, reply, fake seconds until the reply)
+        let cases = [
+            ("timeout=30", None, Reply::TtlExpired, 30),
+            ("color=red; timeout=30", Some(10), Reply::Succeeded, 10),
+            // Malformed or out of range: no limit of the wrapper's own
+            ("timeout=0", Some(100), Reply::Succeeded, 100),
+            ("timeout=301", Some(100), Reply::Succeeded, 100),
+            ("timeout=soon", Some(100), Reply::Succeeded, 100),
+            ("junk", Some(100), Reply::Succeeded, 100),
+        ];
+        for (username, connect_secs, reply, secs) in cases {
+            let connector = Arc::new(FakeConnector::new());
+            let (stream, _destination) = FakeStream::pair();
+            connector.then(match connect_secs {
+                Some(connect_secs) => FakeConnect::Delayed(Duration::from_secs(connect_secs), stream),
+                None => FakeConnect::Hang,
+            });
+            let started = crate::seams::now_monotonic();
+            let session = runtime.spawn(async move {
+                let (mut client, _handler, _closer) = socks_session(connector).await;
+                assert_eq!(socks_greet(&mut client, &[0x02]).await, 0x02);
+                assert_eq!(socks_login(&mut client, username, "x").await, 0x00);
+                socks_connect(&mut client, "example.com", 443).await
+            });
+            while !session.is_finished() {
+                // Let the connect reach its sleep before moving time
+                std::thread::sleep(Duration::from_millis(2));
+                if !session.is_finished() {
+                    time.advance(Duration::from_millis(500));
+                }
+            }
+            let elapsed = crate::seams::now_monotonic() - started;
+            assert_eq!(runtime.block_on(session).unwrap(), reply.frame(), "{}", username);
+            assert!(elapsed >= Duration::from_secs(secs) && elapsed <= Duration::from_secs(secs + 1), "{}: {:?}", username, elapsed);
+        }
+    }
+
+    #[test]
+    fn the_username_deadline_bounds_the_connect() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let runtime = testing::runtime();
+        // (deadline in seconds from now, other options, reply, fake seconds until the reply)
+        let cases = [
+            (20, "", Reply::TtlExpired, 20),
+            (20, ";timeout=5", Reply::TtlExpired, 5),
+            (20, ";timeout=30", Reply::TtlExpired, 20),
+            // Already past: refused at once
+            (-1, ";timeout=30", Reply::TtlExpired, 0),
+        ];
+        for (deadline_secs, options, reply, secs) in cases {
+            let connector = Arc::new(FakeConnector::new());
+            connector.then(FakeConnect::Hang);
+            let now_ms = crate::seams::now_wall().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+            let username = format!("deadline={}{}", now_ms + deadline_secs * 1000, options);
+            let started = crate::seams::now_monotonic();
+            let session = runtime.spawn({
+                let (connector, username) = (Arc::clone(&connector), username.clone());
+                async move {
+                    let (mut client, _handler, _closer) = socks_session(connector).await;
+                    assert_eq!(socks_greet(&mut client, &[0x02]).await, 0x02);
+                    assert_eq!(socks_login(&mut client, &username, "x").await, 0x00);
+                    socks_connect(&mut client, "example.com", 443).await
                 }
-                Err(e) => {
-                    log_error!("Failed to accept SOCKS connection: {:?}", e);
-                    break; // Exit loop on error
+            });
+            while !session.is_finished() {
+                // Let the connect reach its sleep before moving time
+                std::thread::sleep(Duration::from_millis(2));
+                if !session.is_finished() {
+                    time.advance(Duration::from_millis(500));
                 }
             }
+            let elapsed = crate::seams::now_monotonic() - started;
+            assert_eq!(runtime.block_on(session).unwrap(), reply.frame(), "{}", username);
+            assert!(elapsed >= Duration::from_secs(secs) && elapsed <= Duration::from_secs(secs + 1), "{}: {:?}", username, elapsed);
+            assert_eq!(connector.requests().is_empty(), deadline_secs < 0, "{}", username);
         }
+    }
 
-        log_info!("SOCKS proxy task exiting");
-    });
+    #[test]
+    fn connections_list_the_class_and_id_the_client_gave() {
+        let _serial = testing::serial();
+        testing::runtime().block_on(async {
+            for (username, class, connid) in [
+                ("class=Chat;connid=c-17", json!("chat"), json!("c-17")),
+                ("connid=c-18", serde_json::Value::Null, json!("c-18")),
+                ("class=a b;connid=", serde_json::Value::Null, serde_json::Value::Null),
+            ] {
+                let connector = Arc::new(FakeConnector::new());
+                let destination = connector.then_connect();
+                let (mut client, serving, _closer) = socks_session(Arc::clone(&connector)).await;
+                assert_eq!(socks_greet(&mut client, &[0x02]).await, 0x02);
+                assert_eq!(socks_login(&mut client, username, "x").await, 0x00);
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
 
-    // Store handle for cleanup
-    *SOCKS_TASK.lock().unwrap() = Some(handle);
+                let listed: serde_json::Value = serde_json::from_str(&crate::connections::list_json()).unwrap();
+                assert_eq!((&listed[0]["class"], &listed[0]["connid"]), (&class, &connid), "{}", username);
 
-    log_info!("SOCKS proxy started on port {}", port);
-    0
-}
+                drop(client);
+                drop(destination);
+                let _ = tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap();
+            }
+        });
+        assert_eq!(crate::connections::list_json(), "[]");
+    }
 
-/// Handle a single SOCKS connection
-async fn handle_socks_connection(
-    mut stream: tokio::net::TcpStream,
-    client: Arc<TorClient<PreferredRuntime>>,
-) -> Result<()> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    /// CONNECT to an IPv6 address (ATYP 0x04) and read the reply frame
+    async fn socks_connect_ipv6(client: &mut tokio::net::TcpStream, ip: std::net::Ipv6Addr, port: u16) -> [u8; 10] {
+        let mut request = vec![0x05, 0x01, 0x00, 0x04];
+        request.extend_from_slice(&ip.octets());
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply
+    }
 
-    // Simple SOCKS5 handshake
-    let mut buf = [0u8; 512];
+    #[test]
+    fn ipv6_targets_are_refused_while_disabled() {
+        let _serial = testing::serial();
+        let _config = config::save();
+        let ip: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let runtime = testing::runtime();
 
-    // Read version + methods
-    let n = stream.read(&mut buf).await?;
-    if n < 2 {
-        return Err(anyhow::anyhow!("Invalid SOCKS handshake"));
+        // Enabled by default: dialed like any other address
+        let connector = Arc::new(FakeConnector::new());
+        connector.then_connect();
+        runtime.block_on(async {
+            let (mut client, _handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect_ipv6(&mut client, ip, 443).await, Reply::Succeeded.frame());
+        });
+        assert_eq!(connector.requests(), [("2001:0db8:0000:0000:0000:0000:0000:0001".to_string(), 443)]);
+
+        config::set("disable_ipv6_targets", json!(true)).unwrap();
+        config::commit_pending();
+        let refused = super::METRICS.ipv6_targets_refused.load(Ordering::Relaxed);
+        let connector = Arc::new(FakeConnector::new());
+        connector.then_connect();
+        connector.then_connect();
+        runtime.block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect_ipv6(&mut client, ip, 443).await, Reply::AddressTypeNotSupported.frame());
+            assert!(handler.await.unwrap().is_err());
+            // Literals sent as names too, bracketed or not
+            for name in ["2001:db8::1", "[::1]"] {
+                let (mut client, _handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, name, 443).await, Reply::AddressTypeNotSupported.frame(), "{}", name);
+            }
+            // IPv4 and hostnames still go through
+            for name in ["192.0.2.1", "example.com"] {
+                let (mut client, _handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, name, 443).await, Reply::Succeeded.frame(), "{}", name);
+            }
+        });
+        assert_eq!(connector.requests(), [("192.0.2.1".to_string(), 443), ("example.com".to_string(), 443)]);
+        assert_eq!(super::METRICS.ipv6_targets_refused.load(Ordering::Relaxed), refused + 3);
     }
 
-    // Send "no auth required" response
-    stream.write_all(&[0x05, 0x00]).await?;
+    #[test]
+    fn circuit_latency_times_the_second_resolve() {
+        use std::time::Duration;
+        let rtt = Duration::from_millis(30);
+        let timeout = Duration::from_secs(5);
+        testing::runtime().block_on(async {
+            // The first resolve builds the circuit and is not timed
+            let mut calls = 0;
+            let ms = measure_latency(true, timeout, || {
+                calls += 1;
+                let delay = if calls == 1 { Duration::from_millis(300) } else { rtt };
+                async move {
+                    tokio::time::sleep(delay).await;
+                    Ok::<_, String>(())
+                }
+            })
+            .await;
+            assert_eq!(calls, 2);
+            assert!(ms >= rtt.as_millis() as i32 && ms < 300, "{} ms", ms);
 
-    // Read request
-    let n = stream.read(&mut buf).await?;
-    if n < 10 {
-        return Err(anyhow::anyhow!("Invalid SOCKS request"));
+            // An instant answer still reports a positive latency
+            assert_eq!(measure_latency(true, timeout, || async { Ok::<_, String>(()) }).await, 1);
+
+            // No circuit ready for traffic: nothing is resolved
+            let mut called = false;
+            let not_ready = measure_latency(false, timeout, || {
+                called = true;
+                async { Ok::<_, String>(()) }
+            });
+            assert_eq!(not_ready.await, -4);
+            assert!(!called);
+
+            assert_eq!(measure_latency(true, timeout, || async { Err("resolve failed") }).await, -5);
+            let hung = measure_latency(true, Duration::from_millis(20), std::future::pending::<Result<(), String>>);
+            assert_eq!(hung.await, -5);
+        });
     }
 
-    // Parse SOCKS5 request: VER(1) CMD(1) RSV(1) ATYP(1) DST.ADDR DST.PORT(2)
-    let version = buf[0];
-    let cmd = buf[1];
-    let atyp = buf[3];
+    #[test]
+    fn closing_by_target_leaves_other_connections_open() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        let targets = ["a.bad.example", "B.Bad.Example", "good.example"];
+        let mut destinations: Vec<_> = targets.iter().map(|_| connector.then_connect()).collect();
+        let stop = crate::cancel::CancelToken::new();
+        testing::runtime().block_on(async {
+            let listener = crate::net::bind_listener("127.0.0.1:0").await.unwrap();
+            let mut sessions = Vec::new();
+            for target in targets {
+                let mut client = crate::net::connect_loopback(listener.local_addr().unwrap().port()).await.unwrap();
+                let (server, peer) = listener.accept().await.unwrap();
+                let active = super::ActiveConnection::open();
+                let serving = tokio::spawn(super::serve_connection(server, peer, Arc::clone(&connector), stop.clone(), active));
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, target, 443).await, Reply::Succeeded.frame());
+                sessions.push((client, serving));
+            }
 
-    if version != 0x05 {
-        return Err(anyhow::anyhow!("Unsupported SOCKS version: {}", version));
+            // Another port, or a pattern that only covers part of the host,
+            // closes nothing
+            for pattern in ["*.bad.example:80", "bad.example", "*.bad"] {
+                assert_eq!(crate::connections::close_matching(&crate::connections::Pattern::parse(pattern).unwrap()), 0, "{}", pattern);
+            }
+            let pattern = crate::connections::Pattern::parse("*.BAD.example:443").unwrap();
+            assert_eq!(crate::connections::close_matching(&pattern), 2);
+
+            let (mut good, good_serving) = sessions.pop().unwrap();
+            for (mut client, serving) in sessions {
+                tokio::time::timeout(Duration::from_secs(2), serving).await.expect("closed connection kept going").unwrap();
+                // Closed (or reset), with nothing more relayed
+                let mut rest = Vec::new();
+                let _ = client.read_to_end(&mut rest).await;
+                assert!(rest.is_empty());
+            }
+            for destination in &mut destinations[..2] {
+                let mut rest = Vec::new();
+                assert_eq!(destination.read_to_end(&mut rest).await.unwrap(), 0);
+            }
+            let listed: serde_json::Value = serde_json::from_str(&crate::connections::list_json()).unwrap();
+            assert_eq!(listed.as_array().unwrap().len(), 1);
+            assert_eq!(listed[0]["host"], "good.example");
+
+            // The other target still relays both ways
+            good.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            destinations[2].read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            destinations[2].write_all(b"pong").await.unwrap();
+            good.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+            assert!(!good_serving.is_finished());
+
+            stop.cancel();
+            tokio::time::timeout(Duration::from_secs(2), good_serving).await.unwrap().unwrap();
+        });
+        assert_eq!(crate::connections::list_json(), "[]");
     }
 
-    if cmd != 0x01 {
-        // Only support CONNECT command
-        stream.write_all(&[0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
-        return Err(anyhow::anyhow!("Unsupported SOCKS command: {}", cmd));
+    #[test]
+    fn connections_report_their_exit_platform() {
+        let _serial = testing::serial();
+        let _index = crate::geo::fake_index(&[("AA01", Some("de"), Some("Tor 0.4.8.12")), ("AA02", Some("nl"), None)]);
+        testing::runtime().block_on(async {
+            // (exit of the circuit, platform listed)
+            for (exit, platform) in [
+                (Some("AA01"), json!("Tor 0.4.8.12")),
+                (Some("aa01"), json!("Tor 0.4.8.12")),
+                // In the consensus without a platform, or not at all
+                (Some("AA02"), serde_json::Value::Null),
+                (Some("FFFF"), serde_json::Value::Null),
+                // No circuit information
+                (None, serde_json::Value::Null),
+            ] {
+                let connector = Arc::new(FakeConnector::new());
+                let destination = match exit {
+                    Some(exit) => connector.then_connect_via(exit),
+                    None => connector.then_connect(),
+                };
+                let (mut client, serving, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, Reply::Succeeded.frame());
+
+                let listed: serde_json::Value = serde_json::from_str(&crate::connections::list_json()).unwrap();
+                assert_eq!(listed.as_array().unwrap().len(), 1);
+                assert_eq!(listed[0]["exit_platform"], platform, "exit {:?}", exit);
+
+                drop(client);
+                drop(destination);
+                let _ = tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap();
+            }
+        });
+        assert_eq!(crate::connections::list_json(), "[]");
     }
 
-    // Parse target address and port
-    let (target_host, target_port) = match atyp {
-        0x01 => {
-            // IPv4: 4 bytes
-            let ip = format!("{}.{}.{}.{}", buf[4], buf[5], buf[6], buf[7]);
-            let port = u16::from_be_bytes([buf[8], buf[9]]);
-            (ip, port)
-        }
-        0x03 => {
-            // Domain name: length byte + domain
-            let len = buf[4] as usize;
-            if n < 5 + len + 2 {
-                return Err(anyhow::anyhow!("Invalid domain name length"));
+    #[test]
+    fn connections_honor_the_required_exit_flags() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        // AA01 lacks Stable; FFFF isn't in the consensus
+        let _index = crate::geo::fake_exits(&[("AA01", "F"), ("AA02", "FS"), ("AA03", "FS")]);
+        testing::runtime().block_on(async {
+            // (required flags, exits the connects go through, reply, attempts)
+            for (required, exits, reply, attempts) in [
+                (json!([]), vec!["AA01"], Reply::Succeeded, 1),
+                (json!(["Fast"]), vec!["AA01"], Reply::Succeeded, 1),
+                (json!(["Stable"]), vec!["AA02"], Reply::Succeeded, 1),
+                (json!(["Stable"]), vec!["AA01", "AA03"], Reply::Succeeded, 2),
+                (json!(["Stable"]), vec!["FFFF"], Reply::Succeeded, 1),
+                (json!(["Stable", "Fast"]), vec!["AA01", "AA01", "AA01"], Reply::NotAllowedByRuleset, 3),
+            ] {
+                config::set("required_exit_flags", required.clone()).unwrap();
+                config::commit_pending();
+                let connector = Arc::new(FakeConnector::new());
+                let destinations: Vec<_> = exits.iter().map(|exit| connector.then_connect_via(exit)).collect();
+                let generation = crate::exits::generation();
+                let (mut client, serving, _closer) = socks_session(Arc::clone(&connector)).await;
+                socks_greet(&mut client, &[0x00]).await;
+                assert_eq!(socks_connect(&mut client, "example.com", 443).await, reply.frame(), "{} via {:?}", required, exits);
+                assert_eq!(connector.requests().len(), attempts, "{} via {:?}", required, exits);
+                // Every rejected exit moved later streams off its circuit
+                assert_eq!(crate::exits::generation() - generation, attempts as u64 - 1 + u64::from(reply != Reply::Succeeded));
+
+                if reply == Reply::Succeeded {
+                    // The stream is the one through the last exit
+                    let mut destination = destinations.into_iter().last().unwrap();
+                    let mut buf = [0u8; 4];
+                    client.write_all(b"ping").await.unwrap();
+                    destination.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(&buf, b"ping");
+                }
+                drop(client);
+                let _ = tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap();
             }
-            let domain = String::from_utf8_lossy(&buf[5..5 + len]).to_string();
-            let port = u16::from_be_bytes([buf[5 + len], buf[5 + len + 1]]);
-            (domain, port)
-        }
-        0x04 => {
-            // IPv6: 16 bytes + 2 bytes port = 22 bytes total
-            if n < 22 {
-                stream.write_all(&[0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
-                return Err(anyhow::anyhow!("Truncated IPv6 request"));
+        });
+        // The exit generation is part of the isolation key
+        let before = IsolationKey::for_target(443, None);
+        crate::exits::reject("AA01", "Stable");
+        assert_ne!(IsolationKey::for_target(443, None), before);
+    }
+
+    #[test]
+    fn stop_interrupts_a_connect_mid_build() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        connector.then(FakeConnect::Hang);
+        let cancel = super::SOCKS_CANCEL.lock().unwrap().get_or_insert_with(crate::cancel::CancelToken::new).clone();
+        testing::runtime().block_on(async {
+            let listener = crate::net::bind_listener("127.0.0.1:0").await.unwrap();
+            let mut client = crate::net::connect_loopback(listener.local_addr().unwrap().port()).await.unwrap();
+            let (server, peer) = listener.accept().await.unwrap();
+            let active = super::ActiveConnection::open();
+            let serving = tokio::spawn(super::serve_connection(server, peer, Arc::clone(&connector), cancel, active));
+
+            socks_greet(&mut client, &[0x00]).await;
+            let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+            request.extend_from_slice(b"example.com");
+            request.extend_from_slice(&443u16.to_be_bytes());
+            client.write_all(&request).await.unwrap();
+            while connector.requests().is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
             }
-            let ip = format!(
-                "{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}",
-                buf[4], buf[5], buf[6], buf[7], buf[8], buf[9], buf[10], buf[11],
-                buf[12], buf[13], buf[14], buf[15], buf[16], buf[17], buf[18], buf[19]
-            );
-            let port = u16::from_be_bytes([buf[20], buf[21]]);
-            (ip, port)
+            // The circuit build never finishes on its own
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!serving.is_finished());
+
+            let stopped = std::time::Instant::now();
+            tokio::task::spawn_blocking(|| super::stop_within(None)).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(2), serving).await.expect("connect outlived stop").unwrap();
+            assert!(stopped.elapsed() < Duration::from_secs(2));
+            // The client sees the connection closed without a reply
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+        assert!(super::SOCKS_CANCEL.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn stop_neither_waits_for_the_runtime_lock_nor_for_a_bootstrap() {
+        let _serial = testing::serial();
+        let saved_runtime = super::TOKIO_RUNTIME.lock().unwrap().replace(testing::runtime());
+        let bootstrap = crate::debounce::bootstrap_started();
+        // Another call (a bootstrap, a probe) holding the runtime lock
+        let (held_tx, held_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = std::thread::spawn(move || {
+            let _runtime = super::TOKIO_RUNTIME.lock().unwrap();
+            held_tx.send(()).unwrap();
+            let _ = release_rx.recv_timeout(Duration::from_secs(30));
+        });
+        held_rx.recv().unwrap();
+
+        let report = || -> serde_json::Value { serde_json::from_str(&crate::shutdown::report_json()).unwrap() };
+        let started = std::time::Instant::now();
+        assert_eq!(super::stop_within(Some(Duration::from_millis(300))), 0);
+        assert!(started.elapsed() < Duration::from_millis(300), "{:?}", started.elapsed());
+        assert_eq!(report()["within_deadline"], true);
+        assert!(futures::FutureExt::now_or_never(bootstrap.cancelled()).is_some());
+        assert!(!crate::debounce::cancel_bootstrap());
+
+        // Without a deadline the drain is planned, and skipped rather than waited for
+        let started = std::time::Instant::now();
+        super::stop_within(None);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        let phases = report()["phases"].as_array().unwrap().clone();
+        let drain = phases.iter().find(|phase| phase["name"] == "drain").unwrap();
+        assert_eq!(drain["outcome"], "skipped");
+
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        let runtime = std::mem::replace(&mut *super::TOKIO_RUNTIME.lock().unwrap(), saved_runtime);
+        if let Some(runtime) = runtime {
+            runtime.shutdown_background();
         }
-        _ => {
-            stream.write_all(&[0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
-            return Err(anyhow::anyhow!("Unsupported address type: {}", atyp));
+    }
+
+    #[test]
+    fn low_battery_pauses_accepting_but_not_open_connections() {
+        let _serial = testing::serial();
+        let connector = Arc::new(FakeConnector::new());
+        let mut destination = connector.then_connect();
+        let _later = connector.then_connect();
+        let run = crate::listener_exit::Run::start();
+        testing::runtime().block_on(async {
+            let listener = crate::net::bind_listener("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let cancel = crate::cancel::CancelToken::new();
+            let accepting = tokio::spawn({
+                let connector = Arc::clone(&connector);
+                let cancel = cancel.clone();
+                async move { super::accept_connections(listener, connector, cancel, &run).await }
+            });
+
+            let mut open = crate::net::connect_loopback(port).await.unwrap();
+            socks_greet(&mut open, &[0x00]).await;
+            assert_eq!(socks_connect(&mut open, "example.com", 443).await, Reply::Succeeded.frame());
+
+            crate::battery::set_low(true);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // A new client waits in the backlog, unanswered
+            let mut waiting = crate::net::connect_loopback(port).await.unwrap();
+            waiting.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut choice = [0u8; 2];
+            assert!(tokio::time::timeout(Duration::from_millis(100), waiting.read_exact(&mut choice)).await.is_err());
+            // while the open connection keeps relaying
+            open.write_all(b"still here").await.unwrap();
+            let mut buf = [0u8; 10];
+            destination.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"still here");
+
+            crate::battery::set_low(false);
+            tokio::time::timeout(Duration::from_secs(2), waiting.read_exact(&mut choice)).await.unwrap().unwrap();
+            assert_eq!(choice, [0x05, 0x00]);
+
+            crate::listener_exit::aborting(crate::listener_exit::Reason::Stopped, "test");
+            accepting.abort();
+            cancel.cancel();
+        });
+    }
+
+    /// An acceptor whose every accept fails
+    struct FailingAcceptor;
+
+    impl crate::seams::Acceptor for FailingAcceptor {
+        fn accept(&self) -> futures::future::BoxFuture<'_, std::io::Result<(tokio::net::TcpStream, std::net::SocketAddr)>> {
+            Box::pin(async { Err(std::io::Error::other("too many open files")) })
         }
-    };
+    }
 
-    log_info!("SOCKS5 CONNECT to {}:{}", target_host, target_port);
+    #[test]
+    fn the_accept_loop_records_why_it_ended() {
+        use crate::listener_exit::{self, Reason, Run};
 
-    // Establish Tor connection
-    let tor_stream = match client.connect((target_host.as_str(), target_port)).await {
-        Ok(s) => s,
-        Err(e) => {
-            log_error!("Failed to connect through Tor: {:?}", e);
-            // Send SOCKS5 error: general failure
-            stream.write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
-            return Err(e.into());
+        let _serial = testing::serial();
+        let runtime = testing::runtime();
+        let connector = Arc::new(FakeConnector::new());
+        let cancel = crate::cancel::CancelToken::new();
+        let last = || serde_json::from_str::<serde_json::Value>(&listener_exit::last_json()).unwrap();
+
+        // An accept error ends the loop on its own
+        let run = Run::start();
+        runtime.block_on(super::accept_connections(FailingAcceptor, Arc::clone(&connector), cancel.clone(), &run));
+        drop(run);
+        assert_eq!(last()["reason"], "accept_error");
+        assert!(last()["detail"].as_str().unwrap().contains("too many open files"), "{}", last());
+        let failure: serde_json::Value = serde_json::from_str(&crate::diagnostics::last_error_json()).unwrap();
+        assert_eq!(failure["kind"], "proxy_died");
+
+        // Stop and restart record themselves before aborting the loop, which
+        // then records nothing more
+        for (reason, caller, name) in [(Reason::Stopped, "stop", "stopped"), (Reason::Restarted, "startSocksProxy", "restarted")] {
+            let run = Run::start();
+            runtime.block_on(async {
+                let listener = crate::net::bind_listener("127.0.0.1:0").await.unwrap();
+                let accepting = tokio::spawn({
+                    let connector = Arc::clone(&connector);
+                    let cancel = cancel.clone();
+                    async move { super::accept_connections(listener, connector, cancel, &run).await }
+                });
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                listener_exit::aborting(reason, caller);
+                accepting.abort();
+                assert!(accepting.await.unwrap_err().is_cancelled());
+            });
+            assert_eq!(last()["reason"], name);
+            assert_eq!(last()["detail"], caller);
         }
-    };
 
-    log_info!("Tor connection established to {}:{}", target_host, target_port);
+        // A run ending with nothing recorded was fatal
+        drop(Run::start());
+        assert_eq!(last()["reason"], "fatal");
+        assert_eq!(last()["detail"], "task ended unexpectedly");
+        // and an abort with no loop running leaves that alone
+        listener_exit::aborting(Reason::Stopped, "stop");
+        assert_eq!(last()["reason"], "fatal");
+    }
 
-    // Send SOCKS5 success response
-    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    /// The await point a connection is parked at when it gets cancelled
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Parked {
+        Greeting,
+        Request,
+        BufferQueue,
+        Connect,
+        Forwarding,
+        WritingToClient,
+    }
 
-    // Bidirectional data forwarding
-    let (mut client_read, mut client_write) = stream.split();
-    let (mut tor_read, mut tor_write) = tor_stream.split();
+    #[test]
+    fn cancelling_at_any_await_point_leaks_nothing() {
+        use crate::buffers;
 
-    let client_to_tor = async {
-        tokio::io::copy(&mut client_read, &mut tor_write).await
-    };
+        let _serial = testing::serial();
+        // Keeps a queued connection from timing out of the buffer queue
+        let _time = FakeTime::start();
+        let streams_in_groups = || serde_json::from_str::<serde_json::Value>(&crate::isolation_groups::list_json()).unwrap()["total_groups"].clone();
+        let registered = || serde_json::from_str::<Vec<serde_json::Value>>(&crate::connections::list_json()).unwrap().len();
 
-    let tor_to_client = async {
-        tokio::io::copy(&mut tor_read, &mut client_write).await
-    };
+        for point in [Parked::Greeting, Parked::Request, Parked::BufferQueue, Parked::Connect, Parked::Forwarding, Parked::WritingToClient] {
+            testing::runtime().block_on(async {
+                let connector = Arc::new(FakeConnector::new());
+                let service = match point {
+                    Parked::Connect => {
+                        connector.then(FakeConnect::Hang);
+                        None
+                    }
+                    Parked::Forwarding | Parked::WritingToClient => Some(connector.then_connect()),
+                    _ => None,
+                };
+                // Every byte of budget taken, so the connection queues for it
+                let held = if point == Parked::BufferQueue {
+                    assert!(buffers::set_budget(2 * buffers::MIN_BUFFER_SIZE));
+                    buffers::acquire().await
+                } else {
+                    None
+                };
 
-    // Run both directions concurrently, exit when either completes
-    tokio::select! {
-        result = client_to_tor => {
-            if let Err(ref e) = result {
-                log_error!("Client->Tor copy error: {:?}", e);
-            }
+                let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+                if point != Parked::Greeting {
+                    socks_greet(&mut client, &[0x00]).await;
+                }
+                if ![Parked::Greeting, Parked::Request].contains(&point) {
+                    let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+                    request.extend_from_slice(b"example.com");
+                    request.extend_from_slice(&443u16.to_be_bytes());
+                    client.write_all(&request).await.unwrap();
+                }
+                let (idle, sending) = match (point, service) {
+                    (Parked::Forwarding, service) => (service, None),
+                    (Parked::WritingToClient, Some(mut service)) => {
+                        // Until the buffers toward the client are full, then until the stream is gone
+                        let sending = tokio::spawn(async move {
+                            let chunk = vec![0u8; 64 * 1024];
+                            while service.write_all(&chunk).await.is_ok() {}
+                        });
+                        (None, Some(sending))
+                    }
+                    _ => (None, None),
+                };
+                if [Parked::Forwarding, Parked::WritingToClient].contains(&point) {
+                    let mut reply = [0u8; 10];
+                    client.read_exact(&mut reply).await.unwrap();
+                    assert_eq!(reply, Reply::Succeeded.frame());
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert!(!handler.is_finished(), "{:?}", point);
+                let past_registration = ![Parked::Greeting, Parked::Request].contains(&point);
+                assert_eq!(registered(), past_registration as usize, "{:?}", point);
+
+                handler.abort();
+                assert!(handler.await.unwrap_err().is_cancelled(), "{:?}", point);
+                assert_eq!(registered(), 0, "{:?}", point);
+                assert_eq!(streams_in_groups(), 0, "{:?}", point);
+                drop(held);
+                assert!(buffers::set_budget(buffers::DEFAULT_BUDGET_BYTES));
+                assert_eq!(buffers::in_use(), 0, "{:?}", point);
+                // The Tor stream was closed, not left open behind the cancelled handler
+                if let Some(mut service) = idle {
+                    let mut rest = Vec::new();
+                    service.read_to_end(&mut rest).await.unwrap();
+                }
+                if let Some(sending) = sending {
+                    tokio::time::timeout(Duration::from_secs(2), sending).await.unwrap().unwrap();
+                }
+                assert!(connector.requests().len() <= 1);
+            });
         }
-        result = tor_to_client => {
-            if let Err(ref e) = result {
-                log_error!("Tor->Client copy error: {:?}", e);
+    }
+
+    fn free_port() -> i32 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as i32
+    }
+
+    fn is_bound(port: i32) -> bool {
+        std::net::TcpListener::bind(("127.0.0.1", port as u16)).is_err()
+    }
+
+    /// A client that never touches the network, created on `runtime`
+    fn unbootstrapped_client(runtime: &tokio::runtime::Runtime) -> arti_client::TorClient<tor_rtcompat::PreferredRuntime> {
+        let _entered = runtime.enter();
+        let dir = testing::TempDir::new();
+        let config =
+            arti_client::config::TorClientConfigBuilder::from_directories(dir.path().join("state"), dir.path().join("cache"))
+                .build()
+                .unwrap();
+        arti_client::TorClient::builder().config(config).create_unbootstrapped().unwrap()
+    }
+
+    #[test]
+    fn concurrent_starts_leave_exactly_one_listener() {
+        let _serial = testing::serial();
+        let runtime = testing::runtime();
+        let client = unbootstrapped_client(&runtime);
+        let saved_client = super::ARTI_CLIENT.lock().unwrap().replace(Arc::new(client));
+        let saved_runtime = super::TOKIO_RUNTIME.lock().unwrap().replace(runtime);
+
+        for round in 0..10 {
+            let first = free_port();
+            let second = free_port();
+            for (a, b) in [(first, first), (first, second)] {
+                let barrier = Arc::new(std::sync::Barrier::new(2));
+                let starts: Vec<_> = [a, b]
+                    .into_iter()
+                    .map(|port| {
+                        let barrier = Arc::clone(&barrier);
+                        std::thread::spawn(move || {
+                            barrier.wait();
+                            super::start_socks_proxy(port)
+                        })
+                    })
+                    .collect();
+                let results: Vec<i32> = starts.into_iter().map(|start| start.join().unwrap()).collect();
+
+                // Each start either ran to completion or was turned away as busy
+                assert!(results.iter().all(|r| [0, -4].contains(r)), "round {}: {:?}", round, results);
+                assert!(results.contains(&0), "round {}: {:?}", round, results);
+                let bound: Vec<i32> = [a, b].into_iter().filter(|port| is_bound(*port)).collect();
+                let mut expected = vec![super::SOCKS_PORT.load(Ordering::SeqCst)];
+                if a == b {
+                    expected.push(a);
+                }
+                assert_eq!(bound, expected, "round {}: {:?} for starts on {} and {}", round, results, a, b);
+                assert!(super::socks_listener_running());
+                super::stop_within(None);
             }
         }
-    };
 
-    log_info!("SOCKS connection closed for {}:{}", target_host, target_port);
+        let runtime = std::mem::replace(&mut *super::TOKIO_RUNTIME.lock().unwrap(), saved_runtime);
+        *super::ARTI_CLIENT.lock().unwrap() = saved_client;
+        drop(runtime);
+    }
 
-    Ok(())
-}
+    #[test]
+    fn a_repeated_start_keeps_the_matching_listener() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let runtime = testing::runtime();
+        let client = Arc::new(unbootstrapped_client(&runtime));
+        let saved_client = super::ARTI_CLIENT.lock().unwrap().replace(Arc::clone(&client));
+        let saved_runtime = super::TOKIO_RUNTIME.lock().unwrap().replace(runtime);
+        let last_start = || -> (bool, i32) {
+            let report: serde_json::Value = serde_json::from_str(&crate::reconcile::last_start_json()).unwrap();
+            (report["reconciled"].as_bool().unwrap(), report["port"].as_i64().unwrap() as i32)
+        };
+        let count = |text: &str| SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains(text)).count();
+        let port = free_port();
 
-/// Stop Arti and cleanup
-#[no_mangle]
-pub extern "C" fn Java_org_torproject_arti_ArtiNative_stop(
-    _env: JNIEnv,
-    _class: JClass,
-) -> jint {
-    log_info!("AMEx: state changed to Stopping");
-    log_info!("Stopping Arti...");
+        // No listener yet: a plain start
+        assert!(!super::socks_listener_matches(port));
+        assert_eq!(super::start_socks_proxy(port), 0);
+        assert_eq!(last_start(), (false, port));
 
-    // Abort SOCKS proxy task (releases the port)
-    if let Some(handle) = SOCKS_TASK.lock().unwrap().take() {
-        log_info!("Aborting SOCKS server task");
-        handle.abort();
-    }
+        // The same start again, also after a per-connection setting changed
+        for isolate in [false, true] {
+            config::set("isolate_by_dest_port", json!(isolate)).unwrap();
+            SENT_LOG_LINES.lock().unwrap().clear();
+            assert_eq!(super::start_socks_proxy(port), 0);
+            assert_eq!(last_start(), (true, port));
+            assert_eq!(count("with the same settings; keeping it"), 1);
+            assert_eq!(count("Aborting previous SOCKS server task"), 0);
+            assert!(super::socks_listener_running() && is_bound(port));
+        }
 
-    // Give the abort a moment to complete and release the port
-    if let Some(rt) = TOKIO_RUNTIME.lock().unwrap().as_ref() {
-        rt.block_on(async {
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        });
+        // Another port, and another client on the same port, rebind
+        let other = free_port();
+        SENT_LOG_LINES.lock().unwrap().clear();
+        assert_eq!(super::start_socks_proxy(other), 0);
+        assert_eq!(last_start(), (false, other));
+        assert_eq!(count("Aborting previous SOCKS server task"), 1);
+        let runtime = super::TOKIO_RUNTIME.lock().unwrap().take().unwrap();
+        super::ARTI_CLIENT.lock().unwrap().replace(Arc::new(unbootstrapped_client(&runtime)));
+        *super::TOKIO_RUNTIME.lock().unwrap() = Some(runtime);
+        SENT_LOG_LINES.lock().unwrap().clear();
+        assert_eq!(super::start_socks_proxy(other), 0);
+        assert_eq!(last_start(), (false, other));
+        assert_eq!(count("Aborting previous SOCKS server task"), 1);
+
+        // A stopped listener never matches
+        super::stop_within(None);
+        assert!(!super::socks_listener_matches(other));
+        assert_eq!(super::start_socks_proxy(other), 0);
+        assert_eq!(last_start(), (false, other));
+        super::stop_within(None);
+
+        let runtime = std::mem::replace(&mut *super::TOKIO_RUNTIME.lock().unwrap(), saved_runtime);
+        *super::ARTI_CLIENT.lock().unwrap() = saved_client;
+        drop(client);
+        drop(runtime);
     }
 
-    // NOTE: We do NOT clear ARTI_CLIENT here!
-    // The TorClient can be reused for multiple SOCKS proxy start/stop cycles.
-    // Only clear it if you want to force full reinitialization.
+    /// A lifecycle command from the UI
+    #[derive(Clone, Copy, Debug)]
+    enum Command {
+        Start(i32),
+        Stop,
+        /// Wait out the debounce window
+        Settle,
+    }
 
-    // Uncomment this line only if you want to force reinitialization on every start:
-    // *ARTI_CLIENT.lock().unwrap() = None;
+    #[test]
+    fn rapid_toggling_is_coalesced() {
+        use Command::*;
 
-    log_info!("AMEx: state changed to Stopped");
-    log_info!("Arti stopped successfully");
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let runtime = testing::runtime();
+        let client = Arc::new(unbootstrapped_client(&runtime));
+        let saved_client = super::ARTI_CLIENT.lock().unwrap().replace(Arc::clone(&client));
+        let saved_runtime = super::TOKIO_RUNTIME.lock().unwrap().replace(runtime);
+        let window_ms = 100;
+        let count = |text: &str| SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains(text)).count();
+        let coalesced = || {
+            let report: serde_json::Value = serde_json::from_str(&crate::reconcile::last_start_json()).unwrap();
+            report["coalesced"].as_bool().unwrap()
+        };
+        let (port, other) = (free_port(), free_port());
 
-    0
-}
+        // (window, script, full stops run, listener left on, last start coalesced)
+        for (window, script, stops, listening, last_coalesced) in [
+            // The switch mashed: the proxy never goes down
+            (window_ms, vec![Start(port), Stop, Start(port), Stop, Start(port)], 0, Some(port), true),
+            (window_ms, vec![Start(port), Stop, Stop, Start(port), Settle], 0, Some(port), true),
+            // Left off, the stop runs once the window is over
+            (window_ms, vec![Start(port), Stop, Settle], 1, None, false),
+            (window_ms, vec![Start(port), Stop, Stop, Settle], 1, None, false),
+            // A different start runs the pending stop first
+            (window_ms, vec![Start(port), Stop, Start(other)], 1, Some(other), false),
+            // Disabled: every command runs in full
+            (0, vec![Start(port), Stop, Start(port), Stop, Start(port)], 2, Some(port), false),
+            (0, vec![Start(port), Stop], 1, None, false),
+        ] {
+            config::set("lifecycle_debounce_ms", json!(window)).unwrap();
+            SENT_LOG_LINES.lock().unwrap().clear();
+            for command in &script {
+                match command {
+                    Start(port) => assert_eq!(super::start_socks_proxy(*port), 0, "{:?}", script),
+                    Stop => assert_eq!(super::stop(), 0, "{:?}", script),
+                    Settle => std::thread::sleep(std::time::Duration::from_millis(window_ms * 4)),
+                }
+            }
+            assert_eq!(count("Stopping Arti..."), stops, "{:?} with a {} ms window", script, window);
+            match listening {
+                Some(port) => assert!(super::socks_listener_matches(port) && is_bound(port), "{:?}", script),
+                None => assert!(!super::socks_listener_running(), "{:?}", script),
+            }
+            assert_eq!(coalesced(), last_coalesced, "{:?}", script);
+            super::stop_within(None);
+        }
 
-// ============================================================================
-// Android Logger (simple implementation)
-// ============================================================================
+        let runtime = std::mem::replace(&mut *super::TOKIO_RUNTIME.lock().unwrap(), saved_runtime);
+        *super::ARTI_CLIENT.lock().unwrap() = saved_client;
+        drop(client);
+        drop(runtime);
+    }
 
-mod android_logger {
-    use std::ffi::CString;
+    #[test]
+    fn flushing_state_writes_it_and_reports_the_outcome() {
+        let _serial = testing::serial();
+        let temp = testing::TempDir::new();
+        let state = temp.path().join("state.json");
+        std::fs::write(&state, "{\"guards\":[]}").unwrap();
+        let sent = |text: &str| SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains(text));
 
-    #[allow(non_camel_case_types)]
-    type c_int = i32;
+        let saved_client = super::ARTI_CLIENT.lock().unwrap().take();
+        assert_eq!(super::flush_state(), super::ERR_NOT_RUNNING);
+        *super::ARTI_CLIENT.lock().unwrap() = saved_client;
 
-    #[allow(non_camel_case_types)]
-    type c_char = i8;
+        // Holding the state lock: written
+        let flushed = super::flush_state_with(|| {
+            std::fs::write(&state, "{\"guards\":[\"AAAA\"]}").map(|()| true)
+        });
+        assert_eq!(flushed, 0);
+        assert_eq!(std::fs::read_to_string(&state).unwrap(), "{\"guards\":[\"AAAA\"]}");
+        assert!(sent("Tor state flushed to disk"));
 
-    extern "C" {
-        fn __android_log_write(prio: c_int, tag: *const c_char, text: *const c_char) -> c_int;
+        // Another process holds the lock, or the write fails: nothing written
+        assert_eq!(super::flush_state_with(|| Ok::<_, std::io::Error>(false)), -2);
+        assert!(sent("locked by another process"));
+        let failed = super::flush_state_with(|| -> std::io::Result<bool> {
+            Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left on device"))
+        });
+        assert_eq!(failed, -3);
+        assert!(sent("Failed to flush Tor state: no space left on device"));
+        assert_eq!(std::fs::read_to_string(&state).unwrap(), "{\"guards\":[\"AAAA\"]}");
     }
 
-    const ANDROID_LOG_INFO: c_int = 4;
+    #[test]
+    fn initialize_again_reuses_the_bootstrapped_client() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let runtime = testing::runtime();
+        let temp = testing::TempDir::new();
+        let dir = temp.path().join("data").to_string_lossy().into_owned();
+        let other = temp.path().join("other").to_string_lossy().into_owned();
+        let client = Arc::new(unbootstrapped_client(&runtime));
 
-    pub fn log(message: &str) {
-        unsafe {
-            let tag = CString::new("ArtiNative").unwrap();
-            let text = CString::new(message).unwrap();
-            __android_log_write(ANDROID_LOG_INFO, tag.as_ptr() as *const c_char, text.as_ptr() as *const c_char);
-        }
+        // As a successful initialize of `dir` left things
+        let saved_client = super::ARTI_CLIENT.lock().unwrap().replace(Arc::clone(&client));
+        let saved_dir = super::DATA_DIR.lock().unwrap().replace(std::path::PathBuf::from(&dir));
+        let saved_reusable = super::CLIENT_REUSABLE.swap(true, Ordering::SeqCst);
+        let saved_state = super::LIFECYCLE_STATE.load(Ordering::SeqCst);
+        set_state(LifecycleState::Running);
+
+        // The recreated Activity initializes again, recording the same settings
+        let setting = "max_circuit_dirtiness_secs";
+        config::set(setting, json!(super::DEFAULT_CIRCUIT_DIRTINESS_SECS)).unwrap();
+        assert_eq!(super::initialize_or_reuse(dir.clone()), super::INIT_REUSED);
+        let current = super::ARTI_CLIENT.lock().unwrap().clone().unwrap();
+        assert!(Arc::ptr_eq(&current, &client));
+        assert_eq!(LifecycleState::from_i32(super::LIFECYCLE_STATE.load(Ordering::SeqCst)), LifecycleState::Running);
+        assert!(config::reset_pending().is_empty());
+
+        // A changed setting, another directory or a failed bootstrap needs a new client
+        config::set(setting, json!(120)).unwrap();
+        assert!(!super::reuse_client(&dir));
+        assert_eq!(config::reset_pending(), [setting]);
+        assert!(!super::reuse_client(&other));
+        super::CLIENT_REUSABLE.store(false, Ordering::SeqCst);
+        assert!(!super::reuse_client(&dir));
+
+        crate::state_lock::release();
+        set_state(LifecycleState::from_i32(saved_state));
+        super::CLIENT_REUSABLE.store(saved_reusable, Ordering::SeqCst);
+        *super::DATA_DIR.lock().unwrap() = saved_dir;
+        *super::ARTI_CLIENT.lock().unwrap() = saved_client;
+        drop(client);
+        drop(current);
+        drop(runtime);
     }
 }
@@ -0,0 +1,322 @@
+//! SOCKS listener secret and its rotation
+//!
+//! Any local app can connect to the proxy port. Once a listener secret is set
+//! with `rotateSocksAuth`, every handshake must use username/password auth
+//! (RFC 1929) with it: the password must match, and so must the first
+//! `;`-separated field of the username, ahead of any per-connection options
+//! (see target.rs). Other handshakes are refused (status 0x01) and closed.
+//!
+//! Rotating makes the new secret current. For `grace` after the call, the
+//! secret it replaced is still accepted, so a component that read the old
+//! secret just before the rotation doesn't fail; afterwards only the new one
+//! works. Only handshakes are checked, so established connections are never
+//! affected. A rotation during an open grace window is resolved so that at
+//! most two secrets are ever accepted:
+//!
+//! - the newest secret wins;
+//! - the secret that was current until then (the middle one) is wiped right
+//!   away, having never been the only valid secret;
+//! - the secret already in its grace window keeps it, cut short if the new
+//!   `grace` ends sooner.
+//!
+//! Secrets are held in zeroizing buffers, and the secret in grace is wiped
+//! when its window ends (checked at each handshake and by the heartbeat).
+//! Rotations are audited, never with the secrets.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use zeroize::Zeroizing;
+
+use crate::seams;
+
+/// Longest grace window accepted from the Java layer
+pub(crate) const MAX_GRACE_SECS: u64 = 3600;
+
+struct Secret {
+    username: Zeroizing<Vec<u8>>,
+    password: Zeroizing<Vec<u8>>,
+}
+
+impl Secret {
+    fn accepts(&self, username: &[u8], password: &[u8]) -> bool {
+        let presented = username.split(|b| *b == b';').next().unwrap_or_default();
+        // Both are compared in full, whatever the first mismatch
+        constant_time_eq(&self.username, presented) & constant_time_eq(&self.password, password)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+struct Secrets {
+    current: Option<Secret>,
+    /// The replaced secret and the end of its grace window
+    previous: Option<(Secret, Instant)>,
+}
+
+impl Secrets {
+    /// Wipe the previous secret once its window is over
+    fn expire(&mut self, now: Instant) {
+        if self.previous.as_ref().is_some_and(|(_, until)| now >= *until) {
+            self.previous = None;
+            log_info!("SOCKS listener grace window ended; previous secret wiped");
+        }
+    }
+}
+
+static SECRETS: Mutex<Secrets> = Mutex::new(Secrets { current: None, previous: None });
+
+/// Check a listener secret from the Java layer
+pub(crate) fn check(username: &str, password: &str) -> Result<(), String> {
+    if username.is_empty() || password.is_empty() {
+        return Err("username and password must both be non-empty".into());
+    }
+    if username.len() > 255 || password.len() > 255 {
+        return Err("username and password are limited to 255 bytes".into());
+    }
+    if username.contains([';', '=']) {
+        return Err("username must not contain ';' or '='".into());
+    }
+    Ok(())
+}
+
+/// Make `username`/`password` the current secret (checked with `check`)
+pub(crate) fn rotate(username: Zeroizing<Vec<u8>>, password: Zeroizing<Vec<u8>>, grace: Duration) {
+    let now = seams::now_monotonic();
+    let mut secrets = SECRETS.lock().unwrap();
+    secrets.expire(now);
+
+    let replaced = secrets.current.replace(Secret { username, password });
+    let grace_until = now + grace;
+    let overlapped = match (replaced, secrets.previous.as_mut()) {
+        (None, _) => false,
+        // Newest wins; the middle secret is dropped (wiped) here
+        (Some(_middle), Some((_, until))) => {
+            *until = (*until).min(grace_until);
+            true
+        }
+        (Some(replaced), None) => {
+            if !grace.is_zero() {
+                secrets.previous = Some((replaced, grace_until));
+            }
+            false
+        }
+    };
+    drop(secrets);
+
+    let detail = format!("grace_secs={}{}", grace.as_secs(), if overlapped { ",overlapped=1" } else { "" });
+    crate::audit::record("socks_auth_rotated", &detail);
+}
+
+/// Whether handshakes must authenticate
+pub(crate) fn required() -> bool {
+    SECRETS.lock().unwrap().current.is_some()
+}
+
+/// Whether a handshake's credentials match an accepted secret
+pub(crate) fn accepts(username: &[u8], password: &[u8]) -> bool {
+    let mut secrets = SECRETS.lock().unwrap();
+    secrets.expire(seams::now_monotonic());
+    let current = secrets.current.as_ref().is_some_and(|s| s.accepts(username, password));
+    let previous = secrets.previous.as_ref().is_some_and(|(s, _)| s.accepts(username, password));
+    current | previous
+}
+
+/// Wipe an expired grace secret (called from the heartbeat)
+pub(crate) fn sweep() {
+    SECRETS.lock().unwrap().expire(seams::now_monotonic());
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&SECRETS);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::seams::fakes::FakeConnector;
+    use crate::socks::Reply;
+    use crate::testing::{self, socks_connect, socks_greet, socks_login, socks_session, FakeTime};
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn secret(text: &str) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(text.as_bytes().to_vec())
+    }
+
+    fn rotate_to(name: &str, grace: Duration) {
+        rotate(secret(name), secret(&format!("{}-password", name)), grace);
+    }
+
+    fn accepted(name: &str) -> bool {
+        accepts(name.as_bytes(), format!("{}-password", name).as_bytes())
+    }
+
+    /// Puts back the secrets in effect before the test
+    struct Restore(Option<Secrets>);
+
+    impl Restore {
+        fn take() -> Restore {
+            let empty = Secrets { current: None, previous: None };
+            Restore(Some(std::mem::replace(&mut SECRETS.lock().unwrap(), empty)))
+        }
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            *SECRETS.lock().unwrap() = self.0.take().unwrap();
+        }
+    }
+
+    #[test]
+    fn secrets_are_checked() {
+        let long = "x".repeat(256);
+        for (username, password, ok) in [
+            ("app", "secret", true),
+            ("", "secret", false),
+            ("app", "", false),
+            (long.as_str(), "secret", false),
+            ("app", long.as_str(), false),
+            ("app;iso=1", "secret", false),
+            ("app=1", "secret", false),
+        ] {
+            assert_eq!(check(username, password).is_ok(), ok, "{:?} {:?}", username, password);
+        }
+    }
+
+    #[test]
+    fn only_the_secret_and_its_username_field_match() {
+        let _serial = testing::serial();
+        let _restore = Restore::take();
+        assert!(!required());
+
+        rotate_to("app", Duration::ZERO);
+        assert!(required());
+        assert!(accepted("app"));
+        assert!(accepts(b"app;iso=7", b"app-password"));
+        assert!(!accepts(b"app", b"app-passwore"));
+        assert!(!accepts(b"ap", b"app-password"));
+        assert!(!accepts(b"", b""));
+    }
+
+    #[test]
+    fn the_replaced_secret_is_accepted_for_the_grace_window_only() {
+        let _serial = testing::serial();
+        let _restore = Restore::take();
+        let time = FakeTime::start();
+
+        rotate_to("old", Duration::ZERO);
+        rotate_to("new", 60 * SECOND);
+        assert!(accepted("old") && accepted("new"));
+        time.advance(59 * SECOND);
+        assert!(accepted("old") && accepted("new"));
+        time.advance(SECOND);
+        assert!(!accepted("old"));
+        assert!(accepted("new"));
+        assert!(SECRETS.lock().unwrap().previous.is_none());
+
+        // Without a grace window the replaced secret stops working at once
+        rotate_to("newer", Duration::ZERO);
+        assert!(!accepted("new"));
+        assert!(accepted("newer"));
+
+        // The heartbeat wipes an expired secret nobody presented
+        rotate_to("newest", 10 * SECOND);
+        time.advance(10 * SECOND);
+        assert!(SECRETS.lock().unwrap().previous.is_some());
+        sweep();
+        assert!(SECRETS.lock().unwrap().previous.is_none());
+    }
+
+    #[test]
+    fn overlapping_rotations_keep_at_most_two_secrets() {
+        let _serial = testing::serial();
+        let _restore = Restore::take();
+        let time = FakeTime::start();
+
+        // A shorter second window cuts the first one short
+        rotate_to("a", Duration::ZERO);
+        rotate_to("b", 60 * SECOND);
+        time.advance(10 * SECOND);
+        rotate_to("c", 30 * SECOND);
+        assert!(!accepted("b"), "the middle secret is wiped at once");
+        assert!(accepted("a") && accepted("c"));
+        time.advance(30 * SECOND);
+        assert!(!accepted("a"));
+        assert!(accepted("c"));
+
+        // A longer second window doesn't extend the first
+        rotate_to("d", 60 * SECOND);
+        time.advance(10 * SECOND);
+        rotate_to("e", 600 * SECOND);
+        assert!(!accepted("d"));
+        assert!(accepted("c") && accepted("e"));
+        time.advance(50 * SECOND);
+        assert!(!accepted("c"));
+        assert!(accepted("e"));
+    }
+
+    #[test]
+    fn established_connections_outlive_a_rotation() {
+        let _serial = testing::serial();
+        let _restore = Restore::take();
+        let connector = Arc::new(FakeConnector::new());
+        let mut destination = connector.then_connect();
+        rotate_to("old", Duration::ZERO);
+
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            assert_eq!(socks_greet(&mut client, &[0x02]).await, 0x02);
+            assert_eq!(socks_login(&mut client, "old", "old-password").await, 0x00);
+            assert_eq!(socks_connect(&mut client, "example.com", 80).await, Reply::Succeeded.frame());
+
+            // Rotated with no grace: the open connection keeps relaying
+            rotate_to("new", Duration::ZERO);
+            client.write_all(b"ping").await.unwrap();
+            let mut received = [0u8; 4];
+            destination.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"ping");
+            destination.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"pong");
+
+            // A new handshake with the old secret is refused
+            let (mut late, late_handler, _late_closer) = socks_session(Arc::clone(&connector)).await;
+            assert_eq!(socks_greet(&mut late, &[0x02]).await, 0x02);
+            assert_eq!(socks_login(&mut late, "old", "old-password").await, 0x01);
+            let mut rest = Vec::new();
+            late.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            let _ = late_handler.await.unwrap();
+
+            drop(destination);
+            client.shutdown().await.unwrap();
+            handler.await.unwrap().unwrap();
+        });
+        assert_eq!(connector.requests().len(), 1);
+    }
+
+    #[test]
+    fn rotations_are_audited_without_the_secrets() {
+        let _serial = testing::serial();
+        let _restore = Restore::take();
+        crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+
+        rotate_to("first", Duration::ZERO);
+        rotate_to("second", 60 * SECOND);
+        rotate_to("third", 30 * SECOND);
+        let lines = crate::tests::SENT_LOG_LINES.lock().unwrap().clone();
+        let audited: Vec<&String> = lines.iter().filter(|line| line.contains("socks_auth_rotated")).collect();
+        assert_eq!(audited.len(), 3, "{:?}", lines);
+        assert!(audited[1].ends_with("grace_secs=60"), "{}", audited[1]);
+        assert!(audited[2].ends_with("grace_secs=30,overlapped=1"), "{}", audited[2]);
+        assert!(!lines.iter().any(|line| line.contains("-password")), "{:?}", lines);
+    }
+}
@@ -0,0 +1,113 @@
+//! Why the SOCKS accept loop last exited
+//!
+//! The accept loop can end because the app stopped or restarted the proxy,
+//! or on its own: an accept error, or the task ending some other way (a
+//! panic). Before, only a log line told them apart, so the app couldn't tell
+//! whether to restart the proxy. Each run of the loop is now a `Run`, and the
+//! reason it ended is kept for `getLastShutdownReason`:
+//!
+//! - `stopped` / `restarted`: recorded by stop, shutdown, data directory
+//!   migration or a new `startSocksProxy` just before they abort the loop;
+//! - `accept_error`: recorded by the loop itself before it exits;
+//! - `fatal`: recorded when a run ends with nothing else recorded.
+//!
+//! A low-battery pause doesn't end the loop, so it isn't a reason.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_json::json;
+
+use crate::notification::now_epoch_ms;
+use crate::retention;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reason {
+    Stopped,
+    Restarted,
+    AcceptError,
+    Fatal,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Stopped => "stopped",
+            Reason::Restarted => "restarted",
+            Reason::AcceptError => "accept_error",
+            Reason::Fatal => "fatal",
+        }
+    }
+}
+
+struct Record {
+    reason: Reason,
+    detail: String,
+    at_epoch_ms: u64,
+}
+
+/// Ids handed out to runs (0 is never used)
+static RUNS: AtomicU64 = AtomicU64::new(0);
+
+/// The run whose exit hasn't been recorded yet (0: none)
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+static LAST: Mutex<Option<Record>> = Mutex::new(None);
+
+fn store(reason: Reason, detail: String) {
+    log_info!("SOCKS accept loop ended: {} ({})", reason.name(), detail);
+    *LAST.lock().unwrap() = Some(Record { reason, detail, at_epoch_ms: retention::timestamp(now_epoch_ms()) });
+}
+
+/// One run of the accept loop, owned by its task
+///
+/// Dropping it without a recorded reason records `fatal`.
+pub(crate) struct Run(u64);
+
+impl Run {
+    /// Start a run (before spawning its task, so an early abort still ends it)
+    pub(crate) fn start() -> Self {
+        let id = RUNS.fetch_add(1, Ordering::Relaxed) + 1;
+        CURRENT.store(id, Ordering::SeqCst);
+        Run(id)
+    }
+
+    /// Record why this run is ending, unless already recorded
+    pub(crate) fn end(&self, reason: Reason, detail: String) {
+        if CURRENT.compare_exchange(self.0, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            store(reason, detail);
+        }
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let detail = if std::thread::panicking() { "panicked" } else { "task ended unexpectedly" };
+        self.end(Reason::Fatal, detail.to_string());
+    }
+}
+
+/// Record why the running loop is about to be aborted by `caller`
+pub(crate) fn aborting(reason: Reason, caller: &str) {
+    if CURRENT.swap(0, Ordering::SeqCst) != 0 {
+        store(reason, caller.to_string());
+    }
+}
+
+/// The last reason as JSON (`null` if the loop never exited)
+pub(crate) fn last_json() -> String {
+    match LAST.lock().unwrap().as_ref() {
+        None => "null".to_string(),
+        Some(record) => json!({
+            "reason": record.reason.name(),
+            "detail": record.detail,
+            "at_epoch_ms": record.at_epoch_ms,
+        })
+        .to_string(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&LAST);
+}
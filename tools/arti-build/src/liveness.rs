@@ -0,0 +1,293 @@
+//! Lock-free liveness signals for the Kotlin watchdog
+//!
+//! `ping` must answer even when every mutex in the wrapper is held and the
+//! Tokio runtime is saturated, so it only reads atomics maintained by the
+//! components being watched:
+//!
+//! - a heartbeat task on the runtime stamps `HEARTBEAT_MS` every second
+//!   (it also runs the connection stall sweep, see stall.rs, and the network
+//!   token staleness check, see network_token.rs, and the storage re-probe,
+//!   see storage.rs);
+//! - each Java upcall stamps a slot in `UPCALL_STARTED_MS` while its
+//!   callback is executing; the oldest stamp is reported, so a stuck upcall
+//!   stays visible while quicker ones come and go on other threads. Upcalls
+//!   beyond `UPCALL_SLOTS` concurrent ones go untimed.
+//!
+//! Packed result (`jlong`), most significant bits first:
+//!
+//! | bits  | field                                                      |
+//! |-------|------------------------------------------------------------|
+//! | 56-63 | lifecycle state (`LifecycleState` discriminant)            |
+//! | 28-55 | ms since the last runtime heartbeat (saturating)           |
+//! | 0-27  | ms the oldest in-flight Java upcall has run (0 if none)    |
+//!
+//! Staleness the watchdog should apply:
+//!
+//! - heartbeat age > 3 s: the runtime is not scheduling tasks (busy); > 30 s:
+//!   treat the native layer as wedged. The field saturates before the runtime
+//!   exists, so only judge it once `initialize` has succeeded.
+//! - upcall age > 5 s: a Java callback is blocking a native thread; the fault
+//!   is on the Kotlin side, not in the native layer.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::LIFECYCLE_STATE;
+
+const FIELD_MAX: u64 = (1 << 28) - 1;
+
+/// Interval between runtime heartbeats
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Monotonic ms of the last heartbeat (0 = never)
+static HEARTBEAT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Java upcalls timed at once; callbacks run on a handful of native threads
+const UPCALL_SLOTS: usize = 32;
+
+/// Monotonic ms when each in-flight Java upcall started (0 = free slot)
+static UPCALL_STARTED_MS: [AtomicU64; UPCALL_SLOTS] = [const { AtomicU64::new(0) }; UPCALL_SLOTS];
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Milliseconds on a process-local monotonic clock, starting at 1
+pub(crate) fn monotonic_ms() -> u64 {
+    let epoch = *EPOCH.get_or_init(crate::seams::now_monotonic);
+    crate::seams::now_monotonic().saturating_duration_since(epoch).as_millis() as u64 + 1
+}
+
+/// Spawn the heartbeat task on the runtime
+pub(crate) fn start_heartbeat(runtime: &tokio::runtime::Runtime) {
+    runtime.spawn(async {
+        loop {
+            HEARTBEAT_MS.store(monotonic_ms(), Ordering::Relaxed);
+            crate::stall::sweep();
+            crate::network_token::check();
+            crate::storage::probe();
+            crate::listener_auth::sweep();
+            crate::metrics_history::tick();
+            crate::reliability::tick();
+            crate::censorship::tick();
+            crate::seams::sleep(HEARTBEAT_INTERVAL).await;
+        }
+    });
+}
+
+/// Marks a Java upcall as in flight for as long as it is alive
+pub(crate) struct UpcallGuard {
+    slot: Option<&'static AtomicU64>,
+}
+
+impl UpcallGuard {
+    pub(crate) fn new() -> Self {
+        let now = monotonic_ms();
+        let slot = UPCALL_STARTED_MS
+            .iter()
+            .find(|slot| slot.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed).is_ok());
+        UpcallGuard { slot }
+    }
+}
+
+impl Drop for UpcallGuard {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            slot.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Start of the oldest in-flight upcall (0 = none)
+fn oldest_upcall() -> u64 {
+    UPCALL_STARTED_MS
+        .iter()
+        .map(|slot| slot.load(Ordering::Relaxed))
+        .filter(|&started| started != 0)
+        .min()
+        .unwrap_or(0)
+}
+
+fn age(since_ms: u64, now_ms: u64) -> u64 {
+    if since_ms == 0 {
+        return FIELD_MAX;
+    }
+    now_ms.saturating_sub(since_ms).min(FIELD_MAX)
+}
+
+/// Pack the liveness fields; never locks and never touches the runtime
+pub(crate) fn ping() -> i64 {
+    let now = monotonic_ms();
+    let state = LIFECYCLE_STATE.load(Ordering::Relaxed) as u64 & 0xff;
+    let heartbeat_age = age(HEARTBEAT_MS.load(Ordering::Relaxed), now);
+    let upcall = oldest_upcall();
+    let upcall_age = if upcall == 0 { 0 } else { age(upcall, now) };
+
+    ((state << 56) | (heartbeat_age << 28) | upcall_age) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, HeldLocks};
+    use std::sync::mpsc;
+
+    /// Every process-wide lock in the wrapper
+    fn hold_every_lock(held: &mut HeldLocks) {
+    crate::actor::hold_locks(held);
+    crate::audit::hold_locks(held);
+    crate::bootstrap_history::hold_locks(held);
+    crate::bridges::hold_locks(held);
+    crate::bypass::hold_locks(held);
+    crate::caches::hold_locks(held);
+    crate::callbacks::hold_locks(held);
+    crate::censorship::hold_locks(held);
+    #[cfg(feature = "chaos")]
+    crate::chaos::hold_locks(held);
+    crate::config::hold_locks(held);
+    crate::connections::hold_locks(held);
+    crate::consensus_age::hold_locks(held);
+    crate::debounce::hold_locks(held);
+    crate::diagnostics::hold_locks(held);
+    crate::diversity::hold_locks(held);
+    crate::geo::hold_locks(held);
+    crate::isolation_groups::hold_locks(held);
+    crate::hold_locks(held);
+    crate::listener_auth::hold_locks(held);
+    crate::listener_exit::hold_locks(held);
+    crate::log_file::hold_locks(held);
+    crate::log_rate::hold_locks(held);
+    crate::metrics_history::hold_locks(held);
+    crate::network_token::hold_locks(held);
+    crate::notification::hold_locks(held);
+    crate::onion_auth::hold_locks(held);
+    crate::policy::hold_locks(held);
+    crate::query::hold_locks(held);
+    crate::recent_logs::hold_locks(held);
+    crate::reconcile::hold_locks(held);
+    crate::reliability::hold_locks(held);
+    crate::shutdown::hold_locks(held);
+    crate::smoke::hold_locks(held);
+    crate::state_lock::hold_locks(held);
+    crate::storage::hold_locks(held);
+    crate::target::hold_locks(held);
+    crate::traffic::hold_locks(held);
+    }
+
+    #[test]
+    fn ping_never_waits_on_a_lock() {
+        let _serial = testing::serial();
+        let (held_tx, held_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let holder = std::thread::spawn(move || {
+            let mut held = HeldLocks::default();
+            hold_every_lock(&mut held);
+            held_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        held_rx.recv().unwrap();
+
+        let _upcall = UpcallGuard::new();
+        let (done_tx, done_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut took: Vec<Duration> = (0..1000)
+                .map(|_| {
+                    let started = Instant::now();
+                    std::hint::black_box(ping());
+                    started.elapsed()
+                })
+                .collect();
+            took.sort();
+            let _ = done_tx.send(took);
+        });
+        let took = done_rx.recv_timeout(Duration::from_secs(10));
+
+        // Release before asserting so a failure can't wedge the other tests
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        let took = took.expect("ping blocked while the locks were held");
+        assert!(took[took.len() / 2] < Duration::from_micros(100), "median ping {:?}", took[took.len() / 2]);
+    }
+
+    #[test]
+    fn every_global_lock_is_held_by_the_no_block_test() {
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let liveness = std::fs::read_to_string(src.join("liveness.rs")).unwrap();
+        let mut missing = Vec::new();
+        for entry in std::fs::read_dir(&src).unwrap() {
+            let path = entry.unwrap().path();
+            let file = path.file_name().unwrap().to_str().unwrap().to_string();
+            if file == "testing.rs" || !file.ends_with(".rs") {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            let code = text.split("#[cfg(test)]\nmod tests").next().unwrap();
+            let holds = code.split("fn hold_locks").nth(1).unwrap_or("");
+            let module = file.trim_end_matches(".rs");
+            let call = match module {
+                "lib" => "crate::hold_locks(held)".to_string(),
+                _ => format!("crate::{}::hold_locks(held)", module),
+            };
+            for line in code.lines() {
+                let line = line.trim_start();
+                let Some(rest) = line.strip_prefix("static ") else { continue };
+                let Some((name, ty)) = rest.split_once(':') else { continue };
+                if !(ty.contains("Mutex<") || ty.contains("RwLock<")) {
+                    continue;
+                }
+                if !holds.contains(&format!("&{})", name)) && !holds.contains(&format!("&{} ", name)) {
+                    missing.push(format!("{}: {} not in hold_locks", file, name));
+                } else if !liveness.contains(&call) {
+                    missing.push(format!("{}: hold_locks not called here", file));
+                }
+            }
+        }
+        missing.dedup();
+        assert!(missing.is_empty(), "{:#?}", missing);
+    }
+
+    #[test]
+    fn oldest_concurrent_upcall_is_reported() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let upcall_age = || (ping() as u64) & FIELD_MAX;
+        assert_eq!(upcall_age(), 0);
+
+        let first = UpcallGuard::new();
+        time.advance(Duration::from_millis(400));
+        let second = UpcallGuard::new();
+        time.advance(Duration::from_millis(100));
+        assert_eq!(upcall_age(), 500);
+
+        // The first finishing leaves the second visible
+        drop(first);
+        assert_eq!(upcall_age(), 100);
+
+        // So does a quick upcall coming and going beside a stuck one
+        drop(UpcallGuard::new());
+        time.advance(Duration::from_millis(5_000));
+        assert_eq!(upcall_age(), 5_100);
+        drop(second);
+        assert_eq!(upcall_age(), 0);
+    }
+
+    #[test]
+    fn upcalls_past_the_slots_go_untimed() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let guards: Vec<UpcallGuard> = (0..UPCALL_SLOTS + 4).map(|_| UpcallGuard::new()).collect();
+        assert!(guards[..UPCALL_SLOTS].iter().all(|g| g.slot.is_some()));
+        assert!(guards[UPCALL_SLOTS..].iter().all(|g| g.slot.is_none()));
+        time.advance(Duration::from_millis(250));
+        assert_eq!((ping() as u64) & FIELD_MAX, 250);
+        drop(guards);
+        assert!(UPCALL_STARTED_MS.iter().all(|slot| slot.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn ages_saturate() {
+        assert_eq!(age(0, 10), FIELD_MAX);
+        assert_eq!(age(5, 10), 5);
+        assert_eq!(age(10, 5), 0);
+        assert_eq!(age(1, FIELD_MAX + 10), FIELD_MAX);
+    }
+}
@@ -0,0 +1,334 @@
+//! Rotating log file for crash reports
+//!
+//! With `setLogFile` every log line is also appended to a file, so a crash
+//! report can include the native logs of a process that died before the Java
+//! callback got them. Each line is written as soon as the writer thread takes
+//! it (no user-space buffering), so it survives the process.
+//!
+//! Lines go through a bounded channel to a dedicated writer thread: logging
+//! from the relay path only ever does a `try_send`, and a line that doesn't
+//! fit because the disk is slow is dropped and counted rather than waited on.
+//!
+//! Once the file would exceed `MAX_FILE_BYTES` it is rotated: `log` becomes
+//! `log.1`, `log.1` becomes `log.2` and so on, keeping at most `MAX_FILES`
+//! files in total. The file is a retention sink (see retention.rs): lines
+//! carry retention-rounded timestamps, and the files are deleted at the
+//! minimal level.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::notification::now_epoch_ms;
+use crate::redact;
+use crate::retention::{self, Sink};
+
+/// Size at which the file is rotated
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Files kept, the current one included
+const MAX_FILES: usize = 3;
+
+/// Lines waiting for the writer before new ones are dropped
+const QUEUE_LINES: usize = 1024;
+
+enum Command {
+    Line(String),
+    /// Switch to a file (`None` stops writing)
+    Open(Option<PathBuf>),
+    /// Delete the current file and its rotations
+    Purge,
+    /// Acknowledge once every earlier line is written
+    Flush(SyncSender<()>),
+    /// Stop writing until the other end hangs up (tests keep lines queued)
+    #[cfg(test)]
+    Hold(Receiver<()>),
+}
+
+/// Whether a file is set (checked before touching the channel)
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static SENDER: Mutex<Option<SyncSender<Command>>> = Mutex::new(None);
+
+/// Lines dropped because the writer fell behind or failed
+static DROPPED_LINES: AtomicU64 = AtomicU64::new(0);
+
+/// Reject a path the writer could never open
+pub(crate) fn check_path(path: &str) -> Result<(), String> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err("expected an absolute path".into());
+    }
+    if path.is_dir() {
+        return Err("path is a directory".into());
+    }
+    match path.parent() {
+        Some(parent) if parent.is_dir() => Ok(()),
+        _ => Err("parent directory does not exist".into()),
+    }
+}
+
+/// Start writing to `path`, or stop if it is empty
+pub(crate) fn set_path(path: &str) {
+    let path = (!path.is_empty()).then(|| PathBuf::from(path));
+    ENABLED.store(path.is_some(), Ordering::Relaxed);
+    send(Command::Open(path));
+}
+
+/// Queue a log line; never blocks
+pub(crate) fn push(line: &str) {
+    if !ENABLED.load(Ordering::Relaxed) || !retention::retains(Sink::LogFile) {
+        return;
+    }
+    let line = format!("{} {}\n", retention::timestamp(now_epoch_ms()), line);
+    let sent = SENDER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|sender| sender.try_send(Command::Line(line)).is_ok());
+    if !sent {
+        DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Delete the log files (the file stays set, and is recreated by the next line)
+pub(crate) fn purge() {
+    send(Command::Purge);
+}
+
+pub(crate) fn dropped_lines() -> u64 {
+    DROPPED_LINES.load(Ordering::Relaxed)
+}
+
+/// Wait up to `timeout` for the queued lines to be written
+pub(crate) fn flush(timeout: Duration) {
+    if SENDER.lock().unwrap().is_none() {
+        return;
+    }
+    let (tx, rx) = mpsc::sync_channel(1);
+    send(Command::Flush(tx));
+    let _ = rx.recv_timeout(timeout);
+}
+
+/// Send a control command, starting the writer on first use
+fn send(command: Command) {
+    let sender = {
+        let mut sender = SENDER.lock().unwrap();
+        if sender.is_none() {
+            let (tx, rx) = mpsc::sync_channel(QUEUE_LINES);
+            if std::thread::Builder::new().name("arti-log-file".into()).spawn(move || run(rx)).is_err() {
+                return;
+            }
+            *sender = Some(tx);
+        }
+        sender.clone()
+    };
+    // Control commands wait for room (outside the lock, so `push` doesn't);
+    // only lines are dropped
+    if let Some(sender) = sender {
+        let _ = sender.send(command);
+    }
+}
+
+/// Path of the `index`-th rotation (0 is the current file)
+fn rotation(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok((file, len))
+}
+
+/// Shift every rotation up by one, dropping the oldest, and start a fresh file
+fn rotate(path: &Path) -> io::Result<(File, u64)> {
+    let _ = fs::remove_file(rotation(path, MAX_FILES - 1));
+    for index in (0..MAX_FILES - 1).rev() {
+        let _ = fs::rename(rotation(path, index), rotation(path, index + 1));
+    }
+    open(path)
+}
+
+/// Append `line` to the open file, opening or rotating it first as needed
+fn write_line(path: &Path, current: &mut Option<(File, u64)>, line: &str) -> io::Result<()> {
+    let (mut file, mut size) = match current.take() {
+        Some(open_file) => open_file,
+        None => open(path)?,
+    };
+    let len = line.len() as u64;
+    if size > 0 && size + len > MAX_FILE_BYTES {
+        (file, size) = rotate(path)?;
+    }
+    file.write_all(line.as_bytes())?;
+    *current = Some((file, size + len));
+    Ok(())
+}
+
+/// Writer thread
+fn run(commands: Receiver<Command>) {
+    let mut path: Option<PathBuf> = None;
+    let mut current: Option<(File, u64)> = None;
+
+    for command in commands {
+        match command {
+            Command::Open(new_path) => {
+                current = None;
+                path = new_path;
+            }
+            Command::Purge => {
+                current = None;
+                if let Some(path) = path.as_deref() {
+                    for index in 0..MAX_FILES {
+                        let _ = fs::remove_file(rotation(path, index));
+                    }
+                }
+            }
+            Command::Flush(done) => {
+                let _ = done.send(());
+            }
+            #[cfg(test)]
+            Command::Hold(release) => {
+                let _ = release.recv();
+            }
+            Command::Line(line) => {
+                let Some(path) = path.as_deref() else {
+                    continue;
+                };
+                // Redacted now, not when queued (see redact.rs); errors can't
+                // be logged from here (they'd come back as lines)
+                if write_line(path, &mut current, &redact::render(&line)).is_err() {
+                    DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&SENDER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn paths_are_checked_before_use() {
+        let temp = TempDir::new();
+        let file = temp.path().join("arti.log");
+        for (path, accepted) in [
+            (file.clone(), true),
+            (PathBuf::from("relative.log"), false),
+            (temp.path().to_path_buf(), false),
+            (temp.path().join("missing").join("arti.log"), false),
+        ] {
+            assert_eq!(check_path(path.to_str().unwrap()).is_ok(), accepted, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn lines_reach_the_file_with_a_timestamp() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let path = temp.path().join("arti.log");
+
+        set_path(path.to_str().unwrap());
+        push("first line");
+        push("second line");
+        flush(WAIT);
+        let text = fs::read_to_string(&path).unwrap();
+        // Other tests may log meanwhile; ours arrive in order
+        let ours: Vec<&str> = text
+            .lines()
+            .map(|line| {
+                let (at, rest) = line.split_once(' ').unwrap();
+                assert!(at.parse::<u64>().is_ok(), "{}", line);
+                rest
+            })
+            .filter(|rest| rest.ends_with(" line"))
+            .collect();
+        assert_eq!(ours, ["first line", "second line"]);
+
+        // Without a file nothing is queued
+        set_path("");
+        push("not written");
+        flush(WAIT);
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+    }
+
+    #[test]
+    fn queued_lines_follow_the_policy_when_written() {
+        let _serial = testing::serial();
+        let _saved = crate::config::save();
+        let temp = TempDir::new();
+        let path = temp.path().join("arti.log");
+        set_path(path.to_str().unwrap());
+
+        for (queued_safe, written_safe) in [(false, true), (true, false), (false, false), (true, true)] {
+            let host = format!("queued-{}-{}.example", queued_safe, written_safe);
+            let scrubbed = || fs::read_to_string(&path).unwrap_or_default().matches("connect to [scrubbed]").count();
+            let scrubbed_before = scrubbed();
+            crate::config::set("safe_logging", serde_json::json!(queued_safe)).unwrap();
+            // The writer holds off until the policy has changed again
+            let (release, held) = mpsc::sync_channel(0);
+            send(Command::Hold(held));
+            for _ in 0..3 {
+                push(&format!("connect to {}", redact::Sensitive(&host)));
+            }
+            crate::config::set("safe_logging", serde_json::json!(written_safe)).unwrap();
+            drop(release);
+            flush(WAIT);
+
+            let text = fs::read_to_string(&path).unwrap();
+            assert_eq!(text.matches(&host).count(), if written_safe { 0 } else { 3 }, "queued safe {} written safe {}", queued_safe, written_safe);
+            assert_eq!(scrubbed() - scrubbed_before, if written_safe { 3 } else { 0 });
+            // The marks never reach the file
+            assert!(!text.contains('\u{E000}') && !text.contains('\u{E001}'));
+        }
+        set_path("");
+        flush(WAIT);
+    }
+
+    #[test]
+    fn files_rotate_at_the_size_cap_and_are_bounded_in_number() {
+        let temp = TempDir::new();
+        let path = temp.path().join("arti.log");
+        let line = format!("{}\n", "x".repeat(MAX_FILE_BYTES as usize / 4 - 1));
+        let mut current = None;
+
+        // Four lines fill the file exactly; the fifth starts a new one
+        for _ in 0..4 {
+            write_line(&path, &mut current, &line).unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), MAX_FILE_BYTES);
+        assert!(!rotation(&path, 1).exists());
+        write_line(&path, &mut current, &line).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), line.len() as u64);
+        assert_eq!(fs::metadata(rotation(&path, 1)).unwrap().len(), MAX_FILE_BYTES);
+
+        // However much is written, only `MAX_FILES` files are kept
+        for _ in 0..4 * MAX_FILES {
+            write_line(&path, &mut current, &line).unwrap();
+        }
+        let kept = fs::read_dir(temp.path()).unwrap().count();
+        assert_eq!(kept, MAX_FILES);
+        assert_eq!(rotation(&path, MAX_FILES - 1).file_name().unwrap(), format!("arti.log.{}", MAX_FILES - 1).as_str());
+        for index in 0..MAX_FILES {
+            assert!(fs::metadata(rotation(&path, index)).unwrap().len() <= MAX_FILE_BYTES);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Rate limit on log lines reaching the Java callback
+//!
+//! During bootstrap Arti can log hundreds of lines a second, and apps usually
+//! hand each `onLogLine` to their UI thread. With `setLogRateLimitPerSec(n)`
+//! at most `n` lines per one-second window reach the callback:
+//!
+//! - lines over the cap are dropped and counted (`log_callback_dropped_lines`
+//!   in `getMetrics`); the next line delivered after a drop is preceded by
+//!   one line saying how many were dropped, which doesn't count against the
+//!   cap;
+//! - error lines (the wrapper's `ERROR:` lines, Arti's WARN and ERROR events)
+//!   always go through and don't use up the cap either;
+//! - logcat, the log file and the recent-log ring still get every line.
+//!
+//! 0 (the default) means no limit. Called with `LOG_DELIVERY` held (see
+//! `emit_log`), so the window needs no ordering of its own.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::liveness::monotonic_ms;
+
+/// Highest limit of `setLogRateLimitPerSec`
+pub(crate) const MAX_PER_SEC: u64 = 10_000;
+
+const WINDOW_MS: u64 = 1000;
+
+/// Lines per window (0: unlimited)
+static LIMIT: AtomicU32 = AtomicU32::new(0);
+
+/// Lines dropped since process start
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+struct Window {
+    started_ms: u64,
+    delivered: u32,
+    /// Dropped and not yet reported to the callback
+    unreported: u64,
+}
+
+static WINDOW: Mutex<Window> = Mutex::new(Window { started_ms: 0, delivered: 0, unreported: 0 });
+
+pub(crate) fn set_limit(per_sec: u32) {
+    LIMIT.store(per_sec, Ordering::Relaxed);
+}
+
+pub(crate) fn dropped_lines() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+fn is_error(line: &str) -> bool {
+    line.starts_with("ERROR") || line.starts_with("WARN ")
+}
+
+/// What to deliver for one line
+pub(crate) struct Admission {
+    /// Whether the line goes to the callback
+    pub deliver: bool,
+    /// Lines dropped earlier, to report before it (0: none)
+    pub dropped_before: u64,
+}
+
+/// Decide whether `line` reaches the callback
+pub(crate) fn admit(line: &str) -> Admission {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let admission = WINDOW.lock().unwrap().admit(line, limit, monotonic_ms());
+    if !admission.deliver {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    admission
+}
+
+impl Window {
+    /// Decide whether `line`, logged at `now_ms`, fits under `limit`
+    fn admit(&mut self, line: &str, limit: u32, now_ms: u64) -> Admission {
+        if limit == 0 {
+            return Admission { deliver: true, dropped_before: std::mem::take(&mut self.unreported) };
+        }
+        if now_ms.saturating_sub(self.started_ms) >= WINDOW_MS {
+            self.started_ms = now_ms;
+            self.delivered = 0;
+        }
+        if !is_error(line) {
+            if self.delivered >= limit {
+                self.unreported += 1;
+                return Admission { deliver: false, dropped_before: 0 };
+            }
+            self.delivered += 1;
+        }
+        Admission { deliver: true, dropped_before: std::mem::take(&mut self.unreported) }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&WINDOW);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_over_the_cap_are_dropped_until_the_next_window() {
+        let mut window = Window { started_ms: 0, delivered: 0, unreported: 0 };
+        // (line, logged at, delivered, dropped lines reported before it)
+        let steps = [
+            ("one", 1000, true, 0),
+            ("two", 1100, true, 0),
+            ("three", 1200, false, 0),
+            ("ERROR: four", 1300, true, 1),
+            ("five", 1400, false, 0),
+            ("WARN tor_guardmgr: six", 1500, true, 1),
+            ("seven", 1999, false, 0),
+            ("eight", 2000, true, 1),
+            ("nine", 2999, true, 0),
+            ("ten", 2999, false, 0),
+            // A quiet stretch opens a new window whenever the next line comes
+            ("eleven", 9000, true, 1),
+        ];
+        for (i, (line, at, deliver, dropped_before)) in steps.into_iter().enumerate() {
+            let admission = window.admit(line, 2, at);
+            assert_eq!((admission.deliver, admission.dropped_before), (deliver, dropped_before), "step {}", i);
+        }
+    }
+
+    #[test]
+    fn errors_never_use_up_the_cap() {
+        let mut window = Window { started_ms: 0, delivered: 0, unreported: 0 };
+        for i in 0..100 {
+            assert!(window.admit(&format!("ERROR: flood {}", i), 1, 500).deliver);
+        }
+        assert!(window.admit("info", 1, 500).deliver);
+        assert!(!window.admit("info", 1, 500).deliver);
+        // "WARNING" isn't Arti's WARN level
+        assert!(!window.admit("WARNING: just a word", 1, 500).deliver);
+    }
+
+    #[test]
+    fn no_limit_delivers_everything_and_reports_earlier_drops() {
+        let mut window = Window { started_ms: 0, delivered: 0, unreported: 0 };
+        window.admit("a", 1, 100);
+        window.admit("b", 1, 100);
+        window.admit("c", 1, 100);
+        let first = window.admit("d", 0, 100);
+        assert_eq!((first.deliver, first.dropped_before), (true, 2));
+        for _ in 0..1000 {
+            let admission = window.admit("e", 0, 100);
+            assert_eq!((admission.deliver, admission.dropped_before), (true, 0));
+        }
+    }
+}
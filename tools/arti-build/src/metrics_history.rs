@@ -0,0 +1,351 @@
+//! Periodic metrics snapshots kept across restarts
+//!
+//! When a user reports "it was slow all afternoon", the in-memory metrics
+//! have often been reset by a restart since. So every snapshot interval
+//! (default 15 minutes, see `setMetricsSnapshotInterval`; 0 disables) the
+//! heartbeat writes a compact snapshot (lifecycle state, bootstrap progress,
+//! battery condition and the `getMetrics` counters) to a ring of files under
+//! `wrapper/metrics-history/`:
+//!
+//! - each snapshot is its own file, written atomically and named by a
+//!   sequence number, so the ring survives a crash mid-write;
+//! - at most `MAX_SNAPSHOTS` files and `MAX_BYTES` are kept, the oldest
+//!   deleted first;
+//! - a snapshot is skipped while the client is dormant (battery low) and
+//!   nothing was relayed and the state didn't change since the last one, so
+//!   an idle device doesn't fill the ring with copies.
+//!
+//! `getMetricsHistory` and `exportDiagnostics` read the ring back. Snapshot
+//! timestamps follow the retention level, and the minimal level keeps no
+//! ring at all (see retention.rs).
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+use crate::retention::{self, Sink};
+use crate::seams;
+
+/// Default minutes between snapshots
+pub(crate) const DEFAULT_INTERVAL_MINS: u64 = 15;
+
+/// Longest interval of `setMetricsSnapshotInterval`
+pub(crate) const MAX_INTERVAL_MINS: u64 = 24 * 60;
+
+/// Snapshots kept at most
+const MAX_SNAPSHOTS: usize = 24;
+
+/// Bytes the ring may take up at most
+const MAX_BYTES: u64 = 256 * 1024;
+
+static INTERVAL_MINS: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL_MINS);
+
+struct Ring {
+    dir: PathBuf,
+    next_seq: u64,
+    /// When the interval last came round (whether or not it wrote)
+    last_at: Instant,
+    /// Relayed bytes and lifecycle state at the last snapshot
+    last_bytes: u64,
+    last_state: i32,
+}
+
+static RING: Mutex<Option<Ring>> = Mutex::new(None);
+
+/// What the skip-when-idle check compares between snapshots
+#[derive(Clone, Copy)]
+struct Activity {
+    bytes_relayed: u64,
+    state: i32,
+    dormant: bool,
+}
+
+impl Activity {
+    fn current() -> Self {
+        Activity {
+            bytes_relayed: crate::METRICS.bytes_relayed.load(Ordering::Relaxed),
+            state: crate::LIFECYCLE_STATE.load(Ordering::SeqCst),
+            dormant: crate::battery::low(),
+        }
+    }
+}
+
+pub(crate) fn set_interval_mins(mins: u64) {
+    INTERVAL_MINS.store(mins, Ordering::Relaxed);
+}
+
+/// The ring's files as `(seq, path, bytes)`, oldest first
+fn files(dir: &Path) -> Vec<(u64, PathBuf, u64)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<(u64, PathBuf, u64)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let seq = entry.file_name().to_str()?.strip_suffix(".json")?.parse().ok()?;
+            Some((seq, entry.path(), entry.metadata().ok()?.len()))
+        })
+        .collect();
+    files.sort_by_key(|(seq, _, _)| *seq);
+    files
+}
+
+/// Use `data_dir` for the ring; the first snapshot follows one interval later
+pub(crate) fn open(data_dir: &Path) {
+    let dir = layout::path(data_dir, Purpose::MetricsHistory);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        log_error!("Failed to create the metrics history directory: {}", e);
+    }
+    let next_seq = files(&dir).last().map_or(0, |(seq, _, _)| seq + 1);
+    let activity = Activity::current();
+    *RING.lock().unwrap() = Some(Ring {
+        dir,
+        next_seq,
+        last_at: seams::now_monotonic(),
+        last_bytes: activity.bytes_relayed,
+        last_state: activity.state,
+    });
+}
+
+fn snapshot(state: i32) -> Value {
+    let client = crate::ARTI_CLIENT.lock().unwrap().as_ref().map(Arc::clone);
+    let status = client.map(|client| client.bootstrap_status());
+    let metrics: Value = serde_json::from_str(&crate::METRICS.to_json()).unwrap_or(Value::Null);
+    json!({
+        "at_epoch_ms": retention::timestamp(now_epoch_ms()),
+        "state": crate::LifecycleState::from_i32(state).name(),
+        "bootstrap_percent": status.as_ref().map_or(0, |s| (s.as_frac() * 100.0) as u8),
+        "ready_for_traffic": status.as_ref().is_some_and(|s| s.ready_for_traffic()),
+        "battery_low": crate::battery::low(),
+        "metrics": metrics,
+    })
+}
+
+/// Delete the oldest snapshots until the ring is within its bounds
+fn rotate(dir: &Path) {
+    let mut files = files(dir);
+    let mut total: u64 = files.iter().map(|(_, _, bytes)| bytes).sum();
+    while files.len() > 1 && (files.len() > MAX_SNAPSHOTS || total > MAX_BYTES) {
+        let (_, path, bytes) = files.remove(0);
+        if let Err(e) = std::fs::remove_file(&path) {
+            log_error!("Failed to delete an old metrics snapshot: {}", e);
+            return;
+        }
+        total -= bytes;
+    }
+}
+
+/// Write a snapshot if the interval has come round (called from the heartbeat)
+pub(crate) fn tick() {
+    tick_with(Activity::current());
+}
+
+fn tick_with(activity: Activity) {
+    let mins = INTERVAL_MINS.load(Ordering::Relaxed);
+    if mins == 0 || !retention::retains(Sink::MetricsHistory) {
+        return;
+    }
+    let mut ring = RING.lock().unwrap();
+    let Some(ring) = ring.as_mut() else {
+        return;
+    };
+    let now = seams::now_monotonic();
+    if now.saturating_duration_since(ring.last_at) < Duration::from_secs(mins * 60) {
+        return;
+    }
+    ring.last_at = now;
+
+    let Activity { bytes_relayed: bytes, state, dormant } = activity;
+    if dormant && bytes == ring.last_bytes && state == ring.last_state {
+        return;
+    }
+
+    let path = layout::entry(&ring.dir, &format!("{:010}.json", ring.next_seq));
+    match layout::write_atomic(&path, snapshot(state).to_string().as_bytes()) {
+        Ok(()) => {
+            ring.next_seq += 1;
+            ring.last_bytes = bytes;
+            ring.last_state = state;
+            rotate(&ring.dir);
+        }
+        Err(e) => log_error!("Failed to write a metrics snapshot: {}", e),
+    }
+}
+
+/// The newest `max` snapshots as a JSON array, oldest first
+pub(crate) fn history_json(max: usize) -> String {
+    let Some(dir) = RING.lock().unwrap().as_ref().map(|ring| ring.dir.clone()) else {
+        return "[]".to_string();
+    };
+    let files = files(&dir);
+    let snapshots: Vec<Value> = files[files.len().saturating_sub(max)..]
+        .iter()
+        .filter_map(|(_, path, _)| serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok())
+        .collect();
+    Value::Array(snapshots).to_string()
+}
+
+/// Delete every snapshot (retention.rs)
+pub(crate) fn purge() {
+    let Some(dir) = RING.lock().unwrap().as_ref().map(|ring| ring.dir.clone()) else {
+        return;
+    };
+    for (_, path, _) in files(&dir) {
+        if let Err(e) = std::fs::remove_file(&path) {
+            log_error!("Failed to delete a metrics snapshot: {}", e);
+        }
+    }
+}
+
+/// Stop using the open data directory (tests open temporary ones)
+#[cfg(test)]
+pub(crate) fn close() {
+    *RING.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&RING);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+
+    const INTERVAL: Duration = Duration::from_secs(DEFAULT_INTERVAL_MINS * 60);
+
+    fn active(bytes_relayed: u64) -> Activity {
+        Activity { bytes_relayed, state: 3, dormant: false }
+    }
+
+    fn seqs(dir: &Path) -> Vec<u64> {
+        files(dir).into_iter().map(|(seq, _, _)| seq).collect()
+    }
+
+    /// A ring in a temporary data directory, closed on drop
+    struct Opened {
+        temp: TempDir,
+    }
+
+    impl Opened {
+        fn new() -> Opened {
+            let temp = TempDir::new();
+            open(temp.path());
+            set_interval_mins(DEFAULT_INTERVAL_MINS);
+            Opened { temp }
+        }
+
+        fn dir(&self) -> PathBuf {
+            layout::path(self.temp.path(), Purpose::MetricsHistory)
+        }
+    }
+
+    impl Drop for Opened {
+        fn drop(&mut self) {
+            close();
+            set_interval_mins(DEFAULT_INTERVAL_MINS);
+        }
+    }
+
+    #[test]
+    fn snapshots_follow_the_interval() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let ring = Opened::new();
+
+        tick_with(active(1));
+        assert!(seqs(&ring.dir()).is_empty());
+        time.advance(INTERVAL - Duration::from_secs(1));
+        tick_with(active(1));
+        assert!(seqs(&ring.dir()).is_empty());
+        time.advance(Duration::from_secs(1));
+        tick_with(active(1));
+        assert_eq!(seqs(&ring.dir()), [0]);
+        // The next interval counts from this one
+        time.advance(INTERVAL / 2);
+        tick_with(active(2));
+        assert_eq!(seqs(&ring.dir()), [0]);
+
+        // 0 disables
+        set_interval_mins(0);
+        time.advance(INTERVAL * 4);
+        tick_with(active(3));
+        assert_eq!(seqs(&ring.dir()), [0]);
+    }
+
+    #[test]
+    fn idle_dormant_intervals_are_skipped() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let ring = Opened::new();
+        time.advance(INTERVAL);
+        tick_with(active(100));
+
+        let base = active(100);
+        for (name, activity, written) in [
+            ("awake and idle", base, true),
+            ("dormant and idle", Activity { dormant: true, ..base }, false),
+            ("dormant, relaying", Activity { dormant: true, bytes_relayed: 200, ..base }, true),
+            ("dormant, idle since", Activity { dormant: true, bytes_relayed: 200, ..base }, false),
+            ("dormant, stopped", Activity { dormant: true, bytes_relayed: 200, state: 0 }, true),
+        ] {
+            let before = seqs(&ring.dir()).len();
+            time.advance(INTERVAL);
+            tick_with(activity);
+            assert_eq!(seqs(&ring.dir()).len() - before, written as usize, "{}", name);
+        }
+    }
+
+    #[test]
+    fn the_ring_keeps_the_newest_within_its_bounds() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let ring = Opened::new();
+        for bytes in 0..MAX_SNAPSHOTS as u64 + 6 {
+            time.advance(INTERVAL);
+            tick_with(active(bytes));
+        }
+        assert_eq!(seqs(&ring.dir()), (6..MAX_SNAPSHOTS as u64 + 6).collect::<Vec<_>>());
+        // Written atomically: nothing but snapshots in the directory
+        assert_eq!(std::fs::read_dir(ring.dir()).unwrap().count(), MAX_SNAPSHOTS);
+
+        let history: Vec<Value> = serde_json::from_str(&history_json(3)).unwrap();
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|snapshot| snapshot["metrics"].is_object() && snapshot["state"].is_string()));
+
+        // Over the byte cap, the oldest go first and the newest always stays
+        let big = vec![b' '; MAX_BYTES as usize / 2];
+        for seq in 100..103 {
+            std::fs::write(ring.dir().join(format!("{:010}.json", seq)), &big).unwrap();
+        }
+        rotate(&ring.dir());
+        assert_eq!(seqs(&ring.dir()), [101, 102]);
+        std::fs::write(ring.dir().join(format!("{:010}.json", 103)), vec![b' '; MAX_BYTES as usize * 2]).unwrap();
+        rotate(&ring.dir());
+        assert_eq!(seqs(&ring.dir()), [103]);
+    }
+
+    #[test]
+    fn reopening_continues_the_sequence() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let ring = Opened::new();
+        for bytes in 0..3 {
+            time.advance(INTERVAL);
+            tick_with(active(bytes));
+        }
+        open(ring.temp.path());
+        time.advance(INTERVAL);
+        tick_with(active(10));
+        assert_eq!(seqs(&ring.dir()), [0, 1, 2, 3]);
+
+        purge();
+        assert_eq!(history_json(usize::MAX), "[]");
+    }
+}
@@ -0,0 +1,201 @@
+//! Direct (non-Tor) socket creation
+//!
+//! Every socket the wrapper opens itself, as opposed to streams Arti opens on
+//! our behalf, is created through this module. That gives strict Tor-only mode
+//! a single choke point: outbound direct connections are refused and counted
+//! here, so a feature can't forget the check.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::METRICS;
+
+/// Strict Tor-only mode flag (forced on by the `strict-tor-only` feature)
+static STRICT_TOR_ONLY: AtomicBool = AtomicBool::new(cfg!(feature = "strict-tor-only"));
+
+/// A direct network path was refused because strict Tor-only mode is on
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StrictModeBlocked {
+    pub purpose: &'static str,
+}
+
+impl fmt::Display for StrictModeBlocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "strict Tor-only mode blocks direct connection for {}", self.purpose)
+    }
+}
+
+impl std::error::Error for StrictModeBlocked {}
+
+/// Whether strict Tor-only mode is active
+pub(crate) fn strict_tor_only() -> bool {
+    STRICT_TOR_ONLY.load(Ordering::Relaxed)
+}
+
+/// Enable or disable strict Tor-only mode
+///
+/// Returns false if the build forces strict mode and `enabled` is false.
+pub(crate) fn set_strict_tor_only(enabled: bool) -> bool {
+    if cfg!(feature = "strict-tor-only") && !enabled {
+        return false;
+    }
+    STRICT_TOR_ONLY.store(enabled, Ordering::Relaxed);
+    true
+}
+
+/// Check up front whether a feature with a direct network path may run
+///
+/// Features call this before doing any work so they can report
+/// `StrictModeBlocked` without ever reaching the socket layer.
+pub(crate) fn ensure_direct_allowed(purpose: &'static str) -> Result<(), StrictModeBlocked> {
+    if strict_tor_only() {
+        return Err(StrictModeBlocked { purpose });
+    }
+    Ok(())
+}
+
+/// Audit a direct outbound socket about to be created
+///
+/// Reaching this in strict mode means a feature skipped its own
+/// `ensure_direct_allowed` check, so it is logged and counted as a violation.
+fn audit_outbound(purpose: &'static str, addr: &SocketAddr) -> Result<(), StrictModeBlocked> {
+    if strict_tor_only() {
+        METRICS.strict_mode_violations.fetch_add(1, Ordering::Relaxed);
+        log_error!("Strict mode violation: direct connection to {} for {} refused", addr, purpose);
+        return Err(StrictModeBlocked { purpose });
+    }
+    Ok(())
+}
+
+/// Open a direct outbound TCP connection
+pub(crate) async fn connect_tcp(addr: SocketAddr, purpose: &'static str) -> anyhow::Result<TcpStream> {
+    audit_outbound(purpose, &addr)?;
+    Ok(TcpStream::connect(addr).await?)
+}
+
+/// Bind a listener for local clients (always allowed, even in strict mode)
+pub(crate) async fn bind_listener(addr: &str) -> std::io::Result<TcpListener> {
+    TcpListener::bind(addr).await
+}
+
+/// Connect to one of our own loopback listeners (always allowed, even in
+/// strict mode: nothing leaves the device)
+pub(crate) async fn connect_loopback(port: u16) -> std::io::Result<TcpStream> {
+    TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, port)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing;
+
+    /// Files allowed to create sockets themselves
+    const SOCKET_MODULES: &[&str] = &["net.rs", "testing.rs"];
+
+    /// Direct socket constructors, spelled so this file doesn't match itself
+    const CONSTRUCTORS: &[&str] = &[
+        concat!("TcpStream", "::connect"),
+        concat!("TcpSocket", "::new"),
+        concat!("UdpSocket", "::bind"),
+        concat!("std::net::", "TcpStream"),
+        concat!("std::net::", "UdpSocket"),
+    ];
+
+    #[test]
+    fn sockets_are_only_created_here() {
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut offenders = Vec::new();
+        for entry in std::fs::read_dir(&src).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if !name.ends_with(".rs") || SOCKET_MODULES.contains(&name.as_str()) {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            for (number, line) in text.lines().enumerate() {
+                if CONSTRUCTORS.iter().any(|c| line.contains(c)) {
+                    offenders.push(format!("{}:{}: {}", name, number + 1, line.trim()));
+                }
+            }
+        }
+        assert!(offenders.is_empty(), "direct sockets outside net.rs:\n{}", offenders.join("\n"));
+    }
+
+    #[test]
+    fn strict_mode_blocks_a_connect_that_skipped_the_check() {
+        let _serial = testing::serial();
+        testing::runtime().block_on(async {
+            let listener = bind_listener("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let violations = METRICS.strict_mode_violations.load(Ordering::Relaxed);
+
+            assert!(set_strict_tor_only(true));
+            // A feature that never called ensure_direct_allowed
+            let result = connect_tcp(addr, "test_feature").await;
+            if !cfg!(feature = "strict-tor-only") {
+                set_strict_tor_only(false);
+            }
+
+            let blocked = result.err().unwrap();
+            assert_eq!(blocked.downcast_ref::<StrictModeBlocked>().unwrap().purpose, "test_feature");
+            assert_eq!(METRICS.strict_mode_violations.load(Ordering::Relaxed), violations + 1);
+            // Refused before any socket existed
+            assert!(tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn strict_mode_reports_blocked_features_up_front() {
+        let _serial = testing::serial();
+        let violations = METRICS.strict_mode_violations.load(Ordering::Relaxed);
+        set_strict_tor_only(true);
+        let checked = ensure_direct_allowed("test_feature");
+        if !cfg!(feature = "strict-tor-only") {
+            set_strict_tor_only(false);
+        }
+        assert_eq!(checked.err().unwrap().purpose, "test_feature");
+        // Checking is not a violation; only reaching the socket layer is
+        assert_eq!(METRICS.strict_mode_violations.load(Ordering::Relaxed), violations);
+    }
+
+    #[test]
+    fn strict_mode_keeps_loopback_open() {
+        let _serial = testing::serial();
+        set_strict_tor_only(true);
+        testing::runtime().block_on(async {
+            let listener = bind_listener("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            connect_loopback(port).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+        if !cfg!(feature = "strict-tor-only") {
+            set_strict_tor_only(false);
+        }
+    }
+
+    #[cfg(not(feature = "strict-tor-only"))]
+    #[test]
+    fn direct_connects_pass_outside_strict_mode() {
+        let _serial = testing::serial();
+        testing::runtime().block_on(async {
+            let listener = bind_listener("127.0.0.1:0").await.unwrap();
+            connect_tcp(listener.local_addr().unwrap(), "test_feature").await.unwrap();
+            listener.accept().await.unwrap();
+        });
+        assert!(ensure_direct_allowed("test_feature").is_ok());
+    }
+
+    #[cfg(feature = "strict-tor-only")]
+    #[test]
+    fn strict_build_cannot_turn_strict_mode_off() {
+        let _serial = testing::serial();
+        assert!(!set_strict_tor_only(false));
+        assert!(strict_tor_only());
+    }
+}
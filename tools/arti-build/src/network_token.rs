@@ -0,0 +1,220 @@
+//! Per-network isolation token and its staleness
+//!
+//! Kotlin's NetworkCallback passes a token identifying the current network,
+//! which becomes a component of every stream's isolation key so circuits are
+//! never shared across networks. If that callback wedges, the wrapper would
+//! keep using a token from a network the device left long ago. So the token's
+//! set time is tracked (refreshing with the same value resets it), and once
+//! it is older than the configured maximum age the token is treated as stale:
+//!
+//! - isolation additionally includes the stale era, `age / max_age`, which
+//!   advances every `max_age`, so circuits stop being shared across eras;
+//! - a one-shot `NETWORK_TOKEN_STALE` warning goes to the log sinks. It fires
+//!   again only after a refresh has made the token fresh and it goes stale anew.
+//!
+//! The era depends only on the token age and the maximum age (`stale_era`),
+//! so the same inputs always derive the same isolation.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::liveness::monotonic_ms;
+
+/// Largest configurable maximum age
+pub(crate) const MAX_MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Maximum token age in ms (0 = tokens never go stale)
+static MAX_AGE_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Current token and the monotonic ms it was last set or refreshed
+static TOKEN: Mutex<Option<(String, u64)>> = Mutex::new(None);
+
+/// Whether the warning for the current stale period has been emitted
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Stale era for a token of `age_ms` (None while fresh or when disabled)
+pub(crate) fn stale_era(age_ms: u64, max_age_ms: u64) -> Option<u64> {
+    if max_age_ms == 0 || age_ms < max_age_ms {
+        return None;
+    }
+    Some(age_ms / max_age_ms)
+}
+
+/// Set or refresh the network token (empty clears it)
+pub(crate) fn set(token: &str) {
+    let mut current = TOKEN.lock().unwrap();
+    *current = (!token.is_empty()).then(|| (token.to_string(), monotonic_ms()));
+    WARNED.store(false, Ordering::Relaxed);
+}
+
+/// Set the maximum age in seconds (0 disables staleness)
+pub(crate) fn set_max_age_secs(secs: u64) {
+    MAX_AGE_MS.store(secs * 1000, Ordering::Relaxed);
+}
+
+/// Age of the current token in ms, if one is set
+fn age_ms() -> Option<u64> {
+    TOKEN.lock().unwrap().as_ref().map(|(_, set_at)| monotonic_ms().saturating_sub(*set_at))
+}
+
+/// Isolation component: the token plus its stale era, if any
+pub(crate) fn isolation() -> Option<(String, Option<u64>)> {
+    let token = TOKEN.lock().unwrap();
+    let (value, set_at) = token.as_ref()?;
+    let age = monotonic_ms().saturating_sub(*set_at);
+    Some((value.clone(), stale_era(age, MAX_AGE_MS.load(Ordering::Relaxed))))
+}
+
+/// Emit the stale warning once per stale period (called from the heartbeat)
+pub(crate) fn check() {
+    let Some(age) = age_ms() else {
+        return;
+    };
+    if stale_era(age, MAX_AGE_MS.load(Ordering::Relaxed)).is_some() && !WARNED.swap(true, Ordering::Relaxed) {
+        log_error!(
+            "NETWORK_TOKEN_STALE: network token not refreshed for {}s; isolating by time until it is",
+            age / 1000
+        );
+    }
+}
+
+/// Metrics fields: token age in seconds (null if unset) and staleness
+pub(crate) fn metrics_json() -> String {
+    match age_ms() {
+        Some(age) => format!(
+            "\"network_token_age_secs\":{},\"network_token_stale\":{}",
+            age / 1000,
+            stale_era(age, MAX_AGE_MS.load(Ordering::Relaxed)).is_some(),
+        ),
+        None => "\"network_token_age_secs\":null,\"network_token_stale\":false".to_string(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&TOKEN);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    const MAX_AGE: Duration = Duration::from_secs(600);
+
+    fn warnings() -> usize {
+        crate::tests::SENT_LOG_LINES.lock().unwrap().iter().filter(|line| line.contains("NETWORK_TOKEN_STALE")).count()
+    }
+
+    /// Put back the token, the maximum age and the warning flag on drop
+    struct Restore(Option<(String, u64)>, u64, bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            *TOKEN.lock().unwrap() = self.0.take();
+            MAX_AGE_MS.store(self.1, Ordering::Relaxed);
+            WARNED.store(self.2, Ordering::Relaxed);
+        }
+    }
+
+    fn save() -> Restore {
+        Restore(TOKEN.lock().unwrap().take(), MAX_AGE_MS.load(Ordering::Relaxed), WARNED.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn stale_era_golden_vectors() {
+        for (age_ms, max_age_ms, era) in [
+            (0, 0, None),
+            (u64::MAX, 0, None),
+            (0, 600_000, None),
+            (599_999, 600_000, None),
+            (600_000, 600_000, Some(1)),
+            (1_199_999, 600_000, Some(1)),
+            (1_200_000, 600_000, Some(2)),
+            (86_400_000, 600_000, Some(144)),
+            (1, 1, Some(1)),
+            (u64::MAX, 1000, Some(u64::MAX / 1000)),
+        ] {
+            assert_eq!(stale_era(age_ms, max_age_ms), era, "{} of {}", age_ms, max_age_ms);
+        }
+    }
+
+    #[test]
+    fn isolation_advances_by_era_until_refreshed() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _restore = save();
+        set_max_age_secs(MAX_AGE.as_secs());
+
+        assert_eq!(isolation(), None);
+        assert!(metrics_json().contains("\"network_token_age_secs\":null"));
+        set("wifi-1");
+        let token = |era| Some(("wifi-1".to_string(), era));
+        assert_eq!(isolation(), token(None));
+        for (advance, era) in [
+            (MAX_AGE - Duration::from_millis(1), None),
+            (Duration::from_millis(1), Some(1)),
+            (MAX_AGE, Some(2)),
+            (MAX_AGE / 2, Some(2)),
+        ] {
+            time.advance(advance);
+            assert_eq!(isolation(), token(era), "after {:?}", advance);
+        }
+        assert!(metrics_json().contains("\"network_token_age_secs\":1500,\"network_token_stale\":true"));
+
+        // The same value again counts as a refresh
+        set("wifi-1");
+        assert_eq!(isolation(), token(None));
+        assert!(metrics_json().contains("\"network_token_age_secs\":0,\"network_token_stale\":false"));
+
+        // Without a maximum age the token never goes stale
+        set_max_age_secs(0);
+        time.advance(MAX_AGE * 100);
+        assert_eq!(isolation(), token(None));
+        set("");
+        assert_eq!(isolation(), None);
+    }
+
+    #[test]
+    fn stale_warning_fires_once_per_stale_period() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _restore = save();
+        set_max_age_secs(MAX_AGE.as_secs());
+        let before = warnings();
+
+        // Heartbeats while fresh, then through several stale eras
+        set("cell-1");
+        check();
+        time.advance(MAX_AGE);
+        for _ in 0..3 {
+            check();
+            time.advance(MAX_AGE);
+        }
+        assert_eq!(warnings() - before, 1);
+
+        // Fresh again, then stale anew: one more
+        set("cell-1");
+        check();
+        assert_eq!(warnings() - before, 1);
+        time.advance(MAX_AGE);
+        check();
+        check();
+        assert_eq!(warnings() - before, 2);
+
+        // A new network's token starts a fresh period as well
+        set("wifi-2");
+        time.advance(MAX_AGE * 3);
+        check();
+        check();
+        assert_eq!(warnings() - before, 3);
+
+        // No token, nothing to warn about
+        set("");
+        time.advance(MAX_AGE);
+        check();
+        assert_eq!(warnings() - before, 3);
+    }
+}
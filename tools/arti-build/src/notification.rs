@@ -0,0 +1,405 @@
+//! Compact status for the Kotlin foreground-service notification
+//!
+//! Everything the notification shows is rendered from one `StatusInputs`
+//! snapshot taken under a single lock, so the text can never mix a lifecycle
+//! state from one instant with bootstrap progress or connection counts from
+//! another. Changes are pushed to Java at most once per `MIN_NOTIFY_INTERVAL`;
+//! a change that lands inside the window is delivered when the window ends.
+//!
+//! While Arti bootstraps, `BootstrapProgress` mirrors its progress events
+//! into `bootstrap_percent`, capped at 99: 100 means `initialize` finished,
+//! prewarm included, which Arti's own progress can't tell.
+
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use futures::{Stream, StreamExt};
+
+use crate::seams;
+
+use crate::retention::{self, Sink};
+use crate::{prewarm, LifecycleState, METRICS};
+
+/// Minimum spacing between `onNotificationStatusChanged` calls
+const MIN_NOTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Highest percent bootstrap progress may show (see the module docs)
+const MAX_PROGRESS_PERCENT: u8 = 99;
+
+/// Every input the notification depends on, updated together
+pub(crate) struct StatusInputs {
+    pub state: LifecycleState,
+    /// Wall-clock time the current state was entered
+    pub since_epoch_ms: u64,
+    pub bootstrap_percent: u8,
+    pub active_connections: u32,
+    pub prewarm: prewarm::Status,
+    /// Consensus present but no guard reachable (see guards.rs)
+    pub no_usable_guards: bool,
+    /// UTC day number that `day_start_bytes` belongs to
+    day: u64,
+    /// Relayed byte counter value at the start of `day`
+    day_start_bytes: u64,
+}
+
+static INPUTS: Mutex<StatusInputs> = Mutex::new(StatusInputs {
+    state: LifecycleState::Stopped,
+    since_epoch_ms: 0,
+    bootstrap_percent: 0,
+    active_connections: 0,
+    prewarm: prewarm::Status::Skipped,
+    no_usable_guards: false,
+    day: 0,
+    day_start_bytes: 0,
+});
+
+/// Delivery bookkeeping for the change notification
+struct Throttle {
+    last_sent_at: Option<Instant>,
+    last_sent: Option<String>,
+    trailing_scheduled: bool,
+}
+
+static THROTTLE: Mutex<Throttle> = Mutex::new(Throttle {
+    last_sent_at: None,
+    last_sent: None,
+    trailing_scheduled: false,
+});
+
+pub(crate) fn now_epoch_ms() -> u64 {
+    seams::now_wall()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Apply a change to the status inputs, then notify Java if the rendered status changed
+pub(crate) fn update(f: impl FnOnce(&mut StatusInputs)) {
+    f(&mut INPUTS.lock().unwrap());
+    status_changed();
+}
+
+/// Percent shown for a bootstrap progress fraction
+fn progress_percent(fraction: f32) -> u8 {
+    ((fraction.clamp(0.0, 1.0) * 100.0) as u8).min(MAX_PROGRESS_PERCENT)
+}
+
+/// Mirrors bootstrap progress into the status until dropped
+pub(crate) struct BootstrapProgress(tokio::task::JoinHandle<()>);
+
+impl BootstrapProgress {
+    /// Follow `fractions`, Arti's bootstrap events as `BootstrapStatus::as_frac`
+    pub(crate) fn follow(fractions: impl Stream<Item = f32> + Send + 'static) -> BootstrapProgress {
+        BootstrapProgress(tokio::spawn(async move {
+            futures::pin_mut!(fractions);
+            while let Some(fraction) = fractions.next().await {
+                let percent = progress_percent(fraction);
+                update(|inputs| inputs.bootstrap_percent = percent);
+            }
+        }))
+    }
+}
+
+impl Drop for BootstrapProgress {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// SOCKS connections being handled now
+#[cfg(feature = "metrics-endpoint")]
+pub(crate) fn active_connections() -> u32 {
+    INPUTS.lock().unwrap().active_connections
+}
+
+/// Render the current status as JSON from a single consistent snapshot
+pub(crate) fn render() -> String {
+    let mut inputs = INPUTS.lock().unwrap();
+
+    // Read the byte counter while holding the inputs lock so it belongs to
+    // the same instant as the rest of the snapshot
+    let now_ms = now_epoch_ms();
+    let total_bytes = METRICS.bytes_relayed.load(Ordering::Relaxed);
+    let today = now_ms / MS_PER_DAY;
+    // Metrics may have been reset (see retention.rs)
+    if inputs.day != today || total_bytes < inputs.day_start_bytes {
+        inputs.day = today;
+        inputs.day_start_bytes = total_bytes;
+    }
+    let bytes_today = total_bytes - inputs.day_start_bytes;
+
+    let (state, detail_key, args) = match inputs.state {
+        LifecycleState::Stopped => ("stopped", "stopped", Vec::new()),
+        LifecycleState::Initialized if inputs.bootstrap_percent < 100 && inputs.no_usable_guards => {
+            ("bootstrapping", "no_usable_guards", Vec::new())
+        }
+        LifecycleState::Initialized if inputs.bootstrap_percent < 100 => {
+            ("bootstrapping", "bootstrap_percent", vec![inputs.bootstrap_percent as u64])
+        }
+        LifecycleState::Initialized => ("ready", "ready", Vec::new()),
+        LifecycleState::Starting => ("starting", "starting", Vec::new()),
+        LifecycleState::Running if inputs.active_connections > 0 => {
+            ("running", "active_connections", vec![inputs.active_connections as u64])
+        }
+        LifecycleState::Running => ("running", "idle", Vec::new()),
+        LifecycleState::Stopping => ("stopping", "stopping", Vec::new()),
+        LifecycleState::StorageLost => ("storage_lost", "storage_lost", Vec::new()),
+        LifecycleState::DegradedStaleDirectory => ("degraded", "stale_directory", Vec::new()),
+    };
+
+    if !retention::retains(Sink::StatusDetail) {
+        return format!("{{\"state\":\"{}\"}}", state);
+    }
+    let args = args.iter().map(u64::to_string).collect::<Vec<_>>().join(",");
+    format!(
+        "{{\"state\":\"{}\",\"detail_key\":\"{}\",\"args\":[{}],\"bytes_today\":{},\"since_epoch_ms\":{},\"prewarm\":\"{}\"}}",
+        state, detail_key, args, bytes_today, inputs.since_epoch_ms, inputs.prewarm.as_str(),
+    )
+}
+
+/// Deliver the rendered status to Java if it changed, respecting the throttle
+fn status_changed() {
+    let rendered = render();
+
+    let mut throttle = THROTTLE.lock().unwrap();
+    if throttle.last_sent.as_deref() == Some(rendered.as_str()) {
+        return;
+    }
+
+    if let Some(last) = throttle.last_sent_at {
+        let elapsed = seams::now_monotonic().saturating_duration_since(last);
+        if elapsed < MIN_NOTIFY_INTERVAL {
+            // Deliver whatever is current once the window closes, so the
+            // last change inside the window is never lost
+            if !throttle.trailing_scheduled {
+                throttle.trailing_scheduled = true;
+                let wait = MIN_NOTIFY_INTERVAL - elapsed;
+                std::thread::spawn(move || {
+                    seams::sleep_blocking(wait);
+                    THROTTLE.lock().unwrap().trailing_scheduled = false;
+                    status_changed();
+                });
+            }
+            return;
+        }
+    }
+
+    throttle.last_sent_at = Some(seams::now_monotonic());
+    throttle.last_sent = Some(rendered.clone());
+    drop(throttle);
+
+    #[cfg(test)]
+    tests::SENT.lock().unwrap().push(rendered.clone());
+    crate::send_string_to_java(crate::callbacks::Kind::NotificationStatus, "onNotificationStatusChanged", &rendered);
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&INPUTS);
+    held.hold(&THROTTLE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    /// Statuses delivered to Java, oldest first
+    pub(super) static SENT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Start from a stopped status with nothing delivered yet
+    fn reset() {
+        *INPUTS.lock().unwrap() = StatusInputs {
+            state: LifecycleState::Stopped,
+            since_epoch_ms: 0,
+            bootstrap_percent: 0,
+            active_connections: 0,
+            prewarm: prewarm::Status::Skipped,
+            no_usable_guards: false,
+            day: 0,
+            day_start_bytes: 0,
+        };
+        *THROTTLE.lock().unwrap() = Throttle { last_sent_at: None, last_sent: None, trailing_scheduled: false };
+        SENT.lock().unwrap().clear();
+    }
+
+    fn detail(rendered: &str) -> (String, String, Vec<u64>) {
+        let value: serde_json::Value = serde_json::from_str(rendered).unwrap();
+        let args = value["args"].as_array().unwrap().iter().map(|arg| arg.as_u64().unwrap()).collect();
+        (value["state"].as_str().unwrap().to_string(), value["detail_key"].as_str().unwrap().to_string(), args)
+    }
+
+    fn sent_details() -> Vec<(String, String, Vec<u64>)> {
+        SENT.lock().unwrap().iter().map(|sent| detail(sent)).collect()
+    }
+
+    /// Wait (in real time) until `count` statuses have been delivered
+    fn wait_for_sent(count: usize) {
+        for _ in 0..10_000 {
+            if SENT.lock().unwrap().len() >= count {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let sent = SENT.lock().unwrap().clone();
+        panic!("expected {} deliveries, got {:?}", count, sent);
+    }
+
+    #[test]
+    fn progress_is_capped_below_ready() {
+        for (fraction, percent) in [(-0.5, 0), (0.0, 0), (0.254, 25), (0.5, 50), (0.999, 99), (1.0, 99), (7.0, 99)] {
+            assert_eq!(progress_percent(fraction), percent, "fraction {}", fraction);
+        }
+    }
+
+    #[test]
+    fn renders_each_state_from_one_snapshot() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        use LifecycleState::*;
+        // state, percent, connections, no usable guards => shown, detail, args
+        type Case = (LifecycleState, u8, u32, bool, &'static str, &'static str, &'static [u64]);
+        let table: [Case; 10] = [
+            (Stopped, 40, 2, true, "stopped", "stopped", &[]),
+            (Initialized, 0, 0, false, "bootstrapping", "bootstrap_percent", &[0]),
+            (Initialized, 37, 4, false, "bootstrapping", "bootstrap_percent", &[37]),
+            (Initialized, 37, 0, true, "bootstrapping", "no_usable_guards", &[]),
+            (Initialized, 100, 0, true, "ready", "ready", &[]),
+            (Starting, 100, 0, false, "starting", "starting", &[]),
+            // Running never shows leftover bootstrap progress
+            (Running, 55, 0, false, "running", "idle", &[]),
+            (Running, 55, 3, false, "running", "active_connections", &[3]),
+            (Stopping, 100, 3, false, "stopping", "stopping", &[]),
+            (DegradedStaleDirectory, 100, 0, false, "degraded", "stale_directory", &[]),
+        ];
+        for (state, percent, connections, no_guards, shown, detail_key, args) in table {
+            reset();
+            let mut inputs = INPUTS.lock().unwrap();
+            inputs.state = state;
+            inputs.bootstrap_percent = percent;
+            inputs.active_connections = connections;
+            inputs.no_usable_guards = no_guards;
+            drop(inputs);
+            let expected = (shown.to_string(), detail_key.to_string(), args.to_vec());
+            assert_eq!(detail(&render()), expected, "{:?} at {}%", state, percent);
+        }
+    }
+
+    #[test]
+    fn first_change_is_sent_at_once_and_the_rest_coalesce() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        reset();
+
+        update(|inputs| inputs.state = LifecycleState::Initialized);
+        assert_eq!(sent_details(), [("bootstrapping".to_string(), "bootstrap_percent".to_string(), vec![0])]);
+
+        // Inside the window nothing goes out, and a status that renders the
+        // same as the last one sent is never resent
+        for percent in [10, 20, 30] {
+            time.advance(Duration::from_secs(1));
+            update(|inputs| inputs.bootstrap_percent = percent);
+        }
+        update(|inputs| inputs.bootstrap_percent = 30);
+        assert_eq!(SENT.lock().unwrap().len(), 1);
+
+        // When the window closes only the latest status is delivered
+        time.advance(Duration::from_secs(2));
+        wait_for_sent(2);
+        assert_eq!(sent_details()[1], ("bootstrapping".to_string(), "bootstrap_percent".to_string(), vec![30]));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(SENT.lock().unwrap().len(), 2);
+
+        // Once a full window has passed a change goes out at once again
+        time.advance(MIN_NOTIFY_INTERVAL);
+        update(|inputs| inputs.state = LifecycleState::Running);
+        assert_eq!(sent_details()[2], ("running".to_string(), "idle".to_string(), vec![]));
+        reset();
+    }
+
+    #[test]
+    fn unchanged_status_is_not_resent() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        reset();
+
+        update(|inputs| inputs.state = LifecycleState::Running);
+        time.advance(MIN_NOTIFY_INTERVAL * 2);
+        update(|inputs| inputs.state = LifecycleState::Running);
+        update(|inputs| inputs.bootstrap_percent = 80);
+        assert_eq!(SENT.lock().unwrap().len(), 1);
+        reset();
+    }
+
+    #[test]
+    fn snapshots_never_mix_updates() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        reset();
+
+        // One thread alternates between two scripted statuses, each applied
+        // as one update; every render must show one of them whole
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    update(|inputs| {
+                        inputs.state = LifecycleState::Initialized;
+                        inputs.bootstrap_percent = 45;
+                        inputs.active_connections = 0;
+                    });
+                    update(|inputs| {
+                        inputs.state = LifecycleState::Running;
+                        inputs.bootstrap_percent = 100;
+                        inputs.active_connections = 7;
+                    });
+                }
+            })
+        };
+        let bootstrapping = ("bootstrapping".to_string(), "bootstrap_percent".to_string(), vec![45]);
+        let running = ("running".to_string(), "active_connections".to_string(), vec![7]);
+        for _ in 0..2000 {
+            let shown = detail(&render());
+            assert!(shown == bootstrapping || shown == running || shown.0 == "stopped", "mixed status {:?}", shown);
+        }
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        reset();
+    }
+
+    #[test]
+    fn bootstrap_events_drive_the_percent() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        reset();
+        update(|inputs| inputs.state = LifecycleState::Initialized);
+
+        let runtime = testing::runtime();
+        let progress = runtime.block_on(async {
+            let progress = BootstrapProgress::follow(futures::stream::iter([0.1, 0.42]));
+            while INPUTS.lock().unwrap().bootstrap_percent != 42 {
+                tokio::task::yield_now().await;
+            }
+            progress
+        });
+        time.advance(MIN_NOTIFY_INTERVAL);
+        wait_for_sent(2);
+        assert_eq!(sent_details()[1].2, [42]);
+
+        // Arti reaching 100% isn't "ready": that waits for initialize
+        let done = runtime.block_on(async {
+            let done = BootstrapProgress::follow(futures::stream::iter([1.0]));
+            while INPUTS.lock().unwrap().bootstrap_percent != 99 {
+                tokio::task::yield_now().await;
+            }
+            done
+        });
+        assert_eq!(detail(&render()).0, "bootstrapping");
+        drop((progress, done));
+        reset();
+    }
+}
@@ -0,0 +1,261 @@
+//! Onion service client authorization (restricted discovery)
+//!
+//! Onion services that restrict discovery only publish descriptors readable
+//! by authorized clients. Keys configured here are written to a C Tor style
+//! client auth directory (one `<name>.auth_private` file
+//! per service) and handed to Arti as a C Tor client keystore, so connections
+//! to those onions can decrypt the descriptor.
+//!
+//! This is the Tor-level credential for reaching the service. Application
+//! level credentials (e.g. HTTP auth headers) need no support here: after the
+//! SOCKS success reply the tunnel forwards bytes verbatim in both directions.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use arti_client::config::TorClientConfigBuilder;
+
+use crate::layout;
+
+/// Onion service id (without `.onion`) -> base32 x25519 private key
+type KeyMap = HashMap<String, String>;
+
+/// Client auth keys in effect, and the set staged for the next `initialize`
+struct Keys {
+    applied: Option<KeyMap>,
+    /// A full copy of the keys once any change is staged (None = no change)
+    staged: Option<KeyMap>,
+}
+
+impl Keys {
+    fn staged_mut(&mut self) -> &mut KeyMap {
+        let applied = &self.applied;
+        self.staged.get_or_insert_with(|| applied.clone().unwrap_or_default())
+    }
+}
+
+static CLIENT_AUTH_KEYS: Mutex<Keys> = Mutex::new(Keys { applied: None, staged: None });
+
+/// Length of a v3 onion service id in base32 characters
+const ONION_ID_LEN: usize = 56;
+
+/// Length of a base32-encoded (unpadded) 32-byte x25519 key
+const KEY_B32_LEN: usize = 52;
+
+fn is_base32(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b))
+}
+
+/// Stage a client auth key for an onion service
+///
+/// Returns false if the address or key is malformed. Takes effect on the next
+/// `initialize`, unless the pending configuration is reset first.
+pub(crate) fn set(onion_addr: &str, private_key_b32: &str) -> bool {
+    let id = onion_addr.trim().to_ascii_lowercase();
+    let id = id.strip_suffix(".onion").unwrap_or(&id).to_string();
+    let key = private_key_b32.trim().trim_end_matches('=').to_ascii_lowercase();
+
+    if id.len() != ONION_ID_LEN || !is_base32(&id) || key.len() != KEY_B32_LEN || !is_base32(&key) {
+        return false;
+    }
+
+    CLIENT_AUTH_KEYS.lock().unwrap().staged_mut().insert(id, key);
+    true
+}
+
+fn sorted_ids(keys: Option<&KeyMap>) -> Vec<String> {
+    let mut ids: Vec<String> = keys.map(|keys| keys.keys().cloned().collect()).unwrap_or_default();
+    ids.sort();
+    ids
+}
+
+/// Onion service ids with a key for the next `initialize` (staged, else applied), sorted
+pub(crate) fn configured_ids() -> Vec<String> {
+    let keys = CLIENT_AUTH_KEYS.lock().unwrap();
+    sorted_ids(keys.staged.as_ref().or(keys.applied.as_ref()))
+}
+
+/// Onion service ids whose key is in effect, sorted
+pub(crate) fn applied_ids() -> Vec<String> {
+    sorted_ids(CLIENT_AUTH_KEYS.lock().unwrap().applied.as_ref())
+}
+
+/// Stage forgetting the client auth key for an onion service
+pub(crate) fn remove(onion_addr: &str) {
+    let id = onion_addr.trim().to_ascii_lowercase();
+    let id = id.strip_suffix(".onion").unwrap_or(&id);
+    CLIENT_AUTH_KEYS.lock().unwrap().staged_mut().remove(id);
+}
+
+/// Ids whose key a staged change adds, replaces or removes, sorted
+fn changed_ids(keys: &Keys) -> Vec<String> {
+    let Some(staged) = &keys.staged else { return Vec::new() };
+    let empty = KeyMap::new();
+    let applied = keys.applied.as_ref().unwrap_or(&empty);
+    let mut ids: Vec<String> = staged
+        .iter()
+        .filter(|(id, key)| applied.get(*id) != Some(*key))
+        .map(|(id, _)| id.clone())
+        .chain(applied.keys().filter(|id| !staged.contains_key(*id)).cloned())
+        .collect();
+    ids.sort();
+    ids
+}
+
+/// Ids with a staged change, sorted
+pub(crate) fn staged_changes() -> Vec<String> {
+    changed_ids(&CLIENT_AUTH_KEYS.lock().unwrap())
+}
+
+/// Make the staged keys the ones in effect (see `config::commit_pending`)
+pub(crate) fn commit_staged() {
+    let mut keys = CLIENT_AUTH_KEYS.lock().unwrap();
+    if let Some(staged) = keys.staged.take() {
+        keys.applied = Some(staged);
+    }
+}
+
+/// Drop the staged keys, returning the ids whose change was discarded
+pub(crate) fn reset_staged() -> Vec<String> {
+    let mut keys = CLIENT_AUTH_KEYS.lock().unwrap();
+    let discarded = changed_ids(&keys);
+    keys.staged = None;
+    discarded
+}
+
+/// Write the keys in effect to `dir` and point Arti's C Tor client keystore at them
+///
+/// The directory is rewritten from scratch so removed keys don't linger.
+pub(crate) fn apply(builder: &mut TorClientConfigBuilder, dir: &Path) -> std::io::Result<()> {
+    let keys = CLIENT_AUTH_KEYS.lock().unwrap().applied.clone().unwrap_or_default();
+
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    if keys.is_empty() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir)?;
+
+    for (id, key) in &keys {
+        let path = layout::entry(dir, &format!("{}.auth_private", id));
+        std::fs::write(&path, format!("{}:descriptor:x25519:{}\n", id, key))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    builder
+        .storage()
+        .keystore()
+        .ctor()
+        .clients()
+        .push(arti_client::config::CTorClientKeystoreConfig::new("wrapper-client-auth", dir));
+
+    log_info!("Configured client authorization for {} onion service(s)", keys.len());
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&CLIENT_AUTH_KEYS);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    const ID: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid";
+    const KEY: &str = "gtnkrx5wnyxbapyxwkacl3yjtq2mhu6n2p3jn2bxrnnhhbx2a4ea";
+    const OTHER_ID: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+    const OTHER_KEY: &str = "7vcmd7mf5vklcx6dnyyt4uq3dbw3hyhxgj2ri2n7btwmcy4vkd2a";
+
+    fn take_keys() -> Keys {
+        std::mem::replace(&mut CLIENT_AUTH_KEYS.lock().unwrap(), Keys { applied: None, staged: None })
+    }
+
+    #[test]
+    fn keys_are_validated() {
+        let _serial = testing::serial();
+        let saved = take_keys();
+        let padded = format!("{}====", KEY.to_uppercase());
+        for (addr, key, accepted) in [
+            (format!("{}.onion", ID), KEY, true),
+            (format!(" {}.ONION ", ID.to_uppercase()), padded.as_str(), true),
+            (ID.to_string(), KEY, true),
+            (ID[1..].to_string(), KEY, false),
+            (format!("{}1.onion", &ID[1..]), KEY, false),
+            (ID.to_string(), &KEY[1..], false),
+            (ID.to_string(), "not a key", false),
+        ] {
+            assert_eq!(set(&addr, key), accepted, "{:?} {:?}", addr, key);
+        }
+        assert_eq!(configured_ids(), [ID]);
+        remove(&format!("{}.onion", ID));
+        assert!(configured_ids().is_empty());
+        *CLIENT_AUTH_KEYS.lock().unwrap() = saved;
+    }
+
+    #[test]
+    fn apply_rewrites_the_keystore() {
+        let _serial = testing::serial();
+        let saved = take_keys();
+        let temp = TempDir::new();
+        let dir = temp.path().join("client-auth");
+
+        assert!(set(ID, KEY));
+        commit_staged();
+        apply(&mut TorClientConfigBuilder::default(), &dir).unwrap();
+        let file = layout::entry(&dir, &format!("{}.auth_private", ID));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), format!("{}:descriptor:x25519:{}\n", ID, KEY));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // A removed key doesn't linger on disk
+        remove(ID);
+        commit_staged();
+        apply(&mut TorClientConfigBuilder::default(), &dir).unwrap();
+        assert!(!dir.exists());
+        *CLIENT_AUTH_KEYS.lock().unwrap() = saved;
+    }
+
+    #[test]
+    fn keys_are_staged_until_committed() {
+        let _serial = testing::serial();
+        let saved = take_keys();
+
+        assert!(set(ID, KEY));
+        assert_eq!(configured_ids(), [ID]);
+        assert!(applied_ids().is_empty());
+        assert_eq!(staged_changes(), [ID]);
+        commit_staged();
+        assert_eq!(applied_ids(), [ID]);
+        assert!(staged_changes().is_empty());
+
+        // Setting the key in effect again is no change
+        assert!(set(ID, KEY));
+        assert!(staged_changes().is_empty());
+
+        // Adding, replacing and removing are each discarded by a reset
+        assert!(set(OTHER_ID, OTHER_KEY));
+        assert_eq!(staged_changes(), [OTHER_ID]);
+        assert!(set(ID, OTHER_KEY));
+        remove(ID);
+        assert_eq!(configured_ids(), [OTHER_ID]);
+        let mut changed = vec![ID, OTHER_ID];
+        changed.sort();
+        assert_eq!(reset_staged(), changed);
+        assert_eq!(configured_ids(), [ID]);
+        assert_eq!(applied_ids(), [ID]);
+        assert!(reset_staged().is_empty());
+
+        *CLIENT_AUTH_KEYS.lock().unwrap() = saved;
+    }
+}
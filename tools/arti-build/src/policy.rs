@@ -0,0 +1,284 @@
+//! App-level connection policy with a strict time budget
+//!
+//! Some deployments enforce policy at the proxy that native code can't know
+//! ("block this destination category", "confirm first-time onion peers").
+//! With a policy callback registered (`setConnectionPolicyCallback`), every
+//! CONNECT that passes the native checks is put to the app:
+//!
+//! 1. the wrapper calls `onConnectionRequest(long id, String targetJson)`
+//!    with the target the client asked for (host, port, onion or not; never
+//!    the SOCKS credentials or wrapper options);
+//! 2. the app answers with `submitPolicyVerdict(id, allow, annotationJson)`,
+//!    from the callback itself or from any thread later;
+//! 3. the connection waits at most the policy budget (default 50 ms, at most
+//!    200 ms) measured from before the upcall, so a slow callback can't hold
+//!    the connection past it either. Past the budget the default verdict
+//!    applies (allow unless `setPolicyDefaultAllow(false)`) and the timeout
+//!    is counted in the metrics.
+//!
+//! Verdicts are matched by id, so they may arrive in any order. A verdict for
+//! an id that is no longer waiting (answered already, timed out, or never
+//! issued) is refused. Denied connections get SOCKS reply 0x02. The verdict's
+//! annotation (e.g. a category label) is attached to the connection's log
+//! lines.
+//!
+//! Without a callback no request is made and connections proceed at once.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use crate::callbacks::{self, Kind};
+
+/// Default policy budget
+pub(crate) const DEFAULT_BUDGET_MS: u64 = 50;
+
+/// Longest policy budget of `setPolicyBudgetMs`
+pub(crate) const MAX_BUDGET_MS: u64 = 200;
+
+static BUDGET_MS: AtomicU64 = AtomicU64::new(DEFAULT_BUDGET_MS);
+
+/// Verdict applied when the app doesn't answer in time
+static DEFAULT_ALLOW: AtomicBool = AtomicBool::new(true);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Requests waiting for their verdict, by id
+static PENDING: Mutex<Option<HashMap<u64, oneshot::Sender<Verdict>>>> = Mutex::new(None);
+
+/// The app's answer for one connection
+pub(crate) struct Verdict {
+    pub allow: bool,
+    /// Annotation to attach to the connection (compact JSON), if any
+    pub annotation: Option<String>,
+}
+
+pub(crate) fn set_budget_ms(ms: u64) {
+    BUDGET_MS.store(ms, Ordering::Relaxed);
+}
+
+pub(crate) fn set_default_allow(allow: bool) {
+    DEFAULT_ALLOW.store(allow, Ordering::Relaxed);
+}
+
+/// Whether a policy callback is registered
+fn enabled() -> bool {
+    callbacks::slot(Kind::ConnectionPolicy).lock().unwrap().is_some()
+}
+
+/// Call `onConnectionRequest(long, String)` on the policy callback
+fn request(id: u64, target: &str) {
+    // `invoke` doesn't hold the slot lock during the upcall: connections
+    // check it before asking, and the app may re-register from inside the
+    // callback
+    callbacks::invoke(Kind::ConnectionPolicy, |env, callback| {
+        if let Ok(jvalue) = env.new_string(target) {
+            let _ = env.call_method(
+                callback,
+                "onConnectionRequest",
+                "(JLjava/lang/String;)V",
+                &[(id as i64).into(), (&jvalue).into()],
+            );
+        }
+    });
+}
+
+/// Ask the app about a CONNECT to `host:port`, within the policy budget
+pub(crate) async fn ask(host: &str, port: u16) -> Verdict {
+    if !enabled() {
+        return Verdict { allow: true, annotation: None };
+    }
+    ask_with(host, port, request).await
+}
+
+/// `ask`, putting the request to the app with `upcall(id, targetJson)`
+async fn ask_with(host: &str, port: u16, upcall: fn(u64, &str)) -> Verdict {
+    let budget = Duration::from_millis(BUDGET_MS.load(Ordering::Relaxed));
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    PENDING.lock().unwrap().get_or_insert_with(HashMap::new).insert(id, tx);
+
+    let target = json!({
+        "host": host,
+        "port": port,
+        "onion": host.to_ascii_lowercase().ends_with(".onion"),
+    })
+    .to_string();
+    // The upcall blocks its thread for as long as the app takes, so it runs
+    // off the runtime's workers and the budget covers it
+    tokio::task::spawn_blocking(move || upcall(id, &target));
+
+    match tokio::time::timeout(budget, rx).await {
+        Ok(Ok(verdict)) => verdict,
+        _ => {
+            if let Some(pending) = PENDING.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            crate::METRICS.policy_timeouts.fetch_add(1, Ordering::Relaxed);
+            let allow = DEFAULT_ALLOW.load(Ordering::Relaxed);
+            log_info!(
+                "No policy verdict for {}:{} within {} ms; {} by default",
+                crate::redact::Sensitive(host),
+                port,
+                budget.as_millis(),
+                if allow { "allowed" } else { "denied" }
+            );
+            Verdict { allow, annotation: None }
+        }
+    }
+}
+
+/// Why `submit` refused a verdict
+pub(crate) enum Refused {
+    /// Not waiting: answered already, timed out, or never issued
+    UnknownId,
+    InvalidAnnotation,
+}
+
+/// Deliver the app's verdict for request `id`
+pub(crate) fn submit(id: u64, allow: bool, annotation: &str) -> Result<(), Refused> {
+    let annotation = match annotation.trim() {
+        "" => None,
+        text => Some(serde_json::from_str::<Value>(text).map_err(|_| Refused::InvalidAnnotation)?.to_string()),
+    };
+    let waiting = PENDING.lock().unwrap().as_mut().and_then(|pending| pending.remove(&id));
+    let tx = waiting.ok_or(Refused::UnknownId)?;
+    // The connection may have been cancelled since
+    let _ = tx.send(Verdict { allow, annotation });
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&PENDING);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::testing;
+
+    /// Requests the mock callbacks were handed, as `(id, targetJson)`
+    static ASKED: Mutex<Vec<(u64, String)>> = Mutex::new(Vec::new());
+
+    fn asked() -> Vec<(u64, String)> {
+        ASKED.lock().unwrap().clone()
+    }
+
+    /// Answers from inside the callback: deny, with an annotation
+    fn deny_at_once(id: u64, target: &str) {
+        ASKED.lock().unwrap().push((id, target.to_string()));
+        assert!(submit(id, false, r#"{ "category": "ads" }"#).is_ok());
+    }
+
+    /// Whether each late verdict of `slow` was refused
+    static LATE_REFUSED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    /// Blocks well past any budget before answering allow
+    fn slow(id: u64, target: &str) {
+        ASKED.lock().unwrap().push((id, target.to_string()));
+        std::thread::sleep(Duration::from_millis(MAX_BUDGET_MS * 3));
+        let refused = matches!(submit(id, true, ""), Err(Refused::UnknownId));
+        LATE_REFUSED.lock().unwrap().push(refused);
+    }
+
+    /// Records the request and leaves the answer to the test
+    fn record(id: u64, target: &str) {
+        ASKED.lock().unwrap().push((id, target.to_string()));
+    }
+
+    /// Settings back to their defaults on drop
+    struct Reset;
+
+    impl Reset {
+        fn budget(ms: u64) -> Reset {
+            ASKED.lock().unwrap().clear();
+            set_budget_ms(ms);
+            Reset
+        }
+    }
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            set_budget_ms(DEFAULT_BUDGET_MS);
+            set_default_allow(true);
+            PENDING.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+        }
+    }
+
+    #[test]
+    fn a_verdict_within_the_budget_applies() {
+        let _serial = testing::serial();
+        let _reset = Reset::budget(MAX_BUDGET_MS);
+        let verdict = testing::runtime().block_on(ask_with("Example.ONION", 443, deny_at_once));
+        assert!(!verdict.allow);
+        assert_eq!(verdict.annotation.as_deref(), Some(r#"{"category":"ads"}"#));
+        let (_, target) = &asked()[0];
+        assert_eq!(serde_json::from_str::<Value>(target).unwrap(), json!({"host": "Example.ONION", "port": 443, "onion": true}));
+    }
+
+    #[test]
+    fn a_slow_callback_gets_the_default_within_the_budget() {
+        let _serial = testing::serial();
+        let _reset = Reset::budget(DEFAULT_BUDGET_MS);
+        let runtime = testing::runtime();
+        LATE_REFUSED.lock().unwrap().clear();
+        for default_allow in [true, false] {
+            set_default_allow(default_allow);
+            let timeouts = crate::METRICS.policy_timeouts.load(Ordering::Relaxed);
+            let started = Instant::now();
+            let verdict = runtime.block_on(ask_with("example.com", 80, slow));
+            // The budget runs from before the upcall, which is still blocked
+            assert!(started.elapsed() < Duration::from_millis(MAX_BUDGET_MS * 2), "{:?}", started.elapsed());
+            assert_eq!(verdict.allow, default_allow);
+            assert!(verdict.annotation.is_none());
+            assert_eq!(crate::METRICS.policy_timeouts.load(Ordering::Relaxed), timeouts + 1);
+        }
+        // Waits for the callbacks to wake and answer, too late
+        drop(runtime);
+        assert_eq!(*LATE_REFUSED.lock().unwrap(), [true, true]);
+    }
+
+    #[test]
+    fn verdicts_match_by_id_in_any_order_and_only_once() {
+        let _serial = testing::serial();
+        let _reset = Reset::budget(MAX_BUDGET_MS);
+        let runtime = testing::runtime();
+        let (done_tx, done_rx) = mpsc::channel();
+        for host in ["first.example", "second.example"] {
+            let done_tx = done_tx.clone();
+            runtime.spawn(async move {
+                let verdict = ask_with(host, 443, record).await;
+                done_tx.send((host, verdict.allow, verdict.annotation)).unwrap();
+            });
+        }
+        let deadline = Instant::now() + Duration::from_millis(MAX_BUDGET_MS);
+        while asked().len() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let ids: HashMap<String, u64> = asked()
+            .into_iter()
+            .map(|(id, target)| (serde_json::from_str::<Value>(&target).unwrap()["host"].as_str().unwrap().to_string(), id))
+            .collect();
+        let (first, second) = (ids["first.example"], ids["second.example"]);
+
+        // A bad annotation is refused and leaves the request waiting
+        assert!(matches!(submit(second, true, "{not json"), Err(Refused::InvalidAnnotation)));
+        assert!(submit(second, true, r#"{"n":2}"#).is_ok());
+        assert!(submit(first, false, "").is_ok());
+        // Answered already, and never issued
+        assert!(matches!(submit(second, false, ""), Err(Refused::UnknownId)));
+        assert!(matches!(submit(first.max(second) + 1000, true, ""), Err(Refused::UnknownId)));
+
+        let mut verdicts: Vec<_> = (0..2).map(|_| done_rx.recv_timeout(Duration::from_secs(2)).unwrap()).collect();
+        verdicts.sort();
+        assert_eq!(verdicts, [("first.example", false, None), ("second.example", true, Some(r#"{"n":2}"#.to_string()))]);
+    }
+}
@@ -0,0 +1,128 @@
+//! Target ports to prepare circuits for
+//!
+//! Arti builds exit circuits ahead of time (preemptive circuits) for the ports
+//! it predicts will be used: ports seen in the last hour, starting from an
+//! initial list that defaults to 80 and 443. An app that only ever talks to a
+//! few ports can name them with `setTargetPortHints`, so the circuits ready
+//! at startup go to exits that allow those ports and no others are built just
+//! in case.
+//!
+//! The hints seed Arti's prediction; they don't restrict what can be
+//! connected to, and a port used later is still predicted from then on. An
+//! empty list keeps Arti's default. Applies on the next initialize.
+
+use arti_client::config::TorClientConfigBuilder;
+use serde_json::Value;
+
+/// Most ports that can be hinted
+pub(crate) const MAX_PORTS: usize = 16;
+
+/// Parse `setTargetPortHints`' comma-separated list; the entry that isn't a
+/// port number on failure
+pub(crate) fn parse_list(list: &str) -> Result<Vec<u16>, &str> {
+    list.split(',').map(str::trim).filter(|p| !p.is_empty()).map(|port| port.parse().map_err(|_| port)).collect()
+}
+
+/// The hinted ports in `value` (checked with `check`)
+pub(crate) fn ports(value: &Value) -> Vec<u16> {
+    value.as_array().into_iter().flatten().filter_map(|port| port.as_u64()).map(|port| port as u16).collect()
+}
+
+/// Check a `target_port_hints` setting value: distinct ports, at most `MAX_PORTS`
+pub(crate) fn check(value: &Value) -> Result<(), String> {
+    let hints = value.as_array().ok_or("expected an array of ports")?;
+    if hints.len() > MAX_PORTS {
+        return Err(format!("at most {} ports can be hinted", MAX_PORTS));
+    }
+    let mut seen = Vec::new();
+    for port in hints {
+        match port.as_u64() {
+            Some(p @ 1..=65535) if !seen.contains(&p) => seen.push(p),
+            Some(p @ 1..=65535) => return Err(format!("port {} is listed twice", p)),
+            _ => return Err(format!("{} is not a port (1..=65535)", port)),
+        }
+    }
+    Ok(())
+}
+
+/// Apply the hinted ports in `value` (checked with `check`) to a client config
+pub(crate) fn apply(builder: &mut TorClientConfigBuilder, value: &Value) {
+    let ports = ports(value);
+    if !ports.is_empty() {
+        builder.preemptive_circuits().set_initial_predicted_ports(ports);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn lists_parse_into_ports() {
+        for (list, parsed) in [
+            ("", Ok(vec![])),
+            (" , ", Ok(vec![])),
+            ("443", Ok(vec![443])),
+            ("80, 443 ,5222", Ok(vec![80, 443, 5222])),
+            ("443,", Ok(vec![443])),
+            ("443,https", Err("https")),
+            ("65536", Err("65536")),
+            ("-1", Err("-1")),
+            ("44 3", Err("44 3")),
+        ] {
+            assert_eq!(parse_list(list), parsed, "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn hints_are_checked() {
+        let many: Vec<u16> = (1..=MAX_PORTS as u16).collect();
+        let too_many: Vec<u16> = (1..=MAX_PORTS as u16 + 1).collect();
+        for (value, valid) in [
+            (json!([]), true),
+            (json!([80, 443]), true),
+            (json!([1, 65535]), true),
+            (json!(many), true),
+            (json!(too_many), false),
+            (json!([443, 443]), false),
+            (json!([0]), false),
+            (json!([65536]), false),
+            (json!(["443"]), false),
+            (json!(443), false),
+        ] {
+            assert_eq!(check(&value).is_ok(), valid, "{}", value);
+        }
+        assert_eq!(ports(&json!([5222, 443])), [5222, 443]);
+    }
+
+    #[test]
+    fn applied_hints_seed_the_predicted_ports() {
+        let _serial = testing::serial();
+        let _saved = config::save();
+        let temp = TempDir::new();
+        let section = || {
+            let exported = crate::arti_config::render(true);
+            exported.lines().find(|line| line.starts_with("initial_predicted_ports")).map(str::to_string)
+        };
+
+        // Arti's default unless hinted
+        crate::StageSettings::applied().client_config(temp.path(), &[]).unwrap();
+        assert_eq!(section(), None);
+
+        config::set("target_port_hints", json!([443, 5222])).unwrap();
+        // Not before the next initialize
+        assert_eq!(section(), None);
+        config::commit_pending();
+        let settings = crate::StageSettings::applied();
+        assert_eq!(ports(&settings.port_hints), [443, 5222]);
+        let mut builder = TorClientConfigBuilder::from_directories(temp.path().join("state"), temp.path().join("cache"));
+        apply(&mut builder, &settings.port_hints);
+        builder.build().unwrap();
+        settings.client_config(temp.path(), &[]).unwrap();
+        assert_eq!(section().as_deref(), Some("initial_predicted_ports = [443, 5222]"));
+    }
+}
@@ -0,0 +1,239 @@
+//! Best-effort prewarm of the app's first destination
+//!
+//! The first thing a user does after enabling Tor is almost always connecting
+//! to their home server or peer, and even with preemptive circuits that
+//! connect pays for an onion descriptor fetch or an exit selection. When
+//! `first_destination` is configured, initialize opens a stream to it once
+//! bootstrap is done and drops it straight away. The stream uses the isolation
+//! a SOCKS CONNECT to that port would get, so the real connect finds the
+//! descriptor cached and the circuit already built.
+//!
+//! Ready is held back for at most the prewarm budget. When the budget runs out
+//! ready is reported anyway and the prewarm keeps going in the background; a
+//! failed prewarm never holds back ready either. The outcome is reported as
+//! `prewarm` in the notification status.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::{notification, seams, target};
+
+/// Ready is held back this long by default
+pub(crate) const DEFAULT_BUDGET_SECS: u64 = 5;
+
+/// Largest configurable budget
+pub(crate) const MAX_BUDGET_SECS: u64 = 60;
+
+/// Outcome of the current initialize's prewarm
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Status {
+    Skipped,
+    InProgress,
+    Done,
+    Failed,
+}
+
+impl Status {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Status::Skipped => "skipped",
+            Status::InProgress => "in_progress",
+            Status::Done => "done",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// Bumped per initialize, so a background prewarm left over from an earlier
+/// client can't overwrite the current status
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Split `host:port` (IPv6 literals in brackets)
+fn parse(value: &str) -> Option<(String, u16)> {
+    let (host, port) = value.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    let port = port.parse::<u16>().ok().filter(|&p| p != 0)?;
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+/// Setting check: `"host:port"`, or `""` for none
+pub(crate) fn check_destination(value: &Value) -> Result<(), String> {
+    match value.as_str() {
+        Some(s) if s.is_empty() || parse(s).is_some() => Ok(()),
+        _ => Err("expected \"host:port\" or \"\"".into()),
+    }
+}
+
+fn set_status(generation: u64, status: Status) {
+    if GENERATION.load(Ordering::Relaxed) == generation {
+        notification::update(|inputs| inputs.prewarm = status);
+    }
+}
+
+/// Start a new generation with nothing prewarmed (called as bootstrap starts)
+pub(crate) fn reset() {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    set_status(generation, Status::Skipped);
+}
+
+/// Prewarm `destination`, returning once it is done or `budget` has passed
+pub(crate) async fn run<C: seams::Connector>(connector: Arc<C>, destination: &str, budget: Duration) {
+    let generation = GENERATION.load(Ordering::Relaxed);
+    let Some((host, port)) = parse(destination) else {
+        return;
+    };
+    // Never prewarm something a SOCKS client would be refused
+    if let Err(rejection) = target::evaluate(host.clone(), port, &target::ConnectCredentials::default()) {
+        log_info!("Skipping prewarm of first destination: {}", rejection.as_str());
+        return;
+    }
+
+    set_status(generation, Status::InProgress);
+    let prefs = crate::stream_prefs_for(&crate::IsolationKey::for_target(port, None));
+    let task = tokio::spawn(async move {
+        let status = match connector.connect(&host, port, &prefs).await {
+            Ok(_stream) => Status::Done,
+            Err(e) => {
+                log_error!("Prewarm of first destination failed: {}", e);
+                Status::Failed
+            }
+        };
+        set_status(generation, status);
+    });
+
+    tokio::select! {
+        _ = task => {}
+        _ = seams::sleep(budget) => {
+            log_info!("Prewarm still running after {}s; continuing in background", budget.as_secs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seams::fakes::{FakeConnect, FakeConnector, FakeStream};
+    use crate::testing::{self, FakeTime};
+    use arti_client::ErrorKind;
+    use serde_json::json;
+
+    const BUDGET: Duration = Duration::from_secs(DEFAULT_BUDGET_SECS);
+
+    fn status() -> String {
+        let rendered: Value = serde_json::from_str(&notification::render()).unwrap();
+        rendered["prewarm"].as_str().unwrap().to_string()
+    }
+
+    /// Wait (in real time) for the prewarm status to become `expected`
+    fn wait_for_status(expected: Status) {
+        for _ in 0..5000 {
+            if status() == expected.as_str() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("prewarm stayed {}, expected {}", status(), expected.as_str());
+    }
+
+    #[test]
+    fn destinations_are_checked() {
+        for (value, accepted) in [
+            (json!(""), true),
+            (json!("home.example:443"), true),
+            (json!("[2001:db8::1]:8448"), true),
+            (json!("home.example"), false),
+            (json!("home.example:0"), false),
+            (json!(":443"), false),
+            (json!(443), false),
+        ] {
+            assert_eq!(check_destination(&value).is_ok(), accepted, "{}", value);
+        }
+        assert_eq!(parse("[2001:db8::1]:8448"), Some(("2001:db8::1".to_string(), 8448)));
+    }
+
+    #[test]
+    fn a_quick_prewarm_is_done_before_ready() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let connector = Arc::new(FakeConnector::new());
+        let _destination = connector.then_connect();
+        reset();
+        testing::runtime().block_on(run(Arc::clone(&connector), "home.example:443", BUDGET));
+        assert_eq!(status(), "done");
+        assert_eq!(connector.requests(), [("home.example".to_string(), 443)]);
+    }
+
+    #[test]
+    fn a_failed_prewarm_does_not_hold_back_ready() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let connector = Arc::new(FakeConnector::new());
+        connector.then(FakeConnect::Fail(ErrorKind::RemoteConnectionRefused, "refused by the exit"));
+        reset();
+        testing::runtime().block_on(run(connector, "home.example:443", BUDGET));
+        assert_eq!(status(), "failed");
+    }
+
+    #[test]
+    fn a_slow_prewarm_is_cut_at_the_budget_and_finishes_in_the_background() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let connector = Arc::new(FakeConnector::new());
+        let (stream, _destination) = FakeStream::pair();
+        connector.then(FakeConnect::Delayed(2 * BUDGET, stream));
+        reset();
+
+        let runtime = testing::runtime();
+        let ready = runtime.spawn(run(Arc::clone(&connector), "home.example:443", BUDGET));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!ready.is_finished());
+        assert_eq!(status(), "in_progress");
+
+        // Ready goes out when the budget is spent, with the prewarm still going
+        time.advance(BUDGET);
+        runtime.block_on(ready).unwrap();
+        assert_eq!(status(), "in_progress");
+        time.advance(BUDGET);
+        wait_for_status(Status::Done);
+    }
+
+    #[test]
+    fn a_leftover_prewarm_cannot_touch_the_next_status() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let connector = Arc::new(FakeConnector::new());
+        let (stream, _destination) = FakeStream::pair();
+        connector.then(FakeConnect::Delayed(2 * BUDGET, stream));
+        reset();
+
+        let runtime = testing::runtime();
+        let ready = runtime.spawn(run(connector, "home.example:443", BUDGET));
+        std::thread::sleep(Duration::from_millis(20));
+        time.advance(BUDGET);
+        runtime.block_on(ready).unwrap();
+
+        // The next initialize starts over; the old prewarm finishing is ignored
+        reset();
+        time.advance(BUDGET);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(status(), "skipped");
+    }
+
+    #[test]
+    fn refused_and_missing_destinations_are_skipped() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let connector = Arc::new(FakeConnector::new());
+        target::set_denylist(&json!(["*.blocked.example"]));
+        reset();
+        let runtime = testing::runtime();
+        runtime.block_on(run(Arc::clone(&connector), "home.blocked.example:443", BUDGET));
+        runtime.block_on(run(Arc::clone(&connector), "", BUDGET));
+        target::set_denylist(&json!([]));
+        assert_eq!(status(), "skipped");
+        assert!(connector.requests().is_empty());
+    }
+}
@@ -0,0 +1,236 @@
+//! Metrics in the Prometheus text exposition format
+//!
+//! `getPrometheusMetrics` gives a scraper (an on-device exporter, or a test
+//! rig polling over adb) the wrapper's counters without mapping `getMetrics`'
+//! JSON. The text is written in one pass into a single pre-sized string from
+//! the same atomics `getMetrics` reads; beyond them it only reads bootstrap
+//! progress from the client and the notification's connection count.
+//!
+//! Counters are zeroed when the proxy stops unless the retention level keeps
+//! metrics across a stop, which a scraper sees as an ordinary counter reset.
+//! Connect latency is a summary without quantiles: the time from dialing a
+//! CONNECT until it could be answered, as a sum and a count.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::{notification, stall, METRICS};
+
+/// Room for the whole exposition, so it isn't reallocated while written
+const CAPACITY: usize = 4096;
+
+/// Write one metric family's header
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = write!(out, "# HELP {0} {1}\n# TYPE {0} {2}\n", name, help, kind);
+}
+
+/// Write one metric family with a single unlabelled sample
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// The current metrics as exposition text
+pub(crate) fn render() -> String {
+    let client = crate::ARTI_CLIENT.lock().unwrap().as_ref().map(Arc::clone);
+    let status = client.map(|client| client.bootstrap_status());
+    let gauges = [
+        (
+            "arti_bootstrap_percent",
+            "Bootstrap progress of the Tor client.",
+            status.as_ref().map_or(0, |s| (s.as_frac() * 100.0) as u64),
+        ),
+        (
+            "arti_ready_for_traffic",
+            "Whether the Tor client is ready for traffic.",
+            status.as_ref().is_some_and(|s| s.ready_for_traffic()) as u64,
+        ),
+        ("arti_connections_active", "SOCKS connections being handled.", notification::active_connections() as u64),
+    ];
+    let counters = [
+        ("arti_connects_established_total", "CONNECTs established through Tor.", load(&METRICS.connects_established)),
+        (
+            "arti_connect_failures_total",
+            "CONNECTs whose Tor connect failed or timed out.",
+            load(&METRICS.connect_failures),
+        ),
+        (
+            "arti_stream_confirm_failures_total",
+            "Streams that connected but failed confirmation.",
+            load(&METRICS.stream_confirm_failures),
+        ),
+        ("arti_bytes_relayed_total", "Payload bytes relayed in either direction.", load(&METRICS.bytes_relayed)),
+        ("arti_security_events_total", "Security events reported.", load(&METRICS.security_events)),
+        (
+            "arti_strict_mode_violations_total",
+            "Direct connections refused in strict Tor-only mode.",
+            load(&METRICS.strict_mode_violations),
+        ),
+        (
+            "arti_policy_timeouts_total",
+            "Policy requests answered by the default verdict.",
+            load(&METRICS.policy_timeouts),
+        ),
+        ("arti_client_stalled_closes_total", "Connections closed because the client stopped reading.", stall::closes()),
+    ];
+
+    let mut out = String::with_capacity(CAPACITY);
+    for (name, help, value) in gauges {
+        metric(&mut out, name, "gauge", help, value);
+    }
+    for (name, help, value) in counters {
+        metric(&mut out, name, "counter", help, value);
+    }
+    header(
+        &mut out,
+        "arti_connect_latency_seconds",
+        "summary",
+        "Time from dialing a CONNECT until it could be answered.",
+    );
+    let _ = writeln!(out, "arti_connect_latency_seconds_sum {:.3}", load(&METRICS.connect_ms_total) as f64 / 1000.0);
+    let _ = writeln!(out, "arti_connect_latency_seconds_count {}", load(&METRICS.connects_established));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::testing;
+
+    /// A metric family read back from exposition text
+    #[derive(Debug, Default)]
+    struct Family {
+        help: bool,
+        kind: String,
+        samples: Vec<(String, f64)>,
+    }
+
+    fn is_metric_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    }
+
+    /// The family a sample belongs to (summaries add `_sum` and `_count`)
+    fn family_of<'a>(sample: &'a str, families: &HashMap<String, Family>) -> &'a str {
+        for suffix in ["_sum", "_count"] {
+            if let Some(base) = sample.strip_suffix(suffix) {
+                if families.get(base).is_some_and(|family| family.kind == "summary") {
+                    return base;
+                }
+            }
+        }
+        sample
+    }
+
+    /// Parse exposition text, failing on anything the format doesn't allow
+    fn parse(text: &str) -> Result<HashMap<String, Family>, String> {
+        if !text.ends_with('\n') {
+            return Err("no trailing newline".into());
+        }
+        let mut families: HashMap<String, Family> = HashMap::new();
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.splitn(3, ' ');
+                let (keyword, name, rest) = (words.next(), words.next(), words.next());
+                let (Some(name), Some(rest)) = (name, rest) else {
+                    return Err(format!("truncated comment: {}", line));
+                };
+                if !is_metric_name(name) {
+                    return Err(format!("bad name: {}", line));
+                }
+                let family = families.entry(name.to_string()).or_default();
+                if !family.samples.is_empty() {
+                    return Err(format!("metadata after samples: {}", line));
+                }
+                match keyword {
+                    Some("HELP") if !family.help => family.help = true,
+                    Some("TYPE") if family.kind.is_empty() => {
+                        if !["counter", "gauge", "summary", "histogram", "untyped"].contains(&rest) {
+                            return Err(format!("bad type: {}", line));
+                        }
+                        family.kind = rest.to_string();
+                    }
+                    _ => return Err(format!("bad or repeated comment: {}", line)),
+                }
+                continue;
+            }
+            let Some((name, value)) = line.split_once(' ') else {
+                return Err(format!("bad sample: {}", line));
+            };
+            if !is_metric_name(name) {
+                return Err(format!("bad name: {}", line));
+            }
+            let value: f64 = value.parse().map_err(|_| format!("bad value: {}", line))?;
+            let base = family_of(name, &families).to_string();
+            let Some(family) = families.get_mut(&base).filter(|family| !family.kind.is_empty()) else {
+                return Err(format!("sample without a TYPE: {}", line));
+            };
+            if family.kind == "counter" && !name.ends_with("_total") {
+                return Err(format!("counter not named _total: {}", line));
+            }
+            family.samples.push((name.to_string(), value));
+        }
+        Ok(families)
+    }
+
+    fn value(families: &HashMap<String, Family>, family: &str, sample: &str) -> f64 {
+        families[family].samples.iter().find(|(name, _)| name == sample).unwrap().1
+    }
+
+    #[test]
+    fn parser_rejects_malformed_text() {
+        let table = [
+            ("# TYPE a counter\na_total 1\n", "sample without a TYPE"),
+            ("# TYPE a gauge\na 1", "no trailing newline"),
+            ("# TYPE a gauge\na one\n", "bad value"),
+            ("# TYPE 1a gauge\n1a 1\n", "bad name"),
+            ("# TYPE a gauge\n# TYPE a gauge\na 1\n", "repeated"),
+            ("# TYPE a gauge\na 1\n# HELP a late\n", "metadata after samples"),
+            ("# TYPE a_total counter\na_total 1\n# TYPE b counter\nb 1\n", "_total"),
+        ];
+        for (text, error) in table {
+            let parsed = parse(text);
+            assert!(parsed.as_ref().is_err_and(|e| e.contains(error)), "{:?}: {:?}", text, parsed.map(|_| ()));
+        }
+    }
+
+    #[test]
+    fn output_parses_as_prometheus_text() {
+        let _serial = testing::serial();
+        METRICS.reset();
+        METRICS.connects_established.fetch_add(4, Ordering::Relaxed);
+        METRICS.connect_ms_total.fetch_add(2500, Ordering::Relaxed);
+        METRICS.connect_failures.fetch_add(3, Ordering::Relaxed);
+        METRICS.bytes_relayed.fetch_add(1 << 20, Ordering::Relaxed);
+
+        let text = render();
+        let families = parse(&text).unwrap_or_else(|e| panic!("{}\n{}", e, text));
+        for (name, family) in &families {
+            assert!(family.help && !family.kind.is_empty() && !family.samples.is_empty(), "{}: {:?}", name, family);
+        }
+        assert_eq!(families["arti_bootstrap_percent"].kind, "gauge");
+        assert_eq!(value(&families, "arti_connects_established_total", "arti_connects_established_total"), 4.0);
+        assert_eq!(value(&families, "arti_connect_failures_total", "arti_connect_failures_total"), 3.0);
+        assert_eq!(value(&families, "arti_bytes_relayed_total", "arti_bytes_relayed_total"), (1 << 20) as f64);
+        assert_eq!(families["arti_connect_latency_seconds"].kind, "summary");
+        assert_eq!(value(&families, "arti_connect_latency_seconds", "arti_connect_latency_seconds_sum"), 2.5);
+        assert_eq!(value(&families, "arti_connect_latency_seconds", "arti_connect_latency_seconds_count"), 4.0);
+
+        // Written into the one allocation, with room to spare at the largest values
+        assert_eq!(text.capacity(), CAPACITY);
+        METRICS.bytes_relayed.store(u64::MAX, Ordering::Relaxed);
+        METRICS.connect_ms_total.store(u64::MAX, Ordering::Relaxed);
+        let largest = render();
+        assert_eq!(largest.capacity(), CAPACITY);
+        assert!(parse(&largest).is_ok());
+        METRICS.reset();
+    }
+}
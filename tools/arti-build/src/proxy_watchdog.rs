@@ -0,0 +1,201 @@
+//! Opt-in check that a started proxy ever gets a connection through
+//!
+//! "Bootstrapped but nothing works" otherwise shows only as every app
+//! request timing out. With a window set (`setProxyWatchdog`, off by
+//! default), each `startSocksProxy` that starts a listener arms a check: if
+//! connects were attempted during the window but none was established, and
+//! the client still claims to be ready for traffic, the proxy is reported as
+//! non-functional, once per start, as a `PROXY_NON_FUNCTIONAL` error line
+//! and the `proxy_non_functional` diagnostics failure.
+//!
+//! A proxy nobody used in the window is not judged, nor is one whose client
+//! isn't ready yet (a slow bootstrap, not a hung proxy). Stopping or
+//! restarting the listener disarms the pending check.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arti_client::TorClient;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::{diagnostics, seams};
+
+/// Longest window that can be set
+pub(crate) const MAX_WINDOW_SECS: u64 = 3600;
+
+/// Window in seconds (0: off)
+static WINDOW_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Connects attempted and established since process start
+static ATTEMPTED: AtomicU64 = AtomicU64::new(0);
+static ESTABLISHED: AtomicU64 = AtomicU64::new(0);
+
+/// Generation of the armed check; bumped to disarm it
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn set_window_secs(secs: u64) {
+    WINDOW_SECS.store(secs, Ordering::Relaxed);
+}
+
+pub(crate) fn attempted() {
+    ATTEMPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn established() {
+    ESTABLISHED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Drop the pending check (listener stopped or restarted)
+pub(crate) fn disarm() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Arm the check for a listener that just started (no-op while off)
+pub(crate) fn arm(runtime: &tokio::runtime::Handle, client: Arc<TorClient<PreferredRuntime>>) {
+    arm_with(runtime, move || client.bootstrap_status().ready_for_traffic());
+}
+
+/// [`arm`], asking `ready` whether the client is ready for traffic
+///
+/// Returns the pending check, if one was armed.
+fn arm_with(
+    runtime: &tokio::runtime::Handle,
+    ready: impl Fn() -> bool + Send + 'static,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let window = WINDOW_SECS.load(Ordering::Relaxed);
+    if window == 0 {
+        return None;
+    }
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    let attempted = ATTEMPTED.load(Ordering::Relaxed);
+    let established = ESTABLISHED.load(Ordering::Relaxed);
+    Some(runtime.spawn(async move {
+        seams::sleep(Duration::from_secs(window)).await;
+        if GENERATION.load(Ordering::Relaxed) != generation {
+            return;
+        }
+        let attempts = ATTEMPTED.load(Ordering::Relaxed) - attempted;
+        if attempts == 0 || ESTABLISHED.load(Ordering::Relaxed) > established {
+            return;
+        }
+        if !ready() {
+            log_info!("Proxy watchdog: no connection established in {} s, but still bootstrapping", window);
+            return;
+        }
+        let message = format!(
+            "{} connect(s) attempted in the {} s since the proxy started and none established, while ready for traffic",
+            attempts, window
+        );
+        log_error!("PROXY_NON_FUNCTIONAL: {}", message);
+        diagnostics::record_failure("proxy_non_functional", message);
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    const WINDOW: u64 = 30;
+
+    /// Sets the window for a test and disarms whatever it left armed
+    struct Window;
+
+    impl Window {
+        fn set(secs: u64) -> Window {
+            set_window_secs(secs);
+            diagnostics::clear_last_failure();
+            Window
+        }
+    }
+
+    impl Drop for Window {
+        fn drop(&mut self) {
+            set_window_secs(0);
+            disarm();
+            diagnostics::clear_last_failure();
+        }
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum Step {
+        Attempt,
+        Establish,
+        Disarm,
+    }
+
+    /// Whether the check armed with `window` fires after `steps` happen in it
+    fn fires(window: u64, ready: bool, steps: &[Step]) -> bool {
+        let time = FakeTime::start();
+        let _window = Window::set(window);
+        let runtime = testing::runtime();
+        let check = arm_with(runtime.handle(), move || ready);
+        for step in steps {
+            match step {
+                Step::Attempt => attempted(),
+                Step::Establish => established(),
+                Step::Disarm => disarm(),
+            }
+        }
+        if let Some(check) = check {
+            runtime.block_on(async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                assert!(!check.is_finished(), "judged before the window ended");
+                time.advance(Duration::from_secs(window));
+                check.await.unwrap();
+            });
+        }
+        diagnostics::last_error_json().contains("proxy_non_functional")
+    }
+
+    #[test]
+    fn a_ready_proxy_that_connects_nothing_is_reported() {
+        use Step::*;
+        let _serial = testing::serial();
+        let table: &[(&str, u64, bool, &[Step], bool)] = &[
+            ("bootstrapped but broken", WINDOW, true, &[Attempt, Attempt], true),
+            ("one connect got through", WINDOW, true, &[Attempt, Establish, Attempt], false),
+            ("nobody used the proxy", WINDOW, true, &[], false),
+            ("still bootstrapping", WINDOW, false, &[Attempt, Attempt], false),
+            ("listener stopped in the window", WINDOW, true, &[Attempt, Disarm], false),
+            ("watchdog off", 0, true, &[Attempt, Attempt], false),
+        ];
+        for &(case, window, ready, steps, expected) in table {
+            assert_eq!(fires(window, ready, steps), expected, "{}", case);
+        }
+    }
+
+    #[test]
+    fn only_attempts_since_the_start_are_judged() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _window = Window::set(WINDOW);
+        let runtime = testing::runtime();
+
+        // Failures before this start belong to an earlier listener
+        attempted();
+        let check = arm_with(runtime.handle(), || true).unwrap();
+        runtime.block_on(async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            time.advance(Duration::from_secs(WINDOW));
+            check.await.unwrap();
+        });
+        assert_eq!(diagnostics::last_error_json(), "null");
+
+        // A restart disarms the earlier check; the new one still fires
+        let earlier = arm_with(runtime.handle(), || true).unwrap();
+        let later = arm_with(runtime.handle(), || true).unwrap();
+        attempted();
+        runtime.block_on(async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            time.advance(Duration::from_secs(WINDOW));
+            earlier.await.unwrap();
+            later.await.unwrap();
+        });
+        let error: serde_json::Value = serde_json::from_str(&diagnostics::last_error_json()).unwrap();
+        assert_eq!(error["kind"], "proxy_non_functional");
+        let message = error["message"].as_str().unwrap();
+        assert!(message.starts_with("1 connect(s) attempted in the 30 s"), "{}", message);
+    }
+}
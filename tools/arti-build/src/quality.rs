@@ -0,0 +1,600 @@
+//! Per-destination connection quality
+//!
+//! The app's P2P layer picks which peers to sync from and has no other signal
+//! about which ones are fast through Tor from this device. For every
+//! destination we keep rolling statistics: success rate, median connect
+//! latency, median first-byte latency and recent downstream throughput,
+//! folded into a 0-100 score.
+//!
+//! Destinations are keyed by a salted hash of `host:port`, never by name, and
+//! the table is capped at `MAX_ENTRIES` with least-recently-used eviction (and
+//! counts against the shared cache budget, see caches.rs).
+//! Success and failure counts decay with a half-life of `HALF_LIFE`, so old
+//! behavior fades; latency medians are over the last `RECENT_SAMPLES`
+//! connections and throughput is an exponential moving average.
+//!
+//! This is linkable browsing history, so `newIdentity` clears it. Only when
+//! persistent quality tracking is enabled explicitly is it kept across
+//! restarts, in the wrapper state directory (with its salt); disabling it
+//! again deletes the file.
+//!
+//! The table is owned by the bookkeeping actor (see actor.rs): connections
+//! post their samples without waiting, and the getters wait for the actor at
+//! most the query budget, answering as if nothing was recorded past it.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::actor;
+use crate::caches::BoundedMap;
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+use crate::retention::{self, Sink};
+
+/// Destinations tracked at most
+const MAX_ENTRIES: usize = 512;
+
+/// Latency samples kept per destination for the medians
+const RECENT_SAMPLES: usize = 15;
+
+/// Half-life of the success and failure counts
+const HALF_LIFE: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Weight of the newest sample in the throughput average
+const THROUGHPUT_ALPHA: f64 = 0.3;
+
+/// Connect latency at which the speed part of the score halves
+const SCORE_LATENCY_MS: f64 = 2000.0;
+
+/// Persist at most this often while connections keep updating the table
+const SAVE_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Default)]
+struct Stats {
+    successes: f64,
+    failures: f64,
+    connect_ms: VecDeque<u64>,
+    first_byte_ms: VecDeque<u64>,
+    throughput: Option<f64>,
+    updated_epoch_ms: u64,
+}
+
+pub(crate) struct Table {
+    salt: [u8; 16],
+    entries: BoundedMap<String, Stats>,
+    /// Where the table is persisted (only with persistent tracking enabled)
+    file: Option<PathBuf>,
+    persistent: bool,
+    dirty: bool,
+    saved_epoch_ms: u64,
+}
+
+/// What one connection contributed
+pub(crate) struct Sample {
+    connected: bool,
+    connect: Duration,
+    first_byte: Option<Duration>,
+    bytes_down: u64,
+    duration: Duration,
+}
+
+impl Sample {
+    /// A connect attempt that failed
+    pub(crate) fn failed() -> Self {
+        Sample {
+            connected: false,
+            connect: Duration::ZERO,
+            first_byte: None,
+            bytes_down: 0,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+fn fresh_salt() -> [u8; 16] {
+    let mut salt = [0u8; 16];
+    crate::seams::fill_random(&mut salt);
+    salt
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key(salt: &[u8], host: &str, port: u16) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(host.to_ascii_lowercase().as_bytes());
+    hasher.update(port.to_be_bytes());
+    hex(&hasher.finalize()[..16])
+}
+
+/// Multiplier for counts last updated `elapsed_ms` ago
+fn decay_factor(elapsed_ms: u64) -> f64 {
+    0.5f64.powf(elapsed_ms as f64 / HALF_LIFE.as_millis() as f64)
+}
+
+fn median(samples: &VecDeque<u64>) -> Option<u64> {
+    let mut sorted: Vec<u64> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
+}
+
+fn push_sample(samples: &mut VecDeque<u64>, value: u64) {
+    if samples.len() == RECENT_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(value);
+}
+
+impl Stats {
+    /// Approximate memory held for this entry under `key` (caches.rs)
+    fn approx_bytes(&self, key: &str) -> usize {
+        key.len() + std::mem::size_of::<Stats>() + 8 * (self.connect_ms.len() + self.first_byte_ms.len())
+    }
+
+    /// Apply decay up to `now` so counts are comparable across entries
+    fn decay_to(&mut self, now: u64) {
+        let factor = decay_factor(now.saturating_sub(self.updated_epoch_ms));
+        self.successes *= factor;
+        self.failures *= factor;
+        self.updated_epoch_ms = now;
+    }
+
+    fn success_rate(&self) -> Option<f64> {
+        let attempts = self.successes + self.failures;
+        (attempts > 0.0).then(|| self.successes / attempts)
+    }
+
+    /// 60% reliability, 40% speed (halving at `SCORE_LATENCY_MS`)
+    fn score(&self) -> u32 {
+        let reliability = self.success_rate().unwrap_or(0.0);
+        let speed = median(&self.connect_ms).map_or(0.5, |ms| 1.0 / (1.0 + ms as f64 / SCORE_LATENCY_MS));
+        (100.0 * (0.6 * reliability + 0.4 * speed)).round() as u32
+    }
+
+    fn to_json(&self, now: u64) -> Value {
+        let factor = decay_factor(now.saturating_sub(self.updated_epoch_ms));
+        json!({
+            "score": self.score(),
+            "success_rate": self.success_rate(),
+            "attempts": (self.successes + self.failures) * factor,
+            "median_connect_ms": median(&self.connect_ms),
+            "median_first_byte_ms": median(&self.first_byte_ms),
+            "throughput_bytes_per_sec": self.throughput.map(|t| t.round() as u64),
+            "last_used_epoch_ms": self.updated_epoch_ms,
+        })
+    }
+
+    fn to_stored(&self) -> Value {
+        json!({
+            "successes": self.successes,
+            "failures": self.failures,
+            "connect_ms": self.connect_ms,
+            "first_byte_ms": self.first_byte_ms,
+            "throughput": self.throughput,
+            "updated_epoch_ms": self.updated_epoch_ms,
+        })
+    }
+
+    fn from_stored(value: &Value) -> Option<Stats> {
+        let samples = |name: &str| -> VecDeque<u64> {
+            value[name]
+                .as_array()
+                .map(|a| a.iter().filter_map(Value::as_u64).take(RECENT_SAMPLES).collect())
+                .unwrap_or_default()
+        };
+        Some(Stats {
+            successes: value["successes"].as_f64()?,
+            failures: value["failures"].as_f64()?,
+            connect_ms: samples("connect_ms"),
+            first_byte_ms: samples("first_byte_ms"),
+            throughput: value["throughput"].as_f64(),
+            updated_epoch_ms: value["updated_epoch_ms"].as_u64()?,
+        })
+    }
+}
+
+impl Table {
+    pub(crate) fn new() -> Self {
+        Table {
+            salt: fresh_salt(),
+            entries: BoundedMap::new(
+                "destination_quality",
+                MAX_ENTRIES,
+                |key, stats| stats.approx_bytes(key),
+                |target| actor::post(move |state| state.quality.entries.trim(target)),
+            ),
+            file: None,
+            persistent: false,
+            dirty: false,
+            saved_epoch_ms: 0,
+        }
+    }
+
+    fn remove_file(&self) {
+        let Some(file) = self.file.as_ref() else {
+            return;
+        };
+        if let Err(e) = std::fs::remove_file(file) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log_error!("Failed to delete destination quality file: {}", e);
+            }
+        }
+    }
+
+    fn save(&mut self) {
+        let Some(file) = self
+            .file
+            .clone()
+            .filter(|_| self.persistent && retention::retains(Sink::QualityPersistence))
+        else {
+            return;
+        };
+        let entries: Map<String, Value> = self.entries.iter().map(|(k, s)| (k.clone(), s.to_stored())).collect();
+        let text = json!({ "salt": hex(&self.salt), "entries": entries }).to_string();
+        match layout::write_atomic(&file, text.as_bytes()) {
+            Ok(()) => {
+                self.dirty = false;
+                self.saved_epoch_ms = now_epoch_ms();
+            }
+            Err(e) => log_error!("Failed to save destination quality: {}", e),
+        }
+    }
+
+    fn load(&mut self) {
+        let Some(file) = self.file.as_ref() else {
+            return;
+        };
+        let Ok(text) = std::fs::read_to_string(file) else {
+            return;
+        };
+        let Ok(stored) = serde_json::from_str::<Value>(&text) else {
+            log_error!("Ignoring unreadable destination quality file");
+            return;
+        };
+        let salt = stored["salt"].as_str().unwrap_or_default();
+        let salt: Vec<u8> = (0..salt.len() / 2)
+            .filter_map(|i| u8::from_str_radix(salt.get(2 * i..2 * i + 2)?, 16).ok())
+            .collect();
+        let Ok(salt) = <[u8; 16]>::try_from(salt) else {
+            log_error!("Ignoring destination quality file with a bad salt");
+            return;
+        };
+        self.salt = salt;
+        let mut entries: Vec<(String, Stats)> = stored["entries"]
+            .as_object()
+            .map(|m| m.iter().filter_map(|(k, v)| Some((k.clone(), Stats::from_stored(v)?))).collect())
+            .unwrap_or_default();
+        // Least recently used first, so eviction order survives the restart
+        entries.sort_by_key(|(_, stats)| stats.updated_epoch_ms);
+        self.entries.clear();
+        for (key, stats) in entries {
+            self.entries.insert(key, stats);
+        }
+    }
+}
+
+/// Downstream timing of one connection, filled in by `MeteredReader`
+pub(crate) struct Meter {
+    started: Instant,
+    first_byte: Option<Duration>,
+    bytes: u64,
+}
+
+impl Meter {
+    /// Start timing (call when the Tor stream is established)
+    pub(crate) fn start() -> Self {
+        Meter {
+            started: crate::seams::now_monotonic(),
+            first_byte: None,
+            bytes: 0,
+        }
+    }
+
+    /// The sample for a connection that connected after `connect`
+    pub(crate) fn sample(&self, connect: Duration) -> Sample {
+        Sample {
+            connected: true,
+            connect,
+            first_byte: self.first_byte,
+            bytes_down: self.bytes,
+            duration: crate::seams::now_monotonic().saturating_duration_since(self.started),
+        }
+    }
+}
+
+/// Reader adapter recording first-byte latency and bytes read into a `Meter`
+pub(crate) struct MeteredReader<'a, R> {
+    pub inner: R,
+    pub meter: &'a mut Meter,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MeteredReader<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        if read > 0 {
+            let meter = &mut *self.meter;
+            if meter.first_byte.is_none() {
+                meter.first_byte = Some(crate::seams::now_monotonic().saturating_duration_since(meter.started));
+            }
+            meter.bytes += read;
+        }
+        poll
+    }
+}
+
+/// Record the outcome of one connection to `host:port`
+pub(crate) fn record(host: &str, port: u16, sample: Sample) {
+    let now = now_epoch_ms();
+    let host = host.to_string();
+    actor::post(move |state| {
+        let table = &mut state.quality;
+        let key = key(&table.salt, &host, port);
+        table.entries.update(key, Stats::default, |stats| {
+            stats.decay_to(now);
+            if sample.connected {
+                stats.successes += 1.0;
+                push_sample(&mut stats.connect_ms, sample.connect.as_millis() as u64);
+                if let Some(first_byte) = sample.first_byte {
+                    push_sample(&mut stats.first_byte_ms, first_byte.as_millis() as u64);
+                }
+                let secs = sample.duration.as_secs_f64();
+                if sample.bytes_down > 0 && secs > 0.0 {
+                    let rate = sample.bytes_down as f64 / secs;
+                    stats.throughput = Some(match stats.throughput {
+                        Some(t) => t + THROUGHPUT_ALPHA * (rate - t),
+                        None => rate,
+                    });
+                }
+            } else {
+                stats.failures += 1.0;
+            }
+        });
+        table.dirty = true;
+        if table.persistent && now.saturating_sub(table.saved_epoch_ms) >= SAVE_INTERVAL_MS {
+            table.save();
+        }
+    });
+}
+
+/// Quality of `host:port` as JSON, or `null` if it was never seen
+pub(crate) fn destination_json(host: &str, port: u16) -> String {
+    let now = now_epoch_ms();
+    let host = host.to_string();
+    actor::query("getDestinationQuality", "null".to_string(), move |state| {
+        let table = &state.quality;
+        let key = key(&table.salt, &host, port);
+        table.entries.get(&key).map_or("null".to_string(), |s| s.to_json(now).to_string())
+    })
+}
+
+/// The `n` best-scoring destinations as a JSON array (hashed keys only)
+pub(crate) fn top_json(n: usize) -> String {
+    let now = now_epoch_ms();
+    actor::query("getTopDestinations", "[]".to_string(), move |state| {
+        let table = &state.quality;
+        let mut ranked: Vec<(&String, &Stats)> = table.entries.iter().collect();
+        ranked.sort_by(|a, b| b.1.score().cmp(&a.1.score()).then(b.1.updated_epoch_ms.cmp(&a.1.updated_epoch_ms)));
+        let top: Vec<Value> = ranked
+            .into_iter()
+            .take(n)
+            .map(|(key, stats)| {
+                let mut entry = stats.to_json(now);
+                entry["key"] = json!(key);
+                entry
+            })
+            .collect();
+        Value::Array(top).to_string()
+    })
+}
+
+/// Use `data_dir` for the persisted table, loading it if tracking is persistent
+pub(crate) fn open(data_dir: &Path) {
+    let file = layout::path(data_dir, Purpose::DestinationQuality);
+    actor::post(move |state| {
+        let table = &mut state.quality;
+        table.file = Some(file);
+        if table.persistent {
+            table.load();
+        }
+    });
+}
+
+/// Enable or disable keeping scores across restarts
+///
+/// Disabling deletes the persisted copy; the in-memory table is kept.
+pub(crate) fn set_persistent(enabled: bool) {
+    actor::post(move |state| {
+        let table = &mut state.quality;
+        if enabled == table.persistent {
+            return;
+        }
+        table.persistent = enabled;
+        if enabled {
+            table.save();
+        } else {
+            table.remove_file();
+        }
+    });
+}
+
+/// Delete the persisted scores, keeping those in memory (retention.rs)
+pub(crate) fn remove_file() {
+    actor::post(|state| state.quality.remove_file());
+}
+
+/// Forget every destination and start over with a new salt
+///
+/// Called by `newIdentity`; with persistent tracking enabled the scores are
+/// kept, as the user asked for that explicitly.
+pub(crate) fn reset_for_new_identity() {
+    actor::post(|state| {
+        let table = &mut state.quality;
+        if table.persistent {
+            return;
+        }
+        table.entries.clear();
+        table.salt = fresh_salt();
+        table.dirty = false;
+    });
+}
+
+/// Write out pending changes, waiting up to `timeout` (called on stop)
+pub(crate) fn flush(timeout: Duration) -> bool {
+    actor::ask(timeout, |state| {
+        if state.quality.dirty {
+            state.quality.save();
+        }
+    })
+    .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+
+    /// Swap `table` in as the actor's, returning the one it replaces
+    fn swap(table: Table) -> Table {
+        actor::ask(Duration::from_secs(5), move |state| std::mem::replace(&mut state.quality, table)).unwrap()
+    }
+
+    fn connected(connect_ms: u64) -> Sample {
+        Sample {
+            connected: true,
+            connect: Duration::from_millis(connect_ms),
+            first_byte: Some(Duration::from_millis(2 * connect_ms)),
+            bytes_down: 10_000,
+            duration: Duration::from_secs(1),
+        }
+    }
+
+    fn quality(host: &str, port: u16) -> Value {
+        serde_json::from_str(&destination_json(host, port)).unwrap()
+    }
+
+    #[test]
+    fn counts_halve_every_half_life() {
+        let half_life = HALF_LIFE.as_millis() as u64;
+        for (elapsed, factor) in [(0, 1.0), (half_life / 2, 0.5f64.sqrt()), (half_life, 0.5), (3 * half_life, 0.125)] {
+            assert!((decay_factor(elapsed) - factor).abs() < 1e-12, "{} ms", elapsed);
+        }
+
+        let mut stats = Stats { successes: 8.0, failures: 4.0, updated_epoch_ms: 1000, ..Stats::default() };
+        stats.decay_to(1000 + 2 * half_life);
+        assert_eq!((stats.successes, stats.failures), (2.0, 1.0));
+        assert_eq!(stats.updated_epoch_ms, 1000 + 2 * half_life);
+        // Decay keeps the rate, only the weight of the history fades
+        assert_eq!(stats.success_rate(), Some(2.0 / 3.0));
+        // A clock that went backwards decays nothing
+        stats.decay_to(0);
+        assert_eq!(stats.successes, 2.0);
+    }
+
+    #[test]
+    fn score_weighs_reliability_and_latency() {
+        // successes, failures, connect samples => score
+        let table: [(f64, f64, &[u64], u32); 5] = [
+            (0.0, 0.0, &[], 20),
+            (1.0, 0.0, &[], 80),
+            (1.0, 0.0, &[0], 100),
+            (1.0, 1.0, &[2000], 50),
+            (0.0, 3.0, &[100, 6000, 2000], 20),
+        ];
+        for (successes, failures, samples, score) in table {
+            let stats = Stats { successes, failures, connect_ms: samples.iter().copied().collect(), ..Stats::default() };
+            assert_eq!(stats.score(), score, "{} / {} {:?}", successes, failures, samples);
+        }
+    }
+
+    #[test]
+    fn medians_cover_the_recent_samples_only() {
+        let mut samples = VecDeque::new();
+        assert_eq!(median(&samples), None);
+        for value in 0..RECENT_SAMPLES as u64 + 5 {
+            push_sample(&mut samples, 1000 + value);
+        }
+        assert_eq!(samples.len(), RECENT_SAMPLES);
+        assert_eq!(samples.front(), Some(&1005));
+        assert_eq!(median(&samples), Some(1005 + RECENT_SAMPLES as u64 / 2));
+    }
+
+    #[test]
+    fn the_table_keeps_the_most_recently_used_destinations() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let saved = swap(Table::new());
+
+        record("first.example", 443, connected(100));
+        for i in 1..MAX_ENTRIES {
+            record(&format!("peer{}.example", i), 443, connected(100));
+        }
+        // Touching the oldest makes the second oldest the one to go
+        record("FIRST.example", 443, Sample::failed());
+        record("overflow.example", 443, connected(100));
+
+        let len = actor::ask(Duration::from_secs(5), |state| state.quality.entries.iter().count()).unwrap();
+        assert_eq!(len, MAX_ENTRIES);
+        assert_eq!(quality("first.example", 443)["attempts"], 2.0);
+        assert_eq!(quality("peer1.example", 443), Value::Null);
+        assert!(quality("overflow.example", 443).is_object());
+        // Keys are hashes, never the names
+        let top = top_json(MAX_ENTRIES);
+        assert!(!top.contains("example"));
+        assert_eq!(serde_json::from_str::<Value>(&top).unwrap().as_array().unwrap().len(), MAX_ENTRIES);
+
+        swap(saved);
+    }
+
+    #[test]
+    fn new_identity_forgets_unless_persistent() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        let saved = swap(Table::new());
+        let temp = TempDir::new();
+        let file = layout::path(temp.path(), Purpose::DestinationQuality);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        open(temp.path());
+
+        record("peer.example", 80, connected(300));
+        let salt = actor::ask(Duration::from_secs(5), |state| state.quality.salt).unwrap();
+        reset_for_new_identity();
+        assert_eq!(quality("peer.example", 80), Value::Null);
+        assert_ne!(actor::ask(Duration::from_secs(5), |state| state.quality.salt).unwrap(), salt);
+        assert!(flush(Duration::from_secs(5)));
+        assert!(!file.exists());
+
+        // Persistent scores survive the reset and a restart
+        set_persistent(true);
+        record("peer.example", 80, connected(300));
+        reset_for_new_identity();
+        assert_eq!(quality("peer.example", 80)["median_connect_ms"], 300);
+        assert!(flush(Duration::from_secs(5)));
+        assert!(file.exists());
+
+        let mut restarted = Table::new();
+        restarted.persistent = true;
+        swap(restarted);
+        open(temp.path());
+        assert_eq!(quality("peer.example", 80)["median_connect_ms"], 300);
+        assert_eq!(quality("peer.example", 80)["median_first_byte_ms"], 600);
+
+        // Turning persistence off deletes the file but keeps the scores
+        set_persistent(false);
+        assert!(flush(Duration::from_secs(5)));
+        assert!(!file.exists());
+        assert!(quality("peer.example", 80).is_object());
+
+        swap(saved);
+    }
+}
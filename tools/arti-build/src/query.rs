@@ -0,0 +1,551 @@
+//! Status queries answered within a time budget
+//!
+//! Some queries have to run on the Tokio runtime to read their state. A plain
+//! `block_on` from the calling JNI thread can wait indefinitely when the
+//! runtime is saturated, which is exactly when the UI most wants the status.
+//! Such a query is declared as a `Query` and answered through `Query::run`:
+//!
+//! - the computation is spawned on the runtime and waited for at most the
+//!   query budget (default 250 ms, see `setQueryTimeoutMs`);
+//! - every result is kept as the query's snapshot and returned with
+//!   `"stale": false`;
+//! - past the budget, or before there is a runtime, the caller gets the
+//!   snapshot with `"stale": true` instead of blocking or an error. The
+//!   computation keeps running and refreshes the snapshot when it finishes,
+//!   so the next stale answer is as recent as possible.
+//!
+//! A query that has never completed answers with its fallback object, marked
+//! stale.
+//!
+//! Several app components poll the same queries on their own timers, so
+//! their calls are merged:
+//!
+//! - a result is served again, as not stale, for the cache TTL after it was
+//!   computed (default 500 ms, see `setQueryCacheTtlMs`);
+//! - a caller arriving while a computation is in flight waits for that one
+//!   instead of starting another (single flight);
+//! - `invalidate` drops the cached result when the state it reflects changes
+//!   (e.g. the bootstrap status on a lifecycle transition); a computation
+//!   started before then still answers its callers but isn't cached;
+//! - `fresh` callers skip the cache and always start a computation.
+//!
+//! Hits, misses and coalesced calls are counted in the metrics.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+use tokio::runtime::Handle;
+
+use crate::seams;
+
+/// Default query budget
+pub(crate) const DEFAULT_BUDGET_MS: u64 = 250;
+
+/// Bounds of `setQueryTimeoutMs`
+pub(crate) const MIN_BUDGET_MS: u64 = 10;
+pub(crate) const MAX_BUDGET_MS: u64 = 10_000;
+
+/// Default time a result is served again (0 disables the cache)
+pub(crate) const DEFAULT_TTL_MS: u64 = 500;
+
+/// Longest cache TTL of `setQueryCacheTtlMs`
+pub(crate) const MAX_TTL_MS: u64 = 60_000;
+
+static BUDGET_MS: AtomicU64 = AtomicU64::new(DEFAULT_BUDGET_MS);
+
+static TTL_MS: AtomicU64 = AtomicU64::new(DEFAULT_TTL_MS);
+
+/// Runtime the queries are spawned on (set once it is created)
+static HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
+
+/// Queries answered from their snapshot since process start
+static STALE_ANSWERS: AtomicU64 = AtomicU64::new(0);
+
+/// Queries answered from the cache, by starting a computation, and by
+/// joining one in flight, since process start
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+
+/// Make `handle`'s runtime available to queries
+pub(crate) fn attach(handle: Handle) {
+    *HANDLE.lock().unwrap() = Some(handle);
+}
+
+pub(crate) fn set_budget_ms(ms: u64) {
+    BUDGET_MS.store(ms, Ordering::Relaxed);
+}
+
+pub(crate) fn set_ttl_ms(ms: u64) {
+    TTL_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Current query budget
+pub(crate) fn budget() -> Duration {
+    Duration::from_millis(BUDGET_MS.load(Ordering::Relaxed))
+}
+
+/// Count an answer given without the current state
+pub(crate) fn count_stale() {
+    STALE_ANSWERS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn stale_answers() -> u64 {
+    STALE_ANSWERS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn cache_hits() -> u64 {
+    CACHE_HITS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn cache_misses() -> u64 {
+    CACHE_MISSES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn coalesced() -> u64 {
+    COALESCED.load(Ordering::Relaxed)
+}
+
+/// One computation and the callers waiting for it
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<Map<String, Value>>>,
+    done: Condvar,
+}
+
+impl Flight {
+    /// The result, if it arrives within `budget`
+    fn wait(&self, budget: Duration) -> Option<Map<String, Value>> {
+        let result = self.result.lock().unwrap();
+        let (result, _) = self.done.wait_timeout_while(result, budget, |r| r.is_none()).unwrap();
+        result.clone()
+    }
+}
+
+struct Slot {
+    snapshot: Option<Map<String, Value>>,
+    /// When the snapshot was computed, while it may be served from the cache
+    cached_at: Option<Instant>,
+    flight: Option<Arc<Flight>>,
+    /// Bumped by `invalidate`; older computations aren't cached
+    generation: u64,
+}
+
+/// A runtime-backed query with its last snapshot
+pub(crate) struct Query {
+    name: &'static str,
+    fallback: fn() -> Map<String, Value>,
+    slot: Mutex<Slot>,
+}
+
+impl Query {
+    pub(crate) const fn new(name: &'static str, fallback: fn() -> Map<String, Value>) -> Self {
+        Query {
+            name,
+            fallback,
+            slot: Mutex::new(Slot { snapshot: None, cached_at: None, flight: None, generation: 0 }),
+        }
+    }
+
+    fn answer(mut object: Map<String, Value>, stale: bool) -> String {
+        object.insert("stale".to_string(), Value::Bool(stale));
+        Value::Object(object).to_string()
+    }
+
+    /// The snapshot (or the fallback), marked stale
+    fn stale(&self) -> String {
+        count_stale();
+        let object = self.slot.lock().unwrap().snapshot.clone().unwrap_or_else(self.fallback);
+        Self::answer(object, true)
+    }
+
+    /// Drop the cached result; the next call computes a new one
+    pub(crate) fn invalidate(&self) {
+        let mut slot = self.slot.lock().unwrap();
+        slot.generation += 1;
+        slot.cached_at = None;
+        slot.flight = None;
+    }
+
+    /// Keep `object` as the result of `flight` and wake its callers
+    fn finish(&self, flight: &Arc<Flight>, generation: u64, object: Map<String, Value>) {
+        let mut slot = self.slot.lock().unwrap();
+        slot.snapshot = Some(object.clone());
+        if slot.generation == generation {
+            slot.cached_at = Some(seams::now_monotonic());
+        }
+        if slot.flight.as_ref().is_some_and(|f| Arc::ptr_eq(f, flight)) {
+            slot.flight = None;
+        }
+        drop(slot);
+
+        *flight.result.lock().unwrap() = Some(object);
+        flight.done.notify_all();
+    }
+
+    /// Answer with `compute`'s object if it completes within the budget
+    ///
+    /// Unless `fresh`, a cached result or a computation already in flight is
+    /// used instead, and `compute` isn't called.
+    pub(crate) fn run<F>(&'static self, fresh: bool, compute: impl FnOnce() -> F) -> String
+    where
+        F: Future<Output = Map<String, Value>> + Send + 'static,
+    {
+        let mut slot = self.slot.lock().unwrap();
+        if !fresh {
+            if let (Some(at), Some(object)) = (slot.cached_at, slot.snapshot.as_ref()) {
+                let ttl = Duration::from_millis(TTL_MS.load(Ordering::Relaxed));
+                if seams::now_monotonic().saturating_duration_since(at) < ttl {
+                    CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+                    return Self::answer(object.clone(), false);
+                }
+            }
+        }
+
+        let flight = match slot.flight.clone().filter(|_| !fresh) {
+            Some(flight) => {
+                COALESCED.fetch_add(1, Ordering::Relaxed);
+                flight
+            }
+            None => {
+                let Some(handle) = HANDLE.lock().unwrap().clone() else {
+                    drop(slot);
+                    return self.stale();
+                };
+                CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+                let flight = Arc::new(Flight::default());
+                slot.flight = Some(Arc::clone(&flight));
+                let generation = slot.generation;
+                let computation = compute();
+                let finished = Arc::clone(&flight);
+                handle.spawn(async move {
+                    let object = computation.await;
+                    self.finish(&finished, generation, object);
+                });
+                flight
+            }
+        };
+        drop(slot);
+
+        let budget = budget();
+        match flight.wait(budget) {
+            Some(object) => Self::answer(object, false),
+            None => {
+                log_info!(
+                    "{} query exceeded its {} ms budget; answering from the last snapshot",
+                    self.name,
+                    budget.as_millis()
+                );
+                self.stale()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&HANDLE);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+
+    const TEST_BUDGET_MS: u64 = 50;
+
+    fn object(value: u64) -> Map<String, Value> {
+        let mut object = Map::new();
+        object.insert("value".to_string(), json!(value));
+        object
+    }
+
+    fn not_started() -> Map<String, Value> {
+        object(0)
+    }
+
+    fn parse(answer: &str) -> Value {
+        serde_json::from_str(answer).unwrap()
+    }
+
+    /// A one-worker runtime attached for queries, with its budget set
+    struct Attached {
+        runtime: tokio::runtime::Runtime,
+        saved_handle: Option<Handle>,
+        saved_budget_ms: u64,
+    }
+
+    impl Attached {
+        fn new() -> Attached {
+            let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+            let saved_handle = HANDLE.lock().unwrap().replace(runtime.handle().clone());
+            let saved_budget_ms = BUDGET_MS.swap(TEST_BUDGET_MS, Ordering::Relaxed);
+            Attached { runtime, saved_handle, saved_budget_ms }
+        }
+
+        /// Occupy the only worker until the returned sender is dropped
+        fn saturate(&self) -> mpsc::Sender<()> {
+            let (release, released) = mpsc::channel::<()>();
+            let (started, running) = mpsc::channel();
+            self.runtime.spawn(async move {
+                started.send(()).unwrap();
+                let _ = released.recv();
+            });
+            running.recv().unwrap();
+            release
+        }
+    }
+
+    impl Drop for Attached {
+        fn drop(&mut self) {
+            *HANDLE.lock().unwrap() = self.saved_handle.take();
+            BUDGET_MS.store(self.saved_budget_ms, Ordering::Relaxed);
+        }
+    }
+
+    /// Answer of `query` computing `value`, and how long the caller waited
+    fn timed(query: &'static Query, value: u64) -> (Value, Duration) {
+        let started = Instant::now();
+        let answer = query.run(true, move || async move { object(value) });
+        (parse(&answer), started.elapsed())
+    }
+
+    #[test]
+    fn a_saturated_runtime_answers_from_the_snapshot_within_budget() {
+        static QUERY: Query = Query::new("Test", not_started);
+        let _serial = testing::serial();
+        let attached = Attached::new();
+        let stale_before = stale_answers();
+
+        // Never completed: the fallback, marked stale
+        let busy = attached.saturate();
+        let (answer, waited) = timed(&QUERY, 1);
+        assert_eq!(answer, json!({"value": 0, "stale": true}));
+        assert!(waited < Duration::from_millis(4 * TEST_BUDGET_MS), "{:?}", waited);
+
+        // The computation still finishes and refreshes the snapshot
+        drop(busy);
+        let (answer, _) = timed(&QUERY, 2);
+        assert_eq!(answer, json!({"value": 2, "stale": false}));
+
+        // Saturated again: the last result, marked stale
+        let busy = attached.saturate();
+        let (answer, waited) = timed(&QUERY, 3);
+        assert_eq!(answer, json!({"value": 2, "stale": true}));
+        assert!(waited < Duration::from_millis(4 * TEST_BUDGET_MS), "{:?}", waited);
+        assert_eq!(stale_answers() - stale_before, 2);
+        drop(busy);
+    }
+
+    #[test]
+    fn without_a_runtime_the_fallback_is_stale() {
+        static QUERY: Query = Query::new("Test", not_started);
+        let _serial = testing::serial();
+        let saved = HANDLE.lock().unwrap().take();
+
+        let (answer, waited) = timed(&QUERY, 1);
+        assert_eq!(answer, json!({"value": 0, "stale": true}));
+        assert!(waited < Duration::from_millis(TEST_BUDGET_MS));
+
+        *HANDLE.lock().unwrap() = saved;
+    }
+
+    /// A deliberately slow snapshot: each computation is counted and waits
+    /// for the test to release it
+    struct Slow {
+        computations: Arc<AtomicU64>,
+        gates: Arc<Mutex<Vec<tokio::sync::oneshot::Sender<()>>>>,
+    }
+
+    impl Slow {
+        fn new() -> Slow {
+            Slow { computations: Arc::default(), gates: Arc::default() }
+        }
+
+        fn run(&self, query: &'static Query, fresh: bool) -> Value {
+            let computations = Arc::clone(&self.computations);
+            let gates = Arc::clone(&self.gates);
+            parse(&query.run(fresh, move || {
+                let value = computations.fetch_add(1, Ordering::SeqCst) + 1;
+                let (gate, released) = tokio::sync::oneshot::channel();
+                gates.lock().unwrap().push(gate);
+                async move {
+                    let _ = released.await;
+                    object(value)
+                }
+            }))
+        }
+
+        /// Run a call that starts a computation, and let it finish
+        fn computed(&self, query: &'static Query, fresh: bool) -> Value {
+            let started = self.computations();
+            std::thread::scope(|scope| {
+                let caller = scope.spawn(|| self.run(query, fresh));
+                while self.computations() == started {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                self.release();
+                caller.join().unwrap()
+            })
+        }
+
+        /// Let every computation started so far finish
+        fn release(&self) {
+            for gate in self.gates.lock().unwrap().drain(..) {
+                let _ = gate.send(());
+            }
+        }
+
+        fn computations(&self) -> u64 {
+            self.computations.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Hits, misses and coalesced calls since `before`
+    fn counted(before: (u64, u64, u64)) -> (u64, u64, u64) {
+        (cache_hits() - before.0, cache_misses() - before.1, coalesced() - before.2)
+    }
+
+    fn counters() -> (u64, u64, u64) {
+        (cache_hits(), cache_misses(), coalesced())
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_computation() {
+        static QUERY: Query = Query::new("Test", not_started);
+        let _serial = testing::serial();
+        let _attached = Attached::new();
+        BUDGET_MS.store(5_000, Ordering::Relaxed);
+        let _time = testing::FakeTime::start();
+        let slow = Arc::new(Slow::new());
+        let before = counters();
+
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let slow = Arc::clone(&slow);
+                std::thread::spawn(move || slow.run(&QUERY, false))
+            })
+            .collect();
+        // Every caller has joined before the computation finishes
+        while coalesced() - before.2 < 3 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        slow.release();
+        for caller in callers {
+            assert_eq!(caller.join().unwrap(), json!({"value": 1, "stale": false}));
+        }
+        assert_eq!(slow.computations(), 1);
+        assert_eq!(counted(before), (0, 1, 3));
+    }
+
+    #[test]
+    fn results_are_cached_for_the_ttl_unless_fresh() {
+        static QUERY: Query = Query::new("Test", not_started);
+        let _serial = testing::serial();
+        let _attached = Attached::new();
+        BUDGET_MS.store(5_000, Ordering::Relaxed);
+        let time = testing::FakeTime::start();
+        let slow = Slow::new();
+        let before = counters();
+
+        let first = slow.computed(&QUERY, false);
+        assert_eq!(first, json!({"value": 1, "stale": false}));
+
+        // Within the TTL: served again, not computed
+        time.advance(Duration::from_millis(DEFAULT_TTL_MS - 1));
+        assert_eq!(slow.run(&QUERY, false), json!({"value": 1, "stale": false}));
+        assert_eq!(counted(before), (1, 1, 0));
+
+        // A fresh caller always computes, and refreshes the cache
+        let fresh = slow.computed(&QUERY, true);
+        assert_eq!(fresh, json!({"value": 2, "stale": false}));
+        assert_eq!(slow.run(&QUERY, false)["value"], 2);
+
+        // Past the TTL: computed again
+        time.advance(Duration::from_millis(DEFAULT_TTL_MS));
+        let expired = slow.computed(&QUERY, false);
+        assert_eq!(expired["value"], 3);
+        assert_eq!(counted(before), (2, 3, 0));
+    }
+
+    #[test]
+    fn invalidation_drops_the_cache_and_results_computed_before_it() {
+        static QUERY: Query = Query::new("Test", not_started);
+        let _serial = testing::serial();
+        let _attached = Attached::new();
+        BUDGET_MS.store(5_000, Ordering::Relaxed);
+        let _time = testing::FakeTime::start();
+        let slow = Slow::new();
+
+        // A computation in flight when the state changes still answers its
+        // caller, but isn't served to later ones
+        let answer = std::thread::scope(|scope| {
+            let caller = scope.spawn(|| slow.run(&QUERY, false));
+            while slow.computations() < 1 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            QUERY.invalidate();
+            slow.release();
+            caller.join().unwrap()
+        });
+        assert_eq!(answer["value"], 1);
+
+        assert_eq!(slow.computed(&QUERY, false)["value"], 2);
+        // Cached now, until invalidated
+        assert_eq!(slow.run(&QUERY, false)["value"], 2);
+        QUERY.invalidate();
+        assert_eq!(slow.computed(&QUERY, false)["value"], 3);
+    }
+
+    #[test]
+    fn a_lifecycle_transition_invalidates_the_bootstrap_status() {
+        let _serial = testing::serial();
+        let _attached = Attached::new();
+        BUDGET_MS.store(5_000, Ordering::Relaxed);
+        let _time = testing::FakeTime::start();
+        let was = crate::LIFECYCLE_STATE.load(Ordering::SeqCst);
+        crate::BOOTSTRAP_STATUS.invalidate();
+        let before = counters();
+
+        crate::bootstrap_status_json(false);
+        crate::bootstrap_status_json(false);
+        assert_eq!(counted(before), (1, 1, 0));
+
+        let other = if was == crate::LifecycleState::Starting as i32 {
+            crate::LifecycleState::Stopped
+        } else {
+            crate::LifecycleState::Starting
+        };
+        crate::set_state(other);
+        crate::bootstrap_status_json(false);
+        assert_eq!(counted(before), (1, 2, 0));
+
+        crate::LIFECYCLE_STATE.store(was, Ordering::SeqCst);
+        crate::BOOTSTRAP_STATUS.invalidate();
+    }
+
+    #[test]
+    fn every_query_answers_within_budget_on_a_saturated_runtime() {
+        let _serial = testing::serial();
+        let attached = Attached::new();
+        type Answer = fn() -> String;
+        let queries: [(&str, Answer); 1] = [("getBootstrapStatus", || crate::bootstrap_status_json(true))];
+
+        let busy = attached.saturate();
+        for (name, query) in queries {
+            let started = Instant::now();
+            let answer = parse(&query());
+            let waited = started.elapsed();
+            assert_eq!(answer["stale"], true, "{}", name);
+            assert!(waited < Duration::from_millis(4 * TEST_BUDGET_MS), "{}: {:?}", name, waited);
+        }
+        drop(busy);
+    }
+}
@@ -0,0 +1,196 @@
+//! Fast "is direct Tor blocked?" probe
+//!
+//! Drives the "should I enable bridges?" decision without a full bootstrap:
+//! a handful of direct TCP connections to relay OR ports are raced, and any
+//! one completing means the Tor network is directly reachable.
+//!
+//! Candidates are guards from the cached consensus when a client exists, so
+//! the probe looks like an ordinary guard connection, topped up with directory
+//! authorities (which every client contacts when it has no cache). A censor
+//! that blocks by TLS fingerprint rather than by address isn't detected here;
+//! a full bootstrap remains the definitive test.
+//!
+//! Failures are told apart by where they happen: errors raised by the local
+//! stack (no route, network unreachable) mean the device itself is offline and
+//! the answer is `Unknown`; refusals, resets and timeouts on the way to every
+//! candidate mean `Blocked`.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use arti_client::TorClient;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tor_netdir::Timeliness;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::net;
+
+/// Directory authority OR ports, used when no consensus is cached
+const AUTHORITY_OR_PORTS: &[&str] = &[
+    "128.31.0.39:9201",
+    "217.196.147.77:443",
+    "45.66.35.11:443",
+    "131.188.40.189:443",
+    "193.23.244.244:443",
+    "171.25.193.9:80",
+    "199.58.81.140:443",
+    "204.13.164.118:443",
+    "216.218.219.41:443",
+];
+
+/// Connections raced per probe
+const CANDIDATES: usize = 4;
+
+/// Probe outcome (the discriminant is the JNI return code)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reachability {
+    Reachable = 0,
+    Blocked = 1,
+    Unknown = 2,
+}
+
+/// Pick `count` items from `items` at random (order of picks is random too)
+fn pick<T: Clone>(items: &[T], count: usize) -> Vec<T> {
+    let mut pool = items.to_vec();
+    let mut picked = Vec::new();
+    while picked.len() < count && !pool.is_empty() {
+        let index = (crate::seams::random_u64() % pool.len() as u64) as usize;
+        picked.push(pool.swap_remove(index));
+    }
+    picked
+}
+
+/// Addresses to probe: cached guards first, then authorities
+fn candidates(client: Option<&TorClient<PreferredRuntime>>) -> Vec<SocketAddr> {
+    let guards: Vec<SocketAddr> = client
+        .and_then(|c| c.dirmgr().netdir(Timeliness::Unchecked).ok())
+        .map(|netdir| {
+            netdir
+                .relays()
+                .filter(|relay| relay.is_flagged_guard())
+                .filter_map(|relay| relay.addrs().iter().find(|a| a.is_ipv4()).copied())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let authorities: Vec<SocketAddr> = AUTHORITY_OR_PORTS.iter().filter_map(|a| a.parse().ok()).collect();
+
+    let mut picked = pick(&guards, CANDIDATES / 2);
+    picked.extend(pick(&authorities, CANDIDATES - picked.len()));
+    picked
+}
+
+/// Whether a connect error comes from the device's own network stack
+fn is_local(error: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        Some(ErrorKind::NetworkUnreachable | ErrorKind::HostUnreachable | ErrorKind::NetworkDown | ErrorKind::AddrNotAvailable)
+    )
+}
+
+/// Race direct connections to a few relays, giving up after `timeout`
+///
+/// Callers must check `net::ensure_direct_allowed` first.
+pub(crate) async fn probe(client: Option<&TorClient<PreferredRuntime>>, timeout: Duration) -> Reachability {
+    race(&candidates(client), timeout).await
+}
+
+/// Race direct connections to `targets`
+async fn race(targets: &[SocketAddr], timeout: Duration) -> Reachability {
+    let mut attempts: FuturesUnordered<_> = targets
+        .iter()
+        .map(|addr| net::connect_tcp(*addr, "reachability_probe"))
+        .collect();
+
+    let mut local_failures = 0;
+    let raced = tokio::time::timeout(timeout, async {
+        while let Some(result) = attempts.next().await {
+            match result {
+                // The connection is dropped at once; nothing is sent
+                Ok(_stream) => return true,
+                Err(e) => {
+                    log_info!("Reachability probe connect failed: {}", e);
+                    if is_local(&e) {
+                        local_failures += 1;
+                    }
+                }
+            }
+        }
+        false
+    })
+    .await;
+
+    match raced {
+        Ok(true) => Reachability::Reachable,
+        _ if targets.is_empty() || local_failures == targets.len() => Reachability::Unknown,
+        _ => Reachability::Blocked,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use super::*;
+    use crate::testing;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A loopback address nothing listens on (connections are refused)
+    fn refusing() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn any_relay_answering_means_reachable() {
+        let _serial = testing::serial();
+        // Strict builds refuse every direct connect, so not even a listening
+        // relay answers (callers check `ensure_direct_allowed` before probing)
+        let answering = match cfg!(feature = "strict-tor-only") {
+            true => Reachability::Blocked,
+            false => Reachability::Reachable,
+        };
+        testing::runtime().block_on(async {
+            let relay = net::bind_listener("127.0.0.1:0").await.unwrap();
+            let open = relay.local_addr().unwrap();
+            for (targets, expected) in [
+                (vec![open], answering),
+                (vec![refusing(), refusing(), open], answering),
+                // Every relay refused: direct Tor is blocked
+                (vec![refusing(), refusing()], Reachability::Blocked),
+                (vec![], Reachability::Unknown),
+            ] {
+                assert_eq!(race(&targets, TIMEOUT).await, expected, "{:?}", targets);
+            }
+        });
+    }
+
+    #[test]
+    fn local_stack_errors_are_told_apart() {
+        for (kind, local) in [
+            (ErrorKind::NetworkUnreachable, true),
+            (ErrorKind::HostUnreachable, true),
+            (ErrorKind::NetworkDown, true),
+            (ErrorKind::AddrNotAvailable, true),
+            (ErrorKind::ConnectionRefused, false),
+            (ErrorKind::ConnectionReset, false),
+            (ErrorKind::TimedOut, false),
+        ] {
+            assert_eq!(is_local(&anyhow::Error::new(Error::from(kind))), local, "{:?}", kind);
+        }
+        assert!(!is_local(&anyhow::Error::new(net::StrictModeBlocked { purpose: "test" })));
+    }
+
+    #[test]
+    fn authorities_fill_in_without_a_consensus() {
+        let _serial = testing::serial();
+        let picked = candidates(None);
+        assert_eq!(picked.len(), CANDIDATES);
+        let mut distinct = picked.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), CANDIDATES);
+        assert!(picked.iter().all(|addr| AUTHORITY_OR_PORTS.contains(&addr.to_string().as_str())));
+    }
+}
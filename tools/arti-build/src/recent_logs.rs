@@ -0,0 +1,131 @@
+//! Ring buffer of the most recent log lines
+//!
+//! Kept independently of the Java log callback so a "copy diagnostics"
+//! action gets the lines logged before the callback was registered too.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::json_string;
+use crate::redact;
+use crate::retention::{self, Sink};
+
+/// Default number of lines retained
+pub(crate) const DEFAULT_CAPACITY: usize = 200;
+
+/// Largest capacity accepted from the Java layer
+pub(crate) const MAX_CAPACITY: usize = 10_000;
+
+struct Ring {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    lines: VecDeque::new(),
+    capacity: DEFAULT_CAPACITY,
+});
+
+/// Append a line, evicting the oldest once at capacity
+pub(crate) fn push(line: &str) {
+    let mut ring = RING.lock().unwrap();
+    if ring.capacity == 0 || !retention::retains(Sink::RecentLogs) {
+        return;
+    }
+    while ring.lines.len() >= ring.capacity {
+        ring.lines.pop_front();
+    }
+    ring.lines.push_back(line.to_string());
+}
+
+/// Change how many lines are retained, dropping the oldest if shrinking
+pub(crate) fn set_capacity(capacity: usize) {
+    let mut ring = RING.lock().unwrap();
+    ring.capacity = capacity.min(MAX_CAPACITY);
+    while ring.lines.len() > ring.capacity {
+        ring.lines.pop_front();
+    }
+}
+
+/// Drop every retained line
+pub(crate) fn clear() {
+    RING.lock().unwrap().lines.clear();
+}
+
+/// Retained lines as emitted now, oldest first
+///
+/// Lines are kept as logged, so the safe-logging policy applies when they
+/// are read (see redact.rs).
+pub(crate) fn lines() -> Vec<String> {
+    RING.lock().unwrap().lines.iter().map(|l| redact::render(l).into_owned()).collect()
+}
+
+/// Retained lines as a JSON array, oldest first
+pub(crate) fn to_json() -> String {
+    let ring = RING.lock().unwrap();
+    let lines = ring.lines.iter().map(|l| json_string(&redact::render(l))).collect::<Vec<_>>();
+    format!("[{}]", lines.join(","))
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&RING);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Start from an empty ring with `capacity`
+    fn reset(capacity: usize) {
+        set_capacity(capacity);
+        clear();
+    }
+
+    #[test]
+    fn keeps_the_most_recent_lines() {
+        let _serial = testing::serial();
+        reset(3);
+        for i in 0..10 {
+            push(&format!("line {}", i));
+        }
+        assert_eq!(lines(), ["line 7", "line 8", "line 9"]);
+        reset(DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn capacity_changes_apply_to_retained_lines() {
+        let _serial = testing::serial();
+        reset(5);
+        for i in 0..5 {
+            push(&format!("line {}", i));
+        }
+        set_capacity(2);
+        assert_eq!(lines(), ["line 3", "line 4"]);
+
+        // Zero turns the ring off
+        set_capacity(0);
+        push("dropped");
+        assert!(lines().is_empty());
+
+        set_capacity(usize::MAX);
+        assert_eq!(RING.lock().unwrap().capacity, MAX_CAPACITY);
+        reset(DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn json_is_escaped_and_redacted_when_read() {
+        let _serial = testing::serial();
+        reset(DEFAULT_CAPACITY);
+        push("quote \" and\nnewline");
+        push(&format!("dialing {}", redact::Sensitive("example.com")));
+
+        let parsed: Vec<String> = serde_json::from_str(&to_json()).unwrap();
+        assert_eq!(parsed, ["quote \" and\nnewline", "dialing [scrubbed]"]);
+        redact::set_safe_logging(false);
+        assert_eq!(lines()[1], "dialing example.com");
+        redact::set_safe_logging(true);
+        reset(DEFAULT_CAPACITY);
+    }
+}
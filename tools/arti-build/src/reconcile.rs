@@ -0,0 +1,142 @@
+//! Repeated `startSocksProxy` for a listener that is already running
+//!
+//! After Android restores the service from saved state, the Kotlin side may
+//! believe the proxy is down and call `startSocksProxy(port)` while the
+//! native listener on that port is still healthy. A rebind then gains
+//! nothing and interrupts accepting. So each start records what its listener
+//! was started with, and a start whose request matches the live listener
+//! returns at once (`reconciled` in `getLastStartResult`; `coalesced` too
+//! when it took back a pending stop, see debounce.rs):
+//!
+//! - the bind address (the port; the host is always loopback);
+//! - the Tor client the accept loop hands connections to, which a new
+//!   `initialize` may have replaced.
+//!
+//! Listener authentication and the isolation modes are read by each
+//! handshake and connection, so changing them takes effect on the running
+//! listener and never calls for a rebind. A listener whose task has ended
+//! never matches.
+
+use std::sync::{Arc, Mutex};
+
+use arti_client::TorClient;
+use serde_json::json;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::notification::now_epoch_ms;
+use crate::retention;
+
+/// What a listener was started with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Spec {
+    addr: String,
+    /// Identity of the client (not dereferenced; the running task keeps it
+    /// alive, so the address can't be reused while the listener runs)
+    client: usize,
+}
+
+impl Spec {
+    pub(crate) fn new(addr: &str, client: &Arc<TorClient<PreferredRuntime>>) -> Self {
+        Spec { addr: addr.to_string(), client: Arc::as_ptr(client) as usize }
+    }
+}
+
+/// Spec of the most recently started listener
+static LIVE: Mutex<Option<Spec>> = Mutex::new(None);
+
+struct Start {
+    port: i32,
+    reconciled: bool,
+    coalesced: bool,
+    at_epoch_ms: u64,
+}
+
+static LAST_START: Mutex<Option<Start>> = Mutex::new(None);
+
+/// Record the spec of a newly bound listener
+pub(crate) fn started(spec: Spec) {
+    *LIVE.lock().unwrap() = Some(spec);
+}
+
+/// Whether `spec` matches the last started listener (its task must be checked
+/// to be running separately)
+pub(crate) fn matches(spec: &Spec) -> bool {
+    LIVE.lock().unwrap().as_ref() == Some(spec)
+}
+
+/// Record the outcome of a successful `startSocksProxy`
+pub(crate) fn record_start(port: i32, reconciled: bool, coalesced: bool) {
+    *LAST_START.lock().unwrap() = Some(Start { port, reconciled, coalesced, at_epoch_ms: now_epoch_ms() });
+}
+
+/// The last successful start as JSON, or `null` before the first
+pub(crate) fn last_start_json() -> String {
+    match LAST_START.lock().unwrap().as_ref() {
+        Some(start) => json!({
+            "port": start.port,
+            "reconciled": start.reconciled,
+            "coalesced": start.coalesced,
+            "at_epoch_ms": retention::timestamp(start.at_epoch_ms),
+        })
+        .to_string(),
+        None => "null".to_string(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&LIVE);
+    held.hold(&LAST_START);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn spec(addr: &str, client: usize) -> Spec {
+        Spec { addr: addr.to_string(), client }
+    }
+
+    #[test]
+    fn only_the_same_address_and_client_match() {
+        let _serial = testing::serial();
+        let saved = LIVE.lock().unwrap().take();
+
+        // No listener started yet
+        assert!(!matches(&spec("127.0.0.1:9150", 1)));
+
+        started(spec("127.0.0.1:9150", 1));
+        for (request, matching) in [
+            (spec("127.0.0.1:9150", 1), true),
+            (spec("127.0.0.1:9151", 1), false),
+            (spec("127.0.0.1:9150", 2), false),
+            (spec("127.0.0.1:9151", 2), false),
+        ] {
+            assert_eq!(matches(&request), matching, "{:?}", request);
+        }
+
+        // The latest start is what counts
+        started(spec("127.0.0.1:9151", 2));
+        assert!(!matches(&spec("127.0.0.1:9150", 1)));
+        assert!(matches(&spec("127.0.0.1:9151", 2)));
+
+        *LIVE.lock().unwrap() = saved;
+    }
+
+    #[test]
+    fn the_last_start_is_reported() {
+        let _serial = testing::serial();
+        let saved = LAST_START.lock().unwrap().take();
+        assert_eq!(last_start_json(), "null");
+
+        for (reconciled, coalesced) in [(false, false), (true, false), (true, true)] {
+            record_start(9150, reconciled, coalesced);
+            let report: serde_json::Value = serde_json::from_str(&last_start_json()).unwrap();
+            assert_eq!(report["port"], 9150);
+            assert_eq!((report["reconciled"].as_bool(), report["coalesced"].as_bool()), (Some(reconciled), Some(coalesced)));
+        }
+
+        *LAST_START.lock().unwrap() = saved;
+    }
+}
@@ -0,0 +1,150 @@
+//! Safe logging: redaction of sensitive log fields at each sink
+//!
+//! A log line is formatted when it is logged but reaches some sinks later:
+//! the log file's writer thread takes it from a queue, and the recent-log
+//! ring is read by exports long afterwards. Deciding redaction at format
+//! time would let a line formatted with safe logging off still come out
+//! unredacted after it was turned back on. So producers don't decide:
+//! sensitive values (destination hosts, peer and dial addresses) go into the
+//! line wrapped in `Sensitive`, which only marks them, and every sink calls
+//! `render` at the moment it emits the line:
+//!
+//! - logcat and the Java log callback as the line is delivered;
+//! - the log file in its writer thread, as the line is written;
+//! - the recent-log ring when it is read (`getRecentLogs`, diagnostics
+//!   export, callback replay);
+//! - the last failure record and its debug context when read
+//!   (`getLastError`, diagnostics export).
+//!
+//! With safe logging on (the default, `setSafeLogging`) a marked value is
+//! shown as `[scrubbed]`, off it is shown as is; either way the change
+//! applies to every line not yet emitted. Turning it off in a release build
+//! is audited like logcat logging. Arti's own tracing lines follow Arti's
+//! safe logging and carry no marks.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Opens and closes a marked value (Unicode private use, never in log text)
+const START: char = '\u{E000}';
+const END: char = '\u{E001}';
+
+/// What a marked value becomes under safe logging
+const SCRUBBED: &str = "[scrubbed]";
+
+static SAFE_LOGGING: AtomicBool = AtomicBool::new(true);
+
+pub(crate) fn set_safe_logging(enabled: bool) {
+    SAFE_LOGGING.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn safe_logging() -> bool {
+    SAFE_LOGGING.load(Ordering::Relaxed)
+}
+
+/// A sensitive log field, marked for the sinks to redact
+pub(crate) struct Sensitive<T>(pub(crate) T);
+
+impl<T: fmt::Display> fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", START, self.0, END)
+    }
+}
+
+/// `line` as a sink emits it now: marked values scrubbed or unmarked
+pub(crate) fn render(line: &str) -> Cow<'_, str> {
+    if !line.contains(START) {
+        return Cow::Borrowed(line);
+    }
+    let safe = safe_logging();
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(START) {
+        out.push_str(&rest[..start]);
+        let value = &rest[start + START.len_utf8()..];
+        let (value, after) = match value.find(END) {
+            Some(end) => (&value[..end], &value[end + END.len_utf8()..]),
+            None => (value, ""),
+        };
+        out.push_str(if safe { SCRUBBED } else { value });
+        rest = after;
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Safe logging as given for one test, restored after
+    struct Policy(bool);
+
+    impl Policy {
+        fn set(safe: bool) -> Policy {
+            let was = safe_logging();
+            set_safe_logging(safe);
+            Policy(was)
+        }
+    }
+
+    impl Drop for Policy {
+        fn drop(&mut self) {
+            set_safe_logging(self.0);
+        }
+    }
+
+    #[test]
+    fn marked_values_are_scrubbed_or_shown() {
+        let _serial = testing::serial();
+        let line = format!("connect to {}:443 from {}", Sensitive("example.com"), Sensitive("127.0.0.1:5555"));
+        // (safe logging, rendered)
+        for (safe, rendered) in [
+            (true, "connect to [scrubbed]:443 from [scrubbed]"),
+            (false, "connect to example.com:443 from 127.0.0.1:5555"),
+        ] {
+            let _policy = Policy::set(safe);
+            assert_eq!(render(&line), rendered);
+            assert_eq!(render("nothing marked"), "nothing marked");
+            assert!(matches!(render("nothing marked"), Cow::Borrowed(_)));
+            // A value cut off mid-mark is still never shown whole under safe logging
+            let truncated = &line[..line.find(':').unwrap()];
+            assert_eq!(render(truncated), if safe { "connect to [scrubbed]" } else { "connect to example.com" });
+        }
+    }
+
+    #[test]
+    fn retained_lines_follow_the_policy_when_read() {
+        let _serial = testing::serial();
+        let _saved = crate::config::save();
+        crate::recent_logs::clear();
+        let host = "retained.example";
+
+        // Logged with safe logging off, then it is turned back on
+        let policy = Policy::set(false);
+        log_info!("connecting to {}", Sensitive(host));
+        crate::diagnostics::record_failure("bootstrap_failed", format!("no route to {}", Sensitive(host)));
+        assert!(crate::recent_logs::to_json().contains(host));
+        set_safe_logging(true);
+
+        let ring = crate::recent_logs::lines();
+        assert!(ring.iter().any(|line| line.contains("connecting to [scrubbed]")), "{:?}", ring);
+        for emitted in [crate::recent_logs::to_json(), crate::diagnostics::last_error_json(), ring.join("\n")] {
+            assert!(!emitted.contains(host), "{}", emitted);
+            assert!(!emitted.contains(START) && !emitted.contains(END));
+        }
+        assert!(crate::diagnostics::last_error_json().contains("no route to [scrubbed]"));
+
+        // Delivered lines follow the policy in effect as they are delivered
+        crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+        log_info!("connecting again to {}", Sensitive(host));
+        set_safe_logging(false);
+        log_info!("and again to {}", Sensitive(host));
+        let sent = crate::tests::SENT_LOG_LINES.lock().unwrap().clone();
+        assert!(sent.iter().any(|line| line.contains("connecting again to [scrubbed]")), "{:?}", sent);
+        assert!(sent.iter().any(|line| line.contains("and again to retained.example")), "{:?}", sent);
+        drop(policy);
+    }
+}
@@ -0,0 +1,576 @@
+//! Lifecycle history across sessions, folded into reliability figures
+//!
+//! "Tor was available 97% of the time this month" needs history that outlives
+//! the process. Every lifecycle transition is appended to a bounded journal
+//! under `wrapper/session-log`, one `<epoch_ms> <record>` line each, where the
+//! record is a state name or one of:
+//!
+//! - `open`: this process started using the data directory;
+//! - `end`: the previous process was last seen alive (from
+//!   `wrapper/session-alive`, which the heartbeat rewrites every
+//!   `ALIVE_INTERVAL`), written at the next `open` since a killed process
+//!   can't write it itself;
+//! - `bootstrapped`: initialize finished bootstrapping.
+//!
+//! `getReliabilityStats(days)` folds the journal over the window:
+//!
+//! - time in `Running` counts as ready; `Initialized`, `Starting`,
+//!   `StorageLost` and `DegradedStaleDirectory` as degraded (the wrapper is
+//!   up but not serving as configured); `Stopped`, `Stopping` and the gaps
+//!   between sessions as stopped. Time before the first record in the window
+//!   isn't counted at all;
+//! - a bootstrap is an `Initialized` record, timed up to its `bootstrapped`;
+//! - a recovery is leaving `StorageLost` for anything but stopping;
+//! - a record older than the one before it means the wall clock was set
+//!   back: the segment between them is dropped and counted in `clock_jumps`.
+//!
+//! The journal keeps at most `MAX_RECORDS` lines, so a device that changes
+//! state very often covers less than the longest window. Timestamps follow
+//! the retention level, and the minimal level keeps no journal at all.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+use crate::retention::{self, Sink};
+use crate::LifecycleState;
+
+/// Longest window of `getReliabilityStats`
+pub(crate) const MAX_DAYS: u64 = 90;
+
+/// Journal lines kept at most, and kept after trimming
+const MAX_RECORDS: usize = 4096;
+const KEEP_RECORDS: usize = 3072;
+
+/// How often the heartbeat marks this process as alive
+const ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Record {
+    Open,
+    End,
+    Bootstrapped,
+    State(LifecycleState),
+}
+
+impl Record {
+    fn encode(self) -> &'static str {
+        match self {
+            Record::Open => "open",
+            Record::End => "end",
+            Record::Bootstrapped => "bootstrapped",
+            Record::State(state) => state.name(),
+        }
+    }
+
+    fn decode(text: &str) -> Option<Record> {
+        match text {
+            "open" => Some(Record::Open),
+            "end" => Some(Record::End),
+            "bootstrapped" => Some(Record::Bootstrapped),
+            name => (0..=6).map(LifecycleState::from_i32).find(|s| s.name() == name).map(Record::State),
+        }
+    }
+}
+
+struct Journal {
+    path: PathBuf,
+    alive_path: PathBuf,
+    lines: usize,
+    last_alive_ms: u64,
+}
+
+static JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+
+/// Records kept at most before the journal is opened
+const MAX_EARLY: usize = 64;
+
+/// Records made before the journal was opened (initialize reports
+/// `Initialized` before it knows the data directory)
+static EARLY: Mutex<Vec<(u64, Record)>> = Mutex::new(Vec::new());
+
+fn read(path: &Path) -> Vec<(u64, Record)> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (at, record) = line.split_once(' ')?;
+            Some((at.parse().ok()?, Record::decode(record)?))
+        })
+        .collect()
+}
+
+fn line(at: u64, record: Record) -> String {
+    format!("{} {}\n", retention::timestamp(at), record.encode())
+}
+
+/// Append `records`, trimming the journal to `KEEP_RECORDS` once it is full
+fn append(journal: &mut Journal, records: &[(u64, Record)]) {
+    if records.is_empty() || !retention::retains(Sink::SessionHistory) {
+        return;
+    }
+    let text: String = records.iter().map(|(at, record)| line(*at, *record)).collect();
+    let appended = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal.path)
+        .and_then(|mut file| file.write_all(text.as_bytes()));
+    if let Err(e) = appended {
+        log_error!("Failed to append to the session log: {}", e);
+        return;
+    }
+    journal.lines += records.len();
+    if journal.lines > MAX_RECORDS {
+        let kept = read(&journal.path);
+        let kept = &kept[kept.len().saturating_sub(KEEP_RECORDS)..];
+        let text: String = kept.iter().map(|(at, record)| line(*at, *record)).collect();
+        match layout::write_atomic(&journal.path, text.as_bytes()) {
+            Ok(()) => journal.lines = kept.len(),
+            Err(e) => log_error!("Failed to trim the session log: {}", e),
+        }
+    }
+}
+
+fn record(record: Record) {
+    let at = now_epoch_ms();
+    match JOURNAL.lock().unwrap().as_mut() {
+        Some(journal) => append(journal, &[(at, record)]),
+        None => {
+            let mut early = EARLY.lock().unwrap();
+            if early.len() < MAX_EARLY {
+                early.push((at, record));
+            }
+        }
+    }
+}
+
+/// Record a lifecycle transition (called by `set_state`)
+pub(crate) fn state_changed(state: LifecycleState) {
+    record(Record::State(state));
+}
+
+/// Record that initialize finished bootstrapping
+pub(crate) fn bootstrapped() {
+    record(Record::Bootstrapped);
+}
+
+/// Use the journal under `data_dir`, closing the previous session in it
+pub(crate) fn open(data_dir: &Path) {
+    let path = layout::path(data_dir, Purpose::SessionLog);
+    let mut guard = JOURNAL.lock().unwrap();
+    if guard.as_ref().is_some_and(|journal| journal.path == path) {
+        return;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).ok();
+    }
+    let alive_path = layout::path(data_dir, Purpose::SessionAlive);
+    let mut journal = Journal { lines: read(&path).len(), path, alive_path, last_alive_ms: 0 };
+
+    let early = std::mem::take(&mut *EARLY.lock().unwrap());
+    let now = now_epoch_ms();
+    let opened_at = early.first().map_or(now, |(at, _)| (*at).min(now));
+    let mut records = Vec::new();
+    let last_alive = std::fs::read_to_string(&journal.alive_path).ok().and_then(|text| text.trim().parse().ok());
+    if let Some(last_alive) = last_alive {
+        records.push((u64::min(last_alive, opened_at), Record::End));
+    }
+    records.push((opened_at, Record::Open));
+    records.extend(early);
+    append(&mut journal, &records);
+    *guard = Some(journal);
+}
+
+/// Mark this process as alive, at most every `ALIVE_INTERVAL` (heartbeat)
+pub(crate) fn tick() {
+    if !retention::retains(Sink::SessionHistory) {
+        return;
+    }
+    let mut guard = JOURNAL.lock().unwrap();
+    let Some(journal) = guard.as_mut() else {
+        return;
+    };
+    let now = now_epoch_ms();
+    if now.saturating_sub(journal.last_alive_ms) < ALIVE_INTERVAL.as_millis() as u64 {
+        return;
+    }
+    journal.last_alive_ms = now;
+    if let Err(e) = layout::write_atomic(&journal.alive_path, retention::timestamp(now).to_string().as_bytes()) {
+        log_error!("Failed to mark the session alive: {}", e);
+    }
+}
+
+/// Delete the journal and the alive mark (retention.rs)
+pub(crate) fn purge() {
+    let mut guard = JOURNAL.lock().unwrap();
+    let Some(journal) = guard.as_mut() else {
+        return;
+    };
+    for path in [&journal.path, &journal.alive_path] {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log_error!("Failed to delete the session log: {}", e);
+            }
+        }
+    }
+    journal.lines = 0;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Ready,
+    Degraded,
+    Stopped,
+}
+
+fn condition(state: LifecycleState) -> Condition {
+    match state {
+        LifecycleState::Running => Condition::Ready,
+        LifecycleState::Initialized
+        | LifecycleState::Starting
+        | LifecycleState::StorageLost
+        | LifecycleState::DegradedStaleDirectory => Condition::Degraded,
+        LifecycleState::Stopped | LifecycleState::Stopping => Condition::Stopped,
+    }
+}
+
+#[derive(Default)]
+struct Fold {
+    ready_ms: u64,
+    degraded_ms: u64,
+    stopped_ms: u64,
+    sessions: u64,
+    bootstraps: u64,
+    timed_bootstraps: u64,
+    bootstrap_ms_total: u64,
+    recoveries: u64,
+    clock_jumps: u64,
+}
+
+impl Fold {
+    /// Count the part of `since..until` inside `from..now` as `condition`
+    fn add(&mut self, condition: Condition, since: u64, until: u64, from: u64, now: u64) {
+        let ms = until.min(now).saturating_sub(since.max(from));
+        match condition {
+            Condition::Ready => self.ready_ms += ms,
+            Condition::Degraded => self.degraded_ms += ms,
+            Condition::Stopped => self.stopped_ms += ms,
+        }
+    }
+}
+
+/// Fold `records` (oldest first) over `from..now`; the last condition lasts
+/// until `now`, since the last session is this process
+fn fold(records: &[(u64, Record)], from: u64, now: u64) -> Fold {
+    let mut fold = Fold::default();
+    let mut current: Option<(Condition, u64)> = None;
+    let mut last_state = None;
+    let mut bootstrap_started = None;
+    for &(at, record) in records {
+        if let Some((condition, since)) = current {
+            if at < since {
+                fold.clock_jumps += 1;
+            } else {
+                fold.add(condition, since, at, from, now);
+            }
+        }
+        let in_window = (from..=now).contains(&at);
+        let condition = match record {
+            Record::Open | Record::End => {
+                fold.sessions += (record == Record::Open && in_window) as u64;
+                last_state = None;
+                bootstrap_started = None;
+                Condition::Stopped
+            }
+            Record::Bootstrapped => {
+                if let Some(started) = bootstrap_started.take() {
+                    if in_window && at >= started {
+                        fold.timed_bootstraps += 1;
+                        fold.bootstrap_ms_total += at - started;
+                    }
+                }
+                current.map_or(Condition::Degraded, |(condition, _)| condition)
+            }
+            Record::State(state) => {
+                if state == LifecycleState::Initialized {
+                    fold.bootstraps += in_window as u64;
+                    bootstrap_started = Some(at);
+                }
+                if last_state == Some(LifecycleState::StorageLost)
+                    && condition(state) != Condition::Stopped
+                    && state != LifecycleState::StorageLost
+                {
+                    fold.recoveries += in_window as u64;
+                }
+                last_state = Some(state);
+                condition(state)
+            }
+        };
+        current = Some((condition, at));
+    }
+    if let Some((condition, since)) = current {
+        fold.add(condition, since, now, from, now);
+    }
+    fold
+}
+
+/// Reliability over the last `days` days as JSON (`getReliabilityStats`)
+pub(crate) fn stats_json(days: u64) -> String {
+    let path = JOURNAL.lock().unwrap().as_ref().map(|journal| journal.path.clone());
+    let records = path.map(|path| read(&path)).unwrap_or_default();
+    let now = now_epoch_ms();
+    let from = now.saturating_sub(days * DAY_MS);
+    let fold = fold(&records, from, now);
+    let covered_ms = fold.ready_ms + fold.degraded_ms + fold.stopped_ms;
+    json!({
+        "days": days,
+        "from_epoch_ms": retention::timestamp(from),
+        "covered_ms": covered_ms,
+        "ready_ms": fold.ready_ms,
+        "degraded_ms": fold.degraded_ms,
+        "stopped_ms": fold.stopped_ms,
+        "ready_percent": (covered_ms > 0).then(|| fold.ready_ms as f64 * 100.0 / covered_ms as f64),
+        "sessions": fold.sessions,
+        "bootstraps": fold.bootstraps,
+        "avg_bootstrap_ms": (fold.timed_bootstraps > 0).then(|| fold.bootstrap_ms_total / fold.timed_bootstraps),
+        "recoveries": fold.recoveries,
+        "clock_jumps": fold.clock_jumps,
+    })
+    .to_string()
+}
+
+/// Stop using the open data directory (tests open temporary ones)
+#[cfg(test)]
+pub(crate) fn close() {
+    *JOURNAL.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&JOURNAL);
+    held.hold(&EARLY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+
+    use LifecycleState::*;
+
+    const S: fn(LifecycleState) -> Record = Record::State;
+
+    /// Takes the journal out for a test and puts it back on drop
+    struct Isolated {
+        journal: Option<Journal>,
+        early: Vec<(u64, Record)>,
+        level: u8,
+    }
+
+    impl Isolated {
+        fn new() -> Isolated {
+            Isolated {
+                journal: JOURNAL.lock().unwrap().take(),
+                early: std::mem::take(&mut *EARLY.lock().unwrap()),
+                level: retention::level() as u8,
+            }
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            retention::set_level(self.level);
+            *JOURNAL.lock().unwrap() = self.journal.take();
+            *EARLY.lock().unwrap() = std::mem::take(&mut self.early);
+        }
+    }
+
+    fn journal_path() -> PathBuf {
+        JOURNAL.lock().unwrap().as_ref().unwrap().path.clone()
+    }
+
+    #[test]
+    fn folding_covers_sessions_windows_and_jumps() {
+        let two_sessions = [
+            (2000, Record::Open),
+            (2000, S(Initialized)),
+            (2500, Record::Bootstrapped),
+            (3000, S(Starting)),
+            (3500, S(Running)),
+            (6000, Record::End),
+            (7000, Record::Open),
+            (7000, S(Initialized)),
+            (7400, Record::Bootstrapped),
+            (7400, S(Running)),
+            (8000, S(StorageLost)),
+            (8200, S(Running)),
+        ];
+        let jump = [
+            (2000, Record::Open),
+            (2000, S(Running)),
+            (6000, S(Stopping)),
+            (4000, S(Stopped)),
+            (5000, S(Running)),
+        ];
+        let lost_then_stopped = [
+            (2000, Record::Open),
+            (2000, S(Running)),
+            (3000, S(StorageLost)),
+            (4000, S(Stopping)),
+            (5000, S(Stopped)),
+        ];
+        let bootstrap_cut_by_a_restart = [
+            (2000, Record::Open),
+            (2000, S(Initialized)),
+            (3000, Record::End),
+            (4000, Record::Open),
+            (4500, Record::Bootstrapped),
+        ];
+        // (ready, degraded, stopped), sessions, bootstraps, (timed, total
+        // ms), recoveries, clock jumps
+        type Expected = ((u64, u64, u64), u64, u64, (u64, u64), u64, u64);
+        type Records<'a> = &'a [(u64, Record)];
+        let cases: [(&str, Records, u64, Expected); 6] = [
+            ("two sessions", &two_sessions, 1000, ((4900, 2100, 1000), 2, 2, (2, 900), 1, 0)),
+            ("window from mid-session", &two_sessions, 5000, ((3400, 600, 1000), 1, 1, (1, 400), 1, 0)),
+            ("clock set back", &jump, 1000, ((9000, 0, 1000), 1, 0, (0, 0), 0, 1)),
+            ("lost storage then stopped", &lost_then_stopped, 1000, ((1000, 1000, 6000), 1, 0, (0, 0), 0, 0)),
+            ("bootstrap cut by a restart", &bootstrap_cut_by_a_restart, 1000, ((0, 1000, 7000), 2, 1, (0, 0), 0, 0)),
+            ("no records", &[], 1000, ((0, 0, 0), 0, 0, (0, 0), 0, 0)),
+        ];
+        for (name, records, from, expected) in cases {
+            let fold = fold(records, from, 10_000);
+            let got = (
+                (fold.ready_ms, fold.degraded_ms, fold.stopped_ms),
+                fold.sessions,
+                fold.bootstraps,
+                (fold.timed_bootstraps, fold.bootstrap_ms_total),
+                fold.recoveries,
+                fold.clock_jumps,
+            );
+            assert_eq!(got, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn records_round_trip_and_bad_lines_are_skipped() {
+        for record in [Record::Open, Record::End, Record::Bootstrapped]
+            .into_iter()
+            .chain((0..=6).map(|n| S(LifecycleState::from_i32(n))))
+        {
+            assert_eq!(Record::decode(record.encode()), Some(record));
+        }
+        assert_eq!(Record::decode("Sleeping"), None);
+
+        let temp = TempDir::new();
+        let path = temp.path().join("session-log");
+        std::fs::write(&path, "100 open\nnot-a-time open\n200 Sleeping\n300\n400 Running\n").unwrap();
+        assert_eq!(read(&path), [(100, Record::Open), (400, S(Running))]);
+    }
+
+    #[test]
+    fn a_killed_session_ends_at_its_last_alive_mark() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        retention::set_level(0);
+        let time = FakeTime::start();
+        let temp = TempDir::new();
+
+        open(temp.path());
+        let first_open = now_epoch_ms();
+        tick();
+        time.advance(Duration::from_secs(30));
+        // Within the interval the mark isn't rewritten
+        tick();
+        time.advance(ALIVE_INTERVAL);
+        tick();
+        let last_alive = now_epoch_ms();
+        state_changed(Running);
+
+        // Killed; the next process reports a state before opening
+        *JOURNAL.lock().unwrap() = None;
+        time.advance(Duration::from_secs(3600));
+        let second_open = now_epoch_ms();
+        state_changed(Initialized);
+        time.advance(Duration::from_secs(5));
+        open(temp.path());
+        // Opening the same directory again changes nothing
+        open(temp.path());
+
+        assert_eq!(
+            read(&journal_path()),
+            [
+                (first_open, Record::Open),
+                (last_alive, S(Running)),
+                (last_alive, Record::End),
+                (second_open, Record::Open),
+                (second_open, S(Initialized)),
+            ]
+        );
+        let stats: serde_json::Value = serde_json::from_str(&stats_json(1)).unwrap();
+        assert_eq!(stats["sessions"], 2);
+        assert_eq!(stats["bootstraps"], 1);
+        assert_eq!(stats["ready_ms"], 0);
+        assert_eq!(stats["degraded_ms"], 5000);
+        assert_eq!(stats["stopped_ms"], 3690 * 1000);
+    }
+
+    #[test]
+    fn the_journal_is_trimmed_to_its_newest_records() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        retention::set_level(0);
+        let time = FakeTime::start();
+        let temp = TempDir::new();
+
+        open(temp.path());
+        for _ in 0..MAX_RECORDS - 1 {
+            time.advance(Duration::from_millis(1));
+            state_changed(Running);
+        }
+        assert_eq!(read(&journal_path()).len(), MAX_RECORDS);
+
+        time.advance(Duration::from_millis(1));
+        state_changed(Stopped);
+        let records = read(&journal_path());
+        assert_eq!(records.len(), KEEP_RECORDS);
+        assert_eq!(records.last(), Some(&(now_epoch_ms(), S(Stopped))));
+        assert!(records.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(JOURNAL.lock().unwrap().as_ref().unwrap().lines, KEEP_RECORDS);
+    }
+
+    #[test]
+    fn retention_coarsens_then_drops_the_journal() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let time = FakeTime::start();
+        let temp = TempDir::new();
+        let alive = layout::path(temp.path(), Purpose::SessionAlive);
+
+        retention::set_level(1);
+        open(temp.path());
+        time.advance(Duration::from_millis(123_457));
+        state_changed(Running);
+        tick();
+        let records = read(&journal_path());
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|(at, _)| *at == retention::timestamp(*at)));
+        assert_eq!(records[1], (retention::timestamp(now_epoch_ms()), S(Running)));
+        assert!(alive.exists());
+
+        retention::set_level(2);
+        assert!(!journal_path().exists() && !alive.exists());
+        time.advance(ALIVE_INTERVAL);
+        state_changed(Stopped);
+        tick();
+        assert!(!journal_path().exists() && !alive.exists());
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&stats_json(30)).unwrap()["covered_ms"], 0);
+    }
+}
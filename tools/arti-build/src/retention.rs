@@ -0,0 +1,320 @@
+//! Metadata retention policy
+//!
+//! For users whose threat model includes seizure of the device, per-event
+//! timestamps, byte counts and per-destination histories together rebuild an
+//! activity timeline even with hostnames redacted. The retention level limits
+//! what the wrapper keeps:
+//!
+//! | level | name    | effect                                                          |
+//! |-------|---------|-----------------------------------------------------------------|
+//! | 0     | full    | everything as before                                            |
+//! | 1     | reduced | no destination-quality persistence, no bridge health history;   |
+//! |       |         | audit and failure-record timestamps rounded down to 10 minutes  |
+//! | 2     | minimal | as reduced, plus no recent-log or debug ring, no log file, no   |
+//! |       |         | persistent counters, no metrics, session, exit or bootstrap     |
+//! |       |         | history, metrics reset on stop, notification status only        |
+//! |       |         | carries the state                                               |
+//!
+//! The policy is enforced by the sinks, not the producers: every ring,
+//! persisted file and timestamp a sink stores goes through `retains` or
+//! `timestamp` here, so a new producer can't forget it. Raising the level also
+//! purges what the new level would not have kept. Audit records already
+//! written keep their timestamps, since rewriting them would break the HMAC
+//! chain (see audit.rs).
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Timestamp granularity below the full level
+const COARSE_MS: u64 = 10 * 60 * 1000;
+
+/// Retention levels (the numbers are part of the JNI contract)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Full = 0,
+    Reduced = 1,
+    Minimal = 2,
+}
+
+/// Places metadata is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Sink {
+    /// Recent log lines (recent_logs.rs)
+    RecentLogs,
+    /// Rotating log file (log_file.rs)
+    LogFile,
+    /// Debug capture ring frozen into failure records (diagnostics.rs)
+    DebugRing,
+    /// Destination quality file (quality.rs)
+    QualityPersistence,
+    /// Hourly bridge health history, in memory and on disk (bridge_health.rs)
+    BridgeHistory,
+    /// Counters persisted across restarts (traffic.rs)
+    PersistentCounters,
+    /// Periodic metrics snapshots on disk (metrics_history.rs)
+    MetricsHistory,
+    /// Lifecycle transitions across sessions (reliability.rs)
+    SessionHistory,
+    /// Fields of the notification status beyond the state
+    StatusDetail,
+    /// Metrics surviving a stop
+    MetricsAcrossStop,
+    /// Exits of recent streams (diversity.rs)
+    ExitHistory,
+    /// Bootstrap attempts on disk (bootstrap_history.rs)
+    BootstrapHistory,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Full as u8);
+
+pub(crate) fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Full,
+        1 => Level::Reduced,
+        _ => Level::Minimal,
+    }
+}
+
+/// Whether `sink` may keep metadata at the current level
+pub(crate) fn retains(sink: Sink) -> bool {
+    match level() {
+        Level::Full => true,
+        Level::Reduced => !matches!(sink, Sink::QualityPersistence | Sink::BridgeHistory),
+        Level::Minimal => false,
+    }
+}
+
+/// A wall-clock timestamp as a sink may store it
+pub(crate) fn timestamp(epoch_ms: u64) -> u64 {
+    match level() {
+        Level::Full => epoch_ms,
+        _ => epoch_ms - epoch_ms % COARSE_MS,
+    }
+}
+
+/// Change the level, purging what the new level would not have kept
+pub(crate) fn set_level(level: u8) {
+    LEVEL.store(level.min(Level::Minimal as u8), Ordering::Relaxed);
+    if !retains(Sink::RecentLogs) {
+        crate::recent_logs::clear();
+    }
+    if !retains(Sink::LogFile) {
+        crate::log_file::purge();
+    }
+    if !retains(Sink::DebugRing) {
+        crate::diagnostics::clear_debug_context();
+    }
+    if !retains(Sink::QualityPersistence) {
+        crate::quality::remove_file();
+    }
+    if !retains(Sink::BridgeHistory) {
+        crate::bridge_health::clear();
+    }
+    if !retains(Sink::PersistentCounters) {
+        crate::traffic::remove_file();
+    }
+    if !retains(Sink::MetricsHistory) {
+        crate::metrics_history::purge();
+    }
+    if !retains(Sink::SessionHistory) {
+        crate::reliability::purge();
+    }
+    if !retains(Sink::ExitHistory) {
+        crate::diversity::purge();
+    }
+    if !retains(Sink::BootstrapHistory) {
+        crate::bootstrap_history::purge();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::layout::{self, Purpose};
+    use crate::notification::now_epoch_ms;
+    use crate::testing::{self, FakeTime, TempDir};
+    use crate::{actor, bootstrap_history, bridge_health, diagnostics, log_file, quality, recent_logs, traffic};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    const SINKS: [Sink; 12] = [
+        Sink::RecentLogs,
+        Sink::LogFile,
+        Sink::DebugRing,
+        Sink::QualityPersistence,
+        Sink::BridgeHistory,
+        Sink::PersistentCounters,
+        Sink::MetricsHistory,
+        Sink::SessionHistory,
+        Sink::StatusDetail,
+        Sink::MetricsAcrossStop,
+        Sink::ExitHistory,
+        Sink::BootstrapHistory,
+    ];
+
+    /// Every file under `dir`
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                found.extend(files(&path));
+            } else {
+                found.push(path);
+            }
+        }
+        found
+    }
+
+    fn json(text: &str) -> serde_json::Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    /// Open every data-directory sink on `data_dir`, as `initialize` does
+    fn start(data_dir: &Path) {
+        traffic::open(data_dir);
+        quality::open(data_dir);
+        bridge_health::open(data_dir);
+        crate::metrics_history::open(data_dir);
+        crate::reliability::open(data_dir);
+        bootstrap_history::open(data_dir);
+        actor::ask(WAIT, |_| ()).unwrap();
+    }
+
+    /// Feed every sink something to keep
+    fn produce(time: &FakeTime, cache: &Path) {
+        recent_logs::push("a line for the ring");
+        log_file::push("a line for the file");
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(diagnostics::BridgeLayer), || {
+            tracing::debug!("a line for the debug ring")
+        });
+        diagnostics::record_failure("retention_test", "failed".to_string());
+        quality::record("example.com", 443, quality::Sample::failed());
+        traffic::baseline(cache);
+        std::fs::write(cache.join("consensus"), [0u8; 100]).unwrap();
+        traffic::sample(cache);
+        time.advance(Duration::from_secs(crate::metrics_history::DEFAULT_INTERVAL_MINS * 60));
+        crate::metrics_history::tick();
+        crate::reliability::tick();
+        bootstrap_history::Attempt::begin(bootstrap_history::Trigger::User).finish("ok");
+        crate::METRICS.bytes_relayed.fetch_add(1000, std::sync::atomic::Ordering::Relaxed);
+        quality::flush(WAIT);
+        log_file::flush(WAIT);
+    }
+
+    /// Puts back the level and detaches the sinks from the test's directory
+    struct Restore {
+        level: u8,
+        quality: Option<quality::Table>,
+        bridge_health: Option<bridge_health::History>,
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            LEVEL.store(self.level, Ordering::Relaxed);
+            log_file::set_path("");
+            log_file::flush(WAIT);
+            let (quality, bridge_health) = (self.quality.take().unwrap(), self.bridge_health.take().unwrap());
+            actor::ask(WAIT, move |state| {
+                state.quality = quality;
+                state.bridge_health = bridge_health;
+            });
+            traffic::close();
+            crate::metrics_history::close();
+            crate::reliability::close();
+            bootstrap_history::close();
+            recent_logs::clear();
+            diagnostics::clear_debug_context();
+        }
+    }
+
+    #[test]
+    fn levels_decide_what_each_sink_keeps() {
+        let _serial = testing::serial();
+        let saved = LEVEL.load(Ordering::Relaxed);
+        let at = 1_700_000_123_456;
+        for (stored, expected) in [(0, Level::Full), (1, Level::Reduced), (2, Level::Minimal), (9, Level::Minimal)] {
+            LEVEL.store(stored, Ordering::Relaxed);
+            assert_eq!(level(), expected);
+            for sink in SINKS {
+                let kept = match expected {
+                    Level::Full => true,
+                    Level::Reduced => !matches!(sink, Sink::QualityPersistence | Sink::BridgeHistory),
+                    Level::Minimal => false,
+                };
+                assert_eq!(retains(sink), kept, "{:?} at {:?}", sink, expected);
+            }
+            let rounded = if expected == Level::Full { at } else { 1_699_999_800_000 };
+            assert_eq!(timestamp(at), rounded, "{:?}", expected);
+            assert_eq!(timestamp(rounded), rounded);
+        }
+        LEVEL.store(saved, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn minimal_level_keeps_nothing_finer_than_the_policy_across_a_stop() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _restore = Restore {
+            level: LEVEL.load(Ordering::Relaxed),
+            quality: actor::ask(WAIT, |state| std::mem::replace(&mut state.quality, quality::Table::new())),
+            bridge_health: actor::ask(WAIT, |state| std::mem::replace(&mut state.bridge_health, bridge_health::History::new())),
+        };
+        let temp = TempDir::new();
+        let cache = TempDir::new();
+        let data_dir = temp.path().join("data");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let log = temp.path().join("arti.log");
+        LEVEL.store(Level::Full as u8, Ordering::Relaxed);
+
+        // A full-level session leaves something in every sink
+        let bridge = layout::path(&data_dir, Purpose::BridgeHistory);
+        std::fs::create_dir_all(bridge.parent().unwrap()).unwrap();
+        let hour = now_epoch_ms() / 3_600_000;
+        std::fs::write(&bridge, format!("{{\"v\":1,\"bridges\":{{\"AAAA\":[[{},3,1,120]]}}}}", hour)).unwrap();
+        log_file::set_path(log.to_str().unwrap());
+        quality::set_persistent(true);
+        start(&data_dir);
+        produce(&time, cache.path());
+        assert!(log.exists());
+        assert!(files(&data_dir).len() >= 6, "{:?}", files(&data_dir));
+        assert!(!recent_logs::lines().is_empty());
+        assert_eq!(json(&diagnostics::last_error_json())["debug_context"].as_array().unwrap().len(), 1);
+        assert_eq!(json(&bridge_health::history_json())["bridges"].as_array().unwrap().len(), 1);
+
+        // Switching to minimal purges all of it
+        set_level(Level::Minimal as u8);
+        log_file::flush(WAIT);
+        actor::ask(WAIT, |_| ()).unwrap();
+        assert!(!log.exists());
+        assert_eq!(files(&data_dir), Vec::<PathBuf>::new());
+        assert!(recent_logs::lines().is_empty());
+        assert_eq!(bootstrap_history::history_json(), "[]");
+        assert_eq!(json(&bridge_health::history_json())["bridges"], json("[]"));
+
+        // A minimal-level session keeps nothing through a stop and the next start
+        time.advance(Duration::from_millis(1234));
+        produce(&time, cache.path());
+        crate::stop_within(None);
+        start(&data_dir);
+        log_file::flush(WAIT);
+
+        assert!(!log.exists());
+        assert_eq!(files(&data_dir), Vec::<PathBuf>::new());
+        assert!(recent_logs::lines().is_empty());
+        assert_eq!(bootstrap_history::history_json(), "[]");
+        assert_eq!(crate::metrics_history::history_json(usize::MAX), "[]");
+        assert_eq!(json(&bridge_health::history_json())["bridges"], json("[]"));
+        assert_eq!(json(&crate::METRICS.to_json())["bytes_relayed"], 0);
+        let diversity = json(&crate::diversity::report_json());
+        assert_eq!((diversity["streams"].as_u64(), diversity["retained"].as_bool()), (Some(0), Some(false)));
+        // What is left in memory carries coarse timestamps only
+        let failure = json(&diagnostics::last_error_json());
+        assert_eq!(failure["debug_context"], json("[]"));
+        assert_eq!(failure["at_epoch_ms"].as_u64().unwrap() % COARSE_MS, 0);
+    }
+}
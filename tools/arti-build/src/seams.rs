@@ -0,0 +1,721 @@
+//! Injectable clock, entropy and Tor client
+//!
+//! Timing logic (throttles, heartbeats, queue timeouts, the lifecycle sleeps)
+//! reads time and sleeps through the free functions here instead of calling
+//! `std::time`/`tokio::time` directly, and randomness comes from `fill_random`
+//! rather than the OS RNG. In normal builds these resolve to `SystemClock` and
+//! `OsEntropy`, which are plain wrappers around the real thing.
+//!
+//! With the `test-seams` cargo feature, a `FakeClock` and a seeded
+//! `FakeEntropy` can be switched in at runtime (see the `test*` JNI functions),
+//! so a test harness can advance time by hours without real sleeps and get
+//! reproducible "random" values. Unit tests get the same fakes without the
+//! feature.
+//!
+//! The SOCKS handler opens its Tor streams through a `Connector` rather than
+//! a `TorClient`, and fails them with a `ConnectError` carrying Arti's
+//! `ErrorKind`. The Arti client is the only connector in the library; unit
+//! tests drive the handler with a `FakeConnector` whose streams are in-memory
+//! pipes and whose failures are made up from an error kind. Likewise the
+//! accept loop takes its connections from an `Acceptor`, so tests can make
+//! accepting fail.
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant, SystemTime};
+
+use arti_client::{DataStream, ErrorKind, HasKind, StreamPrefs, TorClient};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tor_rtcompat::PreferredRuntime;
+
+/// A sleep in progress
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time
+pub(crate) trait Clock: Send + Sync {
+    /// Monotonic now, for measuring intervals
+    fn now_monotonic(&self) -> Instant;
+    /// Wall-clock now, for timestamps reported to Java
+    fn now_wall(&self) -> SystemTime;
+    /// Sleep on the async runtime
+    fn sleep(&self, duration: Duration) -> Sleep;
+    /// Sleep on a plain thread
+    fn sleep_blocking(&self, duration: Duration);
+}
+
+/// Source of randomness
+pub(crate) trait Entropy: Send + Sync {
+    fn fill(&self, buf: &mut [u8]);
+}
+
+/// The real clock
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_monotonic(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn sleep_blocking(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The OS random number generator
+pub(crate) struct OsEntropy;
+
+impl Entropy for OsEntropy {
+    fn fill(&self, buf: &mut [u8]) {
+        getrandom::fill(buf).expect("OS random number generator unavailable");
+    }
+}
+
+#[cfg(not(any(test, feature = "test-seams")))]
+fn clock() -> &'static SystemClock {
+    &SystemClock
+}
+
+#[cfg(not(any(test, feature = "test-seams")))]
+fn entropy() -> &'static OsEntropy {
+    &OsEntropy
+}
+
+#[cfg(any(test, feature = "test-seams"))]
+fn clock() -> &'static dyn Clock {
+    fakes::clock()
+}
+
+#[cfg(any(test, feature = "test-seams"))]
+fn entropy() -> &'static dyn Entropy {
+    fakes::entropy()
+}
+
+pub(crate) fn now_monotonic() -> Instant {
+    clock().now_monotonic()
+}
+
+pub(crate) fn now_wall() -> SystemTime {
+    clock().now_wall()
+}
+
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    clock().sleep(duration)
+}
+
+pub(crate) fn sleep_blocking(duration: Duration) {
+    clock().sleep_blocking(duration)
+}
+
+/// Fill `buf` with random bytes
+pub(crate) fn fill_random(buf: &mut [u8]) {
+    entropy().fill(buf)
+}
+
+/// A uniformly random u64
+pub(crate) fn random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_random(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Why a Tor connect failed: Arti's error kind and the error itself
+pub(crate) struct ConnectError {
+    kind: ErrorKind,
+    inner: Box<dyn Error + Send + Sync>,
+}
+
+impl ConnectError {
+    /// A failure made up by a connector that doesn't go through Arti (a fake,
+    /// or the smoke test's loopback)
+    pub(crate) fn fake(kind: ErrorKind, inner: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        ConnectError { kind, inner: inner.into() }
+    }
+}
+
+impl From<arti_client::Error> for ConnectError {
+    fn from(error: arti_client::Error) -> Self {
+        ConnectError { kind: error.kind(), inner: Box::new(error) }
+    }
+}
+
+impl HasKind for ConnectError {
+    fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl fmt::Debug for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl Error for ConnectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner.source()
+    }
+}
+
+/// A Tor stream as the SOCKS handler uses it
+pub(crate) trait TorStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    type Reader: AsyncRead + Unpin + Send;
+    type Writer: AsyncWrite + Unpin + Send;
+
+    /// The Arti stream, for circuit introspection (None for a fake)
+    fn data_stream(&self) -> Option<&DataStream>;
+    /// RSA identity of the circuit's exit, uppercase hex, if known
+    fn exit_fingerprint(&self) -> Option<String>;
+    /// Wait until the exit has answered the stream's BEGIN
+    fn wait_for_connection(&mut self) -> BoxFuture<'_, Result<(), ConnectError>>;
+    fn split(self) -> (Self::Reader, Self::Writer);
+}
+
+/// Where the SOCKS handler gets its Tor streams
+pub(crate) trait Connector: Send + Sync + 'static {
+    type Stream: TorStream;
+
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        prefs: &'a StreamPrefs,
+    ) -> BoxFuture<'a, Result<Self::Stream, ConnectError>>;
+    /// The Arti client behind the connector (None for a fake)
+    fn client(&self) -> Option<&TorClient<PreferredRuntime>>;
+}
+
+impl TorStream for DataStream {
+    type Reader = arti_client::DataReader;
+    type Writer = arti_client::DataWriter;
+
+    fn data_stream(&self) -> Option<&DataStream> {
+        Some(self)
+    }
+
+    fn exit_fingerprint(&self) -> Option<String> {
+        crate::circuit_path::exit(self)
+    }
+
+    fn wait_for_connection(&mut self) -> BoxFuture<'_, Result<(), ConnectError>> {
+        Box::pin(async move { Ok(DataStream::wait_for_connection(self).await?) })
+    }
+
+    fn split(self) -> (Self::Reader, Self::Writer) {
+        DataStream::split(self)
+    }
+}
+
+impl Connector for TorClient<PreferredRuntime> {
+    type Stream = DataStream;
+
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        prefs: &'a StreamPrefs,
+    ) -> BoxFuture<'a, Result<DataStream, ConnectError>> {
+        Box::pin(async move { Ok(self.connect_with_prefs((host, port), prefs).await?) })
+    }
+
+    fn client(&self) -> Option<&TorClient<PreferredRuntime>> {
+        Some(self)
+    }
+}
+
+/// Where the SOCKS accept loop gets its connections
+pub(crate) trait Acceptor: Send + 'static {
+    fn accept(&self) -> BoxFuture<'_, std::io::Result<(TcpStream, SocketAddr)>>;
+}
+
+impl Acceptor for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, std::io::Result<(TcpStream, SocketAddr)>> {
+        Box::pin(TcpListener::accept(self))
+    }
+}
+
+#[cfg(any(test, feature = "test-seams"))]
+pub(crate) mod fakes {
+    //! Controllable clock, entropy and Tor client for host-side tests
+
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Condvar, Mutex, OnceLock};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use tokio::sync::Notify;
+
+    use super::{Clock, Entropy, OsEntropy, Sleep, SystemClock};
+
+    /// A clock that only moves when `advance` is called
+    pub(crate) struct FakeClock {
+        monotonic_base: Instant,
+        wall_base: SystemTime,
+        offset: Mutex<Duration>,
+        advanced: Condvar,
+        advanced_async: Notify,
+    }
+
+    impl FakeClock {
+        fn offset(&self) -> Duration {
+            *self.offset.lock().unwrap()
+        }
+
+        /// Move time forward, waking every sleeper whose deadline has passed
+        pub(crate) fn advance(&self, by: Duration) {
+            *self.offset.lock().unwrap() += by;
+            self.advanced.notify_all();
+            self.advanced_async.notify_waiters();
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now_monotonic(&self) -> Instant {
+            self.monotonic_base + self.offset()
+        }
+
+        fn now_wall(&self) -> SystemTime {
+            self.wall_base + self.offset()
+        }
+
+        fn sleep(&self, duration: Duration) -> Sleep {
+            let clock = fake_clock();
+            let deadline = clock.offset() + duration;
+            Box::pin(async move {
+                loop {
+                    let advanced = clock.advanced_async.notified();
+                    tokio::pin!(advanced);
+                    advanced.as_mut().enable();
+                    if clock.offset() >= deadline {
+                        return;
+                    }
+                    advanced.await;
+                }
+            })
+        }
+
+        fn sleep_blocking(&self, duration: Duration) {
+            let mut offset = self.offset.lock().unwrap();
+            let deadline = *offset + duration;
+            while *offset < deadline {
+                offset = self.advanced.wait(offset).unwrap();
+            }
+        }
+    }
+
+    /// Deterministic entropy (SplitMix64) from a seed
+    pub(crate) struct FakeEntropy {
+        state: AtomicU64,
+    }
+
+    impl Entropy for FakeEntropy {
+        fn fill(&self, buf: &mut [u8]) {
+            for chunk in buf.chunks_mut(8) {
+                let mut z = self.state.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+                    .wrapping_add(0x9e37_79b9_7f4a_7c15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+            }
+        }
+    }
+
+    static USE_FAKE_CLOCK: AtomicBool = AtomicBool::new(false);
+    static USE_FAKE_ENTROPY: AtomicBool = AtomicBool::new(false);
+    static FAKE_CLOCK: OnceLock<FakeClock> = OnceLock::new();
+    static FAKE_ENTROPY: FakeEntropy = FakeEntropy { state: AtomicU64::new(0) };
+
+    fn fake_clock() -> &'static FakeClock {
+        FAKE_CLOCK.get_or_init(|| FakeClock {
+            monotonic_base: Instant::now(),
+            wall_base: SystemTime::now(),
+            offset: Mutex::new(Duration::ZERO),
+            advanced: Condvar::new(),
+            advanced_async: Notify::new(),
+        })
+    }
+
+    pub(super) fn clock() -> &'static dyn Clock {
+        if USE_FAKE_CLOCK.load(Ordering::Relaxed) {
+            fake_clock()
+        } else {
+            &SystemClock
+        }
+    }
+
+    pub(super) fn entropy() -> &'static dyn Entropy {
+        if USE_FAKE_ENTROPY.load(Ordering::Relaxed) {
+            &FAKE_ENTROPY
+        } else {
+            &OsEntropy
+        }
+    }
+
+    /// Switch to the fake clock (frozen at the moment of the first switch)
+    pub(crate) fn use_fake_clock() {
+        fake_clock();
+        USE_FAKE_CLOCK.store(true, Ordering::Relaxed);
+    }
+
+    /// Advance the fake clock
+    pub(crate) fn advance_clock(by: Duration) {
+        fake_clock().advance(by);
+    }
+
+    /// Switch to deterministic entropy from `seed`
+    pub(crate) fn seed_entropy(seed: u64) {
+        FAKE_ENTROPY.state.store(seed, Ordering::Relaxed);
+        USE_FAKE_ENTROPY.store(true, Ordering::Relaxed);
+    }
+
+    /// Switch back to the real clock (the fake one keeps its time)
+    #[cfg(test)]
+    pub(crate) fn use_system_clock() {
+        USE_FAKE_CLOCK.store(false, Ordering::Relaxed);
+    }
+
+    /// Switch back to the OS random number generator
+    #[cfg(test)]
+    pub(crate) fn use_os_entropy() {
+        USE_FAKE_ENTROPY.store(false, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub(crate) use connector::{FakeConnect, FakeConnector, FakeStream};
+
+    #[cfg(test)]
+    mod connector {
+        use std::collections::VecDeque;
+        use std::pin::Pin;
+        use std::sync::Mutex;
+        use std::task::{Context, Poll};
+        use std::time::Duration;
+
+        use arti_client::{DataStream, ErrorKind, StreamPrefs, TorClient};
+        use futures::future::BoxFuture;
+        use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+        use tor_rtcompat::PreferredRuntime;
+
+        use super::super::{ConnectError, Connector, TorStream};
+
+        /// Capacity of each direction of a fake stream's pipe
+        const PIPE_BYTES: usize = 64 * 1024;
+
+        /// What the next connect of a `FakeConnector` does
+        pub(crate) enum FakeConnect {
+            /// Succeed with a stream piped to the test's end
+            Stream(FakeStream),
+            /// Fail with this kind and message
+            Fail(ErrorKind, &'static str),
+            /// Never finish
+            Hang,
+            /// Succeed after this long on the fake clock
+            Delayed(Duration, FakeStream),
+        }
+
+        /// A Tor stream that is one end of an in-memory pipe
+        pub(crate) struct FakeStream {
+            pipe: DuplexStream,
+            confirm: Option<ErrorKind>,
+            /// Every write and flush fails, as when the exit drops the stream
+            unwritable: bool,
+            exit: Option<String>,
+        }
+
+        impl FakeStream {
+            /// A stream and the pipe end standing in for the destination
+            pub(crate) fn pair() -> (FakeStream, DuplexStream) {
+                let (ours, theirs) = tokio::io::duplex(PIPE_BYTES);
+                (FakeStream { pipe: ours, confirm: None, unwritable: false, exit: None }, theirs)
+            }
+        }
+
+        impl AsyncRead for FakeStream {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Pin::new(&mut self.pipe).poll_read(cx, buf)
+            }
+        }
+
+        impl AsyncWrite for FakeStream {
+            fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+                if self.unwritable {
+                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+                }
+                Pin::new(&mut self.pipe).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                if self.unwritable {
+                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+                }
+                Pin::new(&mut self.pipe).poll_flush(cx)
+            }
+
+            fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Pin::new(&mut self.pipe).poll_shutdown(cx)
+            }
+        }
+
+        impl TorStream for FakeStream {
+            type Reader = ReadHalf<DuplexStream>;
+            type Writer = WriteHalf<DuplexStream>;
+
+            fn data_stream(&self) -> Option<&DataStream> {
+                None
+            }
+
+            fn exit_fingerprint(&self) -> Option<String> {
+                self.exit.clone()
+            }
+
+            fn wait_for_connection(&mut self) -> BoxFuture<'_, Result<(), ConnectError>> {
+                let confirm = self.confirm;
+                Box::pin(async move {
+                    match confirm {
+                        Some(kind) => Err(ConnectError::fake(kind, "stream refused by the exit")),
+                        None => Ok(()),
+                    }
+                })
+            }
+
+            fn split(self) -> (Self::Reader, Self::Writer) {
+                tokio::io::split(self.pipe)
+            }
+        }
+
+        /// A connector answering each connect from a script, in order
+        ///
+        /// Connects beyond the script fail with `ErrorKind::Other`. Every
+        /// request is recorded with its target.
+        #[derive(Default)]
+        pub(crate) struct FakeConnector {
+            script: Mutex<VecDeque<FakeConnect>>,
+            requests: Mutex<Vec<(String, u16)>>,
+        }
+
+        impl FakeConnector {
+            pub(crate) fn new() -> Self {
+                FakeConnector::default()
+            }
+
+            /// Script the next connect
+            pub(crate) fn then(&self, next: FakeConnect) -> &Self {
+                self.script.lock().unwrap().push_back(next);
+                self
+            }
+
+            /// Script a successful connect, returning the destination's end
+            pub(crate) fn then_connect(&self) -> DuplexStream {
+                let (stream, destination) = FakeStream::pair();
+                self.then(FakeConnect::Stream(stream));
+                destination
+            }
+
+            /// Script a successful connect whose confirmation fails with `kind`
+            pub(crate) fn then_unconfirmed(&self, kind: ErrorKind) -> DuplexStream {
+                let (mut stream, destination) = FakeStream::pair();
+                stream.confirm = Some(kind);
+                self.then(FakeConnect::Stream(stream));
+                destination
+            }
+
+            /// Script a successful connect over a circuit exiting at `exit`
+            pub(crate) fn then_connect_via(&self, exit: &str) -> DuplexStream {
+                let (mut stream, destination) = FakeStream::pair();
+                stream.exit = Some(exit.to_string());
+                self.then(FakeConnect::Stream(stream));
+                destination
+            }
+
+            /// Script a successful connect to a stream that can't be written
+            pub(crate) fn then_unwritable(&self) -> DuplexStream {
+                let (mut stream, destination) = FakeStream::pair();
+                stream.unwritable = true;
+                self.then(FakeConnect::Stream(stream));
+                destination
+            }
+
+            /// Targets connected to so far
+            pub(crate) fn requests(&self) -> Vec<(String, u16)> {
+                self.requests.lock().unwrap().clone()
+            }
+        }
+
+        impl Connector for FakeConnector {
+            type Stream = FakeStream;
+
+            fn connect<'a>(
+                &'a self,
+                host: &'a str,
+                port: u16,
+                _prefs: &'a StreamPrefs,
+            ) -> BoxFuture<'a, Result<FakeStream, ConnectError>> {
+                self.requests.lock().unwrap().push((host.to_string(), port));
+                let next = self.script.lock().unwrap().pop_front();
+                Box::pin(async move {
+                    match next {
+                        Some(FakeConnect::Stream(stream)) => Ok(stream),
+                        Some(FakeConnect::Fail(kind, message)) => Err(ConnectError::fake(kind, message)),
+                        Some(FakeConnect::Hang) => std::future::pending().await,
+                        Some(FakeConnect::Delayed(delay, stream)) => {
+                            super::super::sleep(delay).await;
+                            Ok(stream)
+                        }
+                        None => Err(ConnectError::fake(ErrorKind::Other, "no scripted connect")),
+                    }
+                })
+            }
+
+            fn client(&self) -> Option<&TorClient<PreferredRuntime>> {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use arti_client::{ErrorKind, HasKind, StreamPrefs};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::fakes::{self, FakeConnect, FakeConnector};
+    use super::{Connector, TorStream};
+    use crate::testing::{self, FakeTime};
+
+    #[test]
+    fn fake_clock_moves_only_when_advanced() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let (mono, wall) = (super::now_monotonic(), super::now_wall());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(super::now_monotonic(), mono);
+        time.advance(Duration::from_secs(3600));
+        assert_eq!(super::now_monotonic() - mono, Duration::from_secs(3600));
+        assert_eq!(super::now_wall().duration_since(wall).unwrap(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn async_sleep_wakes_at_its_deadline() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let runtime = testing::runtime();
+        let sleep = runtime.spawn(super::sleep(Duration::from_secs(10)));
+        time.advance(Duration::from_secs(9));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!sleep.is_finished());
+        time.advance(Duration::from_secs(1));
+        runtime.block_on(async { tokio::time::timeout(Duration::from_secs(5), sleep).await }).unwrap().unwrap();
+    }
+
+    #[test]
+    fn blocking_sleep_wakes_at_its_deadline() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let sleeper = std::thread::spawn(|| super::sleep_blocking(Duration::from_secs(60)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!sleeper.is_finished());
+        time.advance(Duration::from_secs(60));
+        sleeper.join().unwrap();
+    }
+
+    #[test]
+    fn system_clock_is_back_after_the_guard() {
+        let _serial = testing::serial();
+        let frozen = {
+            let _time = FakeTime::start();
+            super::now_monotonic()
+        };
+        std::thread::sleep(Duration::from_millis(5));
+        assert_ne!(super::now_monotonic(), frozen);
+    }
+
+    #[test]
+    fn seeded_entropy_repeats() {
+        let _serial = testing::serial();
+        let draw = |seed| {
+            fakes::seed_entropy(seed);
+            let mut bytes = [0u8; 13];
+            super::fill_random(&mut bytes);
+            (bytes, super::random_u64())
+        };
+        let first = draw(7);
+        assert_eq!(draw(7), first);
+        assert_ne!(draw(8), first);
+        fakes::use_os_entropy();
+        assert_ne!(super::random_u64(), super::random_u64());
+    }
+
+    #[test]
+    fn fake_connector_follows_its_script() {
+        let connector = Arc::new(FakeConnector::new());
+        let mut destination = connector.then_connect();
+        connector.then(FakeConnect::Fail(ErrorKind::TorNetworkTimeout, "timed out"));
+        let prefs = StreamPrefs::new();
+
+        testing::runtime().block_on(async {
+            let mut stream = connector.connect("example.com", 443, &prefs).await.unwrap();
+            assert!(stream.data_stream().is_none());
+            stream.wait_for_connection().await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            destination.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            let failed = connector.connect("example.org", 80, &prefs).await.err().unwrap();
+            assert_eq!(failed.kind(), ErrorKind::TorNetworkTimeout);
+            assert_eq!(failed.to_string(), "timed out");
+            // Past the script
+            assert_eq!(connector.connect("example.net", 80, &prefs).await.err().unwrap().kind(), ErrorKind::Other);
+        });
+        assert_eq!(
+            connector.requests(),
+            [("example.com".to_string(), 443), ("example.org".to_string(), 80), ("example.net".to_string(), 80)]
+        );
+    }
+
+    #[test]
+    fn hanging_connect_never_finishes() {
+        let connector = FakeConnector::new();
+        connector.then(FakeConnect::Hang);
+        testing::runtime().block_on(async {
+            let prefs = StreamPrefs::new();
+            let connect = connector.connect("example.com", 443, &prefs);
+            assert!(tokio::time::timeout(Duration::from_millis(50), connect).await.is_err());
+        });
+    }
+
+    #[test]
+    fn unconfirmed_stream_fails_its_confirmation() {
+        let connector = FakeConnector::new();
+        let _destination = connector.then_unconfirmed(ErrorKind::ExitPolicyRejected);
+        testing::runtime().block_on(async {
+            let mut stream = connector.connect("example.com", 443, &StreamPrefs::new()).await.unwrap();
+            assert_eq!(stream.wait_for_connection().await.err().unwrap().kind(), ErrorKind::ExitPolicyRejected);
+        });
+    }
+}
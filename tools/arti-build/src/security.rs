@@ -0,0 +1,375 @@
+//! High-priority security events
+//!
+//! Most Tor failures are connectivity noise. A few deserve attention in their
+//! own right because they can indicate an active attacker: directory documents
+//! whose signatures or certificates don't verify, directory authorities
+//! answering with something unexpected, relays presenting the wrong identity,
+//! and relays violating the protocol. These are reported through a dedicated
+//! callback, separate from the log stream, so the app can surface them.
+//!
+//! Arti exposes two kinds of signal:
+//!
+//! - Errors returned from API calls carry an `ErrorKind`; a few kinds map
+//!   directly to a category (see `classify_kind`). Only the kind decides,
+//!   never the error's text.
+//! - Directory verification problems mostly never reach an API caller (Arti
+//!   logs them and retries another cache), so WARN-and-above tracing events
+//!   from the directory crates are classified by target and message text
+//!   (see `classify_event`). The text match is deliberately narrow: a
+//!   document word alone is not enough, the message must also say the check
+//!   failed. A missed event still reaches the normal log sinks.
+//!
+//! A failed stream connect whose kind has a category failed at the relay
+//! handshake or cryptographic layer (a wrong identity key, certificates or
+//! cell authentication that don't verify), which points at interception on
+//! the path to a relay or a broken exit rather than at the network. It comes
+//! back as `ConnectFailure::Crypto` and is reported as a stream event: a
+//! security event under that category whose `stream` names the target port
+//! and the reply sent. RFC 1928 has no reply code for the cause, so its
+//! client gets the one code no other failure uses, 0x04 (host unreachable):
+//! a network failure gets 0x05 (connection refused) and wrapper faults 0x01
+//! (general failure). The `crypto_failure_event` capability bit tells
+//! clients what 0x04 means (see `check_connect_error`).
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+use arti_client::ErrorKind;
+use tracing::Level;
+
+use crate::json_string;
+use crate::notification::now_epoch_ms;
+use crate::seams::ConnectError;
+use crate::socks::Reply;
+
+/// Security event categories (the strings are part of the Java contract)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Category {
+    /// A directory document failed signature or certificate verification
+    DirectoryVerification,
+    /// A directory authority or cache answered in an unexpected way
+    AuthorityResponse,
+    /// A relay's identity didn't match the one we expected (possible MITM)
+    RelayIdentityMismatch,
+    /// A relay violated the Tor protocol
+    ProtocolViolation,
+}
+
+impl Category {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Category::DirectoryVerification => "directory_verification",
+            Category::AuthorityResponse => "authority_response",
+            Category::RelayIdentityMismatch => "relay_identity_mismatch",
+            Category::ProtocolViolation => "protocol_violation",
+        }
+    }
+}
+
+/// Map an Arti error kind to a security category, if it is one
+///
+/// Arti files a relay presenting an identity other than the one asked for
+/// under `RelayIdMismatch`, and channel handshakes whose certificates or cell
+/// authentication don't verify under `TorProtocolViolation`. Every other kind
+/// is the network's, the destination's or the configuration's.
+pub(crate) fn classify_kind(kind: ErrorKind) -> Option<Category> {
+    match kind {
+        ErrorKind::RelayIdMismatch => Some(Category::RelayIdentityMismatch),
+        ErrorKind::TorProtocolViolation => Some(Category::ProtocolViolation),
+        _ => None,
+    }
+}
+
+/// Words naming a document check in a directory event
+const DOCUMENT_WORDS: &[&str] = &["signature", "certificate"];
+
+/// Words saying the check failed
+const FAILURE_WORDS: &[&str] = &["invalid", "bad", "fail", "verif", "expired", "mismatch", "untrusted"];
+
+/// Map a tracing event to a security category, if it is one
+pub(crate) fn classify_event(level: &Level, target: &str, message: &str) -> Option<Category> {
+    if *level > Level::WARN {
+        return None;
+    }
+    let directory = ["tor_dirmgr", "tor_dirclient", "tor_netdoc", "tor_checkable"]
+        .iter()
+        .any(|c| target.starts_with(c));
+    let message = message.to_ascii_lowercase();
+
+    if directory {
+        let document = DOCUMENT_WORDS.iter().any(|w| message.contains(w));
+        let failed = FAILURE_WORDS.iter().any(|w| message.contains(w));
+        if (document && failed) || message.contains("not signed") || message.contains("untimely") {
+            return Some(Category::DirectoryVerification);
+        }
+        if message.contains("authority") && ["unexpected", "invalid", "malformed"].iter().any(|w| message.contains(w)) {
+            return Some(Category::AuthorityResponse);
+        }
+    }
+    if (target.starts_with("tor_chanmgr") || target.starts_with("tor_proto"))
+        && message.contains("identity")
+        && (message.contains("mismatch") || message.contains("wrong") || message.contains("unexpected"))
+    {
+        return Some(Category::RelayIdentityMismatch);
+    }
+    None
+}
+
+/// Report a security event to the log sinks and the Java callback
+///
+/// `source` says where the signal came from (a tracing target or a wrapper
+/// operation such as "connect").
+pub(crate) fn report(category: Category, source: &str, detail: &str) {
+    report_event(category, source, detail, None);
+}
+
+/// `report`, with the `stream` object of a stream event if any
+fn report_event(category: Category, source: &str, detail: &str, stream: Option<serde_json::Value>) {
+    #[cfg(test)]
+    tests::REPORTED.lock().unwrap().push((category, source.to_string()));
+    crate::METRICS.security_events.fetch_add(1, Ordering::Relaxed);
+    log_error!("SECURITY [{}] from {}: {}", category.as_str(), source, detail);
+
+    let stream = stream.map_or(String::new(), |stream| format!(",\"stream\":{}", stream));
+    let event = format!(
+        "{{\"category\":\"{}\",\"source\":{},\"detail\":{},\"at_epoch_ms\":{}{}}}",
+        category.as_str(),
+        json_string(source),
+        json_string(detail),
+        now_epoch_ms(),
+        stream,
+    );
+    crate::send_string_to_java(crate::callbacks::Kind::SecurityEvent, "onSecurityEvent", &event);
+}
+
+/// Report an API error if its kind is security-relevant
+pub(crate) fn check_error<E: arti_client::HasKind + std::fmt::Display>(error: &E, source: &str) {
+    if let Some(category) = classify_kind(error.kind()) {
+        report(category, source, &error.to_string());
+    }
+}
+
+/// A failed stream connect, by the layer it failed at
+#[derive(Debug)]
+pub(crate) enum ConnectFailure {
+    /// The relay handshake or cryptographic layer
+    Crypto { category: Category, error: ConnectError },
+    /// The network or the destination
+    Network(ConnectError),
+}
+
+impl ConnectFailure {
+    /// The SOCKS reply the client gets
+    pub(crate) fn reply(&self) -> Reply {
+        match self {
+            ConnectFailure::Crypto { .. } => Reply::HostUnreachable,
+            ConnectFailure::Network(_) => Reply::ConnectionRefused,
+        }
+    }
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectFailure::Crypto { category, error } => write!(f, "connect failed at the crypto layer ({}): {}", category.as_str(), error),
+            ConnectFailure::Network(error) => write!(f, "connect failed: {}", error),
+        }
+    }
+}
+
+impl Error for ConnectFailure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConnectFailure::Crypto { error, .. } | ConnectFailure::Network(error) => Some(error),
+        }
+    }
+}
+
+/// Classify a failed connect to `target_port`, reporting a crypto-layer
+/// failure as a stream event
+pub(crate) fn check_connect_error(error: ConnectError, target_port: u16) -> ConnectFailure {
+    use arti_client::HasKind;
+
+    let Some(category) = classify_kind(error.kind()) else {
+        return ConnectFailure::Network(error);
+    };
+    let detail = error.to_string();
+    let failure = ConnectFailure::Crypto { category, error };
+    let stream = serde_json::json!({ "target_port": target_port, "socks_reply": failure.reply() as u8 });
+    report_event(category, "connect", &detail, Some(stream));
+    failure
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tracing::Level;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::seams::ConnectError;
+    use crate::testing;
+
+    /// Events reported, with their source (there is no JVM to receive them)
+    pub(super) static REPORTED: Mutex<Vec<(Category, String)>> = Mutex::new(Vec::new());
+
+    /// Every error kind, the security ones last
+    const KINDS: &[ErrorKind] = &[
+        ErrorKind::TorAccessFailed,
+        ErrorKind::RemoteHostNotFound,
+        ErrorKind::RemoteConnectionRefused,
+        ErrorKind::RemoteNetworkTimeout,
+        ErrorKind::OnionServiceNotFound,
+        ErrorKind::OnionServiceNotRunning,
+        ErrorKind::OnionServiceConnectionFailed,
+        ErrorKind::OnionServiceProtocolViolation,
+        ErrorKind::OnionServiceMissingClientAuth,
+        ErrorKind::OnionServiceWrongClientAuth,
+        ErrorKind::OnionServiceAddressInvalid,
+        ErrorKind::InvalidStreamTarget,
+        ErrorKind::ClockSkew,
+        ErrorKind::BootstrapRequired,
+        ErrorKind::TorNetworkTimeout,
+        ErrorKind::LocalNetworkError,
+        ErrorKind::TorDirectoryError,
+        ErrorKind::DirectoryExpired,
+        ErrorKind::PersistentStateAccessFailed,
+        ErrorKind::CacheAccessFailed,
+        ErrorKind::CacheCorrupted,
+        ErrorKind::InvalidConfig,
+        ErrorKind::TransientFailure,
+        ErrorKind::ExitPolicyRejected,
+        ErrorKind::RemoteStreamClosed,
+        ErrorKind::RemoteStreamReset,
+        ErrorKind::RemoteStreamError,
+        ErrorKind::ForbiddenStreamTarget,
+        ErrorKind::NoPath,
+        ErrorKind::NoExit,
+        ErrorKind::LocalResourceExhausted,
+        ErrorKind::Internal,
+        ErrorKind::Other,
+        ErrorKind::ExitTimeout,
+        ErrorKind::TorDirectoryUnusable,
+        ErrorKind::LocalProtocolViolation,
+        ErrorKind::RemoteProtocolViolation,
+        ErrorKind::FeatureDisabled,
+        ErrorKind::TorProtocolViolation,
+        ErrorKind::RelayIdMismatch,
+    ];
+
+    fn take_reported() -> Vec<(Category, String)> {
+        std::mem::take(&mut *REPORTED.lock().unwrap())
+    }
+
+    #[test]
+    fn only_security_kinds_have_a_category() {
+        for kind in KINDS {
+            let expected = match kind {
+                ErrorKind::RelayIdMismatch => Some(Category::RelayIdentityMismatch),
+                ErrorKind::TorProtocolViolation => Some(Category::ProtocolViolation),
+                _ => None,
+            };
+            assert_eq!(classify_kind(*kind), expected, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn connect_errors_are_classified_by_kind_not_text() {
+        let _serial = testing::serial();
+        take_reported();
+        let cases = [
+            // Words that used to place a failure at the crypto layer
+            (ErrorKind::RemoteHostNotFound, "tls.example.com: host not found", false),
+            (ErrorKind::RemoteConnectionRefused, "certificate-server refused the connection", false),
+            (ErrorKind::TorNetworkTimeout, "timed out waiting for signature service", false),
+            (ErrorKind::RemoteStreamClosed, "decrypt.example closed the stream", false),
+            (ErrorKind::OnionServiceWrongClientAuth, "unable to decrypt the descriptor", false),
+            // Typed failures with bland messages
+            (ErrorKind::RelayIdMismatch, "relay has the wrong identity", true),
+            (ErrorKind::TorProtocolViolation, "channel handshake failed", true),
+        ];
+        for (kind, message, crypto) in cases {
+            crate::tests::SENT_STRINGS.lock().unwrap().clear();
+            let failure = check_connect_error(ConnectError::fake(kind, message), 5222);
+            let reported = take_reported();
+            let events = crate::tests::SENT_STRINGS.lock().unwrap().clone();
+            // The underlying error stays reachable through the typed one
+            assert_eq!(failure.source().unwrap().to_string(), message);
+            if crypto {
+                let category = classify_kind(kind).unwrap();
+                assert!(matches!(failure, ConnectFailure::Crypto { category: c, .. } if c == category), "{:?}", kind);
+                assert_eq!(failure.reply(), Reply::HostUnreachable);
+                assert_eq!(reported, [(category, "connect".to_string())], "{:?}", kind);
+                assert_eq!(events.len(), 1);
+                let event: serde_json::Value = serde_json::from_str(&events[0].1).unwrap();
+                assert_eq!(event["category"], category.as_str());
+                assert_eq!(event["detail"], message);
+                assert_eq!(event["stream"], serde_json::json!({"target_port": 5222, "socks_reply": 0x04}));
+            } else {
+                assert!(matches!(failure, ConnectFailure::Network(_)), "{:?}", kind);
+                assert_eq!(failure.reply(), Reply::ConnectionRefused);
+                assert!(reported.is_empty() && events.is_empty(), "{:?} {}: {:?}", kind, message, reported);
+            }
+        }
+        // Other security events are not stream events
+        crate::tests::SENT_STRINGS.lock().unwrap().clear();
+        report(Category::DirectoryVerification, "tor_dirmgr", "bad signature");
+        take_reported();
+        let event: serde_json::Value = serde_json::from_str(&crate::tests::SENT_STRINGS.lock().unwrap()[0].1).unwrap();
+        assert!(event.get("stream").is_none());
+    }
+
+    #[test]
+    fn directory_events_are_classified() {
+        let cases = [
+            (Level::WARN, "tor_dirmgr", "Consensus signature verification failed", Some(Category::DirectoryVerification)),
+            (Level::ERROR, "tor_netdoc", "invalid certificate on authority key", Some(Category::DirectoryVerification)),
+            (Level::WARN, "tor_checkable", "document is untimely", Some(Category::DirectoryVerification)),
+            (Level::WARN, "tor_dirmgr", "consensus not signed by enough authorities", Some(Category::DirectoryVerification)),
+            (Level::WARN, "tor_dirclient", "authority sent an unexpected response", Some(Category::AuthorityResponse)),
+            (Level::WARN, "tor_chanmgr", "relay identity mismatch", Some(Category::RelayIdentityMismatch)),
+            // Negative cases: wrong level, wrong crate, or no failure named
+            (Level::INFO, "tor_dirmgr", "Consensus signature verification failed", None),
+            (Level::WARN, "tor_dirmgr", "Unable to download certificates: timed out", None),
+            (Level::WARN, "tor_dirmgr", "Fetched 3 certificates from a cache", None),
+            (Level::WARN, "tor_dirmgr", "signatures collected; waiting for microdescs", None),
+            (Level::WARN, "tor_circmgr", "invalid certificate", None),
+            (Level::WARN, "tor_dirclient", "authority unreachable", None),
+            (Level::WARN, "tor_chanmgr", "identity key loaded", None),
+            (Level::WARN, "arti_client", "relay identity mismatch", None),
+        ];
+        for (level, target, message, expected) in cases {
+            assert_eq!(classify_event(&level, target, message), expected, "{} {} {}", level, target, message);
+        }
+    }
+
+    #[test]
+    fn verification_failure_fires_a_security_event() {
+        let _serial = testing::serial();
+        take_reported();
+        let before = crate::METRICS.security_events.load(Ordering::Relaxed);
+
+        let subscriber = tracing_subscriber::registry().with(crate::diagnostics::BridgeLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(target: "tor_dirmgr", "Consensus signature verification failed");
+            tracing::warn!(target: "tor_dirmgr", "Unable to download certificates: timed out");
+        });
+
+        assert_eq!(take_reported(), [(Category::DirectoryVerification, "tor_dirmgr".to_string())]);
+        assert_eq!(crate::METRICS.security_events.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test]
+    fn category_names_match_the_java_contract() {
+        for (category, name) in [
+            (Category::DirectoryVerification, "directory_verification"),
+            (Category::AuthorityResponse, "authority_response"),
+            (Category::RelayIdentityMismatch, "relay_identity_mismatch"),
+            (Category::ProtocolViolation, "protocol_violation"),
+        ] {
+            assert_eq!(category.as_str(), name);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Stopping within a deadline, and the report of the last stop
+//!
+//! On Android 14 the system gives a foreground service a short window to stop
+//! and kills the process if `stop` is still running after it, possibly in the
+//! middle of writing state. `stopWithDeadline(deadlineMs)` therefore budgets
+//! the stop before running it:
+//!
+//! - aborting the listener, cancelling connections and the lifecycle change
+//!   take no time and always happen;
+//! - the other phases get budgets out of what remains of the deadline after
+//!   those and `MARGIN`, in priority order: the state flushes first
+//!   (destination quality, then bridge health), then the drain that gives
+//!   aborted tasks a moment to release the port. Each phase gets what it
+//!   wants or whatever is left; one left with less than its minimum is
+//!   skipped;
+//! - a flush that doesn't finish within its budget is abandoned and reported
+//!   as `timed_out`. The actor still completes it in the background if the
+//!   process lives long enough;
+//! - the drain never waits for the runtime lock: if another call holds it,
+//!   the drain is skipped rather than blocking the stop past its deadline.
+//!
+//! `stop()` runs the same phases without a deadline, each with what it
+//! wants. `getLastShutdownReport` returns what the last stop did with each
+//! phase.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::notification::now_epoch_ms;
+use crate::{bridge_health, quality, retention, seams};
+
+/// Kept back from the deadline for returning to Java
+const MARGIN: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    QualityFlush,
+    BridgeHealthFlush,
+    Drain,
+}
+
+struct PhaseDef {
+    phase: Phase,
+    name: &'static str,
+    /// Budget without a deadline
+    want: Duration,
+    /// Below this the phase is skipped
+    min: Duration,
+}
+
+/// Budgeted phases, in priority order (also the order they run in)
+const PHASES: &[PhaseDef] = &[
+    PhaseDef {
+        phase: Phase::QualityFlush,
+        name: "quality_flush",
+        want: Duration::from_secs(2),
+        min: Duration::from_millis(50),
+    },
+    PhaseDef {
+        phase: Phase::BridgeHealthFlush,
+        name: "bridge_health_flush",
+        want: Duration::from_secs(2),
+        min: Duration::from_millis(50),
+    },
+    PhaseDef {
+        phase: Phase::Drain,
+        name: "drain",
+        want: Duration::from_millis(100),
+        min: Duration::from_millis(20),
+    },
+];
+
+fn name(phase: Phase) -> &'static str {
+    PHASES.iter().find(|def| def.phase == phase).map_or("unknown", |def| def.name)
+}
+
+/// Budget of each phase (`None`: skipped) given `available` time, or
+/// unlimited time without it
+pub(crate) fn plan(available: Option<Duration>) -> Vec<(Phase, Option<Duration>)> {
+    let mut left = available;
+    PHASES
+        .iter()
+        .map(|def| {
+            let budget = match left {
+                None => Some(def.want),
+                Some(left) if left >= def.min => Some(def.want.min(left)),
+                Some(_) => None,
+            };
+            if let (Some(l), Some(budget)) = (left.as_mut(), budget) {
+                *l -= budget;
+            }
+            (def.phase, budget)
+        })
+        .collect()
+}
+
+/// How a phase that was given a budget went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Completed,
+    TimedOut,
+    /// Couldn't start without waiting on another call
+    Skipped,
+}
+
+/// Run one phase within `budget`
+fn run_phase(phase: Phase, budget: Duration) -> Outcome {
+    let finished = |finished| if finished { Outcome::Completed } else { Outcome::TimedOut };
+    match phase {
+        Phase::QualityFlush => finished(quality::flush(budget)),
+        Phase::BridgeHealthFlush => finished(bridge_health::flush(budget)),
+        Phase::Drain => {
+            // Only a handle, and only if the lock is free right now
+            let handle = match crate::TOKIO_RUNTIME.try_lock() {
+                Ok(runtime) => runtime.as_ref().map(|rt| rt.handle().clone()),
+                Err(_) => return Outcome::Skipped,
+            };
+            if let Some(handle) = handle {
+                // Built inside the runtime: a JNI thread has no reactor of its own
+                handle.block_on(async { seams::sleep(budget).await });
+            }
+            Outcome::Completed
+        }
+    }
+}
+
+struct Report {
+    deadline_ms: Option<u64>,
+    elapsed_ms: u64,
+    phases: Vec<serde_json::Value>,
+    at_epoch_ms: u64,
+}
+
+static LAST_REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Run the budgeted phases of a stop that began at `started`
+pub(crate) fn run(started: Instant, deadline: Option<Duration>) {
+    let spent = seams::now_monotonic().saturating_duration_since(started);
+    let available = deadline.map(|deadline| deadline.saturating_sub(spent).saturating_sub(MARGIN));
+    let mut phases = Vec::new();
+    for (phase, budget) in plan(available) {
+        let Some(budget) = budget else {
+            log_info!("Stop deadline: skipping {}", name(phase));
+            phases.push(json!({"name": name(phase), "budget_ms": 0, "elapsed_ms": 0, "outcome": "skipped"}));
+            continue;
+        };
+        let phase_started = seams::now_monotonic();
+        let outcome = run_phase(phase, budget);
+        let elapsed = seams::now_monotonic().saturating_duration_since(phase_started);
+        match outcome {
+            Outcome::Completed => {}
+            Outcome::TimedOut => log_error!("Stop deadline: {} abandoned after {} ms", name(phase), elapsed.as_millis()),
+            Outcome::Skipped => log_info!("Stop: skipping {}, the runtime is busy", name(phase)),
+        }
+        phases.push(json!({
+            "name": name(phase),
+            "budget_ms": budget.as_millis() as u64,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "outcome": match outcome {
+                Outcome::Completed => "completed",
+                Outcome::TimedOut => "timed_out",
+                Outcome::Skipped => "skipped",
+            },
+        }));
+    }
+    *LAST_REPORT.lock().unwrap() = Some(Report {
+        deadline_ms: deadline.map(|d| d.as_millis() as u64),
+        elapsed_ms: seams::now_monotonic().saturating_duration_since(started).as_millis() as u64,
+        phases,
+        at_epoch_ms: now_epoch_ms(),
+    });
+}
+
+/// The last stop's report as JSON, or `null` before the first stop
+pub(crate) fn report_json() -> String {
+    match LAST_REPORT.lock().unwrap().as_ref() {
+        Some(report) => json!({
+            "deadline_ms": report.deadline_ms,
+            "elapsed_ms": report.elapsed_ms,
+            "within_deadline": report.deadline_ms.map(|deadline| report.elapsed_ms <= deadline),
+            "phases": report.phases,
+            "at_epoch_ms": retention::timestamp(report.at_epoch_ms),
+        })
+        .to_string(),
+        None => "null".to_string(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&LAST_REPORT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn report() -> serde_json::Value {
+        serde_json::from_str(&report_json()).unwrap()
+    }
+
+    fn outcomes(report: &serde_json::Value) -> Vec<(String, String)> {
+        report["phases"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|phase| (phase["name"].as_str().unwrap().to_string(), phase["outcome"].as_str().unwrap().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn budgets_go_to_phases_in_priority_order() {
+        use Phase::*;
+        // (available, budgets of quality flush, bridge health flush, drain)
+        let cases: &[(Option<u64>, [Option<u64>; 3])] = &[
+            (None, [Some(2000), Some(2000), Some(100)]),
+            (Some(10_000), [Some(2000), Some(2000), Some(100)]),
+            (Some(4100), [Some(2000), Some(2000), Some(100)]),
+            (Some(4050), [Some(2000), Some(2000), Some(50)]),
+            (Some(4010), [Some(2000), Some(2000), None]),
+            (Some(2070), [Some(2000), Some(70), None]),
+            (Some(2030), [Some(2000), None, Some(30)]),
+            (Some(1000), [Some(1000), None, None]),
+            (Some(50), [Some(50), None, None]),
+            (Some(49), [None, None, Some(49)]),
+            (Some(19), [None, None, None]),
+            (Some(0), [None, None, None]),
+        ];
+        for (available, budgets) in cases {
+            let expected: Vec<_> =
+                [QualityFlush, BridgeHealthFlush, Drain].into_iter().zip(budgets.map(|b| b.map(ms))).collect();
+            assert_eq!(plan(available.map(ms)), expected, "available {:?}", available);
+        }
+    }
+
+    #[test]
+    fn budgets_never_exceed_what_is_available() {
+        for available in (0..5000).step_by(7).map(ms) {
+            let total: Duration = plan(Some(available)).into_iter().filter_map(|(_, budget)| budget).sum();
+            assert!(total <= available, "{:?} planned for {:?}", total, available);
+        }
+    }
+
+    #[test]
+    fn an_exceeded_deadline_skips_every_phase() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let started = seams::now_monotonic();
+        // Stopping the listener and connections already took longer than allowed
+        time.advance(ms(4000));
+        run(started, Some(ms(3000)));
+
+        let report = report();
+        assert_eq!((report["deadline_ms"].as_u64(), report["elapsed_ms"].as_u64()), (Some(3000), Some(4000)));
+        assert_eq!(report["within_deadline"], false);
+        assert!(outcomes(&report).iter().all(|(_, outcome)| outcome == "skipped"), "{}", report);
+        assert_eq!(outcomes(&report).len(), PHASES.len());
+    }
+
+    #[test]
+    fn a_tight_deadline_keeps_the_flushes() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let started = seams::now_monotonic();
+        time.advance(ms(900));
+        // 1000 ms left after what was spent; the margin leaves 950 for the quality flush
+        run(started, Some(ms(1900)));
+
+        let report = report();
+        let names = |outcome: &str| -> Vec<String> {
+            outcomes(&report).into_iter().filter(|(_, o)| o == outcome).map(|(name, _)| name).collect()
+        };
+        assert_eq!(names("completed"), ["quality_flush"]);
+        assert_eq!(names("skipped"), ["bridge_health_flush", "drain"]);
+        assert_eq!(report["phases"][0]["budget_ms"], 950);
+        assert_eq!(report["within_deadline"], true);
+    }
+
+    #[test]
+    fn without_a_deadline_every_phase_runs() {
+        let _serial = testing::serial();
+        let saved = LAST_REPORT.lock().unwrap().take();
+        assert_eq!(report_json(), "null");
+
+        run(seams::now_monotonic(), None);
+        let report = report();
+        assert!(report["deadline_ms"].is_null() && report["within_deadline"].is_null());
+        assert_eq!(
+            outcomes(&report),
+            PHASES.iter().map(|def| (def.name.to_string(), "completed".to_string())).collect::<Vec<_>>()
+        );
+        *LAST_REPORT.lock().unwrap() = saved;
+    }
+}
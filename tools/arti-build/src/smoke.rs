@@ -0,0 +1,582 @@
+//! End-to-end smoke test over loopback (`loopbackSmokeTest`)
+//!
+//! One call, runnable on a device in CI, that exercises the chain a release
+//! depends on: JNI marshalling, the lifecycle state, the SOCKS listener and a
+//! real Tor stream. It runs in legs:
+//!
+//! 1. `echo`: a tiny TCP echo server is started on an ephemeral loopback
+//!    port and a known pattern is pushed through it directly, which checks
+//!    the harness itself;
+//! 2. `listener`: the wrapper's own SOCKS listener is greeted as a client
+//!    would greet it;
+//! 3. `tor_leg`: if a test destination is configured (see
+//!    `setSmokeTestDestination`, e.g. a test onion service running an echo
+//!    server) and the client is ready for traffic, a CONNECT to it goes
+//!    through the listener and a real Tor stream, and the pattern is pushed
+//!    through and its echo verified.
+//!
+//! A leg that can't run is reported as `"skipped"` with the reason rather
+//! than as a failure, so without a destination everything up to the Tor
+//! connect is still validated. Nothing but the configured destination is
+//! contacted.
+//!
+//! In `testing-net` builds the Tor leg runs without Tor or a destination: its
+//! CONNECT goes to a private SOCKS session whose connector dials the echo
+//! server of the first leg instead of opening a Tor stream, so CI can run
+//! the whole chain (and the pattern check) with no network. The report says
+//! which way the leg went in `route`. Never ship a library built with it.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use arti_client::{DataStream, ErrorKind, StreamPrefs, TorClient};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::seams::{ConnectError, Connector, TorStream};
+use crate::{net, seams};
+
+/// Bytes pushed through each echo
+const PATTERN_BYTES: usize = 4096;
+
+/// Time allowed for each loopback leg
+const LOCAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time allowed for the Tor leg (connect and echo)
+const TOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `host:port` the Tor leg connects to (empty: skipped)
+static DESTINATION: Mutex<String> = Mutex::new(String::new());
+
+/// Where the Tor leg's stream goes
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Route {
+    /// Through the SOCKS listener and Arti to the configured destination
+    Tor,
+    /// Through a private SOCKS session to the echo server (`testing-net`)
+    Loopback,
+}
+
+impl Route {
+    fn name(self) -> &'static str {
+        match self {
+            Route::Tor => "tor",
+            Route::Loopback => "loopback",
+        }
+    }
+}
+
+/// Destination the loopback route asks for when none is configured; it is
+/// never resolved
+const LOOPBACK_DESTINATION: &str = "echo.smoke.test:7";
+
+/// Check a `smoke_test_destination` setting value: empty or `host:port`
+pub(crate) fn check(value: &Value) -> Result<(), String> {
+    let destination = value.as_str().ok_or("expected a host:port string")?;
+    if !destination.is_empty() {
+        split(destination).ok_or("expected host:port")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn set_destination(destination: &str) {
+    *DESTINATION.lock().unwrap() = destination.to_string();
+}
+
+fn split(destination: &str) -> Option<(&str, u16)> {
+    let (host, port) = destination.rsplit_once(':')?;
+    let port = port.parse().ok().filter(|p| *p != 0)?;
+    (!host.is_empty() && host.len() <= 255).then_some((host, port))
+}
+
+/// What the test found, leg by leg
+#[derive(Default)]
+struct Report {
+    echo: &'static str,
+    listener: &'static str,
+    tor_leg: &'static str,
+    /// Why the first leg not to pass didn't
+    detail: Option<String>,
+    echo_ms: Option<u64>,
+    listener_ms: Option<u64>,
+    connect_ms: Option<u64>,
+    round_trip_ms: Option<u64>,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl Report {
+    /// Record a leg that didn't pass; the first reason is kept
+    fn note(&mut self, detail: impl Into<String>) {
+        self.detail.get_or_insert_with(|| detail.into());
+    }
+}
+
+fn pattern() -> Vec<u8> {
+    (0..PATTERN_BYTES).map(|i| (i * 31 + 7) as u8).collect()
+}
+
+fn ms_since(started: Instant) -> u64 {
+    seams::now_monotonic().saturating_duration_since(started).as_millis() as u64
+}
+
+/// Push the pattern through `stream` and check it comes back unchanged
+async fn round_trip(stream: &mut TcpStream, report: &mut Report) -> Result<(), String> {
+    let pattern = pattern();
+    stream.write_all(&pattern).await.map_err(|e| format!("write failed: {}", e))?;
+    report.bytes_sent += pattern.len() as u64;
+    let mut echoed = vec![0u8; pattern.len()];
+    stream.read_exact(&mut echoed).await.map_err(|e| format!("read failed: {}", e))?;
+    report.bytes_received += echoed.len() as u64;
+    match pattern.iter().zip(&echoed).position(|(a, b)| a != b) {
+        Some(offset) => Err(format!("echo differs at byte {}", offset)),
+        None => Ok(()),
+    }
+}
+
+/// A connector whose streams go to the echo server on `echo_port`, whatever
+/// the destination
+struct LoopbackConnector {
+    echo_port: u16,
+}
+
+/// A "Tor stream" that is a loopback connection to the echo server
+struct LoopbackStream(TcpStream);
+
+impl AsyncRead for LoopbackStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LoopbackStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl TorStream for LoopbackStream {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    fn data_stream(&self) -> Option<&DataStream> {
+        None
+    }
+
+    fn exit_fingerprint(&self) -> Option<String> {
+        None
+    }
+
+    fn wait_for_connection(&mut self) -> BoxFuture<'_, Result<(), ConnectError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        self.0.into_split()
+    }
+}
+
+impl Connector for LoopbackConnector {
+    type Stream = LoopbackStream;
+
+    fn connect<'a>(
+        &'a self,
+        _host: &'a str,
+        _port: u16,
+        _prefs: &'a StreamPrefs,
+    ) -> BoxFuture<'a, Result<LoopbackStream, ConnectError>> {
+        Box::pin(async move {
+            net::connect_loopback(self.echo_port)
+                .await
+                .map(LoopbackStream)
+                .map_err(|e| ConnectError::fake(ErrorKind::LocalNetworkError, e))
+        })
+    }
+
+    fn client(&self) -> Option<&TorClient<PreferredRuntime>> {
+        None
+    }
+}
+
+/// Serve one SOCKS session on a private loopback listener, its streams going
+/// to the echo server; returns the listener's port
+async fn start_loopback_session(echo_port: u16) -> std::io::Result<(u16, tokio::task::JoinHandle<()>)> {
+    let listener = net::bind_listener("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let connector = Arc::new(LoopbackConnector { echo_port });
+    let task = tokio::spawn(async move {
+        if let Ok((stream, _)) = listener.accept().await {
+            let _ = crate::handle_socks_connection(stream, connector, crate::cancel::CancelToken::new()).await;
+        }
+    });
+    Ok((port, task))
+}
+
+/// Start the echo server; it stops when the returned handle is aborted
+async fn start_echo() -> std::io::Result<(u16, tokio::task::JoinHandle<()>)> {
+    let listener = net::bind_listener("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let task = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok((port, task))
+}
+
+async fn echo_leg(port: u16, report: &mut Report) -> Result<(), String> {
+    let started = seams::now_monotonic();
+    let result = tokio::time::timeout(LOCAL_TIMEOUT, async {
+        let mut stream = net::connect_loopback(port).await.map_err(|e| format!("echo connect failed: {}", e))?;
+        round_trip(&mut stream, report).await
+    })
+    .await
+    .unwrap_or_else(|_| Err("echo timed out".into()));
+    report.echo_ms = Some(ms_since(started));
+    result
+}
+
+/// Greet the listener on `port`; the stream if it accepts without credentials
+async fn listener_leg(port: u16, report: &mut Report) -> Result<Option<TcpStream>, String> {
+    let started = seams::now_monotonic();
+    let result = tokio::time::timeout(LOCAL_TIMEOUT, async {
+        let mut stream = net::connect_loopback(port).await.map_err(|e| format!("listener connect failed: {}", e))?;
+        stream.write_all(&[0x05, 0x01, 0x00]).await.map_err(|e| format!("greeting failed: {}", e))?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await.map_err(|e| format!("greeting reply failed: {}", e))?;
+        match choice {
+            [0x05, 0x00] => Ok(Some(stream)),
+            // The listener is up and enforcing its secret, which we don't use
+            [0x05, 0xFF] => Ok(None),
+            other => Err(format!("unexpected greeting reply {:02x?}", other)),
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err("listener greeting timed out".into()));
+    report.listener_ms = Some(ms_since(started));
+    result
+}
+
+async fn tor_leg(mut stream: TcpStream, host: &str, port: u16, report: &mut Report) -> Result<(), String> {
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let started = seams::now_monotonic();
+    let connected = tokio::time::timeout(TOR_TIMEOUT, async {
+        stream.write_all(&request).await.map_err(|e| format!("CONNECT failed: {}", e))?;
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.map_err(|e| format!("CONNECT reply failed: {}", e))?;
+        match reply[1] {
+            0x00 => Ok(()),
+            code => Err(format!("CONNECT refused with SOCKS reply {:#04x}", code)),
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err("CONNECT timed out".into()));
+    report.connect_ms = Some(ms_since(started));
+    connected?;
+
+    let started = seams::now_monotonic();
+    let echoed = tokio::time::timeout(TOR_TIMEOUT, round_trip(&mut stream, report))
+        .await
+        .unwrap_or_else(|_| Err("echo through Tor timed out".into()));
+    report.round_trip_ms = Some(ms_since(started));
+    echoed
+}
+
+/// Run the Tor leg through a private session to the echo server on `echo_port`
+async fn loopback_leg(echo_port: Option<u16>, report: &mut Report) {
+    let Some(echo_port) = echo_port else {
+        return report.note("no echo server for the loopback route");
+    };
+    let (port, session) = match start_loopback_session(echo_port).await {
+        Ok(started) => started,
+        Err(e) => {
+            report.tor_leg = "failed";
+            return report.note(format!("loopback session failed to start: {}", e));
+        }
+    };
+    let destination = DESTINATION.lock().unwrap().clone();
+    let (host, target_port) = split(&destination).or_else(|| split(LOOPBACK_DESTINATION)).unwrap();
+    let result = match listener_leg(port, report).await {
+        Ok(Some(stream)) => tor_leg(stream, host, target_port, report).await,
+        Ok(None) => Err("loopback session requires credentials".to_string()),
+        Err(e) => Err(e),
+    };
+    session.abort();
+    match result {
+        Ok(()) => report.tor_leg = "passed",
+        Err(e) => {
+            report.tor_leg = "failed";
+            report.note(e);
+        }
+    }
+}
+
+/// Run every leg that can run and report as JSON
+///
+/// `listener_port` is the SOCKS listener's port while it runs; `ready` is
+/// whether the client is ready for traffic.
+pub(crate) async fn run(state: &str, listener_port: Option<u16>, ready: bool) -> String {
+    let route = if cfg!(feature = "testing-net") { Route::Loopback } else { Route::Tor };
+    run_via(route, state, listener_port, ready).await
+}
+
+async fn run_via(route: Route, state: &str, listener_port: Option<u16>, ready: bool) -> String {
+    let mut report = Report { echo: "passed", listener: "skipped", tor_leg: "skipped", ..Report::default() };
+
+    let echo = match start_echo().await {
+        Ok((port, server)) => {
+            if let Err(e) = echo_leg(port, &mut report).await {
+                report.echo = "failed";
+                report.note(e);
+            }
+            Some((port, server))
+        }
+        Err(e) => {
+            report.echo = "failed";
+            report.note(format!("echo server failed to start: {}", e));
+            None
+        }
+    };
+
+    let greeted = match listener_port {
+        None => {
+            report.note("SOCKS listener not running");
+            None
+        }
+        Some(port) => match listener_leg(port, &mut report).await {
+            Ok(Some(stream)) => {
+                report.listener = "passed";
+                Some(stream)
+            }
+            Ok(None) => {
+                report.listener = "passed";
+                report.note("listener requires credentials");
+                None
+            }
+            Err(e) => {
+                report.listener = "failed";
+                report.note(e);
+                None
+            }
+        },
+    };
+
+    let destination = DESTINATION.lock().unwrap().clone();
+    if route == Route::Loopback {
+        // The listener's greeting was the check; the stream isn't needed
+        drop(greeted);
+        loopback_leg(echo.as_ref().map(|(port, _)| *port), &mut report).await;
+    } else if let Some(stream) = greeted {
+        match split(&destination) {
+            None => report.note("no test destination configured"),
+            Some(_) if !ready => report.note("client not ready for traffic"),
+            Some((host, port)) => match tor_leg(stream, host, port, &mut report).await {
+                Ok(()) => report.tor_leg = "passed",
+                Err(e) => {
+                    report.tor_leg = "failed";
+                    report.note(e);
+                }
+            },
+        }
+    }
+    if let Some((_, server)) = echo {
+        server.abort();
+    }
+
+    let ok = report.echo != "failed" && report.listener != "failed" && report.tor_leg != "failed";
+    let outcome = if ok { "passed" } else { "failed" };
+    log_info!(
+        "Loopback smoke test {}: echo {}, listener {}, Tor leg {} ({} route)",
+        outcome,
+        report.echo,
+        report.listener,
+        report.tor_leg,
+        route.name()
+    );
+    json!({
+        "ok": ok,
+        "state": state,
+        "echo": report.echo,
+        "listener": report.listener,
+        "tor_leg": report.tor_leg,
+        "route": route.name(),
+        "detail": report.detail,
+        "echo_ms": report.echo_ms,
+        "listener_ms": report.listener_ms,
+        "connect_ms": report.connect_ms,
+        "round_trip_ms": report.round_trip_ms,
+        "bytes_sent": report.bytes_sent,
+        "bytes_received": report.bytes_received,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&DESTINATION);
+}
+
+#[cfg(test)]
+mod tests {
+    use arti_client::ErrorKind;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::seams::fakes::{FakeConnect, FakeConnector};
+    use crate::testing;
+
+    /// A SOCKS listener like the wrapper's, its streams from `connector`
+    async fn start_listener(connector: Arc<FakeConnector>) -> (u16, JoinHandle<()>) {
+        let listener = net::bind_listener("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = crate::handle_socks_connection(stream, Arc::clone(&connector), crate::cancel::CancelToken::new());
+                tokio::spawn(handler);
+            }
+        });
+        (port, task)
+    }
+
+    /// Echo what reaches the destination end of a fake stream, flipping the
+    /// byte at `corrupt`
+    fn echo_at(mut destination: DuplexStream, corrupt: Option<usize>) {
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let mut seen = 0;
+            while let Ok(n @ 1..) = destination.read(&mut buf).await {
+                if let Some(at) = corrupt.filter(|at| (seen..seen + n).contains(at)) {
+                    buf[at - seen] ^= 0xFF;
+                }
+                seen += n;
+                if destination.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn parse(report: String) -> Value {
+        serde_json::from_str(&report).unwrap()
+    }
+
+    #[test]
+    fn the_loopback_route_runs_every_leg_without_tor() {
+        let _serial = testing::serial();
+        let runtime = testing::runtime();
+        set_destination("");
+        let connector = Arc::new(FakeConnector::new());
+        let (port, listener) = runtime.block_on(start_listener(Arc::clone(&connector)));
+
+        let report = parse(runtime.block_on(run_via(Route::Loopback, "running", Some(port), false)));
+        for (field, expected) in [
+            ("ok", json!(true)),
+            ("state", json!("running")),
+            ("echo", json!("passed")),
+            ("listener", json!("passed")),
+            ("tor_leg", json!("passed")),
+            ("route", json!("loopback")),
+            ("detail", Value::Null),
+            ("bytes_sent", json!(2 * PATTERN_BYTES)),
+            ("bytes_received", json!(2 * PATTERN_BYTES)),
+        ] {
+            assert_eq!(report[field], expected, "{}: {}", field, report);
+        }
+        assert!(report["connect_ms"].is_u64() && report["round_trip_ms"].is_u64(), "{}", report);
+        // The wrapper's listener was only greeted; nothing was dialed through it
+        assert!(connector.requests().is_empty());
+        listener.abort();
+
+        // Neither the listener nor a ready client is needed for the Tor leg
+        let report = parse(runtime.block_on(run_via(Route::Loopback, "stopped", None, false)));
+        assert_eq!(report["ok"], true, "{}", report);
+        assert_eq!(report["listener"], "skipped");
+        assert_eq!(report["tor_leg"], "passed");
+        assert_eq!(report["detail"], "SOCKS listener not running");
+    }
+
+    #[test]
+    fn the_tor_route_skips_what_it_cannot_run() {
+        let _serial = testing::serial();
+        let runtime = testing::runtime();
+        let connector = Arc::new(FakeConnector::new());
+        let (port, listener) = runtime.block_on(start_listener(Arc::clone(&connector)));
+
+        for (listener_port, destination, ready, detail) in [
+            (None, "echo.example:7", true, "SOCKS listener not running"),
+            (Some(port), "", true, "no test destination configured"),
+            (Some(port), "echo.example:7", false, "client not ready for traffic"),
+        ] {
+            set_destination(destination);
+            let report = parse(runtime.block_on(run_via(Route::Tor, "running", listener_port, ready)));
+            assert_eq!(report["ok"], true, "{}", report);
+            assert_eq!(report["echo"], "passed");
+            assert_eq!(report["tor_leg"], "skipped");
+            assert_eq!(report["route"], "tor");
+            assert_eq!(report["detail"], detail);
+            assert_eq!(report["bytes_sent"], PATTERN_BYTES);
+            assert_eq!(report["connect_ms"], Value::Null);
+        }
+        assert!(connector.requests().is_empty());
+        listener.abort();
+        set_destination("");
+    }
+
+    #[test]
+    fn the_tor_route_verifies_the_echo() {
+        let _serial = testing::serial();
+        let runtime = testing::runtime();
+        let connector = Arc::new(FakeConnector::new());
+        let (port, listener) = runtime.block_on(start_listener(Arc::clone(&connector)));
+        set_destination("echo.example:7");
+
+        let destination = connector.then_connect();
+        let report = parse(runtime.block_on(async {
+            echo_at(destination, None);
+            run_via(Route::Tor, "running", Some(port), true).await
+        }));
+        assert_eq!(report["ok"], true, "{}", report);
+        assert_eq!(report["tor_leg"], "passed");
+        assert_eq!(report["bytes_received"], 2 * PATTERN_BYTES);
+        assert_eq!(connector.requests(), [("echo.example".to_string(), 7)]);
+
+        let destination = connector.then_connect();
+        let report = parse(runtime.block_on(async {
+            echo_at(destination, Some(100));
+            run_via(Route::Tor, "running", Some(port), true).await
+        }));
+        assert_eq!(report["ok"], false, "{}", report);
+        assert_eq!(report["tor_leg"], "failed");
+        assert_eq!(report["detail"], "echo differs at byte 100");
+
+        connector.then(FakeConnect::Fail(ErrorKind::RemoteConnectionRefused, "refused"));
+        let report = parse(runtime.block_on(run_via(Route::Tor, "running", Some(port), true)));
+        assert_eq!(report["tor_leg"], "failed", "{}", report);
+        assert_eq!(report["detail"], "CONNECT refused with SOCKS reply 0x05");
+        assert!(report["round_trip_ms"].is_null());
+
+        listener.abort();
+        set_destination("");
+    }
+}
@@ -0,0 +1,110 @@
+//! SOCKS5 reply frames (RFC 1928 section 6)
+//!
+//! Every reply the proxy sends after reading a request goes through here, so
+//! the frame layout is defined once. The bound address is reported as IPv4
+//! 0.0.0.0:0, since clients only look at the reply code for CONNECT, except in
+//! the reply to the capability probe (see capabilities.rs).
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Reply codes the proxy uses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    /// A CONNECT target refused by wrapper policy (see target.rs)
+    NotAllowedByRuleset = 0x02,
+    /// Strict mode and the consensus is too old (see consensus_age.rs)
+    NetworkUnreachable = 0x03,
+    /// A connect that failed at the relay handshake or crypto layer (see
+    /// security.rs)
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    /// The connect outlasted the client's `timeout=` (see target.rs)
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl Reply {
+    /// VER REP RSV ATYP(IPv4) BND.ADDR(0.0.0.0) BND.PORT(0)
+    pub(crate) fn frame(self) -> [u8; 10] {
+        self.frame_with_bound([0; 4], 0)
+    }
+
+    /// VER REP RSV ATYP(IPv4) BND.ADDR(`addr`) BND.PORT(`port`)
+    pub(crate) fn frame_with_bound(self, addr: [u8; 4], port: u16) -> [u8; 10] {
+        let [port_hi, port_lo] = port.to_be_bytes();
+        [0x05, self as u8, 0x00, 0x01, addr[0], addr[1], addr[2], addr[3], port_hi, port_lo]
+    }
+}
+
+/// Send a failure reply, then close our side of the connection cleanly
+///
+/// The shutdown sends FIN after the reply, so the client reads the full
+/// frame before EOF instead of seeing a reset.
+pub(crate) async fn refuse<S: AsyncWrite + Unpin>(stream: &mut S, reply: Reply) -> std::io::Result<()> {
+    refuse_with_frame(stream, reply.frame()).await
+}
+
+/// `refuse` with a prepared failure frame
+pub(crate) async fn refuse_with_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: [u8; 10]) -> std::io::Result<()> {
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RFC 1928 code of each reply; a variant missing here doesn't compile
+    fn rfc_1928_code(reply: Reply) -> u8 {
+        match reply {
+            Reply::Succeeded => 0x00,
+            Reply::GeneralFailure => 0x01,
+            Reply::NotAllowedByRuleset => 0x02,
+            Reply::NetworkUnreachable => 0x03,
+            Reply::HostUnreachable => 0x04,
+            Reply::ConnectionRefused => 0x05,
+            Reply::TtlExpired => 0x06,
+            Reply::CommandNotSupported => 0x07,
+            Reply::AddressTypeNotSupported => 0x08,
+        }
+    }
+
+    #[test]
+    fn frames_match_rfc_1928() {
+        for (reply, code) in [
+            (Reply::Succeeded, 0x00),
+            (Reply::GeneralFailure, 0x01),
+            (Reply::NotAllowedByRuleset, 0x02),
+            (Reply::NetworkUnreachable, 0x03),
+            (Reply::HostUnreachable, 0x04),
+            (Reply::ConnectionRefused, 0x05),
+            (Reply::TtlExpired, 0x06),
+            (Reply::CommandNotSupported, 0x07),
+            (Reply::AddressTypeNotSupported, 0x08),
+        ] {
+            assert_eq!(reply.frame(), [0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0], "{:?}", reply);
+            assert_eq!(rfc_1928_code(reply), code, "{:?}", reply);
+            // Only codes the RFC assigns
+            assert!(code <= 0x08, "{:?}", reply);
+        }
+        assert_eq!(
+            Reply::Succeeded.frame_with_bound([127, 0, 0, 1], 9150),
+            [0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x23, 0xbe]
+        );
+    }
+
+    #[test]
+    fn refusal_is_the_frame_then_end_of_stream() {
+        crate::testing::runtime().block_on(async {
+            let (mut ours, mut theirs) = tokio::io::duplex(64);
+            refuse(&mut ours, Reply::NotAllowedByRuleset).await.unwrap();
+            let mut received = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut theirs, &mut received).await.unwrap();
+            assert_eq!(received, [0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        });
+    }
+}
@@ -0,0 +1,293 @@
+//! Detection of local clients that stop reading
+//!
+//! A local client can complete the SOCKS handshake, trigger a Tor connect and
+//! then never read its socket. Once the kernel buffers fill, our writes toward
+//! it (the success reply, then Tor->client forwarding) block forever, pinning
+//! a Tor stream and a buffer lease.
+//!
+//! Writes toward the client go through `StallWriter`, which stamps when a
+//! write first failed to make progress and clears the stamp as soon as one
+//! does. There are no per-connection timers: the runtime heartbeat (see
+//! liveness.rs) calls `sweep` once a second, which marks every connection
+//! whose write has been stuck for longer than the configured timeout. The
+//! connection task is waiting on `Watch::stalled` alongside its forwarding
+//! loop, so it tears down with close reason `client_stalled`, dropping the
+//! Tor stream and returning its lease.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncWrite;
+use tokio::sync::Notify;
+
+use crate::actor;
+use crate::liveness::monotonic_ms;
+
+/// Default time a write toward the client may go without progress
+pub(crate) const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// Longest configurable stall timeout
+pub(crate) const MAX_TIMEOUT_SECS: u64 = 3600;
+
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_SECS * 1000);
+
+/// Connections closed as `client_stalled` since process start
+static STALLED_CLOSES: AtomicU64 = AtomicU64::new(0);
+
+/// Every open connection's progress record (owned by the actor, see actor.rs)
+pub(crate) struct Registry(Vec<Weak<Progress>>);
+
+impl Registry {
+    pub(crate) const fn new() -> Self {
+        Registry(Vec::new())
+    }
+}
+
+pub(crate) struct Progress {
+    /// Monotonic ms when the current write stopped making progress (0 = not stuck)
+    blocked_since_ms: AtomicU64,
+    stalled: AtomicBool,
+    notify: Notify,
+}
+
+/// Write-progress record for one connection, registered with the sweep
+pub(crate) struct Watch(Arc<Progress>);
+
+impl Watch {
+    pub(crate) fn new() -> Self {
+        let progress = Arc::new(Progress {
+            blocked_since_ms: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+            notify: Notify::new(),
+        });
+        let weak = Arc::downgrade(&progress);
+        actor::post(move |state| state.stall.0.push(weak));
+        Watch(progress)
+    }
+
+    /// Wrap the client-facing writer so its progress is tracked
+    pub(crate) fn writer<W>(&self, inner: W) -> StallWriter<W> {
+        StallWriter {
+            inner,
+            progress: Arc::clone(&self.0),
+        }
+    }
+
+    /// Resolves once the sweep has declared this connection stalled
+    pub(crate) async fn stalled(&self) {
+        loop {
+            // Registered before the flag check, so a sweep in between isn't missed
+            let notified = self.0.notify.notified();
+            if self.0.stalled.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Writer adapter that records whether writes are making progress
+pub(crate) struct StallWriter<W> {
+    inner: W,
+    progress: Arc<Progress>,
+}
+
+impl<W> StallWriter<W> {
+    fn track<T>(&self, poll: Poll<T>) -> Poll<T> {
+        match poll {
+            Poll::Pending => {
+                let _ = self.progress.blocked_since_ms.compare_exchange(
+                    0,
+                    monotonic_ms(),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+            Poll::Ready(_) => self.progress.blocked_since_ms.store(0, Ordering::Relaxed),
+        }
+        poll
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for StallWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track(poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Set how long a write toward a client may go without progress
+pub(crate) fn set_timeout_secs(secs: u64) {
+    TIMEOUT_MS.store(secs * 1000, Ordering::Relaxed);
+}
+
+/// Count a connection closed because its client stopped reading
+pub(crate) fn count_close() {
+    STALLED_CLOSES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Connections closed as `client_stalled` since process start
+pub(crate) fn closes() -> u64 {
+    STALLED_CLOSES.load(Ordering::Relaxed)
+}
+
+/// Mark connections whose client-facing write is stuck past the timeout
+///
+/// Called from the heartbeat; also drops records of closed connections.
+pub(crate) fn sweep() {
+    let now = monotonic_ms();
+    let timeout = TIMEOUT_MS.load(Ordering::Relaxed);
+    actor::post(move |state| sweep_registry(&mut state.stall, now, timeout));
+}
+
+fn sweep_registry(registry: &mut Registry, now: u64, timeout: u64) {
+    registry.0.retain(|weak| {
+        let Some(progress) = weak.upgrade() else {
+            return false;
+        };
+        let since = progress.blocked_since_ms.load(Ordering::Relaxed);
+        if since != 0 && now.saturating_sub(since) >= timeout && !progress.stalled.swap(true, Ordering::AcqRel) {
+            progress.notify.notify_waiters();
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::seams::fakes::FakeConnector;
+    use crate::testing::{self, socks_connect, socks_greet, socks_session, FakeTime};
+
+    const TIMEOUT: Duration = Duration::from_secs(DEFAULT_TIMEOUT_SECS);
+
+    fn blocked_since(watch: &Watch) -> u64 {
+        watch.0.blocked_since_ms.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn a_write_stuck_past_the_timeout_is_stalled() {
+        let _serial = testing::serial();
+        let _time = FakeTime::start();
+        testing::runtime().block_on(async {
+            // The client side holds 64 bytes and is never read
+            let (client, _unread) = tokio::io::duplex(64);
+            let watch = Watch::new();
+            let mut registry = Registry::new();
+            registry.0.push(Arc::downgrade(&watch.0));
+            let mut writer = watch.writer(client);
+            let timeout = TIMEOUT.as_millis() as u64;
+
+            writer.write_all(&[0; 64]).await.unwrap();
+            assert_eq!(blocked_since(&watch), 0);
+            let stuck = tokio::spawn(async move { writer.write_all(&[0; 1]).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let since = blocked_since(&watch);
+            assert_ne!(since, 0);
+
+            for (after, stalled) in [(0, false), (timeout - 1, false), (timeout, true)] {
+                sweep_registry(&mut registry, since + after, timeout);
+                assert_eq!(watch.0.stalled.load(Ordering::Acquire), stalled, "{} ms", after);
+            }
+            tokio::time::timeout(Duration::from_secs(5), watch.stalled()).await.unwrap();
+
+            // Closed connections leave the registry at the next sweep
+            stuck.abort();
+            drop(watch);
+            let _ = stuck.await;
+            sweep_registry(&mut registry, since + timeout, timeout);
+            assert!(registry.0.is_empty());
+        });
+    }
+
+    #[test]
+    fn progress_clears_the_stamp() {
+        let _serial = testing::serial();
+        testing::runtime().block_on(async {
+            let (client, mut reader) = tokio::io::duplex(64);
+            let watch = Watch::new();
+            let mut writer = watch.writer(client);
+            writer.write_all(&[0; 64]).await.unwrap();
+            let resumed = tokio::spawn(async move {
+                writer.write_all(&[0; 32]).await.unwrap();
+                writer
+            });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_ne!(blocked_since(&watch), 0);
+
+            // The client reads a little: the write completes and isn't stuck anymore
+            let mut buf = [0; 64];
+            tokio::io::AsyncReadExt::read_exact(&mut reader, &mut buf).await.unwrap();
+            let _writer = resumed.await.unwrap();
+            assert_eq!(blocked_since(&watch), 0);
+        });
+    }
+
+    #[test]
+    fn a_client_that_stops_reading_is_closed_and_its_budget_released() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let closes_before = closes();
+        let connector = Arc::new(FakeConnector::new());
+        let mut service = connector.then_connect();
+        testing::runtime().block_on(async {
+            let (mut client, handler, _closer) = socks_session(Arc::clone(&connector)).await;
+            socks_greet(&mut client, &[0x00]).await;
+            assert_eq!(socks_connect(&mut client, "example.com", 443).await, crate::socks::Reply::Succeeded.frame());
+            assert_ne!(crate::buffers::in_use(), 0);
+
+            // The destination keeps sending; the client never reads another byte
+            let sent = Arc::new(AtomicU64::new(0));
+            let sending = {
+                let sent = Arc::clone(&sent);
+                tokio::spawn(async move {
+                    let chunk = vec![0u8; 64 * 1024];
+                    while service.write_all(&chunk).await.is_ok() {
+                        sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                    }
+                })
+            };
+            // Until every buffer on the way is full
+            let mut last = u64::MAX;
+            while sent.load(Ordering::Relaxed) != last {
+                last = sent.load(Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+
+            // Still open up to the timeout, closed right after it
+            for (advance, open) in [(Duration::ZERO, true), (TIMEOUT - Duration::from_secs(1), true), (Duration::from_secs(1), false)] {
+                time.advance(advance);
+                sweep();
+                let closed = tokio::time::timeout(Duration::from_millis(500), async {
+                    while !handler.is_finished() {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .is_ok();
+                assert_eq!(closed, !open, "after {:?}", advance);
+            }
+            handler.await.unwrap().unwrap();
+            assert_eq!(closes(), closes_before + 1);
+            assert_eq!(crate::buffers::in_use(), 0);
+            // The Tor stream was dropped along with the connection
+            tokio::time::timeout(Duration::from_secs(5), sending).await.unwrap().unwrap();
+            drop(client);
+        });
+    }
+}
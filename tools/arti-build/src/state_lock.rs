@@ -0,0 +1,484 @@
+//! Cross-process lock on the data directory
+//!
+//! Some OEM builds briefly run a second copy of the app process (an isolated
+//! service restarted next to the old one), and both copies call `initialize`
+//! on the same data directory. Arti's own state lock then fails one of them
+//! half-way through bootstrap, after both have written wrapper files (layout
+//! migration, histories, the guard state). So before either touches the
+//! directory, `initialize` takes an advisory `flock` on its `wrapper.lock`:
+//!
+//! - acquired: the file is rewritten with our PID, process start time and
+//!   acquisition time. Contents naming another process mean its holder died
+//!   without releasing (the kernel drops a dead process's `flock`, the file
+//!   keeps its last contents); that is logged as a recovered stale lock;
+//! - held by another process: `initialize` returns `INIT_ERR_STATE_LOCKED`,
+//!   logging and recording a `state_locked` failure with the holder read
+//!   from the file, which `getStateLockHolder` reports too. With a wait set
+//!   (`setStateLockWait`, off by default) it first retries for that long.
+//!
+//! The lock is held until `shutdown`, which clears the file, or until the
+//! process dies. A holder is identified by PID and start time (field 22 of
+//! `/proc/<pid>/stat`, clock ticks since boot), so a PID since reused by an
+//! unrelated process isn't mistaken for the holder: the holder is `alive`
+//! only when both match.
+//!
+//! Within the process nothing changes: lifecycle calls are serialized as
+//! before (bootstrap under the runtime lock, starts by `SOCKS_STARTING`).
+//! A `flock` belongs to the open file, so the process keeps a single one:
+//! acquiring for the directory already held is a no-op (a repeated or reused
+//! `initialize`), and moving to another directory (`migrateDataDirectory`
+//! and its rollback) releases the old lock before taking the new one. The
+//! file is rewritten in place, never replaced, which would leave the lock on
+//! an unlinked inode.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+use crate::{diagnostics, seams};
+
+/// Longest wait for another holder that can be set
+pub(crate) const MAX_WAIT_SECS: u64 = 60;
+
+/// Spacing of the retries while waiting
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Wait for another holder in seconds (0: fail at once)
+static WAIT_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Who holds (or held) a lock, as written in the lockfile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Holder {
+    pid: u32,
+    /// Process start in clock ticks since boot
+    start_ticks: u64,
+    since_epoch_ms: u64,
+}
+
+impl Holder {
+    fn ours() -> Holder {
+        Holder {
+            pid: std::process::id(),
+            start_ticks: start_ticks(std::process::id()).unwrap_or_default(),
+            since_epoch_ms: now_epoch_ms(),
+        }
+    }
+
+    fn parse(text: &str) -> Option<Holder> {
+        let value: Value = serde_json::from_str(text).ok()?;
+        Some(Holder {
+            pid: u32::try_from(value["pid"].as_u64()?).ok()?,
+            start_ticks: value["start_ticks"].as_u64()?,
+            since_epoch_ms: value["since_epoch_ms"].as_u64().unwrap_or_default(),
+        })
+    }
+
+    /// Whether this process wrote it (not an earlier one given the same PID)
+    fn is_ours(&self) -> bool {
+        self.pid == std::process::id() && start_ticks(self.pid) == Some(self.start_ticks)
+    }
+
+    /// Whether the process that wrote this is still running
+    fn alive(&self) -> bool {
+        start_ticks(self.pid) == Some(self.start_ticks)
+    }
+
+    /// The lockfile contents
+    fn record(self) -> Value {
+        json!({ "pid": self.pid, "start_ticks": self.start_ticks, "since_epoch_ms": self.since_epoch_ms })
+    }
+
+    fn to_json(self) -> Value {
+        let mut value = self.record();
+        value["alive"] = json!(self.alive());
+        value
+    }
+}
+
+/// Start time of process `pid` (None if there is no such process)
+fn start_ticks(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may hold spaces and parentheses; fields resume after
+    // the last ')' with field 3, so field 22 is the 20th from there
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The lock this process holds
+struct Held {
+    path: PathBuf,
+    file: File,
+    holder: Holder,
+}
+
+static HELD: Mutex<Option<Held>> = Mutex::new(None);
+
+/// Holder seen by the last acquire that found the lock taken
+static LAST_CONFLICT: Mutex<Option<Holder>> = Mutex::new(None);
+
+/// Why the lock couldn't be taken
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// Another process holds it (logged and recorded with the holder)
+    Locked,
+    Io(io::Error),
+}
+
+pub(crate) fn set_wait_secs(secs: u64) {
+    WAIT_SECS.store(secs, Ordering::Relaxed);
+}
+
+/// Try to `flock` `file` without blocking; Ok(false) if another holds it
+fn try_flock(file: &File) -> io::Result<bool> {
+    // SAFETY: the descriptor stays open for the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+fn read_holder(file: &File) -> Option<Holder> {
+    let mut buf = [0u8; 256];
+    let len = file.read_at(&mut buf, 0).ok()?;
+    Holder::parse(std::str::from_utf8(&buf[..len]).ok()?)
+}
+
+fn write_holder(file: &File, holder: Option<Holder>) -> io::Result<()> {
+    file.set_len(0)?;
+    if let Some(holder) = holder {
+        file.write_all_at(holder.record().to_string().as_bytes(), 0)?;
+    }
+    file.sync_data()
+}
+
+/// Take the lock on `data_dir`, waiting for another holder as configured
+pub(crate) fn acquire(data_dir: &Path) -> Result<(), Error> {
+    let path = layout::path(data_dir, Purpose::StateLock);
+    let mut held = HELD.lock().unwrap();
+    if held.as_ref().is_some_and(|held| held.path == path) {
+        return Ok(());
+    }
+    if let Some(previous) = held.take() {
+        release_held(previous);
+    }
+
+    std::fs::create_dir_all(data_dir).map_err(Error::Io)?;
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(Error::Io)?;
+    let wait = Duration::from_secs(WAIT_SECS.load(Ordering::Relaxed));
+    let deadline = seams::now_monotonic() + wait;
+    let mut logged_wait = false;
+    while !try_flock(&file).map_err(Error::Io)? {
+        let holder = read_holder(&file);
+        if seams::now_monotonic() >= deadline {
+            let shown = holder.map_or("an unknown process".to_string(), |h| {
+                format!("pid {} (started at tick {}, holding since {} ms)", h.pid, h.start_ticks, h.since_epoch_ms)
+            });
+            let message = format!("the data directory is locked by {} (waited {} s)", shown, wait.as_secs());
+            log_error!("STATE_LOCKED: {}", message);
+            diagnostics::record_failure("state_locked", message);
+            *LAST_CONFLICT.lock().unwrap() = holder;
+            return Err(Error::Locked);
+        }
+        if !logged_wait {
+            log_info!("Data directory locked by another process; waiting up to {} s", wait.as_secs());
+            logged_wait = true;
+        }
+        seams::sleep_blocking(RETRY_INTERVAL);
+    }
+
+    if let Some(stale) = read_holder(&file).filter(|holder| !holder.is_ours()) {
+        log_info!(
+            "Recovered the state lock of pid {} (held since {} ms), which {}",
+            stale.pid,
+            stale.since_epoch_ms,
+            if stale.alive() { "no longer holds it" } else { "exited without releasing it" }
+        );
+    }
+    let holder = Holder::ours();
+    if let Err(e) = write_holder(&file, Some(holder)) {
+        // The flock alone still excludes other processes
+        log_error!("Failed to record the state lock holder: {}", e);
+    }
+    *LAST_CONFLICT.lock().unwrap() = None;
+    *held = Some(Held { path, file, holder });
+    Ok(())
+}
+
+/// Clear the lockfile and drop the lock
+fn release_held(held: Held) {
+    if let Err(e) = write_holder(&held.file, None) {
+        log_error!("Failed to clear the state lock: {}", e);
+    }
+    // SAFETY: the descriptor stays open for the call (closing it unlocks too)
+    unsafe { libc::flock(held.file.as_raw_fd(), libc::LOCK_UN) };
+}
+
+/// Release the lock this process holds, if any (shutdown)
+pub(crate) fn release() {
+    if let Some(held) = HELD.lock().unwrap().take() {
+        release_held(held);
+        log_info!("State lock released");
+    }
+}
+
+/// The lock's state as JSON: whether we hold it and its holder
+///
+/// The holder is this process while it holds the lock, otherwise the one
+/// that held it at the last conflict (null if there was none). An acquire
+/// still waiting counts as not holding, rather than blocking the caller.
+pub(crate) fn holder_json() -> String {
+    let ours = HELD.try_lock().ok().and_then(|held| held.as_ref().map(|held| held.holder));
+    let (held, holder) = match ours {
+        Some(holder) => (true, Some(holder)),
+        None => (false, *LAST_CONFLICT.lock().unwrap()),
+    };
+    json!({ "held": held, "holder": holder.map(Holder::to_json) }).to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&HELD);
+    held.hold(&LAST_CONFLICT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+    use std::process::{Child, Command};
+
+    /// Releases whatever a test left locked and puts the wait back
+    struct Isolated;
+
+    impl Isolated {
+        fn new() -> Isolated {
+            release();
+            *LAST_CONFLICT.lock().unwrap() = None;
+            crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+            Isolated
+        }
+    }
+
+    impl Drop for Isolated {
+        fn drop(&mut self) {
+            release();
+            *LAST_CONFLICT.lock().unwrap() = None;
+            set_wait_secs(0);
+        }
+    }
+
+    /// A process standing in for the app's second copy, killed on drop
+    struct Other(Child);
+
+    impl Other {
+        fn spawn() -> Other {
+            Other(Command::new("sleep").arg("30").spawn().unwrap())
+        }
+
+        fn holder(&self) -> Holder {
+            Holder { pid: self.0.id(), start_ticks: start_ticks(self.0.id()).unwrap(), since_epoch_ms: 1_000 }
+        }
+    }
+
+    impl Drop for Other {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// A holder whose process has exited
+    fn exited() -> Holder {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        let start_ticks = start_ticks(pid).unwrap_or(1);
+        child.wait().unwrap();
+        Holder { pid, start_ticks, since_epoch_ms: 1_000 }
+    }
+
+    /// Our PID as an earlier process that was given it would have written it
+    fn reused_pid() -> Holder {
+        let ours = Holder::ours();
+        Holder { start_ticks: ours.start_ticks + 1, ..ours }
+    }
+
+    /// Lock `dir` as another process would: its own open file, its contents
+    fn compete(dir: &Path, holder: Holder) -> File {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = layout::path(dir, Purpose::StateLock);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap();
+        assert!(try_flock(&file).unwrap());
+        write_holder(&file, Some(holder)).unwrap();
+        file
+    }
+
+    /// Leave `holder` in the lockfile of `dir` without holding the lock
+    fn leave(dir: &Path, holder: Holder) {
+        drop(compete(dir, holder));
+    }
+
+    fn contents(dir: &Path) -> Option<Holder> {
+        read_holder(&File::open(layout::path(dir, Purpose::StateLock)).unwrap())
+    }
+
+    fn holder() -> Value {
+        serde_json::from_str(&holder_json()).unwrap()
+    }
+
+    fn logged(text: &str) -> bool {
+        crate::tests::SENT_LOG_LINES.lock().unwrap().iter().any(|line| line.contains(text))
+    }
+
+    #[test]
+    fn holders_are_told_apart_by_pid_and_start_time() {
+        let other = Other::spawn();
+        let table = [
+            ("this process", Holder::ours(), true, true),
+            ("another live process", other.holder(), false, true),
+            ("an exited process", exited(), false, false),
+            ("an earlier process with our pid", reused_pid(), false, false),
+        ];
+        for (case, holder, ours, alive) in table {
+            assert_eq!((holder.is_ours(), holder.alive()), (ours, alive), "{}", case);
+            assert_eq!(Holder::parse(&holder.record().to_string()), Some(holder), "{}", case);
+        }
+        for garbage in ["", "{}", "{\"pid\":-1,\"start_ticks\":1}", "{\"pid\":1}", "not json"] {
+            assert_eq!(Holder::parse(garbage), None, "{}", garbage);
+        }
+    }
+
+    #[test]
+    fn a_live_holder_keeps_the_lock() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let temp = TempDir::new();
+        let other = Other::spawn();
+        let theirs = compete(temp.path(), other.holder());
+
+        assert!(matches!(acquire(temp.path()), Err(Error::Locked)));
+        let reported = holder();
+        assert_eq!(reported["held"], false);
+        assert_eq!(reported["holder"]["pid"], other.0.id());
+        assert_eq!(reported["holder"]["alive"], true);
+        let error: Value = serde_json::from_str(&diagnostics::last_error_json()).unwrap();
+        assert_eq!(error["kind"], "state_locked");
+        assert!(error["message"].as_str().unwrap().contains(&format!("pid {} ", other.0.id())), "{}", error);
+        // Refused without touching the holder's record
+        assert_eq!(contents(temp.path()), Some(other.holder()));
+
+        // Once it lets go, the lock is ours and the file says so
+        drop(theirs);
+        assert!(acquire(temp.path()).is_ok());
+        assert!(logged(&format!("Recovered the state lock of pid {} (held since 1000 ms), which no longer holds it", other.0.id())));
+        assert!(contents(temp.path()).unwrap().is_ours());
+        let reported = holder();
+        assert_eq!((reported["held"].clone(), reported["holder"]["pid"].clone()), (json!(true), json!(std::process::id())));
+
+        // ...until released, which clears the file for the next holder
+        release();
+        assert_eq!(contents(temp.path()), None);
+        drop(compete(temp.path(), other.holder()));
+    }
+
+    #[test]
+    fn a_dead_holders_record_is_recovered() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        for (case, stale) in [("exited", exited()), ("pid reused", reused_pid())] {
+            let temp = TempDir::new();
+            leave(temp.path(), stale);
+
+            assert!(acquire(temp.path()).is_ok(), "{}", case);
+            assert!(logged(&format!("Recovered the state lock of pid {}", stale.pid)), "{}", case);
+            assert!(logged("exited without releasing it"), "{}", case);
+            let ours = contents(temp.path()).unwrap();
+            assert!(ours.is_ours() && ours != stale, "{}", case);
+            release();
+            crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+        }
+
+        // Our own record (a restart after shutdown didn't clear it) is no news
+        let temp = TempDir::new();
+        leave(temp.path(), Holder::ours());
+        assert!(acquire(temp.path()).is_ok());
+        assert!(!logged("Recovered the state lock"));
+    }
+
+    #[test]
+    fn a_set_wait_outlasts_a_holder_that_lets_go() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let time = FakeTime::start();
+        let other = Other::spawn();
+        set_wait_secs(1);
+
+        for releases in [true, false] {
+            let temp = TempDir::new();
+            let theirs = compete(temp.path(), other.holder());
+            let dir = temp.path().to_path_buf();
+            let waiting = std::thread::spawn(move || acquire(&dir));
+            // Let it find the lock taken and start waiting
+            while !logged("waiting up to 1 s") {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            assert!(!waiting.is_finished());
+            if releases {
+                drop(theirs);
+                time.advance(RETRY_INTERVAL);
+                assert!(waiting.join().unwrap().is_ok());
+                assert_eq!(holder()["held"], true);
+                release();
+            } else {
+                time.advance(Duration::from_secs(1));
+                assert!(matches!(waiting.join().unwrap(), Err(Error::Locked)));
+                assert!(logged("(waited 1 s)"));
+                drop(theirs);
+            }
+            crate::tests::SENT_LOG_LINES.lock().unwrap().clear();
+        }
+    }
+
+    #[test]
+    fn initialize_refuses_a_directory_another_process_holds() {
+        let _serial = testing::serial();
+        let _isolated = Isolated::new();
+        let temp = TempDir::new();
+        let (first, second) = (temp.path().join("first"), temp.path().join("second"));
+
+        // Within the process the lock is taken once per directory...
+        acquire(&first).unwrap();
+        let taken = contents(&first).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        acquire(&first).unwrap();
+        assert_eq!(contents(&first), Some(taken));
+        // ...and moving to another directory lets go of the old one
+        acquire(&second).unwrap();
+        assert_eq!(contents(&first), None);
+        let other = Other::spawn();
+        let theirs = compete(&first, other.holder());
+        release();
+
+        // Another process's lock fails initialize before it changes anything
+        let state = crate::LIFECYCLE_STATE.load(Ordering::SeqCst);
+        let code = crate::initialize_or_reuse(first.to_string_lossy().into_owned());
+        assert_eq!(code, crate::bootstrap::Failure::StateLocked.code());
+        assert_eq!(crate::LIFECYCLE_STATE.load(Ordering::SeqCst), state);
+        assert_eq!(holder()["holder"]["pid"], other.0.id());
+        let entries: Vec<_> = std::fs::read_dir(&first).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(entries, ["wrapper.lock"]);
+        drop(theirs);
+    }
+}
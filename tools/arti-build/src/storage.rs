@@ -0,0 +1,352 @@
+//! Data directory storage that disappears (adopted or removable storage)
+//!
+//! Some users put the data directory on adopted SD-card storage. When Android
+//! unmounts it (USB attach, card error) every state write starts failing with
+//! EIO or ENOENT. Rather than retrying into the void, a failed wrapper write
+//! whose error looks like missing storage triggers a check of the data
+//! directory itself: it must still exist and sit on the filesystem it was on
+//! at initialize (`statvfs`). If it doesn't, the storage is lost:
+//!
+//! - the lifecycle state becomes `StorageLost` (reported through the state
+//!   callback), and a failure record of kind `storage_lost` is written;
+//! - wrapper writes (audit trail, destination quality, traffic accounting)
+//!   are refused up front and counted, while the client keeps running in
+//!   memory, so existing connections are unaffected. Arti's own cache and
+//!   state writes fail on their own and are retried by Arti;
+//! - the heartbeat re-probes with exponential backoff (5 s doubling up to
+//!   5 min). Once the directory is back, `layout::prepare` checks and repairs
+//!   its layout and the audit trail is reopened from its on-disk head before
+//!   persistence resumes and the previous state is restored.
+//!
+//! A directory that comes back on a different filesystem is only accepted if
+//! it still holds a layout version file, i.e. it is the same data.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::backoff::{Backoff, Exponential};
+use crate::layout::{self, Purpose};
+use crate::liveness::monotonic_ms;
+use crate::notification::now_epoch_ms;
+use crate::{diagnostics, LifecycleState, LIFECYCLE_STATE};
+
+/// Re-probe schedule after the storage is lost: 5 s doubling up to 5 min
+const PROBE_SCHEDULE: Exponential = Exponential { initial_ms: 5_000, max_ms: 5 * 60 * 1000, jitter_percent: 0 };
+
+/// Data directory being watched and the filesystem id it had at initialize
+static WATCHED: Mutex<Option<(PathBuf, Option<u64>)>> = Mutex::new(None);
+
+static LOST: AtomicBool = AtomicBool::new(false);
+static LOST_SINCE_EPOCH_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Lifecycle state to restore once the storage is back
+static RESUME_STATE: AtomicI32 = AtomicI32::new(0);
+
+/// Re-probe bookkeeping (monotonic ms)
+static NEXT_PROBE_MS: AtomicU64 = AtomicU64::new(0);
+static BACKOFF: Mutex<Backoff> = Mutex::new(Backoff::new(PROBE_SCHEDULE));
+static PROBES: AtomicU64 = AtomicU64::new(0);
+
+/// Writes refused while the storage was lost
+static SUSPENDED_WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Filesystem facts from `statvfs`
+struct FsInfo {
+    id: u64,
+    free_bytes: u64,
+    total_bytes: u64,
+}
+
+fn statvfs(path: &Path) -> io::Result<FsInfo> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is NUL-terminated and stat is a valid out pointer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(FsInfo {
+        id: stat.f_fsid as u64,
+        free_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_bytes: stat.f_blocks as u64 * stat.f_frsize as u64,
+    })
+}
+
+/// Whether an IO error is the kind missing storage produces
+fn is_storage_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound
+        || matches!(
+            e.raw_os_error(),
+            Some(libc::EIO | libc::ENODEV | libc::ENXIO | libc::EROFS | libc::ESTALE | libc::ENOTCONN)
+        )
+}
+
+/// Whether `dir` is still there, on the filesystem it was on (or holding the same data)
+fn present(dir: &Path, fs_id: Option<u64>) -> bool {
+    if !dir.is_dir() {
+        return false;
+    }
+    match statvfs(dir) {
+        Ok(info) if fs_id.is_none_or(|id| id == info.id) => true,
+        Ok(_) => layout::path(dir, Purpose::LayoutVersion).is_file(),
+        Err(_) => false,
+    }
+}
+
+/// Watch `data_dir` (called at initialize, once its layout is prepared)
+pub(crate) fn watch(data_dir: &Path) {
+    let fs_id = statvfs(data_dir).ok().map(|info| info.id);
+    *WATCHED.lock().unwrap() = Some((data_dir.to_path_buf(), fs_id));
+    LOST.store(false, Ordering::Relaxed);
+}
+
+/// Refuse a write up front while the storage is lost
+pub(crate) fn check_writable() -> io::Result<()> {
+    if LOST.load(Ordering::Relaxed) {
+        SUSPENDED_WRITES.fetch_add(1, Ordering::Relaxed);
+        return Err(io::Error::other("data directory storage is unavailable; write suspended"));
+    }
+    Ok(())
+}
+
+/// Inspect a failed write; enters the lost state if the storage is gone
+pub(crate) fn observe(e: &io::Error) {
+    if LOST.load(Ordering::Relaxed) || !is_storage_error(e) {
+        return;
+    }
+    let Some((dir, fs_id)) = WATCHED.lock().unwrap().clone() else {
+        return;
+    };
+    if present(&dir, fs_id) {
+        return;
+    }
+    if LOST.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    LOST_SINCE_EPOCH_MS.store(now_epoch_ms(), Ordering::Relaxed);
+    let delay = {
+        let mut backoff = BACKOFF.lock().unwrap();
+        backoff.reset();
+        backoff.next_ms()
+    };
+    NEXT_PROBE_MS.store(monotonic_ms() + delay, Ordering::Relaxed);
+    RESUME_STATE.store(LIFECYCLE_STATE.load(Ordering::SeqCst), Ordering::Relaxed);
+
+    let message = format!("data directory storage is gone ({}); persistence suspended", e);
+    log_error!("STORAGE_LOST: {}", message);
+    diagnostics::record_failure("storage_lost", message);
+    crate::set_state(LifecycleState::StorageLost);
+}
+
+/// Re-probe for the storage when due (called from the heartbeat)
+pub(crate) fn probe() {
+    if !LOST.load(Ordering::Relaxed) || monotonic_ms() < NEXT_PROBE_MS.load(Ordering::Relaxed) {
+        return;
+    }
+    PROBES.fetch_add(1, Ordering::Relaxed);
+    let Some((dir, fs_id)) = WATCHED.lock().unwrap().clone() else {
+        return;
+    };
+
+    if present(&dir, fs_id) {
+        // Writes must be allowed again for the layout check to repair anything
+        LOST.store(false, Ordering::Relaxed);
+        match layout::prepare(&dir).and_then(|()| crate::audit::open(&dir)) {
+            Ok(()) => {
+                watch(&dir);
+                log_info!("Data directory storage is back; persistence resumed");
+                // Unless the app moved the lifecycle on while the storage was gone
+                if LIFECYCLE_STATE.load(Ordering::SeqCst) == LifecycleState::StorageLost as i32 {
+                    crate::set_state(LifecycleState::from_i32(RESUME_STATE.load(Ordering::Relaxed)));
+                }
+                return;
+            }
+            Err(e) => {
+                log_error!("Data directory is back but failed its consistency check: {}", e);
+                LOST.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    let delay = BACKOFF.lock().unwrap().next_ms();
+    NEXT_PROBE_MS.store(monotonic_ms() + delay, Ordering::Relaxed);
+}
+
+/// Storage condition as JSON
+pub(crate) fn stats_json() -> String {
+    let watched = WATCHED.lock().unwrap().clone();
+    let lost = LOST.load(Ordering::Relaxed);
+    let info = watched.as_ref().and_then(|(dir, _)| statvfs(dir).ok());
+    let next_probe = NEXT_PROBE_MS.load(Ordering::Relaxed).saturating_sub(monotonic_ms()) / 1000;
+    format!(
+        "{{\"state\":\"{}\",\"lost_since_epoch_ms\":{},\"probes\":{},\"next_probe_in_secs\":{},\"suspended_writes\":{},\"free_bytes\":{},\"total_bytes\":{}}}",
+        match (&watched, lost) {
+            (None, _) => "unwatched",
+            (Some(_), true) => "lost",
+            (Some(_), false) => "ok",
+        },
+        if lost { LOST_SINCE_EPOCH_MS.load(Ordering::Relaxed).to_string() } else { "null".to_string() },
+        PROBES.load(Ordering::Relaxed),
+        if lost { next_probe.to_string() } else { "null".to_string() },
+        SUSPENDED_WRITES.load(Ordering::Relaxed),
+        info.as_ref().map_or_else(|| "null".to_string(), |i| i.free_bytes.to_string()),
+        info.as_ref().map_or_else(|| "null".to_string(), |i| i.total_bytes.to_string()),
+    )
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&WATCHED);
+    held.hold(&BACKOFF);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+    use std::time::Duration;
+
+    /// Nothing watched and nothing lost; the lifecycle state is put back on drop
+    struct Restore(LifecycleState);
+
+    impl Restore {
+        fn start() -> Restore {
+            *WATCHED.lock().unwrap() = None;
+            LOST.store(false, Ordering::Relaxed);
+            Restore(LifecycleState::from_i32(LIFECYCLE_STATE.load(Ordering::SeqCst)))
+        }
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            *WATCHED.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = None;
+            LOST.store(false, Ordering::Relaxed);
+            crate::set_state(self.0);
+        }
+    }
+
+    fn stats() -> serde_json::Value {
+        serde_json::from_str(&stats_json()).unwrap()
+    }
+
+    #[test]
+    fn storage_errors_are_told_apart() {
+        for (error, storage) in [
+            (io::Error::from(io::ErrorKind::NotFound), true),
+            (io::Error::from_raw_os_error(libc::ENOENT), true),
+            (io::Error::from_raw_os_error(libc::EIO), true),
+            (io::Error::from_raw_os_error(libc::ENODEV), true),
+            (io::Error::from_raw_os_error(libc::EROFS), true),
+            (io::Error::from_raw_os_error(libc::ESTALE), true),
+            (io::Error::from_raw_os_error(libc::ENOSPC), false),
+            (io::Error::from_raw_os_error(libc::EACCES), false),
+            (io::Error::from(io::ErrorKind::PermissionDenied), false),
+            (io::Error::other("write suspended"), false),
+        ] {
+            assert_eq!(is_storage_error(&error), storage, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn reprobes_follow_the_documented_schedule() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _restore = Restore::start();
+        let temp = TempDir::new();
+        let dir = temp.path().join("data");
+        layout::prepare(&dir).unwrap();
+        watch(&dir);
+
+        // 5 s doubling up to 5 min, again from 5 s each time it is lost
+        for _ in 0..2 {
+            std::fs::remove_dir_all(&dir).unwrap();
+            observe(&io::Error::from_raw_os_error(libc::EIO));
+            for expected in [5, 10, 20, 40, 80, 160, 300, 300] {
+                assert_eq!(stats()["next_probe_in_secs"], expected);
+                time.advance(Duration::from_secs(expected));
+                probe();
+            }
+            std::fs::create_dir_all(&dir).unwrap();
+            time.advance(Duration::from_secs(300));
+            probe();
+            assert_eq!(stats()["state"], "ok");
+        }
+    }
+
+    #[test]
+    fn a_directory_on_another_filesystem_must_hold_the_same_data() {
+        let temp = TempDir::new();
+        let dir = temp.path().join("data");
+        let other_fs = Some(u64::MAX - 1);
+        assert!(!present(&dir, None));
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let fs_id = statvfs(&dir).unwrap().id;
+        assert!(present(&dir, None));
+        assert!(present(&dir, Some(fs_id)));
+        // An empty mount point in its place is not the data directory
+        assert!(!present(&dir, other_fs));
+        layout::prepare(&dir).unwrap();
+        assert!(present(&dir, other_fs));
+    }
+
+    #[test]
+    fn lost_storage_suspends_writes_until_a_probe_finds_it_again() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let _restore = Restore::start();
+        let temp = TempDir::new();
+        let dir = temp.path().join("data");
+        layout::prepare(&dir).unwrap();
+        watch(&dir);
+        crate::set_state(LifecycleState::Running);
+        assert_eq!(stats()["state"], "ok");
+
+        // A failure that isn't about storage, or with the directory still there, changes nothing
+        observe(&io::Error::from_raw_os_error(libc::ENOSPC));
+        observe(&io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(stats()["state"], "ok");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        observe(&io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(LIFECYCLE_STATE.load(Ordering::SeqCst), LifecycleState::StorageLost as i32);
+        let failure: serde_json::Value = serde_json::from_str(&diagnostics::last_error_json()).unwrap();
+        assert_eq!(failure["kind"], "storage_lost");
+
+        // Writes are refused up front, without touching the filesystem
+        let suspended = SUSPENDED_WRITES.load(Ordering::Relaxed);
+        let file = layout::path(&dir, Purpose::AuditLog);
+        assert!(layout::write_atomic(&file, b"x").is_err());
+        assert_eq!(SUSPENDED_WRITES.load(Ordering::Relaxed), suspended + 1);
+        assert!(!dir.exists());
+        assert_eq!(stats()["state"], "lost");
+        assert_eq!(stats()["next_probe_in_secs"], 5);
+
+        // Probes follow the backoff: none before it's due, then 5 s, 10 s, ...
+        let probes = PROBES.load(Ordering::Relaxed);
+        probe();
+        assert_eq!(PROBES.load(Ordering::Relaxed), probes);
+        time.advance(Duration::from_secs(5));
+        probe();
+        assert_eq!(PROBES.load(Ordering::Relaxed), probes + 1);
+        assert_eq!(stats()["next_probe_in_secs"], 10);
+        time.advance(Duration::from_secs(9));
+        probe();
+        assert_eq!(PROBES.load(Ordering::Relaxed), probes + 1);
+
+        // The storage comes back without its layout: the consistency check
+        // repairs it before persistence resumes and the state is restored
+        std::fs::create_dir_all(&dir).unwrap();
+        time.advance(Duration::from_secs(1));
+        probe();
+        assert_eq!(PROBES.load(Ordering::Relaxed), probes + 2);
+        assert_eq!(stats()["state"], "ok");
+        assert!(layout::path(&dir, Purpose::ArtiState).is_dir());
+        assert_eq!(LIFECYCLE_STATE.load(Ordering::SeqCst), LifecycleState::Running as i32);
+        layout::write_atomic(&file, b"x").unwrap();
+    }
+}
@@ -0,0 +1,689 @@
+//! SOCKS credential convention and CONNECT target evaluation
+//!
+//! Clients that want to pass per-connection options authenticate with
+//! username/password (RFC 1929) and put `key=value` pairs separated by `;` in
+//! the username. Unknown keys are ignored so older wrappers stay compatible
+//! with newer clients. With a listener secret set, the username starts with
+//! the secret's username (see listener_auth.rs), a bare field skipped here.
+//! Supported keys:
+//!
+//! - `host=<name>`: the IP-literal CONNECT target was pre-resolved by the
+//!   client (e.g. from a signed multiaddr) and corresponds to `<name>`.
+//! - `front=<name>`: domain fronting. The CONNECT target is the name the
+//!   client's own TLS talks to (its SNI or Host header); the proxy dials
+//!   `<name>` instead, on the same port. The proxy never looks at the bytes
+//!   it relays, so the ClientHello reaches the front unmodified; choosing an
+//!   SNI is entirely up to the client. Fronting for meek bridges is separate
+//!   and configured through the bridge line's `url=`/`front=` arguments.
+//! - `timeout=<secs>`: give up on the Tor connect after `<secs>` seconds
+//!   (1 to `MAX_CONNECT_TIMEOUT_SECS`) with SOCKS reply `0x06` (TTL expired).
+//!   Without it, or with a value that isn't a whole number in range, Arti's
+//!   own stream timeout applies. Only the connect is timed, not the relaying.
+//! - `deadline=<ms>`: give up on the Tor connect at `<ms>` milliseconds since
+//!   the Unix epoch, also with reply `0x06`; a deadline already past is
+//!   refused before anything is dialed. With `timeout=` as well, whichever
+//!   ends first applies. Anything but a whole number is ignored.
+//! - `class=<name>`: the client's traffic class (e.g. `bulk`, `chat`).
+//!   Streams of different classes never share a circuit, and
+//!   `listConnections` shows the class.
+//! - `connid=<id>`: the client's own id for the connection, shown by
+//!   `listConnections` so the two sides' records can be matched up.
+//! - `iso=<token>`: an isolation token. Streams with different tokens never
+//!   share a circuit (see `IsolationKey` in lib.rs); streams without one
+//!   are isolated only as the global modes say.
+//!
+//! `class=`, `connid=` and `iso=` take up to `MAX_LABEL_LEN` letters, digits, `-`,
+//! `_` and `.`; other values are ignored. Class names are case-insensitive,
+//! isolation tokens are not. For example
+//! `host=example.com;timeout=30;class=chat;connid=c-17;iso=tab-3`.
+//!
+//! Evaluation order for a CONNECT, first failing step wins:
+//!
+//! 1. Parse the SOCKS request into a literal target (`dial_host`, `port`).
+//! 2. Parse the credentials. Malformed pairs are skipped, never fatal.
+//! 3. If a `host=` claim is present:
+//!    - the CONNECT target must be an IP literal (`ClaimWithoutLiteral`);
+//!    - the claimed name must be a plain hostname, not an IP literal or
+//!      `.onion` (`InvalidClaim`);
+//!    - the literal must not be a blocked (local/private) address, since a
+//!      name can't launder an address we would refuse on its own
+//!      (`ClaimForBlockedLiteral`).
+//! 4. With domain targets required (`setRequireDomainTargets`), refuse an
+//!    IP-literal CONNECT target that carries no `host=` claim
+//!    (`LiteralWithoutName`). A literal with a valid claim is accepted: the
+//!    claim names it, and step 3 has already vetted the pair.
+//! 5. If a `front=` override is present, the CONNECT target and the front
+//!    must both be plain hostnames (`InvalidFront`); this also rules out
+//!    combining it with `host=`.
+//! 6. Apply the destination policy to the target, the claimed name and the
+//!    front: a denylist match refuses (`Denied`), and with a non-empty
+//!    allowlist a target matching no entry is refused too (`NotAllowed`).
+//!    Patterns are `host`, `*.domain` (the domain's subdomains) or either
+//!    with a `:port` suffix; matching is case-insensitive.
+//! 7. Unless fronting, map a name with a configured onion alias
+//!    (`addOnionAlias`): the name the client asked for (the claim, else the
+//!    CONNECT target) is dialed as its `.onion` instead, on the same port, so
+//!    the traffic stays end-to-end inside Tor. Only names are mapped, never
+//!    a literal, and the policy has already been applied to the clearnet
+//!    name in step 6.
+//! 8. In onion-only mode (`setOnionOnly`), refuse a `dial_host` that isn't a
+//!    `.onion` address after step 7 (`NotOnion`), so nothing ever leaves
+//!    through an exit. Names with an onion alias still connect.
+//! 9. Connect to `dial_host` (the front or onion alias, if any); use
+//!    `report_host()` (the claimed name, or the CONNECT target when fronting
+//!    or upgraded) for everything keyed by destination: logs and
+//!    per-destination bookkeeping. Streams are pinned to circuits by the
+//!    isolation key (see `IsolationKey` in lib.rs), which includes
+//!    `claimed_host`: a pre-resolved literal shares circuits with streams
+//!    for the same claimed name only, never with another name that happens
+//!    to resolve to the same address.
+//!
+//! So a claim is checked before the domain requirement it satisfies, the
+//! policy sees every name before any mapping, and onion-only mode judges
+//! the final, mapped target.
+//!
+//! Every rejection is answered with SOCKS reply `0x02` (connection not
+//! allowed by ruleset).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+/// Longest `timeout=` a client may ask for
+pub(crate) const MAX_CONNECT_TIMEOUT_SECS: u64 = 300;
+
+/// Longest `class=`, `connid=` or `iso=` value
+pub(crate) const MAX_LABEL_LEN: usize = 64;
+
+/// Options carried in the SOCKS username
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ConnectCredentials {
+    /// Hostname an IP-literal target was resolved from (`host=`)
+    pub host_claim: Option<String>,
+    /// Hostname to dial instead of the CONNECT target (`front=`)
+    pub front: Option<String>,
+    /// Deadline for the Tor connect (`timeout=`)
+    pub connect_timeout: Option<Duration>,
+    /// Wall-clock deadline for the Tor connect (`deadline=`)
+    pub deadline: Option<SystemTime>,
+    /// Traffic class, lowercase (`class=`)
+    pub class: Option<String>,
+    /// The client's id for the connection (`connid=`)
+    pub connid: Option<String>,
+    /// Isolation token (`iso=`)
+    pub isolation: Option<String>,
+}
+
+impl ConnectCredentials {
+    /// Parse the `key=value;key=value` username convention
+    pub(crate) fn parse(username: &str) -> Self {
+        let mut creds = ConnectCredentials::default();
+        for pair in username.split(';') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "host" if !value.trim().is_empty() => {
+                    creds.host_claim = Some(value.trim().to_ascii_lowercase());
+                }
+                "front" if !value.trim().is_empty() => {
+                    creds.front = Some(value.trim().to_ascii_lowercase());
+                }
+                "timeout" => {
+                    creds.connect_timeout = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|secs| (1..=MAX_CONNECT_TIMEOUT_SECS).contains(secs))
+                        .map(Duration::from_secs);
+                }
+                "deadline" => {
+                    creds.deadline = value.trim().parse().ok().map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+                }
+                "class" => creds.class = label(value).map(|class| class.to_ascii_lowercase()),
+                "connid" => creds.connid = label(value),
+                "iso" => creds.isolation = label(value),
+                _ => {}
+            }
+        }
+        creds
+    }
+
+    /// Time the Tor connect may take: the tighter of `timeout=` and what is
+    /// left until `deadline=` (zero once it has passed), if either is given
+    pub(crate) fn connect_limit(&self) -> Option<Duration> {
+        let left = self
+            .deadline
+            .map(|deadline| deadline.duration_since(crate::seams::now_wall()).unwrap_or(Duration::ZERO));
+        match (self.connect_timeout, left) {
+            (Some(timeout), Some(left)) => Some(timeout.min(left)),
+            (timeout, left) => timeout.or(left),
+        }
+    }
+}
+
+/// A `class=`, `connid=` or `iso=` value, if well formed
+fn label(value: &str) -> Option<String> {
+    let value = value.trim();
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    (!value.is_empty() && value.len() <= MAX_LABEL_LEN && value.chars().all(allowed)).then(|| value.to_string())
+}
+
+/// Why a CONNECT target was refused before reaching Tor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TargetRejection {
+    /// `host=` was claimed but the CONNECT target is a hostname
+    ClaimWithoutLiteral,
+    /// The claimed name is not a usable hostname
+    InvalidClaim,
+    /// `host=` was claimed for a local/private literal
+    ClaimForBlockedLiteral,
+    /// Domain targets are required and an IP literal came without a claim
+    LiteralWithoutName,
+    /// `front=` with a front or CONNECT target that isn't a plain hostname
+    InvalidFront,
+    /// The target matches the destination denylist
+    Denied,
+    /// An allowlist is configured and the target matches none of it
+    NotAllowed,
+    /// Onion-only mode and the target would leave through an exit
+    NotOnion,
+}
+
+impl TargetRejection {
+    /// Stable classification string for logs and the Java layer
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            TargetRejection::ClaimWithoutLiteral => "host_claim_without_literal",
+            TargetRejection::InvalidClaim => "host_claim_invalid",
+            TargetRejection::ClaimForBlockedLiteral => "host_claim_blocked_literal",
+            TargetRejection::LiteralWithoutName => "literal_without_name",
+            TargetRejection::InvalidFront => "front_invalid",
+            TargetRejection::Denied => "destination_denied",
+            TargetRejection::NotAllowed => "destination_not_allowed",
+            TargetRejection::NotOnion => "onion_only",
+        }
+    }
+}
+
+/// A CONNECT target after credential evaluation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Destination {
+    /// Address actually handed to Arti
+    pub dial_host: String,
+    pub port: u16,
+    /// Name the client's traffic is for, when it differs from `dial_host`:
+    /// the `host=` claim, or the CONNECT target when fronting or upgraded
+    pub claimed_host: Option<String>,
+    /// `dial_host` is the onion alias of `claimed_host`
+    pub onion_upgraded: bool,
+}
+
+impl Destination {
+    /// Host used for isolation, logs, and per-destination bookkeeping
+    pub(crate) fn report_host(&self) -> &str {
+        self.claimed_host.as_deref().unwrap_or(&self.dial_host)
+    }
+}
+
+/// A destination policy pattern: `host`, `*.domain`, optionally `:port`
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Pattern {
+    host: String,
+    subdomains: bool,
+    port: Option<u16>,
+}
+
+impl Pattern {
+    fn parse(text: &str) -> Option<Pattern> {
+        let text = text.trim().to_ascii_lowercase();
+        // A port suffix, unless the colon belongs to an IPv6 literal
+        let (host, port) = match text.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host.to_string(), Some(port.parse().ok()?)),
+            _ => (text, None),
+        };
+        let (host, subdomains) = match host.strip_prefix("*.") {
+            Some(domain) => (domain.to_string(), true),
+            None => (host, false),
+        };
+        if host.is_empty() || host.contains('*') {
+            return None;
+        }
+        Some(Pattern { host, subdomains, port })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+        let host = host.to_ascii_lowercase();
+        match self.subdomains {
+            true => host.strip_suffix(&self.host).is_some_and(|rest| rest.ends_with('.')),
+            false => host == self.host,
+        }
+    }
+}
+
+struct Policy {
+    deny: Vec<Pattern>,
+    allow: Vec<Pattern>,
+}
+
+static POLICY: RwLock<Policy> = RwLock::new(Policy { deny: Vec::new(), allow: Vec::new() });
+
+fn parse_patterns(value: &Value) -> Result<Vec<Pattern>, String> {
+    let items = value.as_array().ok_or("expected an array of destination patterns")?;
+    items
+        .iter()
+        .map(|item| {
+            let text = item.as_str().ok_or("expected an array of destination patterns")?;
+            Pattern::parse(text).ok_or_else(|| format!("invalid destination pattern: {}", text))
+        })
+        .collect()
+}
+
+/// Check a `destination_denylist`/`destination_allowlist` setting value
+pub(crate) fn check_patterns(value: &Value) -> Result<(), String> {
+    parse_patterns(value).map(|_| ())
+}
+
+/// Replace the denylist (value already checked)
+pub(crate) fn set_denylist(value: &Value) {
+    POLICY.write().unwrap().deny = parse_patterns(value).unwrap_or_default();
+}
+
+/// Replace the allowlist (value already checked; empty allows everything)
+pub(crate) fn set_allowlist(value: &Value) {
+    POLICY.write().unwrap().allow = parse_patterns(value).unwrap_or_default();
+}
+
+/// Refuse IP-literal targets without a `host=` claim (step 4)
+static REQUIRE_DOMAIN_TARGETS: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_require_domain_targets(enabled: bool) {
+    REQUIRE_DOMAIN_TARGETS.store(enabled, Ordering::Relaxed);
+}
+
+/// Refuse every target that isn't an onion address (step 8)
+static ONION_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_onion_only(enabled: bool) {
+    ONION_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn onion_only() -> bool {
+    ONION_ONLY.load(Ordering::Relaxed)
+}
+
+/// Clearnet host to onion address, for the upgrade in step 7
+static ONION_ALIASES: RwLock<Option<HashMap<String, String>>> = RwLock::new(None);
+
+/// Whether `name` is a v3 onion address
+fn is_onion_v3(name: &str) -> bool {
+    name.strip_suffix(".onion")
+        .is_some_and(|id| id.len() == 56 && id.bytes().all(|b| matches!(b, b'a'..=b'z' | b'2'..=b'7')))
+}
+
+/// Check an `onion_aliases` setting value (clearnet host to onion address)
+pub(crate) fn check_onion_aliases(value: &Value) -> Result<(), String> {
+    let aliases = value.as_object().ok_or("expected an object of clearnet host to onion address")?;
+    for (host, onion) in aliases {
+        if !is_valid_hostname(host) || host.to_ascii_lowercase() != *host {
+            return Err(format!("invalid clearnet host: {}", host));
+        }
+        if !onion.as_str().is_some_and(is_onion_v3) {
+            return Err(format!("invalid onion address for {}", host));
+        }
+    }
+    Ok(())
+}
+
+/// Replace the onion aliases (value already checked)
+pub(crate) fn set_onion_aliases(value: &Value) {
+    let aliases = value
+        .as_object()
+        .map(|aliases| {
+            aliases
+                .iter()
+                .filter_map(|(host, onion)| Some((host.clone(), onion.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    *ONION_ALIASES.write().unwrap() = Some(aliases);
+}
+
+/// The onion alias of `host`, if one is configured
+fn onion_alias(host: &str) -> Option<String> {
+    ONION_ALIASES.read().unwrap().as_ref()?.get(&host.to_ascii_lowercase()).cloned()
+}
+
+/// Apply the destination policy to every name the target goes by (step 6)
+fn check_policy(hosts: &[&str], port: u16) -> Result<(), TargetRejection> {
+    let policy = POLICY.read().unwrap();
+    let any = |patterns: &[Pattern]| hosts.iter().any(|h| patterns.iter().any(|p| p.matches(h, port)));
+    if any(&policy.deny) {
+        return Err(TargetRejection::Denied);
+    }
+    if !policy.allow.is_empty() && !any(&policy.allow) {
+        return Err(TargetRejection::NotAllowed);
+    }
+    Ok(())
+}
+
+/// Evaluate a literal CONNECT target against the credentials and the
+/// destination policy (steps 3–8 above)
+pub(crate) fn evaluate(
+    dial_host: String,
+    port: u16,
+    creds: &ConnectCredentials,
+) -> Result<Destination, TargetRejection> {
+    if let Some(claim) = &creds.host_claim {
+        let literal: IpAddr = dial_host
+            .parse()
+            .map_err(|_| TargetRejection::ClaimWithoutLiteral)?;
+
+        if !is_valid_hostname(claim) {
+            return Err(TargetRejection::InvalidClaim);
+        }
+
+        if is_blocked_literal(&literal) {
+            return Err(TargetRejection::ClaimForBlockedLiteral);
+        }
+    } else if REQUIRE_DOMAIN_TARGETS.load(Ordering::Relaxed) && dial_host.parse::<IpAddr>().is_ok() {
+        return Err(TargetRejection::LiteralWithoutName);
+    }
+
+    if let Some(front) = creds.front.clone() {
+        if creds.host_claim.is_some() || !is_valid_hostname(&dial_host) || !is_valid_hostname(&front) {
+            return Err(TargetRejection::InvalidFront);
+        }
+        check_policy(&[&dial_host, &front], port)?;
+        // A front is a plain hostname, so always dialed through an exit
+        if ONION_ONLY.load(Ordering::Relaxed) {
+            return Err(TargetRejection::NotOnion);
+        }
+        return Ok(Destination { dial_host: front, port, claimed_host: Some(dial_host), onion_upgraded: false });
+    }
+
+    let destination = match creds.host_claim.clone() {
+        Some(claim) => {
+            check_policy(&[&dial_host, &claim], port)?;
+            Destination { dial_host, port, claimed_host: Some(claim), onion_upgraded: false }
+        }
+        None => {
+            check_policy(&[&dial_host], port)?;
+            Destination { dial_host, port, claimed_host: None, onion_upgraded: false }
+        }
+    };
+
+    let destination = match onion_alias(destination.report_host()) {
+        Some(onion) => Destination {
+            claimed_host: Some(destination.report_host().to_string()),
+            dial_host: onion,
+            port,
+            onion_upgraded: true,
+        },
+        None => destination,
+    };
+
+    if ONION_ONLY.load(Ordering::Relaxed) && !destination.dial_host.to_ascii_lowercase().ends_with(".onion") {
+        return Err(TargetRejection::NotOnion);
+    }
+    Ok(destination)
+}
+
+/// Whether `name` is a plain DNS hostname (not an IP literal or onion address)
+fn is_valid_hostname(name: &str) -> bool {
+    if name.len() > 253 || name.parse::<IpAddr>().is_ok() || name.ends_with(".onion") {
+        return false;
+    }
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+/// Whether an IP literal points at local or private address space
+pub(crate) fn is_blocked_literal(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80 // link local
+                || v6.to_ipv4_mapped().is_some_and(|v4| is_blocked_literal(&IpAddr::V4(v4)))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold_rw(&POLICY);
+    held.hold_rw(&ONION_ALIASES);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    const ONION: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+    /// Settings a case runs under; the default is the wrapper's default
+    #[derive(Default)]
+    struct Settings {
+        require_domain: bool,
+        onion_only: bool,
+        deny: &'static [&'static str],
+        allow: &'static [&'static str],
+        alias: Option<(&'static str, &'static str)>,
+    }
+
+    impl Settings {
+        fn apply(&self) {
+            set_require_domain_targets(self.require_domain);
+            set_onion_only(self.onion_only);
+            set_denylist(&json!(self.deny));
+            set_allowlist(&json!(self.allow));
+            set_onion_aliases(&json!(self.alias.map(|(host, onion)| json!({ host: onion })).unwrap_or(json!({}))));
+        }
+    }
+
+    fn dest(dial_host: &str, claimed_host: Option<&str>, onion_upgraded: bool) -> Destination {
+        Destination { dial_host: dial_host.into(), port: 443, claimed_host: claimed_host.map(Into::into), onion_upgraded }
+    }
+
+    #[test]
+    fn credentials_parse_known_keys_only() {
+        let creds = ConnectCredentials::parse("secret; host=Example.COM ;timeout=30;junk;front=;color=red");
+        assert_eq!(creds.host_claim.as_deref(), Some("example.com"));
+        assert_eq!(creds.front, None);
+        assert_eq!(creds.connect_timeout, Some(Duration::from_secs(30)));
+        for timeout in ["0", "301", "-1", "1.5", "soon"] {
+            assert_eq!(ConnectCredentials::parse(&format!("timeout={}", timeout)).connect_timeout, None, "{}", timeout);
+        }
+    }
+
+    #[test]
+    fn credentials_parse_class_deadline_and_connid() {
+        let creds = ConnectCredentials::parse("class= Chat ;deadline=1700000000123;connid=c-17.a_b");
+        assert_eq!(creds.class.as_deref(), Some("chat"));
+        assert_eq!(creds.deadline, Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)));
+        assert_eq!(creds.connid.as_deref(), Some("c-17.a_b"));
+        assert_eq!(ConnectCredentials::parse(&format!("connid={}", "a".repeat(MAX_LABEL_LEN))).connid.map(|id| id.len()), Some(MAX_LABEL_LEN));
+
+        let long = "a".repeat(MAX_LABEL_LEN + 1);
+        for bad in ["", "a b", "a/b", "ü", long.as_str()] {
+            let creds = ConnectCredentials::parse(&format!("class={};connid={}", bad, bad));
+            assert_eq!((creds.class, creds.connid), (None, None), "{:?}", bad);
+        }
+        assert_eq!(ConnectCredentials::parse("iso=Tab-3").isolation.as_deref(), Some("Tab-3"));
+        assert_eq!(ConnectCredentials::parse("iso=;iso=a b").isolation, None);
+        for deadline in ["", "-1", "1.5", "tomorrow"] {
+            assert_eq!(ConnectCredentials::parse(&format!("deadline={}", deadline)).deadline, None, "{}", deadline);
+        }
+    }
+
+    #[test]
+    fn the_tighter_of_timeout_and_deadline_limits_the_connect() {
+        let _serial = testing::serial();
+        let time = crate::testing::FakeTime::start();
+        let since_epoch = || crate::seams::now_wall().duration_since(UNIX_EPOCH).unwrap();
+        // On a whole millisecond, as deadlines are given
+        time.advance(Duration::from_millis(1) - Duration::from_nanos(u64::from(since_epoch().subsec_nanos()) % 1_000_000));
+        let now_ms = since_epoch().as_millis();
+        assert_eq!(since_epoch().subsec_nanos() % 1_000_000, 0);
+        let limit = |username: String| ConnectCredentials::parse(&username).connect_limit();
+
+        assert_eq!(limit(String::new()), None);
+        assert_eq!(limit("timeout=30".into()), Some(Duration::from_secs(30)));
+        assert_eq!(limit(format!("deadline={}", now_ms + 5_000)), Some(Duration::from_secs(5)));
+        assert_eq!(limit(format!("timeout=30;deadline={}", now_ms + 5_000)), Some(Duration::from_secs(5)));
+        assert_eq!(limit(format!("timeout=3;deadline={}", now_ms + 5_000)), Some(Duration::from_secs(3)));
+        // Already past
+        assert_eq!(limit(format!("timeout=30;deadline={}", now_ms - 1)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn evaluation_order() {
+        let _serial = testing::serial();
+        use TargetRejection::*;
+        let onion_claim = format!("host={}", ONION);
+        let cases: Vec<(&str, &str, Settings, Result<Destination, TargetRejection>)> = vec![
+            // Plain targets
+            ("example.com", "", Settings::default(), Ok(dest("example.com", None, false))),
+            ("93.184.216.34", "", Settings::default(), Ok(dest("93.184.216.34", None, false))),
+            // Claims (step 3)
+            ("93.184.216.34", "host=example.com", Settings::default(), Ok(dest("93.184.216.34", Some("example.com"), false))),
+            ("example.org", "host=example.com", Settings::default(), Err(ClaimWithoutLiteral)),
+            ("93.184.216.34", "host=10.0.0.1", Settings::default(), Err(InvalidClaim)),
+            ("93.184.216.34", &onion_claim, Settings::default(), Err(InvalidClaim)),
+            ("192.168.1.1", "host=example.com", Settings::default(), Err(ClaimForBlockedLiteral)),
+            ("::ffff:127.0.0.1", "host=example.com", Settings::default(), Err(ClaimForBlockedLiteral)),
+            // A bad claim is judged as such even where domains are required
+            (
+                "192.168.1.1",
+                "host=example.com",
+                Settings { require_domain: true, ..Default::default() },
+                Err(ClaimForBlockedLiteral),
+            ),
+            // Domain targets required (step 4): a valid claim names the literal
+            ("93.184.216.34", "", Settings { require_domain: true, ..Default::default() }, Err(LiteralWithoutName)),
+            ("2606:2800:220:1::", "", Settings { require_domain: true, ..Default::default() }, Err(LiteralWithoutName)),
+            (
+                "93.184.216.34",
+                "host=example.com",
+                Settings { require_domain: true, ..Default::default() },
+                Ok(dest("93.184.216.34", Some("example.com"), false)),
+            ),
+            ("example.com", "", Settings { require_domain: true, ..Default::default() }, Ok(dest("example.com", None, false))),
+            // Fronting (step 5)
+            ("example.com", "front=cdn.example.net", Settings::default(), Ok(dest("cdn.example.net", Some("example.com"), false))),
+            ("93.184.216.34", "front=cdn.example.net", Settings::default(), Err(InvalidFront)),
+            ("93.184.216.34", "host=example.com;front=cdn.example.net", Settings::default(), Err(InvalidFront)),
+            // Policy sees the literal and the claim (step 6)
+            (
+                "93.184.216.34",
+                "host=example.com",
+                Settings { deny: &["*.com"], ..Default::default() },
+                Err(Denied),
+            ),
+            (
+                "93.184.216.34",
+                "host=example.com",
+                Settings { deny: &["93.184.216.34:443"], ..Default::default() },
+                Err(Denied),
+            ),
+            (
+                "93.184.216.34",
+                "host=example.com",
+                Settings { allow: &["example.com"], ..Default::default() },
+                Ok(dest("93.184.216.34", Some("example.com"), false)),
+            ),
+            ("example.org", "", Settings { allow: &["example.com"], ..Default::default() }, Err(NotAllowed)),
+            ("example.com", "front=cdn.example.net", Settings { deny: &["cdn.example.net"], ..Default::default() }, Err(Denied)),
+            // Policy applies before mapping: a denied name isn't rescued by its alias
+            (
+                "example.com",
+                "",
+                Settings { deny: &["example.com"], alias: Some(("example.com", ONION)), ..Default::default() },
+                Err(Denied),
+            ),
+            // Mapping (step 7) maps the claimed name, never the literal
+            (
+                "93.184.216.34",
+                "host=example.com",
+                Settings { alias: Some(("example.com", ONION)), ..Default::default() },
+                Ok(dest(ONION, Some("example.com"), true)),
+            ),
+            (
+                "example.com",
+                "front=cdn.example.net",
+                Settings { alias: Some(("example.com", ONION)), ..Default::default() },
+                Ok(dest("cdn.example.net", Some("example.com"), false)),
+            ),
+            // Onion-only judges the mapped target (step 8)
+            ("example.com", "", Settings { onion_only: true, ..Default::default() }, Err(NotOnion)),
+            (
+                "93.184.216.34",
+                "host=example.com",
+                Settings { onion_only: true, alias: Some(("example.com", ONION)), ..Default::default() },
+                Ok(dest(ONION, Some("example.com"), true)),
+            ),
+            ("example.com", "front=cdn.example.net", Settings { onion_only: true, ..Default::default() }, Err(NotOnion)),
+            (ONION, "", Settings { onion_only: true, require_domain: true, ..Default::default() }, Ok(dest(ONION, None, false))),
+        ];
+        for (target, username, settings, expected) in cases {
+            settings.apply();
+            let outcome = evaluate(target.to_string(), 443, &ConnectCredentials::parse(username));
+            assert_eq!(outcome, expected, "CONNECT {} with {:?}", target, username);
+        }
+        Settings::default().apply();
+
+        // Literals can't be given an alias at all
+        assert!(check_onion_aliases(&json!({ "93.184.216.34": ONION })).is_err());
+        assert!(check_onion_aliases(&json!({ "example.com": ONION })).is_ok());
+    }
+
+    #[test]
+    fn report_host_prefers_the_claimed_name() {
+        assert_eq!(dest("93.184.216.34", Some("example.com"), false).report_host(), "example.com");
+        assert_eq!(dest("example.com", None, false).report_host(), "example.com");
+    }
+
+    #[test]
+    fn patterns() {
+        for (pattern, host, port, matches) in [
+            ("example.com", "EXAMPLE.com", 443, true),
+            ("example.com", "www.example.com", 443, false),
+            ("*.example.com", "www.example.com", 80, true),
+            ("*.example.com", "example.com", 80, false),
+            ("*.example.com", "badexample.com", 80, false),
+            ("example.com:443", "example.com", 443, true),
+            ("example.com:443", "example.com", 80, false),
+        ] {
+            assert_eq!(Pattern::parse(pattern).unwrap().matches(host, port), matches, "{} vs {}:{}", pattern, host, port);
+        }
+        for invalid in ["", "*.", "a*.com", "example.com:http", "*.*.com"] {
+            assert_eq!(Pattern::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+}
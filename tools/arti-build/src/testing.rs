@@ -0,0 +1,140 @@
+//! Helpers shared by the unit tests
+//!
+//! Most of the wrapper's state is process-wide (settings, histories, the
+//! clock and entropy seams), and cargo runs tests on parallel threads, so a
+//! test that touches any of it holds `serial()` for its whole run and puts
+//! back what it changed. Tests of pure functions don't need it.
+//!
+//! SOCKS tests run the real connection handler on a loopback socket, with a
+//! `FakeConnector` (see seams.rs) in place of the Tor client.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::any::Any;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::seams::{fakes, Connector};
+
+/// Hold while touching process-wide state
+pub(crate) fn serial() -> MutexGuard<'static, ()> {
+    static SERIAL: Mutex<()> = Mutex::new(());
+    // A failed test must not fail every test after it
+    SERIAL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Guards of process-wide locks, released together on drop
+///
+/// Each module with global locks lists them in its `hold_locks`, so a test
+/// can prove a path never waits on any of them.
+#[derive(Default)]
+pub(crate) struct HeldLocks(Vec<Box<dyn Any>>);
+
+impl HeldLocks {
+    pub(crate) fn hold<T: 'static>(&mut self, lock: &'static Mutex<T>) {
+        self.0.push(Box::new(lock.lock().unwrap_or_else(PoisonError::into_inner)));
+    }
+
+    pub(crate) fn hold_rw<T: 'static>(&mut self, lock: &'static RwLock<T>) {
+        self.0.push(Box::new(lock.write().unwrap_or_else(PoisonError::into_inner)));
+    }
+}
+
+/// The fake clock while alive, the system clock again once dropped
+pub(crate) struct FakeTime(());
+
+impl FakeTime {
+    pub(crate) fn start() -> FakeTime {
+        fakes::use_fake_clock();
+        FakeTime(())
+    }
+
+    pub(crate) fn advance(&self, by: Duration) {
+        fakes::advance_clock(by);
+    }
+}
+
+impl Drop for FakeTime {
+    fn drop(&mut self) {
+        fakes::use_system_clock();
+    }
+}
+
+/// A fresh directory, removed with everything in it on drop
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("arti-wrapper-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A runtime for tests that drive async code
+pub(crate) fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap()
+}
+
+/// Accept one SOCKS client and run the connection handler on it with `connector`
+///
+/// Returns the client's end of the connection and the handler's task.
+pub(crate) async fn socks_session<C: Connector>(
+    connector: Arc<C>,
+) -> (TcpStream, JoinHandle<anyhow::Result<()>>, crate::cancel::CancelToken) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let closer = crate::cancel::CancelToken::new();
+    let handler = tokio::spawn(crate::handle_socks_connection(server, connector, closer.clone()));
+    (client, handler, closer)
+}
+
+/// Send a SOCKS5 greeting offering `methods`; returns the chosen method
+pub(crate) async fn socks_greet(client: &mut TcpStream, methods: &[u8]) -> u8 {
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    client.write_all(&greeting).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    choice[1]
+}
+
+/// Authenticate with `username` and `password` (RFC 1929); returns the status
+pub(crate) async fn socks_login(client: &mut TcpStream, username: &str, password: &str) -> u8 {
+    let mut request = vec![0x01, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).await.unwrap();
+    reply[1]
+}
+
+/// Send a CONNECT to `host:port` by name and read the reply frame
+pub(crate) async fn socks_connect(client: &mut TcpStream, host: &str, port: u16) -> [u8; 10] {
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    reply
+}
@@ -0,0 +1,457 @@
+//! Directory vs user traffic accounting
+//!
+//! First bootstrap downloads the consensus and microdescriptors, which can be
+//! 10-20MB; later updates are much smaller. Users on metered plans want to see
+//! that separately from the bytes their apps moved.
+//!
+//! User bytes are counted exactly as they pass through the SOCKS relay loop
+//! (`Metrics::bytes_relayed`). Arti has no public per-circuit-purpose byte
+//! accounting, so directory bytes are estimated from the growth of its
+//! directory cache: the cache is sampled before bootstrap and after every
+//! netdir event, and each increase is counted as downloaded. This slightly
+//! overstates compressed transfers and misses documents that replace others
+//! of the same size, which is good enough for a settings-screen figure.
+//!
+//! Cumulative directory bytes are also kept per calendar month (UTC) in the
+//! wrapper state directory, so the figure survives restarts.
+//!
+//! Wire bytes (`getWireBytes`) estimate what those payloads cost on the
+//! network: Arti has no public channel byte counters either, so they are
+//! rebuilt from the payloads. Every chunk the relay loop forwards goes out
+//! (or came in) as whole 514-byte cells carrying at most 498 payload bytes;
+//! each stream adds its BEGIN, CONNECTED and END cells, received data adds
+//! the SENDME cells sent back, and the cells travel in TLS records. Circuit
+//! construction, padding and TCP/IP headers aren't counted, so the figure is
+//! a floor, but never below the payload it carried.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use arti_client::TorClient;
+use futures::StreamExt;
+use tor_rtcompat::PreferredRuntime;
+
+use crate::layout::{self, Purpose};
+use crate::notification::now_epoch_ms;
+
+/// Rough cost of a bootstrap with an empty cache
+const FRESH_BOOTSTRAP_BYTES: u64 = 12 * 1024 * 1024;
+
+/// Rough cost with a cached consensus that has expired (diffs plus new microdescriptors)
+const STALE_CACHE_BOOTSTRAP_BYTES: u64 = 3 * 1024 * 1024;
+
+/// Rough cost with a recent cache (at most a few microdescriptors)
+const FRESH_CACHE_BOOTSTRAP_BYTES: u64 = 512 * 1024;
+
+/// A consensus is replaced hourly and usable for about three hours
+const CACHE_FRESH_FOR: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// Directory bytes since process start
+static DIRECTORY_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Size of a cell on a channel (link protocol 4 and later)
+const CELL_BYTES: u64 = 514;
+
+/// Stream data carried by one RELAY_DATA cell at most
+const CELL_PAYLOAD_BYTES: u64 = 498;
+
+/// Cells to open and close a stream (BEGIN, CONNECTED, END)
+const STREAM_SETUP_CELLS: u64 = 3;
+
+/// Received data cells per stream-level and per circuit-level SENDME
+const STREAM_SENDME_WINDOW: u64 = 50;
+const CIRCUIT_SENDME_WINDOW: u64 = 100;
+
+/// Largest TLS record payload, and what each record adds (header, content
+/// type and AEAD tag)
+const TLS_RECORD_BYTES: u64 = 16 * 1024;
+const TLS_RECORD_OVERHEAD: u64 = 22;
+
+/// Relay cells for user streams since process start, and how many of those
+/// were received
+static USER_CELLS: AtomicU64 = AtomicU64::new(0);
+static RECEIVED_CELLS: AtomicU64 = AtomicU64::new(0);
+
+/// Which way relayed data went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// From the SOCKS client into Tor
+    Upstream,
+    /// From Tor to the SOCKS client
+    Downstream,
+}
+
+/// Cache size at the last sample
+static LAST_CACHE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+struct Monthly {
+    path: PathBuf,
+    /// "YYYY-MM" -> directory bytes
+    months: BTreeMap<String, u64>,
+}
+
+static MONTHLY: Mutex<Option<Monthly>> = Mutex::new(None);
+
+/// Netdir watcher task (replaced whenever a new client is created)
+static WATCHER: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+
+/// UTC calendar month ("YYYY-MM") of a Unix time in milliseconds
+fn month_of(epoch_ms: u64) -> String {
+    // Civil-from-days (Howard Hinnant), valid for any date after 1970
+    let z = (epoch_ms / 86_400_000) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Total size of the regular files under `dir`
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&entry.path()),
+            Ok(t) if t.is_file() => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Newest modification time of any file under `dir`
+fn newest_mtime(dir: &Path) -> Option<SystemTime> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => newest_mtime(&entry.path()),
+            Ok(t) if t.is_file() => entry.metadata().and_then(|m| m.modified()).ok(),
+            _ => None,
+        })
+        .max()
+}
+
+/// Load the per-month totals for `data_dir`
+pub(crate) fn open(data_dir: &Path) {
+    let path = layout::path(data_dir, Purpose::DirectoryBytesByMonth);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).ok();
+    }
+    let months = std::fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (month, bytes) = line.split_once(' ')?;
+            Some((month.to_string(), bytes.parse().ok()?))
+        })
+        .collect();
+    *MONTHLY.lock().unwrap() = Some(Monthly { path, months });
+}
+
+/// Delete the persisted per-month totals, keeping those in memory (retention.rs)
+pub(crate) fn remove_file() {
+    if let Some(monthly) = MONTHLY.lock().unwrap().as_ref() {
+        if let Err(e) = std::fs::remove_file(&monthly.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log_error!("Failed to delete monthly directory bytes: {}", e);
+            }
+        }
+    }
+}
+
+/// Count `bytes` as directory traffic, in the process total and this month
+fn add_directory_bytes(bytes: u64) {
+    DIRECTORY_BYTES.fetch_add(bytes, Ordering::Relaxed);
+
+    let mut monthly = MONTHLY.lock().unwrap();
+    let Some(monthly) = monthly.as_mut() else {
+        return;
+    };
+    *monthly.months.entry(month_of(now_epoch_ms())).or_insert(0) += bytes;
+    if !crate::retention::retains(crate::retention::Sink::PersistentCounters) {
+        return;
+    }
+    let text: String = monthly
+        .months
+        .iter()
+        .map(|(month, bytes)| format!("{} {}\n", month, bytes))
+        .collect();
+    if let Err(e) = layout::write_atomic(&monthly.path, text.as_bytes()) {
+        log_error!("Failed to persist monthly directory bytes: {}", e);
+    }
+}
+
+/// Set the cache-size baseline (call before bootstrap)
+pub(crate) fn baseline(cache_dir: &Path) {
+    LAST_CACHE_SIZE.store(dir_size(cache_dir), Ordering::Relaxed);
+}
+
+/// Count any cache growth since the last sample as directory traffic
+pub(crate) fn sample(cache_dir: &Path) {
+    let size = dir_size(cache_dir);
+    let previous = LAST_CACHE_SIZE.swap(size, Ordering::Relaxed);
+    if size > previous {
+        add_directory_bytes(size - previous);
+    }
+}
+
+/// Sample the cache after every netdir event from `client`
+pub(crate) fn start_watcher(runtime: &tokio::runtime::Handle, client: Arc<TorClient<PreferredRuntime>>, cache_dir: PathBuf) {
+    let mut events = client.dirmgr().events();
+    let handle = runtime.spawn(async move {
+        while events.next().await.is_some() {
+            sample(&cache_dir);
+        }
+    });
+    if let Some(previous) = WATCHER.lock().unwrap().replace(handle) {
+        previous.abort();
+    }
+}
+
+/// Count a chunk the relay loop forwarded as the cells that carried it
+pub(crate) fn count_relayed(direction: Direction, bytes: usize) {
+    let cells = (bytes as u64).div_ceil(CELL_PAYLOAD_BYTES);
+    USER_CELLS.fetch_add(cells, Ordering::Relaxed);
+    if direction == Direction::Downstream {
+        RECEIVED_CELLS.fetch_add(cells, Ordering::Relaxed);
+    }
+}
+
+/// Count the cells that open and close a user stream
+pub(crate) fn count_stream() {
+    USER_CELLS.fetch_add(STREAM_SETUP_CELLS, Ordering::Relaxed);
+}
+
+/// Estimated bytes on the wire since process start, user and directory
+/// traffic together (see the module docs for what is counted)
+pub(crate) fn wire_bytes() -> u64 {
+    let received = RECEIVED_CELLS.load(Ordering::Relaxed);
+    let sendmes = received / STREAM_SENDME_WINDOW + received / CIRCUIT_SENDME_WINDOW;
+    let directory_cells = directory_bytes().div_ceil(CELL_PAYLOAD_BYTES);
+    let cell_bytes = (USER_CELLS.load(Ordering::Relaxed) + sendmes + directory_cells) * CELL_BYTES;
+    cell_bytes + cell_bytes.div_ceil(TLS_RECORD_BYTES) * TLS_RECORD_OVERHEAD
+}
+
+/// Directory bytes since process start
+pub(crate) fn directory_bytes() -> u64 {
+    DIRECTORY_BYTES.load(Ordering::Relaxed)
+}
+
+/// Per-month directory bytes as a JSON object ("YYYY-MM" -> bytes)
+pub(crate) fn months_json() -> String {
+    let monthly = MONTHLY.lock().unwrap();
+    let entries = monthly
+        .as_ref()
+        .map(|m| {
+            m.months
+                .iter()
+                .map(|(month, bytes)| format!("\"{}\":{}", month, bytes))
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    format!("{{{}}}", entries)
+}
+
+/// Rough expected download for the next bootstrap, as JSON
+///
+/// `basis` is `no_cache`, `stale_cache`, `fresh_cache`, or `unknown` when the
+/// data directory isn't known yet (the fresh-bootstrap figure is used then).
+pub(crate) fn estimate_json(data_dir: Option<&Path>) -> String {
+    let newest = data_dir.and_then(|d| newest_mtime(&layout::path(d, Purpose::ArtiCache)));
+    let age = newest.and_then(|t| crate::seams::now_wall().duration_since(t).ok());
+
+    let (basis, bytes) = match (data_dir, age) {
+        (None, _) => ("unknown", FRESH_BOOTSTRAP_BYTES),
+        (Some(_), None) if newest.is_none() => ("no_cache", FRESH_BOOTSTRAP_BYTES),
+        (Some(_), Some(age)) if age > CACHE_FRESH_FOR => ("stale_cache", STALE_CACHE_BOOTSTRAP_BYTES),
+        (Some(_), _) => ("fresh_cache", FRESH_CACHE_BOOTSTRAP_BYTES),
+    };
+    let age_json = age.map_or("null".to_string(), |a| a.as_secs().to_string());
+    format!(
+        "{{\"estimated_bytes\":{},\"basis\":\"{}\",\"cache_age_secs\":{}}}",
+        bytes, basis, age_json,
+    )
+}
+
+/// Stop using the open data directory (tests open temporary ones)
+#[cfg(test)]
+pub(crate) fn close() {
+    *MONTHLY.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+pub(crate) fn hold_locks(held: &mut crate::testing::HeldLocks) {
+    held.hold(&MONTHLY);
+    held.hold(&WATCHER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeTime, TempDir};
+
+    const DAY: Duration = Duration::from_secs(86_400);
+
+    fn grow(dir: &Path, name: &str, bytes: usize) {
+        std::fs::write(dir.join(name), vec![0u8; bytes]).unwrap();
+    }
+
+    fn months(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn calendar_months_are_utc() {
+        for (epoch_ms, month) in [
+            (0, "1970-01"),
+            (951_782_399_999, "2000-02"),
+            (951_782_400_000, "2000-02"),
+            (951_868_800_000, "2000-03"),
+            (1_709_164_800_000, "2024-02"),
+            (1_709_251_199_999, "2024-02"),
+            (1_709_251_200_000, "2024-03"),
+            (1_735_689_599_999, "2024-12"),
+            (1_735_689_600_000, "2025-01"),
+        ] {
+            assert_eq!(month_of(epoch_ms), month, "{}", epoch_ms);
+        }
+    }
+
+    #[test]
+    fn cache_growth_is_directory_traffic_and_relayed_data_is_not() {
+        let _serial = testing::serial();
+        let saved = MONTHLY.lock().unwrap().take();
+        let temp = TempDir::new();
+        let cache = temp.path().join("cache");
+        std::fs::create_dir_all(cache.join("nested")).unwrap();
+        grow(&cache, "consensus", 1000);
+        let before = directory_bytes();
+        let wire_before = wire_bytes();
+
+        // What was cached before bootstrap isn't counted
+        baseline(&cache);
+        sample(&cache);
+        assert_eq!(directory_bytes(), before);
+
+        grow(&cache, "consensus", 1500);
+        grow(&cache.join("nested"), "microdescs", 300);
+        sample(&cache);
+        assert_eq!(directory_bytes() - before, 800);
+        // A smaller cache (documents pruned) doesn't count back down
+        grow(&cache, "consensus", 100);
+        sample(&cache);
+        assert_eq!(directory_bytes() - before, 800);
+        // Growth counts from the last sample, even a smaller one
+        grow(&cache, "consensus", 400);
+        sample(&cache);
+        assert_eq!(directory_bytes() - before, 1100);
+
+        // User traffic only moves the wire estimate
+        let directory = directory_bytes();
+        count_stream();
+        count_relayed(Direction::Upstream, 499);
+        count_relayed(Direction::Downstream, 498 * 100);
+        assert_eq!(directory_bytes(), directory);
+        // 3 setup cells + 2 + 100 data cells + 2 stream and 1 circuit SENDMEs
+        assert!(wire_bytes() - wire_before >= (3 + 2 + 100 + 3) * CELL_BYTES);
+
+        *MONTHLY.lock().unwrap() = saved;
+    }
+
+    #[test]
+    fn relayed_chunks_count_as_whole_cells() {
+        let _serial = testing::serial();
+        for (direction, bytes, cells, received) in [
+            (Direction::Upstream, 1, 1, 0),
+            (Direction::Upstream, 498, 1, 0),
+            (Direction::Upstream, 499, 2, 0),
+            (Direction::Downstream, 498 * 3, 3, 3),
+            (Direction::Downstream, 498 * 3 + 1, 4, 4),
+            (Direction::Upstream, 0, 0, 0),
+        ] {
+            let (user, received_before) = (USER_CELLS.load(Ordering::Relaxed), RECEIVED_CELLS.load(Ordering::Relaxed));
+            count_relayed(direction, bytes);
+            assert_eq!(USER_CELLS.load(Ordering::Relaxed) - user, cells, "{:?} {}", direction, bytes);
+            assert_eq!(RECEIVED_CELLS.load(Ordering::Relaxed) - received_before, received, "{:?} {}", direction, bytes);
+        }
+
+        let user = USER_CELLS.load(Ordering::Relaxed);
+        count_stream();
+        assert_eq!(USER_CELLS.load(Ordering::Relaxed) - user, STREAM_SETUP_CELLS);
+    }
+
+    #[test]
+    fn monthly_totals_roll_over_and_survive_a_restart() {
+        let _serial = testing::serial();
+        let time = FakeTime::start();
+        let saved = MONTHLY.lock().unwrap().take();
+        let temp = TempDir::new();
+        let path = layout::path(temp.path(), Purpose::DirectoryBytesByMonth);
+
+        open(temp.path());
+        let this_month = month_of(now_epoch_ms());
+        add_directory_bytes(700);
+        add_directory_bytes(300);
+        assert_eq!(months(&path), format!("{} 1000\n", this_month));
+
+        // Into the next calendar month
+        while month_of(now_epoch_ms()) == this_month {
+            time.advance(DAY);
+        }
+        let next_month = month_of(now_epoch_ms());
+        add_directory_bytes(50);
+        let expected = format!("{} 1000\n{} 50\n", this_month, next_month);
+        assert_eq!(months(&path), expected);
+        assert_eq!(months_json(), format!("{{\"{}\":1000,\"{}\":50}}", this_month, next_month));
+
+        // A new process continues the totals from disk
+        *MONTHLY.lock().unwrap() = None;
+        assert_eq!(months_json(), "{}");
+        open(temp.path());
+        add_directory_bytes(25);
+        assert_eq!(months(&path), format!("{} 1000\n{} 75\n", this_month, next_month));
+
+        // Malformed lines are dropped rather than failing the load
+        std::fs::write(&path, format!("garbage\n{} x\n{} 75\n", this_month, next_month)).unwrap();
+        open(temp.path());
+        assert_eq!(months_json(), format!("{{\"{}\":75}}", next_month));
+
+        remove_file();
+        assert!(!path.exists());
+        *MONTHLY.lock().unwrap() = saved;
+    }
+
+    #[test]
+    fn bootstrap_estimate_follows_the_cache_age() {
+        let _serial = testing::serial();
+        let temp = TempDir::new();
+        let estimate = |dir: Option<&Path>| -> serde_json::Value { serde_json::from_str(&estimate_json(dir)).unwrap() };
+
+        assert_eq!(estimate(None)["basis"], "unknown");
+        assert_eq!(estimate(None)["estimated_bytes"], FRESH_BOOTSTRAP_BYTES);
+        assert_eq!(estimate(Some(temp.path()))["basis"], "no_cache");
+
+        let cache = layout::path(temp.path(), Purpose::ArtiCache);
+        std::fs::create_dir_all(&cache).unwrap();
+        grow(&cache, "consensus", 10);
+        assert_eq!(estimate(Some(temp.path()))["basis"], "fresh_cache");
+        assert_eq!(estimate(Some(temp.path()))["estimated_bytes"], FRESH_CACHE_BOOTSTRAP_BYTES);
+
+        let time = FakeTime::start();
+        time.advance(CACHE_FRESH_FOR + DAY);
+        let stale = estimate(Some(temp.path()));
+        assert_eq!(stale["basis"], "stale_cache");
+        assert_eq!(stale["estimated_bytes"], STALE_CACHE_BOOTSTRAP_BYTES);
+        assert!(stale["cache_age_secs"].as_u64().unwrap() >= (CACHE_FRESH_FOR + DAY).as_secs());
+    }
+}